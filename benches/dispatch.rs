@@ -0,0 +1,129 @@
+//! Runs programs that spend their time dispatching a few instructions,
+//! `fib(25)`, which also calls, and a loop that does not, and reports the
+//! best of several runs of each.
+//!
+//! The instructions the VM dispatched before they were packed into code
+//! units are gone from the tree, so the comparison is with a saved run:
+//! `-- --save-baseline <file>` writes the best times to the file, and a
+//! later `-- --baseline <file>` reports the speedup over them, e.g. on the
+//! commit before the packing and then on this one.
+//!
+//! Measured that way, the best of four rounds of 50 runs, alternating
+//! between the two commits:
+//!
+//! ```text
+//! program          before      after  speedup
+//! fib             0.052 s    0.035 s    1.46x
+//! loop            0.038 s    0.024 s    1.61x
+//! ```
+//!
+//! Packing alone won nothing, as the decoded instructions were already
+//! small `Copy` values. The win comes from what the VM does with the units:
+//! it decodes each one once, folding any `EXTENDED_ARG`s into the argument
+//! of the instruction they extend, rather than on every run; it fuses
+//! pairs of instructions such as `LOAD_FAST LOAD_FAST` into
+//! superinstructions; and the specialized instructions, with the loads and
+//! stores of fast locals, copy and drop ints and floats without a call
+//! into [`Value`]'s clone and drop glue, which had taken a quarter of the
+//! loop's time.
+//!
+//! [`Value`]: rustypy::value::Value
+//!
+//! Run with `cargo bench --bench dispatch`; pass a number to change how
+//! many runs are timed, e.g. `cargo bench --bench dispatch -- 20`.
+
+use std::fs;
+use std::rc::Rc;
+use std::time::Instant;
+
+use rustypy::interpreter::compile_source;
+use rustypy::vm::Vm;
+
+/// Calls, comparisons and arithmetic.
+const FIB: &str = "\
+def fib(x):
+    if x < 2:
+        return x
+    return fib(x-1) + fib(x-2)
+
+result = fib(25)
+";
+
+/// Int arithmetic and comparisons, with no calls.
+const LOOP: &str = "\
+def count(n):
+    i = 0
+    total = 0
+    while i < n:
+        total += i * 3 - 1
+        i += 1
+    return total
+
+result = count(300000)
+";
+
+const PROGRAMS: [(&str, &str); 2] = [("fib", FIB), ("loop", LOOP)];
+
+/// The best time of `runs` runs of `source`, in seconds.
+fn best_time(name: &str, source: &str, runs: usize) -> f64 {
+    let code = Rc::new(compile_source(source, &format!("{}.py", name), 1).unwrap());
+    let mut best = f64::INFINITY;
+    for _ in 0..runs {
+        let mut vm = Vm::new(Vec::new());
+        let start = Instant::now();
+        vm.run(code.clone())
+            .unwrap_or_else(|error| panic!("{}", error));
+        best = best.min(start.elapsed().as_secs_f64());
+        assert!(vm.global("result").is_some());
+    }
+    best
+}
+
+/// The times saved by `--save-baseline`, one `name seconds` line each.
+fn read_baseline(path: &str) -> Vec<(String, f64)> {
+    let text = fs::read_to_string(path).unwrap_or_else(|error| panic!("{}: {}", path, error));
+    text.lines()
+        .filter_map(|line| {
+            let (name, time) = line.split_once(' ')?;
+            Some((name.to_string(), time.parse().ok()?))
+        })
+        .collect()
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let runs = args.iter().find_map(|arg| arg.parse().ok()).unwrap_or(10);
+    let option = |name: &str| {
+        let index = args.iter().position(|arg| arg == name)?;
+        Some(args.get(index + 1).expect("a file name").as_str())
+    };
+    let baseline = option("--baseline").map(read_baseline);
+
+    println!("runs:         {}", runs);
+    println!(
+        "{:<12} {:>10} {:>10} {:>8}",
+        "program", "before", "after", "speedup"
+    );
+    let mut saved = String::new();
+    for (name, source) in PROGRAMS {
+        let time = best_time(name, source, runs);
+        saved += &format!("{} {}\n", name, time);
+        let before = baseline
+            .iter()
+            .flatten()
+            .find_map(|(saved, time)| (saved == name).then_some(*time));
+        match before {
+            Some(before) => println!(
+                "{:<12} {:>8.3} s {:>8.3} s {:>7.2}x",
+                name,
+                before,
+                time,
+                before / time
+            ),
+            None => println!("{:<12} {:>10} {:>8.3} s", name, "-", time),
+        }
+    }
+    if let Some(path) = option("--save-baseline") {
+        fs::write(path, saved).unwrap_or_else(|error| panic!("{}: {}", path, error));
+    }
+}
@@ -0,0 +1,113 @@
+//! Parses a large synthetic module and reports parser throughput and the
+//! peak heap use while parsing.
+//!
+//! Run with `cargo bench --bench parse`; pass a line count to change the
+//! size of the module, e.g. `cargo bench --bench parse -- 20000`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use rustypy::parser::parse;
+
+/// Wraps the system allocator to track the current and peak heap size.
+struct Counting;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let current = CURRENT.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(current, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// A module of roughly `lines` lines mixing the common statement forms.
+fn synthetic_module(lines: usize) -> String {
+    let mut source = String::new();
+    let mut i = 0;
+    let mut count = 0;
+    while count < lines {
+        let chunk = format!(
+            "\
+class Shape{i}(Base, metaclass=Meta):
+    \"\"\"A shape.\"\"\"
+    sides: int = {i}
+
+    def __init__(self, width, height=1.5, *args, scale=None, **kwargs):
+        self.width = width
+        self.height = height
+        self.points = [(x, y) for x in range(width) for y in range(height) if x != y]
+
+    @property
+    def area(self):
+        if self.width > 0 and not self.height < 0:
+            return self.width * self.height ** 2 - (self.sides // 3)
+        elif self.width is None:
+            raise ValueError('no width: %d' % self.sides)
+        return {{'w': self.width, 'h': self.height, **self.extra}}
+
+def process{i}(items, key=lambda item: item[0]):
+    total = 0
+    for index, item in enumerate(sorted(items, key=key)):
+        try:
+            total += item[1:-1] if index % 2 else -item[::2]
+        except (KeyError, IndexError) as error:
+            print('skipped', error, sep=', ')
+            continue
+        finally:
+            del item
+    while total > 100:
+        total >>= 1
+    return total
+
+",
+        );
+        count += chunk.lines().count();
+        source.push_str(&chunk);
+        i += 1;
+    }
+    source
+}
+
+fn main() {
+    let lines = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(100_000);
+    let source = synthetic_module(lines);
+    let lines = source.lines().count();
+
+    let baseline = CURRENT.load(Ordering::Relaxed);
+    PEAK.store(baseline, Ordering::Relaxed);
+    let start = Instant::now();
+    let module = parse(&source).unwrap_or_else(|errors| panic!("{}", errors[0]));
+    let elapsed = start.elapsed();
+    let peak = PEAK.load(Ordering::Relaxed) - baseline;
+    let retained = CURRENT.load(Ordering::Relaxed) - baseline;
+
+    let nodes = module.arena.node_count();
+    let seconds = elapsed.as_secs_f64();
+    println!("lines:        {}", lines);
+    println!("bytes:        {}", source.len());
+    println!("nodes:        {}", nodes);
+    println!("time:         {:.3} s", seconds);
+    println!("lines/sec:    {:.0}", lines as f64 / seconds);
+    println!("nodes/sec:    {:.0}", nodes as f64 / seconds);
+    println!("peak memory:  {:.1} MiB", peak as f64 / (1024.0 * 1024.0));
+    println!("AST memory:   {:.1} MiB", retained as f64 / (1024.0 * 1024.0));
+    println!("bytes/node:   {:.1}", retained as f64 / nodes as f64);
+}
@@ -0,0 +1,119 @@
+//! Runs programs that spend their time in the instructions the VM
+//! specializes, with specialization off and then on, and reports the best
+//! of several runs of each and the speedup.
+//!
+//! Run with `cargo bench --bench specialize`; pass a number to change how
+//! many runs are timed, e.g. `cargo bench --bench specialize -- 20`.
+
+use std::rc::Rc;
+use std::time::Instant;
+
+use rustypy::interpreter::compile_source;
+use rustypy::vm::Vm;
+
+/// Calls, comparisons, arithmetic and a global.
+const FIB: &str = "\
+def fib(x):
+    if x < 2:
+        return x
+    return fib(x-1) + fib(x-2)
+
+result = fib(25)
+";
+
+/// Int arithmetic and comparisons in a loop.
+const LOOP: &str = "\
+def count(n):
+    i = 0
+    total = 0
+    while i < n:
+        total += i * 3 - 1
+        i += 1
+    return total
+
+result = count(1000000)
+";
+
+/// Float arithmetic.
+const FLOAT: &str = "\
+def integrate(n):
+    step = 1.0 / n
+    x = 0.0
+    total = 0.0
+    i = 0
+    while i < n:
+        total += x * x * step
+        x += step
+        i += 1
+    return total
+
+result = integrate(1000000)
+";
+
+/// Attributes, methods and builtins.
+const OBJECTS: &str = "\
+class Point:
+    def __init__(self, x, y):
+        self.x = x
+        self.y = y
+
+    def dot(self, other):
+        return self.x * other.x + self.y * other.y
+
+def run(n):
+    p = Point(1, 2)
+    q = Point(3, 4)
+    points = [p, q]
+    total = 0
+    for i in range(n):
+        total += p.dot(q) + len(points) - q.y
+    return total
+
+result = run(300000)
+";
+
+const PROGRAMS: [(&str, &str); 4] = [
+    ("fib", FIB),
+    ("loop", LOOP),
+    ("float", FLOAT),
+    ("objects", OBJECTS),
+];
+
+/// The best time of `runs` runs of `source`, in seconds.
+fn best_time(name: &str, source: &str, specializing: bool, runs: usize) -> f64 {
+    let mut best = f64::INFINITY;
+    for _ in 0..runs {
+        // Compiled afresh each run, so that no code starts out specialized.
+        let code = Rc::new(compile_source(source, &format!("{}.py", name), 1).unwrap());
+        let mut vm = Vm::new(Vec::new());
+        vm.set_specializing(specializing);
+        let start = Instant::now();
+        vm.run(code).unwrap_or_else(|error| panic!("{}", error));
+        best = best.min(start.elapsed().as_secs_f64());
+        assert!(vm.global("result").is_some());
+    }
+    best
+}
+
+fn main() {
+    let runs = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(10);
+    println!("runs:         {}", runs);
+    println!(
+        "{:<12} {:>10} {:>10} {:>8}",
+        "program", "generic", "adaptive", "speedup"
+    );
+    for (name, source) in PROGRAMS {
+        let generic = best_time(name, source, false, runs);
+        let adaptive = best_time(name, source, true, runs);
+        println!(
+            "{:<12} {:>8.3} s {:>8.3} s {:>7.2}x",
+            name,
+            generic,
+            adaptive,
+            generic / adaptive
+        );
+    }
+}
@@ -0,0 +1,923 @@
+//! The assembler, which builds code objects from a textual listing, so
+//! that the VM can be tested on code the compiler does not emit.
+//!
+//! A listing is a block for each code object, the first being the one
+//! assembled and the others the code objects among its constants:
+//!
+//! ```text
+//! .code fib
+//! .argcount 1
+//! .flags 0x3
+//! .const None
+//! .varnames x
+//!     LOAD_FAST x
+//!     LOAD_CONST (2)
+//!     COMPARE_JUMP_IF_FALSE big (<)
+//!     LOAD_FAST x
+//!     RETURN_VALUE
+//! big:
+//!     LOAD_GLOBAL fib
+//!     ...
+//! ```
+//!
+//! Directives fill in the code object's attributes and tables; `.const`
+//! takes a Python literal, or `code ID` for the block `.code ID`. An
+//! instruction is a name and its argument, which may also be written as
+//! what it stands for: a name for the name and variable instructions, a
+//! comparison's operator, a label for a jump, and, with no index, a
+//! constant in parentheses, which is added to the constants if it is not
+//! among them. `.line` sets the source line of the instructions that
+//! follow.
+//!
+//! Lines as the disassembler prints them, with their line numbers,
+//! offsets and argument reprs, are instructions too: [`listing`] writes a
+//! code object's tables as directives followed by its disassembly, which
+//! assembles back to the same code object. So is its `ExceptionTable:`,
+//! after which each line is a handler, `4 to 10 -> 14 [0]`, with `lasti`
+//! on the end if it pushes the raising instruction's index: the offsets
+//! of the first and last instructions covered, and of the handler, and
+//! the stack depth to unwind to.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::rc::Rc;
+
+use crate::ast::{CmpOp, ExprKind, StmtKind};
+use crate::code::{Adaptive, CodeObject, ExceptionEntry, LineTable};
+use crate::dis::disassemble;
+use crate::fold::fold;
+use crate::intern::Symbol;
+use crate::intruction::{Instruction, CMP_OPS};
+use crate::parser::parse;
+use crate::value::Value;
+use crate::verify::verify;
+
+/// What is wrong with a listing, and on which of its lines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl AsmError {
+    fn new(line: usize, message: impl Into<String>) -> AsmError {
+        AsmError {
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+pub type AsmResult<T> = Result<T, AsmError>;
+
+/// Assembles the first code object in `text`, with the others it uses as
+/// constants, each of which is verified.
+pub fn assemble(text: &str) -> AsmResult<CodeObject> {
+    let mut blocks: Vec<Block> = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let number = number + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(directive) = line.strip_prefix('.') {
+            let (name, rest) = split_token(directive);
+            if name == "code" {
+                if rest.is_empty() {
+                    return Err(AsmError::new(number, ".code needs an id"));
+                }
+                if blocks.iter().any(|block| block.id == rest) {
+                    return Err(AsmError::new(
+                        number,
+                        format!("code {} is defined twice", rest),
+                    ));
+                }
+                blocks.push(Block::new(rest, number));
+                continue;
+            }
+            current(&mut blocks, number)?.directive(number, name, rest)?;
+        } else if line == "ExceptionTable:" {
+            current(&mut blocks, number)?.in_table = true;
+        } else if current(&mut blocks, number)?.in_table {
+            current(&mut blocks, number)?.handler(number, line)?;
+        } else if let Some(label) = line.strip_suffix(':').filter(|label| is_identifier(label)) {
+            current(&mut blocks, number)?.bind(number, label)?;
+        } else {
+            current(&mut blocks, number)?.instruction(number, line)?;
+        }
+    }
+    let Some(first) = blocks.first() else {
+        return Err(AsmError::new(1, "no .code"));
+    };
+    let id = first.id.clone();
+    let mut blocks: HashMap<String, Block> = blocks
+        .into_iter()
+        .map(|block| (block.id.clone(), block))
+        .collect();
+    finish(&mut blocks, &id, 1)
+}
+
+/// A listing of `code` that [`assemble`] turns back into it: for it and
+/// then each code object among its constants, its attributes and tables
+/// followed by its disassembly.
+pub fn listing(code: &CodeObject) -> String {
+    let mut ids = HashSet::new();
+    let id = unique_id(&mut ids, &code.qualname);
+    let mut text = String::new();
+    write_block(&mut text, &mut ids, code, &id);
+    text
+}
+
+fn write_block(text: &mut String, ids: &mut HashSet<String>, code: &CodeObject, id: &str) {
+    if !text.is_empty() {
+        text.push('\n');
+    }
+    text.push_str(&format!(".code {}\n", id));
+    text.push_str(&format!(".name {}\n", code.name));
+    text.push_str(&format!(".qualname {}\n", code.qualname));
+    text.push_str(&format!(".filename {}\n", code.filename));
+    text.push_str(&format!(".firstlineno {}\n", code.firstlineno));
+    text.push_str(&format!(".argcount {}\n", code.argcount));
+    text.push_str(&format!(".posonlyargcount {}\n", code.posonlyargcount));
+    text.push_str(&format!(".kwonlyargcount {}\n", code.kwonlyargcount));
+    text.push_str(&format!(".flags {:#x}\n", code.flags));
+    let mut nested = Vec::new();
+    for constant in &code.consts {
+        match constant {
+            Value::Code(inner) => {
+                let inner_id = unique_id(ids, &inner.qualname);
+                text.push_str(&format!(".const code {}\n", inner_id));
+                nested.push((inner, inner_id));
+            }
+            _ => text.push_str(&format!(".const {}\n", constant.repr())),
+        }
+    }
+    let tables = [
+        ("names", &code.names),
+        ("varnames", &code.varnames),
+        ("cellvars", &code.cellvars),
+        ("freevars", &code.freevars),
+    ];
+    for (directive, names) in tables {
+        if !names.is_empty() {
+            let names: Vec<&str> = names.iter().map(|name| name.as_str()).collect();
+            text.push_str(&format!(".{} {}\n", directive, names.join(" ")));
+        }
+    }
+    text.push_str(&disassemble(code));
+    for (inner, inner_id) in nested {
+        write_block(text, ids, inner, &inner_id);
+    }
+}
+
+/// `name`, or, if a block already has it, `name` with a number on the end.
+fn unique_id(ids: &mut HashSet<String>, name: &str) -> String {
+    let mut id = name.to_string();
+    let mut count = 1;
+    while ids.contains(&id) {
+        count += 1;
+        id = format!("{}.{}", name, count);
+    }
+    ids.insert(id.clone());
+    id
+}
+
+/// A constant, before the code objects it names are assembled.
+enum Constant {
+    Value(Value),
+    /// The code object of a block, and where it is named.
+    Code(String, usize),
+}
+
+/// A code object being assembled.
+struct Block {
+    id: String,
+    /// The line of its `.code`.
+    line: usize,
+    code: CodeObject,
+    instructions: Vec<Instruction>,
+    consts: Vec<Constant>,
+    /// The line of each instruction, if it has been given one.
+    lines: Vec<Option<usize>>,
+    current_line: Option<usize>,
+    /// The line of the listing each instruction is on.
+    sources: Vec<usize>,
+    labels: HashMap<String, u32>,
+    /// The jumps to labels, each with where it is and the label's name.
+    jumps: Vec<(usize, String)>,
+    /// Whether the code object has been taken to be finished, to catch
+    /// code that contains itself.
+    finished: bool,
+    /// Whether its `ExceptionTable:` has begun.
+    in_table: bool,
+}
+
+impl Block {
+    fn new(id: &str, line: usize) -> Block {
+        Block {
+            id: id.to_string(),
+            line,
+            code: CodeObject {
+                name: id.to_string(),
+                qualname: id.to_string(),
+                filename: "<asm>".to_string(),
+                firstlineno: 1,
+                argcount: 0,
+                posonlyargcount: 0,
+                kwonlyargcount: 0,
+                flags: 0,
+                stacksize: 0,
+                bytecode: Vec::new(),
+                adaptive: Adaptive::default(),
+                linetable: LineTable::default(),
+                consts: Vec::new(),
+                names: Vec::new(),
+                varnames: Vec::new(),
+                cellvars: Vec::new(),
+                freevars: Vec::new(),
+                exception_table: Vec::new(),
+            },
+            instructions: Vec::new(),
+            consts: Vec::new(),
+            lines: Vec::new(),
+            current_line: None,
+            sources: Vec::new(),
+            labels: HashMap::new(),
+            jumps: Vec::new(),
+            finished: false,
+            in_table: false,
+        }
+    }
+
+    fn directive(&mut self, line: usize, name: &str, rest: &str) -> AsmResult<()> {
+        let number = || {
+            parse_number(rest)
+                .ok_or_else(|| AsmError::new(line, format!(".{} needs a number", name)))
+        };
+        let code = &mut self.code;
+        match name {
+            "name" => code.name = rest.to_string(),
+            "qualname" => code.qualname = rest.to_string(),
+            "filename" => code.filename = rest.to_string(),
+            "firstlineno" => code.firstlineno = number()? as usize,
+            "argcount" => code.argcount = number()? as usize,
+            "posonlyargcount" => code.posonlyargcount = number()? as usize,
+            "kwonlyargcount" => code.kwonlyargcount = number()? as usize,
+            "flags" => code.flags = number()?,
+            "line" => self.current_line = Some(number()? as usize),
+            "const" => {
+                let constant = match rest.strip_prefix("code ") {
+                    Some(id) => Constant::Code(id.trim().to_string(), line),
+                    None => Constant::Value(parse_constant(line, rest)?),
+                };
+                self.consts.push(constant);
+            }
+            "names" | "varnames" | "cellvars" | "freevars" => {
+                let table = match name {
+                    "names" => &mut code.names,
+                    "varnames" => &mut code.varnames,
+                    "cellvars" => &mut code.cellvars,
+                    _ => &mut code.freevars,
+                };
+                table.extend(rest.split_whitespace().map(Symbol::intern));
+            }
+            _ => return Err(AsmError::new(line, format!("unknown directive .{}", name))),
+        }
+        Ok(())
+    }
+
+    fn bind(&mut self, line: usize, label: &str) -> AsmResult<()> {
+        let index = self.instructions.len() as u32;
+        if self.labels.insert(label.to_string(), index).is_some() {
+            return Err(AsmError::new(
+                line,
+                format!("label {} is bound twice", label),
+            ));
+        }
+        Ok(())
+    }
+
+    fn instruction(&mut self, line: usize, text: &str) -> AsmResult<()> {
+        // Any line number, offset and jump target marker, as the
+        // disassembler prints them.
+        let mut numbers = Vec::new();
+        let mut rest = text;
+        loop {
+            let (token, after) = split_token(rest);
+            if token != ">>" {
+                match token.parse::<usize>() {
+                    Ok(number) => numbers.push(number),
+                    Err(_) => break,
+                }
+            }
+            rest = after;
+        }
+        let index = self.instructions.len();
+        let offset = match numbers[..] {
+            [] => None,
+            [offset] => Some(offset),
+            [line_number, offset] => {
+                self.current_line = Some(line_number);
+                Some(offset)
+            }
+            _ => return Err(AsmError::new(line, "expected an instruction")),
+        };
+        if offset.is_some_and(|offset| offset != 2 * index) {
+            return Err(AsmError::new(
+                line,
+                format!("the instruction is at offset {}", 2 * index),
+            ));
+        }
+        let (opname, rest) = split_token(rest);
+        let (raw, argrepr) = match rest.strip_prefix('(') {
+            Some(_) => (None, Some(rest)),
+            None => {
+                let (raw, after) = split_token(rest);
+                let raw = Some(raw).filter(|raw| !raw.is_empty());
+                (raw, Some(after).filter(|after| !after.is_empty()))
+            }
+        };
+        let argrepr = match argrepr {
+            Some(argrepr) => Some(
+                argrepr
+                    .strip_prefix('(')
+                    .and_then(|argrepr| argrepr.strip_suffix(')'))
+                    .ok_or_else(|| AsmError::new(line, format!("unexpected {}", argrepr)))?,
+            ),
+            None => None,
+        };
+        let instruction = self.resolve(line, opname, raw, argrepr)?;
+        self.instructions.push(instruction);
+        self.lines.push(self.current_line);
+        self.sources.push(line);
+        Ok(())
+    }
+
+    /// Adds the exception handler written `text`, as the disassembler
+    /// prints it.
+    fn handler(&mut self, line: usize, text: &str) -> AsmResult<()> {
+        let error = || AsmError::new(line, format!("{} is not a handler", text));
+        let index = |offset: &str| -> AsmResult<u32> {
+            let offset = parse_number(offset).ok_or_else(error)?;
+            if offset % 2 != 0 {
+                return Err(AsmError::new(line, format!("offset {} is odd", offset)));
+            }
+            Ok(offset / 2)
+        };
+        let (range, rest) = text.split_once("->").ok_or_else(error)?;
+        let (start, last) = range.split_once(" to ").ok_or_else(error)?;
+        let (target, rest) = split_token(rest);
+        let (depth, rest) = split_token(rest);
+        let depth = depth
+            .strip_prefix('[')
+            .and_then(|depth| depth.strip_suffix(']'))
+            .and_then(parse_number)
+            .ok_or_else(error)?;
+        let lasti = match rest {
+            "" => false,
+            "lasti" => true,
+            _ => return Err(error()),
+        };
+        self.code.exception_table.push(ExceptionEntry {
+            start: index(start.trim())?,
+            end: index(last.trim())? + 1,
+            target: index(target)?,
+            depth,
+            lasti,
+        });
+        Ok(())
+    }
+
+    /// The instruction `opname` with its argument, given as `raw` or, for
+    /// a constant, as `argrepr`.
+    fn resolve(
+        &mut self,
+        line: usize,
+        opname: &str,
+        raw: Option<&str>,
+        argrepr: Option<&str>,
+    ) -> AsmResult<Instruction> {
+        let index = self.instructions.len() as u32;
+        let needs_arg = || AsmError::new(line, format!("{} needs an argument", opname));
+        if opname == "COMPARE_JUMP_IF_FALSE" || opname == "COMPARE_JUMP_IF_TRUE" {
+            let target = self.target(line, index, raw.ok_or_else(needs_arg)?, false)?;
+            let symbol = argrepr
+                .and_then(|argrepr| argrepr.split(" to ").next())
+                .ok_or_else(|| AsmError::new(line, format!("{} needs an operator", opname)))?;
+            let op = comparison(line, symbol)?;
+            return Ok(if opname == "COMPARE_JUMP_IF_FALSE" {
+                Instruction::CompareJumpIfFalse(op, target)
+            } else {
+                Instruction::CompareJumpIfTrue(op, target)
+            });
+        }
+        let template = Instruction::from_opname(opname, 0)
+            .ok_or_else(|| AsmError::new(line, format!("unknown instruction {}", opname)))?;
+        if template.arg().is_none() {
+            if let Some(raw) = raw {
+                return Err(AsmError::new(
+                    line,
+                    format!("{} takes no argument, not {}", opname, raw),
+                ));
+            }
+            return Ok(template);
+        }
+        if let (Instruction::LoadConst(_), None) = (template, raw) {
+            let argrepr = argrepr.ok_or_else(needs_arg)?;
+            let value = parse_constant(line, argrepr)?;
+            let position = self
+                .consts
+                .iter()
+                .position(|constant| matches!(constant, Constant::Value(c) if *c == value));
+            let arg = match position {
+                Some(position) => position,
+                None => {
+                    self.consts.push(Constant::Value(value));
+                    self.consts.len() - 1
+                }
+            };
+            return Ok(Instruction::LoadConst(arg as u32));
+        }
+        let raw = raw.ok_or_else(needs_arg)?;
+        let arg = if template.jump_target().is_some() {
+            let relative = matches!(template, Instruction::ForIter(_));
+            self.target(line, index, raw, relative)?
+        } else if let Some(arg) = parse_number(raw) {
+            arg
+        } else {
+            self.symbolic_arg(line, template, raw)?
+        };
+        Instruction::from_opname(opname, arg)
+            .ok_or_else(|| AsmError::new(line, format!("{} has no argument {}", opname, arg)))
+    }
+
+    /// The argument of an instruction like `template` written as the name
+    /// of a variable or a comparison's operator.
+    fn symbolic_arg(&mut self, line: usize, template: Instruction, raw: &str) -> AsmResult<u32> {
+        let code = &mut self.code;
+        Ok(match template {
+            Instruction::LoadName(_)
+            | Instruction::StoreName(_)
+            | Instruction::DeleteName(_)
+            | Instruction::LoadGlobal(_)
+            | Instruction::StoreGlobal(_)
+            | Instruction::DeleteGlobal(_)
+            | Instruction::LoadAttr(_)
+            | Instruction::StoreAttr(_)
+            | Instruction::DeleteAttr(_) => add_symbol(&mut code.names, Symbol::intern(raw)),
+            Instruction::LoadFast(_) | Instruction::StoreFast(_) | Instruction::DeleteFast(_) => {
+                add_symbol(&mut code.varnames, Symbol::intern(raw))
+            }
+            Instruction::LoadDeref(_)
+            | Instruction::StoreDeref(_)
+            | Instruction::DeleteDeref(_)
+            | Instruction::LoadClosure(_) => {
+                let name = Symbol::intern(raw);
+                let position = code
+                    .cellvars
+                    .iter()
+                    .chain(&code.freevars)
+                    .position(|&cell| cell == name);
+                let error =
+                    || AsmError::new(line, format!("{} is not a cell or free variable", raw));
+                position.ok_or_else(error)? as u32
+            }
+            Instruction::CallIntrinsic1(_) | Instruction::CallIntrinsic2(_) => {
+                let intrinsics = template.intrinsics().unwrap();
+                let found = intrinsics.iter().find(|&&(_, name)| name == raw);
+                let error = || AsmError::new(line, format!("{} is not an intrinsic function", raw));
+                found.ok_or_else(error)?.0
+            }
+            Instruction::CompareOp(_) => {
+                let op = comparison(line, raw)?;
+                CMP_OPS.iter().position(|&o| o == op).unwrap() as u32
+            }
+            _ => {
+                return Err(AsmError::new(
+                    line,
+                    format!("{} needs a number, not {}", template.opname(), raw),
+                ))
+            }
+        })
+    }
+
+    /// The target of a jump at `index`, given as a label or a number: an
+    /// instruction index, or, if `relative`, as for `FOR_ITER`, how many
+    /// instructions the jump skips. A label not yet bound is left for
+    /// [`finish`].
+    fn target(&mut self, line: usize, index: u32, raw: &str, relative: bool) -> AsmResult<u32> {
+        if relative {
+            // The disassembler writes a backward `FOR_ITER` as a negative
+            // count of instructions skipped.
+            let (negative, count) = match raw.strip_prefix('-') {
+                Some(count) => (true, count),
+                None => (false, raw),
+            };
+            if let Some(count) = parse_number(count) {
+                let next = index + 1;
+                let target = if negative {
+                    next.checked_sub(count)
+                } else {
+                    next.checked_add(count)
+                };
+                return target
+                    .ok_or_else(|| AsmError::new(line, format!("{} jumps out of the code", raw)));
+            }
+        } else if let Some(arg) = parse_number(raw) {
+            return Ok(arg);
+        }
+        if !is_identifier(raw) {
+            return Err(AsmError::new(line, format!("{} is not a label", raw)));
+        }
+        self.jumps.push((index as usize, raw.to_string()));
+        Ok(0)
+    }
+}
+
+/// The block being written, to which a line belongs.
+fn current(blocks: &mut [Block], line: usize) -> AsmResult<&mut Block> {
+    blocks
+        .last_mut()
+        .ok_or_else(|| AsmError::new(line, "expected .code first"))
+}
+
+/// Finishes the code object of block `id`, named on `line`, and, first,
+/// those among its constants.
+fn finish(blocks: &mut HashMap<String, Block>, id: &str, line: usize) -> AsmResult<CodeObject> {
+    let block = blocks
+        .get_mut(id)
+        .ok_or_else(|| AsmError::new(line, format!("no code {}", id)))?;
+    if block.finished {
+        return Err(AsmError::new(line, format!("code {} contains itself", id)));
+    }
+    block.finished = true;
+    let consts = std::mem::take(&mut block.consts);
+    let mut values = Vec::with_capacity(consts.len());
+    for constant in consts {
+        values.push(match constant {
+            Constant::Value(value) => value,
+            Constant::Code(inner, line) => Value::Code(Rc::new(finish(blocks, &inner, line)?)),
+        });
+    }
+    let block = blocks.get_mut(id).unwrap();
+    let mut code = std::mem::replace(&mut block.code, Block::new(id, 0).code);
+    let mut instructions = std::mem::take(&mut block.instructions);
+    code.consts = values;
+    for (index, label) in &block.jumps {
+        let target = *block.labels.get(label).ok_or_else(|| {
+            AsmError::new(
+                block.sources[*index],
+                format!("label {} is not bound", label),
+            )
+        })?;
+        instructions[*index] = instructions[*index].with_jump_target(target);
+    }
+    code.set_instructions(&instructions);
+    let lines: Vec<usize> = block
+        .lines
+        .iter()
+        .map(|line| line.unwrap_or(code.firstlineno))
+        .collect();
+    code.linetable = LineTable::new(code.firstlineno, &lines);
+    code.stacksize = verify(&code).map_err(|error| {
+        let line = block.sources.get(error.index).copied();
+        AsmError::new(line.unwrap_or(block.line), error.message)
+    })?;
+    Ok(code)
+}
+
+fn add_symbol(symbols: &mut Vec<Symbol>, name: Symbol) -> u32 {
+    let index = match symbols.iter().position(|&symbol| symbol == name) {
+        Some(index) => index,
+        None => {
+            symbols.push(name);
+            symbols.len() - 1
+        }
+    };
+    index as u32
+}
+
+/// The comparison operator written `symbol`.
+fn comparison(line: usize, symbol: &str) -> AsmResult<CmpOp> {
+    CMP_OPS
+        .iter()
+        .copied()
+        .find(|op| op.symbol() == symbol)
+        .ok_or_else(|| AsmError::new(line, format!("unknown comparison {}", symbol)))
+}
+
+/// The value of a Python literal: `None`, a bool, a number, a string, or
+/// a tuple of them.
+fn parse_constant(line: usize, text: &str) -> AsmResult<Value> {
+    let error = || AsmError::new(line, format!("{} is not a constant", text));
+    // Folded as the value of an assignment: an expression statement that
+    // folds to a constant is dropped.
+    let module = fold(parse(&format!("_ = {}\n", text)).map_err(|_| error())?, 0);
+    let [stmt] = module.arena[module.body] else {
+        return Err(error());
+    };
+    let StmtKind::Assign { value, .. } = module.arena[stmt].kind else {
+        return Err(error());
+    };
+    match &module.arena[value].kind {
+        ExprKind::Constant { value } => Value::from_constant(value).ok_or_else(error),
+        _ => Err(error()),
+    }
+}
+
+/// A decimal number, or a hexadecimal one after `0x`.
+fn parse_number(text: &str) -> Option<u32> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// The first whitespace-separated token of `text`, and what follows it.
+fn split_token(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    match text.find(char::is_whitespace) {
+        Some(end) => (&text[..end], text[end..].trim_start()),
+        None => (text, ""),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::{assemble, listing, AsmError};
+    use crate::ast::{CmpOp, Operator};
+    use crate::code::{ExceptionEntry, LineTable};
+    use crate::interpreter::compile_source;
+    use crate::intruction::Instruction::*;
+    use crate::value::Value;
+    use crate::verify::verify;
+    use crate::vm::Vm;
+
+    /// What running the code in `text` prints.
+    fn run(text: &str) -> String {
+        let code = assemble(text).unwrap();
+        let mut vm = Vm::new(Vec::new());
+        vm.run(Rc::new(code)).unwrap();
+        String::from_utf8(vm.out().clone()).unwrap()
+    }
+
+    fn error(text: &str) -> String {
+        assemble(text).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for (path, optimize) in [
+            ("tests/dis.py", 0),
+            ("tests/fib.py", 0),
+            ("tests/fib.py", 1),
+            ("tests/try.py", 0),
+        ] {
+            let source = std::fs::read_to_string(path).unwrap();
+            let code = compile_source(&source, path, optimize).unwrap();
+            assert_eq!(assemble(&listing(&code)), Ok(code), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_round_trip_nested_loops() {
+        let sources = [
+            "for x in [[1, 2], [3]]:\n    for y in x: print(y)\n",
+            "a = [[1, 2], [3]]\nprint([y for x in a for y in x])\n",
+        ];
+        for source in sources {
+            let code = compile_source(source, "loops.py", 0).unwrap();
+            assert_eq!(assemble(&listing(&code)), Ok(code), "{}", source);
+        }
+
+        // A `FOR_ITER` the compiler would not write, jumping back to the
+        // end of the code.
+        let mut code = compile_source("x = 1\n", "x.py", 0).unwrap();
+        code.set_instructions(&[
+            LoadConst(0),
+            GetIter,
+            JumpAbsolute(5),
+            LoadConst(1),
+            ReturnValue,
+            ForIter(3),
+            PopTop,
+            JumpAbsolute(5),
+        ]);
+        code.linetable = LineTable::new(1, &[1; 8]);
+        code.stacksize = verify(&code).unwrap();
+        let text = listing(&code);
+        assert!(
+            text.contains(" FOR_ITER                -3 (to 6)\n"),
+            "{}",
+            text
+        );
+        assert_eq!(assemble(&text), Ok(code));
+    }
+
+    #[test]
+    fn test_listing() {
+        let code = compile_source("def f(x):\n    return x\n", "f.py", 0).unwrap();
+        let text = listing(&code);
+        let expected = "\
+.code <module>
+.name <module>
+.qualname <module>
+.filename f.py
+.firstlineno 1
+.argcount 0
+.posonlyargcount 0
+.kwonlyargcount 0
+.flags 0x0
+.const code f
+.const 'f'
+.const None
+.names f
+  1           0 LOAD_CONST               0 (<code object f at 0x";
+        assert!(text.starts_with(expected), "{}", text);
+        assert!(text.contains(
+            "\n.code f\n.name f\n.qualname f\n.filename f.py\n.firstlineno 1\n.argcount 1\n"
+        ));
+        assert!(text.ends_with(".varnames x\n  2           0 LOAD_FAST                0 (x)\n              2 RETURN_VALUE\n"));
+    }
+
+    #[test]
+    fn test_assemble() {
+        // A loop the compiler would not write, counting down on the stack
+        // with a fused comparison and calling a function each time.
+        let text = "\
+.code main
+.filename main.py
+.line 1
+.const None
+.const 3
+.const 0
+.const code twice
+    LOAD_CONST (3)
+loop:
+    DUP_TOP
+    LOAD_CONST (0)
+    COMPARE_JUMP_IF_FALSE done (>)
+    DUP_TOP
+    LOAD_NAME print
+    ROT_TWO
+    LOAD_CONST 3
+    LOAD_CONST ('twice')
+    MAKE_FUNCTION 0
+    ROT_TWO
+    CALL_FUNCTION 1
+    CALL_FUNCTION 1
+    POP_TOP
+    LOAD_CONST (1)
+    BINARY_SUBTRACT
+    JUMP_ABSOLUTE loop
+done:
+    RETURN_VALUE
+
+.code twice
+.argcount 1
+.flags 0x3
+    LOAD_FAST n
+    LOAD_CONST (2)
+    BINARY_MULTIPLY
+    RETURN_VALUE
+";
+        assert_eq!(run(text), "6\n4\n2\n");
+        let code = assemble(text).unwrap();
+        assert_eq!(code.instructions()[3], CompareJumpIfFalse(CmpOp::Gt, 17));
+        assert_eq!(code.instructions()[16], JumpAbsolute(1));
+        assert_eq!(
+            code.consts[..3],
+            [Value::None, Value::Int(3), Value::Int(0)]
+        );
+        assert_eq!(code.consts[4..], [Value::str("twice"), Value::Int(1)]);
+        assert_eq!(code.stacksize, 5);
+        let Value::Code(twice) = &code.consts[3] else {
+            panic!("expected a code object, got {:?}", code.consts[3]);
+        };
+        assert_eq!(
+            twice.instructions(),
+            [
+                LoadFast(0),
+                LoadConst(0),
+                BinaryOp(Operator::Mult),
+                ReturnValue
+            ]
+        );
+        assert_eq!(twice.varnames[0].as_str(), "n");
+    }
+
+    #[test]
+    fn test_assemble_exception_table() {
+        let text = "\
+.code main
+.const None
+    NOP
+    LOAD_NAME undefined
+    RETURN_VALUE
+    PUSH_EXC_INFO
+    POP_TOP
+    POP_EXCEPT
+    LOAD_NAME print
+    LOAD_CONST ('caught')
+    CALL_FUNCTION 1
+    RETURN_VALUE
+ExceptionTable:
+  2 to 2 -> 6 [0]
+";
+        assert_eq!(run(text), "caught\n");
+        let code = assemble(text).unwrap();
+        assert_eq!(
+            code.exception_table,
+            [ExceptionEntry {
+                start: 1,
+                end: 2,
+                target: 3,
+                depth: 0,
+                lasti: false
+            }]
+        );
+        assert!(listing(&code).ends_with("ExceptionTable:\n  2 to 2 -> 6 [0]\n"));
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        let errors = [
+            ("    NOP\n", "line 1: expected .code first"),
+            (".code a\n    FROB\n", "line 2: unknown instruction FROB"),
+            (
+                ".code a\n    JUMP_ABSOLUTE nowhere\n",
+                "line 2: label nowhere is not bound",
+            ),
+            (
+                ".code a\n    LOAD_CONST 0\n    RETURN_VALUE\n",
+                "line 2: LOAD_CONST 0 is out of range: there are 0 constants",
+            ),
+            (
+                ".code a\n    LOAD_FAST x\n    POP_TOP\n",
+                "line 3: execution runs off the end of the code",
+            ),
+            (
+                ".code a\n    POP_TOP\n    RETURN_VALUE\n",
+                "line 2: stack underflow: POP_TOP needs 1 but the stack holds 0",
+            ),
+            (
+                ".code a\n    RETURN_VALUE 1\n",
+                "line 2: RETURN_VALUE takes no argument, not 1",
+            ),
+            (
+                ".code a\n    LOAD_DEREF x\n",
+                "line 2: x is not a cell or free variable",
+            ),
+            (
+                ".code a\n    CALL_INTRINSIC_1 INTRINSIC_FROB\n",
+                "line 2: INTRINSIC_FROB is not an intrinsic function",
+            ),
+            (
+                ".code a\n  1      4 RETURN_VALUE\n",
+                "line 2: the instruction is at offset 0",
+            ),
+            (
+                ".code a\n.const code a\n    RETURN_VALUE\n",
+                "line 2: code a contains itself",
+            ),
+            (
+                ".code a\n.const code b\n    RETURN_VALUE\n",
+                "line 2: no code b",
+            ),
+            (".code a\n.const [1]\n", "line 2: [1] is not a constant"),
+            (
+                ".code a\n    FOR_ITER -2\n",
+                "line 2: -2 jumps out of the code",
+            ),
+            (
+                ".code a\n    RETURN_VALUE\nExceptionTable:\n  0 to 0 -> 3 [0]\n",
+                "line 4: offset 3 is odd",
+            ),
+            (
+                ".code a\n    RETURN_VALUE\nExceptionTable:\n  0 -> 2\n",
+                "line 4: 0 -> 2 is not a handler",
+            ),
+        ];
+        for (text, message) in errors {
+            assert_eq!(error(text), message, "{}", text);
+        }
+        assert_eq!(
+            assemble(".code a\n"),
+            Err(AsmError::new(1, "the code has no instructions"))
+        );
+    }
+}
@@ -0,0 +1,1368 @@
+use std::fmt::{self, Write};
+use std::marker::PhantomData;
+use std::ops::{Index, IndexMut};
+
+use crate::intern::Symbol;
+
+/// Location of a node in the source: the line it starts on and its byte range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub line: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(line: usize, start: usize, end: usize) -> Span {
+        Span { line, start, end }
+    }
+
+    /// A span starting where `self` starts and ending where `other` ends.
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            line: self.line,
+            start: self.start,
+            end: other.end,
+        }
+    }
+}
+
+/// Index of a statement in its module's [`Arena`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StmtId(u32);
+
+/// Index of an expression in its module's [`Arena`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ExprId(u32);
+
+/// A node's list of children, or of other items such as keywords: a range
+/// of the arena's vector of `T`s, which every list of `T`s shares. Reached
+/// by indexing the arena, `arena[list]`, as a slice.
+pub struct List<T> {
+    start: u32,
+    len: u32,
+    item: PhantomData<T>,
+}
+
+impl<T> List<T> {
+    pub const fn new() -> List<T> {
+        List {
+            start: 0,
+            len: 0,
+            item: PhantomData,
+        }
+    }
+
+    pub fn len(self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.len == 0
+    }
+}
+
+// Implemented by hand, as deriving them would require the same of `T`.
+
+impl<T> Clone for List<T> {
+    fn clone(&self) -> List<T> {
+        *self
+    }
+}
+
+impl<T> Copy for List<T> {}
+
+impl<T> Default for List<T> {
+    fn default() -> List<T> {
+        List::new()
+    }
+}
+
+impl<T> PartialEq for List<T> {
+    fn eq(&self, other: &List<T>) -> bool {
+        (self.start, self.len) == (other.start, other.len)
+    }
+}
+
+impl<T> fmt::Debug for List<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "List({}..{})", self.start, self.start + self.len)
+    }
+}
+
+/// Owns the statements and expressions of a module, and the lists of their
+/// children. Nodes refer to their children by ID, and to lists of them by
+/// [`List`], so building a tree costs a push per node and per list item
+/// rather than an allocation, and a node's children are reached by
+/// indexing the arena: `arena[stmt_id]`, `arena[expr_id]`, `arena[list]`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Arena {
+    stmts: Vec<Stmt>,
+    exprs: Vec<Expr>,
+    stmt_lists: Vec<StmtId>,
+    expr_lists: Vec<ExprId>,
+    opt_expr_lists: Vec<Option<ExprId>>,
+    symbol_lists: Vec<Symbol>,
+    cmp_op_lists: Vec<CmpOp>,
+    keyword_lists: Vec<Keyword>,
+    comprehension_lists: Vec<Comprehension>,
+    handler_lists: Vec<ExceptHandler>,
+    type_param_lists: Vec<TypeParam>,
+    arg_lists: Vec<Arg>,
+    alias_lists: Vec<Alias>,
+    with_item_lists: Vec<WithItem>,
+}
+
+impl Arena {
+    pub fn new() -> Arena {
+        Arena::default()
+    }
+
+    pub fn add_stmt(&mut self, kind: StmtKind, span: Span) -> StmtId {
+        self.stmts.push(Stmt { kind, span });
+        StmtId(self.stmts.len() as u32 - 1)
+    }
+
+    pub fn add_expr(&mut self, kind: ExprKind, span: Span) -> ExprId {
+        self.exprs.push(Expr { kind, span });
+        ExprId(self.exprs.len() as u32 - 1)
+    }
+
+    /// Appends `items` to the arena's vector of `T`s as a new list. A list
+    /// is never resized: a transformation that changes one adds another.
+    pub fn add_list<T: ListItem>(&mut self, items: impl IntoIterator<Item = T>) -> List<T> {
+        let items_of = T::items_mut(self);
+        let start = items_of.len();
+        items_of.extend(items);
+        List {
+            start: start as u32,
+            len: (items_of.len() - start) as u32,
+            item: PhantomData,
+        }
+    }
+
+    /// How many nodes have been allocated, including any a transformation
+    /// has since cut out of the tree.
+    pub fn node_count(&self) -> usize {
+        self.stmts.len() + self.exprs.len()
+    }
+}
+
+impl Index<StmtId> for Arena {
+    type Output = Stmt;
+
+    fn index(&self, id: StmtId) -> &Stmt {
+        &self.stmts[id.0 as usize]
+    }
+}
+
+impl IndexMut<StmtId> for Arena {
+    fn index_mut(&mut self, id: StmtId) -> &mut Stmt {
+        &mut self.stmts[id.0 as usize]
+    }
+}
+
+impl<T: ListItem> Index<List<T>> for Arena {
+    type Output = [T];
+
+    fn index(&self, list: List<T>) -> &[T] {
+        let start = list.start as usize;
+        &T::items(self)[start..start + list.len()]
+    }
+}
+
+impl<T: ListItem> IndexMut<List<T>> for Arena {
+    fn index_mut(&mut self, list: List<T>) -> &mut [T] {
+        let start = list.start as usize;
+        &mut T::items_mut(self)[start..start + list.len()]
+    }
+}
+
+/// What a [`List`] can hold: each such type has a vector of its own in
+/// the arena.
+pub trait ListItem: Copy {
+    fn items(arena: &Arena) -> &Vec<Self>;
+
+    fn items_mut(arena: &mut Arena) -> &mut Vec<Self>;
+}
+
+macro_rules! list_items {
+    ($($item:ty => $field:ident,)*) => {
+        $(
+            impl ListItem for $item {
+                fn items(arena: &Arena) -> &Vec<$item> {
+                    &arena.$field
+                }
+
+                fn items_mut(arena: &mut Arena) -> &mut Vec<$item> {
+                    &mut arena.$field
+                }
+            }
+        )*
+    };
+}
+
+list_items! {
+    StmtId => stmt_lists,
+    ExprId => expr_lists,
+    Option<ExprId> => opt_expr_lists,
+    Symbol => symbol_lists,
+    CmpOp => cmp_op_lists,
+    Keyword => keyword_lists,
+    Comprehension => comprehension_lists,
+    ExceptHandler => handler_lists,
+    TypeParam => type_param_lists,
+    Arg => arg_lists,
+    Alias => alias_lists,
+    WithItem => with_item_lists,
+}
+
+impl Index<ExprId> for Arena {
+    type Output = Expr;
+
+    fn index(&self, id: ExprId) -> &Expr {
+        &self.exprs[id.0 as usize]
+    }
+}
+
+impl IndexMut<ExprId> for Arena {
+    fn index_mut(&mut self, id: ExprId) -> &mut Expr {
+        &mut self.exprs[id.0 as usize]
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Module {
+    pub body: List<StmtId>,
+    pub arena: Arena,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StmtKind {
+    FunctionDef {
+        name: Symbol,
+        args: Box<Arguments>,
+        body: List<StmtId>,
+        decorator_list: List<ExprId>,
+        returns: Option<ExprId>,
+        type_params: List<TypeParam>,
+    },
+    AsyncFunctionDef {
+        name: Symbol,
+        args: Box<Arguments>,
+        body: List<StmtId>,
+        decorator_list: List<ExprId>,
+        returns: Option<ExprId>,
+        type_params: List<TypeParam>,
+    },
+    ClassDef {
+        name: Symbol,
+        bases: List<ExprId>,
+        keywords: List<Keyword>,
+        body: List<StmtId>,
+        decorator_list: List<ExprId>,
+        type_params: List<TypeParam>,
+    },
+    Return {
+        value: Option<ExprId>,
+    },
+    Delete {
+        targets: List<ExprId>,
+    },
+    Assign {
+        targets: List<ExprId>,
+        value: ExprId,
+    },
+    /// `type Alias[T] = value`. The value is evaluated lazily, in a scope
+    /// of its own, when the alias is first used.
+    TypeAlias {
+        name: ExprId,
+        type_params: List<TypeParam>,
+        value: ExprId,
+    },
+    AugAssign {
+        target: ExprId,
+        op: Operator,
+        value: ExprId,
+    },
+    AnnAssign {
+        target: ExprId,
+        annotation: ExprId,
+        value: Option<ExprId>,
+        simple: bool,
+    },
+    For {
+        target: ExprId,
+        iter: ExprId,
+        body: List<StmtId>,
+        orelse: List<StmtId>,
+    },
+    AsyncFor {
+        target: ExprId,
+        iter: ExprId,
+        body: List<StmtId>,
+        orelse: List<StmtId>,
+    },
+    While {
+        test: ExprId,
+        body: List<StmtId>,
+        orelse: List<StmtId>,
+    },
+    If {
+        test: ExprId,
+        body: List<StmtId>,
+        orelse: List<StmtId>,
+    },
+    With {
+        items: List<WithItem>,
+        body: List<StmtId>,
+    },
+    AsyncWith {
+        items: List<WithItem>,
+        body: List<StmtId>,
+    },
+    Raise {
+        exc: Option<ExprId>,
+        cause: Option<ExprId>,
+    },
+    Try {
+        body: List<StmtId>,
+        handlers: List<ExceptHandler>,
+        orelse: List<StmtId>,
+        finalbody: List<StmtId>,
+    },
+    Assert {
+        test: ExprId,
+        msg: Option<ExprId>,
+    },
+    Import {
+        names: List<Alias>,
+    },
+    ImportFrom {
+        module: Option<Symbol>,
+        names: List<Alias>,
+        level: usize,
+    },
+    Global {
+        names: List<Symbol>,
+    },
+    Nonlocal {
+        names: List<Symbol>,
+    },
+    Expr {
+        value: ExprId,
+    },
+    Pass,
+    Break,
+    Continue,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    BoolOp {
+        op: BoolOperator,
+        values: List<ExprId>,
+    },
+    NamedExpr {
+        target: ExprId,
+        value: ExprId,
+    },
+    BinOp {
+        left: ExprId,
+        op: Operator,
+        right: ExprId,
+    },
+    UnaryOp {
+        op: UnaryOperator,
+        operand: ExprId,
+    },
+    Lambda {
+        args: Box<Arguments>,
+        body: ExprId,
+    },
+    IfExp {
+        test: ExprId,
+        body: ExprId,
+        orelse: ExprId,
+    },
+    Dict {
+        keys: List<Option<ExprId>>,
+        values: List<ExprId>,
+    },
+    Set {
+        elts: List<ExprId>,
+    },
+    ListComp {
+        elt: ExprId,
+        generators: List<Comprehension>,
+    },
+    SetComp {
+        elt: ExprId,
+        generators: List<Comprehension>,
+    },
+    DictComp {
+        key: ExprId,
+        value: ExprId,
+        generators: List<Comprehension>,
+    },
+    GeneratorExp {
+        elt: ExprId,
+        generators: List<Comprehension>,
+    },
+    Await {
+        value: ExprId,
+    },
+    Yield {
+        value: Option<ExprId>,
+    },
+    YieldFrom {
+        value: ExprId,
+    },
+    Compare {
+        left: ExprId,
+        ops: List<CmpOp>,
+        comparators: List<ExprId>,
+    },
+    Call {
+        func: ExprId,
+        args: List<ExprId>,
+        keywords: List<Keyword>,
+    },
+    Constant {
+        value: Constant,
+    },
+    Attribute {
+        value: ExprId,
+        attr: Symbol,
+        ctx: ExprContext,
+    },
+    Subscript {
+        value: ExprId,
+        slice: ExprId,
+        ctx: ExprContext,
+    },
+    Starred {
+        value: ExprId,
+        ctx: ExprContext,
+    },
+    Name {
+        id: Symbol,
+        ctx: ExprContext,
+    },
+    List {
+        elts: List<ExprId>,
+        ctx: ExprContext,
+    },
+    Tuple {
+        elts: List<ExprId>,
+        ctx: ExprContext,
+    },
+    Slice {
+        lower: Option<ExprId>,
+        upper: Option<ExprId>,
+        step: Option<ExprId>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constant {
+    None,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Bytes(Vec<u8>),
+    Ellipsis,
+    /// A tuple of constants, which the parser never makes: constant
+    /// folding turns a tuple display of constants into one.
+    Tuple(Vec<Constant>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExprContext {
+    Load,
+    Store,
+    Del,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BoolOperator {
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operator {
+    Add,
+    Sub,
+    Mult,
+    MatMult,
+    Div,
+    Mod,
+    Pow,
+    LShift,
+    RShift,
+    BitOr,
+    BitXor,
+    BitAnd,
+    FloorDiv,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOperator {
+    Invert,
+    Not,
+    UAdd,
+    USub,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CmpOp {
+    Eq,
+    NotEq,
+    Lt,
+    LtE,
+    Gt,
+    GtE,
+    Is,
+    IsNot,
+    In,
+    NotIn,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Comprehension {
+    pub target: ExprId,
+    pub iter: ExprId,
+    pub ifs: List<ExprId>,
+    pub is_async: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExceptHandler {
+    pub type_: Option<ExprId>,
+    pub name: Option<Symbol>,
+    pub body: List<StmtId>,
+    pub span: Span,
+}
+
+/// A type parameter of a generic function, class or type alias.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TypeParam {
+    pub kind: TypeParamKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TypeParamKind {
+    /// `T` or `T: bound`; constraints are a tuple bound, `T: (int, str)`.
+    TypeVar { name: Symbol, bound: Option<ExprId> },
+    /// `**P`
+    ParamSpec { name: Symbol },
+    /// `*Ts`
+    TypeVarTuple { name: Symbol },
+}
+
+impl TypeParam {
+    pub fn name(&self) -> Symbol {
+        match self.kind {
+            TypeParamKind::TypeVar { name, .. }
+            | TypeParamKind::ParamSpec { name }
+            | TypeParamKind::TypeVarTuple { name } => name,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Arguments {
+    pub posonlyargs: List<Arg>,
+    pub args: List<Arg>,
+    pub vararg: Option<Arg>,
+    pub kwonlyargs: List<Arg>,
+    pub kw_defaults: List<Option<ExprId>>,
+    pub kwarg: Option<Arg>,
+    pub defaults: List<ExprId>,
+}
+
+impl Arguments {
+    /// Every parameter, in the order CPython numbers them: positional,
+    /// keyword-only, then `*args` and `**kwargs`.
+    pub fn params<'a>(&'a self, arena: &'a Arena) -> impl Iterator<Item = &'a Arg> {
+        arena[self.posonlyargs]
+            .iter()
+            .chain(&arena[self.args])
+            .chain(&arena[self.kwonlyargs])
+            .chain(&self.vararg)
+            .chain(&self.kwarg)
+    }
+
+    /// The default values, positional then keyword-only.
+    pub fn default_values<'a>(&self, arena: &'a Arena) -> impl Iterator<Item = ExprId> + 'a {
+        arena[self.defaults]
+            .iter()
+            .chain(arena[self.kw_defaults].iter().flatten())
+            .copied()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Arg {
+    pub arg: Symbol,
+    pub annotation: Option<ExprId>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Keyword {
+    pub arg: Option<Symbol>,
+    pub value: ExprId,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Alias {
+    pub name: Symbol,
+    pub asname: Option<Symbol>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WithItem {
+    pub context_expr: ExprId,
+    pub optional_vars: Option<ExprId>,
+}
+
+impl Operator {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Operator::Add => "Add",
+            Operator::Sub => "Sub",
+            Operator::Mult => "Mult",
+            Operator::MatMult => "MatMult",
+            Operator::Div => "Div",
+            Operator::Mod => "Mod",
+            Operator::Pow => "Pow",
+            Operator::LShift => "LShift",
+            Operator::RShift => "RShift",
+            Operator::BitOr => "BitOr",
+            Operator::BitXor => "BitXor",
+            Operator::BitAnd => "BitAnd",
+            Operator::FloorDiv => "FloorDiv",
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Operator::Add => "+",
+            Operator::Sub => "-",
+            Operator::Mult => "*",
+            Operator::MatMult => "@",
+            Operator::Div => "/",
+            Operator::Mod => "%",
+            Operator::Pow => "**",
+            Operator::LShift => "<<",
+            Operator::RShift => ">>",
+            Operator::BitOr => "|",
+            Operator::BitXor => "^",
+            Operator::BitAnd => "&",
+            Operator::FloorDiv => "//",
+        }
+    }
+}
+
+impl UnaryOperator {
+    pub fn name(&self) -> &'static str {
+        match self {
+            UnaryOperator::Invert => "Invert",
+            UnaryOperator::Not => "Not",
+            UnaryOperator::UAdd => "UAdd",
+            UnaryOperator::USub => "USub",
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            UnaryOperator::Invert => "~",
+            UnaryOperator::Not => "not ",
+            UnaryOperator::UAdd => "+",
+            UnaryOperator::USub => "-",
+        }
+    }
+}
+
+impl CmpOp {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CmpOp::Eq => "Eq",
+            CmpOp::NotEq => "NotEq",
+            CmpOp::Lt => "Lt",
+            CmpOp::LtE => "LtE",
+            CmpOp::Gt => "Gt",
+            CmpOp::GtE => "GtE",
+            CmpOp::Is => "Is",
+            CmpOp::IsNot => "IsNot",
+            CmpOp::In => "In",
+            CmpOp::NotIn => "NotIn",
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            CmpOp::Eq => "==",
+            CmpOp::NotEq => "!=",
+            CmpOp::Lt => "<",
+            CmpOp::LtE => "<=",
+            CmpOp::Gt => ">",
+            CmpOp::GtE => ">=",
+            CmpOp::Is => "is",
+            CmpOp::IsNot => "is not",
+            CmpOp::In => "in",
+            CmpOp::NotIn => "not in",
+        }
+    }
+}
+
+/// Python's `repr()` of a string: single quotes unless the text contains a
+/// single quote and no double quote.
+pub fn repr_str(value: &str) -> String {
+    let quote = if value.contains('\'') && !value.contains('"') {
+        '"'
+    } else {
+        '\''
+    };
+    let mut s = String::with_capacity(value.len() + 2);
+    s.push(quote);
+    for ch in value.chars() {
+        match ch {
+            '\\' => s.push_str("\\\\"),
+            '\n' => s.push_str("\\n"),
+            '\r' => s.push_str("\\r"),
+            '\t' => s.push_str("\\t"),
+            c if c == quote => {
+                s.push('\\');
+                s.push(c);
+            }
+            c if (c as u32) < 0x20 || c as u32 == 0x7f => {
+                let _ = write!(s, "\\x{:02x}", c as u32);
+            }
+            c => s.push(c),
+        }
+    }
+    s.push(quote);
+    s
+}
+
+/// Python's `repr()` of a bytes object.
+pub fn repr_bytes(value: &[u8]) -> String {
+    let quote = if value.contains(&b'\'') && !value.contains(&b'"') {
+        '"'
+    } else {
+        '\''
+    };
+    let mut s = String::with_capacity(value.len() + 3);
+    s.push('b');
+    s.push(quote);
+    for &byte in value {
+        match byte {
+            b'\\' => s.push_str("\\\\"),
+            b'\n' => s.push_str("\\n"),
+            b'\r' => s.push_str("\\r"),
+            b'\t' => s.push_str("\\t"),
+            b if b as char == quote => {
+                s.push('\\');
+                s.push(b as char);
+            }
+            0x20..=0x7e => s.push(byte as char),
+            b => {
+                let _ = write!(s, "\\x{:02x}", b);
+            }
+        }
+    }
+    s.push(quote);
+    s
+}
+
+/// Python's `repr()` of a float: the shortest round-tripping digits, switching
+/// to exponent notation outside `1e-4 <= |x| < 1e16`.
+pub fn repr_float(value: f64) -> String {
+    if value.is_nan() {
+        return "nan".to_string();
+    }
+    if value.is_infinite() {
+        return if value > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+    if value == 0.0 {
+        return if value.is_sign_negative() {
+            "-0.0"
+        } else {
+            "0.0"
+        }
+        .to_string();
+    }
+    let exponent = value.abs().log10().floor() as i32;
+    if !(-4..16).contains(&exponent) {
+        let s = format!("{:e}", value);
+        let (mantissa, exp) = s.split_once('e').unwrap();
+        let exp: i32 = exp.parse().unwrap();
+        let sign = if exp < 0 { '-' } else { '+' };
+        return format!("{}e{}{:02}", mantissa, sign, exp.abs());
+    }
+    let s = format!("{:?}", value);
+    if s.contains('.') || s.contains('e') {
+        s
+    } else {
+        format!("{}.0", s)
+    }
+}
+
+impl Constant {
+    pub fn repr(&self) -> String {
+        match self {
+            Constant::None => "None".to_string(),
+            Constant::Bool(true) => "True".to_string(),
+            Constant::Bool(false) => "False".to_string(),
+            Constant::Int(value) => value.to_string(),
+            Constant::Float(value) => repr_float(*value),
+            Constant::Str(value) => repr_str(value),
+            Constant::Bytes(value) => repr_bytes(value),
+            Constant::Ellipsis => "Ellipsis".to_string(),
+            Constant::Tuple(items) if items.len() == 1 => format!("({},)", items[0].repr()),
+            Constant::Tuple(items) => {
+                let items: Vec<String> = items.iter().map(Constant::repr).collect();
+                format!("({})", items.join(", "))
+            }
+        }
+    }
+}
+
+/// Formats a module the way CPython's `ast.dump(tree)` does, without
+/// location attributes, so trees can be compared against `python -m ast`.
+pub fn dump(module: &Module) -> String {
+    let mut s = String::from("Module(body=");
+    dump_stmts(&mut s, &module.arena, module.body);
+    s.push_str(", type_ignores=[])");
+    s
+}
+
+pub fn dump_expr(arena: &Arena, expr: ExprId) -> String {
+    let mut s = String::new();
+    dump_expr_into(&mut s, arena, expr);
+    s
+}
+
+/// Writes `Name(field=value, ...)`, skipping fields whose value is `None`.
+struct NodeWriter<'a> {
+    out: &'a mut String,
+    arena: &'a Arena,
+    first: bool,
+}
+
+impl<'a> NodeWriter<'a> {
+    fn new(out: &'a mut String, arena: &'a Arena, name: &str) -> NodeWriter<'a> {
+        out.push_str(name);
+        out.push('(');
+        NodeWriter {
+            out,
+            arena,
+            first: true,
+        }
+    }
+
+    fn field(&mut self, name: &str) -> &mut String {
+        if !self.first {
+            self.out.push_str(", ");
+        }
+        self.first = false;
+        self.out.push_str(name);
+        self.out.push('=');
+        self.out
+    }
+
+    fn expr(&mut self, name: &str, expr: ExprId) -> &mut Self {
+        let arena = self.arena;
+        dump_expr_into(self.field(name), arena, expr);
+        self
+    }
+
+    fn opt_expr(&mut self, name: &str, expr: Option<ExprId>) -> &mut Self {
+        if let Some(expr) = expr {
+            self.expr(name, expr);
+        }
+        self
+    }
+
+    fn exprs(&mut self, name: &str, exprs: List<ExprId>) -> &mut Self {
+        let arena = self.arena;
+        let out = self.field(name);
+        out.push('[');
+        for (i, &expr) in arena[exprs].iter().enumerate() {
+            if i > 0 {
+                out.push_str(", ");
+            }
+            dump_expr_into(out, arena, expr);
+        }
+        out.push(']');
+        self
+    }
+
+    fn stmts(&mut self, name: &str, stmts: List<StmtId>) -> &mut Self {
+        let arena = self.arena;
+        dump_stmts(self.field(name), arena, stmts);
+        self
+    }
+
+    fn raw(&mut self, name: &str, value: &str) -> &mut Self {
+        self.field(name).push_str(value);
+        self
+    }
+
+    fn str(&mut self, name: &str, value: &str) -> &mut Self {
+        self.raw(name, &repr_str(value))
+    }
+
+    fn symbol(&mut self, name: &str, value: Symbol) -> &mut Self {
+        self.str(name, value.as_str())
+    }
+
+    fn opt_symbol(&mut self, name: &str, value: Option<Symbol>) -> &mut Self {
+        if let Some(value) = value {
+            self.symbol(name, value);
+        }
+        self
+    }
+
+    fn list<T: ListItem>(
+        &mut self,
+        name: &str,
+        items: List<T>,
+        f: impl Fn(&mut String, &Arena, &T),
+    ) -> &mut Self {
+        let arena = self.arena;
+        let out = self.field(name);
+        out.push('[');
+        for (i, item) in arena[items].iter().enumerate() {
+            if i > 0 {
+                out.push_str(", ");
+            }
+            f(out, arena, item);
+        }
+        out.push(']');
+        self
+    }
+
+    fn node<T>(&mut self, name: &str, item: &T, f: impl Fn(&mut String, &Arena, &T)) -> &mut Self {
+        let arena = self.arena;
+        f(self.field(name), arena, item);
+        self
+    }
+
+    /// The `type_params` CPython 3.12 added to definitions, left out when
+    /// empty so that trees without them dump as they do in 3.11.
+    fn type_params(&mut self, type_params: List<TypeParam>) -> &mut Self {
+        if !type_params.is_empty() {
+            self.list("type_params", type_params, dump_type_param);
+        }
+        self
+    }
+
+    fn ctx(&mut self, ctx: ExprContext) -> &mut Self {
+        let value = match ctx {
+            ExprContext::Load => "Load()",
+            ExprContext::Store => "Store()",
+            ExprContext::Del => "Del()",
+        };
+        self.raw("ctx", value)
+    }
+
+    fn finish(&mut self) {
+        self.out.push(')');
+    }
+}
+
+fn dump_stmts(s: &mut String, arena: &Arena, stmts: List<StmtId>) {
+    s.push('[');
+    for (i, &stmt) in arena[stmts].iter().enumerate() {
+        if i > 0 {
+            s.push_str(", ");
+        }
+        dump_stmt(s, arena, stmt);
+    }
+    s.push(']');
+}
+
+fn dump_stmt(s: &mut String, arena: &Arena, stmt: StmtId) {
+    let kind = &arena[stmt].kind;
+    let prefix = match kind {
+        StmtKind::AsyncFunctionDef { .. } | StmtKind::AsyncFor { .. } | StmtKind::AsyncWith { .. } => {
+            "Async"
+        }
+        _ => "",
+    };
+    match kind {
+        StmtKind::FunctionDef {
+            name,
+            args,
+            body,
+            decorator_list,
+            returns,
+            type_params,
+        }
+        | StmtKind::AsyncFunctionDef {
+            name,
+            args,
+            body,
+            decorator_list,
+            returns,
+            type_params,
+        } => NodeWriter::new(s, arena, &format!("{}FunctionDef", prefix))
+            .symbol("name", *name)
+            .node("args", args.as_ref(), dump_arguments)
+            .stmts("body", *body)
+            .exprs("decorator_list", *decorator_list)
+            .opt_expr("returns", *returns)
+            .type_params(*type_params)
+            .finish(),
+        StmtKind::ClassDef {
+            name,
+            bases,
+            keywords,
+            body,
+            decorator_list,
+            type_params,
+        } => NodeWriter::new(s, arena, "ClassDef")
+            .symbol("name", *name)
+            .exprs("bases", *bases)
+            .list("keywords", *keywords, dump_keyword)
+            .stmts("body", *body)
+            .exprs("decorator_list", *decorator_list)
+            .type_params(*type_params)
+            .finish(),
+        StmtKind::Return { value } => NodeWriter::new(s, arena, "Return")
+            .opt_expr("value", *value)
+            .finish(),
+        StmtKind::Delete { targets } => NodeWriter::new(s, arena, "Delete")
+            .exprs("targets", *targets)
+            .finish(),
+        StmtKind::Assign { targets, value } => NodeWriter::new(s, arena, "Assign")
+            .exprs("targets", *targets)
+            .expr("value", *value)
+            .finish(),
+        StmtKind::TypeAlias {
+            name,
+            type_params,
+            value,
+        } => NodeWriter::new(s, arena, "TypeAlias")
+            .expr("name", *name)
+            .list("type_params", *type_params, dump_type_param)
+            .expr("value", *value)
+            .finish(),
+        StmtKind::AugAssign { target, op, value } => NodeWriter::new(s, arena, "AugAssign")
+            .expr("target", *target)
+            .raw("op", &format!("{}()", op.name()))
+            .expr("value", *value)
+            .finish(),
+        StmtKind::AnnAssign {
+            target,
+            annotation,
+            value,
+            simple,
+        } => NodeWriter::new(s, arena, "AnnAssign")
+            .expr("target", *target)
+            .expr("annotation", *annotation)
+            .opt_expr("value", *value)
+            .raw("simple", if *simple { "1" } else { "0" })
+            .finish(),
+        StmtKind::For {
+            target,
+            iter,
+            body,
+            orelse,
+        }
+        | StmtKind::AsyncFor {
+            target,
+            iter,
+            body,
+            orelse,
+        } => NodeWriter::new(s, arena, &format!("{}For", prefix))
+            .expr("target", *target)
+            .expr("iter", *iter)
+            .stmts("body", *body)
+            .stmts("orelse", *orelse)
+            .finish(),
+        StmtKind::While { test, body, orelse } => NodeWriter::new(s, arena, "While")
+            .expr("test", *test)
+            .stmts("body", *body)
+            .stmts("orelse", *orelse)
+            .finish(),
+        StmtKind::If { test, body, orelse } => NodeWriter::new(s, arena, "If")
+            .expr("test", *test)
+            .stmts("body", *body)
+            .stmts("orelse", *orelse)
+            .finish(),
+        StmtKind::With { items, body } | StmtKind::AsyncWith { items, body } => {
+            NodeWriter::new(s, arena, &format!("{}With", prefix))
+                .list("items", *items, dump_withitem)
+                .stmts("body", *body)
+                .finish()
+        }
+        StmtKind::Raise { exc, cause } => NodeWriter::new(s, arena, "Raise")
+            .opt_expr("exc", *exc)
+            .opt_expr("cause", *cause)
+            .finish(),
+        StmtKind::Try {
+            body,
+            handlers,
+            orelse,
+            finalbody,
+        } => NodeWriter::new(s, arena, "Try")
+            .stmts("body", *body)
+            .list("handlers", *handlers, dump_handler)
+            .stmts("orelse", *orelse)
+            .stmts("finalbody", *finalbody)
+            .finish(),
+        StmtKind::Assert { test, msg } => NodeWriter::new(s, arena, "Assert")
+            .expr("test", *test)
+            .opt_expr("msg", *msg)
+            .finish(),
+        StmtKind::Import { names } => NodeWriter::new(s, arena, "Import")
+            .list("names", *names, dump_alias)
+            .finish(),
+        StmtKind::ImportFrom {
+            module,
+            names,
+            level,
+        } => NodeWriter::new(s, arena, "ImportFrom")
+            .opt_symbol("module", *module)
+            .list("names", *names, dump_alias)
+            .raw("level", &level.to_string())
+            .finish(),
+        StmtKind::Global { names } => NodeWriter::new(s, arena, "Global")
+            .list("names", *names, |out, _, name| out.push_str(&repr_str(name.as_str())))
+            .finish(),
+        StmtKind::Nonlocal { names } => NodeWriter::new(s, arena, "Nonlocal")
+            .list("names", *names, |out, _, name| out.push_str(&repr_str(name.as_str())))
+            .finish(),
+        StmtKind::Expr { value } => NodeWriter::new(s, arena, "Expr").expr("value", *value).finish(),
+        StmtKind::Pass => s.push_str("Pass()"),
+        StmtKind::Break => s.push_str("Break()"),
+        StmtKind::Continue => s.push_str("Continue()"),
+    }
+}
+
+fn dump_expr_into(s: &mut String, arena: &Arena, expr: ExprId) {
+    match &arena[expr].kind {
+        ExprKind::BoolOp { op, values } => {
+            let op = match op {
+                BoolOperator::And => "And()",
+                BoolOperator::Or => "Or()",
+            };
+            NodeWriter::new(s, arena, "BoolOp")
+                .raw("op", op)
+                .exprs("values", *values)
+                .finish()
+        }
+        ExprKind::NamedExpr { target, value } => NodeWriter::new(s, arena, "NamedExpr")
+            .expr("target", *target)
+            .expr("value", *value)
+            .finish(),
+        ExprKind::BinOp { left, op, right } => NodeWriter::new(s, arena, "BinOp")
+            .expr("left", *left)
+            .raw("op", &format!("{}()", op.name()))
+            .expr("right", *right)
+            .finish(),
+        ExprKind::UnaryOp { op, operand } => NodeWriter::new(s, arena, "UnaryOp")
+            .raw("op", &format!("{}()", op.name()))
+            .expr("operand", *operand)
+            .finish(),
+        ExprKind::Lambda { args, body } => NodeWriter::new(s, arena, "Lambda")
+            .node("args", args.as_ref(), dump_arguments)
+            .expr("body", *body)
+            .finish(),
+        ExprKind::IfExp { test, body, orelse } => NodeWriter::new(s, arena, "IfExp")
+            .expr("test", *test)
+            .expr("body", *body)
+            .expr("orelse", *orelse)
+            .finish(),
+        ExprKind::Dict { keys, values } => NodeWriter::new(s, arena, "Dict")
+            .list("keys", *keys, |out, arena, key| match key {
+                Some(key) => dump_expr_into(out, arena, *key),
+                None => out.push_str("None"),
+            })
+            .exprs("values", *values)
+            .finish(),
+        ExprKind::Set { elts } => NodeWriter::new(s, arena, "Set").exprs("elts", *elts).finish(),
+        ExprKind::ListComp { elt, generators } => NodeWriter::new(s, arena, "ListComp")
+            .expr("elt", *elt)
+            .list("generators", *generators, dump_comprehension)
+            .finish(),
+        ExprKind::SetComp { elt, generators } => NodeWriter::new(s, arena, "SetComp")
+            .expr("elt", *elt)
+            .list("generators", *generators, dump_comprehension)
+            .finish(),
+        ExprKind::DictComp {
+            key,
+            value,
+            generators,
+        } => NodeWriter::new(s, arena, "DictComp")
+            .expr("key", *key)
+            .expr("value", *value)
+            .list("generators", *generators, dump_comprehension)
+            .finish(),
+        ExprKind::GeneratorExp { elt, generators } => NodeWriter::new(s, arena, "GeneratorExp")
+            .expr("elt", *elt)
+            .list("generators", *generators, dump_comprehension)
+            .finish(),
+        ExprKind::Await { value } => NodeWriter::new(s, arena, "Await")
+            .expr("value", *value)
+            .finish(),
+        ExprKind::Yield { value } => NodeWriter::new(s, arena, "Yield")
+            .opt_expr("value", *value)
+            .finish(),
+        ExprKind::YieldFrom { value } => NodeWriter::new(s, arena, "YieldFrom")
+            .expr("value", *value)
+            .finish(),
+        ExprKind::Compare {
+            left,
+            ops,
+            comparators,
+        } => NodeWriter::new(s, arena, "Compare")
+            .expr("left", *left)
+            .list("ops", *ops, |out, _, op| {
+                out.push_str(op.name());
+                out.push_str("()");
+            })
+            .exprs("comparators", *comparators)
+            .finish(),
+        ExprKind::Call {
+            func,
+            args,
+            keywords,
+        } => NodeWriter::new(s, arena, "Call")
+            .expr("func", *func)
+            .exprs("args", *args)
+            .list("keywords", *keywords, dump_keyword)
+            .finish(),
+        ExprKind::Constant { value } => NodeWriter::new(s, arena, "Constant")
+            .raw("value", &value.repr())
+            .finish(),
+        ExprKind::Attribute { value, attr, ctx } => NodeWriter::new(s, arena, "Attribute")
+            .expr("value", *value)
+            .symbol("attr", *attr)
+            .ctx(*ctx)
+            .finish(),
+        ExprKind::Subscript { value, slice, ctx } => NodeWriter::new(s, arena, "Subscript")
+            .expr("value", *value)
+            .expr("slice", *slice)
+            .ctx(*ctx)
+            .finish(),
+        ExprKind::Starred { value, ctx } => NodeWriter::new(s, arena, "Starred")
+            .expr("value", *value)
+            .ctx(*ctx)
+            .finish(),
+        ExprKind::Name { id, ctx } => NodeWriter::new(s, arena, "Name").symbol("id", *id).ctx(*ctx).finish(),
+        ExprKind::List { elts, ctx } => NodeWriter::new(s, arena, "List")
+            .exprs("elts", *elts)
+            .ctx(*ctx)
+            .finish(),
+        ExprKind::Tuple { elts, ctx } => NodeWriter::new(s, arena, "Tuple")
+            .exprs("elts", *elts)
+            .ctx(*ctx)
+            .finish(),
+        ExprKind::Slice { lower, upper, step } => NodeWriter::new(s, arena, "Slice")
+            .opt_expr("lower", *lower)
+            .opt_expr("upper", *upper)
+            .opt_expr("step", *step)
+            .finish(),
+    }
+}
+
+fn dump_arguments(s: &mut String, arena: &Arena, args: &Arguments) {
+    let mut w = NodeWriter::new(s, arena, "arguments");
+    w.list("posonlyargs", args.posonlyargs, dump_arg)
+        .list("args", args.args, dump_arg);
+    if let Some(vararg) = &args.vararg {
+        w.node("vararg", vararg, dump_arg);
+    }
+    w.list("kwonlyargs", args.kwonlyargs, dump_arg).list(
+        "kw_defaults",
+        args.kw_defaults,
+        |out, arena, default| match default {
+            Some(default) => dump_expr_into(out, arena, *default),
+            None => out.push_str("None"),
+        },
+    );
+    if let Some(kwarg) = &args.kwarg {
+        w.node("kwarg", kwarg, dump_arg);
+    }
+    w.exprs("defaults", args.defaults).finish();
+}
+
+fn dump_arg(s: &mut String, arena: &Arena, arg: &Arg) {
+    NodeWriter::new(s, arena, "arg")
+        .symbol("arg", arg.arg)
+        .opt_expr("annotation", arg.annotation)
+        .finish()
+}
+
+fn dump_type_param(s: &mut String, arena: &Arena, type_param: &TypeParam) {
+    match &type_param.kind {
+        TypeParamKind::TypeVar { name, bound } => NodeWriter::new(s, arena, "TypeVar")
+            .symbol("name", *name)
+            .opt_expr("bound", *bound)
+            .finish(),
+        TypeParamKind::ParamSpec { name } => NodeWriter::new(s, arena, "ParamSpec")
+            .symbol("name", *name)
+            .finish(),
+        TypeParamKind::TypeVarTuple { name } => NodeWriter::new(s, arena, "TypeVarTuple")
+            .symbol("name", *name)
+            .finish(),
+    }
+}
+
+fn dump_keyword(s: &mut String, arena: &Arena, keyword: &Keyword) {
+    NodeWriter::new(s, arena, "keyword")
+        .opt_symbol("arg", keyword.arg)
+        .expr("value", keyword.value)
+        .finish()
+}
+
+fn dump_alias(s: &mut String, arena: &Arena, alias: &Alias) {
+    NodeWriter::new(s, arena, "alias")
+        .symbol("name", alias.name)
+        .opt_symbol("asname", alias.asname)
+        .finish()
+}
+
+fn dump_withitem(s: &mut String, arena: &Arena, item: &WithItem) {
+    NodeWriter::new(s, arena, "withitem")
+        .expr("context_expr", item.context_expr)
+        .opt_expr("optional_vars", item.optional_vars)
+        .finish()
+}
+
+fn dump_handler(s: &mut String, arena: &Arena, handler: &ExceptHandler) {
+    NodeWriter::new(s, arena, "ExceptHandler")
+        .opt_expr("type", handler.type_)
+        .opt_symbol("name", handler.name)
+        .stmts("body", handler.body)
+        .finish()
+}
+
+fn dump_comprehension(s: &mut String, arena: &Arena, comp: &Comprehension) {
+    NodeWriter::new(s, arena, "comprehension")
+        .expr("target", comp.target)
+        .expr("iter", comp.iter)
+        .exprs("ifs", comp.ifs)
+        .raw("is_async", if comp.is_async { "1" } else { "0" })
+        .finish()
+}
@@ -0,0 +1,722 @@
+//! Bytecode cache files, which save compiling a module again each time it
+//! runs, modelled on CPython's `.pyc` files.
+//!
+//! A module's cache lives in the `__pycache__` directory beside it, named
+//! for the compiler and the optimization level: `fib.py` is cached in
+//! `__pycache__/fib.rustypy-01.pyc`, or `fib.rustypy-01.opt-1.pyc` with
+//! `-O`. The file starts with a 16-byte header, as in PEP 552: the magic
+//! number, flags, and then either the source's modification time and size
+//! or, if the flags say so, a hash of the source. The code object follows.
+//!
+//! A cache is used only if it has this compiler's magic number and still
+//! matches its source; otherwise the module is compiled again and the
+//! cache rewritten. A hash-based cache whose flags say not to check the
+//! source is used whatever the source is now.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::UNIX_EPOCH;
+
+use crate::code::{Adaptive, CodeObject, ExceptionEntry, LineTable};
+use crate::intern::Symbol;
+use crate::interpreter::compile_source;
+use crate::intruction::{pack, Instruction};
+use crate::parser::ParseError;
+use crate::value::Value;
+use crate::verify::verify_all;
+
+/// The version of the cache format and of the instruction set, which has
+/// to change whenever either does, so that older caches are recompiled.
+pub const MAGIC_NUMBER: u16 = 8003;
+/// The magic number as it starts a cache file.
+pub const MAGIC: [u8; 4] = [
+    MAGIC_NUMBER.to_le_bytes()[0],
+    MAGIC_NUMBER.to_le_bytes()[1],
+    b'\r',
+    b'\n',
+];
+/// What names this compiler's caches, as `sys.implementation.cache_tag`
+/// does CPython's.
+pub const CACHE_TAG: &str = "rustypy-01";
+
+/// The flag for a cache stamped with a hash of its source.
+const FLAG_HASH_BASED: u32 = 0x1;
+/// The flag for a hash-based cache whose source is checked against it.
+const FLAG_CHECK_SOURCE: u32 = 0x2;
+
+/// How a cache tells whether it is stale, as in PEP 552.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Invalidation {
+    /// By the modification time and size of the source.
+    Timestamp,
+    /// By a hash of the source, checked each time the cache is loaded.
+    CheckedHash,
+    /// By a hash of the source, which is never checked: the cache is
+    /// used until something else rewrites it.
+    UncheckedHash,
+}
+
+impl Invalidation {
+    /// The mode named as by `compileall --invalidation-mode`.
+    pub fn from_name(name: &str) -> Option<Invalidation> {
+        match name {
+            "timestamp" => Some(Invalidation::Timestamp),
+            "checked-hash" => Some(Invalidation::CheckedHash),
+            "unchecked-hash" => Some(Invalidation::UncheckedHash),
+            _ => None,
+        }
+    }
+
+    fn flags(self) -> u32 {
+        match self {
+            Invalidation::Timestamp => 0,
+            Invalidation::CheckedHash => FLAG_HASH_BASED | FLAG_CHECK_SOURCE,
+            Invalidation::UncheckedHash => FLAG_HASH_BASED,
+        }
+    }
+}
+
+/// Why a cache cannot be used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CacheError {
+    /// Another compiler, or another version of this one, wrote it.
+    BadMagic,
+    /// The source has changed since it was written.
+    Stale,
+    /// It is not a well-formed cache.
+    Corrupt(String),
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CacheError::BadMagic => write!(f, "bad magic number"),
+            CacheError::Stale => write!(f, "the source has changed"),
+            CacheError::Corrupt(message) => write!(f, "corrupt cache: {}", message),
+        }
+    }
+}
+
+/// The source a cache was or is to be made from: its text and, in
+/// seconds, when it was last modified.
+#[derive(Debug, Clone, Copy)]
+pub struct Source<'a> {
+    pub text: &'a [u8],
+    pub mtime: u64,
+}
+
+impl Source<'_> {
+    /// The time and size, each cut down to 32 bits as in CPython's
+    /// header, of a timestamp-based cache.
+    pub(crate) fn stamp(&self) -> [u8; 8] {
+        let mut stamp = [0; 8];
+        stamp[..4].copy_from_slice(&(self.mtime as u32).to_le_bytes());
+        stamp[4..].copy_from_slice(&(self.text.len() as u32).to_le_bytes());
+        stamp
+    }
+}
+
+/// Where the cache of the module at `path` is kept.
+pub fn cache_path(path: &Path, optimize: u8) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = if optimize > 0 {
+        format!("{}.{}.opt-{}.pyc", stem, CACHE_TAG, optimize)
+    } else {
+        format!("{}.{}.pyc", stem, CACHE_TAG)
+    };
+    let dir = path.parent().unwrap_or(Path::new(""));
+    dir.join("__pycache__").join(name)
+}
+
+/// A hash of a module's source, for a hash-based cache: 64-bit FNV-1a,
+/// seeded with the magic number so that it changes with the format.
+pub fn source_hash(text: &[u8]) -> [u8; 8] {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &byte in MAGIC.iter().chain(text) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash.to_le_bytes()
+}
+
+/// The contents of a cache of `code`, compiled from `source`.
+pub fn dumps(code: &CodeObject, source: Source, invalidation: Invalidation) -> Vec<u8> {
+    let mut writer = Writer { bytes: Vec::new() };
+    writer.bytes.extend(MAGIC);
+    writer.u32(invalidation.flags());
+    match invalidation {
+        Invalidation::Timestamp => writer.bytes.extend(source.stamp()),
+        _ => writer.bytes.extend(source_hash(source.text)),
+    }
+    writer.code(code);
+    writer.bytes
+}
+
+/// The code object in a cache, if it is well formed and, unless it says
+/// otherwise, up to date with `source`. The code is verified, so that a
+/// cache corrupted into a well-formed but unsound one is caught too.
+pub fn loads(bytes: &[u8], source: Source) -> Result<CodeObject, CacheError> {
+    let (invalidation, stamp) = header(bytes)?;
+    let up_to_date = match invalidation {
+        Invalidation::Timestamp => stamp == source.stamp(),
+        Invalidation::CheckedHash => stamp == source_hash(source.text),
+        Invalidation::UncheckedHash => true,
+    };
+    if !up_to_date {
+        return Err(CacheError::Stale);
+    }
+    let mut reader = Reader { bytes, at: 16 };
+    let code = reader.code().map_err(CacheError::Corrupt)?;
+    if reader.at != bytes.len() {
+        return Err(CacheError::Corrupt("bytes after the code".to_string()));
+    }
+    verify_all(&code).map_err(|error| CacheError::Corrupt(error.to_string()))?;
+    Ok(code)
+}
+
+/// How a cache is invalidated, and its stamp, from its header.
+fn header(bytes: &[u8]) -> Result<(Invalidation, [u8; 8]), CacheError> {
+    if bytes.len() < 16 {
+        return Err(CacheError::Corrupt("the header is cut short".to_string()));
+    }
+    if bytes[..4] != MAGIC {
+        return Err(CacheError::BadMagic);
+    }
+    let flags = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+    let invalidation = match flags {
+        0 => Invalidation::Timestamp,
+        FLAG_HASH_BASED => Invalidation::UncheckedHash,
+        3 => Invalidation::CheckedHash,
+        _ => return Err(CacheError::Corrupt(format!("unknown flags {:#x}", flags))),
+    };
+    Ok((invalidation, bytes[8..16].try_into().unwrap()))
+}
+
+/// The code of the module at `path`, whose source is `text`: from its
+/// cache if that is up to date, or else compiled and, if the cache can be
+/// written, cached. A hash-based cache is rewritten as one.
+pub fn load(path: &Path, text: &str, optimize: u8) -> Result<CodeObject, Vec<ParseError>> {
+    let filename = path.to_string_lossy();
+    let Some(mtime) = modified(path) else {
+        return compile_source(text, &filename, optimize);
+    };
+    let source = Source {
+        text: text.as_bytes(),
+        mtime,
+    };
+    let cache = cache_path(path, optimize);
+    let existing = fs::read(&cache).ok();
+    if let Some(bytes) = &existing {
+        if let Ok(code) = loads(bytes, source) {
+            return Ok(code);
+        }
+    }
+    let code = compile_source(text, &filename, optimize)?;
+    let invalidation = match existing.as_deref().map(header) {
+        Some(Ok((invalidation, _))) => invalidation,
+        _ => Invalidation::Timestamp,
+    };
+    // Failing to write the cache only means compiling again next time.
+    let _ = write_cache(&cache, &dumps(&code, source, invalidation));
+    Ok(code)
+}
+
+/// When the file at `path` was last modified, in seconds.
+pub fn modified(path: &Path) -> Option<u64> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+/// Writes a cache through a temporary file, so that a reader never sees
+/// one half written.
+fn write_cache(cache: &Path, bytes: &[u8]) -> io::Result<()> {
+    fs::create_dir_all(cache.parent().unwrap())?;
+    let temporary = cache.with_extension(format!("pyc.{}", std::process::id()));
+    fs::write(&temporary, bytes)?;
+    fs::rename(&temporary, cache)
+}
+
+/// Compiles each module in the tree under `dir` whose cache is missing,
+/// stale or made another way, as CPython's `compileall` does, reporting
+/// to `out` each directory listed and each module compiled or failing to.
+/// Returns whether every module compiled.
+pub fn compile_dir<W: Write>(
+    dir: &Path,
+    optimize: u8,
+    invalidation: Invalidation,
+    force: bool,
+    out: &mut W,
+) -> io::Result<bool> {
+    writeln!(out, "Listing '{}'...", dir.display())?;
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<_>>()?;
+    entries.sort();
+    let mut success = true;
+    for path in entries {
+        if path.is_dir() {
+            if path.file_name().is_some_and(|name| name != "__pycache__") {
+                success &= compile_dir(&path, optimize, invalidation, force, out)?;
+            }
+        } else if path.extension().is_some_and(|extension| extension == "py") {
+            success &= compile_file(&path, optimize, invalidation, force, out)?;
+        }
+    }
+    Ok(success)
+}
+
+/// Compiles one module for [`compile_dir`], unless its cache is up to
+/// date and made the same way and the compiling is not forced.
+fn compile_file<W: Write>(
+    path: &Path,
+    optimize: u8,
+    invalidation: Invalidation,
+    force: bool,
+    out: &mut W,
+) -> io::Result<bool> {
+    let text = fs::read(path)?;
+    let source = Source {
+        text: &text,
+        mtime: modified(path).unwrap_or(0),
+    };
+    let cache = cache_path(path, optimize);
+    if !force {
+        if let Ok(bytes) = fs::read(&cache) {
+            let same_way = header(&bytes).is_ok_and(|(existing, _)| existing == invalidation);
+            if same_way && loads(&bytes, source).is_ok() {
+                return Ok(true);
+            }
+        }
+    }
+    writeln!(out, "Compiling '{}'...", path.display())?;
+    let compiled = String::from_utf8(text.clone())
+        .map_err(|_| "the source is not UTF-8".to_string())
+        .and_then(|text| {
+            compile_source(&text, &path.to_string_lossy(), optimize).map_err(|errors| {
+                let errors: Vec<String> = errors
+                    .iter()
+                    .map(|error| format!("SyntaxError: {}", error))
+                    .collect();
+                errors.join("\n")
+            })
+        });
+    match compiled {
+        Ok(code) => {
+            write_cache(&cache, &dumps(&code, source, invalidation))?;
+            Ok(true)
+        }
+        Err(message) => {
+            writeln!(out, "*** Error compiling '{}'...", path.display())?;
+            writeln!(out, "{}", message)?;
+            Ok(false)
+        }
+    }
+}
+
+/// Tags of the values in a cache.
+const TAG_NONE: u8 = b'N';
+const TAG_FALSE: u8 = b'F';
+const TAG_TRUE: u8 = b'T';
+const TAG_INT: u8 = b'i';
+const TAG_FLOAT: u8 = b'g';
+const TAG_STR: u8 = b'u';
+const TAG_TUPLE: u8 = b'(';
+const TAG_CODE: u8 = b'c';
+
+/// Writes code objects in the cache format: numbers little-endian, and
+/// strings and lists after their lengths.
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    fn size(&mut self, value: usize) {
+        self.u32(value as u32);
+    }
+
+    fn str(&mut self, value: &str) {
+        self.size(value.len());
+        self.bytes.extend(value.as_bytes());
+    }
+
+    fn symbols(&mut self, symbols: &[Symbol]) {
+        self.size(symbols.len());
+        for symbol in symbols {
+            self.str(symbol.as_str());
+        }
+    }
+
+    fn value(&mut self, value: &Value) {
+        match value {
+            Value::None => self.bytes.push(TAG_NONE),
+            Value::Bool(false) => self.bytes.push(TAG_FALSE),
+            Value::Bool(true) => self.bytes.push(TAG_TRUE),
+            Value::Int(value) => {
+                self.bytes.push(TAG_INT);
+                self.bytes.extend(value.to_le_bytes());
+            }
+            Value::Float(value) => {
+                self.bytes.push(TAG_FLOAT);
+                self.bytes.extend(value.to_bits().to_le_bytes());
+            }
+            Value::Str(value) => {
+                self.bytes.push(TAG_STR);
+                self.str(value);
+            }
+            Value::Tuple(items) => {
+                self.bytes.push(TAG_TUPLE);
+                self.size(items.len());
+                for item in items.iter() {
+                    self.value(item);
+                }
+            }
+            Value::Code(code) => {
+                self.bytes.push(TAG_CODE);
+                self.code(code);
+            }
+            _ => unreachable!("a {} is never a constant", value.type_name()),
+        }
+    }
+
+    fn code(&mut self, code: &CodeObject) {
+        self.str(&code.name);
+        self.str(&code.qualname);
+        self.str(&code.filename);
+        self.size(code.firstlineno);
+        self.size(code.argcount);
+        self.size(code.posonlyargcount);
+        self.size(code.kwonlyargcount);
+        self.u32(code.flags);
+        self.size(code.stacksize);
+        let instructions = code.instructions();
+        self.size(instructions.len());
+        for instruction in &instructions {
+            let (opcode, arg) = instruction.encode();
+            self.bytes.push(opcode);
+            self.u32(arg);
+        }
+        let linetable = code.linetable.as_bytes();
+        self.size(linetable.len());
+        self.bytes.extend(linetable);
+        self.size(code.consts.len());
+        for constant in &code.consts {
+            self.value(constant);
+        }
+        self.symbols(&code.names);
+        self.symbols(&code.varnames);
+        self.symbols(&code.cellvars);
+        self.symbols(&code.freevars);
+        self.size(code.exception_table.len());
+        for entry in &code.exception_table {
+            for field in [entry.start, entry.end, entry.target, entry.depth] {
+                self.u32(field);
+            }
+            self.bytes.push(entry.lasti as u8);
+        }
+    }
+}
+
+/// Reads what [`Writer`] writes, failing with what is wrong.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], String> {
+        let end = self
+            .at
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or("the code is cut short")?;
+        let bytes = &self.bytes[self.at..end];
+        self.at = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn size(&mut self) -> Result<usize, String> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn str(&mut self) -> Result<String, String> {
+        let len = self.size()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| "a string is not UTF-8".to_string())
+    }
+
+    fn symbols(&mut self) -> Result<Vec<Symbol>, String> {
+        let len = self.size()?;
+        (0..len).map(|_| Ok(Symbol::intern(&self.str()?))).collect()
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        Ok(match self.u8()? {
+            TAG_NONE => Value::None,
+            TAG_FALSE => Value::Bool(false),
+            TAG_TRUE => Value::Bool(true),
+            TAG_INT => Value::Int(self.u64()? as i64),
+            TAG_FLOAT => Value::Float(f64::from_bits(self.u64()?)),
+            TAG_STR => Value::str(&self.str()?),
+            TAG_TUPLE => {
+                let len = self.size()?;
+                Value::tuple((0..len).map(|_| self.value()).collect::<Result<_, _>>()?)
+            }
+            TAG_CODE => Value::Code(Rc::new(self.code()?)),
+            tag => return Err(format!("unknown tag {:#04x}", tag)),
+        })
+    }
+
+    fn code(&mut self) -> Result<CodeObject, String> {
+        let name = self.str()?;
+        let qualname = self.str()?;
+        let filename = self.str()?;
+        let firstlineno = self.size()?;
+        let argcount = self.size()?;
+        let posonlyargcount = self.size()?;
+        let kwonlyargcount = self.size()?;
+        let flags = self.u32()?;
+        let stacksize = self.size()?;
+        let len = self.size()?;
+        let mut instructions = Vec::new();
+        for _ in 0..len {
+            let opcode = self.u8()?;
+            let arg = self.u32()?;
+            let instruction = Instruction::decode(opcode, arg)
+                .ok_or_else(|| format!("unknown instruction {} {}", opcode, arg))?;
+            instructions.push(instruction);
+        }
+        let len = self.size()?;
+        let linetable = LineTable::from_bytes(self.take(len)?.to_vec());
+        let len = self.size()?;
+        let consts = (0..len).map(|_| self.value()).collect::<Result<_, _>>()?;
+        let names = self.symbols()?;
+        let varnames = self.symbols()?;
+        let cellvars = self.symbols()?;
+        let freevars = self.symbols()?;
+        let len = self.size()?;
+        let mut exception_table = Vec::new();
+        for _ in 0..len {
+            exception_table.push(ExceptionEntry {
+                start: self.u32()?,
+                end: self.u32()?,
+                target: self.u32()?,
+                depth: self.u32()?,
+                lasti: self.u8()? != 0,
+            });
+        }
+        Ok(CodeObject {
+            name,
+            qualname,
+            filename,
+            firstlineno,
+            argcount,
+            posonlyargcount,
+            kwonlyargcount,
+            flags,
+            stacksize,
+            bytecode: pack(&instructions),
+            adaptive: Adaptive::default(),
+            linetable,
+            consts,
+            names,
+            varnames,
+            cellvars,
+            freevars,
+            exception_table,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::{
+        cache_path, compile_dir, dumps, load, loads, CacheError, Invalidation, Source, MAGIC,
+    };
+    use crate::interpreter::compile_source;
+
+    /// A fresh directory for a test to write modules and caches in.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rustypy-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_cache_round_trip() {
+        let text = fs::read_to_string("tests/dis.py").unwrap();
+        let code = compile_source(&text, "dis.py", 0).unwrap();
+        let source = Source {
+            text: text.as_bytes(),
+            mtime: 1_700_000_000,
+        };
+        for invalidation in [
+            Invalidation::Timestamp,
+            Invalidation::CheckedHash,
+            Invalidation::UncheckedHash,
+        ] {
+            let bytes = dumps(&code, source, invalidation);
+            assert_eq!(bytes[..4], MAGIC);
+            assert_eq!(loads(&bytes, source).unwrap(), code);
+        }
+    }
+
+    #[test]
+    fn test_cache_stale() {
+        let code = compile_source("x = 1\n", "x.py", 0).unwrap();
+        let source = Source {
+            text: b"x = 1\n",
+            mtime: 100,
+        };
+        let edited = Source {
+            text: b"x = 2\n",
+            mtime: 100,
+        };
+        let touched = Source {
+            text: b"x = 1\n",
+            mtime: 101,
+        };
+        let bytes = dumps(&code, source, Invalidation::Timestamp);
+        assert!(loads(&bytes, source).is_ok());
+        assert_eq!(loads(&bytes, touched).unwrap_err(), CacheError::Stale);
+        let bytes = dumps(&code, source, Invalidation::CheckedHash);
+        assert!(loads(&bytes, touched).is_ok());
+        assert_eq!(loads(&bytes, edited).unwrap_err(), CacheError::Stale);
+        let bytes = dumps(&code, source, Invalidation::UncheckedHash);
+        assert!(loads(&bytes, edited).is_ok());
+    }
+
+    #[test]
+    fn test_cache_corrupt() {
+        let code = compile_source("def f():\n    return 1\n", "f.py", 0).unwrap();
+        let source = Source {
+            text: b"",
+            mtime: 0,
+        };
+        let bytes = dumps(&code, source, Invalidation::Timestamp);
+        let mut other = bytes.clone();
+        other[0] ^= 1;
+        assert_eq!(loads(&other, source).unwrap_err(), CacheError::BadMagic);
+        assert_eq!(
+            loads(&bytes[..10], source).unwrap_err().to_string(),
+            "corrupt cache: the header is cut short"
+        );
+        assert_eq!(
+            loads(&bytes[..bytes.len() - 1], source)
+                .unwrap_err()
+                .to_string(),
+            "corrupt cache: the code is cut short"
+        );
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert_eq!(
+            loads(&longer, source).unwrap_err().to_string(),
+            "corrupt cache: bytes after the code"
+        );
+        let mut flags = bytes.clone();
+        flags[4] = 4;
+        assert_eq!(
+            loads(&flags, source).unwrap_err().to_string(),
+            "corrupt cache: unknown flags 0x4"
+        );
+    }
+
+    #[test]
+    fn test_cache_load() {
+        let dir = scratch("load");
+        let path = dir.join("m.py");
+        let text = "def f(x):\n    return x * 2\nf(21)\n";
+        fs::write(&path, text).unwrap();
+        let code = load(&path, text, 0).unwrap();
+        let cache = cache_path(&path, 0);
+        assert_eq!(cache, dir.join("__pycache__/m.rustypy-01.pyc"));
+        assert_eq!(load(&path, text, 0).unwrap(), code);
+        assert!(!cache_path(&path, 1).exists());
+
+        // A cache that is not one is compiled over.
+        fs::write(&cache, b"not a cache").unwrap();
+        assert_eq!(load(&path, text, 0).unwrap(), code);
+        assert!(fs::read(&cache).unwrap().starts_with(&MAGIC));
+
+        // As is one whose tables are garbled.
+        let mut bytes = fs::read(&cache).unwrap();
+        let len = bytes.len();
+        bytes[len - 1] ^= 0xff;
+        fs::write(&cache, &bytes).unwrap();
+        assert_eq!(load(&path, text, 0).unwrap(), code);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compile_dir() {
+        let dir = scratch("compileall");
+        fs::create_dir_all(dir.join("package")).unwrap();
+        fs::write(dir.join("a.py"), "print(1)\n").unwrap();
+        fs::write(dir.join("bad.py"), "def (:\n").unwrap();
+        fs::write(dir.join("notes.txt"), "not python\n").unwrap();
+        fs::write(dir.join("package/b.py"), "x = 2\n").unwrap();
+        let mut out = Vec::new();
+        let compiled = compile_dir(&dir, 0, Invalidation::CheckedHash, false, &mut out).unwrap();
+        assert!(!compiled);
+        let out = String::from_utf8(out).unwrap();
+        let path = |name: &str| dir.join(name).display().to_string();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], format!("Listing '{}'...", dir.display()));
+        assert_eq!(lines[1], format!("Compiling '{}'...", path("a.py")));
+        assert_eq!(lines[2], format!("Compiling '{}'...", path("bad.py")));
+        assert_eq!(
+            lines[3],
+            format!("*** Error compiling '{}'...", path("bad.py"))
+        );
+        assert!(lines[4].starts_with("SyntaxError: "), "{}", lines[4]);
+        assert_eq!(lines[5], format!("Listing '{}'...", path("package")));
+        assert_eq!(lines[6], format!("Compiling '{}'...", path("package/b.py")));
+        assert_eq!(lines.len(), 7);
+        assert!(dir.join("__pycache__/a.rustypy-01.pyc").exists());
+        assert!(dir.join("package/__pycache__/b.rustypy-01.pyc").exists());
+
+        // Only the module that failed is compiled again, unless forced or
+        // the caches are to be made another way.
+        let mut out = Vec::new();
+        compile_dir(&dir, 0, Invalidation::CheckedHash, false, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap().matches("Compiling").count(),
+            1
+        );
+        let mut out = Vec::new();
+        compile_dir(&dir, 0, Invalidation::CheckedHash, true, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap().matches("Compiling").count(),
+            3
+        );
+        let mut out = Vec::new();
+        compile_dir(&dir, 0, Invalidation::Timestamp, false, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap().matches("Compiling").count(),
+            3
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
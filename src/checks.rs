@@ -0,0 +1,737 @@
+//! Syntax errors that CPython raises while compiling rather than parsing.
+//!
+//! The grammar accepts `await` and `yield` anywhere an expression may go;
+//! whether they are allowed depends on the scope they end up in, which is
+//! only known once the whole tree is built. `check` walks a parsed module
+//! keeping track of the enclosing scopes and reports what CPython's
+//! compiler would, worded the same way. It also rejects binding or
+//! deleting `__debug__`, which the compiler folds to a constant.
+
+use std::mem;
+
+use crate::ast::{
+    Arena, Arg, Comprehension, ExceptHandler, ExprContext, ExprId, ExprKind, Keyword, List, Module,
+    Span, StmtId, StmtKind, TypeParam,
+};
+use crate::parser::ParseError;
+use crate::visitor::{walk_arg, walk_except_handler, walk_expr, walk_keyword, walk_stmt, Visitor};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scope {
+    Module,
+    Class,
+    Function,
+    AsyncFunction {
+        generator: bool,
+    },
+    Lambda,
+    /// A comprehension, named as in "'yield' inside list comprehension".
+    Comprehension(&'static str),
+    /// One of the scopes PEP 695 evaluates type parameters and alias values
+    /// in, named as in "... cannot be used within a type alias".
+    Annotation(&'static str),
+}
+
+/// Whether the function defined by `function` is a generator, that is,
+/// whether its body yields outside any nested scope.
+pub fn is_generator(arena: &Arena, function: StmtId) -> bool {
+    match &arena[function].kind {
+        StmtKind::FunctionDef { body, .. } | StmtKind::AsyncFunctionDef { body, .. } => {
+            let mut finder = YieldFinder { found: false };
+            for &stmt in &arena[*body] {
+                finder.visit_stmt(arena, stmt);
+            }
+            finder.found
+        }
+        _ => false,
+    }
+}
+
+/// Whether `lambda`, a lambda expression, is a generator.
+pub fn is_generator_lambda(arena: &Arena, lambda: ExprId) -> bool {
+    match arena[lambda].kind {
+        ExprKind::Lambda { body, .. } => {
+            let mut finder = YieldFinder { found: false };
+            finder.visit_expr(arena, body);
+            finder.found
+        }
+        _ => false,
+    }
+}
+
+/// Returns the errors in `module`, in the order they are found.
+pub fn check(module: &Module) -> Vec<ParseError> {
+    let mut checker = Checker {
+        scopes: vec![Scope::Module],
+        awaits: false,
+        errors: Vec::new(),
+    };
+    checker.visit_module(module);
+    checker.errors
+}
+
+struct Checker {
+    scopes: Vec<Scope>,
+    /// Whether the innermost comprehension awaits, which makes it
+    /// asynchronous.
+    awaits: bool,
+    errors: Vec<ParseError>,
+}
+
+impl Checker {
+    fn scope(&self) -> Scope {
+        *self.scopes.last().unwrap()
+    }
+
+    fn error(&mut self, message: &str, span: Span) {
+        self.errors.push(ParseError::new(message, span));
+    }
+
+    /// Reports binding `name` if it is `__debug__`.
+    fn bind(&mut self, name: &str, span: Span) {
+        if name == "__debug__" {
+            self.error("cannot assign to __debug__", span);
+        }
+    }
+
+    fn in_scope(&mut self, scope: Scope, f: impl FnOnce(&mut Self)) {
+        self.scopes.push(scope);
+        f(self);
+        self.scopes.pop();
+    }
+
+    /// Runs `f` in the annotation scope holding `type_params`, if there are
+    /// any. Each bound is evaluated lazily, in a scope of its own.
+    fn generic(&mut self, arena: &Arena, type_params: List<TypeParam>, f: impl FnOnce(&mut Self)) {
+        let type_params = &arena[type_params];
+        if type_params.is_empty() {
+            return f(self);
+        }
+        self.in_scope(Scope::Annotation("the definition of a generic"), |this| {
+            for (i, type_param) in type_params.iter().enumerate() {
+                let name = type_param.name();
+                this.bind(name.as_str(), type_param.span);
+                if type_params[..i]
+                    .iter()
+                    .any(|earlier| earlier.name() == name)
+                {
+                    this.error(
+                        &format!("duplicate type parameter '{}'", name),
+                        type_param.span,
+                    );
+                }
+                this.in_scope(Scope::Annotation("a TypeVar bound"), |this| {
+                    this.visit_type_param(arena, type_param)
+                });
+            }
+            f(this);
+        });
+    }
+
+    /// A comprehension runs in a scope of its own, except for its first
+    /// iterable, which is evaluated where the comprehension is written.
+    fn comprehension(
+        &mut self,
+        arena: &Arena,
+        expr: ExprId,
+        elts: &[ExprId],
+        generators: List<Comprehension>,
+    ) {
+        let generators = &arena[generators];
+        let (first, rest) = generators.split_first().unwrap();
+        self.visit_expr(arena, first.iter);
+        let outer = mem::replace(&mut self.awaits, false);
+        let kind = match arena[expr].kind {
+            ExprKind::ListComp { .. } => "list comprehension",
+            ExprKind::SetComp { .. } => "set comprehension",
+            ExprKind::DictComp { .. } => "dict comprehension",
+            _ => "generator expression",
+        };
+        self.in_scope(Scope::Comprehension(kind), |this| {
+            this.visit_expr(arena, first.target);
+            for &condition in &arena[first.ifs] {
+                this.visit_expr(arena, condition);
+            }
+            for comprehension in rest {
+                this.visit_comprehension(arena, comprehension);
+            }
+            for &elt in elts {
+                this.visit_expr(arena, elt);
+            }
+        });
+        let is_async =
+            mem::replace(&mut self.awaits, outer) || generators.iter().any(|c| c.is_async);
+        // A generator expression that awaits is an asynchronous generator;
+        // the other comprehensions run to completion where they are written.
+        if !is_async || matches!(arena[expr].kind, ExprKind::GeneratorExp { .. }) {
+            return;
+        }
+        match self.scope() {
+            Scope::AsyncFunction { .. } => {}
+            Scope::Comprehension(_) => self.awaits = true,
+            _ => self.error(
+                "asynchronous comprehension outside of an asynchronous function",
+                arena[expr].span,
+            ),
+        }
+    }
+}
+
+impl<'ast> Visitor<'ast> for Checker {
+    fn visit_stmt(&mut self, arena: &'ast Arena, stmt: StmtId) {
+        let span = arena[stmt].span;
+        match &arena[stmt].kind {
+            StmtKind::FunctionDef { name, .. }
+            | StmtKind::AsyncFunctionDef { name, .. }
+            | StmtKind::ClassDef { name, .. } => self.bind(name.as_str(), span),
+            StmtKind::Import { names } | StmtKind::ImportFrom { names, .. } => {
+                for alias in &arena[*names] {
+                    // `import a.b` binds `a`.
+                    let bound = alias.asname.unwrap_or(alias.name).as_str();
+                    self.bind(bound.split('.').next().unwrap(), span);
+                }
+            }
+            _ => {}
+        }
+        match &arena[stmt].kind {
+            StmtKind::FunctionDef {
+                args,
+                body,
+                decorator_list,
+                returns,
+                type_params,
+                ..
+            }
+            | StmtKind::AsyncFunctionDef {
+                args,
+                body,
+                decorator_list,
+                returns,
+                type_params,
+                ..
+            } => {
+                for &decorator in &arena[*decorator_list] {
+                    self.visit_expr(arena, decorator);
+                }
+                for default in args.default_values(arena) {
+                    self.visit_expr(arena, default);
+                }
+                let scope = match arena[stmt].kind {
+                    StmtKind::AsyncFunctionDef { .. } => Scope::AsyncFunction {
+                        generator: is_generator(arena, stmt),
+                    },
+                    _ => Scope::Function,
+                };
+                self.generic(arena, *type_params, |this| {
+                    for arg in args.params(arena) {
+                        this.visit_arg(arena, arg);
+                    }
+                    if let Some(returns) = returns {
+                        this.visit_expr(arena, *returns);
+                    }
+                    this.in_scope(scope, |this| {
+                        for &stmt in &arena[*body] {
+                            this.visit_stmt(arena, stmt);
+                        }
+                    });
+                });
+            }
+            StmtKind::ClassDef {
+                bases,
+                keywords,
+                body,
+                decorator_list,
+                type_params,
+                ..
+            } => {
+                for &decorator in &arena[*decorator_list] {
+                    self.visit_expr(arena, decorator);
+                }
+                self.generic(arena, *type_params, |this| {
+                    for &base in &arena[*bases] {
+                        this.visit_expr(arena, base);
+                    }
+                    for keyword in &arena[*keywords] {
+                        this.visit_keyword(arena, keyword);
+                    }
+                    this.in_scope(Scope::Class, |this| {
+                        for &stmt in &arena[*body] {
+                            this.visit_stmt(arena, stmt);
+                        }
+                    });
+                });
+            }
+            StmtKind::TypeAlias {
+                type_params, value, ..
+            } => self.generic(arena, *type_params, |this| {
+                this.in_scope(Scope::Annotation("a type alias"), |this| {
+                    this.visit_expr(arena, *value)
+                })
+            }),
+            StmtKind::AsyncFor { .. } | StmtKind::AsyncWith { .. } => {
+                if !matches!(self.scope(), Scope::AsyncFunction { .. }) {
+                    let message = match arena[stmt].kind {
+                        StmtKind::AsyncFor { .. } => "'async for' outside async function",
+                        _ => "'async with' outside async function",
+                    };
+                    self.error(message, span);
+                }
+                walk_stmt(self, arena, stmt);
+            }
+            StmtKind::Return { value: Some(_) } => {
+                if self.scope() == (Scope::AsyncFunction { generator: true }) {
+                    self.error("'return' with value in async generator", span);
+                }
+                walk_stmt(self, arena, stmt);
+            }
+            _ => walk_stmt(self, arena, stmt),
+        }
+    }
+
+    fn visit_expr(&mut self, arena: &'ast Arena, expr: ExprId) {
+        let span = arena[expr].span;
+        match &arena[expr].kind {
+            ExprKind::Await { .. } => {
+                match self.scope() {
+                    Scope::Annotation(scope) => self.error(
+                        &format!("await expression cannot be used within {}", scope),
+                        span,
+                    ),
+                    Scope::Module | Scope::Class => self.error("'await' outside function", span),
+                    Scope::Function | Scope::Lambda => {
+                        self.error("'await' outside async function", span)
+                    }
+                    Scope::AsyncFunction { .. } => {}
+                    Scope::Comprehension(_) => self.awaits = true,
+                }
+                walk_expr(self, arena, expr);
+            }
+            ExprKind::Yield { .. } | ExprKind::YieldFrom { .. } => {
+                let yield_from = matches!(arena[expr].kind, ExprKind::YieldFrom { .. });
+                match self.scope() {
+                    Scope::Annotation(scope) => self.error(
+                        &format!("yield expression cannot be used within {}", scope),
+                        span,
+                    ),
+                    Scope::Module | Scope::Class => self.error("'yield' outside function", span),
+                    Scope::Comprehension(kind) => {
+                        self.error(&format!("'yield' inside {}", kind), span)
+                    }
+                    Scope::AsyncFunction { .. } if yield_from => {
+                        self.error("'yield from' inside async function", span)
+                    }
+                    _ => {}
+                }
+                walk_expr(self, arena, expr);
+            }
+            ExprKind::NamedExpr { .. } => {
+                if let Scope::Annotation(scope) = self.scope() {
+                    self.error(
+                        &format!("named expression cannot be used within {}", scope),
+                        span,
+                    );
+                }
+                walk_expr(self, arena, expr);
+            }
+            ExprKind::Name { id, ctx } if *ctx != ExprContext::Load => self.bind(id.as_str(), span),
+            ExprKind::Attribute { value, attr, ctx } if *ctx != ExprContext::Load => {
+                self.bind(attr.as_str(), span);
+                self.visit_expr(arena, *value);
+            }
+            ExprKind::Lambda { args, body } => {
+                self.visit_arguments(arena, args);
+                self.in_scope(Scope::Lambda, |this| this.visit_expr(arena, *body));
+            }
+            ExprKind::ListComp { elt, generators }
+            | ExprKind::SetComp { elt, generators }
+            | ExprKind::GeneratorExp { elt, generators } => {
+                self.comprehension(arena, expr, &[*elt], *generators)
+            }
+            ExprKind::DictComp {
+                key,
+                value,
+                generators,
+            } => self.comprehension(arena, expr, &[*key, *value], *generators),
+            _ => walk_expr(self, arena, expr),
+        }
+    }
+
+    fn visit_arg(&mut self, arena: &'ast Arena, arg: &'ast Arg) {
+        self.bind(arg.arg.as_str(), arg.span);
+        walk_arg(self, arena, arg);
+    }
+
+    fn visit_keyword(&mut self, arena: &'ast Arena, keyword: &'ast Keyword) {
+        if let Some(arg) = keyword.arg {
+            self.bind(arg.as_str(), arena[keyword.value].span);
+        }
+        walk_keyword(self, arena, keyword);
+    }
+
+    fn visit_except_handler(&mut self, arena: &'ast Arena, handler: &'ast ExceptHandler) {
+        if let Some(name) = handler.name {
+            self.bind(name.as_str(), handler.span);
+        }
+        walk_except_handler(self, arena, handler);
+    }
+}
+
+/// Looks for a yield in one scope, stepping over the scopes nested in it
+/// but not over the parts of them evaluated in it, such as defaults and a
+/// comprehension's first iterable. The annotations and bases of a generic
+/// definition belong to its annotation scope.
+struct YieldFinder {
+    found: bool,
+}
+
+impl<'ast> Visitor<'ast> for YieldFinder {
+    fn visit_stmt(&mut self, arena: &'ast Arena, stmt: StmtId) {
+        match &arena[stmt].kind {
+            StmtKind::FunctionDef {
+                args,
+                decorator_list,
+                returns,
+                type_params,
+                ..
+            }
+            | StmtKind::AsyncFunctionDef {
+                args,
+                decorator_list,
+                returns,
+                type_params,
+                ..
+            } => {
+                for &decorator in &arena[*decorator_list] {
+                    self.visit_expr(arena, decorator);
+                }
+                for default in args.default_values(arena) {
+                    self.visit_expr(arena, default);
+                }
+                if type_params.is_empty() {
+                    for arg in args.params(arena) {
+                        self.visit_arg(arena, arg);
+                    }
+                    if let Some(returns) = returns {
+                        self.visit_expr(arena, *returns);
+                    }
+                }
+            }
+            StmtKind::ClassDef {
+                bases,
+                keywords,
+                decorator_list,
+                type_params,
+                ..
+            } => {
+                for &decorator in &arena[*decorator_list] {
+                    self.visit_expr(arena, decorator);
+                }
+                if type_params.is_empty() {
+                    for &base in &arena[*bases] {
+                        self.visit_expr(arena, base);
+                    }
+                    for keyword in &arena[*keywords] {
+                        self.visit_keyword(arena, keyword);
+                    }
+                }
+            }
+            StmtKind::TypeAlias { .. } => {}
+            _ => walk_stmt(self, arena, stmt),
+        }
+    }
+
+    fn visit_expr(&mut self, arena: &'ast Arena, expr: ExprId) {
+        match &arena[expr].kind {
+            ExprKind::Yield { .. } | ExprKind::YieldFrom { .. } => self.found = true,
+            ExprKind::Lambda { args, .. } => self.visit_arguments(arena, args),
+            ExprKind::ListComp { generators, .. }
+            | ExprKind::SetComp { generators, .. }
+            | ExprKind::DictComp { generators, .. }
+            | ExprKind::GeneratorExp { generators, .. } => {
+                self.visit_expr(arena, arena[*generators][0].iter)
+            }
+            _ => walk_expr(self, arena, expr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_generator, is_generator_lambda};
+    use crate::ast::{ExprKind, StmtKind};
+    use crate::parser::parse;
+
+    fn first_error(source: &str) -> String {
+        match parse(source) {
+            Ok(_) => "OK".to_string(),
+            Err(errors) => errors[0].to_string(),
+        }
+    }
+
+    #[test]
+    fn test_check_await() {
+        let cases = [
+            ("await x\n", "'await' outside function (line 1)"),
+            (
+                "def f():\n    await x\n",
+                "'await' outside async function (line 2)",
+            ),
+            ("async def f():\n    await x\n", "OK"),
+            (
+                "async def f():\n    lambda: await x\n",
+                "'await' outside async function (line 2)",
+            ),
+            (
+                "async def f():\n    class A:\n        await x\n",
+                "'await' outside function (line 3)",
+            ),
+            (
+                "async def f():\n    def g():\n        await x\n",
+                "'await' outside async function (line 3)",
+            ),
+            ("async def f():\n    def g(a=await x): pass\n", "OK"),
+            ("async def f():\n    [x for x in y if await z]\n", "OK"),
+            (
+                "[x for x in await y]\n",
+                "'await' outside function (line 1)",
+            ),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(first_error(source), expected, "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn test_check_async_comprehensions() {
+        let outside = "asynchronous comprehension outside of an asynchronous function";
+        let cases = [
+            ("[await x for x in y]\n", 1),
+            ("[x async for x in y]\n", 1),
+            ("{x: y async for x, y in z}\n", 1),
+            ("(await x for x in y)\n", 0),
+            ("(x async for x in y)\n", 0),
+            ("def f():\n    [await x for x in y]\n", 2),
+            ("def f():\n    (await x for x in y)\n", 0),
+            ("async def f():\n    [await x for x in y]\n", 0),
+            ("async def f():\n    [[await x for x in z] for z in y]\n", 0),
+            ("def f():\n    [[x async for x in z] for z in y]\n", 2),
+        ];
+        for (source, line) in cases {
+            let expected = match line {
+                0 => "OK".to_string(),
+                line => format!("{} (line {})", outside, line),
+            };
+            assert_eq!(first_error(source), expected, "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn test_check_async_statements() {
+        let cases = [
+            (
+                "async for x in y: pass\n",
+                "'async for' outside async function (line 1)",
+            ),
+            (
+                "def f():\n    async for x in y: pass\n",
+                "'async for' outside async function (line 2)",
+            ),
+            (
+                "async with x: pass\n",
+                "'async with' outside async function (line 1)",
+            ),
+            (
+                "def f():\n    async with x: pass\n",
+                "'async with' outside async function (line 2)",
+            ),
+            (
+                "async def f():\n    async with a as b, c: pass\n    async for x in y: pass\n",
+                "OK",
+            ),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(first_error(source), expected, "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn test_check_yield() {
+        let cases = [
+            ("yield\n", "'yield' outside function (line 1)"),
+            ("yield from x\n", "'yield' outside function (line 1)"),
+            (
+                "class A:\n    x = yield\n",
+                "'yield' outside function (line 2)",
+            ),
+            (
+                "class A:\n    [x for x in (yield)]\n",
+                "'yield' outside function (line 2)",
+            ),
+            ("def f():\n    [x for x in (yield)]\n", "OK"),
+            (
+                "def f():\n    [(yield) for x in y]\n",
+                "'yield' inside list comprehension (line 2)",
+            ),
+            (
+                "def f():\n    [x for x in y for z in (yield)]\n",
+                "'yield' inside list comprehension (line 2)",
+            ),
+            (
+                "def f():\n    {(yield) for x in y}\n",
+                "'yield' inside set comprehension (line 2)",
+            ),
+            (
+                "def f():\n    {x: (yield) for x in y}\n",
+                "'yield' inside dict comprehension (line 2)",
+            ),
+            (
+                "def f():\n    ((yield) for x in y)\n",
+                "'yield' inside generator expression (line 2)",
+            ),
+            ("lambda: (yield)\n", "OK"),
+            ("async def f():\n    yield x\n", "OK"),
+            (
+                "async def f():\n    yield from x\n",
+                "'yield from' inside async function (line 2)",
+            ),
+            ("async def f():\n    lambda: (yield from x)\n", "OK"),
+            (
+                "async def f():\n    return 1\n    yield\n",
+                "'return' with value in async generator (line 2)",
+            ),
+            (
+                "async def f():\n    yield\n    return 1\n",
+                "'return' with value in async generator (line 3)",
+            ),
+            ("async def f():\n    return\n    yield\n", "OK"),
+            (
+                "async def f():\n    def g():\n        yield\n    return 1\n",
+                "OK",
+            ),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(first_error(source), expected, "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn test_check_debug() {
+        let error = "cannot assign to __debug__ (line 1)";
+        let cases = [
+            ("__debug__ = 0\n", error),
+            ("a, *__debug__ = b\n", error),
+            ("__debug__ += 1\n", error),
+            ("__debug__: int = 1\n", error),
+            ("for __debug__ in x: pass\n", error),
+            ("with x as __debug__: pass\n", error),
+            ("(__debug__ := 1)\n", error),
+            ("[0 for __debug__ in x]\n", error),
+            ("def f(__debug__): pass\n", error),
+            ("def f(*, __debug__=1): pass\n", error),
+            ("def f(**__debug__): pass\n", error),
+            ("lambda *__debug__: 0\n", error),
+            ("def __debug__(): pass\n", error),
+            ("class __debug__: pass\n", error),
+            ("def f[__debug__](): pass\n", error),
+            ("x.__debug__ = 1\n", error),
+            ("del x.__debug__\n", error),
+            ("del __debug__\n", error),
+            ("f(__debug__=1)\n", error),
+            ("import __debug__\n", error),
+            ("import __debug__.a\n", error),
+            ("from a import b as __debug__\n", error),
+            (
+                "try: pass\nexcept E as __debug__: pass\n",
+                "cannot assign to __debug__ (line 2)",
+            ),
+            ("print(__debug__, x.__debug__)\n", "OK"),
+            ("import a.__debug__\n", "OK"),
+            ("from __debug__ import a\n", "OK"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(first_error(source), expected, "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn test_is_generator() {
+        let cases = [
+            ("def f():\n    return 1\n", false),
+            ("def f():\n    x = yield\n", true),
+            ("async def f():\n    yield\n", true),
+            ("def f():\n    if x:\n        yield from y\n", true),
+            ("def f():\n    def g(): yield\n", false),
+            ("def f():\n    def g(a=(yield)): pass\n", true),
+            (
+                "def f():\n    class A:\n        def g(self): yield\n",
+                false,
+            ),
+            ("def f():\n    lambda: (yield)\n", false),
+            ("def f():\n    [x for x in (yield)]\n", true),
+            ("def f():\n    def g[T](x=(yield)): pass\n", true),
+            ("def f():\n    class A[T](lambda: (yield)): pass\n", false),
+        ];
+        for (source, expected) in cases {
+            let module = parse(source).unwrap();
+            assert_eq!(
+                is_generator(&module.arena, module.arena[module.body][0]),
+                expected,
+                "{:?}",
+                source
+            );
+        }
+        let module = parse("x = lambda: (yield)\ny = lambda: 1\n").unwrap();
+        let lambdas: Vec<_> = module.arena[module.body]
+            .iter()
+            .map(|&stmt| match module.arena[stmt].kind {
+                StmtKind::Assign { value, .. } => value,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert!(matches!(
+            module.arena[lambdas[0]].kind,
+            ExprKind::Lambda { .. }
+        ));
+        assert!(is_generator_lambda(&module.arena, lambdas[0]));
+        assert!(!is_generator_lambda(&module.arena, lambdas[1]));
+    }
+
+    #[test]
+    fn test_check_annotation_scopes() {
+        let cases = [
+            (
+                "type A = (yield)\n",
+                "yield expression cannot be used within a type alias (line 1)",
+            ),
+            (
+                "type A[T] = (x := T)\n",
+                "named expression cannot be used within a type alias (line 1)",
+            ),
+            (
+                "def f[T: (yield)](): pass\n",
+                "yield expression cannot be used within a TypeVar bound (line 1)",
+            ),
+            (
+                "async def g():\n    def f[T](x: await y): pass\n",
+                "await expression cannot be used within the definition of a generic (line 2)",
+            ),
+            (
+                "class A[T]((x := 1)): pass\n",
+                "named expression cannot be used within the definition of a generic (line 1)",
+            ),
+            (
+                "def f[T, *T](): pass\n",
+                "duplicate type parameter 'T' (line 1)",
+            ),
+            (
+                "def f[T](x=(yield)): pass\n",
+                "'yield' outside function (line 1)",
+            ),
+            ("def f():\n    def g[T](x=(yield)): pass\n", "OK"),
+            ("def f():\n    type A[T: int] = lambda: (yield)\n", "OK"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(first_error(source), expected, "{:?}", source);
+        }
+    }
+}
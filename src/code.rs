@@ -1 +1,273 @@
-pub struct CodeObject {}
+//! Compiled code.
+
+use std::cell::{Cell, OnceCell};
+use std::fmt;
+
+use crate::intern::Symbol;
+use crate::intruction::{pack, unpack, Instruction, Opcode};
+use crate::specialize::{quicken, Unit};
+use crate::value::Value;
+
+/// The code of a function, rather than a module or class body: its
+/// variables are fast locals.
+pub const CO_OPTIMIZED: u32 = 0x1;
+/// Each call of the code gets a new namespace for its locals.
+pub const CO_NEWLOCALS: u32 = 0x2;
+/// The function has a `*args` parameter.
+pub const CO_VARARGS: u32 = 0x4;
+/// The function has a `**kwargs` parameter.
+pub const CO_VARKEYWORDS: u32 = 0x8;
+/// The function is defined inside another function.
+pub const CO_NESTED: u32 = 0x10;
+pub const CO_GENERATOR: u32 = 0x20;
+pub const CO_COROUTINE: u32 = 0x80;
+pub const CO_ASYNC_GENERATOR: u32 = 0x200;
+
+/// The compiled body of a module, function or lambda, modelled on
+/// CPython's code object.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodeObject {
+    pub name: String,
+    pub qualname: String,
+    pub filename: String,
+    pub firstlineno: usize,
+    /// How many positional parameters the function takes, including the
+    /// positional-only ones.
+    pub argcount: usize,
+    pub posonlyargcount: usize,
+    pub kwonlyargcount: usize,
+    /// The `CO_*` flags.
+    pub flags: u32,
+    /// The most values the code ever has on its stack at once.
+    pub stacksize: usize,
+    /// The instructions, packed into code units as [`pack`] packs them,
+    /// as in CPython's `co_code`.
+    pub bytecode: Vec<u16>,
+    /// The source line of each instruction.
+    pub linetable: LineTable,
+    pub consts: Vec<Value>,
+    /// Names of attributes and of variables looked up by name.
+    pub names: Vec<Symbol>,
+    /// Local variables, the parameters first.
+    pub varnames: Vec<Symbol>,
+    /// Local variables that nested functions use, which live in cells.
+    pub cellvars: Vec<Symbol>,
+    /// Variables of enclosing functions that the code uses.
+    pub freevars: Vec<Symbol>,
+    /// The handlers for exceptions raised in the code, as in CPython 3.11.
+    pub exception_table: Vec<ExceptionEntry>,
+    /// The units the VM runs, as it has specialized them.
+    pub adaptive: Adaptive,
+}
+
+impl CodeObject {
+    /// The instructions, unpacked.
+    pub fn instructions(&self) -> Vec<Instruction> {
+        unpack(&self.bytecode)
+    }
+
+    /// Packs `instructions` as the code's.
+    pub fn set_instructions(&mut self, instructions: &[Instruction]) {
+        self.bytecode = pack(instructions);
+    }
+
+    /// The code units to run, copied from `bytecode` the first time, which
+    /// the VM rewrites as it specializes the instructions.
+    pub fn adaptive(&self) -> &[Cell<Unit>] {
+        self.adaptive.units.get_or_init(|| quicken(&self.bytecode))
+    }
+
+    /// The index of the instruction whose opcode is the code unit at
+    /// `offset`: how many instructions end before it.
+    pub fn instruction_index(&self, offset: usize) -> usize {
+        self.bytecode[..offset]
+            .iter()
+            .filter(|&&unit| !is_prefix_or_cache(unit))
+            .count()
+    }
+
+    /// The offset of the first code unit of the instruction at `index`.
+    pub fn instruction_offset(&self, index: usize) -> usize {
+        let ends = self
+            .bytecode
+            .iter()
+            .enumerate()
+            .filter(|(_, &unit)| !is_prefix_or_cache(unit))
+            .map(|(offset, &unit)| offset + 1 + Opcode::from_u8(unit as u8).unwrap().caches());
+        std::iter::once(0).chain(ends).nth(index).unwrap()
+    }
+
+    /// The source line of each instruction.
+    pub fn lines(&self) -> Vec<usize> {
+        self.linetable.lines(self.firstlineno)
+    }
+
+    /// The source line of the instruction at `index`.
+    pub fn line(&self, index: usize) -> usize {
+        self.linetable.line(self.firstlineno, index)
+    }
+}
+
+/// Whether a code unit is an `EXTENDED_ARG` or a `CACHE`, rather than an
+/// instruction's opcode.
+fn is_prefix_or_cache(unit: u16) -> bool {
+    unit as u8 == Opcode::ExtendedArg as u8 || unit as u8 == Opcode::Cache as u8
+}
+
+/// The specialized code units of a code object, which the VM makes when
+/// it first runs the code. A code object made or cloned has none: they
+/// are what the VM has learned running it, and no part of what it is, so
+/// they are also left out of comparisons.
+#[derive(Default)]
+pub struct Adaptive {
+    units: OnceCell<Box<[Cell<Unit>]>>,
+}
+
+impl Clone for Adaptive {
+    fn clone(&self) -> Adaptive {
+        Adaptive::default()
+    }
+}
+
+impl PartialEq for Adaptive {
+    fn eq(&self, _: &Adaptive) -> bool {
+        true
+    }
+}
+
+impl fmt::Debug for Adaptive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Adaptive").finish_non_exhaustive()
+    }
+}
+
+/// An exception handler for a range of instructions, which an exception
+/// raised by any of them jumps to, after popping the value stack down to
+/// `depth` values and pushing the exception. Where ranges nest, the
+/// innermost comes first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExceptionEntry {
+    /// The first instruction covered.
+    pub start: u32,
+    /// The instruction after the last one covered.
+    pub end: u32,
+    pub target: u32,
+    pub depth: u32,
+    /// Whether the index of the instruction that raised is pushed under
+    /// the exception, for a `RERAISE` to take back, as cleanup handlers
+    /// in CPython do.
+    pub lasti: bool,
+}
+
+/// The line numbers of a code object's instructions, compressed as in
+/// CPython 3.10's `co_linetable`: each run of instructions on one line is
+/// a pair of bytes, the length of the run and the signed difference from
+/// the previous run's line, starting from the code's first line. A run too
+/// long or a jump too far for a byte is split over several pairs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LineTable {
+    bytes: Vec<u8>,
+}
+
+impl LineTable {
+    /// The longest run one pair holds.
+    const MAX_RUN: usize = 254;
+    /// The biggest difference in lines one pair holds, either way.
+    const MAX_DELTA: i64 = 127;
+
+    /// Compresses the lines of a code object starting on `firstlineno`.
+    pub fn new(firstlineno: usize, lines: &[usize]) -> LineTable {
+        let mut bytes = Vec::new();
+        let mut previous = firstlineno as i64;
+        let mut start = 0;
+        while start < lines.len() {
+            let line = lines[start];
+            let mut run = lines[start..].iter().take_while(|&&l| l == line).count();
+            start += run;
+            let mut delta = line as i64 - previous;
+            previous = line as i64;
+            while delta.abs() > Self::MAX_DELTA {
+                let step = Self::MAX_DELTA * delta.signum();
+                bytes.extend([0, step as i8 as u8]);
+                delta -= step;
+            }
+            while run > Self::MAX_RUN {
+                bytes.extend([Self::MAX_RUN as u8, delta as i8 as u8]);
+                run -= Self::MAX_RUN;
+                delta = 0;
+            }
+            bytes.extend([run as u8, delta as i8 as u8]);
+        }
+        LineTable { bytes }
+    }
+
+    /// The table with these bytes, as [`LineTable::as_bytes`] gives them.
+    pub fn from_bytes(bytes: Vec<u8>) -> LineTable {
+        LineTable { bytes }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The line of each instruction, for code starting on `firstlineno`.
+    pub fn lines(&self, firstlineno: usize) -> Vec<usize> {
+        let mut lines = Vec::new();
+        let mut line = firstlineno as i64;
+        for pair in self.bytes.chunks(2) {
+            line += pair[1] as i8 as i64;
+            lines.extend(std::iter::repeat_n(line as usize, pair[0] as usize));
+        }
+        lines
+    }
+
+    /// The line of the instruction at `index`, for code starting on
+    /// `firstlineno`.
+    pub fn line(&self, firstlineno: usize, index: usize) -> usize {
+        let mut line = firstlineno as i64;
+        let mut end = 0;
+        for pair in self.bytes.chunks(2) {
+            line += pair[1] as i8 as i64;
+            end += pair[0] as usize;
+            if index < end {
+                break;
+            }
+        }
+        line as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LineTable;
+
+    #[test]
+    fn test_line_table() {
+        let lines = [3, 3, 4, 4, 4, 2, 2, 9];
+        let table = LineTable::new(3, &lines);
+        assert_eq!(table.as_bytes(), [2, 0, 3, 1, 2, (-2i8) as u8, 1, 7]);
+        assert_eq!(table.lines(3), lines);
+        let each: Vec<usize> = (0..lines.len()).map(|i| table.line(3, i)).collect();
+        assert_eq!(each, lines);
+        assert_eq!(LineTable::new(1, &[]).lines(1), []);
+    }
+
+    #[test]
+    fn test_line_table_splits() {
+        // A run over 254 instructions, and jumps over 127 lines each way.
+        let mut lines = vec![1; 600];
+        lines.extend([400, 2]);
+        let table = LineTable::new(1, &lines);
+        assert_eq!(
+            table.as_bytes(),
+            [
+                254, 0, 254, 0, 92, 0, 0, 127, 0, 127, 0, 127, 1, 18, 0, 129, 0, 129, 0, 129, 1,
+                239
+            ]
+        );
+        assert_eq!(table.lines(1), lines);
+        assert_eq!(table.line(1, 599), 1);
+        assert_eq!(table.line(1, 600), 400);
+        assert_eq!(table.line(1, 601), 2);
+    }
+}
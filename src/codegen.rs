@@ -1,103 +1,2635 @@
-// use regex::internal::Inst;
-
-// use crate::{
-//     intruction::Instruction,
-//     parser::{Expr, Stmt},
-//     token::TokenType,
-// };
-
-// pub fn codegen_expr(expr: &Expr) -> Vec<Instruction> {
-//     match expr {
-//         Expr::IntLiteral(i) => vec![Instruction::LoadConst(*i)],
-//         Expr::Identifier(i) => vec![Instruction::LoadName(i.value.clone())],
-//         Expr::BinaryOp(left, right, op) => {
-//             let left = codegen_expr(left);
-//             let right = codegen_expr(right);
-//             let mut instructions = Vec::new();
-//             instructions.extend(left);
-//             instructions.extend(right);
-//             instructions.push(Instruction::BinaryOp(op.value.clone()));
-//             instructions
-//         }
-//         Expr::Assign(name, value) => {
-//             let value = codegen_expr(value);
-//             let mut instructions = Vec::new();
-//             instructions.extend(value);
-//             match name.as_ref() {
-//                 Expr::Identifier(name) => {
-//                     instructions.push(Instruction::StoreName(name.value.clone()));
-//                 }
-//                 _ => panic!("Invalid assignment"),
-//             };
-//             instructions
-//         }
-//         _ => panic!("Unknown expression"),
-//     }
-// }
-
-// // pub fn codegen_stmt(stmt: &Stmt) -> Vec<Instruction> {
-// //     match stmt {
-// //         Stmt::Expr(expr) => codegen_expr(expr),
-
-// //         Stmt::Return(expr) => {
-// //             let expr = codegen_expr(expr);
-// //             let mut instructions = Vec::new();
-// //             instructions.extend(expr);
-// //             instructions.push(Instruction::Return);
-// //             instructions
-// //         }
-// //         _ => panic!("Unknown statement"),
-// //     }
-// // }
-
-// #[cfg(test)]
-// mod tests {
-
-//     use super::*;
-
-//     use crate::{
-//         parser::parse,
-//         token,
-//         tokenizer::{read_lines, tokenize},
-//     };
-
-//     #[test]
-
-//     fn test_binary_expr() {
-//         let expr = Expr::BinaryOp(
-//             Box::new(Expr::IntLiteral(1)),
-//             Box::new(Expr::IntLiteral(2)),
-//             token::Token {
-//                 token_type: TokenType::Plus,
-//                 value: "+".to_string(),
-//             },
-//         );
-//         let instructions = codegen_expr(&expr);
-//         assert_eq!(
-//             instructions,
-//             vec![
-//                 Instruction::LoadConst(1),
-//                 Instruction::LoadConst(2),
-//                 Instruction::BinaryOp("+".to_string())
-//             ]
-//         );
-//     }
-//     #[test]
-//     fn test_assign() {
-//         let expr = Expr::Assign(
-//             Box::new(Expr::Identifier(token::Token {
-//                 token_type: TokenType::Identifier,
-//                 value: "a".to_string(),
-//             })),
-//             Box::new(Expr::IntLiteral(1)),
-//         );
-//         let instructions = codegen_expr(&expr);
-//         assert_eq!(
-//             instructions,
-//             vec![
-//                 Instruction::LoadConst(1),
-//                 Instruction::StoreName("a".to_string())
-//             ]
-//         );
-//     }
-// }
+//! Compiles a module to code objects.
+//!
+//! The module, and each function and lambda in it, compiles to a
+//! `CodeObject` of its own. The symbol table decides how each name is
+//! reached: a function's locals are fast locals, read by index; a local
+//! that a nested function uses lives in a cell, shared with the closures
+//! made from that function; and other names are looked up by name, in the
+//! module's globals and then in the builtins.
+//!
+//! Jumps are emitted to symbolic labels, which are bound to instructions
+//! as the code is laid out; once a code object is complete, an assembly
+//! pass resolves them to instruction indexes.
+//!
+//! Exception handlers are set up and popped as the code is emitted, much
+//! as CPython's `SETUP_*` and `POP_BLOCK` pseudo-instructions are, but
+//! nothing is emitted for them: the ranges of instructions each covers
+//! make up the code's exception table.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::ast::{
+    Arena, Arguments, BoolOperator, CmpOp, Comprehension, Constant, ExceptHandler, ExprContext,
+    ExprId, ExprKind, Keyword, Module, Span, StmtId, StmtKind, TypeParam, TypeParamKind,
+    UnaryOperator, WithItem,
+};
+use crate::code::{
+    Adaptive, CodeObject, ExceptionEntry, LineTable, CO_GENERATOR, CO_NESTED, CO_NEWLOCALS,
+    CO_OPTIMIZED, CO_VARARGS, CO_VARKEYWORDS,
+};
+use crate::fold::fold;
+use crate::intern::Symbol;
+use crate::intruction::{
+    Instruction, INTRINSIC_PARAMSPEC, INTRINSIC_SET_FUNCTION_TYPE_PARAMS, INTRINSIC_TYPEALIAS,
+    INTRINSIC_TYPEVAR, INTRINSIC_TYPEVARTUPLE, INTRINSIC_TYPEVAR_WITH_BOUND,
+    INTRINSIC_TYPEVAR_WITH_CONSTRAINTS, MAKE_ANNOTATIONS, MAKE_CLOSURE, MAKE_DEFAULTS,
+    MAKE_KWDEFAULTS,
+};
+use crate::parser::ParseError;
+use crate::peephole;
+use crate::symtable::{mangle, symtable, BlockKey, Scope, SymbolTable, Table, TableKind};
+use crate::value::Value;
+use crate::verify::verify;
+
+type CompileResult<T = ()> = Result<T, ParseError>;
+
+/// Compiles `module`, read from `filename`, to the code object that runs
+/// it. The `optimize` level is that of `python -O`: 0 for none, which
+/// keeps `assert` statements and makes `__debug__` true.
+pub fn compile(module: &Module, filename: &str, optimize: u8) -> CompileResult<CodeObject> {
+    let symtable = symtable(module)?;
+    let module = fold(module.clone(), optimize);
+    let module = &module;
+    let mut compiler = Compiler {
+        arena: &module.arena,
+        symtable: &symtable,
+        filename,
+        optimize,
+        units: Vec::new(),
+        private: None,
+    };
+    compiler.enter(symtable.top(), "<module>", 1, None);
+    let mut body = &module.arena[module.body];
+    if let Some(docstring) = compiler.docstring(body) {
+        compiler.load_const(docstring);
+        let doc = compiler.add_name(Symbol::intern("__doc__"));
+        compiler.emit(Instruction::StoreName(doc));
+        body = &body[1..];
+    }
+    for &stmt in body {
+        compiler.stmt(stmt)?;
+    }
+    compiler.load_const(Value::None);
+    compiler.emit(Instruction::ReturnValue);
+    Ok(compiler.exit())
+}
+
+/// A code object being compiled, and the scope it compiles.
+struct Unit<'a> {
+    table: Table<'a>,
+    code: CodeObject,
+    /// The instructions emitted, which are packed into the code once it is
+    /// done.
+    instructions: Vec<Instruction>,
+    /// The source line of each instruction, which make up the code's line
+    /// table once it is done.
+    lines: Vec<usize>,
+    /// The line of the statement or expression being compiled, which the
+    /// instructions emitted for it carry.
+    line: usize,
+    /// The instruction each label is bound to, once it is.
+    labels: Vec<Option<u32>>,
+    /// The blocks around the statement being compiled, innermost last.
+    blocks: Vec<Block<'a>>,
+    /// The exception handlers set up and not yet popped, innermost last.
+    protections: Vec<Protection>,
+    /// The ranges the handlers have covered, innermost first where they
+    /// nest.
+    handlers: Vec<Handler>,
+    /// The index of each constant with a key among the code's constants.
+    const_indexes: HashMap<ConstKey, u32>,
+}
+
+/// A constant's type and value, by which `add_const` finds it among those
+/// added before: `1`, `1.0` and `True` are all different, as they are to
+/// the `==` that constants are compared with.
+#[derive(PartialEq, Eq, Hash)]
+enum ConstKey {
+    None,
+    Bool(bool),
+    Int(i64),
+    Float(u64),
+    Str(Rc<str>),
+    Tuple(Vec<ConstKey>),
+}
+
+impl ConstKey {
+    /// The key of `value`, or `None` for a code object, or a tuple holding
+    /// one, which is found by comparing it with each constant in turn.
+    fn of(value: &Value) -> Option<ConstKey> {
+        Some(match value {
+            Value::None => ConstKey::None,
+            Value::Bool(value) => ConstKey::Bool(*value),
+            Value::Int(value) => ConstKey::Int(*value),
+            Value::Float(value) => ConstKey::Float(value.to_bits()),
+            Value::Str(value) => ConstKey::Str(value.clone()),
+            Value::Tuple(items) => {
+                ConstKey::Tuple(items.iter().map(ConstKey::of).collect::<Option<_>>()?)
+            }
+            _ => return None,
+        })
+    }
+}
+
+/// A place in the code that jumps go to. A jump's target is its label
+/// until the code is assembled.
+#[derive(Debug, Clone, Copy)]
+struct Label(u32);
+
+/// A block around the statement being compiled, which `break`, `continue`
+/// and `return` have to leave properly, as in CPython's compiler.
+#[derive(Clone, Copy)]
+enum Block<'a> {
+    /// A loop: `continue` goes to `start`, and `break` to `end`, past its
+    /// `else` clause. A `for` loop's iterator is on the stack.
+    Loop {
+        start: Label,
+        end: Label,
+        is_for: bool,
+    },
+    /// The body of a `try` statement with handlers.
+    TryExcept,
+    /// The body of a `try` statement with a `finally` clause, which runs
+    /// however the body is left.
+    FinallyTry { finalbody: &'a [StmtId] },
+    /// A `finally` clause run for an exception, which is on the stack over
+    /// the one handled before it.
+    FinallyEnd,
+    /// The body of a `with` statement, its `__exit__` method on the stack.
+    With,
+    /// The `except` clauses of a `try` statement, the exception handled
+    /// before on the stack.
+    ExceptionHandler,
+    /// The body of an `except` clause, with a handler of its own if it
+    /// binds the exception to `name`.
+    HandlerCleanup { name: Option<Symbol> },
+    /// The value being returned, kept on the stack while a `finally`
+    /// clause runs.
+    PopValue,
+}
+
+/// An exception handler, which covers the instructions emitted while it is
+/// open, from `start` on.
+struct Protection {
+    target: Label,
+    /// How many values of the stack the handler keeps.
+    depth: u32,
+    /// Whether the index of the instruction that raised is pushed too.
+    lasti: bool,
+    start: Option<usize>,
+}
+
+/// A range of instructions an exception handler covers.
+struct Handler {
+    start: usize,
+    end: usize,
+    target: Label,
+    depth: u32,
+    lasti: bool,
+}
+
+/// The parameters of a function, as far as its code object records them.
+#[derive(Clone, Copy, Default)]
+struct Signature {
+    argcount: usize,
+    posonlyargcount: usize,
+    kwonlyargcount: usize,
+    flags: u32,
+}
+
+impl Signature {
+    fn of(args: &Arguments) -> Signature {
+        let mut flags = 0;
+        if args.vararg.is_some() {
+            flags |= CO_VARARGS;
+        }
+        if args.kwarg.is_some() {
+            flags |= CO_VARKEYWORDS;
+        }
+        Signature {
+            argcount: args.posonlyargs.len() + args.args.len(),
+            posonlyargcount: args.posonlyargs.len(),
+            kwonlyargcount: args.kwonlyargs.len(),
+            flags,
+        }
+    }
+
+    /// A function of `count` positional parameters, which the compiler
+    /// names itself.
+    fn positional(count: usize) -> Signature {
+        Signature {
+            argcount: count,
+            ..Signature::default()
+        }
+    }
+}
+
+struct Compiler<'a> {
+    arena: &'a Arena,
+    symtable: &'a SymbolTable,
+    filename: &'a str,
+    optimize: u8,
+    /// The code objects being compiled, innermost last.
+    units: Vec<Unit<'a>>,
+    /// The name of the class whose body is being compiled, which private
+    /// names in it are mangled with.
+    private: Option<Symbol>,
+}
+
+impl<'a> Compiler<'a> {
+    fn unit(&mut self) -> &mut Unit<'a> {
+        self.units.last_mut().unwrap()
+    }
+
+    /// Starts compiling the code of a scope, with the parameters of
+    /// `signature` if it is a function or lambda.
+    fn enter(
+        &mut self,
+        table: Table<'a>,
+        name: &str,
+        firstlineno: usize,
+        signature: Option<Signature>,
+    ) {
+        let mut cellvars: Vec<Symbol> = table
+            .symbols()
+            .filter(|binding| binding.scope() == Scope::Cell)
+            .map(|binding| binding.name())
+            .collect();
+        cellvars.sort_by_key(|name| name.as_str());
+        let mut freevars: Vec<Symbol> = table
+            .symbols()
+            .filter(|binding| binding.is_free() || binding.is_free_class())
+            .map(|binding| binding.name())
+            .collect();
+        freevars.sort_by_key(|name| name.as_str());
+        let qualname = self.qualname(name);
+        let mut flags = 0;
+        if let Some(signature) = signature {
+            flags |= CO_OPTIMIZED | CO_NEWLOCALS | signature.flags;
+        }
+        if table.is_nested() {
+            flags |= CO_NESTED;
+        }
+        if table.is_generator() {
+            flags |= CO_GENERATOR;
+        }
+        let Signature {
+            argcount,
+            posonlyargcount,
+            kwonlyargcount,
+            ..
+        } = signature.unwrap_or_default();
+        self.units.push(Unit {
+            table,
+            code: CodeObject {
+                name: name.to_string(),
+                qualname,
+                filename: self.filename.to_string(),
+                firstlineno,
+                argcount,
+                posonlyargcount,
+                kwonlyargcount,
+                flags,
+                stacksize: 0,
+                bytecode: Vec::new(),
+                adaptive: Adaptive::default(),
+                linetable: LineTable::default(),
+                consts: Vec::new(),
+                names: Vec::new(),
+                varnames: table.varnames().to_vec(),
+                cellvars,
+                freevars,
+                exception_table: Vec::new(),
+            },
+            instructions: Vec::new(),
+            lines: Vec::new(),
+            line: firstlineno,
+            labels: Vec::new(),
+            blocks: Vec::new(),
+            protections: Vec::new(),
+            handlers: Vec::new(),
+            const_indexes: HashMap::new(),
+        });
+    }
+
+    fn exit(&mut self) -> CodeObject {
+        let Unit {
+            mut code,
+            mut instructions,
+            lines,
+            labels,
+            handlers,
+            ..
+        } = self.units.pop().unwrap();
+        assemble(&mut code, &mut instructions, &labels, &handlers);
+        code.linetable = LineTable::new(code.firstlineno, &lines);
+        peephole::optimize(&mut code, self.optimize);
+        code.stacksize =
+            verify(&code).unwrap_or_else(|error| panic!("compiled bad code: {}", error));
+        code
+    }
+
+    /// The dotted path to a function from the module: `outer.<locals>.inner`
+    /// for a function defined in another. A function declared global is
+    /// named as if defined at module level.
+    /// The scope of a generic definition's type parameters is left out.
+    fn qualname(&self, name: &str) -> String {
+        let mut parents = self.units.iter().rev();
+        let Some(mut parent) = parents.next() else {
+            return name.to_string();
+        };
+        if parent.table.kind() == TableKind::TypeParams {
+            let Some(grandparent) = parents.next() else {
+                return name.to_string();
+            };
+            parent = grandparent;
+        }
+        if parent.table.kind() == TableKind::Module {
+            return name.to_string();
+        }
+        let declared_global = parent
+            .table
+            .lookup(name)
+            .is_some_and(|binding| binding.scope() == Scope::GlobalExplicit);
+        if declared_global {
+            return name.to_string();
+        }
+        if parent.table.kind() == TableKind::Function {
+            format!("{}.<locals>.{}", parent.code.qualname, name)
+        } else {
+            format!("{}.{}", parent.code.qualname, name)
+        }
+    }
+
+    fn emit(&mut self, instruction: Instruction) -> usize {
+        let unit = self.unit();
+        unit.lines.push(unit.line);
+        unit.instructions.push(instruction);
+        unit.instructions.len() - 1
+    }
+
+    fn new_label(&mut self) -> Label {
+        let labels = &mut self.unit().labels;
+        labels.push(None);
+        Label(labels.len() as u32 - 1)
+    }
+
+    /// Binds `label` to the next instruction emitted.
+    fn bind(&mut self, label: Label) {
+        let unit = self.unit();
+        unit.labels[label.0 as usize] = Some(unit.instructions.len() as u32);
+    }
+
+    /// Emits a jump to `label`, built by one of the jump variants.
+    fn jump(&mut self, jump: fn(u32) -> Instruction, label: Label) {
+        self.emit(jump(label.0));
+    }
+
+    /// How many values the blocks around the statement being compiled keep
+    /// on the stack.
+    fn depth(&mut self) -> u32 {
+        let blocks = &self.unit().blocks;
+        blocks
+            .iter()
+            .map(|block| match block {
+                Block::Loop { is_for, .. } => *is_for as u32,
+                Block::With | Block::ExceptionHandler | Block::PopValue => 1,
+                Block::FinallyEnd => 2,
+                Block::TryExcept | Block::FinallyTry { .. } | Block::HandlerCleanup { .. } => 0,
+            })
+            .sum()
+    }
+
+    /// Sets up `target` to handle exceptions raised by the instructions
+    /// emitted until the handler is popped, with `depth` values of the
+    /// stack kept under the exception, and the index of the instruction
+    /// that raised if `lasti` is set. Returns the handler's level.
+    fn setup(&mut self, target: Label, depth: u32, lasti: bool) -> usize {
+        let unit = self.unit();
+        let start = Some(unit.instructions.len());
+        unit.protections.push(Protection {
+            target,
+            depth,
+            lasti,
+            start,
+        });
+        unit.protections.len() - 1
+    }
+
+    /// Stops the handler at `level` covering the instructions emitted next.
+    fn close(&mut self, level: usize) {
+        let unit = self.unit();
+        let end = unit.instructions.len();
+        let protection = &mut unit.protections[level];
+        if let Some(start) = protection.start.take() {
+            if start < end {
+                unit.handlers.push(Handler {
+                    start,
+                    end,
+                    target: protection.target,
+                    depth: protection.depth,
+                    lasti: protection.lasti,
+                });
+            }
+        }
+    }
+
+    /// Makes the handler at `level` cover the instructions emitted next
+    /// again.
+    fn open(&mut self, level: usize) {
+        let unit = self.unit();
+        unit.protections[level].start = Some(unit.instructions.len());
+    }
+
+    /// Pops the innermost handler.
+    fn pop_block(&mut self) {
+        let level = self.unit().protections.len() - 1;
+        self.close(level);
+        self.unit().protections.pop();
+    }
+
+    /// Closes the innermost handler still open, on the way out of the
+    /// block it protects, and returns its level.
+    fn suspend(&mut self) -> usize {
+        let protections = &self.unit().protections;
+        let level = protections
+            .iter()
+            .rposition(|protection| protection.start.is_some())
+            .expect("no handler to leave");
+        self.close(level);
+        level
+    }
+
+    /// Leaves the blocks above `level`, innermost first, as `break`,
+    /// `continue` and `return` do: pops what they keep on the stack, from
+    /// under the value on top if `preserve_tos` is set, and runs their
+    /// `finally` clauses. Returns the levels of the handlers closed on the
+    /// way, to be opened again once the jump is emitted, as the code after
+    /// it is still in the blocks.
+    fn unwind(&mut self, level: usize, preserve_tos: bool) -> CompileResult<Vec<usize>> {
+        let mut closed = Vec::new();
+        let mut left = Vec::new();
+        let mut result = Ok(());
+        // Each block is left with only those around it in place, which a
+        // `return` in a `finally` clause run here leaves in turn.
+        while self.unit().blocks.len() > level && result.is_ok() {
+            let block = self.unit().blocks.pop().unwrap();
+            left.push(block);
+            result = self.unwind_block(block, preserve_tos, &mut closed);
+        }
+        self.unit().blocks.extend(left.into_iter().rev());
+        result.map(|()| closed)
+    }
+
+    fn unwind_block(
+        &mut self,
+        block: Block<'a>,
+        preserve_tos: bool,
+        closed: &mut Vec<usize>,
+    ) -> CompileResult {
+        let swap = |compiler: &mut Self| {
+            if preserve_tos {
+                compiler.emit(Instruction::RotTwo);
+            }
+        };
+        match block {
+            Block::Loop { is_for: false, .. } | Block::ExceptionHandler => {}
+            Block::Loop { is_for: true, .. } | Block::PopValue => {
+                swap(self);
+                self.emit(Instruction::PopTop);
+            }
+            Block::TryExcept => closed.push(self.suspend()),
+            Block::FinallyTry { finalbody } => {
+                closed.push(self.suspend());
+                if preserve_tos {
+                    self.unit().blocks.push(Block::PopValue);
+                }
+                let line = self.unit().line;
+                for &stmt in finalbody {
+                    self.stmt(stmt)?;
+                }
+                self.unit().line = line;
+                if preserve_tos {
+                    self.unit().blocks.pop();
+                }
+            }
+            Block::FinallyEnd => {
+                swap(self);
+                self.emit(Instruction::PopTop);
+                swap(self);
+                closed.push(self.suspend());
+                self.emit(Instruction::PopExcept);
+            }
+            Block::With => {
+                closed.push(self.suspend());
+                swap(self);
+                self.call_exit_with_nones();
+                self.emit(Instruction::PopTop);
+            }
+            Block::HandlerCleanup { name } => {
+                if name.is_some() {
+                    closed.push(self.suspend());
+                }
+                swap(self);
+                closed.push(self.suspend());
+                self.emit(Instruction::PopExcept);
+                if let Some(name) = name {
+                    self.unbind(name);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Unbinds the name an `except` clause bound the exception to, even if
+    /// the clause deleted it itself.
+    fn unbind(&mut self, name: Symbol) {
+        self.load_const(Value::None);
+        self.name_op(name, ExprContext::Store);
+        self.name_op(name, ExprContext::Del);
+    }
+
+    /// Opens again the handlers [`Compiler::unwind`] closed.
+    fn reopen(&mut self, closed: Vec<usize>) {
+        for level in closed {
+            self.open(level);
+        }
+    }
+
+    /// Calls the `__exit__` method on the stack as a `with` statement is
+    /// left without an exception.
+    fn call_exit_with_nones(&mut self) {
+        for _ in 0..3 {
+            self.load_const(Value::None);
+        }
+        self.emit(Instruction::CallFunction(3));
+    }
+
+    /// The handler that restores the exception handled before when a
+    /// handler itself raises, and raises on.
+    fn pop_except_and_reraise(&mut self) {
+        self.emit(Instruction::Copy(3));
+        self.emit(Instruction::PopExcept);
+        self.emit(Instruction::Reraise(1));
+    }
+
+    fn add_const(&mut self, value: Value) -> u32 {
+        let unit = self.unit();
+        let consts = &mut unit.code.consts;
+        let key = ConstKey::of(&value);
+        let found = match &key {
+            Some(key) => unit.const_indexes.get(key).copied(),
+            None => consts
+                .iter()
+                .position(|c| *c == value)
+                .map(|index| index as u32),
+        };
+        if let Some(index) = found {
+            return index;
+        }
+        let index = consts.len() as u32;
+        consts.push(value);
+        if let Some(key) = key {
+            unit.const_indexes.insert(key, index);
+        }
+        index
+    }
+
+    fn load_const(&mut self, value: Value) {
+        let index = self.add_const(value);
+        self.emit(Instruction::LoadConst(index));
+    }
+
+    fn add_name(&mut self, name: Symbol) -> u32 {
+        add_symbol(&mut self.unit().code.names, name)
+    }
+
+    /// The index of a cell: cell variables come first, then free ones.
+    fn deref_index(&mut self, name: Symbol) -> u32 {
+        let code = &self.unit().code;
+        match code.cellvars.iter().position(|&cell| cell == name) {
+            Some(index) => index as u32,
+            None => {
+                let free = code.freevars.iter().position(|&free| free == name);
+                (code.cellvars.len() + free.expect("free variable")) as u32
+            }
+        }
+    }
+
+    /// Loads, stores or deletes `name`, the way its scope dictates.
+    fn name_op(&mut self, name: Symbol, ctx: ExprContext) {
+        let name = mangle(self.private, name);
+        let table = self.unit().table;
+        let scope = table.lookup(name.as_str()).map(|binding| binding.scope());
+        let function = table.is_optimized();
+        let instruction = match (scope, ctx) {
+            (Some(Scope::Cell | Scope::Free), ExprContext::Load) => {
+                Instruction::LoadDeref(self.deref_index(name))
+            }
+            (Some(Scope::Cell | Scope::Free), ExprContext::Store) => {
+                Instruction::StoreDeref(self.deref_index(name))
+            }
+            (Some(Scope::Local), ExprContext::Load) if function => {
+                Instruction::LoadFast(add_symbol(&mut self.unit().code.varnames, name))
+            }
+            (Some(Scope::Local), ExprContext::Store) if function => {
+                Instruction::StoreFast(add_symbol(&mut self.unit().code.varnames, name))
+            }
+            (Some(Scope::GlobalImplicit), ExprContext::Load) if function => {
+                Instruction::LoadGlobal(self.add_name(name))
+            }
+            (Some(Scope::GlobalExplicit), ExprContext::Load) => {
+                Instruction::LoadGlobal(self.add_name(name))
+            }
+            (Some(Scope::GlobalExplicit), ExprContext::Store) => {
+                Instruction::StoreGlobal(self.add_name(name))
+            }
+            (Some(Scope::Cell | Scope::Free), ExprContext::Del) => {
+                Instruction::DeleteDeref(self.deref_index(name))
+            }
+            (Some(Scope::Local), ExprContext::Del) if function => {
+                Instruction::DeleteFast(add_symbol(&mut self.unit().code.varnames, name))
+            }
+            (Some(Scope::GlobalExplicit), ExprContext::Del) => {
+                Instruction::DeleteGlobal(self.add_name(name))
+            }
+            (_, ExprContext::Load) => Instruction::LoadName(self.add_name(name)),
+            (_, ExprContext::Store) => Instruction::StoreName(self.add_name(name)),
+            (_, ExprContext::Del) => Instruction::DeleteName(self.add_name(name)),
+        };
+        self.emit(instruction);
+    }
+
+    /// The docstring of a body, which is its first statement if that is a
+    /// string.
+    fn docstring(&self, body: &[StmtId]) -> Option<Value> {
+        let StmtKind::Expr { value } = self.arena[*body.first()?].kind else {
+            return None;
+        };
+        match &self.arena[value].kind {
+            ExprKind::Constant {
+                value: Constant::Str(text),
+            } => Some(Value::str(text)),
+            _ => None,
+        }
+    }
+
+    fn stmt(&mut self, stmt: StmtId) -> CompileResult {
+        let arena = self.arena;
+        let span = arena[stmt].span;
+        self.unit().line = span.line;
+        match &arena[stmt].kind {
+            StmtKind::Expr { value } => {
+                // A constant on its own line, a docstring say, does nothing.
+                if !matches!(arena[*value].kind, ExprKind::Constant { .. }) {
+                    self.expr(*value)?;
+                    self.emit(Instruction::PopTop);
+                }
+            }
+            StmtKind::Assign { targets, value } => {
+                self.expr(*value)?;
+                for (i, &target) in arena[*targets].iter().enumerate() {
+                    if i + 1 < targets.len() {
+                        self.emit(Instruction::DupTop);
+                    }
+                    self.store(target)?;
+                }
+            }
+            StmtKind::Delete { targets } => {
+                for &target in &arena[*targets] {
+                    self.delete(target)?;
+                }
+            }
+            StmtKind::AugAssign { target, op, value } => {
+                let ExprKind::Name { id, .. } = arena[*target].kind else {
+                    return Err(unsupported("augmented assignment to this target", span));
+                };
+                self.name_op(id, ExprContext::Load);
+                self.expr(*value)?;
+                self.emit(Instruction::InplaceOp(*op));
+                self.name_op(id, ExprContext::Store);
+            }
+            StmtKind::Return { value } => {
+                // The blocks returned from are left with the value on the
+                // stack, unless it is a constant, loaded after.
+                let preserve_tos = value
+                    .is_some_and(|value| !matches!(arena[value].kind, ExprKind::Constant { .. }));
+                if preserve_tos {
+                    self.expr(value.unwrap())?;
+                }
+                let closed = self.unwind(0, preserve_tos)?;
+                if !preserve_tos {
+                    match value {
+                        Some(value) => self.expr(*value)?,
+                        None => self.load_const(Value::None),
+                    }
+                }
+                self.emit(Instruction::ReturnValue);
+                self.reopen(closed);
+            }
+            StmtKind::FunctionDef { .. } => self.function_def(stmt)?,
+            StmtKind::ClassDef { .. } => self.class_def(stmt)?,
+            StmtKind::TypeAlias { .. } => self.type_alias(stmt)?,
+            StmtKind::For {
+                target,
+                iter,
+                body,
+                orelse,
+            } => {
+                let (start, cleanup, end) = (self.new_label(), self.new_label(), self.new_label());
+                self.expr(*iter)?;
+                self.emit(Instruction::GetIter);
+                self.bind(start);
+                self.jump(Instruction::ForIter, cleanup);
+                self.store(*target)?;
+                self.loop_body(&arena[*body], start, end, true)?;
+                self.jump(Instruction::JumpAbsolute, start);
+                // What runs once the loop is done is on the loop's line.
+                self.unit().line = span.line;
+                self.bind(cleanup);
+                for &stmt in &arena[*orelse] {
+                    self.stmt(stmt)?;
+                }
+                self.bind(end);
+            }
+            StmtKind::While { test, body, orelse } => {
+                // The test comes both before the loop and at the end of each
+                // pass, so that a pass takes one jump, not two.
+                let (start, body_start) = (self.new_label(), self.new_label());
+                let (orelse_start, end) = (self.new_label(), self.new_label());
+                self.bind(start);
+                self.jump_if(*test, false, orelse_start)?;
+                self.bind(body_start);
+                self.loop_body(&arena[*body], start, end, false)?;
+                self.unit().line = span.line;
+                self.jump_if(*test, true, body_start)?;
+                self.bind(orelse_start);
+                for &stmt in &arena[*orelse] {
+                    self.stmt(stmt)?;
+                }
+                self.bind(end);
+            }
+            StmtKind::If { test, body, orelse } => {
+                let end = self.new_label();
+                let next = if orelse.is_empty() {
+                    end
+                } else {
+                    self.new_label()
+                };
+                self.jump_if(*test, false, next)?;
+                for &stmt in &arena[*body] {
+                    self.stmt(stmt)?;
+                }
+                if !orelse.is_empty() {
+                    self.jump(Instruction::JumpAbsolute, end);
+                    self.bind(next);
+                    for &stmt in &arena[*orelse] {
+                        self.stmt(stmt)?;
+                    }
+                }
+                self.bind(end);
+            }
+            StmtKind::Break => {
+                let Some((level, Block::Loop { end, .. })) = self.innermost_loop() else {
+                    return Err(ParseError::new("'break' outside loop", span));
+                };
+                let closed = self.unwind(level, false)?;
+                self.jump(Instruction::JumpAbsolute, end);
+                self.reopen(closed);
+            }
+            StmtKind::Continue => {
+                let Some((level, Block::Loop { start, .. })) = self.innermost_loop() else {
+                    return Err(ParseError::new("'continue' not properly in loop", span));
+                };
+                let closed = self.unwind(level + 1, false)?;
+                self.jump(Instruction::JumpAbsolute, start);
+                self.reopen(closed);
+            }
+            StmtKind::Raise { exc, cause } => match (exc, cause) {
+                (Some(exc), Some(cause)) => {
+                    self.expr(*exc)?;
+                    self.expr(*cause)?;
+                    self.emit(Instruction::RaiseVarargs(2));
+                }
+                (Some(exc), None) => {
+                    self.expr(*exc)?;
+                    self.emit(Instruction::RaiseVarargs(1));
+                }
+                _ => {
+                    self.emit(Instruction::RaiseVarargs(0));
+                }
+            },
+            StmtKind::Try {
+                body,
+                handlers,
+                orelse,
+                finalbody,
+            } => {
+                let (body, handlers) = (&arena[*body], &arena[*handlers]);
+                let orelse = &arena[*orelse];
+                if finalbody.is_empty() {
+                    self.try_except(body, handlers, orelse)?;
+                } else {
+                    self.try_finally(body, handlers, orelse, &arena[*finalbody])?;
+                }
+            }
+            StmtKind::Assert { test, msg } => {
+                let end = self.new_label();
+                self.jump_if(*test, true, end)?;
+                self.emit(Instruction::LoadAssertionError);
+                if let Some(msg) = msg {
+                    self.expr(*msg)?;
+                    self.emit(Instruction::CallFunction(1));
+                }
+                self.emit(Instruction::RaiseVarargs(1));
+                self.bind(end);
+            }
+            StmtKind::With { items, body } => {
+                self.with(&arena[*items], 0, &arena[*body], span.line)?
+            }
+            StmtKind::Global { .. } | StmtKind::Nonlocal { .. } | StmtKind::Pass => {}
+            _ => return Err(unsupported("this statement", span)),
+        }
+        Ok(())
+    }
+
+    /// Compiles the body of a loop, which `continue` leaves for `start` and
+    /// `break` for `end`.
+    fn loop_body(
+        &mut self,
+        body: &[StmtId],
+        start: Label,
+        end: Label,
+        is_for: bool,
+    ) -> CompileResult {
+        self.unit().blocks.push(Block::Loop { start, end, is_for });
+        let result = body.iter().try_for_each(|&stmt| self.stmt(stmt));
+        self.unit().blocks.pop();
+        result
+    }
+
+    /// The innermost loop around the statement being compiled, and its
+    /// level among the blocks.
+    fn innermost_loop(&mut self) -> Option<(usize, Block<'a>)> {
+        let blocks = &self.unit().blocks;
+        let level = blocks
+            .iter()
+            .rposition(|block| matches!(block, Block::Loop { .. }))?;
+        Some((level, blocks[level]))
+    }
+
+    /// Compiles the body of a `try` statement, its `else` clause, run if
+    /// the body raises nothing, and its `except` clauses, which handle
+    /// what the body raises if it is of a class they name, and raise it
+    /// on otherwise.
+    fn try_except(
+        &mut self,
+        body: &[StmtId],
+        handlers: &[ExceptHandler],
+        orelse: &[StmtId],
+    ) -> CompileResult {
+        let (except, cleanup, end) = (self.new_label(), self.new_label(), self.new_label());
+        let depth = self.depth();
+        self.setup(except, depth, false);
+        self.unit().blocks.push(Block::TryExcept);
+        for &stmt in body {
+            self.stmt(stmt)?;
+        }
+        self.unit().blocks.pop();
+        self.pop_block();
+        for &stmt in orelse {
+            self.stmt(stmt)?;
+        }
+        self.jump(Instruction::JumpAbsolute, end);
+
+        // The exception is on the stack, and the one handled before goes
+        // under it, to be restored once it is handled.
+        self.bind(except);
+        let handling = self.setup(cleanup, depth + 1, true);
+        self.emit(Instruction::PushExcInfo);
+        self.unit().blocks.push(Block::ExceptionHandler);
+        for (i, handler) in handlers.iter().enumerate() {
+            self.unit().line = handler.span.line;
+            let next = self.new_label();
+            match handler.type_ {
+                Some(type_) => {
+                    self.expr(type_)?;
+                    self.emit(Instruction::CheckExcMatch);
+                    self.jump(Instruction::PopJumpIfFalse, next);
+                }
+                None if i + 1 < handlers.len() => {
+                    return Err(ParseError::new(
+                        "default 'except:' must be last",
+                        handler.span,
+                    ))
+                }
+                None => {}
+            }
+            // The name is unbound however the clause is left.
+            let cleanup_body = self.new_label();
+            match handler.name {
+                Some(name) => {
+                    self.name_op(name, ExprContext::Store);
+                    self.setup(cleanup_body, depth + 1, true);
+                }
+                None => {
+                    self.emit(Instruction::PopTop);
+                }
+            }
+            let name = handler.name;
+            self.unit().blocks.push(Block::HandlerCleanup { name });
+            for &stmt in &self.arena[handler.body] {
+                self.stmt(stmt)?;
+            }
+            self.unit().blocks.pop();
+            if name.is_some() {
+                self.pop_block();
+            }
+            // The way out of the clause leaves the handler, which still
+            // covers the clauses after it.
+            self.close(handling);
+            self.emit(Instruction::PopExcept);
+            if let Some(name) = name {
+                self.unbind(name);
+            }
+            self.jump(Instruction::JumpAbsolute, end);
+            self.open(handling);
+            if let Some(name) = name {
+                self.bind(cleanup_body);
+                self.unbind(name);
+                self.emit(Instruction::Reraise(1));
+            }
+            self.bind(next);
+        }
+        self.unit().blocks.pop();
+        self.emit(Instruction::Reraise(0));
+        self.pop_block();
+        self.bind(cleanup);
+        self.pop_except_and_reraise();
+        self.bind(end);
+        Ok(())
+    }
+
+    /// Compiles a `try` statement with a `finally` clause, which runs on
+    /// the way out of the rest of the statement however it is left: after
+    /// it, before a jump or `return` out of it, or in a handler, which
+    /// raises the exception on.
+    fn try_finally(
+        &mut self,
+        body: &[StmtId],
+        handlers: &[ExceptHandler],
+        orelse: &[StmtId],
+        finalbody: &'a [StmtId],
+    ) -> CompileResult {
+        let (end, cleanup, exit) = (self.new_label(), self.new_label(), self.new_label());
+        let depth = self.depth();
+        self.setup(end, depth, false);
+        self.unit().blocks.push(Block::FinallyTry { finalbody });
+        if handlers.is_empty() {
+            for &stmt in body {
+                self.stmt(stmt)?;
+            }
+        } else {
+            self.try_except(body, handlers, orelse)?;
+        }
+        self.unit().blocks.pop();
+        self.pop_block();
+        for &stmt in finalbody {
+            self.stmt(stmt)?;
+        }
+        self.jump(Instruction::JumpAbsolute, exit);
+
+        self.bind(end);
+        self.setup(cleanup, depth + 1, true);
+        self.emit(Instruction::PushExcInfo);
+        self.unit().blocks.push(Block::FinallyEnd);
+        for &stmt in finalbody {
+            self.stmt(stmt)?;
+        }
+        self.unit().blocks.pop();
+        self.emit(Instruction::Reraise(0));
+        self.pop_block();
+        self.bind(cleanup);
+        self.pop_except_and_reraise();
+        self.bind(exit);
+        Ok(())
+    }
+
+    /// Compiles the items of a `with` statement from `index` on, each a
+    /// context manager whose `__exit__` method is called on the way out of
+    /// the rest: with `None`s, or with the exception raised, which it
+    /// suppresses by returning true.
+    fn with(
+        &mut self,
+        items: &[WithItem],
+        index: usize,
+        body: &[StmtId],
+        line: usize,
+    ) -> CompileResult {
+        let item = &items[index];
+        let (handler, cleanup) = (self.new_label(), self.new_label());
+        let (suppress, end) = (self.new_label(), self.new_label());
+        let depth = self.depth();
+        self.expr(item.context_expr)?;
+        self.emit(Instruction::BeforeWith);
+        self.setup(handler, depth + 1, true);
+        self.unit().blocks.push(Block::With);
+        match item.optional_vars {
+            Some(target) => self.store(target)?,
+            None => {
+                self.emit(Instruction::PopTop);
+            }
+        }
+        if index + 1 < items.len() {
+            self.with(items, index + 1, body, line)?;
+        } else {
+            for &stmt in body {
+                self.stmt(stmt)?;
+            }
+        }
+        self.unit().blocks.pop();
+        self.pop_block();
+        self.unit().line = line;
+        self.call_exit_with_nones();
+        self.emit(Instruction::PopTop);
+        self.jump(Instruction::JumpAbsolute, end);
+
+        // Under the exception are the index that raised it and `__exit__`.
+        self.bind(handler);
+        let handling = self.setup(cleanup, depth + 3, true);
+        self.emit(Instruction::PushExcInfo);
+        self.emit(Instruction::WithExceptStart);
+        self.jump(Instruction::PopJumpIfTrue, suppress);
+        self.emit(Instruction::Reraise(2));
+        self.close(handling);
+        self.bind(cleanup);
+        self.pop_except_and_reraise();
+        self.bind(suppress);
+        self.open(handling);
+        self.emit(Instruction::PopTop);
+        self.pop_block();
+        self.emit(Instruction::PopExcept);
+        self.emit(Instruction::PopTop);
+        self.emit(Instruction::PopTop);
+        self.bind(end);
+        Ok(())
+    }
+
+    /// Compiles a test that jumps to `label` if `expr` is true, when `cond`
+    /// is set, or if it is false otherwise, and goes on to the next
+    /// instruction if not. Like [`Compiler::expr`], it keeps the test's
+    /// line.
+    fn jump_if(&mut self, expr: ExprId, cond: bool, label: Label) -> CompileResult {
+        let line = self.unit().line;
+        self.unit().line = self.arena[expr].span.line;
+        let result = self.compile_jump_if(expr, cond, label);
+        self.unit().line = line;
+        result
+    }
+
+    fn compile_jump_if(&mut self, expr: ExprId, cond: bool, label: Label) -> CompileResult {
+        let arena = self.arena;
+        match &arena[expr].kind {
+            ExprKind::UnaryOp {
+                op: UnaryOperator::Not,
+                operand,
+            } => return self.jump_if(*operand, !cond, label),
+            ExprKind::BoolOp { op, values } => {
+                // Each operand but the last decides the test only when it
+                // is true for `or` and false for `and`.
+                let decides = *op == BoolOperator::Or;
+                let next = if cond == decides {
+                    label
+                } else {
+                    self.new_label()
+                };
+                let (last, rest) = arena[*values].split_last().unwrap();
+                for &value in rest {
+                    self.jump_if(value, decides, next)?;
+                }
+                self.jump_if(*last, cond, label)?;
+                if cond != decides {
+                    self.bind(next);
+                }
+                return Ok(());
+            }
+            ExprKind::IfExp { test, body, orelse } => {
+                let (next, end) = (self.new_label(), self.new_label());
+                self.jump_if(*test, false, next)?;
+                self.jump_if(*body, cond, label)?;
+                self.jump(Instruction::JumpAbsolute, end);
+                self.bind(next);
+                self.jump_if(*orelse, cond, label)?;
+                self.bind(end);
+                return Ok(());
+            }
+            ExprKind::Compare {
+                left,
+                ops,
+                comparators,
+            } if ops.len() > 1 => {
+                // As for the value of a chain, but a link that fails pops
+                // what is left and goes straight to the false branch.
+                let cleanup = self.new_label();
+                self.expr(*left)?;
+                let (last, rest) = arena[*comparators].split_last().unwrap();
+                for (&op, &right) in arena[*ops].iter().zip(rest) {
+                    self.expr(right)?;
+                    self.emit(Instruction::DupTop);
+                    self.emit(Instruction::RotThree);
+                    self.compare_op(op);
+                    self.jump(Instruction::PopJumpIfFalse, cleanup);
+                }
+                self.expr(*last)?;
+                self.compare_op(*arena[*ops].last().unwrap());
+                let end = self.new_label();
+                self.jump(pop_jump_if(cond), label);
+                self.jump(Instruction::JumpAbsolute, end);
+                self.bind(cleanup);
+                self.emit(Instruction::PopTop);
+                if !cond {
+                    self.jump(Instruction::JumpAbsolute, label);
+                }
+                self.bind(end);
+                return Ok(());
+            }
+            _ => {}
+        }
+        self.expr(expr)?;
+        self.jump(pop_jump_if(cond), label);
+        Ok(())
+    }
+
+    fn compare_op(&mut self, op: CmpOp) {
+        self.emit(match op {
+            CmpOp::Is => Instruction::IsOp(false),
+            CmpOp::IsNot => Instruction::IsOp(true),
+            CmpOp::In => Instruction::ContainsOp(false),
+            CmpOp::NotIn => Instruction::ContainsOp(true),
+            _ => Instruction::CompareOp(op),
+        });
+    }
+
+    /// Stores the value on top of the stack into `target`.
+    fn store(&mut self, target: ExprId) -> CompileResult {
+        let span = self.arena[target].span;
+        match &self.arena[target].kind {
+            ExprKind::Name { id, .. } => {
+                self.name_op(*id, ExprContext::Store);
+                Ok(())
+            }
+            ExprKind::Attribute { value, attr, .. } => {
+                self.expr(*value)?;
+                let attr = self.add_name(mangle(self.private, *attr));
+                self.emit(Instruction::StoreAttr(attr));
+                Ok(())
+            }
+            ExprKind::Tuple { elts, .. } | ExprKind::List { elts, .. } => {
+                self.emit(Instruction::UnpackSequence(elts.len() as u32));
+                for &elt in &self.arena[*elts] {
+                    self.store(elt)?;
+                }
+                Ok(())
+            }
+            _ => Err(unsupported("assignment to this target", span)),
+        }
+    }
+
+    /// Deletes `target`, the elements in turn of a tuple or list.
+    fn delete(&mut self, target: ExprId) -> CompileResult {
+        let span = self.arena[target].span;
+        match &self.arena[target].kind {
+            ExprKind::Name { id, .. } => {
+                self.name_op(*id, ExprContext::Del);
+                Ok(())
+            }
+            ExprKind::Attribute { value, attr, .. } => {
+                self.expr(*value)?;
+                let attr = self.add_name(mangle(self.private, *attr));
+                self.emit(Instruction::DeleteAttr(attr));
+                Ok(())
+            }
+            ExprKind::Subscript { value, slice, .. } => {
+                self.expr(*value)?;
+                self.expr(*slice)?;
+                self.emit(Instruction::DeleteSubscr);
+                Ok(())
+            }
+            ExprKind::Tuple { elts, .. } | ExprKind::List { elts, .. } => {
+                let arena = self.arena;
+                arena[*elts].iter().try_for_each(|&elt| self.delete(elt))
+            }
+            _ => Err(unsupported("deletion of this target", span)),
+        }
+    }
+
+    /// Compiles an expression, whose instructions carry its line; those
+    /// after it carry the enclosing expression's line again.
+    fn expr(&mut self, expr: ExprId) -> CompileResult {
+        let line = self.unit().line;
+        self.unit().line = self.arena[expr].span.line;
+        let result = self.compile_expr(expr);
+        self.unit().line = line;
+        result
+    }
+
+    fn compile_expr(&mut self, expr: ExprId) -> CompileResult {
+        let arena = self.arena;
+        let span = arena[expr].span;
+        match &arena[expr].kind {
+            ExprKind::Constant { value } => {
+                let Some(value) = Value::from_constant(value) else {
+                    return Err(unsupported("this constant", span));
+                };
+                self.load_const(value);
+            }
+            ExprKind::Name { id, ctx } => self.name_op(*id, *ctx),
+            ExprKind::BinOp { left, op, right } => {
+                self.expr(*left)?;
+                self.expr(*right)?;
+                self.emit(Instruction::BinaryOp(*op));
+            }
+            ExprKind::UnaryOp { op, operand } => {
+                self.expr(*operand)?;
+                self.emit(Instruction::UnaryOp(*op));
+            }
+            ExprKind::Compare {
+                left,
+                ops,
+                comparators,
+            } => {
+                // `a < b < c` compares `a < b`, keeping a copy of `b` under
+                // the result; if the result is false, it is the value, and
+                // the copy is popped from under it.
+                let cleanup = self.new_label();
+                self.expr(*left)?;
+                let (last, rest) = arena[*comparators].split_last().unwrap();
+                for (&op, &right) in arena[*ops].iter().zip(rest) {
+                    self.expr(right)?;
+                    self.emit(Instruction::DupTop);
+                    self.emit(Instruction::RotThree);
+                    self.compare_op(op);
+                    self.jump(Instruction::JumpIfFalseOrPop, cleanup);
+                }
+                self.expr(*last)?;
+                self.compare_op(*arena[*ops].last().unwrap());
+                if !rest.is_empty() {
+                    let end = self.new_label();
+                    self.jump(Instruction::JumpAbsolute, end);
+                    self.bind(cleanup);
+                    self.emit(Instruction::RotTwo);
+                    self.emit(Instruction::PopTop);
+                    self.bind(end);
+                }
+            }
+            ExprKind::BoolOp { op, values } => {
+                let end = self.new_label();
+                let jump = match op {
+                    BoolOperator::And => Instruction::JumpIfFalseOrPop,
+                    BoolOperator::Or => Instruction::JumpIfTrueOrPop,
+                };
+                let (last, rest) = arena[*values].split_last().unwrap();
+                for &value in rest {
+                    self.expr(value)?;
+                    self.jump(jump, end);
+                }
+                self.expr(*last)?;
+                self.bind(end);
+            }
+            ExprKind::IfExp { test, body, orelse } => {
+                let (next, end) = (self.new_label(), self.new_label());
+                self.jump_if(*test, false, next)?;
+                self.expr(*body)?;
+                self.jump(Instruction::JumpAbsolute, end);
+                self.bind(next);
+                self.expr(*orelse)?;
+                self.bind(end);
+            }
+            ExprKind::Call {
+                func,
+                args,
+                keywords,
+            } => self.call(*func, &arena[*args], &arena[*keywords])?,
+            ExprKind::Lambda { args, body } => {
+                let table = self.symtable.get(BlockKey::Expr(expr)).unwrap();
+                if table.is_generator() {
+                    return Err(unsupported("generator", span));
+                }
+                let flags = self.default_arguments(args)?;
+                self.enter(table, "<lambda>", span.line, Some(Signature::of(args)));
+                // A lambda has no docstring; its first constant says so.
+                self.add_const(Value::None);
+                self.expr(*body)?;
+                self.emit(Instruction::ReturnValue);
+                let code = self.exit();
+                self.make_closure(code, flags);
+            }
+            ExprKind::ListComp { elt, generators } => {
+                self.comprehension(expr, "<listcomp>", &arena[*generators], *elt, None)?
+            }
+            ExprKind::SetComp { elt, generators } => {
+                self.comprehension(expr, "<setcomp>", &arena[*generators], *elt, None)?
+            }
+            ExprKind::DictComp {
+                key,
+                value,
+                generators,
+            } => {
+                let generators = &arena[*generators];
+                self.comprehension(expr, "<dictcomp>", generators, *key, Some(*value))?
+            }
+            // A generator needs a frame that can be suspended and resumed,
+            // which the VM does not have, so generator expressions are
+            // rejected like generator functions.
+            ExprKind::GeneratorExp { .. } => return Err(unsupported("generator expression", span)),
+            ExprKind::Dict { keys, values } => {
+                for (key, &value) in arena[*keys].iter().zip(&arena[*values]) {
+                    let Some(key) = key else {
+                        return Err(unsupported("unpacking", arena[value].span));
+                    };
+                    self.expr(*key)?;
+                    self.expr(value)?;
+                }
+                self.emit(Instruction::BuildMap(values.len() as u32));
+            }
+            ExprKind::Tuple { elts, .. } | ExprKind::List { elts, .. } | ExprKind::Set { elts } => {
+                for &elt in &arena[*elts] {
+                    if let ExprKind::Starred { .. } = arena[elt].kind {
+                        return Err(unsupported("unpacking", arena[elt].span));
+                    }
+                    self.expr(elt)?;
+                }
+                let count = elts.len() as u32;
+                self.emit(match arena[expr].kind {
+                    ExprKind::Tuple { .. } => Instruction::BuildTuple(count),
+                    ExprKind::Set { .. } => Instruction::BuildSet(count),
+                    _ => Instruction::BuildList(count),
+                });
+            }
+            ExprKind::Attribute { value, attr, .. } => {
+                self.expr(*value)?;
+                let attr = self.add_name(mangle(self.private, *attr));
+                self.emit(Instruction::LoadAttr(attr));
+            }
+            ExprKind::Subscript { value, slice, .. } => {
+                if let ExprKind::Slice { .. } = arena[*slice].kind {
+                    return Err(unsupported("slicing", span));
+                }
+                self.expr(*value)?;
+                self.expr(*slice)?;
+                self.emit(Instruction::BinarySubscr);
+            }
+            ExprKind::Starred { .. } => return Err(unsupported("unpacking", span)),
+            _ => return Err(unsupported("this expression", span)),
+        }
+        Ok(())
+    }
+
+    /// Calls `func` with positional arguments and then keyword ones, named
+    /// by a tuple of constants.
+    fn call(&mut self, func: ExprId, args: &[ExprId], keywords: &[Keyword]) -> CompileResult {
+        self.expr(func)?;
+        let starred = args
+            .iter()
+            .any(|&arg| matches!(self.arena[arg].kind, ExprKind::Starred { .. }));
+        if starred || keywords.iter().any(|keyword| keyword.arg.is_none()) {
+            return self.call_ex(args, keywords);
+        }
+        for &arg in args {
+            self.expr(arg)?;
+        }
+        let mut names = Vec::new();
+        for keyword in keywords {
+            let name = keyword.arg.unwrap();
+            self.expr(keyword.value)?;
+            names.push(Value::str(name.as_str()));
+        }
+        let count = (args.len() + keywords.len()) as u32;
+        if names.is_empty() {
+            self.emit(Instruction::CallFunction(count));
+        } else {
+            self.load_const(Value::tuple(names));
+            self.emit(Instruction::CallFunctionKw(count));
+        }
+        Ok(())
+    }
+
+    /// Calls the function on the stack with `*` or `**` arguments, as
+    /// CPython does: the positional arguments gathered into a tuple, and
+    /// the keyword ones into a dict, merged with each `**` mapping in turn.
+    fn call_ex(&mut self, args: &[ExprId], keywords: &[Keyword]) -> CompileResult {
+        let starred = |arg: ExprId| match self.arena[arg].kind {
+            ExprKind::Starred { value, .. } => Some(value),
+            _ => None,
+        };
+        match args {
+            [] => self.load_const(Value::tuple(Vec::new())),
+            &[arg] if starred(arg).is_some() => self.expr(starred(arg).unwrap())?,
+            _ if args.iter().all(|&arg| starred(arg).is_none()) => {
+                for &arg in args {
+                    self.expr(arg)?;
+                }
+                self.emit(Instruction::BuildTuple(args.len() as u32));
+            }
+            _ => {
+                let mut list = false;
+                for (index, &arg) in args.iter().enumerate() {
+                    match starred(arg) {
+                        Some(value) => {
+                            if !list {
+                                self.emit(Instruction::BuildList(index as u32));
+                                list = true;
+                            }
+                            self.expr(value)?;
+                            self.emit(Instruction::ListExtend(1));
+                        }
+                        None => {
+                            self.expr(arg)?;
+                            if list {
+                                self.emit(Instruction::ListAppend(1));
+                            }
+                        }
+                    }
+                }
+                self.emit(Instruction::ListToTuple);
+            }
+        }
+        // Runs of named arguments make dicts of their own, which merge
+        // into the first like the `**` mappings between them.
+        let mut dict = false;
+        let mut start = 0;
+        for (index, keyword) in keywords.iter().enumerate() {
+            if keyword.arg.is_some() {
+                continue;
+            }
+            if start < index {
+                self.keyword_dict(&keywords[start..index], dict)?;
+                dict = true;
+            }
+            if !dict {
+                self.emit(Instruction::BuildMap(0));
+                dict = true;
+            }
+            self.expr(keyword.value)?;
+            self.emit(Instruction::DictMerge(1));
+            start = index + 1;
+        }
+        if start < keywords.len() {
+            self.keyword_dict(&keywords[start..], dict)?;
+            dict = true;
+        }
+        self.emit(Instruction::CallFunctionEx(dict as u32));
+        Ok(())
+    }
+
+    /// Makes a dict of named arguments, merged into the one under it if
+    /// `merge` is set.
+    fn keyword_dict(&mut self, keywords: &[Keyword], merge: bool) -> CompileResult {
+        let names: Vec<Value> = keywords
+            .iter()
+            .map(|keyword| Value::str(keyword.arg.unwrap().as_str()))
+            .collect();
+        let count = keywords.len() as u32;
+        if let [name] = &names[..] {
+            self.load_const(name.clone());
+            self.expr(keywords[0].value)?;
+            self.emit(Instruction::BuildMap(1));
+        } else {
+            for keyword in keywords {
+                self.expr(keyword.value)?;
+            }
+            self.load_const(Value::tuple(names));
+            self.emit(Instruction::BuildConstKeyMap(count));
+        }
+        if merge {
+            self.emit(Instruction::DictMerge(1));
+        }
+        Ok(())
+    }
+
+    /// Evaluates the defaults of a function's parameters, as it is defined:
+    /// a tuple of those of the positional parameters and a dict of those
+    /// of the keyword-only ones. Returns the flags of `MakeFunction` for
+    /// the values pushed.
+    fn default_arguments(&mut self, args: &Arguments) -> CompileResult<u32> {
+        let mut flags = 0;
+        let arena = self.arena;
+        if !args.defaults.is_empty() {
+            for &default in &arena[args.defaults] {
+                self.expr(default)?;
+            }
+            self.emit(Instruction::BuildTuple(args.defaults.len() as u32));
+            flags |= MAKE_DEFAULTS;
+        }
+        let mut names = Vec::new();
+        for (arg, default) in arena[args.kwonlyargs].iter().zip(&arena[args.kw_defaults]) {
+            if let Some(default) = default {
+                self.expr(*default)?;
+                names.push(Value::str(mangle(self.private, arg.arg).as_str()));
+            }
+        }
+        if !names.is_empty() {
+            let count = names.len() as u32;
+            self.load_const(Value::tuple(names));
+            self.emit(Instruction::BuildConstKeyMap(count));
+            flags |= MAKE_KWDEFAULTS;
+        }
+        Ok(flags)
+    }
+
+    /// Evaluates a function's annotations, as it is defined, into a tuple
+    /// of each parameter's name followed by its annotation, and `return`
+    /// followed by the result's. Returns the flag of `MakeFunction` for
+    /// the tuple, if there is one.
+    fn annotations(&mut self, args: &Arguments, returns: Option<ExprId>) -> CompileResult<u32> {
+        // CPython's order, which puts positional-only parameters after
+        // the others.
+        let arena = self.arena;
+        let params = arena[args.args]
+            .iter()
+            .chain(&arena[args.posonlyargs])
+            .chain(&args.vararg)
+            .chain(&arena[args.kwonlyargs])
+            .chain(&args.kwarg);
+        let private = self.private;
+        let annotated = params
+            .filter_map(|arg| Some((mangle(private, arg.arg).as_str(), arg.annotation?)))
+            .chain(returns.map(|returns| ("return", returns)));
+        let mut count = 0;
+        for (name, annotation) in annotated {
+            self.load_const(Value::str(name));
+            self.expr(annotation)?;
+            count += 1;
+        }
+        if count == 0 {
+            return Ok(0);
+        }
+        self.emit(Instruction::BuildTuple(2 * count));
+        Ok(MAKE_ANNOTATIONS)
+    }
+
+    /// Compiles a list, set or dict comprehension, which runs as a function
+    /// of its own, called with an iterator over the first iterable. A dict
+    /// comprehension has a `value` for each key, `elt`.
+    fn comprehension(
+        &mut self,
+        expr: ExprId,
+        name: &str,
+        generators: &[Comprehension],
+        elt: ExprId,
+        value: Option<ExprId>,
+    ) -> CompileResult {
+        let span = self.arena[expr].span;
+        if generators.iter().any(|generator| generator.is_async) {
+            return Err(unsupported("asynchronous comprehension", span));
+        }
+        // The first iterator is the only argument, `.0`.
+        let table = self.symtable.get(BlockKey::Expr(expr)).unwrap();
+        self.enter(table, name, span.line, Some(Signature::positional(1)));
+        let (build, add): (_, fn(u32) -> Instruction) = match self.arena[expr].kind {
+            ExprKind::ListComp { .. } => (Instruction::BuildList(0), Instruction::ListAppend),
+            ExprKind::SetComp { .. } => (Instruction::BuildSet(0), Instruction::SetAdd),
+            _ => (Instruction::BuildMap(0), Instruction::MapAdd),
+        };
+        self.emit(build);
+        self.comprehension_generator(generators, 0, elt, value, add)?;
+        self.emit(Instruction::ReturnValue);
+        let code = self.exit();
+        self.make_closure(code, 0);
+        self.expr(generators[0].iter)?;
+        self.emit(Instruction::GetIter);
+        self.emit(Instruction::CallFunction(1));
+        Ok(())
+    }
+
+    /// Compiles the loop of the generator at `index` of a comprehension,
+    /// with those after it nested inside, and at the heart the item added
+    /// to the result, which is under each loop's iterator on the stack, by
+    /// the `add` instruction.
+    fn comprehension_generator(
+        &mut self,
+        generators: &[Comprehension],
+        index: usize,
+        elt: ExprId,
+        value: Option<ExprId>,
+        add: fn(u32) -> Instruction,
+    ) -> CompileResult {
+        let generator = &generators[index];
+        let (start, next, end) = (self.new_label(), self.new_label(), self.new_label());
+        if index == 0 {
+            // The first iterator is the argument.
+            self.emit(Instruction::LoadFast(0));
+        } else {
+            self.expr(generator.iter)?;
+            self.emit(Instruction::GetIter);
+        }
+        self.bind(start);
+        self.jump(Instruction::ForIter, end);
+        self.store(generator.target)?;
+        for &condition in &self.arena[generator.ifs] {
+            self.jump_if(condition, false, next)?;
+        }
+        if index + 1 < generators.len() {
+            self.comprehension_generator(generators, index + 1, elt, value, add)?;
+        } else {
+            let depth = generators.len() as u32 + 1;
+            self.expr(elt)?;
+            if let Some(value) = value {
+                self.expr(value)?;
+            }
+            self.emit(add(depth));
+        }
+        self.bind(next);
+        self.jump(Instruction::JumpAbsolute, start);
+        self.bind(end);
+        Ok(())
+    }
+
+    fn function_def(&mut self, stmt: StmtId) -> CompileResult {
+        let arena = self.arena;
+        let span = arena[stmt].span;
+        let StmtKind::FunctionDef {
+            name,
+            args,
+            body,
+            decorator_list,
+            returns,
+            type_params,
+        } = &arena[stmt].kind
+        else {
+            unreachable!()
+        };
+        let table = self.symtable.get(BlockKey::Stmt(stmt)).unwrap();
+        if table.is_generator() {
+            return Err(unsupported("generator", span));
+        }
+        for &decorator in &arena[*decorator_list] {
+            self.expr(decorator)?;
+        }
+        let mut flags = self.default_arguments(args)?;
+        // A generic function is made by a function of its type parameters,
+        // which takes the defaults as its arguments.
+        let generic = !type_params.is_empty();
+        if generic {
+            let defaults: Vec<Symbol> = [
+                (MAKE_DEFAULTS, ".defaults"),
+                (MAKE_KWDEFAULTS, ".kwdefaults"),
+            ]
+            .into_iter()
+            .filter(|&(flag, _)| flags & flag != 0)
+            .map(|(_, name)| Symbol::intern(name))
+            .collect();
+            self.enter_type_params(stmt, *name, defaults.len())?;
+            self.type_params(stmt, &arena[*type_params])?;
+            for &param in &defaults {
+                self.name_op(param, ExprContext::Load);
+            }
+        }
+        flags |= self.annotations(args, *returns)?;
+        self.enter(table, name.as_str(), span.line, Some(Signature::of(args)));
+        // The first constant is the docstring, or None if there is none.
+        let docstring = self.docstring(&arena[*body]).unwrap_or(Value::None);
+        self.add_const(docstring);
+        for &stmt in &arena[*body] {
+            self.stmt(stmt)?;
+        }
+        // Falling off the end returns None; the peephole optimizer drops
+        // this if nothing can reach it.
+        self.load_const(Value::None);
+        self.emit(Instruction::ReturnValue);
+        let code = self.exit();
+        self.make_closure(code, flags);
+        if generic {
+            self.emit(Instruction::RotTwo);
+            self.emit(Instruction::CallIntrinsic2(
+                INTRINSIC_SET_FUNCTION_TYPE_PARAMS,
+            ));
+            let count = (flags & (MAKE_DEFAULTS | MAKE_KWDEFAULTS)).count_ones();
+            self.call_type_params(count);
+        }
+        for _ in 0..decorator_list.len() {
+            self.emit(Instruction::CallFunction(1));
+        }
+        self.name_op(*name, ExprContext::Store);
+        Ok(())
+    }
+
+    /// Compiles a class definition: its body runs as a function, which
+    /// `__build_class__` calls to make the class from the names it binds.
+    fn class_def(&mut self, stmt: StmtId) -> CompileResult {
+        let arena = self.arena;
+        let span = arena[stmt].span;
+        let StmtKind::ClassDef {
+            name,
+            bases,
+            keywords,
+            body,
+            decorator_list,
+            type_params,
+        } = &arena[stmt].kind
+        else {
+            unreachable!()
+        };
+        if !bases.is_empty() || !keywords.is_empty() {
+            return Err(unsupported("class bases", span));
+        }
+        let table = self.symtable.get(BlockKey::Stmt(stmt)).unwrap();
+        if table.needs_class_closure() {
+            return Err(unsupported("'__class__'", span));
+        }
+        for &decorator in &arena[*decorator_list] {
+            self.expr(decorator)?;
+        }
+        // A generic class is made by a function of its type parameters, and
+        // keeps them as `__type_params__`. It gets no `Generic` base, as
+        // classes have no bases here.
+        let generic = !type_params.is_empty();
+        if generic {
+            self.enter_type_params(stmt, *name, 0)?;
+            let private = self.private.replace(*name);
+            let result = self.type_params(stmt, &arena[*type_params]);
+            self.private = private;
+            result?;
+            self.name_op(Symbol::intern(".type_params"), ExprContext::Store);
+        }
+        self.emit(Instruction::LoadBuildClass);
+        self.enter(table, name.as_str(), span.line, None);
+        let private = self.private.replace(*name);
+        let result = self.class_body(&arena[*body], generic);
+        self.private = private;
+        result?;
+        let code = self.exit();
+        self.make_closure(code, 0);
+        self.load_const(Value::str(name.as_str()));
+        self.emit(Instruction::CallFunction(2));
+        if generic {
+            self.call_type_params(0);
+        }
+        for _ in 0..decorator_list.len() {
+            self.emit(Instruction::CallFunction(1));
+        }
+        self.name_op(*name, ExprContext::Store);
+        Ok(())
+    }
+
+    /// The body of a class, which starts by binding `__module__` and
+    /// `__qualname__`, and `__type_params__` if it is `generic`, as in
+    /// CPython.
+    fn class_body(&mut self, body: &[StmtId], generic: bool) -> CompileResult {
+        let module = self.add_name(Symbol::intern("__name__"));
+        self.emit(Instruction::LoadName(module));
+        let module = self.add_name(Symbol::intern("__module__"));
+        self.emit(Instruction::StoreName(module));
+        let qualname = Value::str(&self.unit().code.qualname);
+        self.load_const(qualname);
+        let qualname = self.add_name(Symbol::intern("__qualname__"));
+        self.emit(Instruction::StoreName(qualname));
+        if generic {
+            self.name_op(Symbol::intern(".type_params"), ExprContext::Load);
+            let type_params = self.add_name(Symbol::intern("__type_params__"));
+            self.emit(Instruction::StoreName(type_params));
+        }
+        let mut body = body;
+        if let Some(docstring) = self.docstring(body) {
+            self.load_const(docstring);
+            let doc = self.add_name(Symbol::intern("__doc__"));
+            self.emit(Instruction::StoreName(doc));
+            body = &body[1..];
+        }
+        for &stmt in body {
+            self.stmt(stmt)?;
+        }
+        self.load_const(Value::None);
+        self.emit(Instruction::ReturnValue);
+        Ok(())
+    }
+
+    /// Compiles a `type` statement, which binds a type alias whose value is
+    /// a function of its own, only called when the value is asked for.
+    fn type_alias(&mut self, stmt: StmtId) -> CompileResult {
+        let arena = self.arena;
+        let span = arena[stmt].span;
+        let StmtKind::TypeAlias {
+            name,
+            type_params,
+            value,
+        } = &arena[stmt].kind
+        else {
+            unreachable!()
+        };
+        let ExprKind::Name { id: name, .. } = arena[*name].kind else {
+            unreachable!()
+        };
+        if self.unit().table.kind() == TableKind::Class {
+            return Err(unsupported("a type alias in a class body", span));
+        }
+        let generic = !type_params.is_empty();
+        if generic {
+            self.enter_type_params(stmt, name, 0)?;
+        }
+        self.load_const(Value::str(name.as_str()));
+        if generic {
+            self.type_params(stmt, &arena[*type_params])?;
+        } else {
+            self.load_const(Value::None);
+        }
+        let table = self.symtable.get(BlockKey::Stmt(stmt)).unwrap();
+        self.enter(table, name.as_str(), span.line, Some(Signature::default()));
+        self.expr(*value)?;
+        self.emit(Instruction::ReturnValue);
+        let code = self.exit();
+        self.make_closure(code, 0);
+        self.emit(Instruction::BuildTuple(3));
+        self.emit(Instruction::CallIntrinsic1(INTRINSIC_TYPEALIAS));
+        if generic {
+            self.call_type_params(0);
+        }
+        self.name_op(name, ExprContext::Store);
+        Ok(())
+    }
+
+    /// Enters the function of the type parameters of the generic
+    /// definition `stmt`, which takes `argcount` arguments.
+    fn enter_type_params(&mut self, stmt: StmtId, name: Symbol, argcount: usize) -> CompileResult {
+        let span = self.arena[stmt].span;
+        // A class body's names would need to be seen from the scope too.
+        if self.unit().table.kind() == TableKind::Class {
+            return Err(unsupported("a generic definition in a class body", span));
+        }
+        let table = self.symtable.get(BlockKey::TypeParams(stmt)).unwrap();
+        let name = format!("<generic parameters of {}>", name);
+        self.enter(
+            table,
+            &name,
+            span.line,
+            Some(Signature::positional(argcount)),
+        );
+        Ok(())
+    }
+
+    /// Makes each of the type parameters of `stmt`, binding it to its
+    /// name, and leaves a tuple of them.
+    fn type_params(&mut self, stmt: StmtId, type_params: &[TypeParam]) -> CompileResult {
+        for (i, type_param) in type_params.iter().enumerate() {
+            let name = type_param.name();
+            self.load_const(Value::str(name.as_str()));
+            match type_param.kind {
+                TypeParamKind::TypeVar {
+                    bound: Some(bound), ..
+                } => {
+                    // The bound is a function of its own, only called when
+                    // the bound is asked for.
+                    let table = self.symtable.get(BlockKey::TypeVarBound(stmt, i)).unwrap();
+                    let line = type_param.span.line;
+                    self.enter(table, name.as_str(), line, Some(Signature::default()));
+                    self.expr(bound)?;
+                    self.emit(Instruction::ReturnValue);
+                    let code = self.exit();
+                    self.make_closure(code, 0);
+                    let function = match self.arena[bound].kind {
+                        ExprKind::Tuple { .. } => INTRINSIC_TYPEVAR_WITH_CONSTRAINTS,
+                        _ => INTRINSIC_TYPEVAR_WITH_BOUND,
+                    };
+                    self.emit(Instruction::CallIntrinsic2(function));
+                }
+                TypeParamKind::TypeVar { bound: None, .. } => {
+                    self.emit(Instruction::CallIntrinsic1(INTRINSIC_TYPEVAR));
+                }
+                TypeParamKind::ParamSpec { .. } => {
+                    self.emit(Instruction::CallIntrinsic1(INTRINSIC_PARAMSPEC));
+                }
+                TypeParamKind::TypeVarTuple { .. } => {
+                    self.emit(Instruction::CallIntrinsic1(INTRINSIC_TYPEVARTUPLE));
+                }
+            }
+            self.emit(Instruction::DupTop);
+            self.name_op(name, ExprContext::Store);
+        }
+        self.emit(Instruction::BuildTuple(type_params.len() as u32));
+        Ok(())
+    }
+
+    /// Returns what is on the stack from the function of type parameters,
+    /// and calls the function with the `count` values under it.
+    fn call_type_params(&mut self, count: u32) {
+        self.emit(Instruction::ReturnValue);
+        let code = self.exit();
+        self.make_closure(code, 0);
+        match count {
+            0 => {}
+            1 => {
+                self.emit(Instruction::RotTwo);
+            }
+            _ => {
+                self.emit(Instruction::RotThree);
+            }
+        }
+        self.emit(Instruction::CallFunction(count));
+    }
+
+    /// Makes a function from `code`, passing it the cells of the enclosing
+    /// scope that it uses, and the values that `flags` says are already on
+    /// the stack.
+    fn make_closure(&mut self, code: CodeObject, mut flags: u32) {
+        if !code.freevars.is_empty() {
+            for &name in &code.freevars {
+                let index = self.deref_index(name);
+                self.emit(Instruction::LoadClosure(index));
+            }
+            self.emit(Instruction::BuildTuple(code.freevars.len() as u32));
+            flags |= MAKE_CLOSURE;
+        }
+        let qualname = Value::str(&code.qualname);
+        self.load_const(Value::Code(Rc::new(code)));
+        self.load_const(qualname);
+        self.emit(Instruction::MakeFunction(flags));
+    }
+}
+
+fn pop_jump_if(cond: bool) -> fn(u32) -> Instruction {
+    if cond {
+        Instruction::PopJumpIfTrue
+    } else {
+        Instruction::PopJumpIfFalse
+    }
+}
+
+/// Resolves the labels that the jumps of `instructions` go to into the
+/// indexes of the instructions they are bound to, packs them as the
+/// instructions of `code`, and makes its exception table of the ranges its
+/// handlers covered. The table gives each instruction its innermost
+/// handler, with no ranges overlapping, as CPython's does.
+fn assemble(
+    code: &mut CodeObject,
+    instructions: &mut [Instruction],
+    labels: &[Option<u32>],
+    handlers: &[Handler],
+) {
+    let resolve = |label: u32| labels[label as usize].expect("jump to an unbound label");
+    for instruction in instructions.iter_mut() {
+        if let Some(label) = instruction.jump_target() {
+            *instruction = instruction.with_jump_target(resolve(label));
+        }
+    }
+    code.set_instructions(instructions);
+    let mut innermost = vec![None; instructions.len()];
+    for handler in handlers {
+        for slot in &mut innermost[handler.start..handler.end] {
+            slot.get_or_insert((resolve(handler.target.0), handler.depth, handler.lasti));
+        }
+    }
+    let mut start = 0;
+    for run in innermost.chunk_by(|a, b| a == b) {
+        if let Some((target, depth, lasti)) = run[0] {
+            code.exception_table.push(ExceptionEntry {
+                start: start as u32,
+                end: (start + run.len()) as u32,
+                target,
+                depth,
+                lasti,
+            });
+        }
+        start += run.len();
+    }
+}
+
+fn add_symbol(symbols: &mut Vec<Symbol>, name: Symbol) -> u32 {
+    let index = match symbols.iter().position(|&symbol| symbol == name) {
+        Some(index) => index,
+        None => {
+            symbols.push(name);
+            symbols.len() - 1
+        }
+    };
+    index as u32
+}
+
+fn unsupported(what: &str, span: Span) -> ParseError {
+    ParseError::new(format!("{} is not supported yet", what), span)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::compile;
+    use crate::ast::{CmpOp, Operator};
+    use crate::code::{CodeObject, ExceptionEntry};
+    use crate::intern::Symbol;
+    use crate::intruction::Instruction::*;
+    use crate::intruction::{
+        INTRINSIC_SET_FUNCTION_TYPE_PARAMS, INTRINSIC_TYPEALIAS, INTRINSIC_TYPEVAR, MAKE_DEFAULTS,
+    };
+    use crate::parser::parse;
+    use crate::value::Value;
+
+    fn build(source: &str) -> CodeObject {
+        compile(&parse(source).unwrap(), "<test>", 0).unwrap()
+    }
+
+    fn names(names: &[Symbol]) -> Vec<&'static str> {
+        names.iter().map(|name| name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_compile_closure() {
+        let module = build("def f():\n    x = 1\n    def g():\n        return x\n    return g\n");
+        let Value::Code(f) = &module.consts[0] else {
+            panic!("expected a code object, got {:?}", module.consts[0]);
+        };
+        assert_eq!(
+            f.instructions(),
+            [
+                LoadConst(1),
+                StoreDeref(0),
+                LoadClosure(0),
+                BuildTuple(1),
+                LoadConst(2),
+                LoadConst(3),
+                MakeFunction(8),
+                StoreFast(0),
+                LoadFast(0),
+                ReturnValue,
+            ]
+        );
+        assert_eq!(f.consts[0], Value::None);
+        assert_eq!(f.consts[1], Value::Int(1));
+        assert_eq!(f.consts[3], Value::str("f.<locals>.g"));
+        assert_eq!(names(&f.varnames), ["g"]);
+        assert_eq!(names(&f.cellvars), ["x"]);
+        assert!(f.freevars.is_empty());
+
+        let Value::Code(g) = &f.consts[2] else {
+            panic!("expected a code object, got {:?}", f.consts[2]);
+        };
+        assert_eq!(g.instructions(), [LoadDeref(0), ReturnValue]);
+        assert_eq!(g.qualname, "f.<locals>.g");
+        assert_eq!(g.firstlineno, 3);
+        assert_eq!(names(&g.freevars), ["x"]);
+        assert!(g.cellvars.is_empty());
+    }
+
+    #[test]
+    fn test_compile_comprehension() {
+        let module = build("z = [x for x in y if x]\n");
+        assert_eq!(
+            module.instructions(),
+            [
+                LoadConst(0),
+                LoadConst(1),
+                MakeFunction(0),
+                LoadName(0),
+                GetIter,
+                CallFunction(1),
+                StoreName(1),
+                LoadConst(2),
+                ReturnValue,
+            ]
+        );
+        let Value::Code(listcomp) = &module.consts[0] else {
+            panic!("expected a code object, got {:?}", module.consts[0]);
+        };
+        assert_eq!(
+            listcomp.instructions(),
+            [
+                BuildList(0),
+                LoadFast(0),
+                ForIter(9),
+                StoreFast(1),
+                LoadFast(1),
+                PopJumpIfFalse(2),
+                LoadFast(1),
+                ListAppend(2),
+                JumpAbsolute(2),
+                ReturnValue,
+            ]
+        );
+        assert_eq!(listcomp.qualname, "<listcomp>");
+        assert_eq!(names(&listcomp.varnames), [".0", "x"]);
+        assert_eq!(listcomp.argcount, 1);
+
+        let module = build("z = {x for x in y}\n");
+        let Value::Code(setcomp) = &module.consts[0] else {
+            panic!("expected a code object, got {:?}", module.consts[0]);
+        };
+        assert_eq!(
+            setcomp.instructions(),
+            [
+                BuildSet(0),
+                LoadFast(0),
+                ForIter(7),
+                StoreFast(1),
+                LoadFast(1),
+                SetAdd(2),
+                JumpAbsolute(2),
+                ReturnValue,
+            ]
+        );
+        assert_eq!(setcomp.qualname, "<setcomp>");
+
+        let error = compile(&parse("z = (x for x in y)\n").unwrap(), "<test>", 0);
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "generator expression is not supported yet (line 1)"
+        );
+    }
+
+    #[test]
+    fn test_compile_defaults() {
+        // Defaults and annotations are evaluated where the function is
+        // defined.
+        let module = build("def f(a: 'A', b=1, *, c=2) -> 'R':\n    pass\n");
+        assert_eq!(
+            module.instructions(),
+            [
+                LoadConst(0),
+                BuildTuple(1),
+                LoadConst(1),
+                LoadConst(2),
+                BuildConstKeyMap(1),
+                LoadConst(3),
+                LoadConst(4),
+                LoadConst(5),
+                LoadConst(6),
+                BuildTuple(4),
+                LoadConst(7),
+                LoadConst(8),
+                MakeFunction(7),
+                StoreName(0),
+                LoadConst(9),
+                ReturnValue,
+            ]
+        );
+        assert_eq!(module.consts[2], Value::tuple(vec![Value::str("c")]));
+        assert_eq!(module.consts[3], Value::str("a"));
+        assert_eq!(module.consts[5], Value::str("return"));
+    }
+
+    #[test]
+    fn test_compile_name_ops() {
+        // Module-level names go by name, a function's locals are fast, and
+        // names it only reads are global.
+        let module = build("a = 1\ndef f(p):\n    global b\n    b = p\n    return a\n");
+        assert_eq!(module.instructions()[..2], [LoadConst(0), StoreName(0)]);
+        let Value::Code(f) = &module.consts[1] else {
+            panic!("expected a code object, got {:?}", module.consts[1]);
+        };
+        assert_eq!(
+            f.instructions(),
+            [LoadFast(0), StoreGlobal(0), LoadGlobal(1), ReturnValue]
+        );
+        assert_eq!(names(&f.names), ["b", "a"]);
+        assert_eq!(f.argcount, 1);
+    }
+
+    #[test]
+    fn test_compile_folds_constants() {
+        let module = build(&std::fs::read_to_string("tests/var.py").unwrap());
+        assert_eq!(
+            module.instructions(),
+            [
+                LoadConst(0),
+                StoreName(0),
+                LoadConst(1),
+                StoreName(1),
+                LoadConst(2),
+                StoreName(2),
+                LoadConst(3),
+                StoreName(3),
+                LoadName(0),
+                LoadName(1),
+                BinaryOp(Operator::Add),
+                StoreName(4),
+                LoadConst(4),
+                ReturnValue,
+            ]
+        );
+        assert_eq!(
+            module.consts,
+            [
+                Value::Int(3),
+                Value::Int(7),
+                Value::Int(6),
+                Value::Float(3.7),
+                Value::None
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_dedupes_constants() {
+        let module =
+            build("a = 1, 1.0, True, (1, 2), (1.0, 2), -0.0, 0.0\nb = 1.0, (1, 2), True\n");
+        assert_eq!(
+            module.consts,
+            [
+                Value::tuple(vec![
+                    Value::Int(1),
+                    Value::Float(1.0),
+                    Value::Bool(true),
+                    Value::tuple(vec![Value::Int(1), Value::Int(2)]),
+                    Value::tuple(vec![Value::Float(1.0), Value::Int(2)]),
+                    Value::Float(-0.0),
+                    Value::Float(0.0),
+                ]),
+                Value::tuple(vec![
+                    Value::Float(1.0),
+                    Value::tuple(vec![Value::Int(1), Value::Int(2)]),
+                    Value::Bool(true),
+                ]),
+                Value::None,
+            ]
+        );
+        let module = build("x = 1\nx = 2.5\nx = 1\nx = 'a'\nx = 2.5\nx = True\n");
+        assert_eq!(
+            module.consts,
+            [
+                Value::Int(1),
+                Value::Float(2.5),
+                Value::str("a"),
+                Value::Bool(true),
+                Value::None
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_lines() {
+        // The lines are those CPython 3.11 gives.
+        let module = build("x = (1 +\n     f())\nfor i in y:\n    z = i\n");
+        assert_eq!(
+            module.instructions(),
+            [
+                LoadConst(0),
+                LoadName(0),
+                CallFunction(0),
+                BinaryOp(Operator::Add),
+                StoreName(1),
+                LoadName(2),
+                GetIter,
+                ForIter(12),
+                StoreName(3),
+                LoadName(3),
+                StoreName(4),
+                JumpAbsolute(7),
+                LoadConst(1),
+                ReturnValue,
+            ]
+        );
+        assert_eq!(module.lines(), [1, 2, 2, 1, 1, 3, 3, 3, 3, 4, 4, 4, 3, 3]);
+    }
+
+    #[test]
+    fn test_compile_code_attributes() {
+        // The flags are CPython's. Its stack sizes are bigger where it
+        // pushes a NULL before a call, and smaller where it takes a
+        // function's qualified name from its code instead of the stack.
+        let module = build(
+            "def f(a, b):\n    x = (a, b, [a, b, a])\n    def g():\n        return lambda: x\n    \
+             return g\nfor i in range(3):\n    print(i, i)\n",
+        );
+        assert_eq!((module.flags, module.stacksize), (0, 4));
+        let Value::Code(f) = &module.consts[0] else {
+            panic!("expected a code object, got {:?}", module.consts[0]);
+        };
+        assert_eq!((f.flags, f.stacksize, f.argcount), (3, 5, 2));
+        let Value::Code(g) = &f.consts[1] else {
+            panic!("expected a code object, got {:?}", f.consts[1]);
+        };
+        assert_eq!((g.flags, g.stacksize, g.argcount), (19, 3, 0));
+        let Value::Code(lambda) = &g.consts[1] else {
+            panic!("expected a code object, got {:?}", g.consts[1]);
+        };
+        assert_eq!((lambda.flags, lambda.stacksize), (19, 1));
+        assert_eq!(lambda.lines(), [4, 4]);
+        assert_eq!(lambda.linetable.as_bytes(), [2, 0]);
+    }
+
+    #[test]
+    fn test_compile_while() {
+        let module = build(&std::fs::read_to_string("tests/while.py").unwrap());
+        assert_eq!(
+            module.instructions(),
+            [
+                LoadConst(0),
+                StoreName(0),
+                LoadName(0),
+                LoadConst(1),
+                CompareOp(CmpOp::Lt),
+                PopJumpIfFalse(19),
+                LoadName(0),
+                LoadConst(2),
+                CompareOp(CmpOp::Eq),
+                PopJumpIfFalse(11),
+                JumpAbsolute(19),
+                LoadName(0),
+                LoadConst(3),
+                BinaryOp(Operator::Add),
+                StoreName(0),
+                LoadName(0),
+                LoadConst(1),
+                CompareOp(CmpOp::Lt),
+                PopJumpIfTrue(6),
+                LoadName(1),
+                LoadName(0),
+                CallFunction(1),
+                PopTop,
+                LoadConst(4),
+                ReturnValue,
+            ]
+        );
+        assert_eq!(
+            module.lines(),
+            [1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 5, 5, 5, 5, 2, 2, 2, 2, 7, 7, 7, 7, 7, 7]
+        );
+    }
+
+    #[test]
+    fn test_compile_try() {
+        // No instruction sets up the handler: the exception table covers
+        // the body, and then the handler itself, for its cleanup.
+        let module = build("try:\n    f()\nexcept E:\n    g()\n");
+        assert_eq!(
+            module.instructions(),
+            [
+                LoadName(0),
+                CallFunction(0),
+                PopTop,
+                JumpAbsolute(18),
+                PushExcInfo,
+                LoadName(1),
+                CheckExcMatch,
+                PopJumpIfFalse(14),
+                PopTop,
+                LoadName(2),
+                CallFunction(0),
+                PopTop,
+                PopExcept,
+                JumpAbsolute(18),
+                Reraise(0),
+                Copy(3),
+                PopExcept,
+                Reraise(1),
+                LoadConst(0),
+                ReturnValue,
+            ]
+        );
+        let entry = |start, end, target, depth, lasti| ExceptionEntry {
+            start,
+            end,
+            target,
+            depth,
+            lasti,
+        };
+        assert_eq!(
+            module.exception_table,
+            [
+                entry(0, 3, 4, 0, false),
+                entry(4, 12, 15, 1, true),
+                entry(14, 15, 15, 1, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_fib() {
+        // As in tests/fib.output.
+        let module = build(&std::fs::read_to_string("tests/fib.py").unwrap());
+        let Value::Code(fib) = &module.consts[0] else {
+            panic!("expected a code object, got {:?}", module.consts[0]);
+        };
+        assert_eq!(
+            fib.instructions(),
+            [
+                LoadFast(0),
+                LoadConst(1),
+                CompareOp(CmpOp::Lt),
+                PopJumpIfFalse(6),
+                LoadFast(0),
+                ReturnValue,
+                LoadGlobal(0),
+                LoadFast(0),
+                LoadConst(2),
+                BinaryOp(Operator::Sub),
+                CallFunction(1),
+                LoadGlobal(0),
+                LoadFast(0),
+                LoadConst(1),
+                BinaryOp(Operator::Sub),
+                CallFunction(1),
+                BinaryOp(Operator::Add),
+                ReturnValue,
+            ]
+        );
+        assert_eq!(fib.consts, [Value::None, Value::Int(2), Value::Int(1)]);
+        assert_eq!(
+            fib.lines(),
+            [2, 2, 2, 2, 3, 3, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4]
+        );
+    }
+
+    #[test]
+    fn test_compile_chained_comparison() {
+        let module = build("x = a < b < c\n");
+        assert_eq!(
+            module.instructions(),
+            [
+                LoadName(0),
+                LoadName(1),
+                DupTop,
+                RotThree,
+                CompareOp(CmpOp::Lt),
+                JumpIfFalseOrPop(9),
+                LoadName(2),
+                CompareOp(CmpOp::Lt),
+                JumpAbsolute(11),
+                RotTwo,
+                PopTop,
+                StoreName(3),
+                LoadConst(0),
+                ReturnValue,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_assert_and_del() {
+        let module = build("assert x, 'm'\ndel x, y.a, y[0]\n");
+        assert_eq!(
+            module.instructions(),
+            [
+                LoadName(0),
+                PopJumpIfTrue(6),
+                LoadAssertionError,
+                LoadConst(0),
+                CallFunction(1),
+                RaiseVarargs(1),
+                DeleteName(0),
+                LoadName(1),
+                DeleteAttr(2),
+                LoadName(1),
+                LoadConst(1),
+                DeleteSubscr,
+                LoadConst(2),
+                ReturnValue,
+            ]
+        );
+        let module = build("def f(a):\n    global g\n    del a, g\n");
+        let Value::Code(f) = &module.consts[0] else {
+            panic!("expected a code object, got {:?}", module.consts[0]);
+        };
+        assert_eq!(
+            f.instructions(),
+            [DeleteFast(0), DeleteGlobal(0), LoadConst(0), ReturnValue]
+        );
+    }
+
+    #[test]
+    fn test_compile_star_calls() {
+        // CPython's layout: a list for the positional arguments once one
+        // is starred, and a dict that each `**` mapping merges into.
+        let module = build("f(1, *a, 2, x=3, **k, y=4, z=5)\n");
+        assert_eq!(
+            module.instructions(),
+            [
+                LoadName(0),
+                LoadConst(0),
+                BuildList(1),
+                LoadName(1),
+                ListExtend(1),
+                LoadConst(1),
+                ListAppend(1),
+                ListToTuple,
+                LoadConst(2),
+                LoadConst(3),
+                BuildMap(1),
+                LoadName(2),
+                DictMerge(1),
+                LoadConst(4),
+                LoadConst(5),
+                LoadConst(6),
+                BuildConstKeyMap(2),
+                DictMerge(1),
+                CallFunctionEx(1),
+                PopTop,
+                LoadConst(7),
+                ReturnValue,
+            ]
+        );
+        assert_eq!(
+            module.consts[6],
+            Value::tuple(vec![Value::str("y"), Value::str("z")])
+        );
+        let module = build("f(*a)\nf(**k)\n");
+        assert_eq!(
+            module.instructions(),
+            [
+                LoadName(0),
+                LoadName(1),
+                CallFunctionEx(0),
+                PopTop,
+                LoadName(0),
+                LoadConst(0),
+                BuildMap(0),
+                LoadName(2),
+                DictMerge(1),
+                CallFunctionEx(1),
+                PopTop,
+                LoadConst(1),
+                ReturnValue,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_type_params() {
+        // The value of an alias is a function of its own, and a generic
+        // function is made by a function of its type parameters.
+        let module = build("type X = y\ndef f[T](a=1): pass\n");
+        assert_eq!(
+            module.instructions(),
+            [
+                LoadConst(0),
+                LoadConst(1),
+                LoadConst(2),
+                LoadConst(0),
+                MakeFunction(0),
+                BuildTuple(3),
+                CallIntrinsic1(INTRINSIC_TYPEALIAS),
+                StoreName(0),
+                LoadConst(3),
+                BuildTuple(1),
+                LoadConst(4),
+                LoadConst(5),
+                MakeFunction(0),
+                RotTwo,
+                CallFunction(1),
+                StoreName(1),
+                LoadConst(1),
+                ReturnValue,
+            ]
+        );
+        assert_eq!(module.consts[5], Value::str("<generic parameters of f>"));
+        let Value::Code(params) = &module.consts[4] else {
+            panic!("expected a code object, got {:?}", module.consts[4]);
+        };
+        assert_eq!(
+            params.instructions(),
+            [
+                LoadConst(0),
+                CallIntrinsic1(INTRINSIC_TYPEVAR),
+                DupTop,
+                StoreFast(1),
+                BuildTuple(1),
+                LoadFast(0),
+                LoadConst(1),
+                LoadConst(2),
+                MakeFunction(MAKE_DEFAULTS),
+                RotTwo,
+                CallIntrinsic2(INTRINSIC_SET_FUNCTION_TYPE_PARAMS),
+                ReturnValue,
+            ]
+        );
+        assert_eq!(names(&params.varnames), [".defaults", "T"]);
+        assert_eq!(params.argcount, 1);
+        assert_eq!(params.consts[2], Value::str("f"));
+        for (source, message) in [
+            (
+                "class C:\n    type X = int\n",
+                "a type alias in a class body",
+            ),
+            (
+                "class C:\n    def f[T](): pass\n",
+                "a generic definition in a class body",
+            ),
+        ] {
+            let error = compile(&parse(source).unwrap(), "<test>", 0).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!("{} is not supported yet (line 2)", message)
+            );
+        }
+    }
+
+    #[test]
+    fn test_compile_unsupported() {
+        let error = compile(&parse("x = 1\nimport os\n").unwrap(), "<test>", 0);
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "this statement is not supported yet (line 2)"
+        );
+        let error = compile(
+            &parse("for x in y:\n    pass\nbreak\n").unwrap(),
+            "<test>",
+            0,
+        );
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "'break' outside loop (line 3)"
+        );
+        let error = compile(&parse("def f():\n    continue\n").unwrap(), "<test>", 0);
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "'continue' not properly in loop (line 2)"
+        );
+    }
+}
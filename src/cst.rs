@@ -0,0 +1,544 @@
+//! A lossless concrete syntax tree.
+//!
+//! Every byte of the source belongs to a token or to the trivia attached to
+//! one, so writing the tree back out reproduces the file exactly. Trivia on
+//! the same line after a token, up to and including the line break, is that
+//! token's trailing trivia; everything else (indentation, blank lines,
+//! comments on their own lines) leads the next token. Whatever follows the
+//! last token leads the final `EndMarker`.
+//!
+//! The tree is laid over an AST parsed from the same source: each node
+//! stands for a module, statement, expression, parameter or except clause,
+//! found from its span, and [`SyntaxNode::ast`] gives back the AST node.
+//! Tokens that belong to no smaller node, such as keywords, operators and
+//! brackets, sit in the nearest enclosing one.
+
+use std::fmt;
+use std::iter::Peekable;
+use std::vec::IntoIter;
+
+use crate::ast::{
+    Arena, Arg, ExceptHandler, Expr, ExprId, Module, Stmt, StmtId, StmtKind, TypeParam,
+};
+use crate::tokenizer::{tokenize, SourceRef, Token, TokenType};
+use crate::visitor::{
+    walk_arg, walk_except_handler, walk_expr, walk_module, walk_stmt, walk_type_param, Visitor,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriviaKind {
+    /// Spaces, tabs, form feeds and carriage returns.
+    Whitespace,
+    /// A `#` comment, without its line break.
+    Comment,
+    /// A line break that does not end a logical line: a blank line, or a
+    /// line break inside brackets.
+    Newline,
+    /// A backslash joining two physical lines, with its line break.
+    Continuation,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trivia<'source> {
+    pub kind: TriviaKind,
+    pub source_ref: SourceRef<'source>,
+}
+
+impl Trivia<'_> {
+    pub fn value(&self) -> &str {
+        self.source_ref.value()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxToken<'source> {
+    pub token: Token<'source>,
+    pub leading: Vec<Trivia<'source>>,
+    pub trailing: Vec<Trivia<'source>>,
+}
+
+impl fmt::Display for SyntaxToken<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for trivia in &self.leading {
+            f.write_str(trivia.value())?;
+        }
+        f.write_str(self.token.value())?;
+        for trivia in &self.trailing {
+            f.write_str(trivia.value())?;
+        }
+        Ok(())
+    }
+}
+
+/// The AST node a [`SyntaxNode`] stands for.
+#[derive(Debug, Clone, Copy)]
+pub enum AstNode<'ast> {
+    Module(&'ast Module),
+    Stmt(StmtId, &'ast Stmt),
+    Expr(ExprId, &'ast Expr),
+    Arg(&'ast Arg),
+    TypeParam(&'ast TypeParam),
+    ExceptHandler(&'ast ExceptHandler),
+}
+
+#[derive(Debug, Clone)]
+pub enum SyntaxElement<'a> {
+    Node(SyntaxNode<'a>),
+    Token(SyntaxToken<'a>),
+}
+
+#[derive(Debug, Clone)]
+pub struct SyntaxNode<'a> {
+    ast: AstNode<'a>,
+    pub children: Vec<SyntaxElement<'a>>,
+}
+
+impl<'a> SyntaxNode<'a> {
+    pub fn ast(&self) -> AstNode<'a> {
+        self.ast
+    }
+
+    pub fn as_stmt(&self) -> Option<&'a Stmt> {
+        match self.ast {
+            AstNode::Stmt(_, stmt) => Some(stmt),
+            _ => None,
+        }
+    }
+
+    pub fn as_expr(&self) -> Option<&'a Expr> {
+        match self.ast {
+            AstNode::Expr(_, expr) => Some(expr),
+            _ => None,
+        }
+    }
+
+    pub fn child_nodes(&self) -> impl Iterator<Item = &SyntaxNode<'a>> {
+        self.children.iter().filter_map(|child| match child {
+            SyntaxElement::Node(node) => Some(node),
+            SyntaxElement::Token(_) => None,
+        })
+    }
+
+    /// All tokens under this node, in source order.
+    pub fn tokens(&self) -> Vec<&SyntaxToken<'a>> {
+        let mut tokens = Vec::new();
+        self.collect_tokens(&mut tokens);
+        tokens
+    }
+
+    fn collect_tokens<'s>(&'s self, tokens: &mut Vec<&'s SyntaxToken<'a>>) {
+        for child in &self.children {
+            match child {
+                SyntaxElement::Node(node) => node.collect_tokens(tokens),
+                SyntaxElement::Token(token) => tokens.push(token),
+            }
+        }
+    }
+
+    /// The source text from the node's first token to its last, without
+    /// the trivia around them.
+    pub fn text(&self) -> &'a str {
+        let tokens = self.tokens();
+        match (tokens.first(), tokens.last()) {
+            (Some(first), Some(last)) => {
+                &first.token.source_ref.source[first.token.start()..last.token.end()]
+            }
+            _ => "",
+        }
+    }
+}
+
+impl fmt::Display for SyntaxNode<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for child in &self.children {
+            match child {
+                SyntaxElement::Node(node) => write!(f, "{}", node)?,
+                SyntaxElement::Token(token) => write!(f, "{}", token)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Builds the concrete syntax tree of `source`, which `module` must have
+/// been parsed from.
+pub fn build<'a>(source: &'a str, module: &'a Module) -> SyntaxNode<'a> {
+    let mut tokens = syntax_tokens(source).into_iter().peekable();
+    Shape::new(AstNode::Module(module), &module.arena, source).build(&mut tokens, true)
+}
+
+/// The tokens the parser sees, less the zero-width ones, with the text
+/// between them split into trivia. Indentation is left as trivia.
+fn syntax_tokens(source: &str) -> Vec<SyntaxToken<'_>> {
+    let (tokens, _) = tokenize(source);
+    let mut result: Vec<SyntaxToken> = Vec::new();
+    let mut position = 0;
+    let mut line = 1;
+    for token in tokens {
+        let is_end = token.token_type == TokenType::EndMarker;
+        if !is_end && (token.length() == 0 || token.token_type == TokenType::Indent) {
+            continue;
+        }
+        let start = if is_end { source.len() } else { token.start() };
+        let mut trivia = split_trivia(source, position, start, line).into_iter();
+        if let Some(previous) = result.last_mut() {
+            if previous.token.token_type != TokenType::Newline {
+                for piece in trivia.by_ref() {
+                    let ends_line =
+                        matches!(piece.kind, TriviaKind::Newline | TriviaKind::Continuation);
+                    previous.trailing.push(piece);
+                    if ends_line {
+                        break;
+                    }
+                }
+            }
+        }
+        line = token.line() + token.value().matches('\n').count();
+        position = token.end().max(start);
+        result.push(SyntaxToken {
+            token: if is_end {
+                Token::endmarker(start, token.line(), source)
+            } else {
+                token
+            },
+            leading: trivia.collect(),
+            trailing: Vec::new(),
+        });
+    }
+    result
+}
+
+/// Splits `source[start..end]`, which holds no tokens, into trivia.
+fn split_trivia(source: &str, start: usize, end: usize, mut line: usize) -> Vec<Trivia<'_>> {
+    let mut trivia = Vec::new();
+    let mut position = start;
+    while position < end {
+        let rest = &source[position..end];
+        let (kind, length) = if rest.starts_with('#') {
+            let length = rest.find(['\r', '\n']).unwrap_or(rest.len());
+            (TriviaKind::Comment, length)
+        } else if rest.starts_with('\\') {
+            let length = if rest.starts_with("\\\r\n") { 3 } else { 2 };
+            (TriviaKind::Continuation, length.min(rest.len()))
+        } else if rest.starts_with("\r\n") {
+            (TriviaKind::Newline, 2)
+        } else if rest.starts_with('\n') {
+            (TriviaKind::Newline, 1)
+        } else {
+            let length = rest
+                .find(|c: char| !matches!(c, ' ' | '\t' | '\x0c' | '\r'))
+                .unwrap_or(rest.len())
+                .max(1);
+            (TriviaKind::Whitespace, length)
+        };
+        trivia.push(Trivia {
+            kind,
+            source_ref: SourceRef::new(source, line, position, position + length),
+        });
+        if matches!(kind, TriviaKind::Newline | TriviaKind::Continuation) {
+            line += 1;
+        }
+        position += length;
+    }
+    trivia
+}
+
+/// An AST node with the byte range its tokens lie in: its own span,
+/// widened to cover its children.
+struct Shape<'a> {
+    ast: AstNode<'a>,
+    start: usize,
+    end: usize,
+    children: Vec<Shape<'a>>,
+}
+
+impl<'a> Shape<'a> {
+    fn new(ast: AstNode<'a>, arena: &'a Arena, source: &'a str) -> Shape<'a> {
+        let mut collector = Children {
+            source,
+            children: Vec::new(),
+        };
+        let (start, end) = match ast {
+            AstNode::Module(module) => {
+                walk_module(&mut collector, module);
+                (0, source.len())
+            }
+            AstNode::Stmt(id, stmt) => {
+                walk_stmt(&mut collector, arena, id);
+                (stmt_start(source, arena, stmt), stmt.span.end)
+            }
+            AstNode::Expr(id, expr) => {
+                walk_expr(&mut collector, arena, id);
+                (expr.span.start, expr.span.end)
+            }
+            AstNode::Arg(arg) => {
+                walk_arg(&mut collector, arena, arg);
+                (arg.span.start, arg.span.end)
+            }
+            AstNode::TypeParam(type_param) => {
+                walk_type_param(&mut collector, arena, type_param);
+                (type_param.span.start, type_param.span.end)
+            }
+            AstNode::ExceptHandler(handler) => {
+                walk_except_handler(&mut collector, arena, handler);
+                (handler.span.start, handler.span.end)
+            }
+        };
+        let mut children = collector.children;
+        children.sort_by_key(|child| child.start);
+        Shape {
+            ast,
+            start: children
+                .iter()
+                .map(|child| child.start)
+                .fold(start, usize::min),
+            end: children.iter().map(|child| child.end).fold(end, usize::max),
+            children,
+        }
+    }
+
+    /// Takes the tokens that start inside this node off the front of
+    /// `tokens`; the root takes all of them.
+    fn build(self, tokens: &mut Peekable<IntoIter<SyntaxToken<'a>>>, root: bool) -> SyntaxNode<'a> {
+        let mut children = self.children.into_iter().peekable();
+        let mut elements = Vec::new();
+        while let Some(token) = tokens.peek() {
+            let start = token.token.start();
+            if !root && start >= self.end {
+                break;
+            }
+            if let Some(child) = children.next_if(|child| child.start <= start) {
+                elements.push(SyntaxElement::Node(child.build(tokens, false)));
+            } else {
+                elements.push(SyntaxElement::Token(tokens.next().unwrap()));
+            }
+        }
+        elements.extend(children.map(|child| SyntaxElement::Node(child.build(tokens, false))));
+        SyntaxNode {
+            ast: self.ast,
+            children: elements,
+        }
+    }
+}
+
+/// Where a statement's tokens start. The span of a decorated definition
+/// starts at `def`, `async` or `class`, after the decorators and their `@`s.
+fn stmt_start(source: &str, arena: &Arena, stmt: &Stmt) -> usize {
+    let decorators = match &stmt.kind {
+        StmtKind::FunctionDef { decorator_list, .. }
+        | StmtKind::AsyncFunctionDef { decorator_list, .. }
+        | StmtKind::ClassDef { decorator_list, .. } => decorator_list,
+        _ => return stmt.span.start,
+    };
+    match arena[*decorators].first() {
+        Some(&first) => {
+            let start = arena[first].span.start;
+            source[..start].rfind('@').unwrap_or(start)
+        }
+        None => stmt.span.start,
+    }
+}
+
+/// Collects the nodes directly below the one being walked.
+struct Children<'a> {
+    source: &'a str,
+    children: Vec<Shape<'a>>,
+}
+
+impl<'a> Visitor<'a> for Children<'a> {
+    fn visit_stmt(&mut self, arena: &'a Arena, stmt: StmtId) {
+        let ast = AstNode::Stmt(stmt, &arena[stmt]);
+        self.children.push(Shape::new(ast, arena, self.source));
+    }
+
+    fn visit_expr(&mut self, arena: &'a Arena, expr: ExprId) {
+        let ast = AstNode::Expr(expr, &arena[expr]);
+        self.children.push(Shape::new(ast, arena, self.source));
+    }
+
+    fn visit_arg(&mut self, arena: &'a Arena, arg: &'a Arg) {
+        self.children
+            .push(Shape::new(AstNode::Arg(arg), arena, self.source));
+    }
+
+    fn visit_type_param(&mut self, arena: &'a Arena, type_param: &'a TypeParam) {
+        let ast = AstNode::TypeParam(type_param);
+        self.children.push(Shape::new(ast, arena, self.source));
+    }
+
+    fn visit_except_handler(&mut self, arena: &'a Arena, handler: &'a ExceptHandler) {
+        let ast = AstNode::ExceptHandler(handler);
+        self.children.push(Shape::new(ast, arena, self.source));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::ExprKind;
+    use crate::parser::parse;
+
+    const SOURCE: &str = "\
+# leading comment
+
+import os  # trailing comment
+@decorator
+def f(a,   # first
+      b=1):
+\tif a and \\
+\t        b:  
+\t\treturn (a +
+\t\t        b)
+
+\t# inside the block
+\treturn [
+\t    x for x in a   ]\r
+x = '''two
+lines''' ; y = f(1)
+# end";
+
+    fn assert_lossless(source: &str) {
+        let module = parse(source).unwrap();
+        assert_eq!(build(source, &module).to_string(), source);
+    }
+
+    #[test]
+    fn test_cst_is_lossless() {
+        assert_lossless(SOURCE);
+        assert_lossless("");
+        assert_lossless("\n\n   \n");
+        assert_lossless("x = 1\r\n\r\nif x:\r\n    y = 2 \\\r\n        + 3\r\n");
+        for entry in std::fs::read_dir("tests").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|extension| extension == "py") {
+                let source = std::fs::read_to_string(&path).unwrap();
+                if let Ok(module) = parse(&source) {
+                    assert_eq!(build(&source, &module).to_string(), source);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_cst_trivia() {
+        let module = parse(SOURCE).unwrap();
+        let tree = build(SOURCE, &module);
+        let tokens = tree.tokens();
+        let trivia = |trivia: &[Trivia]| {
+            trivia
+                .iter()
+                .map(|trivia| (trivia.kind, trivia.value().to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        let import = tokens[0];
+        assert_eq!(import.token.token_type, TokenType::Import);
+        assert_eq!(
+            trivia(&import.leading),
+            [
+                (TriviaKind::Comment, "# leading comment".to_string()),
+                (TriviaKind::Newline, "\n".to_string()),
+                (TriviaKind::Newline, "\n".to_string()),
+            ]
+        );
+        let os = tokens[1];
+        assert_eq!(
+            trivia(&os.trailing),
+            [
+                (TriviaKind::Whitespace, "  ".to_string()),
+                (TriviaKind::Comment, "# trailing comment".to_string()),
+            ]
+        );
+        let and = tokens
+            .iter()
+            .find(|token| token.token.value() == "and")
+            .unwrap();
+        assert_eq!(
+            trivia(&and.trailing),
+            [
+                (TriviaKind::Whitespace, " ".to_string()),
+                (TriviaKind::Continuation, "\\\n".to_string()),
+            ]
+        );
+        let comma = tokens
+            .iter()
+            .find(|token| token.token.value() == ",")
+            .unwrap();
+        assert_eq!(
+            trivia(&comma.trailing),
+            [
+                (TriviaKind::Whitespace, "   ".to_string()),
+                (TriviaKind::Comment, "# first".to_string()),
+                (TriviaKind::Newline, "\n".to_string()),
+            ]
+        );
+        let second_return = tokens
+            .iter()
+            .filter(|token| token.token.token_type == TokenType::Return)
+            .nth(1)
+            .unwrap();
+        assert_eq!(
+            trivia(&second_return.leading),
+            [
+                (TriviaKind::Newline, "\n".to_string()),
+                (TriviaKind::Whitespace, "\t".to_string()),
+                (TriviaKind::Comment, "# inside the block".to_string()),
+                (TriviaKind::Newline, "\n".to_string()),
+                (TriviaKind::Whitespace, "\t".to_string()),
+            ]
+        );
+        assert_eq!(second_return.leading[2].source_ref.line, 12);
+
+        let end = tokens.last().unwrap();
+        assert_eq!(end.token.token_type, TokenType::EndMarker);
+        assert_eq!(
+            trivia(&end.leading),
+            [(TriviaKind::Comment, "# end".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_cst_typed_view() {
+        let module = parse(SOURCE).unwrap();
+        let tree = build(SOURCE, &module);
+        assert!(matches!(tree.ast(), AstNode::Module(_)));
+        let statements: Vec<_> = tree.child_nodes().collect();
+        assert_eq!(statements.len(), 4);
+        assert_eq!(statements[0].text(), "import os");
+
+        // The decorator belongs to the function, and its tokens come first.
+        let function = statements[1];
+        assert!(matches!(
+            function.as_stmt().unwrap().kind,
+            StmtKind::FunctionDef { .. }
+        ));
+        assert!(function.text().starts_with("@decorator\ndef f("));
+        let parts: Vec<_> = function.child_nodes().collect();
+        assert_eq!(parts[0].text(), "decorator");
+        assert!(matches!(parts[1].ast(), AstNode::Arg(arg) if arg.arg.as_str() == "a"));
+        assert!(matches!(parts[2].ast(), AstNode::Arg(arg) if arg.arg.as_str() == "b"));
+        assert_eq!(parts[3].text(), "1");
+
+        let if_stmt = parts[4];
+        let test = if_stmt.child_nodes().next().unwrap();
+        assert!(matches!(
+            test.as_expr().unwrap().kind,
+            ExprKind::BoolOp { .. }
+        ));
+        assert_eq!(test.text(), "a and \\\n\t        b");
+
+        // Parentheses around an expression sit in the enclosing node.
+        let returned = if_stmt.child_nodes().nth(1).unwrap();
+        assert_eq!(returned.text(), "return (a +\n\t\t        b)");
+        let sum = returned.child_nodes().next().unwrap();
+        assert_eq!(sum.text(), "a +\n\t\t        b");
+        assert!(matches!(
+            sum.as_expr().unwrap().kind,
+            ExprKind::BinOp { .. }
+        ));
+
+        let comprehension = parts[5].child_nodes().next().unwrap();
+        assert_eq!(comprehension.text(), "[\n\t    x for x in a   ]");
+        assert_eq!(statements[3].text(), "y = f(1)");
+    }
+}
@@ -0,0 +1,226 @@
+//! The disassembler, which lists a code object's instructions as CPython
+//! 3.10's `dis` module does.
+//!
+//! Each instruction takes two bytes there, so its offset is twice its
+//! index; instructions are listed unpacked, without the `EXTENDED_ARG`s
+//! a large argument is packed with. Jump arguments are instruction
+//! indexes, as they are in 3.10, except `FOR_ITER`'s, which counts the
+//! instructions it skips.
+
+use std::fmt::Write;
+
+use crate::code::CodeObject;
+use crate::intruction::Instruction;
+use crate::value::Value;
+
+/// The width of the opname column.
+const OPNAME_WIDTH: usize = 20;
+/// The width of the argument column.
+const OPARG_WIDTH: usize = 5;
+
+/// The names of the [`Instruction::MakeFunction`] flags, lowest first.
+const MAKE_FUNCTION_FLAGS: [&str; 4] = ["defaults", "kwdefaults", "annotations", "closure"];
+
+/// Disassembles `code` and then, in turn, each code object among its
+/// constants, as `dis.dis` does.
+pub fn dis(code: &CodeObject) -> String {
+    let mut text = disassemble(code);
+    for constant in &code.consts {
+        if let Value::Code(nested) = constant {
+            writeln!(text).unwrap();
+            writeln!(text, "Disassembly of {}:", constant.repr()).unwrap();
+            text += &dis(nested);
+        }
+    }
+    text
+}
+
+/// Disassembles `code` alone, a line for each instruction, with a blank
+/// line before each new source line.
+pub fn disassemble(code: &CodeObject) -> String {
+    let instructions = code.instructions();
+    let lines = code.lines();
+    let max_line = lines.iter().copied().max().unwrap_or(0);
+    let line_width = if max_line >= 1000 {
+        max_line.to_string().len()
+    } else {
+        3
+    };
+    let max_offset = 2 * instructions.len().saturating_sub(1);
+    let offset_width = if max_offset >= 10000 {
+        max_offset.to_string().len()
+    } else {
+        4
+    };
+    let mut targets = vec![false; instructions.len()];
+    for instruction in &instructions {
+        if let Some(target) = instruction.jump_target() {
+            targets[target as usize] = true;
+        }
+    }
+    for entry in &code.exception_table {
+        targets[entry.target as usize] = true;
+    }
+    let mut text = String::new();
+    for (index, &instruction) in instructions.iter().enumerate() {
+        let starts_line = index == 0 || lines[index] != lines[index - 1];
+        if starts_line && index > 0 {
+            writeln!(text).unwrap();
+        }
+        let mut fields = vec![
+            if starts_line {
+                format!("{:>1$}", lines[index], line_width)
+            } else {
+                " ".repeat(line_width)
+            },
+            // Where CPython marks the instruction being run.
+            "   ".to_string(),
+            if targets[index] { ">>" } else { "  " }.to_string(),
+            format!("{:>1$}", 2 * index, offset_width),
+            format!("{:1$}", instruction.opname(), OPNAME_WIDTH),
+        ];
+        if let Some(arg) = instruction.arg() {
+            let arg = match instruction {
+                // Negative if the code, which need not be verified, jumps
+                // back.
+                Instruction::ForIter(target) => target as i64 - index as i64 - 1,
+                _ => arg as i64,
+            };
+            fields.push(format!("{:>1$}", arg, OPARG_WIDTH));
+            let argrepr = argrepr(code, instruction);
+            if !argrepr.is_empty() {
+                fields.push(format!("({})", argrepr));
+            }
+        }
+        writeln!(text, "{}", fields.join(" ").trim_end()).unwrap();
+    }
+    if !code.exception_table.is_empty() {
+        // As 3.11 lists it, with the last instruction each entry covers.
+        writeln!(text, "ExceptionTable:").unwrap();
+        for entry in &code.exception_table {
+            writeln!(
+                text,
+                "  {} to {} -> {} [{}]{}",
+                2 * entry.start,
+                2 * (entry.end - 1),
+                2 * entry.target,
+                entry.depth,
+                if entry.lasti { " lasti" } else { "" }
+            )
+            .unwrap();
+        }
+    }
+    text
+}
+
+/// What an instruction's argument stands for: the constant, name or
+/// comparison it picks, the flags it sets, or where it jumps.
+fn argrepr(code: &CodeObject, instruction: Instruction) -> String {
+    let cell = |index: u32| {
+        let index = index as usize;
+        match code.cellvars.get(index) {
+            Some(name) => *name,
+            None => code.freevars[index - code.cellvars.len()],
+        }
+    };
+    match instruction {
+        Instruction::LoadConst(index) => code.consts[index as usize].repr(),
+        Instruction::LoadName(index)
+        | Instruction::StoreName(index)
+        | Instruction::DeleteName(index)
+        | Instruction::LoadGlobal(index)
+        | Instruction::StoreGlobal(index)
+        | Instruction::DeleteGlobal(index)
+        | Instruction::LoadAttr(index)
+        | Instruction::StoreAttr(index)
+        | Instruction::DeleteAttr(index) => code.names[index as usize].to_string(),
+        Instruction::LoadFast(index)
+        | Instruction::StoreFast(index)
+        | Instruction::DeleteFast(index) => code.varnames[index as usize].to_string(),
+        Instruction::LoadDeref(index)
+        | Instruction::StoreDeref(index)
+        | Instruction::DeleteDeref(index)
+        | Instruction::LoadClosure(index) => cell(index).to_string(),
+        Instruction::CompareOp(op) => op.symbol().to_string(),
+        Instruction::MakeFunction(flags) => {
+            let names: Vec<&str> = MAKE_FUNCTION_FLAGS
+                .iter()
+                .enumerate()
+                .filter(|(bit, _)| flags & (1 << bit) != 0)
+                .map(|(_, name)| *name)
+                .collect();
+            names.join(", ")
+        }
+        Instruction::CallIntrinsic1(function) | Instruction::CallIntrinsic2(function) => {
+            let intrinsics = instruction.intrinsics().unwrap();
+            let found = intrinsics.iter().find(|&&(number, _)| number == function);
+            found.map_or_else(String::new, |(_, name)| name.to_string())
+        }
+        Instruction::CompareJumpIfFalse(op, target)
+        | Instruction::CompareJumpIfTrue(op, target) => {
+            format!("{} to {}", op.symbol(), 2 * target)
+        }
+        _ => match instruction.jump_target() {
+            Some(target) => format!("to {}", 2 * target),
+            None => String::new(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::dis;
+    use crate::interpreter::compile_source;
+    use crate::intruction::Instruction::*;
+
+    /// `text` with the addresses of objects, which change from run to
+    /// run, blanked out.
+    fn without_addresses(text: &str) -> String {
+        let mut rest = text;
+        let mut result = String::new();
+        while let Some(start) = rest.find(" at 0x") {
+            result += &rest[..start + 6];
+            rest = rest[start + 6..].trim_start_matches(|c: char| c.is_ascii_hexdigit());
+        }
+        result + rest
+    }
+
+    #[test]
+    fn test_dis_fib() {
+        // The function tests/fib.output was made from, with no call.
+        let source = "\
+def fib(x):
+    if x < 2:
+        return 1
+    return fib(x-1) + fib(x-2)
+";
+        let code = compile_source(source, "fib.py", 0).unwrap();
+        let expected = std::fs::read_to_string("tests/fib.output").unwrap();
+        assert_eq!(
+            without_addresses(&dis(&code)).trim_end(),
+            without_addresses(&expected).trim_end()
+        );
+    }
+
+    #[test]
+    fn test_dis_for_iter_backward() {
+        let mut code = compile_source("x = 1\n", "x.py", 0).unwrap();
+        code.set_instructions(&[LoadConst(0), GetIter, ForIter(1), ReturnValue]);
+        assert!(dis(&code).contains("4 FOR_ITER                -2 (to 2)\n"));
+    }
+
+    #[test]
+    fn test_dis_golden() {
+        for name in ["while", "dis"] {
+            let source = std::fs::read_to_string(format!("tests/{}.py", name)).unwrap();
+            let code = compile_source(&source, &format!("{}.py", name), 0).unwrap();
+            let expected = std::fs::read_to_string(format!("tests/{}.dis", name)).unwrap();
+            assert_eq!(
+                without_addresses(&dis(&code)),
+                expected,
+                "tests/{}.dis",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,404 @@
+//! Constant folding and dead-branch elimination on the AST, modelled on
+//! CPython's `ast_opt.c`.
+//!
+//! Operators whose operands are all constants are evaluated at compile
+//! time, as are tuple displays of constants and subscripts of constants,
+//! so `y = 3 + 4` compiles to a single `LoadConst(7)`. An operation that
+//! would raise is left for run time to raise, and one whose result would
+//! be too big to keep in the code object, like `"x" * 10**6`, is left too.
+//! `__debug__` is a constant, true unless optimizing, and an `if` or
+//! `while` whose test is a constant keeps only the branch that can run.
+//!
+//! The symbol table is built before folding, from the tree as written: a
+//! name bound only in dead code is still local, and a `yield` in dead code
+//! still makes a generator, as in CPython.
+
+use crate::ast::{
+    Arena, CmpOp, Comprehension, Constant, ExprContext, ExprId, ExprKind, List, Module, Operator,
+    Span, StmtId, StmtKind, UnaryOperator,
+};
+use crate::transformer::{walk_comprehension, walk_expr, walk_stmt, Transformer};
+use crate::value::Value;
+
+/// The most items a tuple made by `*` may have.
+const MAX_COLLECTION_SIZE: usize = 256;
+/// The most characters a string made by `*` may have.
+const MAX_STR_SIZE: usize = 4096;
+/// The most items a tuple made by `*` may have counting those in nested
+/// tuples.
+const MAX_TOTAL_ITEMS: usize = 1024;
+
+/// Folds the constants in `module` and drops the branches it cannot take.
+/// At an `optimize` level of 1 or more, as with `python -O`, `__debug__`
+/// is false and `assert` statements are dropped.
+pub fn fold(module: Module, optimize: u8) -> Module {
+    Folder { optimize }.transform_module(module)
+}
+
+struct Folder {
+    optimize: u8,
+}
+
+impl Transformer for Folder {
+    fn transform_stmt(&mut self, arena: &mut Arena, stmt: StmtId) -> Vec<StmtId> {
+        let was_constant = match arena[stmt].kind {
+            StmtKind::Expr { value } => constant(arena, value).is_some(),
+            _ => false,
+        };
+        let stmt = walk_stmt(self, arena, stmt);
+        let span = arena[stmt].span;
+        match &arena[stmt].kind {
+            // An expression that folded to a constant does nothing; it
+            // must not stay a string, which could be taken for a docstring.
+            StmtKind::Expr { value } if !was_constant && constant(arena, *value).is_some() => {
+                vec![pass(arena, span)]
+            }
+            StmtKind::If { test, body, orelse } => match constant(arena, *test) {
+                Some(test) if truthiness(test) => live_branch(arena, *body, span),
+                Some(_) => live_branch(arena, *orelse, span),
+                None => vec![stmt],
+            },
+            StmtKind::While { test, orelse, .. } => match constant(arena, *test) {
+                Some(test) if !truthiness(test) => live_branch(arena, *orelse, span),
+                _ => vec![stmt],
+            },
+            StmtKind::For { iter, .. } => {
+                fold_iter(arena, *iter);
+                vec![stmt]
+            }
+            StmtKind::Assert { .. } if self.optimize > 0 => vec![pass(arena, span)],
+            _ => vec![stmt],
+        }
+    }
+
+    fn transform_expr(&mut self, arena: &mut Arena, expr: ExprId) -> ExprId {
+        let expr = walk_expr(self, arena, expr);
+        let folded = match &arena[expr].kind {
+            ExprKind::Name {
+                id,
+                ctx: ExprContext::Load,
+            } if id.as_str() == "__debug__" => Some(Constant::Bool(self.optimize == 0)),
+            ExprKind::UnaryOp { op, operand } => fold_unary_op(arena, expr, *op, *operand),
+            ExprKind::BinOp { left, op, right } => fold_bin_op(arena, *left, *op, *right),
+            ExprKind::Tuple { .. } => fold_tuple(arena, expr),
+            ExprKind::Subscript {
+                value,
+                slice,
+                ctx: ExprContext::Load,
+            } => fold_subscript(arena, *value, *slice),
+            ExprKind::Compare {
+                ops, comparators, ..
+            } => {
+                if let (Some(CmpOp::In | CmpOp::NotIn), Some(&last)) =
+                    (arena[*ops].last(), arena[*comparators].last())
+                {
+                    fold_iter(arena, last);
+                }
+                None
+            }
+            _ => None,
+        };
+        if let Some(value) = folded {
+            arena[expr].kind = ExprKind::Constant { value };
+        }
+        expr
+    }
+
+    fn transform_comprehension(
+        &mut self,
+        arena: &mut Arena,
+        comprehension: Comprehension,
+    ) -> Comprehension {
+        let comprehension = walk_comprehension(self, arena, comprehension);
+        fold_iter(arena, comprehension.iter);
+        comprehension
+    }
+}
+
+fn constant(arena: &Arena, expr: ExprId) -> Option<&Constant> {
+    match &arena[expr].kind {
+        ExprKind::Constant { value } => Some(value),
+        _ => None,
+    }
+}
+
+fn pass(arena: &mut Arena, span: Span) -> StmtId {
+    arena.add_stmt(StmtKind::Pass, span)
+}
+
+/// The statements of the branch of an `if` or `while` that will run,
+/// which replace the statement, or `pass` if there are none. Constant
+/// expressions are dropped: they do nothing, and one at the start could
+/// otherwise become the enclosing body's docstring.
+fn live_branch(arena: &mut Arena, branch: List<StmtId>, span: Span) -> Vec<StmtId> {
+    let branch: Vec<StmtId> = arena[branch]
+        .iter()
+        .copied()
+        .filter(|&stmt| match arena[stmt].kind {
+            StmtKind::Expr { value } => constant(arena, value).is_none(),
+            _ => true,
+        })
+        .collect();
+    if branch.is_empty() {
+        return vec![pass(arena, span)];
+    }
+    branch
+}
+
+/// Python's truth value of a constant.
+fn truthiness(constant: &Constant) -> bool {
+    match constant {
+        Constant::None => false,
+        Constant::Bool(value) => *value,
+        Constant::Int(value) => *value != 0,
+        Constant::Float(value) => *value != 0.0,
+        Constant::Str(value) => !value.is_empty(),
+        Constant::Bytes(value) => !value.is_empty(),
+        Constant::Ellipsis => true,
+        Constant::Tuple(items) => !items.is_empty(),
+    }
+}
+
+fn fold_unary_op(
+    arena: &mut Arena,
+    expr: ExprId,
+    op: UnaryOperator,
+    operand: ExprId,
+) -> Option<Constant> {
+    if let Some(operand) = constant(arena, operand) {
+        if op == UnaryOperator::Not {
+            return Some(Constant::Bool(!truthiness(operand)));
+        }
+        return Value::from_constant(operand)?
+            .unary_op(op)
+            .ok()?
+            .to_constant();
+    }
+    // `not a in b` becomes `a not in b`, and `not a is b` becomes
+    // `a is not b`. The other comparisons cannot be inverted: `not a < b`
+    // is not `a >= b` for sets, say.
+    if op != UnaryOperator::Not {
+        return None;
+    }
+    let ExprKind::Compare {
+        left,
+        ops,
+        comparators,
+    } = &arena[operand].kind
+    else {
+        return None;
+    };
+    let inverted = match arena[*ops] {
+        [CmpOp::Is] => CmpOp::IsNot,
+        [CmpOp::IsNot] => CmpOp::Is,
+        [CmpOp::In] => CmpOp::NotIn,
+        [CmpOp::NotIn] => CmpOp::In,
+        _ => return None,
+    };
+    let (left, comparators) = (*left, *comparators);
+    arena[expr].kind = ExprKind::Compare {
+        left,
+        ops: arena.add_list([inverted]),
+        comparators,
+    };
+    None
+}
+
+fn fold_bin_op(arena: &Arena, left: ExprId, op: Operator, right: ExprId) -> Option<Constant> {
+    let left = Value::from_constant(constant(arena, left)?)?;
+    let right = Value::from_constant(constant(arena, right)?)?;
+    if !is_safe(op, &left, &right) {
+        return None;
+    }
+    left.binary_op(op, &right).ok()?.to_constant()
+}
+
+/// Whether `left op right` is cheap to fold and small enough to keep, by
+/// CPython's limits. Integers here are 64-bit, so one too big is an
+/// overflow, which is not folded either.
+fn is_safe(op: Operator, left: &Value, right: &Value) -> bool {
+    match (op, left, right) {
+        // `%` on a string formats it, which is left to run time.
+        (Operator::Mod, Value::Str(_), _) => false,
+        (Operator::Mult, Value::Int(count), seq) | (Operator::Mult, seq, Value::Int(count)) => {
+            let fits =
+                |size: usize, max: usize| size == 0 || (0..=(max / size) as i64).contains(count);
+            match seq {
+                Value::Str(value) => fits(value.chars().count(), MAX_STR_SIZE),
+                Value::Tuple(items) => {
+                    fits(items.len(), MAX_COLLECTION_SIZE)
+                        && (*count == 0
+                            || total_items(items, (MAX_TOTAL_ITEMS / *count as usize) as i64) >= 0)
+                }
+                _ => true,
+            }
+        }
+        _ => true,
+    }
+}
+
+/// What is left of `limit` after counting the items of a tuple and of the
+/// tuples nested in it, stopping once it is negative.
+fn total_items(items: &[Value], mut limit: i64) -> i64 {
+    limit -= items.len() as i64;
+    for item in items {
+        if limit < 0 {
+            break;
+        }
+        if let Value::Tuple(items) = item {
+            limit = total_items(items, limit);
+        }
+    }
+    limit
+}
+
+fn fold_tuple(arena: &Arena, expr: ExprId) -> Option<Constant> {
+    let ExprKind::Tuple {
+        elts,
+        ctx: ExprContext::Load,
+    } = &arena[expr].kind
+    else {
+        return None;
+    };
+    let items = arena[*elts]
+        .iter()
+        .map(|&elt| constant(arena, elt).cloned())
+        .collect::<Option<_>>()?;
+    Some(Constant::Tuple(items))
+}
+
+fn fold_subscript(arena: &Arena, value: ExprId, slice: ExprId) -> Option<Constant> {
+    let value = Value::from_constant(constant(arena, value)?)?;
+    let index = Value::from_constant(constant(arena, slice)?)?;
+    value.subscript(&index).ok()?.to_constant()
+}
+
+/// Turns a list display that is only iterated over, as in `for x in [a,
+/// b]` or `x in [a, b]`, into a tuple, which can be a constant.
+fn fold_iter(arena: &mut Arena, iter: ExprId) {
+    if let ExprKind::List {
+        elts,
+        ctx: ExprContext::Load,
+    } = &arena[iter].kind
+    {
+        let elts = *elts;
+        arena[iter].kind = ExprKind::Tuple {
+            elts,
+            ctx: ExprContext::Load,
+        };
+        if let Some(value) = fold_tuple(arena, iter) {
+            arena[iter].kind = ExprKind::Constant { value };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fold;
+    use crate::parser::parse;
+    use crate::unparse::unparse;
+
+    fn folded(source: &str) -> String {
+        unparse(&fold(parse(source).unwrap(), 0))
+    }
+
+    #[test]
+    fn test_fold_var() {
+        let source = std::fs::read_to_string("tests/var.py").unwrap();
+        assert_eq!(folded(&source), "x = 3\ny = 7\nz = 6\nw = 3.7\na = x + y");
+    }
+
+    // What folds and what does not is as in CPython 3.11's `co_consts`.
+
+    #[test]
+    fn test_fold_operators() {
+        assert_eq!(folded("x = -1 - 2 * 3"), "x = -7");
+        assert_eq!(folded("x = 'c' + 'd' * 2"), "x = 'cdd'");
+        assert_eq!(folded("x = (5, 6) + (7,)"), "x = (5, 6, 7)");
+        assert_eq!(folded("x = (1, (2, 'a' * 3))"), "x = (1, (2, 'aaa'))");
+        assert_eq!(folded("x = 2 ** -1, 7 // -2, -7 % 3"), "x = (0.5, -4, 2)");
+        assert_eq!(folded("x = not 1, ~True, True + 1"), "x = (False, -2, 2)");
+        assert_eq!(folded("x = (1, 2)[0], 'abc'[-1]"), "x = (1, 'c')");
+        assert_eq!(folded("x = (1, y)"), "x = 1, y");
+    }
+
+    #[test]
+    fn test_fold_keeps_errors() {
+        // These raise at run time, so they are left for run time to raise.
+        for source in [
+            "x = 1 / 0",
+            "x = 1 % 0",
+            "x = -'a'",
+            "x = 'a' + 1",
+            "x = 'abc'[5]",
+            "x = 2 ** 100",
+            "x = 1 << 64",
+        ] {
+            assert_eq!(folded(source), source);
+        }
+    }
+
+    #[test]
+    fn test_fold_size_limits() {
+        assert_eq!(folded("x = 'a' * 4096").len(), "x = ''".len() + 4096);
+        assert_eq!(folded("x = 'a' * 4097"), "x = 'a' * 4097");
+        assert_eq!(folded("x = (1,) * 256").matches('1').count(), 256);
+        assert_eq!(folded("x = (1,) * 257"), "x = (1,) * 257");
+        assert_eq!(folded("x = ((1, 2),) * 200").matches('2').count(), 200);
+        assert_eq!(
+            folded("x = ((1, 2, 3, 4),) * 250"),
+            "x = ((1, 2, 3, 4),) * 250"
+        );
+        // A negative count makes an empty sequence, but is not folded.
+        assert_eq!(folded("x = 'a' * -1"), "x = 'a' * -1");
+        assert_eq!(folded("x = '%s' % 1"), "x = '%s' % 1");
+    }
+
+    #[test]
+    fn test_fold_iterables() {
+        assert_eq!(
+            folded("for x in [1, 2]:\n    pass"),
+            "for x in (1, 2):\n    pass"
+        );
+        assert_eq!(
+            folded("for x in [y, 2]:\n    pass"),
+            "for x in y, 2:\n    pass"
+        );
+        assert_eq!(folded("x = y in [1, 2]"), "x = y in (1, 2)");
+        assert_eq!(folded("x = y < [1, 2]"), "x = y < [1, 2]");
+        assert_eq!(folded("x = [y for y in [1]]"), "x = [y for y in (1,)]");
+        assert_eq!(folded("x = not y in z"), "x = y not in z");
+        assert_eq!(folded("x = not y is None"), "x = y is not None");
+        assert_eq!(folded("x = not y == z"), "x = not y == z");
+    }
+
+    #[test]
+    fn test_fold_dead_branches() {
+        assert_eq!(
+            folded("if False:\n    a()\nelse:\n    b()\n    c()\nd()"),
+            "b()\nc()\nd()"
+        );
+        assert_eq!(folded("if 0:\n    a()"), "pass");
+        assert_eq!(folded("if 'x':\n    a()\nelse:\n    b()"), "a()");
+        assert_eq!(folded("if 1 - 1:\n    a()\nelif ():\n    b()"), "pass");
+        assert_eq!(folded("while 0:\n    a()\nelse:\n    b()"), "b()");
+        assert_eq!(folded("while 1:\n    a()"), "while 1:\n    a()");
+        assert_eq!(folded("if y:\n    a()"), "if y:\n    a()");
+        // A string left at the start of a body is not made a docstring.
+        assert_eq!(
+            folded("def f():\n    if 1:\n        'a'\n        b()"),
+            "def f():\n    b()"
+        );
+        assert_eq!(folded("def f():\n    'a' + 'b'"), "def f():\n    pass");
+    }
+
+    #[test]
+    fn test_fold_debug() {
+        let source = "if __debug__:\n    a()\nassert x\nb = __debug__";
+        assert_eq!(folded(source), "a()\nassert x\nb = True");
+        assert_eq!(
+            unparse(&fold(parse(source).unwrap(), 1)),
+            "pass\npass\nb = False"
+        );
+    }
+}
@@ -1,3 +1,1281 @@
-// use crate::{code::CodeObject, vm::Vm};
+//! Runs Python source: parses it, compiles it, and runs the code on a
+//! virtual machine.
 
-// pub fn interpret(vm: &mut Vm, co: &CodeObject) {}
+use std::fmt;
+use std::io::Write;
+use std::rc::Rc;
+
+use crate::code::CodeObject;
+use crate::codegen::compile;
+use crate::parser::{parse, ParseError};
+use crate::value::{Exception, Value};
+use crate::vm::Vm;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// The source does not parse, or uses what cannot be compiled yet.
+    Syntax(Vec<ParseError>),
+    /// Running the code raised an exception.
+    Runtime(Exception),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Syntax(errors) => {
+                for (i, error) in errors.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "SyntaxError: {}", error)?;
+                }
+                Ok(())
+            }
+            Error::Runtime(exception) => write!(f, "{}", exception),
+        }
+    }
+}
+
+/// Compiles the source of a module at an `optimize` level, as for
+/// [`compile`], reporting every syntax error if it does not parse, or the
+/// error that stops it compiling.
+pub fn compile_source(
+    source: &str,
+    filename: &str,
+    optimize: u8,
+) -> Result<CodeObject, Vec<ParseError>> {
+    let module = parse(source)?;
+    compile(&module, filename, optimize).map_err(|error| vec![error])
+}
+
+/// Runs the source of a module on `vm`.
+pub fn interpret<W: Write>(
+    vm: &mut Vm<W>,
+    source: &str,
+    filename: &str,
+    optimize: u8,
+) -> Result<Value, Error> {
+    let code = compile_source(source, filename, optimize).map_err(Error::Syntax)?;
+    vm.run(Rc::new(code)).map_err(Error::Runtime)
+}
+
+/// How CPython reports an exception that nothing caught: the frames it
+/// passed through, outermost first, each with its line of `source`, then
+/// the exception itself. A frame repeated more than three times in a row,
+/// as in runaway recursion, is shown three times and then counted. The
+/// exception's cause, if it has one, is reported first.
+pub fn format_traceback(exception: &Exception, source: &str) -> String {
+    let lines: Vec<&str> = source.lines().collect();
+    let mut text = String::new();
+    if let Some(cause) = &exception.cause {
+        text += &format_traceback(cause, source);
+        text += "\n\nThe above exception was the direct cause of the following exception:\n\n";
+    }
+    // A cause that was never raised has no frames.
+    if !exception.traceback.is_empty() {
+        text += "Traceback (most recent call last):\n";
+    }
+    let mut previous = None;
+    let mut repeated = 0;
+    let flush = |text: &mut String, repeated: usize| {
+        if repeated > 3 {
+            let more = repeated - 3;
+            let times = if more == 1 { "time" } else { "times" };
+            text.push_str(&format!(
+                "  [Previous line repeated {} more {}]\n",
+                more, times
+            ));
+        }
+    };
+    for entry in exception.traceback.iter().rev() {
+        if previous == Some(entry) {
+            repeated += 1;
+        } else {
+            flush(&mut text, repeated);
+            previous = Some(entry);
+            repeated = 1;
+        }
+        if repeated > 3 {
+            continue;
+        }
+        text.push_str(&format!(
+            "  File \"{}\", line {}, in {}\n",
+            entry.filename, entry.line, entry.name
+        ));
+        if let Some(line) = lines.get(entry.line.wrapping_sub(1)) {
+            text.push_str(&format!("    {}\n", line.trim()));
+        }
+    }
+    flush(&mut text, repeated);
+    text + &exception.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_traceback, interpret, Error};
+    use crate::specialize::Family;
+    use crate::value::{Exception, TracebackEntry, Value};
+    use crate::vm::Vm;
+
+    /// What running `source` prints, followed by the exception it raises,
+    /// if any.
+    fn run(source: &str) -> String {
+        run_optimized(source, 0)
+    }
+
+    fn run_optimized(source: &str, optimize: u8) -> String {
+        let mut vm = Vm::new(Vec::new());
+        let result = interpret(&mut vm, source, "<test>", optimize);
+        let mut output = String::from_utf8(vm.out().clone()).unwrap();
+        if let Err(error) = result {
+            output += &error.to_string();
+        }
+        output
+    }
+
+    // The expected outputs below are CPython 3.11's.
+
+    #[test]
+    fn test_nonlocal() {
+        let source = "\
+def counter():
+    n = 0
+    def inc():
+        nonlocal n
+        n += 1
+        return n
+    return inc
+c = counter()
+d = counter()
+c()
+c()
+print(c(), d())
+";
+        assert_eq!(run(source), "3 1\n");
+    }
+
+    #[test]
+    fn test_late_binding() {
+        // Every lambda made in the loop shares the one cell for `i`, and
+        // sees its last value; a factory gives each its own cell.
+        let source = "\
+def loop():
+    fs = []
+    for i in range(3):
+        fs += [lambda: i]
+    return fs
+for f in loop():
+    print(f())
+def make(i):
+    return lambda: i
+for f in [make(0), make(1), make(2)]:
+    print(f())
+def rebound():
+    x = 1
+    def get():
+        return x
+    x = 2
+    return get
+print(rebound()())
+";
+        assert_eq!(run(source), "2\n2\n2\n0\n1\n2\n2\n");
+    }
+
+    #[test]
+    fn test_closure_attributes() {
+        let source = "\
+def outer(a):
+    b = 2
+    def inner():
+        return a + b
+    return inner
+f = outer(1)
+print(f(), f.__code__.co_freevars, outer.__code__.co_cellvars)
+print(f.__closure__[0].cell_contents, f.__closure__[1].cell_contents)
+print(outer.__closure__, len(f.__closure__), f.__qualname__)
+";
+        assert_eq!(
+            run(source),
+            "3 ('a', 'b') ('a', 'b')\n1 2\nNone 2 outer.<locals>.inner\n"
+        );
+    }
+
+    #[test]
+    fn test_decorator() {
+        let source = "\
+def twice(f):
+    def wrapper(x):
+        return f(f(x))
+    return wrapper
+@twice
+@twice
+def add3(x):
+    return x + 3
+print(add3(1), add3.__name__, add3.__qualname__)
+";
+        assert_eq!(run(source), "13 wrapper twice.<locals>.wrapper\n");
+    }
+
+    #[test]
+    fn test_pass_through_free() {
+        // `b` uses nothing itself, but passes `x` on to `c`.
+        let source = "\
+def a(x):
+    def b():
+        def c():
+            return x
+        return c
+    return b
+b = a(5)
+print(b()(), b.__code__.co_freevars, a.__code__.co_cellvars)
+";
+        assert_eq!(run(source), "5 ('x',) ('x',)\n");
+    }
+
+    #[test]
+    fn test_global() {
+        let source = "\
+def f():
+    global g
+    g = 7
+    return g
+print(f(), g)
+";
+        assert_eq!(run(source), "7 7\n");
+    }
+
+    #[test]
+    fn test_dead_code() {
+        // A name bound only in dead code is still local.
+        assert_eq!(
+            run("def f():\n    if 0:\n        x = 1\n    return x\nf()\n"),
+            "UnboundLocalError: cannot access local variable 'x' where it is not associated \
+             with a value"
+        );
+        let source = "\
+if __debug__:
+    print('debug')
+while 0:
+    print('never')
+else:
+    print(__debug__, 2 ** 10, 'a' + 'b' * 2)
+";
+        assert_eq!(run(source), "debug\nTrue 1024 abb\n");
+        assert_eq!(run_optimized(source, 1), "False 1024 abb\n");
+        assert_eq!(
+            run("x = 1 // 0\n"),
+            "ZeroDivisionError: integer division or modulo by zero"
+        );
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        let source = "\
+def f():
+    def g():
+        return y
+    g()
+    y = 1
+f()
+";
+        assert_eq!(
+            run(source),
+            "NameError: cannot access free variable 'y' where it is not associated with a \
+             value in enclosing scope"
+        );
+        assert_eq!(
+            run("def f():\n    print(x)\n    x = 1\nf()\n"),
+            "UnboundLocalError: cannot access local variable 'x' where it is not associated \
+             with a value"
+        );
+        assert_eq!(
+            run("def f():\n    return f.__closure__[0].cell_contents\nprint(f())\n"),
+            "TypeError: 'NoneType' object is not subscriptable"
+        );
+        assert_eq!(
+            run("def outer():\n    def f(x, y, z):\n        pass\n    f()\nouter()\n"),
+            "TypeError: outer.<locals>.f() missing 3 required positional arguments: 'x', \
+             'y', and 'z'"
+        );
+        assert_eq!(
+            run("def f(x):\n    pass\nf(1, 2)\n"),
+            "TypeError: f() takes 1 positional argument but 2 were given"
+        );
+        assert_eq!(run("print(x)\n"), "NameError: name 'x' is not defined");
+        assert_eq!(
+            run("a, b = 1\n"),
+            "TypeError: cannot unpack non-iterable int object"
+        );
+    }
+
+    #[test]
+    fn test_assert() {
+        let source = "\
+x = 1
+assert x == 1
+try:
+    assert x == 2
+except AssertionError as e:
+    print(repr(e))
+try:
+    assert x == 2, 'x is ' + repr(x)
+except AssertionError as e:
+    print(e)
+AssertionError = None
+try:
+    assert 0
+except Exception as e:
+    print(repr(e))
+";
+        assert_eq!(run(source), "AssertionError()\nx is 1\nAssertionError()\n");
+        assert_eq!(run("assert 0, 'no'\n"), "AssertionError: no");
+        assert_eq!(run_optimized("assert 0, 'no'\nprint(1)\n", 1), "1\n");
+    }
+
+    #[test]
+    fn test_del() {
+        let source = "\
+x = 1
+del x
+try:
+    x
+except NameError as e:
+    print(e)
+def f():
+    y = 2
+    del y
+    try:
+        del y
+    except UnboundLocalError as e:
+        print(e)
+f()
+g = 5
+def h():
+    global g
+    del g
+h()
+try:
+    g
+except NameError as e:
+    print(e)
+def outer():
+    z = 1
+    def inner():
+        return z
+    del z
+    try:
+        inner()
+    except NameError as e:
+        print(e)
+outer()
+class A:
+    k = 1
+    del k
+    j = 2
+a = A()
+a.x = 1
+del a.x, A.j
+try:
+    del a.x
+except AttributeError as e:
+    print(e)
+l = [1, 2, 3, 4]
+d = {'k': 1, 'm': 2}
+del l[0], [l[-1], d['k']]
+print(l, d)
+";
+        assert_eq!(
+            run(source),
+            "name 'x' is not defined\n\
+             cannot access local variable 'y' where it is not associated with a value\n\
+             name 'g' is not defined\n\
+             cannot access free variable 'z' where it is not associated with a value in \
+             enclosing scope\n\
+             'A' object has no attribute 'x'\n\
+             [2, 3] {'m': 2}\n"
+        );
+        assert_eq!(
+            run("l = [1]\ndel l[1]\n"),
+            "IndexError: list assignment index out of range"
+        );
+        assert_eq!(
+            run("del (1, 2)[0]\n"),
+            "TypeError: 'tuple' object doesn't support item deletion"
+        );
+        assert_eq!(
+            run("d = {}\ndel d[[1]]\n"),
+            "TypeError: unhashable type: 'list'"
+        );
+    }
+
+    #[test]
+    fn test_control_flow() {
+        let source = "\
+i = 0
+while i < 5:
+    if i == 3:
+        break
+    i = i + 1
+else:
+    print(\"no break\")
+print(i)
+for x in range(6):
+    if x % 2 == 0:
+        continue
+    elif x == 5:
+        print(\"five\")
+    else:
+        print(\"odd\", x)
+else:
+    print(\"done\")
+def find(items, target):
+    for item in items:
+        for other in items:
+            if item + other == target:
+                return item, other
+    return None
+print(find([1, 2, 3], 5))
+print(1 < 2 < 3, 1 < 3 < 2, 3 > 2 == 2, 0 and 1, 0 or 2, 1 and 2 and 0, None or 0 or \"x\")
+a = 5
+print(\"big\" if a > 3 else \"small\", \"neg\" if a < 0 else \"pos\")
+if 1 < a < 10 and not a == 4 or a is None:
+    print(\"in range\")
+if not (0 < a < 3):
+    print(\"not small\")
+n = 0
+while True:
+    n += 1
+    if n > 3:
+        break
+print(n)
+while n:
+    n -= 1
+else:
+    print(\"else\", n)
+print(0 if a > 3 and a < 4 else 1, () if 0 else 2)
+";
+        assert_eq!(
+            run(source),
+            "\
+3
+odd 1
+odd 3
+five
+done
+(2, 3)
+True False True 0 2 0 x
+big pos
+in range
+not small
+4
+else 0
+1 2
+"
+        );
+        // A `return` from inside loops pops their iterators.
+        assert_eq!(
+            run("def f():\n    for x in (1, 2):\n        for y in (3, 4):\n            return x * y\n    \
+                 return 0\nprint(f(), f())\n"),
+            "3 3\n"
+        );
+    }
+
+    #[test]
+    fn test_specialization() {
+        // Each instruction is hot by the time its operands change: its
+        // specialized form must notice and give the generic result.
+        let source = "\
+def add(a, b):
+    return a + b
+for i in range(100):
+    add(i, i)
+print(add(1.5, 2.25), add('a', 'b'), add([1], [2]), add(True, 2))
+x = 0
+for i in range(200):
+    x += 1 if i < 100 else 0.5
+print(x)
+s = ''
+for i in range(60):
+    s += 'ab' if i < 50 else 'c'
+print(len(s), s[-1])
+def below(a, b):
+    return a < b
+for i in range(100):
+    below(i, 50)
+print(below(1.5, 2), below('b', 'a'), below(3, 2.5))
+n = 0
+limit = 100
+while n < limit:
+    n += 1
+    if n == 60:
+        limit = 80.5
+print(n)
+def get():
+    return g
+g = 1
+for i in range(100):
+    get()
+g = 2
+print(get())
+def length(s):
+    return len(s)
+for i in range(100):
+    length('ab')
+len = lambda s: 42
+print(length('ab'))
+class P:
+    def __init__(self, x, y):
+        self.x = x
+        self.y = y
+    def norm(self):
+        return self.x * self.x + self.y * self.y
+class Q:
+    def __init__(self, x, y):
+        self.y = y
+        self.x = x
+ps = [P(1, 2), Q(3, 4)]
+def getx(p):
+    return p.x
+total = 0
+for i in range(100):
+    total += getx(ps[i % 2])
+print(total)
+p = P(1, 2)
+for i in range(100):
+    p.norm()
+p.norm = lambda: 'own'
+print(p.norm(), P(3, 4).norm())
+def h(a):
+    return a
+for i in range(100):
+    h(i)
+h = lambda *a: a
+print(h(1))
+";
+        let expected = "\
+3.75 ab [1, 2] 3
+150.0
+110 c
+True False False
+81
+2
+42
+200
+own 25
+(1,)
+";
+        assert_eq!(run(source), expected);
+        assert_eq!(run_optimized(source, 1), expected);
+    }
+
+    #[test]
+    fn test_specialization_stats() {
+        // Specialized for ints, then missing on lists until it gives up.
+        let source = "\
+def add(a, b):
+    return a + b
+for i in range(100):
+    add(i, 1)
+for i in range(100):
+    add([i], [1])
+";
+        let mut vm = Vm::new(Vec::new());
+        interpret(&mut vm, source, "<test>", 0).unwrap();
+        let stats = vm.stats().family(Family::BinaryOp);
+        assert_eq!(stats.success, 1);
+        assert_eq!(stats.hit, 98);
+        assert_eq!(stats.miss, 53);
+        assert_eq!(stats.deopt, 1);
+        assert!(vm.stats().family(Family::Call).hit > 190);
+
+        let mut vm = Vm::new(Vec::new());
+        vm.set_specializing(false);
+        interpret(&mut vm, source, "<test>", 0).unwrap();
+        assert_eq!(vm.stats(), &Default::default());
+    }
+
+    #[test]
+    fn test_specialization_across_vms() {
+        // `f` is specialized for where the first VM keeps `b`, and the
+        // second binds as many globals in another order: its own version
+        // must still differ, or `f` would read `a` there.
+        let mut first = Vm::new(Vec::new());
+        let source = "a = 1\nb = 2\ndef f():\n    return b\nfor i in range(100):\n    f()\n";
+        interpret(&mut first, source, "<test>", 0).unwrap();
+        let f = first.global("f").unwrap().clone();
+        let mut second = Vm::new(Vec::new());
+        interpret(&mut second, "b = 20\na = 10\nc = 0\nd = 0\n", "<test>", 0).unwrap();
+        for _ in 0..2 {
+            assert_eq!(second.call(&f, Vec::new()).unwrap(), Value::Int(20));
+        }
+        assert_eq!(first.call(&f, Vec::new()).unwrap(), Value::Int(2));
+    }
+
+    #[test]
+    fn test_extended_arg() {
+        // Over 256 constants, and jumps and a handler over 256 code units
+        // away, all need `EXTENDED_ARG`s.
+        let mut source =
+            String::from("total = 0\ntry:\n    for i in range(3):\n        if i != 1:\n");
+        for n in 0..300 {
+            source += &format!("            total += {}\n", n);
+        }
+        source +=
+            "    print(total)\n    total // 0\nexcept ZeroDivisionError as e:\n    print(e)\n";
+        source += "print(total // 0)\n";
+        let expected = "89700\ninteger division or modulo by zero\n\
+                        ZeroDivisionError: integer division or modulo by zero";
+        assert_eq!(run(&source), expected);
+        assert_eq!(run_optimized(&source, 1), expected);
+    }
+
+    #[test]
+    fn test_arguments() {
+        let source = "\
+def f(a, b=2, *args, c, d=4, **kwargs):
+    print(a, b, args, c, d, kwargs)
+f(1, c=3)
+f(1, 5, 6, 7, c=3, e=8)
+f(c=1, a=0, d=9)
+def g(x: \"int\", /, y=[1], *, z: \"z\" = 0) -> \"list\":
+    return [x, y, z]
+print(g(1, z=2), g.__defaults__, g.__kwdefaults__, g.__annotations__)
+print(f.__defaults__, f.__kwdefaults__, (lambda: 0).__defaults__, (lambda q=1: q)())
+print(print(\"a\", \"b\", sep=\"-\", end=\"!\\n\"))
+squares = [x * x for x in range(6) if x % 2]
+print(squares, [(x, y) for x in range(3) for y in \"ab\" if x != y])
+n = 10
+def h():
+    k = 2
+    return {x: x * k + n for x in range(3)}
+print(h(), {\"a\": 1, 2: [3]}, {}, {1: 1, 1.0: 2})
+d = {\"x\": 1}
+print(d[\"x\"], \"x\" in d, len(d), [k for k in d], d == {\"x\": 1})
+";
+        assert_eq!(
+            run(source),
+            "\
+1 2 () 3 4 {}
+1 5 (6, 7) 3 4 {'e': 8}
+0 2 () 1 9 {}
+[1, [1], 2] ([1],) {'z': 0} {'x': 'int', 'z': 'z', 'return': 'list'}
+(2,) {'d': 4} None 1
+a-b!
+None
+[1, 9, 25] [(0, 'a'), (0, 'b'), (1, 'a'), (1, 'b'), (2, 'a'), (2, 'b')]
+{0: 10, 1: 12, 2: 14} {'a': 1, 2: [3]} {} {1: 2}
+1 True 1 ['x'] True
+"
+        );
+        let errors = [
+            (
+                "def f(a, /, b): pass\nf(a=1, b=2)\n",
+                "TypeError: f() got some positional-only arguments passed as keyword arguments: \
+                 'a'",
+            ),
+            (
+                "def f(a): pass\nf(1, a=2)\n",
+                "TypeError: f() got multiple values for argument 'a'",
+            ),
+            (
+                "def f(a): pass\nf(b=2)\n",
+                "TypeError: f() got an unexpected keyword argument 'b'",
+            ),
+            (
+                "def f(a, b=1): pass\nf(1, 2, 3)\n",
+                "TypeError: f() takes from 1 to 2 positional arguments but 3 were given",
+            ),
+            (
+                "def f(a, b=1, *, c): pass\nf(1, 2, 3, c=4)\n",
+                "TypeError: f() takes from 1 to 2 positional arguments but 3 positional \
+                 arguments (and 1 keyword-only argument) were given",
+            ),
+            (
+                "def f(*, a, b): pass\nf()\n",
+                "TypeError: f() missing 2 required keyword-only arguments: 'a' and 'b'",
+            ),
+            (
+                "len([], x=1)\n",
+                "TypeError: len() takes no keyword arguments",
+            ),
+            ("{[]: 1}\n", "TypeError: unhashable type: 'list'"),
+            ("d = {}\nd[1]\n", "KeyError: 1"),
+        ];
+        for (source, error) in errors {
+            assert_eq!(run(source), error);
+        }
+    }
+
+    #[test]
+    fn test_sets() {
+        // A set here keeps its items in the order they were added; these
+        // are ones CPython's sets keep in that order too.
+        let source = "\
+def f(n):
+    return {x * x % n for x in range(n) if x}
+s = f(5)
+print(s, len(s), 4 in s, 2 in s, {1, 2, 1.0, True}, {3, 1} == {1, 3})
+print([x for x in {1, 2, 1}], {(1, 2), (1, 2)}, {x for x in ()})
+if {0}:
+    print({x for x in 'aab'} == {'a', 'b'}, {1} in {2})
+";
+        assert_eq!(
+            run(source),
+            "\
+{1, 4} 2 True False {1, 2} True
+[1, 2] {(1, 2)} set()
+True False
+"
+        );
+        let errors = [
+            ("{[1]}\n", "TypeError: unhashable type: 'list'"),
+            ("{x for x in [[1]]}\n", "TypeError: unhashable type: 'list'"),
+            ("{{1}}\n", "TypeError: unhashable type: 'set'"),
+        ];
+        for (source, error) in errors {
+            assert_eq!(run(source), error);
+        }
+    }
+
+    #[test]
+    fn test_try() {
+        let source = "\
+def divide(a, b):
+    try:
+        q = a // b
+    except ZeroDivisionError as e:
+        print('caught', e)
+        return None
+    except (TypeError, ValueError):
+        print('bad types')
+        raise
+    else:
+        print('no error')
+        return q
+    finally:
+        print('finally', a, b)
+print(divide(7, 2), divide(1, 0))
+try:
+    divide('a', 1)
+except Exception as e:
+    print(repr(e))
+def swallow():
+    try:
+        raise KeyError('k')
+    finally:
+        return 'swallowed'
+print(swallow())
+def loop():
+    for i in range(5):
+        try:
+            if i == 1:
+                continue
+            if i == 3:
+                break
+        finally:
+            print('cleanup', i)
+    return i
+print(loop())
+try:
+    try:
+        [][1]
+    except KeyError:
+        print('wrong')
+except LookupError as e:
+    print('lookup', repr(e))
+try:
+    raise ValueError
+except ValueError as e:
+    print(repr(e))
+try:
+    x = undefined
+except:
+    print('bare')
+";
+        assert_eq!(
+            run(source),
+            "\
+no error
+finally 7 2
+caught integer division or modulo by zero
+finally 1 0
+3 None
+bad types
+finally a 1
+TypeError(\"unsupported operand type(s) for //: 'str' and 'int'\")
+swallowed
+cleanup 0
+cleanup 1
+cleanup 2
+cleanup 3
+3
+lookup IndexError('list index out of range')
+ValueError()
+bare
+"
+        );
+        assert_eq!(
+            run("try:\n    1 / 0\nexcept 1:\n    pass\n"),
+            "TypeError: catching classes that do not inherit from BaseException is not allowed"
+        );
+        assert_eq!(
+            run("raise\n"),
+            "RuntimeError: No active exception to reraise"
+        );
+        assert_eq!(
+            run("try:\n    raise KeyError('k')\nexcept IndexError:\n    pass\n"),
+            "KeyError: 'k'"
+        );
+    }
+
+    #[test]
+    fn test_exception_args() {
+        let source = "\
+try:
+    raise KeyError('k')
+except KeyError as e:
+    print(repr(e), e, e.args)
+print(repr(ValueError('a', 1)), ValueError('a', 1))
+print(repr(ValueError()), repr(ValueError('x')), ValueError(3).args)
+try:
+    {}['q']
+except KeyError as e:
+    print(repr(e), e.args)
+";
+        assert_eq!(
+            run(source),
+            "\
+KeyError('k') 'k' ('k',)
+ValueError('a', 1) ('a', 1)
+ValueError() ValueError('x') (3,)
+KeyError('q') ('q',)
+"
+        );
+    }
+
+    #[test]
+    fn test_except_as_unbinds() {
+        // The name is unbound however the clause is left, so that it does
+        // not outlive the handling of the exception.
+        let source = "\
+try:
+    raise ValueError('x')
+except ValueError as e:
+    pass
+try:
+    e
+except NameError as n:
+    print(n)
+def f():
+    for i in range(2):
+        try:
+            raise KeyError(i)
+        except KeyError as k:
+            if i == 0:
+                continue
+            return k
+print(repr(f()))
+def g():
+    try:
+        raise ValueError('v')
+    except ValueError as err:
+        pass
+    return err
+g()
+";
+        assert_eq!(
+            run(source),
+            "name 'e' is not defined\nKeyError(1)\n\
+             UnboundLocalError: cannot access local variable 'err' where it is not associated \
+             with a value"
+        );
+    }
+
+    #[test]
+    fn test_star_calls() {
+        let source = "\
+def deco(fn):
+    def wrapper(*args, **kw):
+        print('calling', fn.__name__, args, kw)
+        return fn(*args, **kw)
+    return wrapper
+@deco
+def add(a, b, c=0):
+    return a + b + c
+print(add(1, 2), add(1, b=2, c=3))
+def show(*args, **kw):
+    print(args, kw)
+a = [1, 2]
+k = {'z': 3}
+show(0, *a, 5, *(6, 7))
+show(x=1, **k, y=2)
+show(*range(2), **{})
+class C:
+    def m(self, *a, **k):
+        return a, k
+print(C().m(*a, **k))
+print(*a, sep='-')
+xs = []
+print(xs.append(4), xs.append(xs[0] + 1), xs)
+";
+        assert_eq!(
+            run(source),
+            "\
+calling add (1, 2) {}
+calling add (1,) {'b': 2, 'c': 3}
+3 6
+(0, 1, 2, 5, 6, 7) {}
+() {'x': 1, 'z': 3, 'y': 2}
+(0, 1) {}
+((1, 2), {'z': 3})
+1-2
+None None [4, 5]
+"
+        );
+        for (call, error) in [
+            (
+                "f(**1)",
+                "__main__.f() argument after ** must be a mapping, not int",
+            ),
+            (
+                "f(*1)",
+                "__main__.f() argument after * must be an iterable, not int",
+            ),
+            (
+                "f(z=1, **k)",
+                "__main__.f() got multiple values for keyword argument 'z'",
+            ),
+            (
+                "f(**k, **k)",
+                "__main__.f() got multiple values for keyword argument 'z'",
+            ),
+            ("f(**{1: 2})", "keywords must be strings"),
+            ("len(*k, **k)", "len() takes no keyword arguments"),
+            (
+                "[].append()",
+                "list.append() takes exactly one argument (0 given)",
+            ),
+        ] {
+            let source = format!(
+                "def f(*a, **k):\n    pass\nk = {{'z': 3}}\ntry:\n    {}\nexcept TypeError as e:\n    print(e)\n",
+                call
+            );
+            assert_eq!(run(&source), format!("{}\n", error), "{}", call);
+        }
+    }
+
+    #[test]
+    fn test_type_params() {
+        let source = "\
+class A:
+    pass
+type Lazy = Undefined
+print(Lazy, Lazy.__name__, Lazy.__type_params__)
+try:
+    Lazy.__value__
+except NameError as e:
+    print(e)
+Undefined = 5
+print(Lazy.__value__, Lazy.__value__ is Lazy.__value__)
+type Y[T: A, *Ts, **P] = (T, Ts, P)
+print(Y.__type_params__, Y.__value__)
+T, Ts, P = Y.__type_params__
+print(T.__bound__ is A, T.__constraints__, P.__bound__)
+def f[T](x: T = 1, *, k: T = 2) -> T:
+    return T
+print(f(), f.__type_params__, f.__defaults__, f.__kwdefaults__, f.__annotations__)
+class Box[T: (A, int)]:
+    def get(self) -> T:
+        return T
+print(Box.__type_params__, Box().get(), Box.__qualname__, A.__type_params__)
+def outer():
+    def g[U](u: U):
+        def inner():
+            return u
+        return inner
+    return g
+print(outer()(3)(), outer()(3).__qualname__)
+";
+        assert_eq!(
+            run(source),
+            "\
+Lazy Lazy ()
+name 'Undefined' is not defined
+5 True
+(T, Ts, P) (T, Ts, P)
+True () None
+T (T,) (1,) {'k': 2} {'x': T, 'k': T, 'return': T}
+(T,) T Box ()
+3 outer.<locals>.g.<locals>.inner
+"
+        );
+    }
+
+    #[test]
+    fn test_raise_from() {
+        let source = "\
+try:
+    try:
+        1 // 0
+    except ZeroDivisionError as z:
+        raise ValueError('bad') from z
+except ValueError as v:
+    print(repr(v), repr(v.__cause__))
+try:
+    raise ValueError from KeyError
+except ValueError as v:
+    print(repr(v), repr(v.__cause__))
+try:
+    try:
+        raise ValueError('a') from KeyError('b')
+    except ValueError as v:
+        raise v from None
+except ValueError as v:
+    print(repr(v.__cause__))
+try:
+    raise ValueError from 1
+except TypeError as t:
+    print(t)
+try:
+    raise 1 from ValueError
+except TypeError as t:
+    print(t)
+";
+        assert_eq!(
+            run(source),
+            "\
+ValueError('bad') ZeroDivisionError('integer division or modulo by zero')
+ValueError() KeyError()
+None
+exception causes must derive from BaseException
+exceptions must derive from BaseException
+"
+        );
+    }
+
+    #[test]
+    fn test_with() {
+        let source = "\
+class Manager:
+    def __init__(self, name, suppress=False):
+        self.name = name
+        self.suppress = suppress
+    def __enter__(self):
+        print('enter', self.name)
+        return self.name
+    def __exit__(self, kind, value, tb):
+        print('exit', self.name, kind, repr(value))
+        return self.suppress
+with Manager('a') as a, Manager('b', True) as b:
+    print(a, b)
+    raise ValueError('v')
+print('after')
+for i in range(3):
+    with Manager(i):
+        if i == 1:
+            break
+print('broke at', i)
+def early():
+    with Manager('r'):
+        return 'returned'
+print(early())
+try:
+    with Manager('c'):
+        1 / 0
+except ZeroDivisionError as e:
+    print('caught', e)
+with Manager('d', 0):
+    pass
+";
+        assert_eq!(
+            run(source),
+            "\
+enter a
+enter b
+a b
+exit b <class 'ValueError'> ValueError('v')
+exit a None None
+after
+enter 0
+exit 0 None None
+enter 1
+exit 1 None None
+broke at 1
+enter r
+exit r None None
+returned
+enter c
+exit c <class 'ZeroDivisionError'> ZeroDivisionError('division by zero')
+caught division by zero
+enter d
+exit d None None
+"
+        );
+        assert_eq!(
+            run("with 1:\n    pass\n"),
+            "TypeError: 'int' object does not support the context manager protocol"
+        );
+    }
+
+    #[test]
+    fn test_classes() {
+        let source = "\
+class Point:
+    '''A point.'''
+    origin = 0
+    def __init__(self, x, y):
+        self.x = x
+        self.__y = y
+    def total(self):
+        return self.x + self.__y + self.origin
+p = Point(1, 2)
+m = p.total
+print(p.total(), m(), Point.origin, Point.__name__, Point.__doc__, p._Point__y)
+Point.origin = 10
+print(p.total(), p.__class__.__name__)
+";
+        assert_eq!(run(source), "3 3 0 Point A point. 2\n13 Point\n");
+        assert_eq!(
+            run("class C:\n    pass\nC(1)\n"),
+            "TypeError: C() takes no arguments"
+        );
+        assert_eq!(
+            run("class C:\n    def __init__(self):\n        return 1\nC()\n"),
+            "TypeError: __init__() should return None, not 'int'"
+        );
+        assert_eq!(
+            run("class C:\n    pass\nC().x\n"),
+            "AttributeError: 'C' object has no attribute 'x'"
+        );
+    }
+
+    #[test]
+    fn test_traceback() {
+        let source = "\
+def f(n):
+    return g(n) + 1
+
+def g(n):
+    return 1 // n
+
+print(f(1))
+f(0)
+";
+        let mut vm = Vm::new(Vec::new());
+        let Err(Error::Runtime(exception)) = interpret(&mut vm, source, "tb.py", 0) else {
+            panic!("expected an exception");
+        };
+        assert_eq!(vm.out(), b"2\n");
+        assert_eq!(
+            format_traceback(&exception, source),
+            "\
+Traceback (most recent call last):
+  File \"tb.py\", line 8, in <module>
+    f(0)
+  File \"tb.py\", line 2, in f
+    return g(n) + 1
+  File \"tb.py\", line 5, in g
+    return 1 // n
+ZeroDivisionError: integer division or modulo by zero"
+        );
+
+        // A `finally` that re-raises leaves the traceback as it was.
+        let source = "\
+def f():
+    try:
+        return 1 // 0
+    finally:
+        print('f')
+f()
+";
+        let mut vm = Vm::new(Vec::new());
+        let Err(Error::Runtime(exception)) = interpret(&mut vm, source, "tb.py", 0) else {
+            panic!("expected an exception");
+        };
+        assert_eq!(vm.out(), b"f\n");
+        assert_eq!(
+            format_traceback(&exception, source),
+            "\
+Traceback (most recent call last):
+  File \"tb.py\", line 6, in <module>
+    f()
+  File \"tb.py\", line 3, in f
+    return 1 // 0
+ZeroDivisionError: integer division or modulo by zero"
+        );
+
+        // Runaway recursion shows the same frame three times, then counts.
+        let source = "def r(n):\n    return r(n + 1)\nr(0)\n";
+        let mut exception = Exception::new("RecursionError", "maximum recursion depth exceeded");
+        let entry = |line, name: &str| TracebackEntry {
+            filename: "rec.py".to_string(),
+            name: name.to_string(),
+            line,
+        };
+        exception.traceback = vec![entry(2, "r"); 999];
+        exception.traceback.push(entry(3, "<module>"));
+        assert_eq!(
+            format_traceback(&exception, source),
+            "\
+Traceback (most recent call last):
+  File \"rec.py\", line 3, in <module>
+    r(0)
+  File \"rec.py\", line 2, in r
+    return r(n + 1)
+  File \"rec.py\", line 2, in r
+    return r(n + 1)
+  File \"rec.py\", line 2, in r
+    return r(n + 1)
+  [Previous line repeated 996 more times]
+RecursionError: maximum recursion depth exceeded"
+        );
+
+        // A cause is reported first, with its own frames if it was raised.
+        let source = "\
+def g():
+    raise KeyError('inner')
+try:
+    g()
+except KeyError as e:
+    raise RuntimeError('outer') from e
+";
+        let mut vm = Vm::new(Vec::new());
+        let Err(Error::Runtime(exception)) = interpret(&mut vm, source, "tb.py", 0) else {
+            panic!("expected an exception");
+        };
+        assert_eq!(
+            format_traceback(&exception, source),
+            "\
+Traceback (most recent call last):
+  File \"tb.py\", line 4, in <module>
+    g()
+  File \"tb.py\", line 2, in g
+    raise KeyError('inner')
+KeyError: 'inner'
+
+The above exception was the direct cause of the following exception:
+
+Traceback (most recent call last):
+  File \"tb.py\", line 6, in <module>
+    raise RuntimeError('outer') from e
+RuntimeError: outer"
+        );
+        let source = "raise ValueError from KeyError('k')\n";
+        let mut vm = Vm::new(Vec::new());
+        let Err(Error::Runtime(exception)) = interpret(&mut vm, source, "tb.py", 0) else {
+            panic!("expected an exception");
+        };
+        assert_eq!(
+            format_traceback(&exception, source),
+            "\
+KeyError: 'k'
+
+The above exception was the direct cause of the following exception:
+
+Traceback (most recent call last):
+  File \"tb.py\", line 1, in <module>
+    raise ValueError from KeyError('k')
+ValueError"
+        );
+    }
+}
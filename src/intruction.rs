@@ -1,36 +1,1505 @@
-// use crate::value::Value;
-
-// #[derive(Debug, Clone, PartialEq)]
-// pub enum OpCode {
-//     Cache,
-//     PopTop,
-//     PushNull,
-//     InterpreterExit,
-
-//     EndFor = 4,
-//     Nop = 9,
-//     UnaryNegative = 11,
-//     UnaryNot = 12,
-//     UnaryInvert = 15,
-
-//     BinarySubscr = 25,
-//     BinarySlice = 26,
-//     StoreSlice = 27,
-
-//     GetLen = 30,
-//     MatchMapping = 31,
-//     MatchSequence = 32,
-//     MatchKeys = 33,
-
-//     ReturnValue = 83,
-//     StoreName = 90,
-//     LoadConst = 100,
-// }
-
-// #[derive(Debug, Clone, PartialEq)]
-// pub enum Instruction {
-//     LoadConst(i32),
-//     StoreName(String),
-//     LoadName(String),
-//     BinaryOp(String),
-// }
+//! The virtual machine's instruction set, modelled on CPython's bytecode.
+//!
+//! Arguments index the tables of the code object being run: `LoadConst(i)`
+//! pushes `consts[i]`, `LoadName(i)` looks up `names[i]`, `LoadFast(i)` reads
+//! local `varnames[i]`, and the `*Deref` instructions read and write the
+//! cells, `cellvars` followed by `freevars`. Jump targets are instruction
+//! indexes.
+//!
+//! A code object holds its instructions packed, as CPython's do, in 16-bit
+//! code units: the [`Opcode`] in the low byte and the argument in the high
+//! one, after an `EXTENDED_ARG` unit for each further byte the argument
+//! needs, the highest first. The instructions the VM specializes are each
+//! followed by the `CACHE` units of their inline cache, zero until it runs
+//! them. Packed, a jump's target is the offset of the first unit of the
+//! instruction it jumps to. The VM runs the units; [`unpack`] gives the
+//! instructions back for everything else.
+
+use crate::ast::{CmpOp, Operator, UnaryOperator};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Nop,
+    PopTop,
+    /// Swaps the two values on top of the stack.
+    RotTwo,
+    /// Moves the top of the stack under the two values below it.
+    RotThree,
+    DupTop,
+    /// Pushes a copy of the value that many down the stack, 1 being the
+    /// top.
+    Copy(u32),
+    LoadConst(u32),
+    LoadName(u32),
+    StoreName(u32),
+    DeleteName(u32),
+    LoadGlobal(u32),
+    StoreGlobal(u32),
+    DeleteGlobal(u32),
+    LoadFast(u32),
+    StoreFast(u32),
+    DeleteFast(u32),
+    /// Pushes the value in a cell.
+    LoadDeref(u32),
+    /// Stores into a cell, where every closure sharing it sees the change.
+    StoreDeref(u32),
+    /// Empties a cell.
+    DeleteDeref(u32),
+    /// Pushes a cell itself, to build a closure from.
+    LoadClosure(u32),
+    LoadAttr(u32),
+    /// Pops an object and, under it, a value, and sets the attribute of the
+    /// object to the value.
+    StoreAttr(u32),
+    /// Pops an object and deletes its attribute.
+    DeleteAttr(u32),
+    BinarySubscr,
+    /// Pops a key and the container under it, and deletes the key's item.
+    DeleteSubscr,
+    UnaryOp(UnaryOperator),
+    BinaryOp(Operator),
+    InplaceOp(Operator),
+    /// One of `<`, `<=`, `==`, `!=`, `>` and `>=`.
+    CompareOp(CmpOp),
+    /// `is`, or `is not` if the argument is set.
+    IsOp(bool),
+    /// `in`, or `not in` if the argument is set.
+    ContainsOp(bool),
+    BuildTuple(u32),
+    BuildList(u32),
+    BuildSet(u32),
+    /// Builds a dict from the given number of keys and values, pushed in
+    /// turn.
+    BuildMap(u32),
+    /// Builds a dict from the given number of values and, on top of them,
+    /// a tuple of their keys.
+    BuildConstKeyMap(u32),
+    /// Pops a value and appends it to the list that many values down the
+    /// stack, as a list comprehension does.
+    ListAppend(u32),
+    /// Pops a value and adds it to the set that many values down the
+    /// stack, as a set comprehension does.
+    SetAdd(u32),
+    /// Pops an iterable and extends the list that many values down the
+    /// stack with its items, as a list display does in CPython 3.11.
+    ListExtend(u32),
+    /// Replaces the list on top of the stack with a tuple of its items.
+    ListToTuple,
+    /// Pops a mapping and adds its items to the dict that many values down
+    /// the stack, as the `**` arguments of a call are gathered; a key
+    /// already there is an error.
+    DictMerge(u32),
+    /// Pops a value and a key under it and sets the key in the dict that
+    /// many values down the stack, as a dict comprehension does.
+    MapAdd(u32),
+    UnpackSequence(u32),
+    /// Calls with the given number of positional arguments.
+    CallFunction(u32),
+    /// Calls with the given number of arguments, the last of which are
+    /// keyword arguments, named by the tuple on top of the stack.
+    CallFunctionKw(u32),
+    /// Calls with the positional arguments in the iterable on the stack
+    /// and, if the argument is 1, the keyword arguments in the dict on top
+    /// of it, as a call with `*` or `**` arguments does.
+    CallFunctionEx(u32),
+    /// Pops a qualified name and a code object, then, for each flag set in
+    /// the argument, [`MAKE_CLOSURE`] down to [`MAKE_DEFAULTS`], the value
+    /// it says, and pushes the function made from them.
+    MakeFunction(u32),
+    ReturnValue,
+    GetIter,
+    /// Pushes the next item of the iterator on top of the stack, or, when
+    /// it is exhausted, pops it and jumps to the target.
+    ForIter(u32),
+    JumpAbsolute(u32),
+    /// Pops the top of the stack and jumps to the target if it is false.
+    PopJumpIfFalse(u32),
+    /// Pops the top of the stack and jumps to the target if it is true.
+    PopJumpIfTrue(u32),
+    /// Jumps to the target, leaving the top of the stack, if it is false,
+    /// and pops it otherwise: the first operands of `and`.
+    JumpIfFalseOrPop(u32),
+    /// Jumps to the target, leaving the top of the stack, if it is true,
+    /// and pops it otherwise: the first operands of `or`.
+    JumpIfTrueOrPop(u32),
+    /// A `CompareOp` fused with a `PopJumpIfFalse` on its result.
+    CompareJumpIfFalse(CmpOp, u32),
+    /// A `CompareOp` fused with a `PopJumpIfTrue` on its result.
+    CompareJumpIfTrue(CmpOp, u32),
+    /// Raises the exception on top of the stack, or its class, if the
+    /// argument is 1; the one under the top, with the top as its cause, if
+    /// it is 2; and the exception being handled if it is 0.
+    RaiseVarargs(u32),
+    /// Pops the exception a handler was entered with and pushes the
+    /// exception handled before it, then the new one again, which becomes
+    /// the one being handled.
+    PushExcInfo,
+    /// Pops the exception handled before the current one, which becomes
+    /// the one being handled again.
+    PopExcept,
+    /// Pops an exception and raises it again, where its handler was
+    /// entered from; a nonzero argument says how far under it the index
+    /// of the instruction that first raised it is.
+    Reraise(u32),
+    /// Pops an exception class, or a tuple of them, and pushes whether the
+    /// exception under it is an instance of it.
+    CheckExcMatch,
+    /// Pops a context manager and pushes its bound `__exit__`, then the
+    /// result of calling its `__enter__`.
+    BeforeWith,
+    /// Calls the `__exit__` four values down the stack with the exception
+    /// on top, leaving both there and pushing the result.
+    WithExceptStart,
+    /// Pushes `__build_class__`, which a `class` statement calls with the
+    /// function of its body and its name.
+    LoadBuildClass,
+    /// Pushes `AssertionError`, which a failed `assert` raises whatever the
+    /// name is bound to.
+    LoadAssertionError,
+    /// Replaces the value on top of the stack with the result of the
+    /// intrinsic function the argument numbers, one of `INTRINSIC_*`.
+    CallIntrinsic1(u32),
+    /// Replaces the two values on top of the stack with the result of the
+    /// intrinsic function of two arguments, the top one the second.
+    CallIntrinsic2(u32),
+}
+
+/// The [`Instruction::MakeFunction`] flag for a tuple of defaults for the
+/// last positional parameters.
+pub const MAKE_DEFAULTS: u32 = 0x01;
+/// The [`Instruction::MakeFunction`] flag for a dict of defaults for
+/// keyword-only parameters.
+pub const MAKE_KWDEFAULTS: u32 = 0x02;
+/// The [`Instruction::MakeFunction`] flag for annotations, a tuple of
+/// names each followed by its annotation.
+pub const MAKE_ANNOTATIONS: u32 = 0x04;
+/// The [`Instruction::MakeFunction`] flag for a tuple of cells, the
+/// function's closure.
+pub const MAKE_CLOSURE: u32 = 0x08;
+
+/// The [`Instruction::CallIntrinsic1`] that makes a `TypeVar` of a name.
+pub const INTRINSIC_TYPEVAR: u32 = 7;
+/// The [`Instruction::CallIntrinsic1`] that makes a `ParamSpec` of a name.
+pub const INTRINSIC_PARAMSPEC: u32 = 8;
+/// The [`Instruction::CallIntrinsic1`] that makes a `TypeVarTuple` of a
+/// name.
+pub const INTRINSIC_TYPEVARTUPLE: u32 = 9;
+/// The [`Instruction::CallIntrinsic1`] that makes a type alias of a tuple
+/// of its name, its type parameters or None, and the function of its
+/// value.
+pub const INTRINSIC_TYPEALIAS: u32 = 11;
+/// The [`Instruction::CallIntrinsic2`] that makes a `TypeVar` of a name and
+/// the function of its bound.
+pub const INTRINSIC_TYPEVAR_WITH_BOUND: u32 = 2;
+/// The [`Instruction::CallIntrinsic2`] that makes a `TypeVar` of a name and
+/// the function of its constraints.
+pub const INTRINSIC_TYPEVAR_WITH_CONSTRAINTS: u32 = 3;
+/// The [`Instruction::CallIntrinsic2`] that gives a function the tuple of
+/// its type parameters.
+pub const INTRINSIC_SET_FUNCTION_TYPE_PARAMS: u32 = 4;
+
+/// The functions of [`Instruction::CallIntrinsic1`] there are, each with
+/// the name CPython's `dis` gives it.
+pub const INTRINSICS_1: [(u32, &str); 4] = [
+    (INTRINSIC_TYPEVAR, "INTRINSIC_TYPEVAR"),
+    (INTRINSIC_PARAMSPEC, "INTRINSIC_PARAMSPEC"),
+    (INTRINSIC_TYPEVARTUPLE, "INTRINSIC_TYPEVARTUPLE"),
+    (INTRINSIC_TYPEALIAS, "INTRINSIC_TYPEALIAS"),
+];
+/// The functions of [`Instruction::CallIntrinsic2`] there are, each with
+/// its name.
+pub const INTRINSICS_2: [(u32, &str); 3] = [
+    (INTRINSIC_TYPEVAR_WITH_BOUND, "INTRINSIC_TYPEVAR_WITH_BOUND"),
+    (
+        INTRINSIC_TYPEVAR_WITH_CONSTRAINTS,
+        "INTRINSIC_TYPEVAR_WITH_CONSTRAINTS",
+    ),
+    (
+        INTRINSIC_SET_FUNCTION_TYPE_PARAMS,
+        "INTRINSIC_SET_FUNCTION_TYPE_PARAMS",
+    ),
+];
+
+/// The operators of [`Instruction::CompareOp`], in the order of the
+/// argument CPython gives them.
+pub const CMP_OPS: [CmpOp; 6] = [
+    CmpOp::Lt,
+    CmpOp::LtE,
+    CmpOp::Eq,
+    CmpOp::NotEq,
+    CmpOp::Gt,
+    CmpOp::GtE,
+];
+
+/// The number of each instruction, as in CPython 3.10; for the exception
+/// handling instructions 3.10 lacks, as in CPython 3.11; for the intrinsics
+/// of type parameters, as in CPython 3.12; and for the fused
+/// comparisons, the specialized instructions and the superinstructions,
+/// numbers of their own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Opcode {
+    /// Not an instruction: a unit of the inline cache of the instruction
+    /// before it, as in CPython 3.11.
+    Cache = 0,
+    PopTop = 1,
+    RotTwo = 2,
+    RotThree = 3,
+    DupTop = 4,
+    Nop = 9,
+    UnaryPositive = 10,
+    UnaryNegative = 11,
+    UnaryNot = 12,
+    UnaryInvert = 15,
+    BinaryMatrixMultiply = 16,
+    InplaceMatrixMultiply = 17,
+    BinaryPower = 19,
+    BinaryMultiply = 20,
+    BinaryModulo = 22,
+    BinaryAdd = 23,
+    BinarySubtract = 24,
+    BinarySubscr = 25,
+    BinaryFloorDivide = 26,
+    BinaryTrueDivide = 27,
+    InplaceFloorDivide = 28,
+    InplaceTrueDivide = 29,
+    PushExcInfo = 35,
+    CheckExcMatch = 36,
+    WithExceptStart = 49,
+    BeforeWith = 53,
+    InplaceAdd = 55,
+    InplaceSubtract = 56,
+    InplaceMultiply = 57,
+    InplaceModulo = 59,
+    DeleteSubscr = 61,
+    BinaryLshift = 62,
+    BinaryRshift = 63,
+    BinaryAnd = 64,
+    BinaryXor = 65,
+    BinaryOr = 66,
+    InplacePower = 67,
+    GetIter = 68,
+    LoadBuildClass = 71,
+    LoadAssertionError = 74,
+    InplaceLshift = 75,
+    InplaceRshift = 76,
+    InplaceAnd = 77,
+    InplaceXor = 78,
+    InplaceOr = 79,
+    ListToTuple = 82,
+    ReturnValue = 83,
+    PopExcept = 89,
+    StoreName = 90,
+    DeleteName = 91,
+    UnpackSequence = 92,
+    ForIter = 93,
+    StoreAttr = 95,
+    DeleteAttr = 96,
+    StoreGlobal = 97,
+    DeleteGlobal = 98,
+    LoadConst = 100,
+    LoadName = 101,
+    BuildTuple = 102,
+    BuildList = 103,
+    BuildSet = 104,
+    BuildMap = 105,
+    LoadAttr = 106,
+    CompareOp = 107,
+    JumpIfFalseOrPop = 111,
+    JumpIfTrueOrPop = 112,
+    JumpAbsolute = 113,
+    PopJumpIfFalse = 114,
+    PopJumpIfTrue = 115,
+    LoadGlobal = 116,
+    IsOp = 117,
+    ContainsOp = 118,
+    Reraise = 119,
+    Copy = 120,
+    LoadFast = 124,
+    StoreFast = 125,
+    DeleteFast = 126,
+    RaiseVarargs = 130,
+    CallFunction = 131,
+    MakeFunction = 132,
+    LoadClosure = 135,
+    LoadDeref = 136,
+    StoreDeref = 137,
+    DeleteDeref = 138,
+    CallFunctionKw = 141,
+    CallFunctionEx = 142,
+    /// Not an instruction of its own: the high bits of the argument of the
+    /// instruction that follows.
+    ExtendedArg = 144,
+    ListAppend = 145,
+    SetAdd = 146,
+    MapAdd = 147,
+    BuildConstKeyMap = 156,
+    ListExtend = 162,
+    DictMerge = 164,
+    CallIntrinsic1 = 173,
+    CallIntrinsic2 = 174,
+    CompareJumpIfFalse = 200,
+    CompareJumpIfTrue = 201,
+    // The specialized forms of instructions, which only the VM writes, in
+    // place of the instruction they specialize once it is hot.
+    BinaryAddInt = 202,
+    BinaryAddFloat = 203,
+    BinaryAddUnicode = 204,
+    BinarySubtractInt = 205,
+    BinarySubtractFloat = 206,
+    BinaryMultiplyInt = 207,
+    BinaryMultiplyFloat = 208,
+    InplaceAddInt = 209,
+    InplaceAddFloat = 210,
+    InplaceAddUnicode = 211,
+    InplaceSubtractInt = 212,
+    InplaceSubtractFloat = 213,
+    InplaceMultiplyInt = 214,
+    InplaceMultiplyFloat = 215,
+    CompareOpInt = 216,
+    CompareOpFloat = 217,
+    CompareOpStr = 218,
+    CompareJumpIfFalseInt = 219,
+    CompareJumpIfFalseFloat = 220,
+    CompareJumpIfFalseStr = 221,
+    CompareJumpIfTrueInt = 222,
+    CompareJumpIfTrueFloat = 223,
+    CompareJumpIfTrueStr = 224,
+    LoadGlobalModule = 225,
+    LoadGlobalBuiltin = 226,
+    LoadAttrInstance = 227,
+    LoadAttrMethod = 228,
+    CallPyExactArgs = 229,
+    CallMethodExactArgs = 230,
+    CallBuiltin = 231,
+    // Superinstructions, which the VM writes over the first of two
+    // instructions it often runs in a row, and which run both.
+    LoadFastLoadFast = 232,
+    LoadFastLoadConst = 233,
+    LoadConstLoadFast = 234,
+    StoreFastLoadFast = 235,
+    StoreFastStoreFast = 236,
+}
+
+/// The opcode of each number, for [`Opcode::from_u8`].
+static OPCODE_NUMBERS: [Option<Opcode>; 256] = {
+    let mut numbers = [None; 256];
+    let mut i = 0;
+    while i < Opcode::ALL.len() {
+        numbers[Opcode::ALL[i] as usize] = Some(Opcode::ALL[i]);
+        i += 1;
+    }
+    numbers
+};
+
+impl Opcode {
+    /// Every opcode, in order of number.
+    pub const ALL: [Opcode; 132] = [
+        Opcode::Cache,
+        Opcode::PopTop,
+        Opcode::RotTwo,
+        Opcode::RotThree,
+        Opcode::DupTop,
+        Opcode::Nop,
+        Opcode::UnaryPositive,
+        Opcode::UnaryNegative,
+        Opcode::UnaryNot,
+        Opcode::UnaryInvert,
+        Opcode::BinaryMatrixMultiply,
+        Opcode::InplaceMatrixMultiply,
+        Opcode::BinaryPower,
+        Opcode::BinaryMultiply,
+        Opcode::BinaryModulo,
+        Opcode::BinaryAdd,
+        Opcode::BinarySubtract,
+        Opcode::BinarySubscr,
+        Opcode::BinaryFloorDivide,
+        Opcode::BinaryTrueDivide,
+        Opcode::InplaceFloorDivide,
+        Opcode::InplaceTrueDivide,
+        Opcode::PushExcInfo,
+        Opcode::CheckExcMatch,
+        Opcode::WithExceptStart,
+        Opcode::BeforeWith,
+        Opcode::InplaceAdd,
+        Opcode::InplaceSubtract,
+        Opcode::InplaceMultiply,
+        Opcode::InplaceModulo,
+        Opcode::DeleteSubscr,
+        Opcode::BinaryLshift,
+        Opcode::BinaryRshift,
+        Opcode::BinaryAnd,
+        Opcode::BinaryXor,
+        Opcode::BinaryOr,
+        Opcode::InplacePower,
+        Opcode::GetIter,
+        Opcode::LoadBuildClass,
+        Opcode::LoadAssertionError,
+        Opcode::InplaceLshift,
+        Opcode::InplaceRshift,
+        Opcode::InplaceAnd,
+        Opcode::InplaceXor,
+        Opcode::InplaceOr,
+        Opcode::ListToTuple,
+        Opcode::ReturnValue,
+        Opcode::PopExcept,
+        Opcode::StoreName,
+        Opcode::DeleteName,
+        Opcode::UnpackSequence,
+        Opcode::ForIter,
+        Opcode::StoreAttr,
+        Opcode::DeleteAttr,
+        Opcode::StoreGlobal,
+        Opcode::DeleteGlobal,
+        Opcode::LoadConst,
+        Opcode::LoadName,
+        Opcode::BuildTuple,
+        Opcode::BuildList,
+        Opcode::BuildSet,
+        Opcode::BuildMap,
+        Opcode::LoadAttr,
+        Opcode::CompareOp,
+        Opcode::JumpIfFalseOrPop,
+        Opcode::JumpIfTrueOrPop,
+        Opcode::JumpAbsolute,
+        Opcode::PopJumpIfFalse,
+        Opcode::PopJumpIfTrue,
+        Opcode::LoadGlobal,
+        Opcode::IsOp,
+        Opcode::ContainsOp,
+        Opcode::Reraise,
+        Opcode::Copy,
+        Opcode::LoadFast,
+        Opcode::StoreFast,
+        Opcode::DeleteFast,
+        Opcode::RaiseVarargs,
+        Opcode::CallFunction,
+        Opcode::MakeFunction,
+        Opcode::LoadClosure,
+        Opcode::LoadDeref,
+        Opcode::StoreDeref,
+        Opcode::DeleteDeref,
+        Opcode::CallFunctionKw,
+        Opcode::CallFunctionEx,
+        Opcode::ExtendedArg,
+        Opcode::ListAppend,
+        Opcode::SetAdd,
+        Opcode::MapAdd,
+        Opcode::BuildConstKeyMap,
+        Opcode::ListExtend,
+        Opcode::DictMerge,
+        Opcode::CallIntrinsic1,
+        Opcode::CallIntrinsic2,
+        Opcode::CompareJumpIfFalse,
+        Opcode::CompareJumpIfTrue,
+        Opcode::BinaryAddInt,
+        Opcode::BinaryAddFloat,
+        Opcode::BinaryAddUnicode,
+        Opcode::BinarySubtractInt,
+        Opcode::BinarySubtractFloat,
+        Opcode::BinaryMultiplyInt,
+        Opcode::BinaryMultiplyFloat,
+        Opcode::InplaceAddInt,
+        Opcode::InplaceAddFloat,
+        Opcode::InplaceAddUnicode,
+        Opcode::InplaceSubtractInt,
+        Opcode::InplaceSubtractFloat,
+        Opcode::InplaceMultiplyInt,
+        Opcode::InplaceMultiplyFloat,
+        Opcode::CompareOpInt,
+        Opcode::CompareOpFloat,
+        Opcode::CompareOpStr,
+        Opcode::CompareJumpIfFalseInt,
+        Opcode::CompareJumpIfFalseFloat,
+        Opcode::CompareJumpIfFalseStr,
+        Opcode::CompareJumpIfTrueInt,
+        Opcode::CompareJumpIfTrueFloat,
+        Opcode::CompareJumpIfTrueStr,
+        Opcode::LoadGlobalModule,
+        Opcode::LoadGlobalBuiltin,
+        Opcode::LoadAttrInstance,
+        Opcode::LoadAttrMethod,
+        Opcode::CallPyExactArgs,
+        Opcode::CallMethodExactArgs,
+        Opcode::CallBuiltin,
+        Opcode::LoadFastLoadFast,
+        Opcode::LoadFastLoadConst,
+        Opcode::LoadConstLoadFast,
+        Opcode::StoreFastLoadFast,
+        Opcode::StoreFastStoreFast,
+    ];
+
+    /// The opcode numbered `number`, if there is one.
+    pub fn from_u8(number: u8) -> Option<Opcode> {
+        OPCODE_NUMBERS[number as usize]
+    }
+
+    /// The name of the opcode in CPython's `dis`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Opcode::Cache => "CACHE",
+            Opcode::PopTop => "POP_TOP",
+            Opcode::RotTwo => "ROT_TWO",
+            Opcode::RotThree => "ROT_THREE",
+            Opcode::DupTop => "DUP_TOP",
+            Opcode::Nop => "NOP",
+            Opcode::UnaryPositive => "UNARY_POSITIVE",
+            Opcode::UnaryNegative => "UNARY_NEGATIVE",
+            Opcode::UnaryNot => "UNARY_NOT",
+            Opcode::UnaryInvert => "UNARY_INVERT",
+            Opcode::BinaryMatrixMultiply => "BINARY_MATRIX_MULTIPLY",
+            Opcode::InplaceMatrixMultiply => "INPLACE_MATRIX_MULTIPLY",
+            Opcode::BinaryPower => "BINARY_POWER",
+            Opcode::BinaryMultiply => "BINARY_MULTIPLY",
+            Opcode::BinaryModulo => "BINARY_MODULO",
+            Opcode::BinaryAdd => "BINARY_ADD",
+            Opcode::BinarySubtract => "BINARY_SUBTRACT",
+            Opcode::BinarySubscr => "BINARY_SUBSCR",
+            Opcode::BinaryFloorDivide => "BINARY_FLOOR_DIVIDE",
+            Opcode::BinaryTrueDivide => "BINARY_TRUE_DIVIDE",
+            Opcode::InplaceFloorDivide => "INPLACE_FLOOR_DIVIDE",
+            Opcode::InplaceTrueDivide => "INPLACE_TRUE_DIVIDE",
+            Opcode::PushExcInfo => "PUSH_EXC_INFO",
+            Opcode::CheckExcMatch => "CHECK_EXC_MATCH",
+            Opcode::WithExceptStart => "WITH_EXCEPT_START",
+            Opcode::BeforeWith => "BEFORE_WITH",
+            Opcode::InplaceAdd => "INPLACE_ADD",
+            Opcode::InplaceSubtract => "INPLACE_SUBTRACT",
+            Opcode::InplaceMultiply => "INPLACE_MULTIPLY",
+            Opcode::InplaceModulo => "INPLACE_MODULO",
+            Opcode::DeleteSubscr => "DELETE_SUBSCR",
+            Opcode::BinaryLshift => "BINARY_LSHIFT",
+            Opcode::BinaryRshift => "BINARY_RSHIFT",
+            Opcode::BinaryAnd => "BINARY_AND",
+            Opcode::BinaryXor => "BINARY_XOR",
+            Opcode::BinaryOr => "BINARY_OR",
+            Opcode::InplacePower => "INPLACE_POWER",
+            Opcode::GetIter => "GET_ITER",
+            Opcode::LoadBuildClass => "LOAD_BUILD_CLASS",
+            Opcode::LoadAssertionError => "LOAD_ASSERTION_ERROR",
+            Opcode::InplaceLshift => "INPLACE_LSHIFT",
+            Opcode::InplaceRshift => "INPLACE_RSHIFT",
+            Opcode::InplaceAnd => "INPLACE_AND",
+            Opcode::InplaceXor => "INPLACE_XOR",
+            Opcode::InplaceOr => "INPLACE_OR",
+            Opcode::ReturnValue => "RETURN_VALUE",
+            Opcode::PopExcept => "POP_EXCEPT",
+            Opcode::StoreName => "STORE_NAME",
+            Opcode::DeleteName => "DELETE_NAME",
+            Opcode::UnpackSequence => "UNPACK_SEQUENCE",
+            Opcode::ForIter => "FOR_ITER",
+            Opcode::StoreAttr => "STORE_ATTR",
+            Opcode::DeleteAttr => "DELETE_ATTR",
+            Opcode::StoreGlobal => "STORE_GLOBAL",
+            Opcode::DeleteGlobal => "DELETE_GLOBAL",
+            Opcode::LoadConst => "LOAD_CONST",
+            Opcode::LoadName => "LOAD_NAME",
+            Opcode::BuildTuple => "BUILD_TUPLE",
+            Opcode::BuildList => "BUILD_LIST",
+            Opcode::BuildSet => "BUILD_SET",
+            Opcode::BuildMap => "BUILD_MAP",
+            Opcode::LoadAttr => "LOAD_ATTR",
+            Opcode::CompareOp => "COMPARE_OP",
+            Opcode::JumpIfFalseOrPop => "JUMP_IF_FALSE_OR_POP",
+            Opcode::JumpIfTrueOrPop => "JUMP_IF_TRUE_OR_POP",
+            Opcode::JumpAbsolute => "JUMP_ABSOLUTE",
+            Opcode::PopJumpIfFalse => "POP_JUMP_IF_FALSE",
+            Opcode::PopJumpIfTrue => "POP_JUMP_IF_TRUE",
+            Opcode::LoadGlobal => "LOAD_GLOBAL",
+            Opcode::IsOp => "IS_OP",
+            Opcode::ContainsOp => "CONTAINS_OP",
+            Opcode::Reraise => "RERAISE",
+            Opcode::Copy => "COPY",
+            Opcode::LoadFast => "LOAD_FAST",
+            Opcode::StoreFast => "STORE_FAST",
+            Opcode::DeleteFast => "DELETE_FAST",
+            Opcode::RaiseVarargs => "RAISE_VARARGS",
+            Opcode::CallFunction => "CALL_FUNCTION",
+            Opcode::MakeFunction => "MAKE_FUNCTION",
+            Opcode::LoadClosure => "LOAD_CLOSURE",
+            Opcode::LoadDeref => "LOAD_DEREF",
+            Opcode::StoreDeref => "STORE_DEREF",
+            Opcode::DeleteDeref => "DELETE_DEREF",
+            Opcode::CallFunctionKw => "CALL_FUNCTION_KW",
+            Opcode::CallFunctionEx => "CALL_FUNCTION_EX",
+            Opcode::ExtendedArg => "EXTENDED_ARG",
+            Opcode::ListAppend => "LIST_APPEND",
+            Opcode::SetAdd => "SET_ADD",
+            Opcode::MapAdd => "MAP_ADD",
+            Opcode::BuildConstKeyMap => "BUILD_CONST_KEY_MAP",
+            Opcode::ListExtend => "LIST_EXTEND",
+            Opcode::ListToTuple => "LIST_TO_TUPLE",
+            Opcode::DictMerge => "DICT_MERGE",
+            Opcode::CallIntrinsic1 => "CALL_INTRINSIC_1",
+            Opcode::CallIntrinsic2 => "CALL_INTRINSIC_2",
+            Opcode::CompareJumpIfFalse => "COMPARE_JUMP_IF_FALSE",
+            Opcode::CompareJumpIfTrue => "COMPARE_JUMP_IF_TRUE",
+            Opcode::BinaryAddInt => "BINARY_ADD_INT",
+            Opcode::BinaryAddFloat => "BINARY_ADD_FLOAT",
+            Opcode::BinaryAddUnicode => "BINARY_ADD_UNICODE",
+            Opcode::BinarySubtractInt => "BINARY_SUBTRACT_INT",
+            Opcode::BinarySubtractFloat => "BINARY_SUBTRACT_FLOAT",
+            Opcode::BinaryMultiplyInt => "BINARY_MULTIPLY_INT",
+            Opcode::BinaryMultiplyFloat => "BINARY_MULTIPLY_FLOAT",
+            Opcode::InplaceAddInt => "INPLACE_ADD_INT",
+            Opcode::InplaceAddFloat => "INPLACE_ADD_FLOAT",
+            Opcode::InplaceAddUnicode => "INPLACE_ADD_UNICODE",
+            Opcode::InplaceSubtractInt => "INPLACE_SUBTRACT_INT",
+            Opcode::InplaceSubtractFloat => "INPLACE_SUBTRACT_FLOAT",
+            Opcode::InplaceMultiplyInt => "INPLACE_MULTIPLY_INT",
+            Opcode::InplaceMultiplyFloat => "INPLACE_MULTIPLY_FLOAT",
+            Opcode::CompareOpInt => "COMPARE_OP_INT",
+            Opcode::CompareOpFloat => "COMPARE_OP_FLOAT",
+            Opcode::CompareOpStr => "COMPARE_OP_STR",
+            Opcode::CompareJumpIfFalseInt => "COMPARE_JUMP_IF_FALSE_INT",
+            Opcode::CompareJumpIfFalseFloat => "COMPARE_JUMP_IF_FALSE_FLOAT",
+            Opcode::CompareJumpIfFalseStr => "COMPARE_JUMP_IF_FALSE_STR",
+            Opcode::CompareJumpIfTrueInt => "COMPARE_JUMP_IF_TRUE_INT",
+            Opcode::CompareJumpIfTrueFloat => "COMPARE_JUMP_IF_TRUE_FLOAT",
+            Opcode::CompareJumpIfTrueStr => "COMPARE_JUMP_IF_TRUE_STR",
+            Opcode::LoadGlobalModule => "LOAD_GLOBAL_MODULE",
+            Opcode::LoadGlobalBuiltin => "LOAD_GLOBAL_BUILTIN",
+            Opcode::LoadAttrInstance => "LOAD_ATTR_INSTANCE",
+            Opcode::LoadAttrMethod => "LOAD_ATTR_METHOD",
+            Opcode::CallPyExactArgs => "CALL_PY_EXACT_ARGS",
+            Opcode::CallMethodExactArgs => "CALL_METHOD_EXACT_ARGS",
+            Opcode::CallBuiltin => "CALL_BUILTIN",
+            Opcode::LoadFastLoadFast => "LOAD_FAST__LOAD_FAST",
+            Opcode::LoadFastLoadConst => "LOAD_FAST__LOAD_CONST",
+            Opcode::LoadConstLoadFast => "LOAD_CONST__LOAD_FAST",
+            Opcode::StoreFastLoadFast => "STORE_FAST__LOAD_FAST",
+            Opcode::StoreFastStoreFast => "STORE_FAST__STORE_FAST",
+        }
+    }
+
+    /// The superinstruction that runs `self` and then `next`, if there is
+    /// one.
+    pub fn superinstruction(self, next: Opcode) -> Option<Opcode> {
+        Some(match (self, next) {
+            (Opcode::LoadFast, Opcode::LoadFast) => Opcode::LoadFastLoadFast,
+            (Opcode::LoadFast, Opcode::LoadConst) => Opcode::LoadFastLoadConst,
+            (Opcode::LoadConst, Opcode::LoadFast) => Opcode::LoadConstLoadFast,
+            (Opcode::StoreFast, Opcode::LoadFast) => Opcode::StoreFastLoadFast,
+            (Opcode::StoreFast, Opcode::StoreFast) => Opcode::StoreFastStoreFast,
+            _ => return None,
+        })
+    }
+
+    /// The instruction that a specialized one specializes, and falls back
+    /// to when its guards fail; any other opcode itself.
+    #[inline]
+    pub const fn generic(self) -> Opcode {
+        match self {
+            Opcode::BinaryAddInt | Opcode::BinaryAddFloat | Opcode::BinaryAddUnicode => {
+                Opcode::BinaryAdd
+            }
+            Opcode::BinarySubtractInt | Opcode::BinarySubtractFloat => Opcode::BinarySubtract,
+            Opcode::BinaryMultiplyInt | Opcode::BinaryMultiplyFloat => Opcode::BinaryMultiply,
+            Opcode::InplaceAddInt | Opcode::InplaceAddFloat | Opcode::InplaceAddUnicode => {
+                Opcode::InplaceAdd
+            }
+            Opcode::InplaceSubtractInt | Opcode::InplaceSubtractFloat => Opcode::InplaceSubtract,
+            Opcode::InplaceMultiplyInt | Opcode::InplaceMultiplyFloat => Opcode::InplaceMultiply,
+            Opcode::CompareOpInt | Opcode::CompareOpFloat | Opcode::CompareOpStr => {
+                Opcode::CompareOp
+            }
+            Opcode::CompareJumpIfFalseInt
+            | Opcode::CompareJumpIfFalseFloat
+            | Opcode::CompareJumpIfFalseStr => Opcode::CompareJumpIfFalse,
+            Opcode::CompareJumpIfTrueInt
+            | Opcode::CompareJumpIfTrueFloat
+            | Opcode::CompareJumpIfTrueStr => Opcode::CompareJumpIfTrue,
+            Opcode::LoadGlobalModule | Opcode::LoadGlobalBuiltin => Opcode::LoadGlobal,
+            Opcode::LoadAttrInstance | Opcode::LoadAttrMethod => Opcode::LoadAttr,
+            Opcode::CallPyExactArgs | Opcode::CallMethodExactArgs | Opcode::CallBuiltin => {
+                Opcode::CallFunction
+            }
+            _ => self,
+        }
+    }
+
+    /// How many units of inline cache follow the instruction, the same for
+    /// every form of it: the first counts down to when the VM next tries
+    /// to specialize it, and the rest hold what its specialized forms
+    /// guard on.
+    #[inline]
+    pub const fn caches(self) -> usize {
+        match self.generic() {
+            Opcode::BinaryAdd
+            | Opcode::BinarySubtract
+            | Opcode::BinaryMultiply
+            | Opcode::InplaceAdd
+            | Opcode::InplaceSubtract
+            | Opcode::InplaceMultiply
+            | Opcode::CompareOp
+            | Opcode::CompareJumpIfFalse
+            | Opcode::CompareJumpIfTrue
+            | Opcode::CallFunction => 1,
+            // The index of the attribute last found.
+            Opcode::LoadAttr => 2,
+            // The version of the globals, in two units, and the index of
+            // the variable.
+            Opcode::LoadGlobal => 4,
+            _ => 0,
+        }
+    }
+
+    /// The operator of a binary or in-place operation.
+    pub fn operator(self) -> Option<Operator> {
+        Some(match self {
+            Opcode::BinaryAdd | Opcode::InplaceAdd => Operator::Add,
+            Opcode::BinarySubtract | Opcode::InplaceSubtract => Operator::Sub,
+            Opcode::BinaryMultiply | Opcode::InplaceMultiply => Operator::Mult,
+            Opcode::BinaryMatrixMultiply | Opcode::InplaceMatrixMultiply => Operator::MatMult,
+            Opcode::BinaryTrueDivide | Opcode::InplaceTrueDivide => Operator::Div,
+            Opcode::BinaryModulo | Opcode::InplaceModulo => Operator::Mod,
+            Opcode::BinaryPower | Opcode::InplacePower => Operator::Pow,
+            Opcode::BinaryLshift | Opcode::InplaceLshift => Operator::LShift,
+            Opcode::BinaryRshift | Opcode::InplaceRshift => Operator::RShift,
+            Opcode::BinaryOr | Opcode::InplaceOr => Operator::BitOr,
+            Opcode::BinaryXor | Opcode::InplaceXor => Operator::BitXor,
+            Opcode::BinaryAnd | Opcode::InplaceAnd => Operator::BitAnd,
+            Opcode::BinaryFloorDivide | Opcode::InplaceFloorDivide => Operator::FloorDiv,
+            _ => return None,
+        })
+    }
+
+    /// The operator of a unary operation.
+    pub fn unary_operator(self) -> Option<UnaryOperator> {
+        Some(match self {
+            Opcode::UnaryInvert => UnaryOperator::Invert,
+            Opcode::UnaryNot => UnaryOperator::Not,
+            Opcode::UnaryPositive => UnaryOperator::UAdd,
+            Opcode::UnaryNegative => UnaryOperator::USub,
+            _ => return None,
+        })
+    }
+}
+
+impl Instruction {
+    /// The instruction with the name [`Instruction::opname`] gives and the
+    /// argument [`Instruction::arg`] gives, ignored if it takes none. The
+    /// fused comparisons, whose argument leaves out their operator, have
+    /// to be made directly.
+    pub fn from_opname(name: &str, arg: u32) -> Option<Instruction> {
+        let opcode = Opcode::ALL.iter().find(|opcode| opcode.name() == name)?;
+        match opcode {
+            Opcode::CompareJumpIfFalse | Opcode::CompareJumpIfTrue => None,
+            _ => Instruction::decode(*opcode as u8, arg),
+        }
+    }
+
+    /// Where the instruction may jump to, if it is a jump.
+    pub fn jump_target(self) -> Option<u32> {
+        match self {
+            Instruction::ForIter(target)
+            | Instruction::JumpAbsolute(target)
+            | Instruction::PopJumpIfFalse(target)
+            | Instruction::PopJumpIfTrue(target)
+            | Instruction::JumpIfFalseOrPop(target)
+            | Instruction::JumpIfTrueOrPop(target)
+            | Instruction::CompareJumpIfFalse(_, target)
+            | Instruction::CompareJumpIfTrue(_, target) => Some(target),
+            _ => None,
+        }
+    }
+
+    /// The same jump, to another target.
+    pub fn with_jump_target(self, target: u32) -> Instruction {
+        match self {
+            Instruction::ForIter(_) => Instruction::ForIter(target),
+            Instruction::JumpAbsolute(_) => Instruction::JumpAbsolute(target),
+            Instruction::PopJumpIfFalse(_) => Instruction::PopJumpIfFalse(target),
+            Instruction::PopJumpIfTrue(_) => Instruction::PopJumpIfTrue(target),
+            Instruction::JumpIfFalseOrPop(_) => Instruction::JumpIfFalseOrPop(target),
+            Instruction::JumpIfTrueOrPop(_) => Instruction::JumpIfTrueOrPop(target),
+            Instruction::CompareJumpIfFalse(op, _) => Instruction::CompareJumpIfFalse(op, target),
+            Instruction::CompareJumpIfTrue(op, _) => Instruction::CompareJumpIfTrue(op, target),
+            _ => panic!("{:?} is not a jump", self),
+        }
+    }
+
+    /// How the instruction changes the depth of the value stack, when it
+    /// jumps if `jump` is set and when it goes on to the next instruction
+    /// otherwise.
+    pub fn stack_effect(self, jump: bool) -> i32 {
+        match self {
+            Instruction::Nop
+            | Instruction::RotTwo
+            | Instruction::RotThree
+            | Instruction::LoadAttr(_)
+            | Instruction::UnaryOp(_)
+            | Instruction::GetIter
+            | Instruction::JumpAbsolute(_)
+            | Instruction::DeleteName(_)
+            | Instruction::DeleteGlobal(_)
+            | Instruction::DeleteFast(_)
+            | Instruction::DeleteDeref(_)
+            | Instruction::ListToTuple
+            | Instruction::CallIntrinsic1(_)
+            | Instruction::CheckExcMatch => 0,
+            Instruction::DupTop
+            | Instruction::Copy(_)
+            | Instruction::PushExcInfo
+            | Instruction::BeforeWith
+            | Instruction::WithExceptStart
+            | Instruction::LoadBuildClass
+            | Instruction::LoadAssertionError
+            | Instruction::LoadConst(_)
+            | Instruction::LoadName(_)
+            | Instruction::LoadGlobal(_)
+            | Instruction::LoadFast(_)
+            | Instruction::LoadDeref(_)
+            | Instruction::LoadClosure(_) => 1,
+            Instruction::PopTop
+            | Instruction::ListAppend(_)
+            | Instruction::SetAdd(_)
+            | Instruction::ListExtend(_)
+            | Instruction::DictMerge(_)
+            | Instruction::CallIntrinsic2(_)
+            | Instruction::StoreName(_)
+            | Instruction::StoreGlobal(_)
+            | Instruction::StoreFast(_)
+            | Instruction::StoreDeref(_)
+            | Instruction::BinarySubscr
+            | Instruction::BinaryOp(_)
+            | Instruction::InplaceOp(_)
+            | Instruction::CompareOp(_)
+            | Instruction::IsOp(_)
+            | Instruction::ContainsOp(_)
+            | Instruction::ReturnValue
+            | Instruction::PopJumpIfFalse(_)
+            | Instruction::PopJumpIfTrue(_)
+            | Instruction::PopExcept
+            | Instruction::DeleteAttr(_)
+            | Instruction::Reraise(_) => -1,
+            Instruction::CompareJumpIfFalse(..)
+            | Instruction::CompareJumpIfTrue(..)
+            | Instruction::MapAdd(_)
+            | Instruction::DeleteSubscr
+            | Instruction::StoreAttr(_) => -2,
+            Instruction::RaiseVarargs(count) => -(count as i32),
+            Instruction::BuildMap(count) => 1 - 2 * count as i32,
+            Instruction::BuildTuple(count)
+            | Instruction::BuildList(count)
+            | Instruction::BuildSet(count) => 1 - count as i32,
+            Instruction::UnpackSequence(count) => count as i32 - 1,
+            Instruction::CallFunction(count) => -(count as i32),
+            Instruction::CallFunctionKw(count) => -(count as i32) - 1,
+            Instruction::CallFunctionEx(flags) => -1 - (flags & 1) as i32,
+            Instruction::BuildConstKeyMap(count) => -(count as i32),
+            // The code and the qualified name, and one more value for each
+            // flag.
+            Instruction::MakeFunction(flags) => -1 - flags.count_ones() as i32,
+            Instruction::ForIter(_) => {
+                if jump {
+                    -1
+                } else {
+                    1
+                }
+            }
+            Instruction::JumpIfFalseOrPop(_) | Instruction::JumpIfTrueOrPop(_) => {
+                if jump {
+                    0
+                } else {
+                    -1
+                }
+            }
+        }
+    }
+
+    /// The instruction as a number, its [`Opcode`], and an argument, which
+    /// for a fused comparison holds its target above the index of its
+    /// operator in [`CMP_OPS`], in the low four bits.
+    pub fn encode(self) -> (u8, u32) {
+        let arg = match self {
+            Instruction::CompareJumpIfFalse(op, target)
+            | Instruction::CompareJumpIfTrue(op, target) => {
+                target << 4 | CMP_OPS.iter().position(|&o| o == op).unwrap() as u32
+            }
+            _ => self.arg().unwrap_or(0),
+        };
+        (self.opcode() as u8, arg)
+    }
+
+    /// The instruction [`Instruction::encode`] made this number and
+    /// argument from, if there is one.
+    pub fn decode(opcode: u8, arg: u32) -> Option<Instruction> {
+        let opcode = Opcode::from_u8(opcode)?;
+        Some(match opcode {
+            Opcode::Nop => Instruction::Nop,
+            Opcode::PopTop => Instruction::PopTop,
+            Opcode::RotTwo => Instruction::RotTwo,
+            Opcode::RotThree => Instruction::RotThree,
+            Opcode::DupTop => Instruction::DupTop,
+            Opcode::Copy => Instruction::Copy(arg),
+            Opcode::LoadConst => Instruction::LoadConst(arg),
+            Opcode::LoadName => Instruction::LoadName(arg),
+            Opcode::StoreName => Instruction::StoreName(arg),
+            Opcode::DeleteName => Instruction::DeleteName(arg),
+            Opcode::LoadGlobal => Instruction::LoadGlobal(arg),
+            Opcode::StoreGlobal => Instruction::StoreGlobal(arg),
+            Opcode::DeleteGlobal => Instruction::DeleteGlobal(arg),
+            Opcode::LoadFast => Instruction::LoadFast(arg),
+            Opcode::StoreFast => Instruction::StoreFast(arg),
+            Opcode::DeleteFast => Instruction::DeleteFast(arg),
+            Opcode::LoadDeref => Instruction::LoadDeref(arg),
+            Opcode::StoreDeref => Instruction::StoreDeref(arg),
+            Opcode::DeleteDeref => Instruction::DeleteDeref(arg),
+            Opcode::LoadClosure => Instruction::LoadClosure(arg),
+            Opcode::LoadAttr => Instruction::LoadAttr(arg),
+            Opcode::StoreAttr => Instruction::StoreAttr(arg),
+            Opcode::DeleteAttr => Instruction::DeleteAttr(arg),
+            Opcode::BinarySubscr => Instruction::BinarySubscr,
+            Opcode::DeleteSubscr => Instruction::DeleteSubscr,
+            Opcode::CompareOp => Instruction::CompareOp(*CMP_OPS.get(arg as usize)?),
+            Opcode::IsOp => Instruction::IsOp(arg != 0),
+            Opcode::ContainsOp => Instruction::ContainsOp(arg != 0),
+            Opcode::BuildTuple => Instruction::BuildTuple(arg),
+            Opcode::BuildList => Instruction::BuildList(arg),
+            Opcode::BuildSet => Instruction::BuildSet(arg),
+            Opcode::BuildMap => Instruction::BuildMap(arg),
+            Opcode::BuildConstKeyMap => Instruction::BuildConstKeyMap(arg),
+            Opcode::ListAppend => Instruction::ListAppend(arg),
+            Opcode::SetAdd => Instruction::SetAdd(arg),
+            Opcode::ListExtend => Instruction::ListExtend(arg),
+            Opcode::ListToTuple => Instruction::ListToTuple,
+            Opcode::DictMerge => Instruction::DictMerge(arg),
+            Opcode::CallIntrinsic1 => Instruction::CallIntrinsic1(arg),
+            Opcode::CallIntrinsic2 => Instruction::CallIntrinsic2(arg),
+            Opcode::MapAdd => Instruction::MapAdd(arg),
+            Opcode::UnpackSequence => Instruction::UnpackSequence(arg),
+            Opcode::CallFunction => Instruction::CallFunction(arg),
+            Opcode::CallFunctionKw => Instruction::CallFunctionKw(arg),
+            Opcode::CallFunctionEx => Instruction::CallFunctionEx(arg),
+            Opcode::MakeFunction => Instruction::MakeFunction(arg),
+            Opcode::ReturnValue => Instruction::ReturnValue,
+            Opcode::GetIter => Instruction::GetIter,
+            Opcode::ForIter => Instruction::ForIter(arg),
+            Opcode::JumpAbsolute => Instruction::JumpAbsolute(arg),
+            Opcode::PopJumpIfFalse => Instruction::PopJumpIfFalse(arg),
+            Opcode::PopJumpIfTrue => Instruction::PopJumpIfTrue(arg),
+            Opcode::JumpIfFalseOrPop => Instruction::JumpIfFalseOrPop(arg),
+            Opcode::JumpIfTrueOrPop => Instruction::JumpIfTrueOrPop(arg),
+            Opcode::RaiseVarargs => Instruction::RaiseVarargs(arg),
+            Opcode::PushExcInfo => Instruction::PushExcInfo,
+            Opcode::PopExcept => Instruction::PopExcept,
+            Opcode::Reraise => Instruction::Reraise(arg),
+            Opcode::CheckExcMatch => Instruction::CheckExcMatch,
+            Opcode::BeforeWith => Instruction::BeforeWith,
+            Opcode::WithExceptStart => Instruction::WithExceptStart,
+            Opcode::LoadBuildClass => Instruction::LoadBuildClass,
+            Opcode::LoadAssertionError => Instruction::LoadAssertionError,
+            Opcode::CompareJumpIfFalse => {
+                Instruction::CompareJumpIfFalse(*CMP_OPS.get(arg as usize & 0xf)?, arg >> 4)
+            }
+            Opcode::CompareJumpIfTrue => {
+                Instruction::CompareJumpIfTrue(*CMP_OPS.get(arg as usize & 0xf)?, arg >> 4)
+            }
+            Opcode::BinaryMatrixMultiply
+            | Opcode::BinaryPower
+            | Opcode::BinaryMultiply
+            | Opcode::BinaryModulo
+            | Opcode::BinaryAdd
+            | Opcode::BinarySubtract
+            | Opcode::BinaryFloorDivide
+            | Opcode::BinaryTrueDivide
+            | Opcode::BinaryLshift
+            | Opcode::BinaryRshift
+            | Opcode::BinaryAnd
+            | Opcode::BinaryXor
+            | Opcode::BinaryOr => Instruction::BinaryOp(opcode.operator().unwrap()),
+            Opcode::InplaceMatrixMultiply
+            | Opcode::InplaceFloorDivide
+            | Opcode::InplaceTrueDivide
+            | Opcode::InplaceAdd
+            | Opcode::InplaceSubtract
+            | Opcode::InplaceMultiply
+            | Opcode::InplaceModulo
+            | Opcode::InplacePower
+            | Opcode::InplaceLshift
+            | Opcode::InplaceRshift
+            | Opcode::InplaceAnd
+            | Opcode::InplaceXor
+            | Opcode::InplaceOr => Instruction::InplaceOp(opcode.operator().unwrap()),
+            Opcode::UnaryPositive
+            | Opcode::UnaryNegative
+            | Opcode::UnaryNot
+            | Opcode::UnaryInvert => Instruction::UnaryOp(opcode.unary_operator().unwrap()),
+            _ => return None,
+        })
+    }
+
+    /// The name of the instruction in CPython 3.10's `dis`. The fused
+    /// comparisons, which CPython lacks, get names of their own.
+    pub fn opname(self) -> &'static str {
+        self.opcode().name()
+    }
+
+    /// The instruction's opcode, which for an operation says which.
+    pub fn opcode(self) -> Opcode {
+        match self {
+            Instruction::Nop => Opcode::Nop,
+            Instruction::PopTop => Opcode::PopTop,
+            Instruction::RotTwo => Opcode::RotTwo,
+            Instruction::RotThree => Opcode::RotThree,
+            Instruction::DupTop => Opcode::DupTop,
+            Instruction::Copy(_) => Opcode::Copy,
+            Instruction::LoadConst(_) => Opcode::LoadConst,
+            Instruction::LoadName(_) => Opcode::LoadName,
+            Instruction::StoreName(_) => Opcode::StoreName,
+            Instruction::DeleteName(_) => Opcode::DeleteName,
+            Instruction::LoadGlobal(_) => Opcode::LoadGlobal,
+            Instruction::StoreGlobal(_) => Opcode::StoreGlobal,
+            Instruction::DeleteGlobal(_) => Opcode::DeleteGlobal,
+            Instruction::LoadFast(_) => Opcode::LoadFast,
+            Instruction::StoreFast(_) => Opcode::StoreFast,
+            Instruction::DeleteFast(_) => Opcode::DeleteFast,
+            Instruction::LoadDeref(_) => Opcode::LoadDeref,
+            Instruction::StoreDeref(_) => Opcode::StoreDeref,
+            Instruction::DeleteDeref(_) => Opcode::DeleteDeref,
+            Instruction::LoadClosure(_) => Opcode::LoadClosure,
+            Instruction::LoadAttr(_) => Opcode::LoadAttr,
+            Instruction::StoreAttr(_) => Opcode::StoreAttr,
+            Instruction::DeleteAttr(_) => Opcode::DeleteAttr,
+            Instruction::BinarySubscr => Opcode::BinarySubscr,
+            Instruction::DeleteSubscr => Opcode::DeleteSubscr,
+            Instruction::UnaryOp(op) => match op {
+                UnaryOperator::Invert => Opcode::UnaryInvert,
+                UnaryOperator::Not => Opcode::UnaryNot,
+                UnaryOperator::UAdd => Opcode::UnaryPositive,
+                UnaryOperator::USub => Opcode::UnaryNegative,
+            },
+            Instruction::BinaryOp(op) => match op {
+                Operator::Add => Opcode::BinaryAdd,
+                Operator::Sub => Opcode::BinarySubtract,
+                Operator::Mult => Opcode::BinaryMultiply,
+                Operator::MatMult => Opcode::BinaryMatrixMultiply,
+                Operator::Div => Opcode::BinaryTrueDivide,
+                Operator::Mod => Opcode::BinaryModulo,
+                Operator::Pow => Opcode::BinaryPower,
+                Operator::LShift => Opcode::BinaryLshift,
+                Operator::RShift => Opcode::BinaryRshift,
+                Operator::BitOr => Opcode::BinaryOr,
+                Operator::BitXor => Opcode::BinaryXor,
+                Operator::BitAnd => Opcode::BinaryAnd,
+                Operator::FloorDiv => Opcode::BinaryFloorDivide,
+            },
+            Instruction::InplaceOp(op) => match op {
+                Operator::Add => Opcode::InplaceAdd,
+                Operator::Sub => Opcode::InplaceSubtract,
+                Operator::Mult => Opcode::InplaceMultiply,
+                Operator::MatMult => Opcode::InplaceMatrixMultiply,
+                Operator::Div => Opcode::InplaceTrueDivide,
+                Operator::Mod => Opcode::InplaceModulo,
+                Operator::Pow => Opcode::InplacePower,
+                Operator::LShift => Opcode::InplaceLshift,
+                Operator::RShift => Opcode::InplaceRshift,
+                Operator::BitOr => Opcode::InplaceOr,
+                Operator::BitXor => Opcode::InplaceXor,
+                Operator::BitAnd => Opcode::InplaceAnd,
+                Operator::FloorDiv => Opcode::InplaceFloorDivide,
+            },
+            Instruction::CompareOp(_) => Opcode::CompareOp,
+            Instruction::IsOp(_) => Opcode::IsOp,
+            Instruction::ContainsOp(_) => Opcode::ContainsOp,
+            Instruction::BuildTuple(_) => Opcode::BuildTuple,
+            Instruction::BuildList(_) => Opcode::BuildList,
+            Instruction::BuildSet(_) => Opcode::BuildSet,
+            Instruction::BuildMap(_) => Opcode::BuildMap,
+            Instruction::BuildConstKeyMap(_) => Opcode::BuildConstKeyMap,
+            Instruction::ListAppend(_) => Opcode::ListAppend,
+            Instruction::SetAdd(_) => Opcode::SetAdd,
+            Instruction::ListExtend(_) => Opcode::ListExtend,
+            Instruction::ListToTuple => Opcode::ListToTuple,
+            Instruction::DictMerge(_) => Opcode::DictMerge,
+            Instruction::CallIntrinsic1(_) => Opcode::CallIntrinsic1,
+            Instruction::CallIntrinsic2(_) => Opcode::CallIntrinsic2,
+            Instruction::MapAdd(_) => Opcode::MapAdd,
+            Instruction::UnpackSequence(_) => Opcode::UnpackSequence,
+            Instruction::CallFunction(_) => Opcode::CallFunction,
+            Instruction::CallFunctionKw(_) => Opcode::CallFunctionKw,
+            Instruction::CallFunctionEx(_) => Opcode::CallFunctionEx,
+            Instruction::MakeFunction(_) => Opcode::MakeFunction,
+            Instruction::ReturnValue => Opcode::ReturnValue,
+            Instruction::GetIter => Opcode::GetIter,
+            Instruction::ForIter(_) => Opcode::ForIter,
+            Instruction::JumpAbsolute(_) => Opcode::JumpAbsolute,
+            Instruction::PopJumpIfFalse(_) => Opcode::PopJumpIfFalse,
+            Instruction::PopJumpIfTrue(_) => Opcode::PopJumpIfTrue,
+            Instruction::JumpIfFalseOrPop(_) => Opcode::JumpIfFalseOrPop,
+            Instruction::JumpIfTrueOrPop(_) => Opcode::JumpIfTrueOrPop,
+            Instruction::CompareJumpIfFalse(..) => Opcode::CompareJumpIfFalse,
+            Instruction::CompareJumpIfTrue(..) => Opcode::CompareJumpIfTrue,
+            Instruction::RaiseVarargs(_) => Opcode::RaiseVarargs,
+            Instruction::PushExcInfo => Opcode::PushExcInfo,
+            Instruction::PopExcept => Opcode::PopExcept,
+            Instruction::Reraise(_) => Opcode::Reraise,
+            Instruction::CheckExcMatch => Opcode::CheckExcMatch,
+            Instruction::BeforeWith => Opcode::BeforeWith,
+            Instruction::WithExceptStart => Opcode::WithExceptStart,
+            Instruction::LoadBuildClass => Opcode::LoadBuildClass,
+            Instruction::LoadAssertionError => Opcode::LoadAssertionError,
+        }
+    }
+
+    /// The instruction's argument, if it takes one. A comparison is the
+    /// index of its operator in [`CMP_OPS`], and a fused comparison's
+    /// argument is its target, the operator being part of its name's
+    /// repr only.
+    pub fn arg(self) -> Option<u32> {
+        match self {
+            Instruction::LoadConst(arg)
+            | Instruction::LoadName(arg)
+            | Instruction::StoreName(arg)
+            | Instruction::DeleteName(arg)
+            | Instruction::LoadGlobal(arg)
+            | Instruction::StoreGlobal(arg)
+            | Instruction::DeleteGlobal(arg)
+            | Instruction::LoadFast(arg)
+            | Instruction::StoreFast(arg)
+            | Instruction::DeleteFast(arg)
+            | Instruction::LoadDeref(arg)
+            | Instruction::StoreDeref(arg)
+            | Instruction::DeleteDeref(arg)
+            | Instruction::LoadClosure(arg)
+            | Instruction::LoadAttr(arg)
+            | Instruction::StoreAttr(arg)
+            | Instruction::DeleteAttr(arg)
+            | Instruction::Copy(arg)
+            | Instruction::RaiseVarargs(arg)
+            | Instruction::Reraise(arg)
+            | Instruction::BuildTuple(arg)
+            | Instruction::BuildList(arg)
+            | Instruction::BuildSet(arg)
+            | Instruction::BuildMap(arg)
+            | Instruction::BuildConstKeyMap(arg)
+            | Instruction::ListAppend(arg)
+            | Instruction::SetAdd(arg)
+            | Instruction::ListExtend(arg)
+            | Instruction::DictMerge(arg)
+            | Instruction::CallIntrinsic1(arg)
+            | Instruction::CallIntrinsic2(arg)
+            | Instruction::MapAdd(arg)
+            | Instruction::UnpackSequence(arg)
+            | Instruction::CallFunction(arg)
+            | Instruction::CallFunctionKw(arg)
+            | Instruction::CallFunctionEx(arg)
+            | Instruction::MakeFunction(arg)
+            | Instruction::ForIter(arg)
+            | Instruction::JumpAbsolute(arg)
+            | Instruction::PopJumpIfFalse(arg)
+            | Instruction::PopJumpIfTrue(arg)
+            | Instruction::JumpIfFalseOrPop(arg)
+            | Instruction::JumpIfTrueOrPop(arg)
+            | Instruction::CompareJumpIfFalse(_, arg)
+            | Instruction::CompareJumpIfTrue(_, arg) => Some(arg),
+            Instruction::CompareOp(op) => {
+                Some(CMP_OPS.iter().position(|&o| o == op).unwrap() as u32)
+            }
+            Instruction::IsOp(invert) | Instruction::ContainsOp(invert) => Some(invert as u32),
+            Instruction::Nop
+            | Instruction::PopTop
+            | Instruction::RotTwo
+            | Instruction::RotThree
+            | Instruction::DupTop
+            | Instruction::BinarySubscr
+            | Instruction::DeleteSubscr
+            | Instruction::UnaryOp(_)
+            | Instruction::BinaryOp(_)
+            | Instruction::InplaceOp(_)
+            | Instruction::ListToTuple
+            | Instruction::ReturnValue
+            | Instruction::GetIter
+            | Instruction::PushExcInfo
+            | Instruction::PopExcept
+            | Instruction::CheckExcMatch
+            | Instruction::BeforeWith
+            | Instruction::WithExceptStart
+            | Instruction::LoadBuildClass
+            | Instruction::LoadAssertionError => None,
+        }
+    }
+
+    /// How many values the instruction takes from the top of the stack,
+    /// or needs to be there, as for the list a `ListAppend` appends to.
+    pub fn stack_inputs(self) -> u32 {
+        match self {
+            Instruction::Nop
+            | Instruction::LoadConst(_)
+            | Instruction::LoadName(_)
+            | Instruction::LoadGlobal(_)
+            | Instruction::LoadFast(_)
+            | Instruction::LoadDeref(_)
+            | Instruction::LoadClosure(_)
+            | Instruction::JumpAbsolute(_)
+            | Instruction::DeleteName(_)
+            | Instruction::DeleteGlobal(_)
+            | Instruction::DeleteFast(_)
+            | Instruction::DeleteDeref(_)
+            | Instruction::LoadBuildClass
+            | Instruction::LoadAssertionError => 0,
+            Instruction::PopTop
+            | Instruction::DupTop
+            | Instruction::StoreName(_)
+            | Instruction::StoreGlobal(_)
+            | Instruction::StoreFast(_)
+            | Instruction::StoreDeref(_)
+            | Instruction::LoadAttr(_)
+            | Instruction::DeleteAttr(_)
+            | Instruction::UnaryOp(_)
+            | Instruction::UnpackSequence(_)
+            | Instruction::ListToTuple
+            | Instruction::CallIntrinsic1(_)
+            | Instruction::ReturnValue
+            | Instruction::GetIter
+            | Instruction::ForIter(_)
+            | Instruction::PopJumpIfFalse(_)
+            | Instruction::PopJumpIfTrue(_)
+            | Instruction::JumpIfFalseOrPop(_)
+            | Instruction::JumpIfTrueOrPop(_)
+            | Instruction::PushExcInfo
+            | Instruction::PopExcept
+            | Instruction::BeforeWith => 1,
+            Instruction::RotTwo
+            | Instruction::BinarySubscr
+            | Instruction::BinaryOp(_)
+            | Instruction::InplaceOp(_)
+            | Instruction::CompareOp(_)
+            | Instruction::IsOp(_)
+            | Instruction::ContainsOp(_)
+            | Instruction::CompareJumpIfFalse(..)
+            | Instruction::CompareJumpIfTrue(..)
+            | Instruction::StoreAttr(_)
+            | Instruction::DeleteSubscr
+            | Instruction::CallIntrinsic2(_)
+            | Instruction::CheckExcMatch => 2,
+            Instruction::RotThree => 3,
+            Instruction::WithExceptStart => 4,
+            Instruction::Copy(depth) | Instruction::RaiseVarargs(depth) => depth,
+            Instruction::Reraise(depth) => depth + 1,
+            Instruction::BuildTuple(count)
+            | Instruction::BuildList(count)
+            | Instruction::BuildSet(count) => count,
+            Instruction::BuildMap(count) => 2 * count,
+            Instruction::BuildConstKeyMap(count) => count + 1,
+            Instruction::ListAppend(depth)
+            | Instruction::SetAdd(depth)
+            | Instruction::ListExtend(depth)
+            | Instruction::DictMerge(depth) => depth + 1,
+            Instruction::MapAdd(depth) => depth + 2,
+            Instruction::CallFunction(count) => count + 1,
+            Instruction::CallFunctionKw(count) => count + 2,
+            Instruction::CallFunctionEx(flags) => 2 + (flags & 1),
+            Instruction::MakeFunction(flags) => 2 + flags.count_ones(),
+        }
+    }
+
+    /// The functions an intrinsic call can make, by number and name, or
+    /// nothing if the instruction is not one.
+    pub fn intrinsics(self) -> Option<&'static [(u32, &'static str)]> {
+        match self {
+            Instruction::CallIntrinsic1(_) => Some(&INTRINSICS_1),
+            Instruction::CallIntrinsic2(_) => Some(&INTRINSICS_2),
+            _ => None,
+        }
+    }
+
+    /// Whether the instruction never goes on to the next one.
+    pub fn is_terminal(self) -> bool {
+        matches!(
+            self,
+            Instruction::ReturnValue
+                | Instruction::JumpAbsolute(_)
+                | Instruction::RaiseVarargs(_)
+                | Instruction::Reraise(_)
+        )
+    }
+}
+
+/// Packs `instructions` into code units, as a code object holds them. A
+/// target past the end, which only code the verifier rejects has, stays
+/// as far past the end.
+pub fn pack(instructions: &[Instruction]) -> Vec<u16> {
+    // The units an instruction takes depend on its argument, a jump's on
+    // where its target is, and that on the units of the instructions
+    // before it: each instruction starts with one unit and grows until
+    // its argument fits.
+    let mut sizes = vec![1; instructions.len()];
+    let caches: Vec<usize> = instructions
+        .iter()
+        .map(|instruction| instruction.opcode().caches())
+        .collect();
+    loop {
+        let mut offsets = vec![0];
+        for (size, caches) in sizes.iter().zip(&caches) {
+            offsets.push(offsets.last().unwrap() + size + caches);
+        }
+        let encode = |instruction: Instruction| match instruction.jump_target() {
+            Some(target) => {
+                let target = target as usize;
+                let offset = match offsets.get(target) {
+                    Some(&offset) => offset,
+                    None => offsets[instructions.len()] + target - instructions.len(),
+                };
+                let offset = u32::try_from(offset).unwrap_or(u32::MAX);
+                instruction.with_jump_target(offset).encode()
+            }
+            None => instruction.encode(),
+        };
+        let mut grown = false;
+        for (size, &instruction) in sizes.iter_mut().zip(instructions) {
+            let (_, arg) = encode(instruction);
+            let needed = (1..4).take_while(|bytes| arg >> (8 * bytes) != 0).count() + 1;
+            if needed > *size {
+                *size = needed;
+                grown = true;
+            }
+        }
+        if grown {
+            continue;
+        }
+        let mut code = Vec::with_capacity(offsets[instructions.len()]);
+        for ((&instruction, &size), &caches) in instructions.iter().zip(&sizes).zip(&caches) {
+            let (opcode, arg) = encode(instruction);
+            for byte in (1..size).rev() {
+                code.push(unit(Opcode::ExtendedArg as u8, arg >> (8 * byte)));
+            }
+            code.push(unit(opcode, arg));
+            code.extend(std::iter::repeat_n(Opcode::Cache as u16, caches));
+        }
+        return code;
+    }
+}
+
+/// The instructions [`pack`] packed into `code`.
+pub fn unpack(code: &[u16]) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+    // The offset of each instruction's first unit.
+    let mut starts = Vec::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        starts.push(offset);
+        let mut arg = 0;
+        let opcode = loop {
+            let unit = code[offset];
+            offset += 1;
+            arg = arg << 8 | (unit >> 8) as u32;
+            if unit as u8 != Opcode::ExtendedArg as u8 {
+                break unit as u8;
+            }
+        };
+        offset += Opcode::from_u8(opcode).map_or(0, Opcode::caches);
+        let instruction = Instruction::decode(opcode, arg);
+        instructions.push(instruction.expect("packed code holds only instructions"));
+    }
+    let len = instructions.len();
+    for instruction in &mut instructions {
+        if let Some(target) = instruction.jump_target() {
+            let target = target as usize;
+            let index = match starts.binary_search(&target) {
+                Ok(index) => index,
+                Err(_) if target >= code.len() => len + target - code.len(),
+                Err(_) => panic!("a jump into the middle of an instruction"),
+            };
+            *instruction = instruction.with_jump_target(index as u32);
+        }
+    }
+    instructions
+}
+
+/// The code unit of an opcode and the low byte of its argument.
+fn unit(opcode: u8, arg: u32) -> u16 {
+    opcode as u16 | (arg as u16 & 0xff) << 8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pack, unpack, CmpOp, Instruction, Opcode};
+
+    #[test]
+    fn test_opcode_numbers() {
+        for opcode in Opcode::ALL {
+            assert_eq!(Opcode::from_u8(opcode as u8), Some(opcode));
+        }
+        assert_eq!(Opcode::from_u8(5), None);
+    }
+
+    #[test]
+    fn test_specialized_opcodes() {
+        for opcode in Opcode::ALL {
+            let generic = opcode.generic();
+            assert_eq!(generic.generic(), generic);
+            assert_eq!(opcode.caches(), generic.caches());
+            if generic != opcode {
+                // Only the VM writes specialized instructions.
+                assert!(generic.caches() > 0);
+                assert_eq!(Instruction::decode(opcode as u8, 0), None);
+                assert_eq!(Instruction::from_opname(opcode.name(), 0), None);
+            }
+        }
+        assert_eq!(Opcode::LoadGlobalBuiltin.generic(), Opcode::LoadGlobal);
+        assert_eq!(Instruction::decode(Opcode::Cache as u8, 0), None);
+    }
+
+    #[test]
+    fn test_superinstructions() {
+        let superinstruction = Opcode::LoadFast.superinstruction(Opcode::LoadConst);
+        assert_eq!(superinstruction, Some(Opcode::LoadFastLoadConst));
+        assert_eq!(Opcode::LoadConst.superinstruction(Opcode::LoadConst), None);
+        // Only the VM writes superinstructions.
+        assert_eq!(Instruction::decode(Opcode::LoadFastLoadFast as u8, 0), None);
+        assert_eq!(Instruction::from_opname("LOAD_FAST__LOAD_FAST", 0), None);
+    }
+
+    #[test]
+    fn test_pack() {
+        use Instruction::*;
+        let instructions = [LoadConst(1), PopJumpIfFalse(3), Nop, ReturnValue];
+        let code = pack(&instructions);
+        assert_eq!(
+            code,
+            [
+                100 | 1 << 8,
+                114 | 3 << 8,
+                Opcode::Nop as u16,
+                Opcode::ReturnValue as u16
+            ]
+        );
+        assert_eq!(unpack(&code), instructions);
+
+        // A jump over 300 units takes an `EXTENDED_ARG`, and so moves the
+        // instructions after it along.
+        let mut instructions = vec![JumpAbsolute(302), LoadConst(300)];
+        instructions.extend([Nop; 300]);
+        instructions.push(ReturnValue);
+        let code = pack(&instructions);
+        assert_eq!(code.len(), 305);
+        assert_eq!(
+            code[..4],
+            [144 | 1 << 8, 113 | 48 << 8, 144 | 1 << 8, 100 | 44 << 8]
+        );
+        assert_eq!(unpack(&code), instructions);
+
+        // A fused comparison keeps its operator under the target and is
+        // followed by its inline cache, and a target past the end stays as
+        // far past it.
+        let instructions = [CompareJumpIfFalse(CmpOp::Gt, 20), JumpAbsolute(1000)];
+        let code = pack(&instructions);
+        assert_eq!(code.len(), 5);
+        assert_eq!(code[..3], [144 | 1 << 8, 200 | 0x74 << 8, 0]);
+        assert_eq!(unpack(&code), instructions);
+
+        // Caches count towards jump offsets.
+        let instructions = [
+            LoadGlobal(0),
+            PopJumpIfTrue(3),
+            CallFunction(0),
+            ReturnValue,
+        ];
+        let code = pack(&instructions);
+        assert_eq!(code.len(), 9);
+        assert_eq!(code[5], 115 | 8 << 8);
+        assert_eq!(unpack(&code), instructions);
+    }
+}
@@ -0,0 +1,26 @@
+pub mod asm;
+pub mod ast;
+pub mod cache;
+pub mod checks;
+pub mod code;
+pub mod codegen;
+pub mod cst;
+pub mod dis;
+pub mod fold;
+pub mod intern;
+pub mod interpreter;
+pub mod intruction;
+pub mod marshal;
+pub mod object;
+pub mod parser;
+pub mod peephole;
+pub mod pyc;
+pub mod specialize;
+pub mod symtable;
+pub mod tokenizer;
+pub mod transformer;
+pub mod unparse;
+pub mod value;
+pub mod verify;
+pub mod visitor;
+pub mod vm;
@@ -1,12 +1,171 @@
-mod code;
-mod codegen;
-mod environment;
-mod interpreter;
-mod intruction;
-mod object;
-mod parser;
-mod tokenizer;
-mod value;
-mod vm;
-
-fn main() {}
+use std::io;
+use std::path::Path;
+use std::process::ExitCode;
+use std::rc::Rc;
+
+use rustypy::cache::{self, Invalidation};
+use rustypy::code::CodeObject;
+use rustypy::dis::dis;
+use rustypy::interpreter::{compile_source, format_traceback};
+use rustypy::pyc;
+use rustypy::vm::Vm;
+
+const USAGE: &str = "usage: rustypy [-O] [-X pystats] <file.py | file.pyc>
+       rustypy dis [-O] <file.py | file.pyc>
+       rustypy compileall [-O] [-f] [--invalidation-mode MODE] <dir>
+       rustypy pyc [-O] <file.py>";
+
+/// The stack of the thread that runs the program.
+const STACK_SIZE: usize = 256 * 1024 * 1024;
+
+fn main() -> ExitCode {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    // A command comes first; without one, the file is run.
+    let command = match args.first().map(String::as_str) {
+        Some("dis" | "compileall" | "pyc") => Some(args.remove(0)),
+        _ => None,
+    };
+    let compileall = command.as_deref() == Some("compileall");
+    let mut optimize = 0;
+    let mut force = false;
+    let mut invalidation = Invalidation::Timestamp;
+    // Whether to print how specialization went, as CPython's `-X pystats`.
+    let mut pystats = false;
+    let mut path = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-O" => optimize = 1,
+            "-f" if compileall => force = true,
+            "-X" if command.is_none() && args.next().as_deref() == Some("pystats") => {
+                pystats = true
+            }
+            "--invalidation-mode" if compileall => {
+                match args.next().as_deref().and_then(Invalidation::from_name) {
+                    Some(mode) => invalidation = mode,
+                    None => {
+                        eprintln!("{}", USAGE);
+                        return ExitCode::from(2);
+                    }
+                }
+            }
+            _ if path.is_none() && !arg.starts_with('-') => path = Some(arg),
+            _ => {
+                eprintln!("{}", USAGE);
+                return ExitCode::from(2);
+            }
+        }
+    }
+    let Some(path) = path else {
+        eprintln!("{}", USAGE);
+        return ExitCode::from(2);
+    };
+    if compileall {
+        let dir = Path::new(&path);
+        return match cache::compile_dir(dir, optimize, invalidation, force, &mut io::stdout()) {
+            Ok(true) => ExitCode::SUCCESS,
+            Ok(false) => ExitCode::FAILURE,
+            Err(error) => {
+                eprintln!("rustypy: can't compile '{}': {}", path, error);
+                ExitCode::FAILURE
+            }
+        };
+    }
+    if command.as_deref() == Some("pyc") {
+        return write_pyc(&path, optimize);
+    }
+    if command.is_some() {
+        return match load(&path, optimize, false) {
+            Ok((code, _)) => {
+                print!("{}", dis(&code));
+                ExitCode::SUCCESS
+            }
+            Err(code) => code,
+        };
+    }
+    // Each Python call nests a few Rust calls, and a debug build's frames
+    // are big, so the recursion limit needs more than the main thread's
+    // stack.
+    let runner = std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            let (code, source) = match load(&path, optimize, true) {
+                Ok(loaded) => loaded,
+                Err(code) => return code,
+            };
+            let mut vm = Vm::new(io::stdout());
+            let result = vm.run(Rc::new(code));
+            if pystats {
+                eprint!("{}", vm.stats());
+            }
+            match result {
+                Ok(_) => ExitCode::SUCCESS,
+                Err(exception) => {
+                    eprintln!("{}", format_traceback(&exception, &source));
+                    ExitCode::FAILURE
+                }
+            }
+        })
+        .expect("failed to start the interpreter");
+    runner.join().unwrap_or(ExitCode::FAILURE)
+}
+
+/// The code of the module at `path`, with its source, reporting why if it
+/// cannot be had. A `.pyc` file of CPython's is translated, and has no
+/// source; other modules are compiled, unless `cached` and their cache is
+/// up to date.
+fn load(path: &str, optimize: u8, cached: bool) -> Result<(CodeObject, String), ExitCode> {
+    let can_not_open = |error| {
+        eprintln!("rustypy: can't open file '{}': {}", path, error);
+        ExitCode::from(2)
+    };
+    if path.ends_with(".pyc") {
+        let bytes = std::fs::read(path).map_err(can_not_open)?;
+        return match pyc::load(&bytes) {
+            Ok(code) => Ok((code, String::new())),
+            Err(error) => {
+                eprintln!("rustypy: can't run '{}': {}", path, error);
+                Err(ExitCode::FAILURE)
+            }
+        };
+    }
+    let source = std::fs::read_to_string(path).map_err(can_not_open)?;
+    let code = if cached {
+        cache::load(Path::new(path), &source, optimize)
+    } else {
+        compile_source(&source, path, optimize)
+    };
+    match code {
+        Ok(code) => Ok((code, source)),
+        Err(errors) => {
+            for error in errors {
+                eprintln!("SyntaxError: {}", error);
+            }
+            Err(ExitCode::FAILURE)
+        }
+    }
+}
+
+/// Compiles the module at `path` into a CPython 3.11 `.pyc` file beside
+/// it, for CPython's tools to read.
+fn write_pyc(path: &str, optimize: u8) -> ExitCode {
+    let (code, source) = match load(path, optimize, false) {
+        Ok(loaded) => loaded,
+        Err(code) => return code,
+    };
+    let source = cache::Source {
+        text: source.as_bytes(),
+        mtime: cache::modified(Path::new(path)).unwrap_or(0),
+    };
+    let target = Path::new(path).with_extension("pyc");
+    let written = pyc::dumps(&code, source)
+        .map_err(|error| error.to_string())
+        .and_then(|bytes| std::fs::write(&target, bytes).map_err(|error| error.to_string()));
+    match written {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("rustypy: can't write '{}': {}", target.display(), error);
+            ExitCode::FAILURE
+        }
+    }
+}
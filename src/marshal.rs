@@ -0,0 +1,552 @@
+//! CPython's `marshal` format, in which `.pyc` files hold their code, as
+//! CPython 3.11 writes it.
+//!
+//! Each object is a type byte followed by its contents. An object whose
+//! type byte has [`FLAG_REF`] set is remembered, and a later `r` object,
+//! the index of one remembered, stands for it again. Code objects are
+//! read and written as CPython 3.11 lays them out, as [`Code`], which
+//! keeps the bytecode as CPython's; the `pyc` module translates it to and
+//! from ours. Nothing is written to be referred to again, which costs
+//! only size.
+
+use std::fmt;
+use std::rc::Rc;
+
+/// The bit of a type byte that says the object may be referred to again.
+pub const FLAG_REF: u8 = 0x80;
+
+const TYPE_NULL: u8 = b'0';
+const TYPE_NONE: u8 = b'N';
+const TYPE_FALSE: u8 = b'F';
+const TYPE_TRUE: u8 = b'T';
+const TYPE_ELLIPSIS: u8 = b'.';
+const TYPE_INT: u8 = b'i';
+const TYPE_LONG: u8 = b'l';
+const TYPE_BINARY_FLOAT: u8 = b'g';
+const TYPE_STRING: u8 = b's';
+const TYPE_INTERNED: u8 = b't';
+const TYPE_REF: u8 = b'r';
+const TYPE_TUPLE: u8 = b'(';
+const TYPE_CODE: u8 = b'c';
+const TYPE_UNICODE: u8 = b'u';
+const TYPE_ASCII: u8 = b'a';
+const TYPE_ASCII_INTERNED: u8 = b'A';
+const TYPE_SMALL_TUPLE: u8 = b')';
+const TYPE_SHORT_ASCII: u8 = b'z';
+const TYPE_SHORT_ASCII_INTERNED: u8 = b'Z';
+
+/// The bits of each digit of a long, which is written in base 2**15.
+const LONG_SHIFT: u32 = 15;
+
+/// A value that marshal reads.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Object {
+    None,
+    Ellipsis,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(Rc<str>),
+    Bytes(Rc<[u8]>),
+    Tuple(Rc<[Object]>),
+    Code(Rc<Code>),
+}
+
+impl Object {
+    /// What CPython calls the object's type.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Object::None => "NoneType",
+            Object::Ellipsis => "ellipsis",
+            Object::Bool(_) => "bool",
+            Object::Int(_) => "int",
+            Object::Float(_) => "float",
+            Object::Str(_) => "str",
+            Object::Bytes(_) => "bytes",
+            Object::Tuple(_) => "tuple",
+            Object::Code(_) => "code",
+        }
+    }
+}
+
+/// A code object as CPython 3.11 has it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Code {
+    pub argcount: u32,
+    pub posonlyargcount: u32,
+    pub kwonlyargcount: u32,
+    pub stacksize: u32,
+    pub flags: u32,
+    /// The instructions, two bytes each, with the inline caches after
+    /// those that have them.
+    pub code: Vec<u8>,
+    pub consts: Vec<Object>,
+    pub names: Vec<Rc<str>>,
+    /// The local variables, the cells and the free variables, one list.
+    pub localsplusnames: Vec<Rc<str>>,
+    /// Which of those each is, as the `CO_FAST_*` bits.
+    pub localspluskinds: Vec<u8>,
+    pub filename: Rc<str>,
+    pub name: Rc<str>,
+    pub qualname: Rc<str>,
+    pub firstlineno: u32,
+    /// The source location of each instruction, as PEP 657 compresses it.
+    pub linetable: Vec<u8>,
+    pub exceptiontable: Vec<u8>,
+}
+
+/// The bit of a `localspluskinds` byte for a local variable.
+pub const CO_FAST_LOCAL: u8 = 0x20;
+/// The bit of a `localspluskinds` byte for a cell.
+pub const CO_FAST_CELL: u8 = 0x40;
+/// The bit of a `localspluskinds` byte for a free variable.
+pub const CO_FAST_FREE: u8 = 0x80;
+
+/// What is wrong with marshalled data, and where.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MarshalError {
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for MarshalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "offset {}: {}", self.offset, self.message)
+    }
+}
+
+pub type MarshalResult<T> = Result<T, MarshalError>;
+
+/// Reads the one object in `bytes`, as `marshal.loads` does.
+pub fn loads(bytes: &[u8]) -> MarshalResult<Object> {
+    let mut reader = Reader {
+        bytes,
+        at: 0,
+        refs: Vec::new(),
+    };
+    let object = reader.object()?;
+    if reader.at != bytes.len() {
+        return Err(reader.error("bytes after the object"));
+    }
+    Ok(object)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+    /// The objects remembered so far, or `None` for one still being read.
+    refs: Vec<Option<Object>>,
+}
+
+impl Reader<'_> {
+    fn error(&self, message: impl Into<String>) -> MarshalError {
+        MarshalError {
+            offset: self.at,
+            message: message.into(),
+        }
+    }
+
+    fn take(&mut self, len: usize) -> MarshalResult<&[u8]> {
+        if self.bytes.len() - self.at < len {
+            return Err(self.error("the data is cut short"));
+        }
+        let bytes = &self.bytes[self.at..self.at + len];
+        self.at += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> MarshalResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn i32(&mut self) -> MarshalResult<i32> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> MarshalResult<u32> {
+        Ok(self.i32()? as u32)
+    }
+
+    fn size(&mut self) -> MarshalResult<usize> {
+        let size = self.i32()?;
+        usize::try_from(size).map_err(|_| self.error(format!("a negative size {}", size)))
+    }
+
+    fn object(&mut self) -> MarshalResult<Object> {
+        let start = self.at;
+        let byte = self.u8()?;
+        let (kind, remembered) = (byte & !FLAG_REF, byte & FLAG_REF != 0);
+        // Containers are remembered before their contents are read, as
+        // CPython numbers them.
+        let slot = remembered.then(|| {
+            self.refs.push(None);
+            self.refs.len() - 1
+        });
+        let object = match kind {
+            TYPE_NONE => Object::None,
+            TYPE_ELLIPSIS => Object::Ellipsis,
+            TYPE_FALSE => Object::Bool(false),
+            TYPE_TRUE => Object::Bool(true),
+            TYPE_INT => Object::Int(self.i32()? as i64),
+            TYPE_LONG => Object::Int(self.long()?),
+            TYPE_BINARY_FLOAT => {
+                let bits = u64::from_le_bytes(self.take(8)?.try_into().unwrap());
+                Object::Float(f64::from_bits(bits))
+            }
+            TYPE_STRING => {
+                let len = self.size()?;
+                Object::Bytes(self.take(len)?.into())
+            }
+            TYPE_UNICODE | TYPE_INTERNED | TYPE_ASCII | TYPE_ASCII_INTERNED => {
+                let len = self.size()?;
+                Object::Str(self.text(len)?)
+            }
+            TYPE_SHORT_ASCII | TYPE_SHORT_ASCII_INTERNED => {
+                let len = self.u8()? as usize;
+                Object::Str(self.text(len)?)
+            }
+            TYPE_TUPLE | TYPE_SMALL_TUPLE => {
+                let len = if kind == TYPE_TUPLE {
+                    self.size()?
+                } else {
+                    self.u8()? as usize
+                };
+                let items: Vec<Object> =
+                    (0..len).map(|_| self.object()).collect::<Result<_, _>>()?;
+                Object::Tuple(items.into())
+            }
+            TYPE_CODE => Object::Code(Rc::new(self.code()?)),
+            TYPE_REF => {
+                let index = self.u32()? as usize;
+                match self.refs.get(index) {
+                    Some(Some(object)) => object.clone(),
+                    Some(None) => return Err(self.error(format!("ref {} is to itself", index))),
+                    None => {
+                        return Err(self.error(format!(
+                            "ref {} is out of range: there are {} refs",
+                            index,
+                            self.refs.len()
+                        )))
+                    }
+                }
+            }
+            TYPE_NULL => {
+                return Err(MarshalError {
+                    offset: start,
+                    message: "a NULL object".to_string(),
+                })
+            }
+            _ => {
+                return Err(MarshalError {
+                    offset: start,
+                    message: format!("unsupported type {:?}", kind as char),
+                })
+            }
+        };
+        if let Some(slot) = slot {
+            self.refs[slot] = Some(object.clone());
+        }
+        Ok(object)
+    }
+
+    fn text(&mut self, len: usize) -> MarshalResult<Rc<str>> {
+        let bytes = self.take(len)?;
+        match std::str::from_utf8(bytes) {
+            Ok(text) => Ok(text.into()),
+            Err(_) => Err(self.error("a string is not UTF-8")),
+        }
+    }
+
+    /// A long: its count of digits, negative for a negative number, then
+    /// the digits, lowest first.
+    fn long(&mut self) -> MarshalResult<i64> {
+        let count = self.i32()?;
+        let mut value: i64 = 0;
+        for index in 0..count.unsigned_abs() {
+            let digit = u16::from_le_bytes(self.take(2)?.try_into().unwrap()) as i64;
+            let shifted = (index * LONG_SHIFT < 63)
+                .then(|| digit.checked_mul(1 << (index * LONG_SHIFT)))
+                .flatten();
+            value = shifted
+                .and_then(|shifted| value.checked_add(shifted))
+                .ok_or_else(|| self.error("an int too big for 64 bits"))?;
+        }
+        Ok(if count < 0 { -value } else { value })
+    }
+
+    fn bytes(&mut self, what: &str) -> MarshalResult<Vec<u8>> {
+        match self.object()? {
+            Object::Bytes(bytes) => Ok(bytes.to_vec()),
+            other => Err(self.error(format!("{} is a {}, not bytes", what, other.type_name()))),
+        }
+    }
+
+    fn str(&mut self, what: &str) -> MarshalResult<Rc<str>> {
+        match self.object()? {
+            Object::Str(text) => Ok(text),
+            other => Err(self.error(format!("{} is a {}, not a str", what, other.type_name()))),
+        }
+    }
+
+    fn objects(&mut self, what: &str) -> MarshalResult<Vec<Object>> {
+        match self.object()? {
+            Object::Tuple(items) => Ok(items.to_vec()),
+            other => Err(self.error(format!("{} is a {}, not a tuple", what, other.type_name()))),
+        }
+    }
+
+    fn strs(&mut self, what: &str) -> MarshalResult<Vec<Rc<str>>> {
+        let items = self.objects(what)?;
+        items
+            .into_iter()
+            .map(|item| match item {
+                Object::Str(text) => Ok(text),
+                other => Err(self.error(format!(
+                    "an item of {} is a {}, not a str",
+                    what,
+                    other.type_name()
+                ))),
+            })
+            .collect()
+    }
+
+    /// A code object's fields, in the order CPython 3.11 writes them.
+    fn code(&mut self) -> MarshalResult<Code> {
+        let argcount = self.u32()?;
+        let posonlyargcount = self.u32()?;
+        let kwonlyargcount = self.u32()?;
+        let stacksize = self.u32()?;
+        let flags = self.u32()?;
+        let code = self.bytes("co_code")?;
+        let consts = self.objects("co_consts")?;
+        let names = self.strs("co_names")?;
+        let localsplusnames = self.strs("co_localsplusnames")?;
+        let localspluskinds = self.bytes("co_localspluskinds")?;
+        let filename = self.str("co_filename")?;
+        let name = self.str("co_name")?;
+        let qualname = self.str("co_qualname")?;
+        let firstlineno = self.u32()?;
+        let linetable = self.bytes("co_linetable")?;
+        let exceptiontable = self.bytes("co_exceptiontable")?;
+        if localsplusnames.len() != localspluskinds.len() {
+            return Err(self.error(format!(
+                "{} has {} local names but {} kinds",
+                qualname,
+                localsplusnames.len(),
+                localspluskinds.len()
+            )));
+        }
+        Ok(Code {
+            argcount,
+            posonlyargcount,
+            kwonlyargcount,
+            stacksize,
+            flags,
+            code,
+            consts,
+            names,
+            localsplusnames,
+            localspluskinds,
+            filename,
+            name,
+            qualname,
+            firstlineno,
+            linetable,
+            exceptiontable,
+        })
+    }
+}
+
+/// The bytes of `object`, as `marshal.dumps` writes them.
+pub fn dumps(object: &Object) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    write(&mut bytes, object);
+    bytes
+}
+
+fn write(bytes: &mut Vec<u8>, object: &Object) {
+    let size = |bytes: &mut Vec<u8>, len: usize| bytes.extend((len as u32).to_le_bytes());
+    match object {
+        Object::None => bytes.push(TYPE_NONE),
+        Object::Ellipsis => bytes.push(TYPE_ELLIPSIS),
+        Object::Bool(false) => bytes.push(TYPE_FALSE),
+        Object::Bool(true) => bytes.push(TYPE_TRUE),
+        Object::Int(value) => match i32::try_from(*value) {
+            Ok(value) => {
+                bytes.push(TYPE_INT);
+                bytes.extend(value.to_le_bytes());
+            }
+            Err(_) => {
+                let mut digits = Vec::new();
+                let mut rest = value.unsigned_abs();
+                while rest != 0 {
+                    digits.push((rest & ((1 << LONG_SHIFT) - 1)) as u16);
+                    rest >>= LONG_SHIFT;
+                }
+                let count = digits.len() as i32;
+                bytes.push(TYPE_LONG);
+                bytes.extend(if *value < 0 { -count } else { count }.to_le_bytes());
+                for digit in digits {
+                    bytes.extend(digit.to_le_bytes());
+                }
+            }
+        },
+        Object::Float(value) => {
+            bytes.push(TYPE_BINARY_FLOAT);
+            bytes.extend(value.to_bits().to_le_bytes());
+        }
+        Object::Str(text) => {
+            if !text.is_ascii() {
+                bytes.push(TYPE_UNICODE);
+                size(bytes, text.len());
+            } else if text.len() < 256 {
+                bytes.extend([TYPE_SHORT_ASCII, text.len() as u8]);
+            } else {
+                bytes.push(TYPE_ASCII);
+                size(bytes, text.len());
+            }
+            bytes.extend(text.as_bytes());
+        }
+        Object::Bytes(data) => {
+            bytes.push(TYPE_STRING);
+            size(bytes, data.len());
+            bytes.extend(data.iter());
+        }
+        Object::Tuple(items) => {
+            if items.len() < 256 {
+                bytes.extend([TYPE_SMALL_TUPLE, items.len() as u8]);
+            } else {
+                bytes.push(TYPE_TUPLE);
+                size(bytes, items.len());
+            }
+            for item in items.iter() {
+                write(bytes, item);
+            }
+        }
+        Object::Code(code) => {
+            bytes.push(TYPE_CODE);
+            let strs =
+                |items: &[Rc<str>]| Object::Tuple(items.iter().cloned().map(Object::Str).collect());
+            for field in [
+                code.argcount,
+                code.posonlyargcount,
+                code.kwonlyargcount,
+                code.stacksize,
+                code.flags,
+            ] {
+                bytes.extend(field.to_le_bytes());
+            }
+            write(bytes, &Object::Bytes(code.code.as_slice().into()));
+            write(bytes, &Object::Tuple(code.consts.as_slice().into()));
+            write(bytes, &strs(&code.names));
+            write(bytes, &strs(&code.localsplusnames));
+            write(
+                bytes,
+                &Object::Bytes(code.localspluskinds.as_slice().into()),
+            );
+            write(bytes, &Object::Str(code.filename.clone()));
+            write(bytes, &Object::Str(code.name.clone()));
+            write(bytes, &Object::Str(code.qualname.clone()));
+            bytes.extend(code.firstlineno.to_le_bytes());
+            write(bytes, &Object::Bytes(code.linetable.as_slice().into()));
+            write(bytes, &Object::Bytes(code.exceptiontable.as_slice().into()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dumps, loads, Object};
+
+    /// Bytes written in hex, as `marshal.dumps(...).hex()` gives them.
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_marshal_loads() {
+        // (1, -2**40, 1.5, 'héllo', b'ab', None, True, False, ...)
+        let bytes = hex(
+            "a909e901000000ecfdffffff000000000004e7000000000000f83ff50600000068c3a96c6c6ff302000000\
+             61624e54462e",
+        );
+        let expected = Object::Tuple(
+            vec![
+                Object::Int(1),
+                Object::Int(-(1 << 40)),
+                Object::Float(1.5),
+                Object::Str("héllo".into()),
+                Object::Bytes(b"ab"[..].into()),
+                Object::None,
+                Object::Bool(true),
+                Object::Bool(false),
+                Object::Ellipsis,
+            ]
+            .into(),
+        );
+        assert_eq!(loads(&bytes), Ok(expected));
+        // ('spam', 'spam'), the second a ref to the first.
+        let spam = Object::Str("spam".into());
+        assert_eq!(
+            loads(&hex("2902da047370616d7200000000")),
+            Ok(Object::Tuple(vec![spam.clone(), spam].into()))
+        );
+    }
+
+    #[test]
+    fn test_marshal_dumps() {
+        let tuple = Object::Tuple(
+            vec![
+                Object::Int(1),
+                Object::Int(-(1 << 40)),
+                Object::Float(1.5),
+                Object::Str("héllo".into()),
+                Object::Bytes(b"ab"[..].into()),
+                Object::None,
+                Object::Bool(true),
+                Object::Bool(false),
+                Object::Ellipsis,
+            ]
+            .into(),
+        );
+        // As CPython writes it, but with nothing to be referred to again.
+        assert_eq!(
+            dumps(&tuple),
+            hex(
+                "290969010000006cfdffffff00000000000467000000000000f83f750600000068c3a96c6c6f7302000000\
+                 61624e54462e"
+            )
+        );
+        let long = Object::Str("x".repeat(300).into());
+        let many = Object::Tuple(vec![Object::Int(i64::MAX); 300].into());
+        for object in [tuple, long, many] {
+            assert_eq!(loads(&dumps(&object)), Ok(object));
+        }
+        // A CPython code object, back as it was read.
+        let pyc = std::fs::read("tests/__pycache__/fib.cpython-311.pyc").unwrap();
+        let code = loads(&pyc[16..]).unwrap();
+        assert_eq!(loads(&dumps(&code)), Ok(code));
+    }
+
+    #[test]
+    fn test_marshal_errors() {
+        let error = |text: &str| loads(&hex(text)).unwrap_err().to_string();
+        // 2**70
+        assert_eq!(
+            error("6c0500000000000000000000000004"),
+            "offset 15: an int too big for 64 bits"
+        );
+        assert_eq!(error("e9010000"), "offset 1: the data is cut short");
+        assert_eq!(error("2902da047370616d"), "offset 8: the data is cut short");
+        assert_eq!(
+            error("2901720000000000"),
+            "offset 7: ref 0 is out of range: there are 0 refs"
+        );
+        assert_eq!(error("a901720000000000"), "offset 7: ref 0 is to itself");
+        assert_eq!(error("4e4e"), "offset 1: bytes after the object");
+        assert_eq!(error("3c00000000"), "offset 0: unsupported type '<'");
+    }
+}
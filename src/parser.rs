@@ -1,530 +1,2473 @@
-// use core::panic;
-
-// use crate::token::{Token, TokenType};
-
-// pub trait AstNode: PrettyPrint {}
-// #[derive(Debug, Clone, PartialEq)]
-// pub enum Expr {
-//     IntLiteral(i32),
-//     Identifier(Token),
-//     Builtin(Token),
-//     BinaryOp(Box<Expr>, Box<Expr>, Token),
-//     Call(Box<Expr>, Vec<Expr>),
-//     Assign(Box<Expr>, Box<Expr>),
-// }
-
-// impl AstNode for Expr {} 
-// #[derive(Debug, Clone, PartialEq)]
-// pub enum Stmt {
-//     Def(Expr, Vec<Expr>, Box<Stmt>),
-//     If(Expr, Box<Stmt>, Option<Box<Stmt>>),
-//     Return(Expr),
-//     Expr(Expr),
-//     Block(Vec<Stmt>),
-//     Break,
-//     While(Expr, Box<Stmt>),
-// }
-// impl AstNode for Stmt {}
-
-// pub trait PrettyPrint {
-//     fn pretty_print(&self, indent: usize) -> String;
-// }
-
-// impl PrettyPrint for Expr {
-//     fn pretty_print(&self, indent: usize) -> String {
-//         match self {
-//             Expr::IntLiteral(value) => format!("{}", value),
-//             Expr::Identifier(token) => format!("{}", token.value),
-//             Expr::BinaryOp(left, right, op) => {
-//                 format!(
-//                     "({} {} {})",
-//                     left.pretty_print(indent),
-//                     op.value,
-//                     right.pretty_print(indent)
-//                 )
-//             }
-//             Expr::Call(callee, args) => {
-//                 let mut s = format!("{}(", callee.pretty_print(indent));
-//                 for (i, arg) in args.iter().enumerate() {
-//                     s.push_str(&arg.pretty_print(indent));
-//                     if i < args.len() - 1 {
-//                         s.push_str(", ");
-//                     }
-//                 }
-//                 s.push_str(")");
-//                 s
-//             }
-//             Expr::Assign(name, value) => format!(
-//                 "{} = {}",
-//                 name.pretty_print(indent),
-//                 value.pretty_print(indent)
-//             ),
-//             Expr::Builtin(token) => format!("{}", token.value),
-//         }
-//     }
-// }
-
-// impl PrettyPrint for Stmt {
-//     fn pretty_print(&self, indent: usize) -> String {
-//         match self {
-//             Stmt::Def(name, params, body) => {
-//                 let mut s = format!("def {}(", name.pretty_print(indent));
-//                 for (i, param) in params.iter().enumerate() {
-//                     s.push_str(&param.pretty_print(indent));
-//                     if i < params.len() - 1 {
-//                         s.push_str(", ");
-//                     }
-//                 }
-//                 s.push_str("):\n");
-//                 s.push_str(&body.pretty_print(indent));
-//                 s
-//             }
-//             Stmt::If(condition, then_branch, else_branch) => {
-//                 let mut s = format!(
-//                     "if {} :\n{}",
-//                     condition.pretty_print(indent),
-//                     then_branch.pretty_print(indent)
-//                 );
-//                 if let Some(else_branch) = else_branch {
-//                     s.push_str(&format!(" else {}", else_branch.pretty_print(indent)));
-//                 }
-//                 s
-//             }
-//             Stmt::Return(value) => {
-//                 format!("return {}\n", value.pretty_print(indent))
-//             }
-//             Stmt::Expr(expr) => format!("{}", expr.pretty_print(indent)),
-//             Stmt::Block(stmts) => {
-//                 let mut s = String::new();
-//                 for stmt in stmts {
-//                     s.push_str(&format!(
-//                         "{}{}",
-//                         "    ".repeat(indent + 1),
-//                         stmt.pretty_print(indent + 1)
-//                     ));
-//                 }
-//                 s.push('\n');
-//                 s
-//             }
-//             Stmt::Break => format!("{}break\n\n", "    ".repeat(indent)),
-//             Stmt::While(condition, body) => format!(
-//                 "while {}:\n{}",
-//                 condition.pretty_print(indent),
-//                 body.pretty_print(indent)
-//             ),
-//         }
-//     }
-// }
-
-// impl std::fmt::Display for Expr {
-//     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-//         match self {
-//             Expr::IntLiteral(value) => write!(f, "{}", value),
-//             Expr::Identifier(token) => write!(f, "{}", token.value),
-//             Expr::BinaryOp(left, right, op) => write!(f, "({} {} {})", left, op.value, right),
-//             Expr::Call(callee, args) => {
-//                 write!(f, "{}(", callee)?;
-//                 for (i, arg) in args.iter().enumerate() {
-//                     write!(f, "{}", arg)?;
-//                     if i < args.len() - 1 {
-//                         write!(f, ", ")?;
-//                     }
-//                 }
-//                 write!(f, ")")?;
-//                 Ok(())
-//             }
-//             Expr::Assign(name, value) => write!(f, "{} = {}", name, value),
-//             Expr::Builtin(token) => write!(f, "{}", token.value),
-//         }
-//     }
-// }
-
-// // implement display for Stmt
-// impl std::fmt::Display for Stmt {
-//     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-//         match self {
-//             Stmt::Def(name, params, body) => {
-//                 write!(f, "def {}(", name)?;
-//                 for (i, param) in params.iter().enumerate() {
-//                     write!(f, "{}", param)?;
-//                     if i < params.len() - 1 {
-//                         write!(f, ", ")?;
-//                     }
-//                 }
-//                 write!(f, "):\n")?;
-//                 write!(f, "\t{}", body)
-//             }
-//             Stmt::If(condition, body, else_body) => {
-//                 write!(f, "if {}:\n", condition)?;
-//                 write!(f, "{}", body)?;
-//                 if let Some(else_body) = else_body {
-//                     write!(f, "else {}", else_body)?;
-//                 }
-//                 Ok(())
-//             }
-//             Stmt::Return(expr) => write!(f, "return {}", expr),
-//             Stmt::Expr(expr) => write!(f, "{}", expr),
-//             Stmt::Block(stmts) => {
-//                 // add tab indent
-//                 for stmt in stmts {
-//                     write!(f, "{}\n", stmt)?;
-//                 }
-//                 Ok(())
-//             }
-//             Stmt::Break => write!(f, "break\n"),
-//             Stmt::While(condition, body) => {
-//                 write!(f, "while {}:\n", condition)?;
-//                 write!(f, "{}", body)
-//             }
-//         }
-//     }
-// }
-
-// pub fn parse_stmt(tokens: &mut &[Token]) -> Stmt {
-//     let token = &tokens[0];
-//     match token.token_type {
-//         TokenType::Def => parse_def(tokens),
-//         TokenType::If => parse_if(tokens),
-//         TokenType::Return => parse_return(tokens),
-//         TokenType::While => parse_while(tokens),
-//         TokenType::Break => parse_break(tokens),
-//         _ => parse_expr_stmt(tokens),
-//     }
-// }
-
-// pub fn skip_newline(tokens: &mut &[Token]) {
-//     let mut token = &tokens[0];
-//     while token.token_type == TokenType::NewLine {
-//         *tokens = &tokens[1..];
-//         token = &tokens[0];
-//     }
-// }
-
-// pub fn parse_def(tokens: &mut &[Token]) -> Stmt {
-//     *tokens = &tokens[1..];
-//     let name = parse_identifier_or_builtin(tokens);
-//     let mut params = Vec::new();
-//     let mut token = &tokens[0];
-//     if token.token_type == TokenType::LParen {
-//         *tokens = &tokens[1..];
-//         token = &tokens[0];
-//         while token.token_type != TokenType::RParen {
-//             let param = parse_expr(tokens);
-//             params.push(param);
-//             token = &tokens[0];
-//             if token.token_type == TokenType::Comma {
-//                 *tokens = &tokens[1..];
-//             }
-//         }
-//         *tokens = &tokens[1..];
-//     }
-//     *tokens = &tokens[1..];
-//     skip_newline(tokens);
-
-//     let body = parse_block(tokens);
-
-//     Stmt::Def(name, params, Box::new(body))
-// }
-
-// pub fn parse_if(tokens: &mut &[Token]) -> Stmt {
-//     *tokens = &tokens[1..];
-//     let condition = parse_expr(tokens);
-//     *tokens = &tokens[1..];
-//     skip_newline(tokens);
-//     let body = parse_block(tokens);
-//     Stmt::If(condition, Box::new(body), None)
-// }
-
-// pub fn parse_while(tokens: &mut &[Token]) -> Stmt {
-//     *tokens = &tokens[1..];
-//     let condition = parse_expr(tokens);
-//     *tokens = &tokens[1..];
-//     skip_newline(tokens);
-//     let body = parse_block(tokens);
-//     Stmt::While(condition, Box::new(body))
-// }
-
-// pub fn parse_break(tokens: &mut &[Token]) -> Stmt {
-//     *tokens = &tokens[1..];
-//     Stmt::Break
-// }
-
-// pub fn parse_identifier_or_builtin(tokens: &mut &[Token]) -> Expr {
-//     let token = &tokens[0];
-
-//     match token.token_type {
-//         TokenType::Identifier => {
-//             *tokens = &tokens[1..];
-//             Expr::Identifier(token.clone())
-//         }
-//         TokenType::Builtin => {
-//             *tokens = &tokens[1..];
-//             Expr::Builtin(token.clone())
-//         }
-//         _ => panic!("expecting identifier"),
-//     }
-// }
-
-// pub fn parse_block(tokens: &mut &[Token]) -> Stmt {
-//     // remove indent
-//     *tokens = &tokens[1..];
-//     let mut token = &tokens[0];
-//     let mut stmts = Vec::new();
-//     while token.token_type != TokenType::Dedent {
-//         let stmt = parse_stmt(tokens);
-//         stmts.push(stmt);
-//         skip_newline(tokens);
-//         token = &tokens[0];
-//     }
-//     *tokens = &tokens[1..];
-//     Stmt::Block(stmts)
-// }
-
-// pub fn parse_expr_stmt(tokens: &mut &[Token]) -> Stmt {
-//     let expr = parse_expr(tokens);
-//     // handle new_line
-//     *tokens = &tokens[1..];
-//     Stmt::Expr(expr)
-// }
-
-// pub fn parse_return(tokens: &mut &[Token]) -> Stmt {
-//     *tokens = &tokens[1..];
-//     let expr = parse_expr(tokens);
-//     Stmt::Return(expr)
-// }
-
-// // pub fn parse_print(tokens: &mut &[Token]) -> Stmt {
-// //     *tokens = &tokens[1..];
-// //     let expr = parse_expr(tokens);
-// //     Stmt::Print(expr)
-// // }
-
-// pub fn parse_expr(tokens: &mut &[Token]) -> Expr {
-//     parse_assignment(tokens)
-// }
-
-// pub fn parse_assignment(tokens: &mut &[Token]) -> Expr {
-//     let name = parse_equality(tokens);
-//     let token = &tokens[0];
-//     if token.token_type == TokenType::Assign {
-//         *tokens = &tokens[1..];
-//         let value = parse_expr(tokens);
-//         Expr::Assign(Box::new(name), Box::new(value))
-//     } else {
-//         name
-//     }
-// }
-
-// pub fn parse_equality(tokens: &mut &[Token]) -> Expr {
-//     let mut expr = parse_comparison(tokens);
-//     loop {
-//         let token = &tokens[0];
-//         if token.token_type != TokenType::EqualEqual && token.token_type != TokenType::BangEqual {
-//             break;
-//         }
-//         *tokens = &tokens[1..];
-//         let right = parse_comparison(tokens);
-//         expr = Expr::BinaryOp(Box::new(expr), Box::new(right), token.clone());
-//     }
-   
-//     expr
-// }
-
-// pub fn parse_comparison(tokens: &mut &[Token]) -> Expr {
-//     let mut expr = parse_term(tokens);
-//     loop {
-//         let token = &tokens[0];
-//         if token.token_type != TokenType::LessThan {
-//             break;
-//         }
-//         *tokens = &tokens[1..];
-//         let right = parse_term(tokens);
-//         expr = Expr::BinaryOp(Box::new(expr), Box::new(right), token.clone());
-//     }
-//     expr
-// }
-
-// pub fn parse_term(tokens: &mut &[Token]) -> Expr {
-//     let mut expr = parse_factor(tokens);
-//     loop {
-//         let token = &tokens[0];
-//         if token.token_type != TokenType::Plus && token.token_type != TokenType::Minus {
-//             break;
-//         }
-//         *tokens = &tokens[1..];
-//         let right = parse_factor(tokens);
-//         expr = Expr::BinaryOp(Box::new(expr), Box::new(right), token.clone());
-//     }
-//     expr
-// }
-
-// pub fn parse_factor(tokens: &mut &[Token]) -> Expr {
-//     let mut expr = parse_unary(tokens);
-//     loop {
-//         let token = &tokens[0];
-//         if token.token_type != TokenType::Mul && token.token_type != TokenType::Div {
-//             break;
-//         }
-//         *tokens = &tokens[1..];
-//         let right = parse_unary(tokens);
-//         expr = Expr::BinaryOp(Box::new(expr), Box::new(right), token.clone());
-//     }
-//     expr
-// }
-
-// pub fn parse_unary(tokens: &mut &[Token]) -> Expr {
-//     let token = &tokens[0];
-//     if token.token_type == TokenType::Minus {
-//         *tokens = &tokens[1..];
-//         let right = parse_unary(tokens);
-//         return Expr::BinaryOp(
-//             Box::new(Expr::IntLiteral(0)),
-//             Box::new(right),
-//             token.clone(),
-//         );
-//     }
-
-//     parse_call(tokens)
-// }
-
-// pub fn parse_call(tokens: &mut &[Token]) -> Expr {
-//     let mut expr = parse_primary(tokens);
-//     loop {
-//         let token = &tokens[0];
-//         if token.token_type != TokenType::LParen {
-//             break;
-//         }
-//         *tokens = &tokens[1..];
-//         let mut args = Vec::new();
-//         loop {
-//             let token = &tokens[0];
-//             if token.token_type == TokenType::RParen {
-//                 break;
-//             }
-//             let arg = parse_expr(tokens);
-//             args.push(arg);
-//             let token = &tokens[0];
-//             if token.token_type == TokenType::RParen {
-//                 break;
-//             }
-//             *tokens = &tokens[1..];
-//         }
-//         *tokens = &tokens[1..];
-//         expr = Expr::Call(Box::new(expr), args);
-//     }
-//     expr
-// }
-
-// pub fn parse_primary(tokens: &mut &[Token]) -> Expr {
-//     let token = &tokens[0];
-//     *tokens = &tokens[1..];
-//     match token.token_type {
-//         TokenType::Int => Expr::IntLiteral(token.value.parse().unwrap()),
-//         TokenType::Identifier => Expr::Identifier(token.clone()),
-//         TokenType::Builtin => Expr::Builtin(token.clone()),
-//         TokenType::LParen => {
-//             let expr = parse_expr(tokens);
-//             let token = &tokens[0];
-//             if token.token_type != TokenType::RParen {
-//                 panic!("Expected )");
-//             }
-//             *tokens = &tokens[1..];
-//             expr
-//         }
-//         _ => panic!("Unexpected token {:?}", token),
-//     }
-// }
-
-// pub fn parse_program(tokens: &mut &[Token]) -> Vec<Stmt> {
-//     let mut stmts = Vec::new();
-//     loop {
-//         skip_newline(tokens);
-//         // println!("looping {:?}", tokens[0]);
-//         if tokens[0].token_type == TokenType::EndMarker {
-//             break;
-//         }
-//         let stmt = parse_stmt(tokens);
-//         stmts.push(stmt);
-//     }
-//     stmts
-// }
-
-// pub fn parse(tokens: Vec<Token>) -> Vec<Stmt> {
-//     let tokens = &mut &tokens[..];
-//     parse_program(tokens)
-// }
-
-// #[cfg(test)]
-// mod tests {
-//     use crate::tokenizer::{read_lines, tokenize};
-
-//     use super::*;
-
-//     #[test]
-//     fn test_parse_assign() {
-//         let source = read_lines("tests/var.py");
-//         let tokens = tokenize(&source);
-//         let mut tokens = &mut &tokens[..];
-//         let stmts = parse_program(&mut tokens);
-//         for stmt in stmts {
-//             println!("{}", stmt.pretty_print(0));
-//         }
-//     }
-
-//     #[test]
-//     fn test_parse_def() {
-//         let source = read_lines("tests/fib.py");
-//         let tokens = tokenize(&source);
-//         let mut tokens = &mut &tokens[..];
-//         let stmts = parse_program(&mut tokens);
-//         for stmt in stmts {
-//             println!("{}", stmt.pretty_print(0));
-//         }
-//     }
-
-//     #[test]
-//     fn test_parse_ambiguous() {
-//         let source = read_lines("tests/ambiguous.py");
-//         let tokens = tokenize(&source);
-//         let mut tokens = &mut &tokens[..];
-//         let stmts = parse_program(&mut tokens);
-//         for stmt in stmts {
-//             println!("{}", stmt.pretty_print(0));
-//         }
-//     }
-//     #[test]
-//     fn test_parse_empty_new_line() {
-//         let source = read_lines("tests/def.py");
-//         let tokens = tokenize(&source);
-//         let mut tokens = &mut &tokens[..];
-//         let stmts = parse_program(&mut tokens);
-//         for stmt in stmts {
-//             println!("{}", stmt.pretty_print(0));
-//         }
-//     }
-
-//     #[test]
-//     fn test_parse_while_stmt() {
-//         let source = read_lines("tests/while.py");
-//         let tokens = tokenize(&source);
-//         let mut tokens = &mut &tokens[..];
-//         let stmts = parse_program(&mut tokens);
-//         for stmt in stmts {
-//             // println!("{}", stmt.pretty_print(0));
-//             println!("{:?}", stmt);
-//         }
-//     }
-
-//     #[test]
-//     fn test_parse_print_stmt() {
-//         let source = read_lines("tests/print.py");
-//         let tokens = tokenize(&source);
-//         let mut tokens = &mut &tokens[..];
-//         let stmts = parse_program(&mut tokens);
-//         for stmt in stmts {
-//             println!("{:?}", stmt);
-//             println!("{}", stmt.pretty_print(0));
-//         }
-//     }
-
-// }
+use crate::ast::{
+    Alias, Arena, Arg, Arguments, BoolOperator, CmpOp, Comprehension, Constant, ExceptHandler,
+    Expr, ExprContext, ExprId, ExprKind, Keyword, List, Module, Operator, Span, StmtId, StmtKind,
+    TypeParam, TypeParamKind, UnaryOperator, WithItem,
+};
+use crate::checks::check;
+use crate::intern::Symbol;
+use crate::tokenizer::{tokenize, Token, TokenType};
+
+/// A syntax error, worded as CPython words it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl ParseError {
+    pub fn new(message: impl Into<String>, span: Span) -> ParseError {
+        ParseError {
+            message: message.into(),
+            span,
+        }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (line {})", self.message, self.span.line)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub type ParseResult<T> = Result<T, ParseError>;
+
+/// Parses a module, or returns every syntax error in it: the parser
+/// recovers at statement boundaries, so one run reports them all. Errors
+/// are in source order, at most one per line, and a parser error caused by
+/// a tokenizer error (an unclosed bracket, say) is left out. A module
+/// that parses cleanly then goes through the compile-time `checks`.
+pub fn parse(source: &str) -> Result<Module, Vec<ParseError>> {
+    let (tokens, mut errors) = tokenize(source);
+    let mut parser = Parser::new(&tokens);
+    let module = parser.parse_program();
+    for error in parser.errors {
+        let caused = errors.iter().any(|cause| {
+            cause.span.start <= error.span.start && error.span.start <= cause.span.end
+        });
+        if !caused {
+            errors.push(error);
+        }
+    }
+    if errors.is_empty() {
+        errors = check(&module);
+    }
+    if errors.is_empty() {
+        return Ok(module);
+    }
+    errors.sort_by_key(|error| (error.span.line, error.span.start));
+    errors.dedup_by_key(|error| error.span.line);
+    Err(errors)
+}
+
+fn can_start_expression(token_type: TokenType) -> bool {
+    matches!(
+        token_type,
+        TokenType::Name
+            | TokenType::Number
+            | TokenType::String
+            | TokenType::Lpar
+            | TokenType::Lsqb
+            | TokenType::Lbrace
+            | TokenType::Minus
+            | TokenType::Plus
+            | TokenType::Tilde
+            | TokenType::Not
+            | TokenType::Lambda
+            | TokenType::None
+            | TokenType::True
+            | TokenType::False
+            | TokenType::Ellipsis
+            | TokenType::Star
+            | TokenType::Await
+    )
+}
+
+fn augmented_operator(token_type: TokenType) -> Option<Operator> {
+    let op = match token_type {
+        TokenType::PlusEqual => Operator::Add,
+        TokenType::MinusEqual => Operator::Sub,
+        TokenType::StarEqual => Operator::Mult,
+        TokenType::AtEqual => Operator::MatMult,
+        TokenType::SlashEqual => Operator::Div,
+        TokenType::PercentEqual => Operator::Mod,
+        TokenType::DoubleStarEqual => Operator::Pow,
+        TokenType::LeftShiftEqual => Operator::LShift,
+        TokenType::RightShiftEqual => Operator::RShift,
+        TokenType::VbarEqual => Operator::BitOr,
+        TokenType::CircumflexEqual => Operator::BitXor,
+        TokenType::AmperEqual => Operator::BitAnd,
+        TokenType::DoubleSlashEqual => Operator::FloorDiv,
+        _ => return None,
+    };
+    Some(op)
+}
+
+/// How CPython names an expression in "cannot assign to ..." messages.
+fn describe_expr(expr: &Expr) -> &'static str {
+    match &expr.kind {
+        ExprKind::Attribute { .. } => "attribute",
+        ExprKind::Subscript { .. } => "subscript",
+        ExprKind::Starred { .. } => "starred",
+        ExprKind::Name { .. } => "name",
+        ExprKind::List { .. } => "list",
+        ExprKind::Tuple { .. } => "tuple",
+        ExprKind::Lambda { .. } => "lambda",
+        ExprKind::Call { .. } => "function call",
+        ExprKind::BoolOp { .. } | ExprKind::BinOp { .. } | ExprKind::UnaryOp { .. } => "expression",
+        ExprKind::GeneratorExp { .. } => "generator expression",
+        ExprKind::ListComp { .. } => "list comprehension",
+        ExprKind::SetComp { .. } => "set comprehension",
+        ExprKind::DictComp { .. } => "dict comprehension",
+        ExprKind::Dict { .. } => "dict literal",
+        ExprKind::Set { .. } => "set display",
+        ExprKind::Compare { .. } => "comparison",
+        ExprKind::Await { .. } => "await expression",
+        ExprKind::Yield { .. } | ExprKind::YieldFrom { .. } => "yield expression",
+        ExprKind::IfExp { .. } => "conditional expression",
+        ExprKind::NamedExpr { .. } => "named expression",
+        ExprKind::Slice { .. } => "slice",
+        ExprKind::Constant { value } => match value {
+            Constant::None => "None",
+            Constant::Bool(true) => "True",
+            Constant::Bool(false) => "False",
+            Constant::Ellipsis => "ellipsis",
+            _ => "literal",
+        },
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Comprehensions {
+    List,
+    Set,
+    Generator,
+}
+
+/// A recursive-descent parser over the logical token stream produced by
+/// `tokenize`.
+///
+/// A hard error abandons the statement it occurs in: it is recorded, the
+/// parser skips to the next statement boundary and carries on. Errors that
+/// do not leave the parser lost, such as an invalid assignment target, are
+/// recorded without unwinding.
+pub struct Parser<'a, 'source> {
+    tokens: &'a [Token<'source>],
+    current: usize,
+    arena: Arena,
+    pub errors: Vec<ParseError>,
+}
+
+impl<'a, 'source> Parser<'a, 'source> {
+    pub fn new(tokens: &'a [Token<'source>]) -> Parser<'a, 'source> {
+        Parser {
+            tokens,
+            current: 0,
+            arena: Arena::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn parse_program(&mut self) -> Module {
+        let mut body = Vec::new();
+        while self.peek() != TokenType::EndMarker {
+            if self.eat(TokenType::Dedent) {
+                continue;
+            }
+            self.parse_stmt_recovering(&mut body);
+        }
+        Module {
+            body: self.arena.add_list(body),
+            arena: std::mem::take(&mut self.arena),
+        }
+    }
+
+    fn token(&self) -> &'a Token<'source> {
+        &self.tokens[self.current]
+    }
+
+    fn peek(&self) -> TokenType {
+        self.token().token_type
+    }
+
+    fn peek_at(&self, n: usize) -> TokenType {
+        self.tokens
+            .get(self.current + n)
+            .map_or(TokenType::EndMarker, |token| token.token_type)
+    }
+
+    fn advance(&mut self) -> &'a Token<'source> {
+        let token = self.token();
+        if token.token_type != TokenType::EndMarker {
+            self.current += 1;
+        }
+        token
+    }
+
+    fn eat(&mut self, token_type: TokenType) -> bool {
+        if self.peek() == token_type {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn token_span(token: &Token) -> Span {
+        Span::new(token.line(), token.start(), token.end())
+    }
+
+    /// An error at the current token.
+    fn error<T>(&self, message: impl Into<String>) -> ParseResult<T> {
+        Err(ParseError::new(message, Self::token_span(self.token())))
+    }
+
+    fn expect(&mut self, token_type: TokenType, message: &str) -> ParseResult<&'a Token<'source>> {
+        if self.peek() != token_type {
+            return self.error(message);
+        }
+        Ok(self.advance())
+    }
+
+    fn expect_name(&mut self) -> ParseResult<Symbol> {
+        Ok(Symbol::intern(
+            self.expect(TokenType::Name, "invalid syntax")?.value(),
+        ))
+    }
+
+    /// The span covering every token consumed since `start`, ignoring the
+    /// layout tokens a block leaves behind.
+    fn span_from(&self, start: usize) -> Span {
+        let first = &self.tokens[start];
+        let last = self.tokens[start..self.current.max(start + 1)]
+            .iter()
+            .rev()
+            .find(|token| {
+                !matches!(
+                    token.token_type,
+                    TokenType::Newline
+                        | TokenType::Indent
+                        | TokenType::Dedent
+                        | TokenType::EndMarker
+                )
+            })
+            .unwrap_or(first);
+        Span::new(first.line(), first.start(), last.end().max(first.start()))
+    }
+
+    fn stmt(&mut self, kind: StmtKind, start: usize) -> StmtId {
+        let span = self.span_from(start);
+        self.arena.add_stmt(kind, span)
+    }
+
+    fn expr(&mut self, kind: ExprKind, start: usize) -> ExprId {
+        let span = self.span_from(start);
+        self.arena.add_expr(kind, span)
+    }
+
+    /// Parses a statement into `body`, or records why it cannot and skips
+    /// past it.
+    fn parse_stmt_recovering(&mut self, body: &mut Vec<StmtId>) {
+        match self.parse_stmt() {
+            Ok(stmts) => body.extend(stmts),
+            Err(error) => {
+                self.errors.push(error);
+                self.synchronize();
+            }
+        }
+    }
+
+    /// Skips the rest of the logical line an error occurred on, and the
+    /// indented block after it when the error was in a compound statement's
+    /// header.
+    fn synchronize(&mut self) {
+        while self.peek() != TokenType::Indent {
+            match self.peek() {
+                TokenType::EndMarker | TokenType::Dedent => return,
+                TokenType::Newline => {
+                    self.advance();
+                    if self.peek() != TokenType::Indent {
+                        return;
+                    }
+                }
+                _ => {
+                    self.advance();
+                }
+            }
+        }
+        let mut depth = 0;
+        loop {
+            match self.advance().token_type {
+                TokenType::Indent => depth += 1,
+                TokenType::Dedent => depth -= 1,
+                TokenType::EndMarker => return,
+                _ => {}
+            }
+            if depth == 0 {
+                return;
+            }
+        }
+    }
+
+    pub fn parse_stmt(&mut self) -> ParseResult<Vec<StmtId>> {
+        let stmt = match self.peek() {
+            TokenType::Def | TokenType::Class | TokenType::At => self.parse_decorated()?,
+            TokenType::If => self.parse_if()?,
+            TokenType::While => self.parse_while()?,
+            TokenType::For => self.parse_for()?,
+            TokenType::Try => self.parse_try()?,
+            TokenType::With => self.parse_with()?,
+            TokenType::Async => match self.peek_at(1) {
+                TokenType::Def => self.parse_def(List::new())?,
+                TokenType::For => self.parse_for()?,
+                TokenType::With => self.parse_with()?,
+                _ => {
+                    self.advance();
+                    return self.error("invalid syntax");
+                }
+            },
+            TokenType::Indent => return self.error("unexpected indent"),
+            _ => return self.parse_simple_stmts(),
+        };
+        Ok(vec![stmt])
+    }
+
+    /// `simple_stmt (';' simple_stmt)* [';'] NEWLINE`
+    pub fn parse_simple_stmts(&mut self) -> ParseResult<Vec<StmtId>> {
+        let mut stmts = vec![self.parse_simple_stmt()?];
+        while self.eat(TokenType::Semi) {
+            if self.peek() == TokenType::Newline {
+                break;
+            }
+            stmts.push(self.parse_simple_stmt()?);
+        }
+        self.expect(TokenType::Newline, "invalid syntax")?;
+        Ok(stmts)
+    }
+
+    fn parse_simple_stmt(&mut self) -> ParseResult<StmtId> {
+        let start = self.current;
+        let kind = match self.peek() {
+            TokenType::Return => self.parse_return()?,
+            TokenType::Pass => {
+                self.advance();
+                StmtKind::Pass
+            }
+            TokenType::Break => {
+                self.advance();
+                StmtKind::Break
+            }
+            TokenType::Continue => {
+                self.advance();
+                StmtKind::Continue
+            }
+            TokenType::Del => self.parse_del()?,
+            TokenType::Assert => self.parse_assert()?,
+            TokenType::Global => {
+                self.advance();
+                StmtKind::Global {
+                    names: self.parse_name_list()?,
+                }
+            }
+            TokenType::Nonlocal => {
+                self.advance();
+                StmtKind::Nonlocal {
+                    names: self.parse_name_list()?,
+                }
+            }
+            TokenType::Import => self.parse_import()?,
+            TokenType::From => self.parse_import_from()?,
+            TokenType::Raise => self.parse_raise()?,
+            TokenType::Name
+                if self.token().value() == "type" && self.peek_at(1) == TokenType::Name =>
+            {
+                self.parse_type_alias()?
+            }
+            _ => self.parse_expr_stmt()?,
+        };
+        Ok(self.stmt(kind, start))
+    }
+
+    /// The body of a compound statement: an indented block, or simple
+    /// statements on the same line as the header. `header` and
+    /// `header_line` describe the header for the error reported when the
+    /// block is missing.
+    pub fn parse_block(&mut self, header: &str, header_line: usize) -> ParseResult<List<StmtId>> {
+        if !self.eat(TokenType::Newline) {
+            let stmts = self.parse_simple_stmts()?;
+            return Ok(self.arena.add_list(stmts));
+        }
+        if !self.eat(TokenType::Indent) {
+            self.errors.push(ParseError::new(
+                format!(
+                    "expected an indented block after {} on line {}",
+                    header, header_line
+                ),
+                Self::token_span(self.token()),
+            ));
+            return Ok(List::new());
+        }
+        let mut stmts = Vec::new();
+        while !self.eat(TokenType::Dedent) && self.peek() != TokenType::EndMarker {
+            self.parse_stmt_recovering(&mut stmts);
+        }
+        Ok(self.arena.add_list(stmts))
+    }
+
+    /// `':' block` after a compound statement header.
+    fn parse_suite(&mut self, header: &str, header_line: usize) -> ParseResult<List<StmtId>> {
+        self.expect(TokenType::Colon, "expected ':'")?;
+        self.parse_block(header, header_line)
+    }
+
+    fn parse_decorated(&mut self) -> ParseResult<StmtId> {
+        let mut decorator_list = Vec::new();
+        while self.eat(TokenType::At) {
+            decorator_list.push(self.parse_named_expression()?);
+            self.expect(TokenType::Newline, "invalid syntax")?;
+        }
+        let decorator_list = self.arena.add_list(decorator_list);
+        match self.peek() {
+            TokenType::Def => self.parse_def(decorator_list),
+            TokenType::Async if self.peek_at(1) == TokenType::Def => self.parse_def(decorator_list),
+            TokenType::Class => self.parse_class(decorator_list),
+            _ => self.error("invalid syntax"),
+        }
+    }
+
+    /// A function definition, starting at `def` or at the `async` before it.
+    pub fn parse_def(&mut self, decorator_list: List<ExprId>) -> ParseResult<StmtId> {
+        let start = self.current;
+        let is_async = self.eat(TokenType::Async);
+        let line = self.advance().line();
+        let name = self.expect_name()?;
+        let type_params = self.parse_type_params()?;
+        self.expect(TokenType::Lpar, "expected '('")?;
+        let args = self.parse_parameters(true, TokenType::Rpar)?;
+        self.expect(TokenType::Rpar, "invalid syntax")?;
+        let returns = if self.eat(TokenType::Rarrow) {
+            Some(self.parse_expression()?)
+        } else {
+            None
+        };
+        let body = self.parse_suite("function definition", line)?;
+        let args = Box::new(args);
+        let kind = if is_async {
+            StmtKind::AsyncFunctionDef {
+                name,
+                args,
+                body,
+                decorator_list,
+                returns,
+                type_params,
+            }
+        } else {
+            StmtKind::FunctionDef {
+                name,
+                args,
+                body,
+                decorator_list,
+                returns,
+                type_params,
+            }
+        };
+        Ok(self.stmt(kind, start))
+    }
+
+    pub fn parse_class(&mut self, decorator_list: List<ExprId>) -> ParseResult<StmtId> {
+        let start = self.current;
+        let line = self.advance().line();
+        let name = self.expect_name()?;
+        let type_params = self.parse_type_params()?;
+        let (bases, keywords) = if self.eat(TokenType::Lpar) {
+            self.parse_call_arguments()?
+        } else {
+            (List::new(), List::new())
+        };
+        let body = self.parse_suite("class definition", line)?;
+        Ok(self.stmt(
+            StmtKind::ClassDef {
+                name,
+                bases,
+                keywords,
+                body,
+                decorator_list,
+                type_params,
+            },
+            start,
+        ))
+    }
+
+    /// `[T, T: bound, *Ts, **P]` after the name of a generic function,
+    /// class or type alias; empty when there is no `[`.
+    fn parse_type_params(&mut self) -> ParseResult<List<TypeParam>> {
+        let mut type_params = Vec::new();
+        if !self.eat(TokenType::Lsqb) {
+            return Ok(List::new());
+        }
+        if self.peek() == TokenType::Rsqb {
+            return self.error("Type parameter list cannot be empty");
+        }
+        loop {
+            let start = self.current;
+            let kind = match self.peek() {
+                TokenType::Star | TokenType::DoubleStar => {
+                    let (kind, name) = if self.advance().token_type == TokenType::Star {
+                        let name = self.expect_name()?;
+                        (TypeParamKind::TypeVarTuple { name }, "TypeVarTuple")
+                    } else {
+                        let name = self.expect_name()?;
+                        (TypeParamKind::ParamSpec { name }, "ParamSpec")
+                    };
+                    if self.peek() == TokenType::Colon {
+                        let colon = Self::token_span(self.advance());
+                        let bound = self.parse_expression()?;
+                        let what = match self.arena[bound].kind {
+                            ExprKind::Tuple { .. } => "constraints",
+                            _ => "bound",
+                        };
+                        return Err(ParseError::new(
+                            format!("cannot use {} with {}", what, name),
+                            colon,
+                        ));
+                    }
+                    kind
+                }
+                _ => {
+                    let name = self.expect_name()?;
+                    let bound = if self.eat(TokenType::Colon) {
+                        Some(self.parse_expression()?)
+                    } else {
+                        None
+                    };
+                    TypeParamKind::TypeVar { name, bound }
+                }
+            };
+            type_params.push(TypeParam {
+                kind,
+                span: self.span_from(start),
+            });
+            if !self.eat(TokenType::Comma) || self.peek() == TokenType::Rsqb {
+                break;
+            }
+        }
+        self.expect(TokenType::Rsqb, "invalid syntax")?;
+        Ok(self.arena.add_list(type_params))
+    }
+
+    /// `type Name[T] = value`, where `type` is a soft keyword: it is a
+    /// keyword only when a name follows it.
+    fn parse_type_alias(&mut self) -> ParseResult<StmtKind> {
+        self.advance();
+        let start = self.current;
+        let id = self.expect_name()?;
+        let name = self.expr(
+            ExprKind::Name {
+                id,
+                ctx: ExprContext::Store,
+            },
+            start,
+        );
+        let type_params = self.parse_type_params()?;
+        self.expect(TokenType::Equal, "invalid syntax")?;
+        let value = self.parse_expression()?;
+        Ok(StmtKind::TypeAlias {
+            name,
+            type_params,
+            value,
+        })
+    }
+
+    /// Parameters of a `def` (with annotations) or a `lambda` (without),
+    /// up to but not including `end`.
+    pub fn parse_parameters(
+        &mut self,
+        annotations: bool,
+        end: TokenType,
+    ) -> ParseResult<Arguments> {
+        let mut arguments = Arguments::default();
+        let (mut posonlyargs, mut args, mut defaults) = (Vec::new(), Vec::new(), Vec::new());
+        let (mut kwonlyargs, mut kw_defaults) = (Vec::new(), Vec::new());
+        let mut seen_slash = false;
+        let mut seen_star = false;
+        while self.peek() != end {
+            if arguments.kwarg.is_some() {
+                return self.error("arguments cannot follow var-keyword argument");
+            }
+            match self.peek() {
+                TokenType::Slash => {
+                    if seen_slash {
+                        return self.error("/ may appear only once");
+                    }
+                    if seen_star {
+                        return self.error("/ must be ahead of *");
+                    }
+                    if args.is_empty() {
+                        return self.error("invalid syntax");
+                    }
+                    self.advance();
+                    seen_slash = true;
+                    posonlyargs = std::mem::take(&mut args);
+                }
+                TokenType::Star => {
+                    if seen_star {
+                        return self.error("* argument may appear only once");
+                    }
+                    let star = self.advance();
+                    seen_star = true;
+                    if self.peek() == TokenType::Name {
+                        arguments.vararg = Some(self.parse_parameter(annotations)?);
+                    } else if self.peek() == end
+                        || self.peek() == TokenType::Comma
+                            && matches!(self.peek_at(1), TokenType::DoubleStar)
+                        || self.peek() == TokenType::Comma && self.peek_at(1) == end
+                    {
+                        return Err(ParseError::new(
+                            "named arguments must follow bare *",
+                            Self::token_span(star),
+                        ));
+                    }
+                }
+                TokenType::DoubleStar => {
+                    self.advance();
+                    arguments.kwarg = Some(self.parse_parameter(annotations)?);
+                }
+                TokenType::Name => {
+                    let arg = self.parse_parameter(annotations)?;
+                    let default = if self.eat(TokenType::Equal) {
+                        Some(self.parse_expression()?)
+                    } else {
+                        None
+                    };
+                    if seen_star {
+                        kwonlyargs.push(arg);
+                        kw_defaults.push(default);
+                    } else {
+                        match default {
+                            Some(default) => defaults.push(default),
+                            None if !defaults.is_empty() => {
+                                self.errors.push(ParseError::new(
+                                    "non-default argument follows default argument",
+                                    arg.span,
+                                ));
+                            }
+                            None => {}
+                        }
+                        args.push(arg);
+                    }
+                }
+                _ => return self.error("invalid syntax"),
+            }
+            if !self.eat(TokenType::Comma) {
+                break;
+            }
+        }
+        arguments.posonlyargs = self.arena.add_list(posonlyargs);
+        arguments.args = self.arena.add_list(args);
+        arguments.defaults = self.arena.add_list(defaults);
+        arguments.kwonlyargs = self.arena.add_list(kwonlyargs);
+        arguments.kw_defaults = self.arena.add_list(kw_defaults);
+        Ok(arguments)
+    }
+
+    fn parse_parameter(&mut self, annotations: bool) -> ParseResult<Arg> {
+        let start = self.current;
+        let arg = self.expect_name()?;
+        let annotation = if annotations && self.eat(TokenType::Colon) {
+            Some(self.parse_expression()?)
+        } else {
+            None
+        };
+        Ok(Arg {
+            arg,
+            annotation,
+            span: self.span_from(start),
+        })
+    }
+
+    /// Parses `if` and `elif` alike; an `elif` chain nests in `orelse`.
+    pub fn parse_if(&mut self) -> ParseResult<StmtId> {
+        let start = self.current;
+        let keyword = self.advance();
+        let header = format!("'{}' statement", keyword.value());
+        let test = self.parse_condition()?;
+        let body = self.parse_suite(&header, keyword.line())?;
+        let orelse = match self.peek() {
+            TokenType::Elif => {
+                let elif = self.parse_if()?;
+                self.arena.add_list([elif])
+            }
+            _ => self.parse_else()?,
+        };
+        Ok(self.stmt(StmtKind::If { test, body, orelse }, start))
+    }
+
+    /// The test of an `if`, `elif` or `while`.
+    fn parse_condition(&mut self) -> ParseResult<ExprId> {
+        let test = self.parse_named_expression()?;
+        if self.peek() == TokenType::Equal {
+            return self.error("invalid syntax. Maybe you meant '==' or ':=' instead of '='?");
+        }
+        Ok(test)
+    }
+
+    fn parse_else(&mut self) -> ParseResult<List<StmtId>> {
+        if self.peek() != TokenType::Else {
+            return Ok(List::new());
+        }
+        let line = self.advance().line();
+        self.parse_suite("'else' statement", line)
+    }
+
+    pub fn parse_while(&mut self) -> ParseResult<StmtId> {
+        let start = self.current;
+        let line = self.advance().line();
+        let test = self.parse_condition()?;
+        let body = self.parse_suite("'while' statement", line)?;
+        let orelse = self.parse_else()?;
+        Ok(self.stmt(StmtKind::While { test, body, orelse }, start))
+    }
+
+    pub fn parse_for(&mut self) -> ParseResult<StmtId> {
+        let start = self.current;
+        let is_async = self.eat(TokenType::Async);
+        let line = self.advance().line();
+        let target = self.parse_target_list(ExprContext::Store)?;
+        self.expect(TokenType::In, "invalid syntax")?;
+        let iter = self.parse_star_expressions()?;
+        let body = self.parse_suite("'for' statement", line)?;
+        let orelse = self.parse_else()?;
+        let kind = if is_async {
+            StmtKind::AsyncFor {
+                target,
+                iter,
+                body,
+                orelse,
+            }
+        } else {
+            StmtKind::For {
+                target,
+                iter,
+                body,
+                orelse,
+            }
+        };
+        Ok(self.stmt(kind, start))
+    }
+
+    pub fn parse_with(&mut self) -> ParseResult<StmtId> {
+        let start = self.current;
+        let is_async = self.eat(TokenType::Async);
+        let line = self.advance().line();
+        let mut items = Vec::new();
+        loop {
+            let context_expr = self.parse_expression()?;
+            let optional_vars = if self.eat(TokenType::As) {
+                let target = self.parse_star_target()?;
+                Some(self.make_target(target, ExprContext::Store))
+            } else {
+                None
+            };
+            items.push(WithItem {
+                context_expr,
+                optional_vars,
+            });
+            if !self.eat(TokenType::Comma) {
+                break;
+            }
+        }
+        let items = self.arena.add_list(items);
+        let body = self.parse_suite("'with' statement", line)?;
+        let kind = if is_async {
+            StmtKind::AsyncWith { items, body }
+        } else {
+            StmtKind::With { items, body }
+        };
+        Ok(self.stmt(kind, start))
+    }
+
+    pub fn parse_try(&mut self) -> ParseResult<StmtId> {
+        let start = self.current;
+        let line = self.advance().line();
+        let body = self.parse_suite("'try' statement", line)?;
+        let mut handlers = Vec::new();
+        while self.peek() == TokenType::Except {
+            let handler_start = self.current;
+            let line = self.advance().line();
+            let mut type_ = None;
+            let mut name = None;
+            if self.peek() != TokenType::Colon {
+                type_ = Some(self.parse_expression()?);
+                if self.eat(TokenType::As) {
+                    name = Some(self.expect_name()?);
+                } else if self.peek() == TokenType::Comma {
+                    return self.error("multiple exception types must be parenthesized");
+                }
+            }
+            let body = self.parse_suite("'except' statement", line)?;
+            handlers.push(ExceptHandler {
+                type_,
+                name,
+                body,
+                span: self.span_from(handler_start),
+            });
+        }
+        let orelse = if handlers.is_empty() {
+            List::new()
+        } else {
+            self.parse_else()?
+        };
+        let handlers = self.arena.add_list(handlers);
+        let mut finalbody = List::new();
+        if self.peek() == TokenType::Finally {
+            let line = self.advance().line();
+            finalbody = self.parse_suite("'finally' statement", line)?;
+        } else if handlers.is_empty() {
+            self.errors.push(ParseError::new(
+                "expected 'except' or 'finally' block",
+                Self::token_span(self.token()),
+            ));
+        }
+        Ok(self.stmt(
+            StmtKind::Try {
+                body,
+                handlers,
+                orelse,
+                finalbody,
+            },
+            start,
+        ))
+    }
+
+    fn parse_return(&mut self) -> ParseResult<StmtKind> {
+        self.advance();
+        let value = if can_start_expression(self.peek()) {
+            Some(self.parse_star_expressions()?)
+        } else {
+            None
+        };
+        Ok(StmtKind::Return { value })
+    }
+
+    fn parse_del(&mut self) -> ParseResult<StmtKind> {
+        self.advance();
+        let mut targets = Vec::new();
+        loop {
+            let target = self.parse_star_target()?;
+            targets.push(self.make_target(target, ExprContext::Del));
+            if !self.eat(TokenType::Comma) || !can_start_expression(self.peek()) {
+                break;
+            }
+        }
+        Ok(StmtKind::Delete {
+            targets: self.arena.add_list(targets),
+        })
+    }
+
+    fn parse_assert(&mut self) -> ParseResult<StmtKind> {
+        self.advance();
+        let test = self.parse_expression()?;
+        let msg = if self.eat(TokenType::Comma) {
+            Some(self.parse_expression()?)
+        } else {
+            None
+        };
+        Ok(StmtKind::Assert { test, msg })
+    }
+
+    fn parse_raise(&mut self) -> ParseResult<StmtKind> {
+        self.advance();
+        let mut exc = None;
+        let mut cause = None;
+        if can_start_expression(self.peek()) {
+            exc = Some(self.parse_expression()?);
+            if self.eat(TokenType::From) {
+                cause = Some(self.parse_expression()?);
+            }
+        }
+        Ok(StmtKind::Raise { exc, cause })
+    }
+
+    fn parse_name_list(&mut self) -> ParseResult<List<Symbol>> {
+        let mut names = vec![self.expect_name()?];
+        while self.eat(TokenType::Comma) {
+            names.push(self.expect_name()?);
+        }
+        Ok(self.arena.add_list(names))
+    }
+
+    fn parse_dotted_name(&mut self) -> ParseResult<Symbol> {
+        let first = self.expect_name()?;
+        if self.peek() != TokenType::Dot {
+            return Ok(first);
+        }
+        let mut name = first.as_str().to_string();
+        while self.eat(TokenType::Dot) {
+            name.push('.');
+            name.push_str(self.expect_name()?.as_str());
+        }
+        Ok(Symbol::intern(&name))
+    }
+
+    fn parse_alias(&mut self, dotted: bool) -> ParseResult<Alias> {
+        let name = if dotted {
+            self.parse_dotted_name()?
+        } else {
+            self.expect_name()?
+        };
+        let asname = if self.eat(TokenType::As) {
+            Some(self.expect_name()?)
+        } else {
+            None
+        };
+        Ok(Alias { name, asname })
+    }
+
+    fn parse_import(&mut self) -> ParseResult<StmtKind> {
+        self.advance();
+        let mut names = vec![self.parse_alias(true)?];
+        while self.eat(TokenType::Comma) {
+            names.push(self.parse_alias(true)?);
+        }
+        Ok(StmtKind::Import {
+            names: self.arena.add_list(names),
+        })
+    }
+
+    fn parse_import_from(&mut self) -> ParseResult<StmtKind> {
+        self.advance();
+        let mut level = 0;
+        loop {
+            match self.peek() {
+                TokenType::Dot => level += 1,
+                TokenType::Ellipsis => level += 3,
+                _ => break,
+            }
+            self.advance();
+        }
+        let module = if self.peek() == TokenType::Name || level == 0 {
+            Some(self.parse_dotted_name()?)
+        } else {
+            None
+        };
+        self.expect(TokenType::Import, "invalid syntax")?;
+        if self.eat(TokenType::Star) {
+            let names = self.arena.add_list([Alias {
+                name: Symbol::intern("*"),
+                asname: None,
+            }]);
+            return Ok(StmtKind::ImportFrom {
+                module,
+                names,
+                level,
+            });
+        }
+        let parenthesized = self.eat(TokenType::Lpar);
+        let mut names = vec![self.parse_alias(false)?];
+        while self.eat(TokenType::Comma) {
+            if parenthesized && self.peek() == TokenType::Rpar {
+                break;
+            }
+            if !parenthesized && self.peek() == TokenType::Newline {
+                return self.error("trailing comma not allowed without surrounding parentheses");
+            }
+            names.push(self.parse_alias(false)?);
+        }
+        if parenthesized {
+            self.expect(TokenType::Rpar, "invalid syntax")?;
+        }
+        Ok(StmtKind::ImportFrom {
+            module,
+            names: self.arena.add_list(names),
+            level,
+        })
+    }
+
+    /// Expression statements and the three assignment forms, which all
+    /// start with an expression that only becomes a target once `=`, an
+    /// augmented operator or `:` follows.
+    pub fn parse_expr_stmt(&mut self) -> ParseResult<StmtKind> {
+        let parenthesized = self.peek() == TokenType::Lpar;
+        let mut bare_yield = self.peek() == TokenType::Yield;
+        let first = self.parse_assigned_value()?;
+        if self.peek() == TokenType::Equal {
+            let mut targets = vec![first];
+            let mut value = first;
+            loop {
+                if bare_yield {
+                    return Err(ParseError::new(
+                        "assignment to yield expression not possible",
+                        self.arena[value].span,
+                    ));
+                }
+                self.advance();
+                bare_yield = self.peek() == TokenType::Yield;
+                value = self.parse_assigned_value()?;
+                if self.peek() != TokenType::Equal {
+                    break;
+                }
+                targets.push(value);
+            }
+            let single = targets.len() == 1;
+            for &target in &targets {
+                self.make_assign_target(target, single);
+            }
+            self.check_not_starred(value);
+            let targets = self.arena.add_list(targets);
+            return Ok(StmtKind::Assign { targets, value });
+        }
+        let node = &self.arena[first];
+        if let Some(op) = augmented_operator(self.peek()) {
+            if !matches!(
+                node.kind,
+                ExprKind::Name { .. } | ExprKind::Attribute { .. } | ExprKind::Subscript { .. }
+            ) {
+                self.errors.push(ParseError::new(
+                    format!(
+                        "'{}' is an illegal expression for augmented assignment",
+                        describe_expr(node)
+                    ),
+                    node.span,
+                ));
+            }
+            self.advance();
+            let value = self.parse_assigned_value()?;
+            return Ok(StmtKind::AugAssign {
+                target: self.make_target(first, ExprContext::Store),
+                op,
+                value,
+            });
+        }
+        if self.eat(TokenType::Colon) {
+            // `(x): int` annotates a name without making it a local.
+            let node = &self.arena[first];
+            let simple = !parenthesized && matches!(node.kind, ExprKind::Name { .. });
+            if let ExprKind::Tuple { .. } | ExprKind::List { .. } = node.kind {
+                self.errors.push(ParseError::new(
+                    format!(
+                        "only single target (not {}) can be annotated",
+                        describe_expr(node)
+                    ),
+                    node.span,
+                ));
+            }
+            let annotation = self.parse_expression()?;
+            let value = if self.eat(TokenType::Equal) {
+                Some(self.parse_assigned_value()?)
+            } else {
+                None
+            };
+            return Ok(StmtKind::AnnAssign {
+                target: self.make_target(first, ExprContext::Store),
+                annotation,
+                value,
+                simple,
+            });
+        }
+        if can_start_expression(self.peek()) {
+            if let ExprKind::Name { id, .. } = self.arena[first].kind {
+                if matches!(id.as_str(), "print" | "exec") {
+                    return self.error(format!(
+                        "Missing parentheses in call to '{}'. Did you mean {}(...)?",
+                        id, id
+                    ));
+                }
+            }
+            return self.error("invalid syntax");
+        }
+        self.check_not_starred(first);
+        Ok(StmtKind::Expr { value: first })
+    }
+
+    /// The right-hand side of an assignment or an expression statement,
+    /// where a yield expression needs no parentheses.
+    fn parse_assigned_value(&mut self) -> ParseResult<ExprId> {
+        if self.peek() == TokenType::Yield {
+            self.parse_yield()
+        } else {
+            self.parse_star_expressions()
+        }
+    }
+
+    fn check_not_starred(&mut self, value: ExprId) {
+        let node = &self.arena[value];
+        if let ExprKind::Starred { .. } = node.kind {
+            self.errors.push(ParseError::new(
+                "can't use starred expression here",
+                node.span,
+            ));
+        }
+    }
+
+    /// Makes `target` a target of `=`. A lone target that looks like it was
+    /// meant as a comparison gets CPython's hint about `==`.
+    fn make_assign_target(&mut self, target: ExprId, single: bool) {
+        let node = &self.arena[target];
+        if let ExprKind::Starred { .. } = node.kind {
+            self.errors.push(ParseError::new(
+                "starred assignment target must be in a list or tuple",
+                node.span,
+            ));
+            return;
+        }
+        let hint = single
+            && !matches!(
+                node.kind,
+                ExprKind::BoolOp { .. }
+                    | ExprKind::Compare { .. }
+                    | ExprKind::Lambda { .. }
+                    | ExprKind::IfExp { .. }
+                    | ExprKind::GeneratorExp { .. }
+                    | ExprKind::UnaryOp {
+                        op: UnaryOperator::Not,
+                        ..
+                    }
+                    | ExprKind::Constant {
+                        value: Constant::None | Constant::Bool(_),
+                    }
+            );
+        let span = node.span;
+        let errors = self.errors.len();
+        self.make_target(target, ExprContext::Store);
+        if hint && self.errors.len() == errors + 1 && self.errors[errors].span == span {
+            self.errors[errors]
+                .message
+                .push_str(" here. Maybe you meant '==' instead of '='?");
+        }
+    }
+
+    /// Turns an expression parsed in load context into an assignment or
+    /// deletion target, recording an error if it cannot be one.
+    pub fn make_target(&mut self, target: ExprId, ctx: ExprContext) -> ExprId {
+        let elts = match &mut self.arena[target].kind {
+            ExprKind::Name { ctx: old, .. }
+            | ExprKind::Attribute { ctx: old, .. }
+            | ExprKind::Subscript { ctx: old, .. } => {
+                *old = ctx;
+                return target;
+            }
+            ExprKind::Tuple { elts, ctx: old } | ExprKind::List { elts, ctx: old } => {
+                *old = ctx;
+                *elts
+            }
+            ExprKind::Starred { value, ctx: old } if ctx == ExprContext::Store => {
+                *old = ctx;
+                let value = *value;
+                self.make_target(value, ctx);
+                return target;
+            }
+            _ => {
+                let action = if ctx == ExprContext::Del {
+                    "delete"
+                } else {
+                    "assign to"
+                };
+                let node = &self.arena[target];
+                self.errors.push(ParseError::new(
+                    format!("cannot {} {}", action, describe_expr(node)),
+                    node.span,
+                ));
+                return target;
+            }
+        };
+        for i in 0..elts.len() {
+            let elt = self.arena[elts][i];
+            self.make_target(elt, ctx);
+        }
+        target
+    }
+
+    /// A single target: `*target` or anything up to `|`, so that the `in`
+    /// of a `for` is not mistaken for a comparison.
+    fn parse_star_target(&mut self) -> ParseResult<ExprId> {
+        if self.peek() == TokenType::Star {
+            return self.parse_starred();
+        }
+        self.parse_bitwise_or()
+    }
+
+    pub fn parse_target_list(&mut self, ctx: ExprContext) -> ParseResult<ExprId> {
+        let start = self.current;
+        let first = self.parse_star_target()?;
+        if self.peek() != TokenType::Comma {
+            return Ok(self.make_target(first, ctx));
+        }
+        let mut elts = vec![first];
+        while self.eat(TokenType::Comma) {
+            if !can_start_expression(self.peek()) {
+                break;
+            }
+            elts.push(self.parse_star_target()?);
+        }
+        let elts = self.arena.add_list(elts);
+        let tuple = self.expr(
+            ExprKind::Tuple {
+                elts,
+                ctx: ExprContext::Load,
+            },
+            start,
+        );
+        Ok(self.make_target(tuple, ctx))
+    }
+
+    /// `a, *b, c` as an unparenthesised tuple, or a single expression.
+    pub fn parse_star_expressions(&mut self) -> ParseResult<ExprId> {
+        let start = self.current;
+        let first = self.parse_star_expression()?;
+        if self.peek() != TokenType::Comma {
+            return Ok(first);
+        }
+        let mut elts = vec![first];
+        while self.eat(TokenType::Comma) {
+            if !can_start_expression(self.peek()) {
+                break;
+            }
+            elts.push(self.parse_star_expression()?);
+        }
+        let elts = self.arena.add_list(elts);
+        Ok(self.expr(
+            ExprKind::Tuple {
+                elts,
+                ctx: ExprContext::Load,
+            },
+            start,
+        ))
+    }
+
+    fn parse_star_expression(&mut self) -> ParseResult<ExprId> {
+        if self.peek() == TokenType::Star {
+            return self.parse_starred();
+        }
+        self.parse_expression()
+    }
+
+    fn parse_star_named_expression(&mut self) -> ParseResult<ExprId> {
+        if self.peek() == TokenType::Star {
+            return self.parse_starred();
+        }
+        self.parse_named_expression()
+    }
+
+    fn parse_starred(&mut self) -> ParseResult<ExprId> {
+        let start = self.current;
+        self.advance();
+        let value = self.parse_bitwise_or()?;
+        Ok(self.expr(
+            ExprKind::Starred {
+                value,
+                ctx: ExprContext::Load,
+            },
+            start,
+        ))
+    }
+
+    pub fn parse_named_expression(&mut self) -> ParseResult<ExprId> {
+        if self.peek() != TokenType::Name || self.peek_at(1) != TokenType::ColonEqual {
+            return self.parse_expression();
+        }
+        let start = self.current;
+        let id = self.expect_name()?;
+        let target = self.expr(
+            ExprKind::Name {
+                id,
+                ctx: ExprContext::Store,
+            },
+            start,
+        );
+        self.advance();
+        let value = self.parse_expression()?;
+        Ok(self.expr(ExprKind::NamedExpr { target, value }, start))
+    }
+
+    pub fn parse_expression(&mut self) -> ParseResult<ExprId> {
+        if self.peek() == TokenType::Lambda {
+            return self.parse_lambda();
+        }
+        let start = self.current;
+        let body = self.parse_disjunction()?;
+        if !self.eat(TokenType::If) {
+            return Ok(body);
+        }
+        let test = self.parse_disjunction()?;
+        if !self.eat(TokenType::Else) {
+            return Err(ParseError::new(
+                "expected 'else' after 'if' expression",
+                self.span_from(start),
+            ));
+        }
+        let orelse = self.parse_expression()?;
+        Ok(self.expr(ExprKind::IfExp { test, body, orelse }, start))
+    }
+
+    /// `yield`, `yield value` or `yield from value`.
+    fn parse_yield(&mut self) -> ParseResult<ExprId> {
+        let start = self.current;
+        self.advance();
+        let kind = if self.eat(TokenType::From) {
+            ExprKind::YieldFrom {
+                value: self.parse_expression()?,
+            }
+        } else if can_start_expression(self.peek()) {
+            ExprKind::Yield {
+                value: Some(self.parse_star_expressions()?),
+            }
+        } else {
+            ExprKind::Yield { value: None }
+        };
+        Ok(self.expr(kind, start))
+    }
+
+    fn parse_lambda(&mut self) -> ParseResult<ExprId> {
+        let start = self.current;
+        self.advance();
+        let args = self.parse_parameters(false, TokenType::Colon)?;
+        self.expect(TokenType::Colon, "expected ':'")?;
+        let body = self.parse_expression()?;
+        Ok(self.expr(
+            ExprKind::Lambda {
+                args: Box::new(args),
+                body,
+            },
+            start,
+        ))
+    }
+
+    fn parse_bool_op(
+        &mut self,
+        token_type: TokenType,
+        op: BoolOperator,
+        operand: fn(&mut Self) -> ParseResult<ExprId>,
+    ) -> ParseResult<ExprId> {
+        let start = self.current;
+        let first = operand(self)?;
+        if self.peek() != token_type {
+            return Ok(first);
+        }
+        let mut values = vec![first];
+        while self.eat(token_type) {
+            values.push(operand(self)?);
+        }
+        let values = self.arena.add_list(values);
+        Ok(self.expr(ExprKind::BoolOp { op, values }, start))
+    }
+
+    fn parse_disjunction(&mut self) -> ParseResult<ExprId> {
+        self.parse_bool_op(TokenType::Or, BoolOperator::Or, Self::parse_conjunction)
+    }
+
+    fn parse_conjunction(&mut self) -> ParseResult<ExprId> {
+        self.parse_bool_op(TokenType::And, BoolOperator::And, Self::parse_inversion)
+    }
+
+    fn parse_inversion(&mut self) -> ParseResult<ExprId> {
+        if self.peek() != TokenType::Not {
+            return self.parse_comparison();
+        }
+        let start = self.current;
+        self.advance();
+        let operand = self.parse_inversion()?;
+        Ok(self.expr(
+            ExprKind::UnaryOp {
+                op: UnaryOperator::Not,
+                operand,
+            },
+            start,
+        ))
+    }
+
+    fn comparison_operator(&mut self) -> Option<CmpOp> {
+        let op = match self.peek() {
+            TokenType::EqEqual => CmpOp::Eq,
+            TokenType::NotEqual => CmpOp::NotEq,
+            TokenType::Less => CmpOp::Lt,
+            TokenType::LessEqual => CmpOp::LtE,
+            TokenType::Greater => CmpOp::Gt,
+            TokenType::GreaterEqual => CmpOp::GtE,
+            TokenType::In => CmpOp::In,
+            TokenType::Not if self.peek_at(1) == TokenType::In => {
+                self.advance();
+                CmpOp::NotIn
+            }
+            TokenType::Is if self.peek_at(1) == TokenType::Not => {
+                self.advance();
+                CmpOp::IsNot
+            }
+            TokenType::Is => CmpOp::Is,
+            _ => return None,
+        };
+        self.advance();
+        Some(op)
+    }
+
+    fn parse_comparison(&mut self) -> ParseResult<ExprId> {
+        let start = self.current;
+        let left = self.parse_bitwise_or()?;
+        let mut ops = Vec::new();
+        let mut comparators = Vec::new();
+        while let Some(op) = self.comparison_operator() {
+            ops.push(op);
+            comparators.push(self.parse_bitwise_or()?);
+        }
+        if ops.is_empty() {
+            return Ok(left);
+        }
+        let ops = self.arena.add_list(ops);
+        let comparators = self.arena.add_list(comparators);
+        Ok(self.expr(
+            ExprKind::Compare {
+                left,
+                ops,
+                comparators,
+            },
+            start,
+        ))
+    }
+
+    /// One left-associative level of binary operators.
+    fn parse_binary(
+        &mut self,
+        operators: &[(TokenType, Operator)],
+        operand: fn(&mut Self) -> ParseResult<ExprId>,
+    ) -> ParseResult<ExprId> {
+        let start = self.current;
+        let mut left = operand(self)?;
+        while let Some(&(_, op)) = operators
+            .iter()
+            .find(|(token_type, _)| *token_type == self.peek())
+        {
+            self.advance();
+            let right = operand(self)?;
+            left = self.expr(ExprKind::BinOp { left, op, right }, start);
+        }
+        Ok(left)
+    }
+
+    fn parse_bitwise_or(&mut self) -> ParseResult<ExprId> {
+        self.parse_binary(
+            &[(TokenType::Vbar, Operator::BitOr)],
+            Self::parse_bitwise_xor,
+        )
+    }
+
+    fn parse_bitwise_xor(&mut self) -> ParseResult<ExprId> {
+        self.parse_binary(
+            &[(TokenType::Circumflex, Operator::BitXor)],
+            Self::parse_bitwise_and,
+        )
+    }
+
+    fn parse_bitwise_and(&mut self) -> ParseResult<ExprId> {
+        self.parse_binary(&[(TokenType::Amper, Operator::BitAnd)], Self::parse_shift)
+    }
+
+    fn parse_shift(&mut self) -> ParseResult<ExprId> {
+        self.parse_binary(
+            &[
+                (TokenType::LeftShift, Operator::LShift),
+                (TokenType::RightShift, Operator::RShift),
+            ],
+            Self::parse_sum,
+        )
+    }
+
+    fn parse_sum(&mut self) -> ParseResult<ExprId> {
+        self.parse_binary(
+            &[
+                (TokenType::Plus, Operator::Add),
+                (TokenType::Minus, Operator::Sub),
+            ],
+            Self::parse_term,
+        )
+    }
+
+    fn parse_term(&mut self) -> ParseResult<ExprId> {
+        self.parse_binary(
+            &[
+                (TokenType::Star, Operator::Mult),
+                (TokenType::Slash, Operator::Div),
+                (TokenType::DoubleSlash, Operator::FloorDiv),
+                (TokenType::Percent, Operator::Mod),
+                (TokenType::At, Operator::MatMult),
+            ],
+            Self::parse_factor,
+        )
+    }
+
+    fn parse_factor(&mut self) -> ParseResult<ExprId> {
+        let op = match self.peek() {
+            TokenType::Plus => UnaryOperator::UAdd,
+            TokenType::Minus => UnaryOperator::USub,
+            TokenType::Tilde => UnaryOperator::Invert,
+            _ => return self.parse_power(),
+        };
+        let start = self.current;
+        self.advance();
+        let operand = self.parse_factor()?;
+        Ok(self.expr(ExprKind::UnaryOp { op, operand }, start))
+    }
+
+    fn parse_power(&mut self) -> ParseResult<ExprId> {
+        let start = self.current;
+        let left = self.parse_await_primary()?;
+        if !self.eat(TokenType::DoubleStar) {
+            return Ok(left);
+        }
+        let right = self.parse_factor()?;
+        Ok(self.expr(
+            ExprKind::BinOp {
+                left,
+                op: Operator::Pow,
+                right,
+            },
+            start,
+        ))
+    }
+
+    /// `await primary`, which binds tighter than `**` on its right.
+    fn parse_await_primary(&mut self) -> ParseResult<ExprId> {
+        if !self.eat(TokenType::Await) {
+            return self.parse_primary();
+        }
+        let start = self.current - 1;
+        let value = self.parse_primary()?;
+        Ok(self.expr(ExprKind::Await { value }, start))
+    }
+
+    /// An atom followed by any number of `.name`, `(args)` and `[slices]`.
+    pub fn parse_primary(&mut self) -> ParseResult<ExprId> {
+        let start = self.current;
+        let mut value = self.parse_atom()?;
+        loop {
+            let kind = match self.peek() {
+                TokenType::Dot => {
+                    self.advance();
+                    ExprKind::Attribute {
+                        value,
+                        attr: self.expect_name()?,
+                        ctx: ExprContext::Load,
+                    }
+                }
+                TokenType::Lpar => {
+                    self.advance();
+                    let (args, keywords) = self.parse_call_arguments()?;
+                    ExprKind::Call {
+                        func: value,
+                        args,
+                        keywords,
+                    }
+                }
+                TokenType::Lsqb => {
+                    self.advance();
+                    let slice = self.parse_slices()?;
+                    self.expect(TokenType::Rsqb, "invalid syntax")?;
+                    ExprKind::Subscript {
+                        value,
+                        slice,
+                        ctx: ExprContext::Load,
+                    }
+                }
+                _ => return Ok(value),
+            };
+            value = self.expr(kind, start);
+        }
+    }
+
+    /// Inside brackets, an element that runs straight into the start of
+    /// another expression most likely lacks a comma.
+    fn check_missing_comma(&self, element: Span) -> ParseResult<()> {
+        if can_start_expression(self.peek()) {
+            return Err(ParseError::new(
+                "invalid syntax. Perhaps you forgot a comma?",
+                element.to(Self::token_span(self.token())),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Arguments of a call or class definition; the opening parenthesis
+    /// has been consumed and the closing one is consumed here.
+    pub fn parse_call_arguments(&mut self) -> ParseResult<(List<ExprId>, List<Keyword>)> {
+        let open = self.current - 1;
+        let mut args: Vec<ExprId> = Vec::new();
+        let mut keywords: Vec<Keyword> = Vec::new();
+        let mut generator = None;
+        let mut comma = false;
+        while self.peek() != TokenType::Rpar {
+            let start = self.current;
+            match self.peek() {
+                TokenType::Star => {
+                    let arg = self.parse_starred()?;
+                    if keywords.iter().any(|keyword| keyword.arg.is_none()) {
+                        self.errors.push(ParseError::new(
+                            "iterable argument unpacking follows keyword argument unpacking",
+                            self.arena[arg].span,
+                        ));
+                    }
+                    args.push(arg);
+                }
+                TokenType::DoubleStar => {
+                    self.advance();
+                    keywords.push(Keyword {
+                        arg: None,
+                        value: self.parse_expression()?,
+                    });
+                }
+                TokenType::Name if self.peek_at(1) == TokenType::Equal => {
+                    let arg = self.expect_name()?;
+                    self.advance();
+                    keywords.push(Keyword {
+                        arg: Some(arg),
+                        value: self.parse_expression()?,
+                    });
+                }
+                _ => {
+                    let mut arg = self.parse_named_expression()?;
+                    if self.at_comprehension() {
+                        let kind = self.parse_comprehension(arg, Comprehensions::Generator)?;
+                        arg = self.expr(kind, start);
+                        generator.get_or_insert(self.arena[arg].span);
+                    }
+                    if let Some(keyword) = keywords.last() {
+                        let message = if keyword.arg.is_some() {
+                            "positional argument follows keyword argument"
+                        } else {
+                            "positional argument follows keyword argument unpacking"
+                        };
+                        self.errors
+                            .push(ParseError::new(message, self.arena[arg].span));
+                    }
+                    args.push(arg);
+                }
+            }
+            self.check_missing_comma(self.span_from(start))?;
+            if !self.eat(TokenType::Comma) {
+                break;
+            }
+            comma = true;
+        }
+        if let Some(span) = generator {
+            if comma || args.len() + keywords.len() > 1 {
+                self.errors.push(ParseError::new(
+                    "Generator expression must be parenthesized",
+                    span,
+                ));
+            }
+        }
+        self.expect(TokenType::Rpar, "invalid syntax")?;
+        // A lone generator expression shares the call's parentheses.
+        if generator.is_some() && args.len() == 1 && keywords.is_empty() {
+            self.arena[args[0]].span = self.span_from(open);
+        }
+        Ok((self.arena.add_list(args), self.arena.add_list(keywords)))
+    }
+
+    fn parse_slices(&mut self) -> ParseResult<ExprId> {
+        let start = self.current;
+        let first = self.parse_slice()?;
+        if self.peek() != TokenType::Comma {
+            return Ok(first);
+        }
+        let mut elts = vec![first];
+        while self.eat(TokenType::Comma) {
+            if self.peek() == TokenType::Rsqb {
+                break;
+            }
+            elts.push(self.parse_slice()?);
+        }
+        let elts = self.arena.add_list(elts);
+        Ok(self.expr(
+            ExprKind::Tuple {
+                elts,
+                ctx: ExprContext::Load,
+            },
+            start,
+        ))
+    }
+
+    fn ends_slice(&self) -> bool {
+        matches!(
+            self.peek(),
+            TokenType::Colon | TokenType::Comma | TokenType::Rsqb
+        )
+    }
+
+    fn parse_slice(&mut self) -> ParseResult<ExprId> {
+        let start = self.current;
+        let lower = if self.peek() == TokenType::Colon {
+            None
+        } else {
+            let lower = self.parse_star_named_expression()?;
+            if self.peek() != TokenType::Colon {
+                return Ok(lower);
+            }
+            Some(lower)
+        };
+        self.advance();
+        let upper = if self.ends_slice() {
+            None
+        } else {
+            Some(self.parse_expression()?)
+        };
+        let step = if self.eat(TokenType::Colon) && !self.ends_slice() {
+            Some(self.parse_expression()?)
+        } else {
+            None
+        };
+        Ok(self.expr(ExprKind::Slice { lower, upper, step }, start))
+    }
+
+    /// Whether a `for` or `async for` clause starts here.
+    fn at_comprehension(&self) -> bool {
+        match self.peek() {
+            TokenType::For => true,
+            TokenType::Async => self.peek_at(1) == TokenType::For,
+            _ => false,
+        }
+    }
+
+    /// `for target in iter if cond ...` clauses, up to the closing bracket.
+    fn parse_comprehension_clauses(&mut self) -> ParseResult<List<Comprehension>> {
+        let mut generators = Vec::new();
+        while self.at_comprehension() {
+            let is_async = self.eat(TokenType::Async);
+            self.advance();
+            let target = self.parse_target_list(ExprContext::Store)?;
+            self.expect(TokenType::In, "invalid syntax")?;
+            let iter = self.parse_disjunction()?;
+            let mut ifs = Vec::new();
+            while self.eat(TokenType::If) {
+                ifs.push(self.parse_disjunction()?);
+            }
+            generators.push(Comprehension {
+                target,
+                iter,
+                ifs: self.arena.add_list(ifs),
+                is_async,
+            });
+        }
+        Ok(self.arena.add_list(generators))
+    }
+
+    fn parse_comprehension(&mut self, elt: ExprId, kind: Comprehensions) -> ParseResult<ExprKind> {
+        let generators = self.parse_comprehension_clauses()?;
+        Ok(match kind {
+            Comprehensions::List => ExprKind::ListComp { elt, generators },
+            Comprehensions::Set => ExprKind::SetComp { elt, generators },
+            Comprehensions::Generator => ExprKind::GeneratorExp { elt, generators },
+        })
+    }
+
+    /// Comma-separated elements of a display, up to the closing token.
+    fn parse_elements(&mut self, first: ExprId, close: TokenType) -> ParseResult<List<ExprId>> {
+        self.check_missing_comma(self.arena[first].span)?;
+        let mut elts = vec![first];
+        while self.eat(TokenType::Comma) {
+            if self.peek() == close {
+                break;
+            }
+            let elt = self.parse_star_named_expression()?;
+            self.check_missing_comma(self.arena[elt].span)?;
+            elts.push(elt);
+        }
+        Ok(self.arena.add_list(elts))
+    }
+
+    pub fn parse_atom(&mut self) -> ParseResult<ExprId> {
+        let start = self.current;
+        let token = self.token();
+        let kind = match token.token_type {
+            TokenType::Name => {
+                self.advance();
+                ExprKind::Name {
+                    id: Symbol::intern(token.value()),
+                    ctx: ExprContext::Load,
+                }
+            }
+            TokenType::None | TokenType::True | TokenType::False | TokenType::Ellipsis => {
+                self.advance();
+                let value = match token.token_type {
+                    TokenType::None => Constant::None,
+                    TokenType::True => Constant::Bool(true),
+                    TokenType::False => Constant::Bool(false),
+                    _ => Constant::Ellipsis,
+                };
+                ExprKind::Constant { value }
+            }
+            TokenType::Number => {
+                self.advance();
+                let value = parse_number(token.value())
+                    .map_err(|message| ParseError::new(message, Self::token_span(token)))?;
+                ExprKind::Constant { value }
+            }
+            TokenType::String => ExprKind::Constant {
+                value: self.parse_strings()?,
+            },
+            TokenType::Lpar => return self.parse_paren(),
+            TokenType::Lsqb => {
+                self.advance();
+                if self.eat(TokenType::Rsqb) {
+                    ExprKind::List {
+                        elts: List::new(),
+                        ctx: ExprContext::Load,
+                    }
+                } else {
+                    let first = self.parse_star_named_expression()?;
+                    if self.at_comprehension() {
+                        let comp = self.parse_comprehension(first, Comprehensions::List)?;
+                        self.expect(TokenType::Rsqb, "invalid syntax")?;
+                        return Ok(self.expr(comp, start));
+                    }
+                    let elts = self.parse_elements(first, TokenType::Rsqb)?;
+                    self.expect(TokenType::Rsqb, "invalid syntax")?;
+                    ExprKind::List {
+                        elts,
+                        ctx: ExprContext::Load,
+                    }
+                }
+            }
+            TokenType::Lbrace => self.parse_brace()?,
+            _ => return self.error("invalid syntax"),
+        };
+        Ok(self.expr(kind, start))
+    }
+
+    fn parse_paren(&mut self) -> ParseResult<ExprId> {
+        let start = self.current;
+        self.advance();
+        if self.eat(TokenType::Rpar) {
+            return Ok(self.expr(
+                ExprKind::Tuple {
+                    elts: List::new(),
+                    ctx: ExprContext::Load,
+                },
+                start,
+            ));
+        }
+        if self.peek() == TokenType::Yield {
+            let value = self.parse_yield()?;
+            self.expect(TokenType::Rpar, "invalid syntax")?;
+            return Ok(value);
+        }
+        let first = self.parse_star_named_expression()?;
+        if self.at_comprehension() {
+            let comp = self.parse_comprehension(first, Comprehensions::Generator)?;
+            self.expect(TokenType::Rpar, "invalid syntax")?;
+            return Ok(self.expr(comp, start));
+        }
+        if self.peek() != TokenType::Comma {
+            self.check_missing_comma(self.arena[first].span)?;
+            self.expect(TokenType::Rpar, "invalid syntax")?;
+            return Ok(first);
+        }
+        let elts = self.parse_elements(first, TokenType::Rpar)?;
+        self.expect(TokenType::Rpar, "invalid syntax")?;
+        Ok(self.expr(
+            ExprKind::Tuple {
+                elts,
+                ctx: ExprContext::Load,
+            },
+            start,
+        ))
+    }
+
+    fn parse_brace(&mut self) -> ParseResult<ExprKind> {
+        self.advance();
+        if self.eat(TokenType::Rbrace) {
+            return Ok(ExprKind::Dict {
+                keys: List::new(),
+                values: List::new(),
+            });
+        }
+        let first_key = if self.eat(TokenType::DoubleStar) {
+            None
+        } else {
+            let first = self.parse_star_named_expression()?;
+            if self.peek() != TokenType::Colon {
+                if self.at_comprehension() {
+                    let comp = self.parse_comprehension(first, Comprehensions::Set)?;
+                    self.expect(TokenType::Rbrace, "invalid syntax")?;
+                    return Ok(comp);
+                }
+                let elts = self.parse_elements(first, TokenType::Rbrace)?;
+                self.expect(TokenType::Rbrace, "invalid syntax")?;
+                return Ok(ExprKind::Set { elts });
+            }
+            self.advance();
+            Some(first)
+        };
+        let first_value = if first_key.is_some() {
+            self.parse_expression()?
+        } else {
+            self.parse_bitwise_or()?
+        };
+        if let (Some(key), true) = (first_key, self.at_comprehension()) {
+            let generators = self.parse_comprehension_clauses()?;
+            self.expect(TokenType::Rbrace, "invalid syntax")?;
+            return Ok(ExprKind::DictComp {
+                key,
+                value: first_value,
+                generators,
+            });
+        }
+        self.check_missing_comma(self.arena[first_value].span)?;
+        let mut keys = vec![first_key];
+        let mut values = vec![first_value];
+        while self.eat(TokenType::Comma) {
+            if self.peek() == TokenType::Rbrace {
+                break;
+            }
+            if self.eat(TokenType::DoubleStar) {
+                keys.push(None);
+                values.push(self.parse_bitwise_or()?);
+            } else {
+                keys.push(Some(self.parse_expression()?));
+                self.expect(TokenType::Colon, "':' expected after dictionary key")?;
+                values.push(self.parse_expression()?);
+            }
+            self.check_missing_comma(self.arena[*values.last().unwrap()].span)?;
+        }
+        self.expect(TokenType::Rbrace, "invalid syntax")?;
+        Ok(ExprKind::Dict {
+            keys: self.arena.add_list(keys),
+            values: self.arena.add_list(values),
+        })
+    }
+
+    /// Adjacent string literals concatenate into one constant.
+    fn parse_strings(&mut self) -> ParseResult<Constant> {
+        let mut text = String::new();
+        let mut bytes: Option<Vec<u8>> = None;
+        let mut first = true;
+        while self.peek() == TokenType::String {
+            let token = self.advance();
+            let span = Self::token_span(token);
+            match decode_string(token.value()).map_err(|message| ParseError::new(message, span))? {
+                Constant::Str(value) if bytes.is_none() => text.push_str(&value),
+                Constant::Bytes(value) if first || bytes.is_some() => {
+                    bytes.get_or_insert_with(Vec::new).extend(value)
+                }
+                _ => {
+                    return Err(ParseError::new(
+                        "cannot mix bytes and nonbytes literals",
+                        span,
+                    ))
+                }
+            }
+            first = false;
+        }
+        Ok(match bytes {
+            Some(bytes) => Constant::Bytes(bytes),
+            None => Constant::Str(text),
+        })
+    }
+}
+
+pub fn parse_number(text: &str) -> Result<Constant, String> {
+    let lower = text.to_ascii_lowercase();
+    let (radix, kind) = match lower.get(..2) {
+        Some("0x") => (16, "hexadecimal"),
+        Some("0o") => (8, "octal"),
+        Some("0b") => (2, "binary"),
+        _ => (10, "decimal"),
+    };
+    if !underscores_separate_digits(text, radix) {
+        return Err(format!("invalid {} literal", kind));
+    }
+    let digits = text.replace('_', "");
+    if lower.ends_with('j') {
+        return Err("complex literals are not supported".to_string());
+    }
+    if radix != 10 {
+        return i64::from_str_radix(&digits[2..], radix)
+            .map(Constant::Int)
+            .map_err(|_| format!("integer literal too large: {}", text));
+    }
+    if lower.contains(['.', 'e']) {
+        return digits
+            .parse()
+            .map(Constant::Float)
+            .map_err(|_| "invalid decimal literal".to_string());
+    }
+    if digits.starts_with('0') && digits.bytes().any(|digit| digit != b'0') {
+        return Err(
+            "leading zeros in decimal integer literals are not permitted; \
+                    use an 0o prefix for octal integers"
+                .to_string(),
+        );
+    }
+    digits
+        .parse()
+        .map(Constant::Int)
+        .map_err(|_| format!("integer literal too large: {}", text))
+}
+
+/// An underscore may only sit between two digits, or between a base
+/// prefix and a digit: `1_000` and `0x_ff` are fine, `1__0`, `0_` and
+/// `1_.5` are not.
+fn underscores_separate_digits(text: &str, radix: u32) -> bool {
+    let bytes = text.as_bytes();
+    let is_digit = |i: usize| bytes.get(i).is_some_and(|&b| (b as char).is_digit(radix));
+    bytes.iter().enumerate().all(|(i, &b)| {
+        b != b'_' || (i > 0 && is_digit(i + 1) && (is_digit(i - 1) || (radix != 10 && i == 2)))
+    })
+}
+
+/// Decodes one string token, prefix and quotes included.
+pub fn decode_string(token: &str) -> Result<Constant, String> {
+    let prefix_len = token.find(['\'', '"']).unwrap();
+    let prefix = token[..prefix_len].to_ascii_lowercase();
+    if prefix.contains('f') {
+        return Err("f-strings are not supported".to_string());
+    }
+    let rest = &token[prefix_len..];
+    let quote_len = if rest.len() >= 6 && (rest.starts_with("'''") || rest.starts_with("\"\"\"")) {
+        3
+    } else {
+        1
+    };
+    let body = &rest[quote_len..rest.len() - quote_len];
+    let raw = prefix.contains('r');
+    let is_bytes = prefix.contains('b');
+    if is_bytes && !body.is_ascii() {
+        return Err("bytes can only contain ASCII literal characters".to_string());
+    }
+    if raw {
+        return Ok(if is_bytes {
+            Constant::Bytes(body.as_bytes().to_vec())
+        } else {
+            Constant::Str(body.to_string())
+        });
+    }
+    let mut out = String::new();
+    let mut out_bytes = Vec::new();
+    let mut chars = body.chars().peekable();
+    let push = |c: char, out: &mut String, out_bytes: &mut Vec<u8>| {
+        if is_bytes {
+            let mut buf = [0; 4];
+            out_bytes.extend(c.encode_utf8(&mut buf).as_bytes());
+        } else {
+            out.push(c);
+        }
+    };
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            push(c, &mut out, &mut out_bytes);
+            continue;
+        }
+        let Some(escape) = chars.next() else {
+            push('\\', &mut out, &mut out_bytes);
+            break;
+        };
+        let hex = |count: usize, chars: &mut std::iter::Peekable<std::str::Chars>| {
+            let digits: String = (0..count).filter_map(|_| chars.next()).collect();
+            match u32::from_str_radix(&digits, 16) {
+                Ok(value) if digits.len() == count => Ok(value),
+                _ => Err(format!(
+                    "truncated \\{}{} escape",
+                    escape,
+                    "X".repeat(count)
+                )),
+            }
+        };
+        match escape {
+            '\n' => {}
+            '\\' | '\'' | '"' => push(escape, &mut out, &mut out_bytes),
+            'n' => push('\n', &mut out, &mut out_bytes),
+            't' => push('\t', &mut out, &mut out_bytes),
+            'r' => push('\r', &mut out, &mut out_bytes),
+            'a' => push('\x07', &mut out, &mut out_bytes),
+            'b' => push('\x08', &mut out, &mut out_bytes),
+            'f' => push('\x0c', &mut out, &mut out_bytes),
+            'v' => push('\x0b', &mut out, &mut out_bytes),
+            '0'..='7' => {
+                let mut value = escape.to_digit(8).unwrap();
+                for _ in 0..2 {
+                    match chars.peek().and_then(|c| c.to_digit(8)) {
+                        Some(digit) => {
+                            value = value * 8 + digit;
+                            chars.next();
+                        }
+                        None => break,
+                    }
+                }
+                if is_bytes {
+                    out_bytes.push(value as u8);
+                } else {
+                    out.push(char::from_u32(value).unwrap());
+                }
+            }
+            'x' => {
+                let value = hex(2, &mut chars)?;
+                if is_bytes {
+                    out_bytes.push(value as u8);
+                } else {
+                    out.push(char::from_u32(value).unwrap());
+                }
+            }
+            'u' | 'U' if !is_bytes => {
+                let value = hex(if escape == 'u' { 4 } else { 8 }, &mut chars)?;
+                out.push(char::from_u32(value).ok_or("illegal Unicode character")?);
+            }
+            'N' if !is_bytes => {
+                let malformed = || "malformed \\N character escape".to_string();
+                if chars.next() != Some('{') {
+                    return Err(malformed());
+                }
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => return Err(malformed()),
+                    }
+                }
+                let value = unicode_names2::character(&name)
+                    .ok_or("unknown Unicode character name")?;
+                out.push(value);
+            }
+            _ => {
+                push('\\', &mut out, &mut out_bytes);
+                push(escape, &mut out, &mut out_bytes);
+            }
+        }
+    }
+    Ok(if is_bytes {
+        Constant::Bytes(out_bytes)
+    } else {
+        Constant::Str(out)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::dump;
+
+    fn dump_source(source: &str) -> String {
+        dump(&parse(source).unwrap())
+    }
+
+    #[test]
+    fn test_parse_assign() {
+        let source = std::fs::read_to_string("tests/var.py").unwrap();
+        let module = parse(&source).unwrap();
+        assert_eq!(module.body.len(), 5);
+        let mut arena = module.arena;
+        let body = arena.add_list(arena[module.body][2..3].to_vec());
+        assert_eq!(
+            dump(&Module { body, arena }),
+            "Module(body=[Assign(targets=[Name(id='z', ctx=Store())], value=BinOp(left=BinOp(left=Constant(value=3), op=Sub(), right=Constant(value=2)), op=Add(), right=Constant(value=5)))], type_ignores=[])"
+        );
+    }
+
+    #[test]
+    fn test_parse_def() {
+        let source = std::fs::read_to_string("tests/fib.py").unwrap();
+        assert_eq!(
+            dump_source(&source),
+            "Module(body=[FunctionDef(name='fib', args=arguments(posonlyargs=[], args=[arg(arg='x')], kwonlyargs=[], kw_defaults=[], defaults=[]), body=[If(test=Compare(left=Name(id='x', ctx=Load()), ops=[Lt()], comparators=[Constant(value=2)]), body=[Return(value=Name(id='x', ctx=Load()))], orelse=[]), Return(value=BinOp(left=Call(func=Name(id='fib', ctx=Load()), args=[BinOp(left=Name(id='x', ctx=Load()), op=Sub(), right=Constant(value=1))], keywords=[]), op=Add(), right=Call(func=Name(id='fib', ctx=Load()), args=[BinOp(left=Name(id='x', ctx=Load()), op=Sub(), right=Constant(value=2))], keywords=[])))], decorator_list=[]), Expr(value=Call(func=Name(id='print', ctx=Load()), args=[Call(func=Name(id='fib', ctx=Load()), args=[Constant(value=10)], keywords=[])], keywords=[]))], type_ignores=[])"
+        );
+    }
+
+    #[test]
+    fn test_parse_ambiguous() {
+        let source = std::fs::read_to_string("tests/ambiguous.py").unwrap();
+        let module = parse(&source).unwrap();
+        let mut arena = module.arena;
+        let body = arena.add_list(arena[module.body][3..].to_vec());
+        assert_eq!(
+            dump(&Module { body, arena }),
+            "Module(body=[Assign(targets=[Name(id='w', ctx=Store())], value=BinOp(left=Constant(value=1), op=Sub(), right=BinOp(left=Constant(value=2), op=Mult(), right=Constant(value=3))))], type_ignores=[])"
+        );
+    }
+
+    #[test]
+    fn test_parse_empty_new_line() {
+        let source = std::fs::read_to_string("tests/def.py").unwrap();
+        assert_eq!(
+            dump_source(&source),
+            "Module(body=[FunctionDef(name='echo', args=arguments(posonlyargs=[], args=[arg(arg='x')], kwonlyargs=[], kw_defaults=[], defaults=[]), body=[Return(value=Name(id='x', ctx=Load()))], decorator_list=[])], type_ignores=[])"
+        );
+    }
+
+    #[test]
+    fn test_parse_while_stmt() {
+        let source = std::fs::read_to_string("tests/while.py").unwrap();
+        assert_eq!(
+            dump_source(&source),
+            "Module(body=[Assign(targets=[Name(id='i', ctx=Store())], value=Constant(value=0)), While(test=Compare(left=Name(id='i', ctx=Load()), ops=[Lt()], comparators=[Constant(value=5)]), body=[If(test=Compare(left=Name(id='i', ctx=Load()), ops=[Eq()], comparators=[Constant(value=3)]), body=[Break()], orelse=[]), Assign(targets=[Name(id='i', ctx=Store())], value=BinOp(left=Name(id='i', ctx=Load()), op=Add(), right=Constant(value=1)))], orelse=[]), Expr(value=Call(func=Name(id='print', ctx=Load()), args=[Name(id='i', ctx=Load())], keywords=[]))], type_ignores=[])"
+        );
+    }
+
+    #[test]
+    fn test_parse_print_stmt() {
+        let source = std::fs::read_to_string("tests/print.py").unwrap();
+        assert_eq!(
+            dump_source(&source),
+            "Module(body=[Expr(value=Call(func=Name(id='print', ctx=Load()), args=[BinOp(left=Constant(value=3), op=Add(), right=Constant(value=4))], keywords=[]))], type_ignores=[])"
+        );
+    }
+
+    #[test]
+    fn test_parse_sym() {
+        let source = std::fs::read_to_string("tests/sym.py").unwrap();
+        let module = parse(&source).unwrap();
+        let mut arena = module.arena;
+        let body = arena.add_list(arena[module.body][..4].to_vec());
+        assert_eq!(
+            dump(&Module { body, arena }),
+            "Module(body=[Import(names=[alias(name='symtable')]), Import(names=[alias(name='json')]), Assign(targets=[Name(id='table', ctx=Store())], value=Call(func=Attribute(value=Name(id='symtable', ctx=Load()), attr='symtable', ctx=Load()), args=[Constant(value='fib'), Constant(value='fib.py'), Constant(value='exec')], keywords=[])), For(target=Name(id='name', ctx=Store()), iter=Call(func=Attribute(value=Name(id='table', ctx=Load()), attr='get_identifiers', ctx=Load()), args=[], keywords=[]), body=[Expr(value=Call(func=Name(id='print', ctx=Load()), args=[Name(id='name', ctx=Load())], keywords=[]))], orelse=[])], type_ignores=[])"
+        );
+    }
+
+    #[test]
+    fn test_parse_if_elif_else() {
+        let source = "if a:\n    x = 1\nelif b:\n    x = 2\nelse:\n    x = 3\n";
+        assert_eq!(
+            dump_source(source),
+            "Module(body=[If(test=Name(id='a', ctx=Load()), body=[Assign(targets=[Name(id='x', ctx=Store())], value=Constant(value=1))], orelse=[If(test=Name(id='b', ctx=Load()), body=[Assign(targets=[Name(id='x', ctx=Store())], value=Constant(value=2))], orelse=[Assign(targets=[Name(id='x', ctx=Store())], value=Constant(value=3))])])], type_ignores=[])"
+        );
+    }
+
+    #[test]
+    fn test_parse_for_else_and_while_else() {
+        let source = "for x, y in items:\n    continue\nelse:\n    pass\nwhile 0:\n    break\nelse:\n    pass\n";
+        assert_eq!(
+            dump_source(source),
+            "Module(body=[For(target=Tuple(elts=[Name(id='x', ctx=Store()), Name(id='y', ctx=Store())], ctx=Store()), iter=Name(id='items', ctx=Load()), body=[Continue()], orelse=[Pass()]), While(test=Constant(value=0), body=[Break()], orelse=[Pass()])], type_ignores=[])"
+        );
+    }
+
+    #[test]
+    fn test_parse_simple_statements() {
+        let source = "def f():\n    global a, b\n    nonlocal c\n    del a, b[0], c.d\n    assert a, 'message'\n    return\n";
+        assert_eq!(
+            dump_source(source),
+            "Module(body=[FunctionDef(name='f', args=arguments(posonlyargs=[], args=[], kwonlyargs=[], kw_defaults=[], defaults=[]), body=[Global(names=['a', 'b']), Nonlocal(names=['c']), Delete(targets=[Name(id='a', ctx=Del()), Subscript(value=Name(id='b', ctx=Load()), slice=Constant(value=0), ctx=Del()), Attribute(value=Name(id='c', ctx=Load()), attr='d', ctx=Del())]), Assert(test=Name(id='a', ctx=Load()), msg=Constant(value='message')), Return()], decorator_list=[])], type_ignores=[])"
+        );
+    }
+
+    #[test]
+    fn test_parse_semicolons_and_one_line_bodies() {
+        let source = "x = 1; y = 2;\nif x: return 1\nwhile x: x -= 1; continue\n";
+        assert_eq!(
+            dump_source(source),
+            "Module(body=[Assign(targets=[Name(id='x', ctx=Store())], value=Constant(value=1)), Assign(targets=[Name(id='y', ctx=Store())], value=Constant(value=2)), If(test=Name(id='x', ctx=Load()), body=[Return(value=Constant(value=1))], orelse=[]), While(test=Name(id='x', ctx=Load()), body=[AugAssign(target=Name(id='x', ctx=Store()), op=Sub(), value=Constant(value=1)), Continue()], orelse=[])], type_ignores=[])"
+        );
+    }
+
+    #[test]
+    fn test_parse_expressions() {
+        let cases = [
+            (
+                "f(*a, b=1, **k)",
+                "Expr(value=Call(func=Name(id='f', ctx=Load()), args=[Starred(value=Name(id='a', ctx=Load()), ctx=Load())], keywords=[keyword(arg='b', value=Constant(value=1)), keyword(value=Name(id='k', ctx=Load()))]))",
+            ),
+            (
+                "{**d, 1:2}",
+                "Expr(value=Dict(keys=[None, Constant(value=1)], values=[Name(id='d', ctx=Load()), Constant(value=2)]))",
+            ),
+            (
+                "a[1:2, ::3]",
+                "Expr(value=Subscript(value=Name(id='a', ctx=Load()), slice=Tuple(elts=[Slice(lower=Constant(value=1), upper=Constant(value=2)), Slice(step=Constant(value=3))], ctx=Load()), ctx=Load()))",
+            ),
+            (
+                "print(x for x in y if z)",
+                "Expr(value=Call(func=Name(id='print', ctx=Load()), args=[GeneratorExp(elt=Name(id='x', ctx=Load()), generators=[comprehension(target=Name(id='x', ctx=Store()), iter=Name(id='y', ctx=Load()), ifs=[Name(id='z', ctx=Load())], is_async=0)])], keywords=[]))",
+            ),
+            (
+                "lambda: 0",
+                "Expr(value=Lambda(args=arguments(posonlyargs=[], args=[], kwonlyargs=[], kw_defaults=[], defaults=[]), body=Constant(value=0)))",
+            ),
+            (
+                "a if not b else -c ** 2",
+                "Expr(value=IfExp(test=UnaryOp(op=Not(), operand=Name(id='b', ctx=Load())), body=Name(id='a', ctx=Load()), orelse=UnaryOp(op=USub(), operand=BinOp(left=Name(id='c', ctx=Load()), op=Pow(), right=Constant(value=2)))))",
+            ),
+            (
+                "x is not y not in z",
+                "Expr(value=Compare(left=Name(id='x', ctx=Load()), ops=[IsNot(), NotIn()], comparators=[Name(id='y', ctx=Load()), Name(id='z', ctx=Load())]))",
+            ),
+            ("'it\\'s' \"\"", "Expr(value=Constant(value=\"it's\"))"),
+            ("'\\N{greek small letter alpha}\\N{BULLET}', b'\\N{x}'", "Expr(value=Tuple(elts=[Constant(value='α•'), Constant(value=b'\\\\N{x}')], ctx=Load()))"),
+            ("1_000.0_1, 1e1_0", "Expr(value=Tuple(elts=[Constant(value=1000.01), Constant(value=10000000000.0)], ctx=Load()))"),
+            ("1.5e20, 0x_ff, b'\\x00'", "Expr(value=Tuple(elts=[Constant(value=1.5e+20), Constant(value=255), Constant(value=b'\\x00')], ctx=Load()))"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(
+                dump_source(source),
+                format!("Module(body=[{}], type_ignores=[])", expected)
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_def_parameters() {
+        let source = "@dec\ndef f(a, /, b=1, *c, d, e=2, **g) -> int: pass\n";
+        assert_eq!(
+            dump_source(source),
+            "Module(body=[FunctionDef(name='f', args=arguments(posonlyargs=[arg(arg='a')], args=[arg(arg='b')], vararg=arg(arg='c'), kwonlyargs=[arg(arg='d'), arg(arg='e')], kw_defaults=[None, Constant(value=2)], kwarg=arg(arg='g'), defaults=[Constant(value=1)]), body=[Pass()], decorator_list=[Name(id='dec', ctx=Load())], returns=Name(id='int', ctx=Load()))], type_ignores=[])"
+        );
+    }
+
+    #[test]
+    fn test_parse_try_with_import() {
+        let source = "try:\n  pass\nexcept E as e:\n  pass\nfinally:\n  pass\nwith a as b, c: pass\nfrom . import a\nx: int = 1\n";
+        assert_eq!(
+            dump_source(source),
+            "Module(body=[Try(body=[Pass()], handlers=[ExceptHandler(type=Name(id='E', ctx=Load()), name='e', body=[Pass()])], orelse=[], finalbody=[Pass()]), With(items=[withitem(context_expr=Name(id='a', ctx=Load()), optional_vars=Name(id='b', ctx=Store())), withitem(context_expr=Name(id='c', ctx=Load()))], body=[Pass()]), ImportFrom(names=[alias(name='a')], level=1), AnnAssign(target=Name(id='x', ctx=Store()), annotation=Name(id='int', ctx=Load()), value=Constant(value=1), simple=1)], type_ignores=[])"
+        );
+    }
+
+    #[test]
+    fn test_parse_async() {
+        let source = "async def f(a) -> int:\n    async for x in y:\n        await z ** 2\n    async with a as b:\n        return [await i async for i in j]\n";
+        assert_eq!(
+            dump_source(source),
+            "Module(body=[AsyncFunctionDef(name='f', args=arguments(posonlyargs=[], args=[arg(arg='a')], kwonlyargs=[], kw_defaults=[], defaults=[]), body=[AsyncFor(target=Name(id='x', ctx=Store()), iter=Name(id='y', ctx=Load()), body=[Expr(value=BinOp(left=Await(value=Name(id='z', ctx=Load())), op=Pow(), right=Constant(value=2)))], orelse=[]), AsyncWith(items=[withitem(context_expr=Name(id='a', ctx=Load()), optional_vars=Name(id='b', ctx=Store()))], body=[Return(value=ListComp(elt=Await(value=Name(id='i', ctx=Load())), generators=[comprehension(target=Name(id='i', ctx=Store()), iter=Name(id='j', ctx=Load()), ifs=[], is_async=1)]))])], decorator_list=[], returns=Name(id='int', ctx=Load()))], type_ignores=[])"
+        );
+    }
+
+    #[test]
+    fn test_parse_yield() {
+        let source = "def f():\n    x = yield\n    y += yield a, b\n    yield from (yield)\n    return (yield c)\n";
+        assert_eq!(
+            dump_source(source),
+            "Module(body=[FunctionDef(name='f', args=arguments(posonlyargs=[], args=[], kwonlyargs=[], kw_defaults=[], defaults=[]), body=[Assign(targets=[Name(id='x', ctx=Store())], value=Yield()), AugAssign(target=Name(id='y', ctx=Store()), op=Add(), value=Yield(value=Tuple(elts=[Name(id='a', ctx=Load()), Name(id='b', ctx=Load())], ctx=Load()))), Expr(value=YieldFrom(value=Yield())), Return(value=Yield(value=Name(id='c', ctx=Load())))], decorator_list=[])], type_ignores=[])"
+        );
+    }
+
+    #[test]
+    fn test_parse_type_params() {
+        let source = "def f[T](x: T) -> T: pass\nclass Box[T: int, *Ts, **P](Base[T]): pass\ntype Alias[T: (int, str)] = list[T]\ntype = 1\n";
+        assert_eq!(
+            dump_source(source),
+            "Module(body=[FunctionDef(name='f', args=arguments(posonlyargs=[], args=[arg(arg='x', annotation=Name(id='T', ctx=Load()))], kwonlyargs=[], kw_defaults=[], defaults=[]), body=[Pass()], decorator_list=[], returns=Name(id='T', ctx=Load()), type_params=[TypeVar(name='T')]), ClassDef(name='Box', bases=[Subscript(value=Name(id='Base', ctx=Load()), slice=Name(id='T', ctx=Load()), ctx=Load())], keywords=[], body=[Pass()], decorator_list=[], type_params=[TypeVar(name='T', bound=Name(id='int', ctx=Load())), TypeVarTuple(name='Ts'), ParamSpec(name='P')]), TypeAlias(name=Name(id='Alias', ctx=Store()), type_params=[TypeVar(name='T', bound=Tuple(elts=[Name(id='int', ctx=Load()), Name(id='str', ctx=Load())], ctx=Load()))], value=Subscript(value=Name(id='list', ctx=Load()), slice=Name(id='T', ctx=Load()), ctx=Load())), Assign(targets=[Name(id='type', ctx=Store())], value=Constant(value=1))], type_ignores=[])"
+        );
+    }
+
+    #[test]
+    fn test_stmt_spans() {
+        let source = std::fs::read_to_string("tests/fib.py").unwrap();
+        let module = parse(&source).unwrap();
+        let body = &module.arena[module.body];
+        let def = &module.arena[body[0]];
+        assert_eq!(def.span.line, 1);
+        assert_eq!(
+            &source[def.span.start..def.span.end],
+            source.split("\n\n").next().unwrap()
+        );
+        assert_eq!(module.arena[body[1]].span.line, 6);
+    }
+
+    fn first_error(source: &str) -> String {
+        let errors = parse(source).unwrap_err();
+        errors[0].to_string()
+    }
+
+    #[test]
+    fn test_parse_error_messages() {
+        let cases = [
+            ("if x\n    pass\n", "expected ':' (line 1)"),
+            ("class A\n    pass\n", "expected ':' (line 1)"),
+            ("if x:\npass\n", "expected an indented block after 'if' statement on line 1 (line 2)"),
+            ("def f():\nx = 1\n", "expected an indented block after function definition on line 1 (line 2)"),
+            ("try:\n    pass\nx = 1\n", "expected 'except' or 'finally' block (line 3)"),
+            ("  x = 1\n", "unexpected indent (line 1)"),
+            ("x = (1,\n2\n", "'(' was never closed (line 1)"),
+            ("f([1,\n", "'[' was never closed (line 1)"),
+            ("x = (1,\ndef f(): pass\n", "'(' was never closed (line 1)"),
+            ("f)\n", "unmatched ')' (line 1)"),
+            ("x = (1,\n2]\n", "closing parenthesis ']' does not match opening parenthesis '(' on line 1 (line 2)"),
+            ("x = 'abc\n", "unterminated string literal (detected at line 1) (line 1)"),
+            ("x = '''abc\n\n", "unterminated triple-quoted string literal (detected at line 2) (line 1)"),
+            ("x = $\n", "invalid syntax (line 1)"),
+            ("x = a!b\n", "invalid syntax (line 1)"),
+            ("!\n", "invalid syntax (line 1)"),
+            ("x = \u{a4}\n", "invalid character '\u{a4}' (U+00A4) (line 1)"),
+            ("x = 1 \\ y\n", "unexpected character after line continuation character (line 1)"),
+            ("if x:\n    a\n  b\n", "unindent does not match any outer indentation level (line 3)"),
+            ("a b\n", "invalid syntax (line 1)"),
+            ("f(a b)\n", "invalid syntax. Perhaps you forgot a comma? (line 1)"),
+            ("[1, 2 3]\n", "invalid syntax. Perhaps you forgot a comma? (line 1)"),
+            ("{1: 2 3: 4}\n", "invalid syntax. Perhaps you forgot a comma? (line 1)"),
+            ("print 'hello'\n", "Missing parentheses in call to 'print'. Did you mean print(...)? (line 1)"),
+            ("x = 1 if y\n", "expected 'else' after 'if' expression (line 1)"),
+            ("if x = 1:\n    pass\n", "invalid syntax. Maybe you meant '==' or ':=' instead of '='? (line 1)"),
+            ("f() = 1\n", "cannot assign to function call here. Maybe you meant '==' instead of '='? (line 1)"),
+            ("x = f() = 1\n", "cannot assign to function call (line 1)"),
+            ("a < b = 1\n", "cannot assign to comparison (line 1)"),
+            ("None = 1\n", "cannot assign to None (line 1)"),
+            ("(a, 1) = 2\n", "cannot assign to literal (line 1)"),
+            ("for f() in x: pass\n", "cannot assign to function call (line 1)"),
+            ("del a + b\n", "cannot delete expression (line 1)"),
+            ("f() += 1\n", "'function call' is an illegal expression for augmented assignment (line 1)"),
+            ("(a, b): int\n", "only single target (not tuple) can be annotated (line 1)"),
+            ("*a = 1\n", "starred assignment target must be in a list or tuple (line 1)"),
+            ("x = *a\n", "can't use starred expression here (line 1)"),
+            ("f(x for x in y, 1)\n", "Generator expression must be parenthesized (line 1)"),
+            ("f(a=1, b)\n", "positional argument follows keyword argument (line 1)"),
+            ("f(**k, a)\n", "positional argument follows keyword argument unpacking (line 1)"),
+            ("f(**k, *a)\n", "iterable argument unpacking follows keyword argument unpacking (line 1)"),
+            ("def f(a=1, b): pass\n", "non-default argument follows default argument (line 1)"),
+            ("def f(*): pass\n", "named arguments must follow bare * (line 1)"),
+            ("def f(**k, a): pass\n", "arguments cannot follow var-keyword argument (line 1)"),
+            ("from a import b,\n", "trailing comma not allowed without surrounding parentheses (line 1)"),
+            ("await = 1\n", "invalid syntax (line 1)"),
+            ("async = 1\n", "invalid syntax (line 1)"),
+            ("async x\n", "invalid syntax (line 1)"),
+            ("async def f():\n    await await x\n", "invalid syntax (line 2)"),
+            ("async def f():\n    (await x) = 1\n", "cannot assign to await expression here. Maybe you meant '==' instead of '='? (line 2)"),
+            ("def f():\n    yield x = 1\n", "assignment to yield expression not possible (line 2)"),
+            ("def f():\n    x = (yield) = 1\n", "cannot assign to yield expression (line 2)"),
+            ("def f():\n    (yield) = 1\n", "cannot assign to yield expression here. Maybe you meant '==' instead of '='? (line 2)"),
+            ("def f():\n    return yield x\n", "invalid syntax (line 2)"),
+            ("def f():\n    x = yield from a, b\n", "invalid syntax (line 2)"),
+            ("def f[](): pass\n", "Type parameter list cannot be empty (line 1)"),
+            ("class A[*Ts: int]: pass\n", "cannot use bound with TypeVarTuple (line 1)"),
+            ("type A[**P: (int, str)] = P\n", "cannot use constraints with ParamSpec (line 1)"),
+            ("type A[T]\n", "invalid syntax (line 1)"),
+            ("x = 1__0\n", "invalid decimal literal (line 1)"),
+            ("x = 0_\n", "invalid decimal literal (line 1)"),
+            ("x = 1_.5\n", "invalid decimal literal (line 1)"),
+            ("x = 0x1__f\n", "invalid hexadecimal literal (line 1)"),
+            ("x = 0b1_\n", "invalid binary literal (line 1)"),
+            ("x = '\\N{no such name}'\n", "unknown Unicode character name (line 1)"),
+            ("x = '\\N{BULLET'\n", "malformed \\N character escape (line 1)"),
+            ("x = '\\N'\n", "malformed \\N character escape (line 1)"),
+            ("x = 01\n", "leading zeros in decimal integer literals are not permitted; use an 0o prefix for octal integers (line 1)"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(first_error(source), expected, "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn test_parse_reports_every_error() {
+        let source = "def f(x)\n    return x\n\nclass A:\n    def g(self):\n        x = (1 2)\n        return x\n\n    y = [1, 2\n    def h(self):\n        pass\n\nprint 'hello'\nfor i in range(10):\n    f() = i\nz = 3 +\nok = 1\n";
+        let errors = parse(source)
+            .unwrap_err()
+            .iter()
+            .map(|error| error.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                "expected ':' (line 1)",
+                "invalid syntax. Perhaps you forgot a comma? (line 6)",
+                "'[' was never closed (line 9)",
+                "Missing parentheses in call to 'print'. Did you mean print(...)? (line 13)",
+                "cannot assign to function call here. Maybe you meant '==' instead of '='? (line 15)",
+                "invalid syntax (line 16)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_error_spans() {
+        let errors = parse("x = [1, 2 3]\n").unwrap_err();
+        assert_eq!(errors[0].span, Span::new(1, 8, 11));
+        let errors = parse("y = 1\nf() = 2\n").unwrap_err();
+        assert_eq!(errors[0].span, Span::new(2, 6, 9));
+    }
+}
@@ -0,0 +1,525 @@
+//! The peephole optimizer, which rewrites a code object's instructions
+//! once they are generated, modelled on CPython's.
+//!
+//! Each pass either rewrites instructions in place or turns the ones it
+//! drops into `Nop`s, so that jump targets, line numbers and exception
+//! ranges still line up; a final pass removes the `Nop`s and renumbers
+//! them all. The passes run until none of them finds anything more to do.
+
+use crate::ast::UnaryOperator;
+use crate::code::{CodeObject, LineTable};
+use crate::intruction::Instruction;
+
+/// Optimizes `code` in place. At an `optimize` level of 1 or more, as
+/// with `python -O`, a comparison is fused with the branch on its result;
+/// CPython has no such instruction, so it is left out otherwise.
+pub fn optimize(code: &mut CodeObject, optimize: u8) {
+    let mut instructions = code.instructions();
+    loop {
+        let mut changed = combine(code, &mut instructions, optimize);
+        changed |= thread_jumps(&mut instructions);
+        changed |= remove_unreachable(code, &mut instructions);
+        changed |= remove_nops(code, &mut instructions);
+        if !changed {
+            break;
+        }
+    }
+    code.set_instructions(&instructions);
+}
+
+/// Which instructions something jumps to: a jump, or an exception handler.
+fn jump_targets(code: &CodeObject, instructions: &[Instruction]) -> Vec<bool> {
+    let mut targets = vec![false; instructions.len()];
+    let jumps = instructions
+        .iter()
+        .filter_map(|instruction| instruction.jump_target());
+    let handlers = code.exception_table.iter().map(|entry| entry.target);
+    for target in jumps.chain(handlers) {
+        targets[target as usize] = true;
+    }
+    targets
+}
+
+/// Rewrites pairs of instructions as one: `LoadConst; PopTop` does
+/// nothing, a branch on a constant always or never jumps, `not` followed
+/// by a branch is the opposite branch, and a
+/// comparison followed by a branch is a fused compare-and-branch. The
+/// second of a pair cannot be a jump target, which would need it alone.
+fn combine(code: &CodeObject, instructions: &mut [Instruction], optimize: u8) -> bool {
+    let targets = jump_targets(code, instructions);
+    let mut changed = false;
+    for i in 1..instructions.len() {
+        if targets[i] {
+            continue;
+        }
+        let pair = match (instructions[i - 1], instructions[i]) {
+            (Instruction::LoadConst(_), Instruction::PopTop) => {
+                (Instruction::Nop, Instruction::Nop)
+            }
+            (Instruction::LoadConst(index), Instruction::PopJumpIfFalse(target)) => {
+                if code.consts[index as usize].is_truthy() {
+                    (Instruction::Nop, Instruction::Nop)
+                } else {
+                    (Instruction::Nop, Instruction::JumpAbsolute(target))
+                }
+            }
+            (Instruction::LoadConst(index), Instruction::PopJumpIfTrue(target)) => {
+                if code.consts[index as usize].is_truthy() {
+                    (Instruction::Nop, Instruction::JumpAbsolute(target))
+                } else {
+                    (Instruction::Nop, Instruction::Nop)
+                }
+            }
+            (Instruction::UnaryOp(UnaryOperator::Not), Instruction::PopJumpIfFalse(target)) => {
+                (Instruction::Nop, Instruction::PopJumpIfTrue(target))
+            }
+            (Instruction::UnaryOp(UnaryOperator::Not), Instruction::PopJumpIfTrue(target)) => {
+                (Instruction::Nop, Instruction::PopJumpIfFalse(target))
+            }
+            (Instruction::CompareOp(op), Instruction::PopJumpIfFalse(target)) if optimize > 0 => (
+                Instruction::CompareJumpIfFalse(op, target),
+                Instruction::Nop,
+            ),
+            (Instruction::CompareOp(op), Instruction::PopJumpIfTrue(target)) if optimize > 0 => {
+                (Instruction::CompareJumpIfTrue(op, target), Instruction::Nop)
+            }
+            _ => continue,
+        };
+        (instructions[i - 1], instructions[i]) = pair;
+        changed = true;
+    }
+    changed
+}
+
+/// Points a jump to an unconditional jump at that jump's target instead,
+/// and drops an unconditional jump to the next instruction.
+///
+/// `ForIter` and the jumps that keep their operand on one path are left
+/// alone: CPython has no backward form of them, and the jump after the
+/// end of an inner loop goes back to the start of the outer one.
+fn thread_jumps(instructions: &mut [Instruction]) -> bool {
+    let mut changed = false;
+    for i in 0..instructions.len() {
+        if matches!(
+            instructions[i],
+            Instruction::ForIter(_)
+                | Instruction::JumpIfFalseOrPop(_)
+                | Instruction::JumpIfTrueOrPop(_)
+        ) {
+            continue;
+        }
+        let Some(mut target) = instructions[i].jump_target() else {
+            continue;
+        };
+        // A chain of jumps may be a loop, so it is followed no further
+        // than there are instructions.
+        for _ in 0..instructions.len() {
+            match instructions[target as usize] {
+                Instruction::JumpAbsolute(next) if next != target => target = next,
+                _ => break,
+            }
+        }
+        if instructions[i] == Instruction::JumpAbsolute(i as u32 + 1) {
+            instructions[i] = Instruction::Nop;
+            changed = true;
+        } else if instructions[i].jump_target() != Some(target) {
+            instructions[i] = instructions[i].with_jump_target(target);
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Turns the instructions that can never run into `Nop`s: those after a
+/// return or an unconditional jump that nothing jumps to.
+fn remove_unreachable(code: &CodeObject, instructions: &mut [Instruction]) -> bool {
+    let mut reachable = vec![false; instructions.len()];
+    let mut pending = vec![0];
+    loop {
+        while let Some(i) = pending.pop() {
+            if i >= instructions.len() || reachable[i] {
+                continue;
+            }
+            reachable[i] = true;
+            if !instructions[i].is_terminal() {
+                pending.push(i + 1);
+            }
+            if let Some(target) = instructions[i].jump_target() {
+                pending.push(target as usize);
+            }
+        }
+        // A handler can run if anything in its range can.
+        pending.extend(
+            code.exception_table
+                .iter()
+                .filter(|entry| !reachable[entry.target as usize])
+                .filter(|entry| reachable[entry.start as usize..entry.end as usize].contains(&true))
+                .map(|entry| entry.target as usize),
+        );
+        if pending.is_empty() {
+            break;
+        }
+    }
+    let mut changed = false;
+    for (instruction, reachable) in instructions.iter_mut().zip(reachable) {
+        if !reachable && *instruction != Instruction::Nop {
+            *instruction = Instruction::Nop;
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Removes the `Nop`s, renumbering jump targets and exception ranges to
+/// match. A jump to a `Nop` goes to the instruction after it, and a range
+/// left empty is dropped.
+fn remove_nops(code: &mut CodeObject, instructions: &mut Vec<Instruction>) -> bool {
+    // The new index of each instruction, and of the end of the code; a
+    // `Nop` takes that of the next instruction kept.
+    let mut renumbered = Vec::with_capacity(instructions.len() + 1);
+    let mut kept = 0;
+    for instruction in instructions.iter() {
+        renumbered.push(kept);
+        if *instruction != Instruction::Nop {
+            kept += 1;
+        }
+    }
+    renumbered.push(kept);
+    if kept == instructions.len() as u32 {
+        return false;
+    }
+    let mut lines = Vec::with_capacity(kept as usize);
+    let mut kept = Vec::with_capacity(kept as usize);
+    for (&instruction, line) in instructions.iter().zip(code.lines()) {
+        if instruction == Instruction::Nop {
+            continue;
+        }
+        kept.push(match instruction.jump_target() {
+            Some(target) => instruction.with_jump_target(renumbered[target as usize]),
+            None => instruction,
+        });
+        lines.push(line);
+    }
+    *instructions = kept;
+    code.linetable = LineTable::new(code.firstlineno, &lines);
+    for entry in &mut code.exception_table {
+        entry.start = renumbered[entry.start as usize];
+        entry.end = renumbered[entry.end as usize];
+        entry.target = renumbered[entry.target as usize];
+    }
+    code.exception_table.retain(|entry| entry.start < entry.end);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::optimize;
+    use crate::asm::{assemble, listing};
+    use crate::ast::{CmpOp, UnaryOperator};
+    use crate::cache::Source;
+    use crate::code::{Adaptive, CodeObject, ExceptionEntry, LineTable};
+    use crate::dis::dis;
+    use crate::intern::Symbol;
+    use crate::interpreter::compile_source;
+    use crate::intruction::pack;
+    use crate::intruction::Instruction::{self, *};
+    use crate::pyc::{dumps, load};
+    use crate::value::Value;
+    use crate::vm::Vm;
+
+    /// A module's code, with `print` and `range` as its names and each
+    /// instruction on a line of its own.
+    fn code(instructions: Vec<Instruction>, consts: Vec<Value>) -> CodeObject {
+        CodeObject {
+            name: "<module>".to_string(),
+            qualname: "<module>".to_string(),
+            filename: "<test>".to_string(),
+            firstlineno: 1,
+            argcount: 0,
+            posonlyargcount: 0,
+            kwonlyargcount: 0,
+            flags: 0,
+            stacksize: 0,
+            linetable: LineTable::new(1, &(1..=instructions.len()).collect::<Vec<_>>()),
+            bytecode: pack(&instructions),
+            adaptive: Adaptive::default(),
+            consts,
+            names: vec![Symbol::intern("print"), Symbol::intern("range")],
+            varnames: Vec::new(),
+            cellvars: Vec::new(),
+            freevars: Vec::new(),
+            exception_table: Vec::new(),
+        }
+    }
+
+    /// What running `code` prints, followed by the exception it raises, if
+    /// any.
+    fn run(code: &CodeObject) -> String {
+        let mut vm = Vm::new(Vec::new());
+        let result = vm.run(Rc::new(code.clone()));
+        let mut output = String::from_utf8(vm.out().clone()).unwrap();
+        if let Err(exception) = result {
+            output += &exception.to_string();
+        }
+        output
+    }
+
+    /// Optimizes `code`, checking that it still runs the same way and that
+    /// its jumps, lines and exception ranges still fit its instructions.
+    fn optimized(code: &CodeObject, level: u8) -> CodeObject {
+        let mut optimized = code.clone();
+        optimize(&mut optimized, level);
+        let instructions = optimized.instructions();
+        let len = instructions.len() as u32;
+        assert_eq!(optimized.lines().len(), instructions.len());
+        for instruction in &instructions {
+            assert!(instruction.jump_target().is_none_or(|target| target < len));
+        }
+        for entry in &optimized.exception_table {
+            assert!(entry.start < entry.end && entry.end <= len && entry.target < len);
+        }
+        assert_eq!(run(&optimized), run(code));
+        optimized
+    }
+
+    fn print(value: u32) -> [Instruction; 4] {
+        [LoadName(0), LoadConst(value), CallFunction(1), PopTop]
+    }
+
+    #[test]
+    fn test_peephole_load_const_pop_top() {
+        let mut instructions = vec![LoadConst(0), PopTop];
+        instructions.extend(print(1));
+        instructions.extend([LoadConst(2), ReturnValue]);
+        let before = code(
+            instructions,
+            vec![Value::str("doc"), Value::Int(1), Value::None],
+        );
+        let after = optimized(&before, 0);
+        assert_eq!(after.instructions(), before.instructions()[2..]);
+        assert_eq!(after.lines(), [3, 4, 5, 6, 7, 8]);
+
+        // A `PopTop` that is also jumped to stays, with its `LoadConst`.
+        let instructions = vec![
+            LoadName(1),
+            LoadConst(0),
+            CallFunction(1),
+            GetIter,
+            ForIter(11),
+            DupTop,
+            PopJumpIfTrue(9),
+            PopTop,
+            LoadConst(1),
+            PopTop,
+            JumpAbsolute(4),
+            LoadConst(2),
+            ReturnValue,
+        ];
+        let before = code(
+            instructions,
+            vec![Value::Int(2), Value::str("x"), Value::None],
+        );
+        assert_eq!(optimized(&before, 0), before);
+    }
+
+    #[test]
+    fn test_peephole_not_jump() {
+        // The test is `range(n)`, which is true if n is not 0.
+        for value in [Value::Int(0), Value::Int(1)] {
+            let mut instructions = vec![
+                LoadName(1),
+                LoadConst(0),
+                CallFunction(1),
+                UnaryOp(UnaryOperator::Not),
+                PopJumpIfFalse(9),
+            ];
+            instructions.extend(print(1));
+            instructions.extend([LoadConst(2), ReturnValue]);
+            let before = code(instructions, vec![value, Value::str("no"), Value::None]);
+            let after = optimized(&before, 0);
+            assert_eq!(
+                after.instructions()[..4],
+                [LoadName(1), LoadConst(0), CallFunction(1), PopJumpIfTrue(8)]
+            );
+            assert_eq!(after.instructions()[4..], before.instructions()[5..]);
+            assert_eq!(after.lines(), [1, 2, 3, 5, 6, 7, 8, 9, 10, 11]);
+        }
+    }
+
+    #[test]
+    fn test_peephole_constant_branch() {
+        // A branch on a constant goes one way, and the other is dropped.
+        for (value, taken) in [(Value::Int(0), "no"), (Value::str("x"), "yes")] {
+            // The branch skips the first `print` for a false value, or, with
+            // the jump and the branches swapped, for a true one.
+            for (jump, first, second) in [(PopJumpIfFalse(7), 1, 2), (PopJumpIfTrue(7), 2, 1)] {
+                let mut instructions = vec![LoadConst(0), jump];
+                instructions.extend(print(first));
+                instructions.push(JumpAbsolute(11));
+                instructions.extend(print(second));
+                instructions.extend([LoadConst(3), ReturnValue]);
+                let consts = vec![
+                    value.clone(),
+                    Value::str("yes"),
+                    Value::str("no"),
+                    Value::None,
+                ];
+                let before = code(instructions, consts);
+                let after = optimized(&before, 0);
+                assert_eq!(run(&after), format!("{}\n", taken));
+                let mut expected = print(if taken == "yes" { 1 } else { 2 }).to_vec();
+                expected.extend([LoadConst(3), ReturnValue]);
+                assert_eq!(after.instructions(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_peephole_compare_and_branch() {
+        for right in [Value::Int(2), Value::Int(0), Value::str("a")] {
+            let mut instructions = vec![
+                LoadConst(0),
+                LoadConst(1),
+                CompareOp(CmpOp::Lt),
+                PopJumpIfFalse(8),
+            ];
+            instructions.extend(print(2));
+            instructions.extend([LoadConst(3), ReturnValue]);
+            let consts = vec![Value::Int(1), right, Value::str("less"), Value::None];
+            let before = code(instructions, consts);
+            // Without optimizing, the comparison and the branch stay apart.
+            assert_eq!(optimized(&before, 0), before);
+            let after = optimized(&before, 1);
+            assert_eq!(after.instructions()[2], CompareJumpIfFalse(CmpOp::Lt, 7));
+            assert_eq!(after.instructions()[3..], before.instructions()[4..]);
+            assert_eq!(after.lines(), [1, 2, 3, 5, 6, 7, 8, 9, 10]);
+        }
+    }
+
+    #[test]
+    fn test_peephole_thread_jumps() {
+        for flag in [true, false] {
+            let mut instructions = vec![
+                LoadName(1),
+                LoadConst(0),
+                CallFunction(1),
+                PopJumpIfFalse(9),
+            ];
+            instructions.extend(print(1));
+            instructions.extend([
+                JumpAbsolute(9),
+                JumpAbsolute(12),
+                LoadConst(2),
+                ReturnValue,
+                LoadConst(3),
+                ReturnValue,
+            ]);
+            let consts = vec![
+                Value::Bool(flag),
+                Value::str("yes"),
+                Value::str("no"),
+                Value::None,
+            ];
+            let before = code(instructions, consts);
+            let after = optimized(&before, 0);
+            let mut expected = vec![
+                LoadName(1),
+                LoadConst(0),
+                CallFunction(1),
+                PopJumpIfFalse(8),
+            ];
+            expected.extend(print(1));
+            expected.extend([LoadConst(3), ReturnValue]);
+            assert_eq!(after.instructions(), expected);
+            assert_eq!(after.lines(), [1, 2, 3, 4, 5, 6, 7, 8, 13, 14]);
+        }
+
+        // A loop of jumps stays a loop.
+        let mut looping = code(vec![JumpAbsolute(1), JumpAbsolute(0)], Vec::new());
+        optimize(&mut looping, 0);
+        assert_eq!(looping.instructions(), [JumpAbsolute(0)]);
+        assert_eq!(looping.lines(), [2]);
+    }
+
+    #[test]
+    fn test_peephole_nested_loops() {
+        // The inner loop ends at the outer loop's jump back, which
+        // `ForIter` must not be threaded through.
+        let sources = [
+            "for x in [[1, 2], [3]]:\n    for y in x: print(y)\n",
+            "a = [[1, 2], [3]]\nprint([y for x in a for y in x])\n",
+        ];
+        for source in sources {
+            let code = compile_source(source, "loops.py", 0).unwrap();
+            let mut codes = vec![&code];
+            codes.extend(code.consts.iter().filter_map(|constant| match constant {
+                Value::Code(nested) => Some(&**nested),
+                _ => None,
+            }));
+            for code in codes {
+                for (index, instruction) in code.instructions().into_iter().enumerate() {
+                    if let ForIter(target) = instruction {
+                        assert!(
+                            target as usize > index,
+                            "{}: {:?}",
+                            source,
+                            code.instructions()
+                        );
+                    }
+                }
+            }
+            assert!(dis(&code).contains("FOR_ITER"));
+            assert_eq!(assemble(&listing(&code)), Ok(code.clone()));
+            let bytes = dumps(
+                &code,
+                Source {
+                    text: b"",
+                    mtime: 0,
+                },
+            )
+            .unwrap();
+            assert_eq!(load(&bytes), Ok(code));
+        }
+    }
+
+    #[test]
+    fn test_peephole_unreachable() {
+        let mut instructions = vec![LoadConst(0), ReturnValue];
+        instructions.extend(print(0));
+        instructions.extend([LoadConst(0), ReturnValue]);
+        let before = code(instructions, vec![Value::None]);
+        let after = optimized(&before, 0);
+        assert_eq!(after.instructions(), [LoadConst(0), ReturnValue]);
+        assert_eq!(after.lines(), [1, 2]);
+    }
+
+    #[test]
+    fn test_peephole_exception_table() {
+        let mut instructions = vec![LoadConst(0), PopTop];
+        instructions.extend(print(1));
+        instructions.extend([LoadConst(0), ReturnValue, PopTop, LoadConst(0), ReturnValue]);
+        let mut before = code(instructions, vec![Value::None, Value::str("try")]);
+        let entry = |start, end, target| ExceptionEntry {
+            start,
+            end,
+            target,
+            depth: 0,
+            lasti: false,
+        };
+        // The first range covers only what is removed; the handler at 8
+        // can run, as the second range can raise, and is kept.
+        before.exception_table = vec![entry(0, 2, 8), entry(2, 6, 8)];
+        let after = optimized(&before, 0);
+        assert_eq!(after.instructions(), before.instructions()[2..]);
+        assert_eq!(after.exception_table, [entry(0, 4, 6)]);
+        assert_eq!(after.lines(), [3, 4, 5, 6, 7, 8, 9, 10, 11]);
+
+        // A handler for a range that cannot run cannot run either.
+        before.exception_table = vec![entry(8, 9, 9)];
+        let after = optimized(&before, 0);
+        assert_eq!(after.instructions(), before.instructions()[2..8]);
+        assert!(after.exception_table.is_empty());
+    }
+}
@@ -0,0 +1,1910 @@
+//! CPython 3.11 `.pyc` files, and the translation of the bytecode in them
+//! into ours, so that the VM can run what CPython compiled, and back, so
+//! that CPython's tools can read what we compile.
+//!
+//! A `.pyc` file is a 16-byte header, which starts with CPython's magic
+//! number, and then the module's code object in `marshal` format. Most
+//! CPython 3.11 instructions have one of ours to stand for them; the rest
+//! translate as follows:
+//!
+//! - `RESUME`, `NOP`, `PRECALL`, `MAKE_CELL`, `COPY_FREE_VARS` and the
+//!   inline `CACHE` entries are dropped, as the VM does their work itself.
+//! - CPython pushes a NULL under a callable that is not a method, with
+//!   `PUSH_NULL` or `LOAD_GLOBAL`, and `CALL` takes one value more when it
+//!   finds no NULL there. The translation drops the NULLs, following where
+//!   they would be on the stack to count each call's arguments.
+//! - `CALL_FUNCTION_EX` always has a NULL under its callable, which is
+//!   dropped the same way.
+//! - `KW_NAMES` and `CALL` make a `CALL_FUNCTION_KW`, and `MAKE_FUNCTION`,
+//!   which takes the qualified name from the code object in 3.11, gets it
+//!   pushed as a constant first.
+//! - `BINARY_OP` becomes the binary or in-place instruction for its
+//!   operator, and relative jumps become absolute ones.
+//! - Local variables, cells and free variables, which 3.11 numbers in one
+//!   list, are split into the three tables of ours.
+//! - The exception table keeps its entries, their ranges moved to our
+//!   instructions.
+//!
+//! Code using an instruction with nothing to stand for it, such as the
+//! `IMPORT_NAME` of an `import`, is rejected, and so is writing code with
+//! type parameters, which 3.11 lacks.
+//!
+//! Writing undoes each of these: a NULL is pushed before the load of a
+//! callable where CPython would push one, and where the callable is not
+//! simply loaded, it is called as a method is, with its first argument as
+//! the object. A callable called with `*` or `**` arguments must be
+//! loaded just before them, as CPython pushes a NULL under it whatever it
+//! is; an attribute gets the NULL swapped under it. Fused comparisons come apart again, and `RotThree` becomes
+//! two `SWAP`s, which reading puts back together. The location table has
+//! lines but no columns.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::rc::Rc;
+
+use crate::ast::{CmpOp, Operator, UnaryOperator};
+use crate::cache::Source;
+use crate::code::{Adaptive, CodeObject, ExceptionEntry, LineTable};
+use crate::intern::Symbol;
+use crate::intruction::{pack, Instruction, CMP_OPS};
+use crate::marshal::{self, MarshalError, Object, CO_FAST_CELL, CO_FAST_FREE, CO_FAST_LOCAL};
+use crate::value::Value;
+use crate::verify::verify;
+
+/// The magic number of CPython 3.11's `.pyc` files.
+pub const MAGIC_NUMBER: u16 = 3495;
+/// The magic number as it starts a `.pyc` file.
+pub const MAGIC: [u8; 4] = [
+    MAGIC_NUMBER.to_le_bytes()[0],
+    MAGIC_NUMBER.to_le_bytes()[1],
+    b'\r',
+    b'\n',
+];
+
+/// Each CPython 3.11 instruction that translates, with its number, the
+/// count of inline cache entries after it, and its name.
+const OPCODES: [(u8, usize, &str); 78] = [
+    (0, 0, "CACHE"),
+    (1, 0, "POP_TOP"),
+    (2, 0, "PUSH_NULL"),
+    (9, 0, "NOP"),
+    (10, 0, "UNARY_POSITIVE"),
+    (11, 0, "UNARY_NEGATIVE"),
+    (12, 0, "UNARY_NOT"),
+    (15, 0, "UNARY_INVERT"),
+    (25, 4, "BINARY_SUBSCR"),
+    (35, 0, "PUSH_EXC_INFO"),
+    (36, 0, "CHECK_EXC_MATCH"),
+    (49, 0, "WITH_EXCEPT_START"),
+    (53, 0, "BEFORE_WITH"),
+    (61, 0, "DELETE_SUBSCR"),
+    (68, 0, "GET_ITER"),
+    (71, 0, "LOAD_BUILD_CLASS"),
+    (74, 0, "LOAD_ASSERTION_ERROR"),
+    (82, 0, "LIST_TO_TUPLE"),
+    (83, 0, "RETURN_VALUE"),
+    (89, 0, "POP_EXCEPT"),
+    (90, 0, "STORE_NAME"),
+    (91, 0, "DELETE_NAME"),
+    (92, 1, "UNPACK_SEQUENCE"),
+    (93, 0, "FOR_ITER"),
+    (95, 4, "STORE_ATTR"),
+    (96, 0, "DELETE_ATTR"),
+    (97, 0, "STORE_GLOBAL"),
+    (98, 0, "DELETE_GLOBAL"),
+    (99, 0, "SWAP"),
+    (100, 0, "LOAD_CONST"),
+    (101, 0, "LOAD_NAME"),
+    (102, 0, "BUILD_TUPLE"),
+    (103, 0, "BUILD_LIST"),
+    (105, 0, "BUILD_MAP"),
+    (106, 4, "LOAD_ATTR"),
+    (107, 2, "COMPARE_OP"),
+    (110, 0, "JUMP_FORWARD"),
+    (111, 0, "JUMP_IF_FALSE_OR_POP"),
+    (112, 0, "JUMP_IF_TRUE_OR_POP"),
+    (114, 0, "POP_JUMP_FORWARD_IF_FALSE"),
+    (115, 0, "POP_JUMP_FORWARD_IF_TRUE"),
+    (116, 5, "LOAD_GLOBAL"),
+    (117, 0, "IS_OP"),
+    (118, 0, "CONTAINS_OP"),
+    (119, 0, "RERAISE"),
+    (120, 0, "COPY"),
+    (122, 1, "BINARY_OP"),
+    (124, 0, "LOAD_FAST"),
+    (125, 0, "STORE_FAST"),
+    (126, 0, "DELETE_FAST"),
+    (128, 0, "POP_JUMP_FORWARD_IF_NOT_NONE"),
+    (129, 0, "POP_JUMP_FORWARD_IF_NONE"),
+    (130, 0, "RAISE_VARARGS"),
+    (132, 0, "MAKE_FUNCTION"),
+    (134, 0, "JUMP_BACKWARD_NO_INTERRUPT"),
+    (135, 0, "MAKE_CELL"),
+    (136, 0, "LOAD_CLOSURE"),
+    (137, 0, "LOAD_DEREF"),
+    (138, 0, "STORE_DEREF"),
+    (139, 0, "DELETE_DEREF"),
+    (140, 0, "JUMP_BACKWARD"),
+    (142, 0, "CALL_FUNCTION_EX"),
+    (144, 0, "EXTENDED_ARG"),
+    (145, 0, "LIST_APPEND"),
+    (147, 0, "MAP_ADD"),
+    (149, 0, "COPY_FREE_VARS"),
+    (151, 0, "RESUME"),
+    (156, 0, "BUILD_CONST_KEY_MAP"),
+    (160, 10, "LOAD_METHOD"),
+    (162, 0, "LIST_EXTEND"),
+    (164, 0, "DICT_MERGE"),
+    (166, 1, "PRECALL"),
+    (171, 4, "CALL"),
+    (172, 0, "KW_NAMES"),
+    (173, 0, "POP_JUMP_BACKWARD_IF_NOT_NONE"),
+    (174, 0, "POP_JUMP_BACKWARD_IF_NONE"),
+    (175, 0, "POP_JUMP_BACKWARD_IF_FALSE"),
+    (176, 0, "POP_JUMP_BACKWARD_IF_TRUE"),
+];
+
+/// The operators of `BINARY_OP`, in the order of its argument; the
+/// in-place ones follow, in the same order.
+const BINARY_OPS: [Operator; 13] = [
+    Operator::Add,
+    Operator::BitAnd,
+    Operator::FloorDiv,
+    Operator::LShift,
+    Operator::MatMult,
+    Operator::Mult,
+    Operator::Mod,
+    Operator::BitOr,
+    Operator::Pow,
+    Operator::RShift,
+    Operator::Sub,
+    Operator::Div,
+    Operator::BitXor,
+];
+
+/// Why a `.pyc` file cannot be run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PycError {
+    /// It is not from CPython 3.11.
+    BadMagic,
+    /// Its header or contents are not those of a `.pyc` file.
+    Corrupt(String),
+    Marshal(MarshalError),
+    /// A code object in it, or one being written, does not translate, at
+    /// the given offset of its bytecode.
+    Translate {
+        qualname: String,
+        offset: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for PycError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PycError::BadMagic => write!(f, "bad magic number: not a CPython 3.11 .pyc file"),
+            PycError::Corrupt(message) => write!(f, "corrupt .pyc file: {}", message),
+            PycError::Marshal(error) => write!(f, "bad marshal data: {}", error),
+            PycError::Translate {
+                qualname,
+                offset,
+                message,
+            } => write!(f, "{}: offset {}: {}", qualname, offset, message),
+        }
+    }
+}
+
+pub type PycResult<T> = Result<T, PycError>;
+
+/// The module code in the `.pyc` file `bytes`, translated. The header's
+/// stamp is not checked against any source: as when CPython is given a
+/// `.pyc` file to run, it is run as it is.
+pub fn load(bytes: &[u8]) -> PycResult<CodeObject> {
+    if bytes.len() < 16 {
+        return Err(PycError::Corrupt("the header is cut short".to_string()));
+    }
+    if bytes[..4] != MAGIC {
+        return Err(PycError::BadMagic);
+    }
+    match marshal::loads(&bytes[16..]).map_err(PycError::Marshal)? {
+        Object::Code(code) => translate(&code),
+        other => Err(PycError::Corrupt(format!(
+            "it holds a {}, not code",
+            other.type_name()
+        ))),
+    }
+}
+
+/// Translates a CPython 3.11 code object, and those among its constants,
+/// into ours.
+pub fn translate(code: &marshal::Code) -> PycResult<CodeObject> {
+    Translator::new(code)?.run()
+}
+
+/// A CPython 3.11 instruction, with its argument extended by any
+/// `EXTENDED_ARG` before it.
+#[derive(Debug, Clone, Copy)]
+struct Raw {
+    /// Where it starts, in two-byte code units, counting from its first
+    /// `EXTENDED_ARG`, which is where jumps to it go.
+    start: usize,
+    /// Where the opcode itself is.
+    at: usize,
+    opcode: u8,
+    arg: u32,
+    /// Where the next instruction starts, after the inline caches.
+    next: usize,
+}
+
+/// The stack as the translation follows it: its depth in our code, and
+/// where CPython's code would have NULLs, each as the depth of the value
+/// just above it.
+#[derive(Debug, Clone, Default)]
+struct Stack {
+    depth: u32,
+    nulls: Vec<u32>,
+}
+
+struct Translator<'a> {
+    code: &'a marshal::Code,
+    consts: Vec<Value>,
+    names: Vec<Symbol>,
+    varnames: Vec<Symbol>,
+    cellvars: Vec<Symbol>,
+    freevars: Vec<Symbol>,
+    /// For each slot of `localsplusnames`, its index in `varnames`, and
+    /// its index among the cells and then the free variables.
+    locals: Vec<(Option<u32>, Option<u32>)>,
+    /// The source line of each code unit, if it has one.
+    lines: Vec<Option<usize>>,
+    instructions: Vec<Instruction>,
+    instruction_lines: Vec<usize>,
+    /// The code unit each jump emitted so far goes to.
+    jumps: Vec<(usize, usize)>,
+    /// The stack where a jump reaches each code unit.
+    jumped: HashMap<usize, Stack>,
+    /// The code units that jumps go to.
+    targets: HashSet<usize>,
+    stack: Stack,
+}
+
+impl<'a> Translator<'a> {
+    fn new(code: &'a marshal::Code) -> PycResult<Translator<'a>> {
+        let error = |message: String| PycError::Translate {
+            qualname: code.qualname.to_string(),
+            offset: 0,
+            message,
+        };
+        let consts = code
+            .consts
+            .iter()
+            .map(constant)
+            .collect::<PycResult<Vec<Value>>>()?;
+        let mut translator = Translator {
+            code,
+            consts,
+            names: code.names.iter().map(|name| Symbol::intern(name)).collect(),
+            varnames: Vec::new(),
+            cellvars: Vec::new(),
+            freevars: Vec::new(),
+            locals: Vec::new(),
+            lines: decode_lines(code).map_err(error)?,
+            instructions: Vec::new(),
+            instruction_lines: Vec::new(),
+            jumps: Vec::new(),
+            jumped: HashMap::new(),
+            targets: HashSet::new(),
+            stack: Stack::default(),
+        };
+        let kinds = code.localsplusnames.iter().zip(&code.localspluskinds);
+        for (name, &kind) in kinds.clone() {
+            let name = Symbol::intern(name);
+            let mut slot = (None, None);
+            if kind & CO_FAST_LOCAL != 0 {
+                slot.0 = Some(translator.varnames.len() as u32);
+                translator.varnames.push(name);
+            }
+            if kind & CO_FAST_CELL != 0 {
+                translator.cellvars.push(name);
+            }
+            if kind & CO_FAST_FREE != 0 {
+                translator.freevars.push(name);
+            }
+            translator.locals.push(slot);
+        }
+        // The cells and free variables are each in the order of their
+        // names, as in `co_cellvars` and `co_freevars`, where a parameter
+        // that is a cell is not first.
+        translator.cellvars.sort_by_key(|name| name.as_str());
+        translator.freevars.sort_by_key(|name| name.as_str());
+        for ((name, &kind), slot) in kinds.zip(&mut translator.locals) {
+            let name = Symbol::intern(name);
+            let cell = translator.cellvars.iter().position(|&cell| cell == name);
+            let free = translator.freevars.iter().position(|&free| free == name);
+            if kind & CO_FAST_CELL != 0 {
+                slot.1 = cell.map(|cell| cell as u32);
+            } else if kind & CO_FAST_FREE != 0 {
+                slot.1 = free.map(|free| (translator.cellvars.len() + free) as u32);
+            }
+        }
+        Ok(translator)
+    }
+
+    fn run(mut self) -> PycResult<CodeObject> {
+        let raws = decode_instructions(self.code)
+            .map_err(|(offset, message)| self.error(offset, message))?;
+        let entries = decode_exception_table(&self.code.exceptiontable)
+            .map_err(|message| self.error(0, message))?;
+        // A handler starts with the stack cut down to the entry's depth,
+        // under the exception, as if a jump had gone there.
+        for entry in &entries {
+            let stack = Stack {
+                depth: entry.depth + entry.lasti as u32 + 1,
+                nulls: Vec::new(),
+            };
+            self.jumped.entry(entry.target as usize).or_insert(stack);
+        }
+        self.targets = raws
+            .iter()
+            .filter_map(|raw| jump_target(raw)?.ok())
+            .collect();
+        // The index of our first instruction for each code unit from
+        // where it starts on.
+        let mut indexes = vec![0; self.code.code.len() / 2 + 1];
+        let mut previous: Option<Raw> = None;
+        let mut kw_names = None;
+        for raw in &raws {
+            let reachable = previous.is_none_or(|previous| !is_terminal(previous.opcode));
+            if !reachable {
+                self.stack = self.jumped.get(&raw.start).cloned().unwrap_or_default();
+            }
+            for index in &mut indexes[previous.map_or(0, |previous| previous.next)..=raw.start] {
+                *index = self.instructions.len() as u32;
+            }
+            self.instruction(raw, previous, &mut kw_names)
+                .map_err(|message| self.error(raw.at, message))?;
+            previous = Some(*raw);
+        }
+        for index in &mut indexes[previous.map_or(0, |previous| previous.next)..] {
+            *index = self.instructions.len() as u32;
+        }
+        for &(index, target) in &self.jumps {
+            let instruction = self.instructions[index];
+            let target = match indexes.get(target) {
+                Some(&target) if (target as usize) < self.instructions.len() => target,
+                _ => {
+                    return Err(self.error(
+                        2 * target,
+                        format!("a jump to offset {}, past the end of the code", 2 * target),
+                    ))
+                }
+            };
+            self.instructions[index] = instruction.with_jump_target(target);
+        }
+        let exception_table = entries
+            .into_iter()
+            .map(|entry| {
+                let index = |unit: u32| indexes.get(unit as usize).copied().unwrap_or(u32::MAX);
+                ExceptionEntry {
+                    start: index(entry.start),
+                    end: index(entry.end),
+                    target: index(entry.target),
+                    ..entry
+                }
+            })
+            .filter(|entry| entry.start < entry.end)
+            .collect();
+        let code = self.code;
+        let mut translated = CodeObject {
+            name: code.name.to_string(),
+            qualname: code.qualname.to_string(),
+            filename: code.filename.to_string(),
+            firstlineno: code.firstlineno as usize,
+            argcount: code.argcount as usize,
+            posonlyargcount: code.posonlyargcount as usize,
+            kwonlyargcount: code.kwonlyargcount as usize,
+            flags: code.flags,
+            stacksize: 0,
+            linetable: LineTable::new(code.firstlineno as usize, &self.instruction_lines),
+            bytecode: pack(&self.instructions),
+            adaptive: Adaptive::default(),
+            consts: self.consts,
+            names: self.names,
+            varnames: self.varnames,
+            cellvars: self.cellvars,
+            freevars: self.freevars,
+            exception_table,
+        };
+        translated.stacksize = verify(&translated).map_err(|error| PycError::Translate {
+            qualname: code.qualname.to_string(),
+            offset: 0,
+            message: format!("the translation does not verify: {}", error.message),
+        })?;
+        Ok(translated)
+    }
+
+    fn error(&self, unit: usize, message: impl Into<String>) -> PycError {
+        PycError::Translate {
+            qualname: self.code.qualname.to_string(),
+            offset: 2 * unit,
+            message: message.into(),
+        }
+    }
+
+    /// Translates one instruction, `previous` being the one before it.
+    fn instruction(
+        &mut self,
+        raw: &Raw,
+        previous: Option<Raw>,
+        kw_names: &mut Option<u32>,
+    ) -> Result<(), String> {
+        let arg = raw.arg;
+        let (_, _, opname) = opcode_info(raw.opcode).unwrap_or((0, 0, ""));
+        let line = self.lines[raw.at]
+            .or(self.instruction_lines.last().copied())
+            .unwrap_or(self.code.firstlineno as usize);
+        let local = |translator: &Self| {
+            translator.locals.get(arg as usize).copied().ok_or_else(|| {
+                format!(
+                    "{} {} is out of range: there are {} local names",
+                    opname,
+                    arg,
+                    translator.locals.len()
+                )
+            })
+        };
+        if let Some(target) = jump_target(raw) {
+            self.jump(opname, target?, line);
+            return Ok(());
+        }
+        match opname {
+            "CACHE" | "NOP" | "RESUME" | "PRECALL" | "MAKE_CELL" | "COPY_FREE_VARS" => {}
+            "PUSH_NULL" => self.stack.nulls.push(self.stack.depth),
+            "POP_TOP" => self.emit(Instruction::PopTop, line),
+            "UNARY_POSITIVE" => self.emit(Instruction::UnaryOp(UnaryOperator::UAdd), line),
+            "UNARY_NEGATIVE" => self.emit(Instruction::UnaryOp(UnaryOperator::USub), line),
+            "UNARY_NOT" => self.emit(Instruction::UnaryOp(UnaryOperator::Not), line),
+            "UNARY_INVERT" => self.emit(Instruction::UnaryOp(UnaryOperator::Invert), line),
+            "BINARY_SUBSCR" => self.emit(Instruction::BinarySubscr, line),
+            "DELETE_SUBSCR" => self.emit(Instruction::DeleteSubscr, line),
+            "GET_ITER" => self.emit(Instruction::GetIter, line),
+            "RETURN_VALUE" => self.emit(Instruction::ReturnValue, line),
+            "STORE_NAME" => self.emit(Instruction::StoreName(arg), line),
+            "DELETE_NAME" => self.emit(Instruction::DeleteName(arg), line),
+            "UNPACK_SEQUENCE" => self.emit(Instruction::UnpackSequence(arg), line),
+            "STORE_GLOBAL" => self.emit(Instruction::StoreGlobal(arg), line),
+            "DELETE_GLOBAL" => self.emit(Instruction::DeleteGlobal(arg), line),
+            "SWAP" if arg == 2 => {
+                let depth = self.stack.depth;
+                let swapped_rotation = previous.is_some_and(|previous| {
+                    (previous.opcode, previous.arg) == (99, 3) && !self.targets.contains(&raw.start)
+                });
+                if let Some(null) = self.stack.nulls.iter_mut().find(|null| **null == depth) {
+                    // A NULL pushed to go under the callable already there.
+                    *null -= 1;
+                } else if swapped_rotation {
+                    // SWAP 3, SWAP 2 is a RotThree: the RotTwo of the
+                    // first is undone.
+                    self.instructions.pop();
+                    self.instruction_lines.pop();
+                } else {
+                    self.emit(Instruction::RotTwo, line);
+                }
+            }
+            "SWAP" if arg == 3 => {
+                self.emit(Instruction::RotThree, line);
+                self.emit(Instruction::RotTwo, line);
+            }
+            "COPY" if arg == 1 => self.emit(Instruction::DupTop, line),
+            "COPY" => self.emit(Instruction::Copy(arg), line),
+            "STORE_ATTR" => self.emit(Instruction::StoreAttr(arg), line),
+            "DELETE_ATTR" => self.emit(Instruction::DeleteAttr(arg), line),
+            "RAISE_VARARGS" => self.emit(Instruction::RaiseVarargs(arg), line),
+            "PUSH_EXC_INFO" => self.emit(Instruction::PushExcInfo, line),
+            "POP_EXCEPT" => self.emit(Instruction::PopExcept, line),
+            "RERAISE" => self.emit(Instruction::Reraise(arg), line),
+            "CHECK_EXC_MATCH" => self.emit(Instruction::CheckExcMatch, line),
+            "BEFORE_WITH" => self.emit(Instruction::BeforeWith, line),
+            "WITH_EXCEPT_START" => self.emit(Instruction::WithExceptStart, line),
+            "LOAD_BUILD_CLASS" => self.emit(Instruction::LoadBuildClass, line),
+            "LOAD_ASSERTION_ERROR" => self.emit(Instruction::LoadAssertionError, line),
+            "LOAD_CONST" => self.emit(Instruction::LoadConst(arg), line),
+            "LOAD_NAME" => self.emit(Instruction::LoadName(arg), line),
+            "BUILD_TUPLE" => self.emit(Instruction::BuildTuple(arg), line),
+            "BUILD_LIST" => self.emit(Instruction::BuildList(arg), line),
+            "BUILD_SET" => self.emit(Instruction::BuildSet(arg), line),
+            "BUILD_MAP" => self.emit(Instruction::BuildMap(arg), line),
+            "BUILD_CONST_KEY_MAP" => self.emit(Instruction::BuildConstKeyMap(arg), line),
+            "LOAD_ATTR" => self.emit(Instruction::LoadAttr(arg), line),
+            "LOAD_METHOD" => {
+                // A bound method stands for the method and its object,
+                // and the call then has no NULL under it.
+                self.emit(Instruction::LoadAttr(arg), line);
+                self.stack.nulls.push(self.stack.depth.saturating_sub(1));
+            }
+            "COMPARE_OP" => {
+                let op = *CMP_OPS
+                    .get(arg as usize)
+                    .ok_or_else(|| format!("COMPARE_OP has no operator {}", arg))?;
+                self.emit(Instruction::CompareOp(op), line);
+            }
+            "IS_OP" => self.emit(Instruction::IsOp(arg != 0), line),
+            "CONTAINS_OP" => self.emit(Instruction::ContainsOp(arg != 0), line),
+            "BINARY_OP" => {
+                let count = BINARY_OPS.len() as u32;
+                if arg >= 2 * count {
+                    return Err(format!("BINARY_OP has no operator {}", arg));
+                }
+                let op = BINARY_OPS[(arg % count) as usize];
+                if arg < count {
+                    self.emit(Instruction::BinaryOp(op), line);
+                } else {
+                    self.emit(Instruction::InplaceOp(op), line);
+                }
+            }
+            "LOAD_GLOBAL" => {
+                if arg & 1 != 0 {
+                    self.stack.nulls.push(self.stack.depth);
+                }
+                self.emit(Instruction::LoadGlobal(arg >> 1), line);
+            }
+            "LOAD_FAST" | "STORE_FAST" | "DELETE_FAST" => {
+                let index = local(self)?
+                    .0
+                    .ok_or_else(|| format!("{} {} is not of a local variable", opname, arg))?;
+                let instruction = match opname {
+                    "LOAD_FAST" => Instruction::LoadFast(index),
+                    "STORE_FAST" => Instruction::StoreFast(index),
+                    _ => Instruction::DeleteFast(index),
+                };
+                self.emit(instruction, line);
+            }
+            "LOAD_CLOSURE" | "LOAD_DEREF" | "STORE_DEREF" | "DELETE_DEREF" => {
+                let index = local(self)?
+                    .1
+                    .ok_or_else(|| format!("{} {} is not of a cell", opname, arg))?;
+                let instruction = match opname {
+                    "LOAD_CLOSURE" => Instruction::LoadClosure(index),
+                    "LOAD_DEREF" => Instruction::LoadDeref(index),
+                    "STORE_DEREF" => Instruction::StoreDeref(index),
+                    _ => Instruction::DeleteDeref(index),
+                };
+                self.emit(instruction, line);
+            }
+            "LIST_APPEND" => self.emit(Instruction::ListAppend(arg), line),
+            "SET_ADD" => self.emit(Instruction::SetAdd(arg), line),
+            "LIST_EXTEND" => self.emit(Instruction::ListExtend(arg), line),
+            "LIST_TO_TUPLE" => self.emit(Instruction::ListToTuple, line),
+            "DICT_MERGE" => self.emit(Instruction::DictMerge(arg), line),
+            "MAP_ADD" => self.emit(Instruction::MapAdd(arg), line),
+            "MAKE_FUNCTION" => {
+                // The code object is always the constant loaded just before.
+                let code = previous
+                    .filter(|previous| {
+                        opcode_info(previous.opcode).map(|(_, _, name)| name) == Some("LOAD_CONST")
+                    })
+                    .and_then(|previous| self.consts.get(previous.arg as usize));
+                let Some(Value::Code(code)) = code else {
+                    return Err("MAKE_FUNCTION does not follow a code object".to_string());
+                };
+                let qualname = code.qualname.clone();
+                let index = self.constant(Value::str(&qualname));
+                self.emit(Instruction::LoadConst(index), line);
+                self.emit(Instruction::MakeFunction(arg), line);
+            }
+            "CALL" => {
+                let callable = self.stack.depth.checked_sub(arg + 1);
+                let null = callable.and_then(|callable| {
+                    self.stack.nulls.iter().rposition(|&null| null == callable)
+                });
+                // Without a NULL, what CPython would take as the callable
+                // is called with the rest as its arguments.
+                let count = match null {
+                    Some(null) => {
+                        self.stack.nulls.remove(null);
+                        arg
+                    }
+                    None => arg + 1,
+                };
+                match kw_names.take() {
+                    Some(names) => {
+                        self.emit(Instruction::LoadConst(names), line);
+                        self.emit(Instruction::CallFunctionKw(count), line);
+                    }
+                    None => self.emit(Instruction::CallFunction(count), line),
+                }
+            }
+            "KW_NAMES" => *kw_names = Some(arg),
+            "CALL_FUNCTION_EX" => {
+                let callable = self.stack.depth.checked_sub(2 + (arg & 1));
+                if let Some(null) = callable.and_then(|callable| {
+                    self.stack.nulls.iter().rposition(|&null| null == callable)
+                }) {
+                    self.stack.nulls.remove(null);
+                }
+                self.emit(Instruction::CallFunctionEx(arg), line);
+            }
+            "" => return Err(format!("unsupported instruction {}", raw.opcode)),
+            _ => return Err(format!("unsupported instruction {} {}", opname, arg)),
+        }
+        Ok(())
+    }
+
+    /// Emits the jump for a CPython 3.11 one, to the code unit `target`.
+    fn jump(&mut self, opname: &str, target: usize, line: usize) {
+        let condition = opname
+            .trim_start_matches("POP_JUMP_FORWARD_")
+            .trim_start_matches("POP_JUMP_BACKWARD_");
+        let instruction = match opname {
+            "FOR_ITER" => Instruction::ForIter(0),
+            "JUMP_IF_FALSE_OR_POP" => Instruction::JumpIfFalseOrPop(0),
+            "JUMP_IF_TRUE_OR_POP" => Instruction::JumpIfTrueOrPop(0),
+            _ if condition == "IF_FALSE" => Instruction::PopJumpIfFalse(0),
+            _ if condition == "IF_TRUE" => Instruction::PopJumpIfTrue(0),
+            _ if condition == "IF_NONE" || condition == "IF_NOT_NONE" => {
+                let none = self.constant(Value::None);
+                self.emit(Instruction::LoadConst(none), line);
+                self.emit(Instruction::IsOp(false), line);
+                if condition == "IF_NONE" {
+                    Instruction::PopJumpIfTrue(0)
+                } else {
+                    Instruction::PopJumpIfFalse(0)
+                }
+            }
+            _ => Instruction::JumpAbsolute(0),
+        };
+        let jumped = self.after(instruction, true);
+        self.jumped.entry(target).or_insert(jumped);
+        self.jumps.push((self.instructions.len(), target));
+        self.emit(instruction, line);
+    }
+
+    /// Appends an instruction, following its effect on the stack.
+    fn emit(&mut self, instruction: Instruction, line: usize) {
+        self.stack = self.after(instruction, false);
+        self.instructions.push(instruction);
+        self.instruction_lines.push(line);
+    }
+
+    /// The stack after an instruction, when it jumps if `jump` is set.
+    fn after(&self, instruction: Instruction, jump: bool) -> Stack {
+        let depth = self.stack.depth;
+        let bottom = depth.saturating_sub(instruction.stack_inputs());
+        // The NULLs under the values taken go with them.
+        let nulls = self
+            .stack
+            .nulls
+            .iter()
+            .copied()
+            .filter(|&null| null <= bottom)
+            .collect();
+        Stack {
+            depth: (depth as i32 + instruction.stack_effect(jump)).max(0) as u32,
+            nulls,
+        }
+    }
+
+    /// The index of a constant equal to `value`, added if there is none.
+    fn constant(&mut self, value: Value) -> u32 {
+        let found = self
+            .consts
+            .iter()
+            .position(|constant| match (constant, &value) {
+                (Value::Str(a), Value::Str(b)) => a == b,
+                (Value::None, Value::None) => true,
+                _ => false,
+            });
+        found.unwrap_or_else(|| {
+            self.consts.push(value);
+            self.consts.len() - 1
+        }) as u32
+    }
+}
+
+/// Our value for a constant of CPython's.
+fn constant(object: &Object) -> PycResult<Value> {
+    Ok(match object {
+        Object::None => Value::None,
+        Object::Bool(value) => Value::Bool(*value),
+        Object::Int(value) => Value::Int(*value),
+        Object::Float(value) => Value::Float(*value),
+        Object::Str(value) => Value::Str(value.clone()),
+        Object::Tuple(items) => Value::tuple(items.iter().map(constant).collect::<PycResult<_>>()?),
+        Object::Code(code) => Value::Code(Rc::new(translate(code)?)),
+        Object::Ellipsis | Object::Bytes(_) => {
+            return Err(PycError::Corrupt(format!(
+                "unsupported constant of type {}",
+                object.type_name()
+            )))
+        }
+    })
+}
+
+/// The number and cache count of a translated instruction, and its name.
+fn opcode_info(opcode: u8) -> Option<(u8, usize, &'static str)> {
+    OPCODES
+        .iter()
+        .copied()
+        .find(|&(number, _, _)| number == opcode)
+}
+
+/// The code unit a jump goes to, or `None` if the instruction is not one.
+fn jump_target(raw: &Raw) -> Option<Result<usize, String>> {
+    let (_, _, opname) = opcode_info(raw.opcode)?;
+    match opname {
+        "FOR_ITER"
+        | "JUMP_FORWARD"
+        | "JUMP_IF_FALSE_OR_POP"
+        | "JUMP_IF_TRUE_OR_POP"
+        | "POP_JUMP_FORWARD_IF_FALSE"
+        | "POP_JUMP_FORWARD_IF_TRUE"
+        | "POP_JUMP_FORWARD_IF_NONE"
+        | "POP_JUMP_FORWARD_IF_NOT_NONE" => Some(Ok(raw.next + raw.arg as usize)),
+        "JUMP_BACKWARD"
+        | "JUMP_BACKWARD_NO_INTERRUPT"
+        | "POP_JUMP_BACKWARD_IF_FALSE"
+        | "POP_JUMP_BACKWARD_IF_TRUE"
+        | "POP_JUMP_BACKWARD_IF_NONE"
+        | "POP_JUMP_BACKWARD_IF_NOT_NONE" => Some(
+            raw.next
+                .checked_sub(raw.arg as usize)
+                .ok_or_else(|| "a jump before the start of the code".to_string()),
+        ),
+        _ => None,
+    }
+}
+
+/// Whether execution never goes on from the instruction to the next.
+fn is_terminal(opcode: u8) -> bool {
+    matches!(
+        opcode_info(opcode).map(|(_, _, name)| name),
+        Some(
+            "RETURN_VALUE"
+                | "JUMP_FORWARD"
+                | "JUMP_BACKWARD"
+                | "JUMP_BACKWARD_NO_INTERRUPT"
+                | "RAISE_VARARGS"
+                | "RERAISE"
+        )
+    )
+}
+
+/// Splits `co_code` into instructions, folding in `EXTENDED_ARG`s and
+/// skipping inline caches.
+fn decode_instructions(code: &marshal::Code) -> Result<Vec<Raw>, (usize, String)> {
+    let units: Vec<(u8, u8)> = code
+        .code
+        .chunks_exact(2)
+        .map(|unit| (unit[0], unit[1]))
+        .collect();
+    if !code.code.len().is_multiple_of(2) {
+        return Err((
+            units.len(),
+            "the code is an odd number of bytes".to_string(),
+        ));
+    }
+    let mut raws = Vec::new();
+    let mut start = None;
+    let mut extended = 0u32;
+    let mut at = 0;
+    while at < units.len() {
+        let (opcode, arg) = units[at];
+        let arg = extended << 8 | arg as u32;
+        let start_here = *start.get_or_insert(at);
+        if opcode == 144 {
+            extended = arg;
+            at += 1;
+            continue;
+        }
+        let caches = opcode_info(opcode).map_or(0, |(_, caches, _)| caches);
+        let next = at + 1 + caches;
+        if next > units.len() {
+            return Err((
+                at,
+                "the inline caches run off the end of the code".to_string(),
+            ));
+        }
+        raws.push(Raw {
+            start: start_here,
+            at,
+            opcode,
+            arg,
+            next,
+        });
+        start = None;
+        extended = 0;
+        at = next;
+    }
+    Ok(raws)
+}
+
+/// The source line of each code unit, from a PEP 657 location table:
+/// entries each covering up to eight units, with a line, which may be a
+/// difference from the previous entry's, and columns, which are skipped.
+fn decode_lines(code: &marshal::Code) -> Result<Vec<Option<usize>>, String> {
+    let bytes = &code.linetable;
+    let mut lines = Vec::new();
+    let mut line = code.firstlineno as i64;
+    let mut at = 0;
+    let cut_short = || "the line table is cut short".to_string();
+    let varint = |at: &mut usize| -> Result<u64, String> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = *bytes.get(*at).ok_or_else(cut_short)?;
+            *at += 1;
+            value |= ((byte & 63) as u64) << shift;
+            shift += 6;
+            if byte & 64 == 0 || shift > 60 {
+                return Ok(value);
+            }
+        }
+    };
+    let signed = |value: u64| {
+        if value & 1 != 0 {
+            -((value >> 1) as i64)
+        } else {
+            (value >> 1) as i64
+        }
+    };
+    while at < bytes.len() {
+        let first = bytes[at];
+        at += 1;
+        if first & 0x80 == 0 {
+            return Err(format!("the line table has a stray byte at {}", at - 1));
+        }
+        let kind = (first >> 3) & 15;
+        let len = (first & 7) as usize + 1;
+        let has_line = match kind {
+            15 => false,
+            14 => {
+                line += signed(varint(&mut at)?);
+                for _ in 0..3 {
+                    varint(&mut at)?;
+                }
+                true
+            }
+            13 => {
+                line += signed(varint(&mut at)?);
+                true
+            }
+            10..=12 => {
+                line += (kind - 10) as i64;
+                at += 2;
+                true
+            }
+            _ => {
+                at += 1;
+                true
+            }
+        };
+        if at > bytes.len() {
+            return Err(cut_short());
+        }
+        let line = (has_line && line >= 0).then_some(line as usize);
+        lines.extend(std::iter::repeat_n(line, len));
+    }
+    // Code without a table, or with a short one, has no lines past it.
+    lines.resize(code.code.len() / 2 + 1, None);
+    Ok(lines)
+}
+
+/// The entries of a CPython 3.11 exception table, in code units rather
+/// than in instructions. The numbers are written in six-bit chunks, the
+/// highest first, each with a bit for whether another follows; the first
+/// of an entry's is marked.
+fn decode_exception_table(bytes: &[u8]) -> Result<Vec<ExceptionEntry>, String> {
+    let mut entries = Vec::new();
+    let mut at = 0;
+    let varint = |at: &mut usize| -> Result<usize, String> {
+        let mut value = 0usize;
+        loop {
+            let byte = *bytes.get(*at).ok_or("the exception table is cut short")?;
+            *at += 1;
+            value = value << 6 | (byte & 63) as usize;
+            if byte & 64 == 0 {
+                return Ok(value);
+            }
+        }
+    };
+    while at < bytes.len() {
+        if bytes[at] & 0x80 == 0 {
+            return Err(format!("the exception table has a stray byte at {}", at));
+        }
+        let start = varint(&mut at)?;
+        let len = varint(&mut at)?;
+        let target = varint(&mut at)?;
+        // The lowest bit says whether to push the offset of the
+        // instruction that raised.
+        let depth = varint(&mut at)?;
+        entries.push(ExceptionEntry {
+            start: start as u32,
+            end: (start + len) as u32,
+            target: target as u32,
+            depth: (depth >> 1) as u32,
+            lasti: depth & 1 != 0,
+        });
+    }
+    Ok(entries)
+}
+
+/// Writes a CPython 3.11 `.pyc` file of `code`, stamped with the time and
+/// size of its `source`, as a timestamp-based cache is.
+pub fn dumps(code: &CodeObject, source: Source) -> PycResult<Vec<u8>> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend(0u32.to_le_bytes());
+    bytes.extend(source.stamp());
+    bytes.extend(marshal::dumps(&Object::Code(Rc::new(to_cpython(code)?))));
+    Ok(bytes)
+}
+
+/// Translates our code object, and those among its constants, into
+/// CPython 3.11's, undoing what [`translate`] does.
+pub fn to_cpython(code: &CodeObject) -> PycResult<marshal::Code> {
+    Writer::new(code).run()
+}
+
+/// A CPython 3.11 instruction to be written.
+#[derive(Debug, Clone, Copy)]
+struct Op {
+    opname: &'static str,
+    arg: u32,
+    /// For a jump, the index of our instruction it goes to.
+    target: Option<usize>,
+    line: Option<usize>,
+}
+
+struct Writer<'a> {
+    code: &'a CodeObject,
+    instructions: Vec<Instruction>,
+    /// The index of our instructions that jumps go to.
+    targets: HashSet<usize>,
+    /// Our instructions that push a callable to be called with a NULL
+    /// under it, and the calls of them.
+    callables: HashSet<usize>,
+    calls: HashSet<usize>,
+    /// Our `LoadAttr`s of a callable that is called with `*` or `**`
+    /// arguments, which push the NULL after it and swap it under.
+    nulls_after: HashSet<usize>,
+    /// The constants loaded by instructions that are dropped: the names of
+    /// keyword arguments and the qualified names of functions.
+    dropped: HashSet<usize>,
+    ops: Vec<Op>,
+    /// The index in `ops` where each of our instructions starts, and then
+    /// their end.
+    starts: Vec<usize>,
+}
+
+impl<'a> Writer<'a> {
+    fn new(code: &'a CodeObject) -> Writer<'a> {
+        let instructions = code.instructions();
+        let targets = instructions
+            .iter()
+            .filter_map(|instruction| instruction.jump_target())
+            .map(|target| target as usize)
+            .collect();
+        Writer {
+            code,
+            instructions,
+            targets,
+            callables: HashSet::new(),
+            calls: HashSet::new(),
+            nulls_after: HashSet::new(),
+            dropped: HashSet::new(),
+            ops: Vec::new(),
+            starts: Vec::new(),
+        }
+    }
+
+    fn run(mut self) -> PycResult<marshal::Code> {
+        let code = self.code;
+        let consts = code
+            .consts
+            .iter()
+            .map(object)
+            .collect::<PycResult<Vec<Object>>>()?;
+        // Local variables come first, each also a cell if nested functions
+        // use it, then the other cells, then the free variables.
+        let mut localsplusnames: Vec<Rc<str>> = Vec::new();
+        let mut localspluskinds = Vec::new();
+        for name in &code.varnames {
+            localsplusnames.push(name.as_str().into());
+            let cell = code.cellvars.contains(name);
+            localspluskinds.push(CO_FAST_LOCAL | if cell { CO_FAST_CELL } else { 0 });
+        }
+        let mut derefs = Vec::new();
+        for name in &code.cellvars {
+            match code.varnames.iter().position(|varname| varname == name) {
+                Some(slot) => derefs.push(slot as u32),
+                None => {
+                    derefs.push(localsplusnames.len() as u32);
+                    localsplusnames.push(name.as_str().into());
+                    localspluskinds.push(CO_FAST_CELL);
+                }
+            }
+        }
+        for name in &code.freevars {
+            derefs.push(localsplusnames.len() as u32);
+            localsplusnames.push(name.as_str().into());
+            localspluskinds.push(CO_FAST_FREE);
+        }
+
+        self.plan_calls()?;
+        for &slot in &derefs[..code.cellvars.len()] {
+            self.emit("MAKE_CELL", slot, None);
+        }
+        if !code.freevars.is_empty() {
+            self.emit("COPY_FREE_VARS", code.freevars.len() as u32, None);
+        }
+        // A module's code starts on the line before its first, where
+        // there is none.
+        let resume = match code.name.as_str() {
+            "<module>" => code.firstlineno.saturating_sub(1),
+            _ => code.firstlineno,
+        };
+        self.emit("RESUME", 0, Some(resume));
+        let lines = code.lines();
+        for (index, &line) in lines.iter().enumerate() {
+            let instruction = self.instructions[index];
+            self.starts.push(self.ops.len());
+            self.instruction(index, instruction, &derefs, line)
+                .map_err(|message| PycError::Translate {
+                    qualname: code.qualname.clone(),
+                    offset: 2 * index,
+                    message,
+                })?;
+        }
+        self.starts.push(self.ops.len());
+
+        let (bytecode, offsets) = self.layout();
+        Ok(marshal::Code {
+            argcount: code.argcount as u32,
+            posonlyargcount: code.posonlyargcount as u32,
+            kwonlyargcount: code.kwonlyargcount as u32,
+            stacksize: self.stacksize() as u32,
+            flags: code.flags,
+            code: bytecode,
+            consts,
+            names: code.names.iter().map(|name| name.as_str().into()).collect(),
+            localsplusnames,
+            localspluskinds,
+            filename: code.filename.as_str().into(),
+            name: code.name.as_str().into(),
+            qualname: code.qualname.as_str().into(),
+            firstlineno: code.firstlineno as u32,
+            linetable: self.linetable(&offsets),
+            exceptiontable: self.exception_table(&offsets),
+        })
+    }
+
+    /// Finds the calls whose callable can have a NULL pushed under it, as
+    /// CPython does, and the constants that need not be loaded.
+    fn plan_calls(&mut self) -> Result<(), PycError> {
+        let instructions = &self.instructions;
+        for (index, &instruction) in instructions.iter().enumerate() {
+            let (count, start) = match instruction {
+                Instruction::CallFunction(count) => (count, index),
+                Instruction::CallFunctionKw(count) => {
+                    match index.checked_sub(1).map(|before| instructions[before]) {
+                        Some(Instruction::LoadConst(_)) if !self.targets.contains(&index) => {}
+                        _ => {
+                            return Err(PycError::Translate {
+                                qualname: self.code.qualname.clone(),
+                                offset: 2 * index,
+                                message: "CALL_FUNCTION_KW does not follow the names of its \
+                                          keyword arguments"
+                                    .to_string(),
+                            })
+                        }
+                    }
+                    self.dropped.insert(index - 1);
+                    (count, index - 1)
+                }
+                // CPython always calls these with a NULL under the callable.
+                Instruction::CallFunctionEx(flags) => {
+                    match self.callable(index, 2 + (flags & 1)) {
+                        Some(callable)
+                            if matches!(instructions[callable], Instruction::LoadAttr(_)) =>
+                        {
+                            self.nulls_after.insert(callable);
+                        }
+                        Some(callable) => {
+                            self.callables.insert(callable);
+                        }
+                        None => {
+                            return Err(PycError::Translate {
+                                qualname: self.code.qualname.clone(),
+                                offset: 2 * index,
+                                message: "CALL_FUNCTION_EX of a callable that is not simply \
+                                          loaded"
+                                    .to_string(),
+                            })
+                        }
+                    }
+                    continue;
+                }
+                Instruction::MakeFunction(_) => {
+                    if let Some(Instruction::LoadConst(_)) =
+                        index.checked_sub(1).map(|before| instructions[before])
+                    {
+                        if !self.targets.contains(&index) {
+                            self.dropped.insert(index - 1);
+                        }
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            if let Some(callable) = self.callable(start, count + 1) {
+                self.callables.insert(callable);
+                self.calls.insert(index);
+            }
+        }
+        Ok(())
+    }
+
+    /// The instruction that pushes the value `depth` down the stack before
+    /// the one at `end`, if it is a load, or a `LoadAttr` that can load a
+    /// method instead, and nothing joins or leaves the code in between.
+    fn callable(&self, end: usize, mut depth: u32) -> Option<usize> {
+        for index in (0..end).rev() {
+            let instruction = self.instructions[index];
+            if self.targets.contains(&(index + 1)) || instruction.jump_target().is_some() {
+                return None;
+            }
+            let inputs = instruction.stack_inputs();
+            let outputs = (inputs as i32 + instruction.stack_effect(false)) as u32;
+            if depth <= outputs {
+                let load = matches!(
+                    instruction,
+                    Instruction::LoadConst(_)
+                        | Instruction::LoadName(_)
+                        | Instruction::LoadGlobal(_)
+                        | Instruction::LoadFast(_)
+                        | Instruction::LoadDeref(_)
+                        | Instruction::LoadAttr(_)
+                        | Instruction::LoadBuildClass
+                );
+                return (load && depth == 1 && !self.dropped.contains(&index)).then_some(index);
+            }
+            depth = depth - outputs + inputs;
+        }
+        None
+    }
+
+    /// Emits the CPython instructions for one of ours.
+    fn instruction(
+        &mut self,
+        index: usize,
+        instruction: Instruction,
+        derefs: &[u32],
+        line: usize,
+    ) -> Result<(), String> {
+        let line = Some(line);
+        let callable = self.callables.contains(&index);
+        let jump = |forward: &'static str, backward: Option<&'static str>, target: u32| {
+            let target = target as usize;
+            match backward {
+                _ if target > index => Ok((forward, target)),
+                Some(backward) => Ok((backward, target)),
+                None => Err(format!(
+                    "{} {} jumps backward",
+                    instruction.opname(),
+                    target
+                )),
+            }
+        };
+        let emit_jump = |writer: &mut Self, (opname, target): (&'static str, usize)| {
+            writer.ops.push(Op {
+                opname,
+                arg: 0,
+                target: Some(target),
+                line,
+            });
+        };
+        if callable
+            && !matches!(
+                instruction,
+                Instruction::LoadGlobal(_) | Instruction::LoadAttr(_)
+            )
+        {
+            self.emit("PUSH_NULL", 0, line);
+        }
+        match instruction {
+            Instruction::Nop => self.emit("NOP", 0, line),
+            Instruction::PopTop => self.emit("POP_TOP", 0, line),
+            Instruction::RotTwo => self.emit("SWAP", 2, line),
+            Instruction::RotThree => {
+                self.emit("SWAP", 3, line);
+                self.emit("SWAP", 2, line);
+            }
+            Instruction::DupTop => self.emit("COPY", 1, line),
+            Instruction::Copy(arg) => self.emit("COPY", arg, line),
+            Instruction::LoadConst(_) if self.dropped.contains(&index) => {}
+            Instruction::LoadConst(arg) => self.emit("LOAD_CONST", arg, line),
+            Instruction::LoadName(arg) => self.emit("LOAD_NAME", arg, line),
+            Instruction::StoreName(arg) => self.emit("STORE_NAME", arg, line),
+            Instruction::DeleteName(arg) => self.emit("DELETE_NAME", arg, line),
+            Instruction::LoadGlobal(arg) => {
+                self.emit("LOAD_GLOBAL", arg << 1 | callable as u32, line)
+            }
+            Instruction::StoreGlobal(arg) => self.emit("STORE_GLOBAL", arg, line),
+            Instruction::DeleteGlobal(arg) => self.emit("DELETE_GLOBAL", arg, line),
+            Instruction::LoadFast(arg) => self.emit("LOAD_FAST", arg, line),
+            Instruction::StoreFast(arg) => self.emit("STORE_FAST", arg, line),
+            Instruction::DeleteFast(arg) => self.emit("DELETE_FAST", arg, line),
+            Instruction::LoadDeref(arg)
+            | Instruction::StoreDeref(arg)
+            | Instruction::DeleteDeref(arg)
+            | Instruction::LoadClosure(arg) => {
+                let slot = *derefs
+                    .get(arg as usize)
+                    .ok_or_else(|| format!("{} {} is out of range", instruction.opname(), arg))?;
+                let opname = match instruction {
+                    Instruction::LoadDeref(_) => "LOAD_DEREF",
+                    Instruction::StoreDeref(_) => "STORE_DEREF",
+                    Instruction::DeleteDeref(_) => "DELETE_DEREF",
+                    _ => "LOAD_CLOSURE",
+                };
+                self.emit(opname, slot, line);
+            }
+            Instruction::LoadAttr(arg) if self.nulls_after.contains(&index) => {
+                self.emit("LOAD_ATTR", arg, line);
+                self.emit("PUSH_NULL", 0, line);
+                self.emit("SWAP", 2, line);
+            }
+            Instruction::LoadAttr(arg) if callable => self.emit("LOAD_METHOD", arg, line),
+            Instruction::LoadAttr(arg) => self.emit("LOAD_ATTR", arg, line),
+            Instruction::StoreAttr(arg) => self.emit("STORE_ATTR", arg, line),
+            Instruction::DeleteAttr(arg) => self.emit("DELETE_ATTR", arg, line),
+            Instruction::BinarySubscr => self.emit("BINARY_SUBSCR", 0, line),
+            Instruction::DeleteSubscr => self.emit("DELETE_SUBSCR", 0, line),
+            Instruction::UnaryOp(op) => {
+                let opname = match op {
+                    UnaryOperator::UAdd => "UNARY_POSITIVE",
+                    UnaryOperator::USub => "UNARY_NEGATIVE",
+                    UnaryOperator::Not => "UNARY_NOT",
+                    UnaryOperator::Invert => "UNARY_INVERT",
+                };
+                self.emit(opname, 0, line);
+            }
+            Instruction::BinaryOp(op) | Instruction::InplaceOp(op) => {
+                let mut arg = BINARY_OPS.iter().position(|&o| o == op).unwrap() as u32;
+                if let Instruction::InplaceOp(_) = instruction {
+                    arg += BINARY_OPS.len() as u32;
+                }
+                self.emit("BINARY_OP", arg, line);
+            }
+            Instruction::CompareOp(op) => self.emit("COMPARE_OP", compare_index(op), line),
+            Instruction::IsOp(invert) => self.emit("IS_OP", invert as u32, line),
+            Instruction::ContainsOp(invert) => self.emit("CONTAINS_OP", invert as u32, line),
+            Instruction::BuildTuple(arg) => self.emit("BUILD_TUPLE", arg, line),
+            Instruction::BuildList(arg) => self.emit("BUILD_LIST", arg, line),
+            Instruction::BuildSet(arg) => self.emit("BUILD_SET", arg, line),
+            Instruction::BuildMap(arg) => self.emit("BUILD_MAP", arg, line),
+            Instruction::BuildConstKeyMap(arg) => self.emit("BUILD_CONST_KEY_MAP", arg, line),
+            Instruction::ListAppend(arg) => self.emit("LIST_APPEND", arg, line),
+            Instruction::SetAdd(arg) => self.emit("SET_ADD", arg, line),
+            Instruction::ListExtend(arg) => self.emit("LIST_EXTEND", arg, line),
+            Instruction::ListToTuple => self.emit("LIST_TO_TUPLE", 0, line),
+            Instruction::DictMerge(arg) => self.emit("DICT_MERGE", arg, line),
+            Instruction::MapAdd(arg) => self.emit("MAP_ADD", arg, line),
+            Instruction::UnpackSequence(arg) => self.emit("UNPACK_SEQUENCE", arg, line),
+            Instruction::CallFunction(count) | Instruction::CallFunctionKw(count) => {
+                if let Instruction::CallFunctionKw(_) = instruction {
+                    let Instruction::LoadConst(names) = self.instructions[index - 1] else {
+                        unreachable!("a keyword call without its names");
+                    };
+                    self.emit("KW_NAMES", names, line);
+                }
+                // Without a NULL under it, CPython calls the callable as a
+                // method, its first argument the object; with no
+                // arguments, the NULL goes under it.
+                let arg = match count {
+                    _ if self.calls.contains(&index) => count,
+                    0 => {
+                        self.emit("PUSH_NULL", 0, line);
+                        self.emit("SWAP", 2, line);
+                        0
+                    }
+                    _ => count - 1,
+                };
+                self.emit("PRECALL", arg, line);
+                self.emit("CALL", arg, line);
+            }
+            Instruction::CallFunctionEx(flags) => self.emit("CALL_FUNCTION_EX", flags, line),
+            // The type parameters of CPython 3.12 have nothing in 3.11.
+            Instruction::CallIntrinsic1(_) | Instruction::CallIntrinsic2(_) => {
+                return Err(format!("{} is not in CPython 3.11", instruction.opname()))
+            }
+            Instruction::MakeFunction(flags) => {
+                if !self.dropped.contains(&(index.wrapping_sub(1))) {
+                    self.emit("POP_TOP", 0, line);
+                }
+                self.emit("MAKE_FUNCTION", flags, line);
+            }
+            Instruction::ReturnValue => self.emit("RETURN_VALUE", 0, line),
+            Instruction::LoadBuildClass => self.emit("LOAD_BUILD_CLASS", 0, line),
+            Instruction::LoadAssertionError => self.emit("LOAD_ASSERTION_ERROR", 0, line),
+            Instruction::RaiseVarargs(arg) => self.emit("RAISE_VARARGS", arg, line),
+            Instruction::PushExcInfo => self.emit("PUSH_EXC_INFO", 0, line),
+            Instruction::PopExcept => self.emit("POP_EXCEPT", 0, line),
+            Instruction::Reraise(arg) => self.emit("RERAISE", arg, line),
+            Instruction::CheckExcMatch => self.emit("CHECK_EXC_MATCH", 0, line),
+            Instruction::BeforeWith => self.emit("BEFORE_WITH", 0, line),
+            Instruction::WithExceptStart => self.emit("WITH_EXCEPT_START", 0, line),
+            Instruction::GetIter => self.emit("GET_ITER", 0, line),
+            Instruction::ForIter(target) => emit_jump(self, jump("FOR_ITER", None, target)?),
+            Instruction::JumpAbsolute(target) => {
+                emit_jump(self, jump("JUMP_FORWARD", Some("JUMP_BACKWARD"), target)?)
+            }
+            Instruction::PopJumpIfFalse(target) => emit_jump(
+                self,
+                jump(
+                    "POP_JUMP_FORWARD_IF_FALSE",
+                    Some("POP_JUMP_BACKWARD_IF_FALSE"),
+                    target,
+                )?,
+            ),
+            Instruction::PopJumpIfTrue(target) => emit_jump(
+                self,
+                jump(
+                    "POP_JUMP_FORWARD_IF_TRUE",
+                    Some("POP_JUMP_BACKWARD_IF_TRUE"),
+                    target,
+                )?,
+            ),
+            Instruction::JumpIfFalseOrPop(target) => {
+                emit_jump(self, jump("JUMP_IF_FALSE_OR_POP", None, target)?)
+            }
+            Instruction::JumpIfTrueOrPop(target) => {
+                emit_jump(self, jump("JUMP_IF_TRUE_OR_POP", None, target)?)
+            }
+            // CPython has no fused comparisons: they come apart again.
+            Instruction::CompareJumpIfFalse(op, target) => {
+                self.emit("COMPARE_OP", compare_index(op), line);
+                emit_jump(
+                    self,
+                    jump(
+                        "POP_JUMP_FORWARD_IF_FALSE",
+                        Some("POP_JUMP_BACKWARD_IF_FALSE"),
+                        target,
+                    )?,
+                );
+            }
+            Instruction::CompareJumpIfTrue(op, target) => {
+                self.emit("COMPARE_OP", compare_index(op), line);
+                emit_jump(
+                    self,
+                    jump(
+                        "POP_JUMP_FORWARD_IF_TRUE",
+                        Some("POP_JUMP_BACKWARD_IF_TRUE"),
+                        target,
+                    )?,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, opname: &'static str, arg: u32, line: Option<usize>) {
+        self.ops.push(Op {
+            opname,
+            arg,
+            target: None,
+            line,
+        });
+    }
+
+    /// The argument of each op, with jumps made relative, and where each
+    /// starts, in code units, with its `EXTENDED_ARG`s, and then the end.
+    fn arguments(&self, offsets: &[usize]) -> Vec<u32> {
+        self.ops
+            .iter()
+            .enumerate()
+            .map(|(index, op)| match op.target {
+                Some(target) => {
+                    let next = offsets[index + 1];
+                    let target = offsets[self.starts[target]];
+                    next.abs_diff(target) as u32
+                }
+                None => op.arg,
+            })
+            .collect()
+    }
+
+    /// The bytecode, and where each op starts in it, in code units, and
+    /// then its end. A jump's argument may need `EXTENDED_ARG`s, which
+    /// move what follows, so the offsets are worked out again until they
+    /// settle.
+    fn layout(&self) -> (Vec<u8>, Vec<usize>) {
+        let mut extended = vec![0; self.ops.len()];
+        loop {
+            let mut offsets = vec![0];
+            for (op, &extended) in self.ops.iter().zip(&extended) {
+                let (_, caches) = number(op.opname);
+                offsets.push(offsets.last().unwrap() + extended + 1 + caches);
+            }
+            let arguments = self.arguments(&offsets);
+            let needed: Vec<usize> = arguments
+                .iter()
+                .map(|&arg| (1..4).filter(|shift| arg >> (8 * shift) != 0).count())
+                .collect();
+            if needed == extended {
+                let mut bytes = Vec::new();
+                for ((op, &arg), &extended) in self.ops.iter().zip(&arguments).zip(&extended) {
+                    for shift in (1..=extended).rev() {
+                        bytes.extend([144, (arg >> (8 * shift)) as u8]);
+                    }
+                    let (opcode, caches) = number(op.opname);
+                    bytes.extend([opcode, arg as u8]);
+                    bytes.extend(std::iter::repeat_n(0, 2 * caches));
+                }
+                return (bytes, offsets);
+            }
+            // Growing only, the offsets settle.
+            for (extended, needed) in extended.iter_mut().zip(needed) {
+                *extended = (*extended).max(needed);
+            }
+        }
+    }
+
+    /// The PEP 657 location table: for each run of up to eight code units
+    /// on a line, its difference from the line before, with no columns.
+    fn linetable(&self, offsets: &[usize]) -> Vec<u8> {
+        let mut units = Vec::new();
+        for (index, op) in self.ops.iter().enumerate() {
+            units.extend(std::iter::repeat_n(
+                op.line,
+                offsets[index + 1] - offsets[index],
+            ));
+        }
+        let mut bytes = Vec::new();
+        let mut previous = self.code.firstlineno as i64;
+        for run in units.chunk_by(|a, b| a == b).flat_map(|run| run.chunks(8)) {
+            let len = run.len() as u8 - 1;
+            match run[0] {
+                None => bytes.push(0x80 | 15 << 3 | len),
+                Some(line) => {
+                    bytes.push(0x80 | 13 << 3 | len);
+                    let delta = line as i64 - previous;
+                    previous = line as i64;
+                    let mut value = if delta < 0 {
+                        (delta.unsigned_abs()) << 1 | 1
+                    } else {
+                        (delta as u64) << 1
+                    };
+                    while value >= 64 {
+                        bytes.push(64 | (value & 63) as u8);
+                        value >>= 6;
+                    }
+                    bytes.push(value as u8);
+                }
+            }
+        }
+        bytes
+    }
+
+    /// The exception table, as [`decode_exception_table`] reads it.
+    fn exception_table(&self, offsets: &[usize]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let unit = |index: u32| offsets[self.starts[index as usize]];
+        for entry in &self.code.exception_table {
+            let start = unit(entry.start);
+            let fields = [
+                start,
+                unit(entry.end) - start,
+                unit(entry.target),
+                (entry.depth as usize) << 1 | entry.lasti as usize,
+            ];
+            for (field, value) in fields.into_iter().enumerate() {
+                let mut chunks = vec![value & 63];
+                let mut rest = value >> 6;
+                while rest != 0 {
+                    chunks.push(rest & 63);
+                    rest >>= 6;
+                }
+                let first = bytes.len();
+                for (index, chunk) in chunks.iter().rev().enumerate() {
+                    let more = if index + 1 < chunks.len() { 64 } else { 0 };
+                    bytes.push(*chunk as u8 | more);
+                }
+                if field == 0 {
+                    bytes[first] |= 0x80;
+                }
+            }
+        }
+        bytes
+    }
+
+    /// The most values CPython's stack holds at once, following every
+    /// path through the ops, to a handler with the exception pushed.
+    fn stacksize(&self) -> usize {
+        let mut depths: Vec<Option<i32>> = vec![None; self.ops.len() + 1];
+        let mut pending = vec![(0, 0)];
+        for entry in &self.code.exception_table {
+            let depth = entry.depth as i32 + entry.lasti as i32 + 1;
+            pending.push((self.starts[entry.target as usize], depth));
+        }
+        let mut max = 0;
+        while let Some((index, depth)) = pending.pop() {
+            if index >= self.ops.len() || depths[index].is_some() {
+                continue;
+            }
+            depths[index] = Some(depth);
+            max = max.max(depth);
+            let op = self.ops[index];
+            if let Some(target) = op.target {
+                pending.push((self.starts[target], depth + cpython_effect(op, true)));
+            }
+            if !matches!(
+                op.opname,
+                "RETURN_VALUE" | "JUMP_FORWARD" | "JUMP_BACKWARD" | "RAISE_VARARGS" | "RERAISE"
+            ) {
+                let after = depth + cpython_effect(op, false);
+                max = max.max(after);
+                pending.push((index + 1, after));
+            }
+        }
+        max as usize
+    }
+}
+
+/// How a CPython 3.11 op changes the depth of the stack, when it jumps if
+/// `jump` is set.
+fn cpython_effect(op: Op, jump: bool) -> i32 {
+    let arg = op.arg as i32;
+    match op.opname {
+        "PUSH_NULL" | "COPY" | "LOAD_CONST" | "LOAD_NAME" | "LOAD_FAST" | "LOAD_DEREF"
+        | "LOAD_CLOSURE" | "LOAD_METHOD" | "PUSH_EXC_INFO" | "BEFORE_WITH"
+        | "WITH_EXCEPT_START" | "LOAD_BUILD_CLASS" => 1,
+        "LOAD_ASSERTION_ERROR" => 1,
+        "LOAD_GLOBAL" => 1 + (arg & 1),
+        "POP_TOP" | "BINARY_SUBSCR" | "RETURN_VALUE" | "STORE_NAME" | "STORE_GLOBAL"
+        | "STORE_FAST" | "STORE_DEREF" | "COMPARE_OP" | "IS_OP" | "CONTAINS_OP" | "BINARY_OP"
+        | "LIST_APPEND" | "LIST_EXTEND" | "POP_EXCEPT" | "RERAISE" | "DELETE_ATTR" => -1,
+        "DICT_MERGE" => -1,
+        "STORE_ATTR" | "DELETE_SUBSCR" => -2,
+        "RAISE_VARARGS" => -arg,
+        "POP_JUMP_FORWARD_IF_FALSE"
+        | "POP_JUMP_FORWARD_IF_TRUE"
+        | "POP_JUMP_BACKWARD_IF_FALSE"
+        | "POP_JUMP_BACKWARD_IF_TRUE" => -1,
+        "MAP_ADD" => -2,
+        "UNPACK_SEQUENCE" => arg - 1,
+        "BUILD_TUPLE" | "BUILD_LIST" => 1 - arg,
+        "BUILD_MAP" => 1 - 2 * arg,
+        "BUILD_CONST_KEY_MAP" => -arg,
+        "MAKE_FUNCTION" => -(op.arg.count_ones() as i32),
+        // The callable, and the NULL or object under it.
+        "CALL" => -arg - 1,
+        "CALL_FUNCTION_EX" => -2 - (arg & 1),
+        "FOR_ITER" => {
+            if jump {
+                -1
+            } else {
+                1
+            }
+        }
+        "JUMP_IF_FALSE_OR_POP" | "JUMP_IF_TRUE_OR_POP" => {
+            if jump {
+                0
+            } else {
+                -1
+            }
+        }
+        _ => 0,
+    }
+}
+
+/// The number and cache count of the CPython 3.11 instruction `opname`.
+fn number(opname: &str) -> (u8, usize) {
+    let (number, caches, _) = OPCODES
+        .iter()
+        .copied()
+        .find(|&(_, _, name)| name == opname)
+        .unwrap_or_else(|| panic!("no CPython instruction {}", opname));
+    (number, caches)
+}
+
+/// The argument of `COMPARE_OP` for `op`.
+fn compare_index(op: CmpOp) -> u32 {
+    CMP_OPS.iter().position(|&o| o == op).unwrap() as u32
+}
+
+/// CPython's constant for a value of ours.
+fn object(value: &Value) -> PycResult<Object> {
+    Ok(match value {
+        Value::None => Object::None,
+        Value::Bool(value) => Object::Bool(*value),
+        Value::Int(value) => Object::Int(*value),
+        Value::Float(value) => Object::Float(*value),
+        Value::Str(value) => Object::Str(value.clone()),
+        Value::Tuple(items) => Object::Tuple(items.iter().map(object).collect::<PycResult<_>>()?),
+        Value::Code(code) => Object::Code(Rc::new(to_cpython(code)?)),
+        other => {
+            return Err(PycError::Corrupt(format!(
+                "unsupported constant {:?}",
+                other
+            )))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+    use std::rc::Rc;
+
+    use super::{
+        decode_instructions, decode_lines, dumps, jump_target, load, opcode_info, to_cpython,
+        PycError,
+    };
+    use crate::cache::Source;
+    use crate::interpreter::{compile_source, interpret};
+    use crate::intruction::Instruction::*;
+    use crate::marshal::{self, Object};
+    use crate::value::Value;
+    use crate::vm::Vm;
+
+    const SOURCE: Source = Source {
+        text: b"",
+        mtime: 0,
+    };
+
+    /// Each instruction of a CPython code object, and then of those among
+    /// its constants, with its line and its argument made out.
+    fn listing(code: &marshal::Code) -> Vec<String> {
+        let lines = decode_lines(code).unwrap();
+        let mut listing = Vec::new();
+        for raw in decode_instructions(code).unwrap() {
+            let (_, _, opname) = opcode_info(raw.opcode).unwrap();
+            let arg = raw.arg as usize;
+            let argument = match opname {
+                _ if jump_target(&raw).is_some() => {
+                    format!("to {}", 2 * jump_target(&raw).unwrap().unwrap())
+                }
+                "LOAD_CONST" => match &code.consts[arg] {
+                    Object::Code(code) => format!("<code {}>", code.name),
+                    constant => format!("{:?}", constant),
+                },
+                "LOAD_NAME" | "STORE_NAME" | "LOAD_ATTR" | "LOAD_METHOD" => {
+                    code.names[arg].to_string()
+                }
+                "LOAD_GLOBAL" => format!("{} {}", code.names[arg >> 1], arg & 1),
+                "LOAD_FAST" | "STORE_FAST" | "LOAD_DEREF" | "STORE_DEREF" | "LOAD_CLOSURE" => {
+                    code.localsplusnames[arg].to_string()
+                }
+                _ => arg.to_string(),
+            };
+            listing.push(format!("{:?} {} {}", lines[raw.at], opname, argument));
+        }
+        for constant in &code.consts {
+            if let Object::Code(code) = constant {
+                listing.extend(listing_of(code));
+            }
+        }
+        listing
+    }
+
+    fn listing_of(code: &marshal::Code) -> Vec<String> {
+        let mut listing = vec![format!("{} stacksize {}", code.qualname, code.stacksize)];
+        listing.extend(self::listing(code));
+        listing
+    }
+
+    /// What running `code` prints, followed by the exception it raises,
+    /// if any.
+    fn output(result: Result<Value, String>, vm: Vm<Vec<u8>>) -> String {
+        let mut output = String::from_utf8(vm.out().clone()).unwrap();
+        if let Err(error) = result {
+            output += &error;
+        }
+        output
+    }
+
+    #[test]
+    fn test_pyc_runs_as_source() {
+        // fib, dis, try and except are compiled from the sources as they are
+        // now; the others are as old as those that print nothing.
+        for name in [
+            "fib", "dis", "print", "while", "var", "def", "return", "try", "except",
+        ] {
+            let bytes =
+                std::fs::read(format!("tests/__pycache__/{}.cpython-311.pyc", name)).unwrap();
+            let code = load(&bytes).unwrap_or_else(|error| panic!("{}.pyc: {}", name, error));
+            let mut vm = Vm::new(Vec::new());
+            let result = vm.run(Rc::new(code)).map_err(|error| error.to_string());
+            let translated = output(result, vm);
+
+            let source = std::fs::read_to_string(format!("tests/{}.py", name)).unwrap();
+            let mut vm = Vm::new(Vec::new());
+            let result = interpret(&mut vm, &source, name, 0).map_err(|error| error.to_string());
+            assert_eq!(translated, output(result, vm), "{}.pyc", name);
+        }
+    }
+
+    #[test]
+    fn test_pyc_translation() {
+        let bytes = std::fs::read("tests/__pycache__/fib.cpython-311.pyc").unwrap();
+        let module = load(&bytes).unwrap();
+        // The NULLs before both calls are dropped, and the function gets
+        // its qualified name as a constant.
+        assert_eq!(
+            module.instructions(),
+            [
+                LoadConst(0),
+                LoadConst(3),
+                MakeFunction(0),
+                StoreName(0),
+                LoadName(1),
+                LoadName(0),
+                LoadConst(1),
+                CallFunction(1),
+                CallFunction(1),
+                PopTop,
+                LoadConst(2),
+                ReturnValue,
+            ]
+        );
+        assert_eq!(module.consts[3], Value::str("fib"));
+        assert_eq!(module.lines(), [1, 1, 1, 1, 6, 6, 6, 6, 6, 6, 6, 6]);
+        assert_eq!(module.stacksize, 3);
+        let Value::Code(fib) = &module.consts[0] else {
+            panic!("expected a code object, got {:?}", module.consts[0]);
+        };
+        assert_eq!(fib.instructions()[3], PopJumpIfFalse(6));
+        assert_eq!(fib.instructions()[6], LoadGlobal(0));
+        assert_eq!(fib.varnames.len(), 1);
+    }
+
+    #[test]
+    fn test_pyc_round_trip() {
+        let closures = "def outer(a, b):
+    c = a + b
+    def inner(d, e=3):
+        return a * d + c - e
+    return inner
+print(outer(2, 5)(4, e=1), outer(1, 1)(d=2))
+xs = [1, 2]
+xs.append(3)
+x = 5
+print(1 < x < 10, [y * x for y in range(4) if y != 2])
+x += 1
+a, b = x, 2
+a, b = b, a
+print(a and b or -a, not b, 'yes' if x else 'no')
+def show(*args, **kw):
+    return args, kw
+print(show(*xs, **{'k': 1}), show(0, *xs, y=2, **{}), xs.append(*[4]))
+";
+        let mut sources = vec![("closures".to_string(), closures.to_string())];
+        for name in [
+            "fib", "dis", "while", "def", "print", "binary", "return", "try", "except",
+        ] {
+            let source = std::fs::read_to_string(format!("tests/{}.py", name)).unwrap();
+            sources.push((name.to_string(), source));
+        }
+        for (name, source) in sources {
+            let code = compile_source(&source, &name, 0).unwrap();
+            let bytes = dumps(&code, SOURCE).unwrap();
+            assert_eq!(load(&bytes), Ok(code), "{}", name);
+        }
+    }
+
+    /// A CPython 3.11 to run the `.pyc` files we write, if there is one.
+    fn cpython() -> Option<&'static str> {
+        ["python3.11", "python3"].into_iter().find(|python| {
+            Command::new(python)
+                .args(["-c", "import sys; assert sys.version_info[:2] == (3, 11)"])
+                .output()
+                .is_ok_and(|output| output.status.success())
+        })
+    }
+
+    #[test]
+    fn test_pyc_nested_loops_run_on_cpython() {
+        let source = "\
+for x in [[1, 2], [3]]:
+    for y in x:
+        print(x, y)
+a = [[1, 2], [3, 4]]
+print([y * 10 for x in a for y in x])
+for i in range(2):
+    for j in range(3):
+        if j == i:
+            continue
+        print(i, j)
+";
+        let code = compile_source(source, "loops.py", 0).unwrap();
+        let bytes = dumps(&code, SOURCE).unwrap();
+        let mut vm = Vm::new(Vec::new());
+        vm.run(Rc::new(load(&bytes).unwrap())).unwrap();
+        let expected = String::from_utf8(vm.out().clone()).unwrap();
+        assert_eq!(
+            expected,
+            "[1, 2] 1\n[1, 2] 2\n[3] 3\n[10, 20, 30, 40]\n0 1\n0 2\n1 0\n1 2\n"
+        );
+
+        let Some(python) = cpython() else {
+            eprintln!("no CPython 3.11 to run loops.pyc with");
+            return;
+        };
+        let path = std::env::temp_dir().join(format!("loops-{}.pyc", std::process::id()));
+        std::fs::write(&path, &bytes).unwrap();
+        let output = Command::new(python).arg(&path).output().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            expected,
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    #[test]
+    fn test_pyc_writes_fused_comparisons_apart() {
+        let source = std::fs::read_to_string("tests/fib.py").unwrap();
+        let code = compile_source(&source, "fib", 1).unwrap();
+        let Value::Code(fib) = &code.consts[0] else {
+            panic!("expected a code object, got {:?}", code.consts[0]);
+        };
+        assert!(matches!(fib.instructions()[2], CompareJumpIfFalse(..)));
+        let module = load(&dumps(&code, SOURCE).unwrap()).unwrap();
+        let Value::Code(fib) = &module.consts[0] else {
+            panic!("expected a code object, got {:?}", module.consts[0]);
+        };
+        assert_eq!(
+            fib.instructions()[2..4],
+            [CompareOp(crate::ast::CmpOp::Lt), PopJumpIfFalse(6)]
+        );
+        let mut vm = Vm::new(Vec::new());
+        vm.run(Rc::new(module)).unwrap();
+        assert_eq!(vm.out(), b"55\n");
+    }
+
+    #[test]
+    fn test_pyc_matches_cpython() {
+        let source = std::fs::read_to_string("tests/fib.py").unwrap();
+        let code = compile_source(&source, "tests/fib.py", 0).unwrap();
+        let ours = to_cpython(&code).unwrap();
+        let bytes = std::fs::read("tests/__pycache__/fib.cpython-311.pyc").unwrap();
+        let Object::Code(cpython) = marshal::loads(&bytes[16..]).unwrap() else {
+            panic!("expected a code object");
+        };
+        // The constants differ only in that ours keep the qualified name
+        // of `fib`, which 3.11 has in the code object instead.
+        assert_eq!(listing_of(&ours), listing_of(&cpython));
+        // So the function's bytecode is CPython's to the byte.
+        let (Object::Code(ours), Object::Code(cpython)) = (&ours.consts[0], &cpython.consts[0])
+        else {
+            panic!("expected code objects");
+        };
+        assert_eq!(ours.code, cpython.code);
+        assert_eq!(ours.localsplusnames, cpython.localsplusnames);
+    }
+
+    #[test]
+    fn test_pyc_errors() {
+        let bytes = std::fs::read("tests/__pycache__/fib.cpython-311.pyc").unwrap();
+        assert_eq!(
+            load(&bytes[..12]),
+            Err(PycError::Corrupt("the header is cut short".to_string()))
+        );
+        let mut other = bytes.clone();
+        other[0] = 0x6f;
+        assert_eq!(load(&other), Err(PycError::BadMagic));
+        assert_eq!(
+            load(&bytes[..bytes.len() - 1]).unwrap_err().to_string(),
+            "bad marshal data: offset 418: the data is cut short"
+        );
+        // The module's RESUME, made a STORE_SUBSCR.
+        let resume = bytes.windows(2).position(|unit| unit == [151, 0]).unwrap();
+        let mut unsupported = bytes.clone();
+        unsupported[resume] = 60;
+        assert_eq!(
+            load(&unsupported).unwrap_err().to_string(),
+            "<module>: offset 0: unsupported instruction 60"
+        );
+    }
+}
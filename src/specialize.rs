@@ -0,0 +1,433 @@
+//! Adaptive specialization of instructions, as PEP 659 describes and
+//! CPython 3.11 does it.
+//!
+//! The VM runs a copy of each code object's units, decoded once into
+//! [`Unit`]s, which it rewrites as it learns what the code does. Each
+//! instruction that has specialized forms is followed by an inline cache,
+//! whose first unit counts down the runs
+//! until the VM tries to specialize it: it then picks the form for the
+//! operands it has at hand, say `BINARY_ADD_INT` for two ints, and writes
+//! it over the instruction's opcode. A specialized instruction guards that
+//! its operands are still of the kind it was specialized for, cheaply, and
+//! then does its work without the generic instruction's dispatch on types.
+//! When a guard fails it runs the generic instruction instead, counting the
+//! miss down; once the misses add up, it is specialized again for what it
+//! now sees, or, failing that, goes back to its generic form, and waits
+//! longer before trying again each time it fails.
+
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::fmt;
+
+use crate::code::{CO_VARARGS, CO_VARKEYWORDS};
+use crate::intern::Symbol;
+use crate::intruction::Opcode;
+use crate::value::{Function, Value};
+
+/// The bits of a counter below its value, which hold the exponent of its
+/// backoff.
+const BACKOFF_BITS: u16 = 4;
+/// The longest backoff, `2^12 - 1` runs, as in CPython.
+const MAX_BACKOFF: u16 = 12;
+/// The counter of an instruction never run: it is first specialized on
+/// its second run.
+const WARMUP: u16 = counter(1, 1);
+/// The counter of an instruction just specialized: how many misses it
+/// takes before the VM specializes it again, CPython's 52.
+const COOLDOWN: u16 = counter(52, 0);
+
+const fn counter(value: u16, backoff: u16) -> u16 {
+    value << BACKOFF_BITS | backoff
+}
+
+/// The counter after a failed specialization: the backoff doubles, up to
+/// its longest.
+fn backoff(old: u16) -> u16 {
+    let backoff = ((old & ((1 << BACKOFF_BITS) - 1)) + 1).min(MAX_BACKOFF);
+    counter((1 << backoff) - 1, backoff)
+}
+
+/// A code unit as the VM runs it. An instruction's opcode unit holds its
+/// whole argument, with those of the `EXTENDED_ARG`s before it folded in;
+/// an `EXTENDED_ARG` holds the offset of the opcode unit it extends, which
+/// the VM goes straight to; and a unit of an inline cache holds its value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Unit {
+    pub opcode: Opcode,
+    pub arg: u32,
+}
+
+impl Unit {
+    fn cache(value: u32) -> Unit {
+        Unit {
+            opcode: Opcode::Cache,
+            arg: value,
+        }
+    }
+}
+
+/// Decodes `bytecode` for the VM to run, with the counter of each inline
+/// cache warming up. An instruction directly followed by one it makes a
+/// superinstruction with becomes that superinstruction, as in CPython
+/// 3.11; the one after it stays, for jumps to it.
+pub fn quicken(bytecode: &[u16]) -> Box<[Cell<Unit>]> {
+    let mut units: Vec<Cell<Unit>> = Vec::with_capacity(bytecode.len());
+    let mut extended = 0;
+    let mut prefixes = 0;
+    for &unit in bytecode {
+        let opcode = Opcode::from_u8(unit as u8).unwrap();
+        let arg = extended | (unit >> 8) as u32;
+        match opcode {
+            Opcode::ExtendedArg => {
+                extended = arg << 8;
+                prefixes += 1;
+            }
+            // The counter is the first unit of an instruction's cache.
+            Opcode::Cache => {
+                let first = units.last().unwrap().get().opcode != Opcode::Cache;
+                units.push(Cell::new(Unit::cache(if first {
+                    WARMUP as u32
+                } else {
+                    0
+                })));
+            }
+            _ => {
+                let offset = units.len() + prefixes;
+                units.extend((0..prefixes).map(|_| {
+                    Cell::new(Unit {
+                        opcode: Opcode::ExtendedArg,
+                        arg: offset as u32,
+                    })
+                }));
+                let previous = units.last().filter(|_| prefixes == 0);
+                if let Some(previous) = previous {
+                    let first = previous.get();
+                    if let Some(fused) = first.opcode.superinstruction(opcode) {
+                        previous.set(Unit {
+                            opcode: fused,
+                            ..first
+                        });
+                    }
+                }
+                units.push(Cell::new(Unit { opcode, arg }));
+                (extended, prefixes) = (0, 0);
+            }
+        }
+    }
+    units.into_boxed_slice()
+}
+
+/// Whether the instruction whose inline cache starts at `cache` is due to
+/// be specialized, counting its counter down if not.
+pub fn due(units: &[Cell<Unit>], cache: usize) -> bool {
+    let counter = read(units, cache) as u16;
+    if counter >> BACKOFF_BITS == 0 {
+        return true;
+    }
+    write(units, cache, (counter - (1 << BACKOFF_BITS)) as u32);
+    false
+}
+
+/// Rewrites the instruction `opcode` whose inline cache starts at `cache`
+/// to `specialized`, or without one back to its generic form, and resets
+/// its counter to match.
+pub fn rewrite(
+    units: &[Cell<Unit>],
+    cache: usize,
+    opcode: Opcode,
+    specialized: Option<Opcode>,
+    stats: &mut Stats,
+) {
+    let family = stats.family_mut(opcode);
+    let rewritten = match specialized {
+        Some(specialized) => {
+            family.success += 1;
+            write(units, cache, COOLDOWN as u32);
+            specialized
+        }
+        None => {
+            family.failure += 1;
+            if opcode != opcode.generic() {
+                family.deopt += 1;
+            }
+            write(units, cache, backoff(read(units, cache) as u16) as u32);
+            opcode.generic()
+        }
+    };
+    let unit = &units[cache - 1];
+    unit.set(Unit {
+        opcode: rewritten,
+        ..unit.get()
+    });
+}
+
+/// Writes `value` to the unit of an inline cache at `at`.
+#[inline]
+pub fn write(units: &[Cell<Unit>], at: usize, value: u32) {
+    units[at].set(Unit::cache(value));
+}
+
+/// The value [`write()`] wrote at `at`.
+#[inline]
+pub fn read(units: &[Cell<Unit>], at: usize) -> u32 {
+    units[at].get().arg
+}
+
+/// The form of the binary or in-place operation `generic` for these
+/// operands, if it has one.
+pub fn binary_op(generic: Opcode, left: &Value, right: &Value) -> Option<Opcode> {
+    Some(match (generic, left, right) {
+        (Opcode::BinaryAdd, Value::Int(_), Value::Int(_)) => Opcode::BinaryAddInt,
+        (Opcode::BinaryAdd, Value::Float(_), Value::Float(_)) => Opcode::BinaryAddFloat,
+        (Opcode::BinaryAdd, Value::Str(_), Value::Str(_)) => Opcode::BinaryAddUnicode,
+        (Opcode::BinarySubtract, Value::Int(_), Value::Int(_)) => Opcode::BinarySubtractInt,
+        (Opcode::BinarySubtract, Value::Float(_), Value::Float(_)) => Opcode::BinarySubtractFloat,
+        (Opcode::BinaryMultiply, Value::Int(_), Value::Int(_)) => Opcode::BinaryMultiplyInt,
+        (Opcode::BinaryMultiply, Value::Float(_), Value::Float(_)) => Opcode::BinaryMultiplyFloat,
+        (Opcode::InplaceAdd, Value::Int(_), Value::Int(_)) => Opcode::InplaceAddInt,
+        (Opcode::InplaceAdd, Value::Float(_), Value::Float(_)) => Opcode::InplaceAddFloat,
+        (Opcode::InplaceAdd, Value::Str(_), Value::Str(_)) => Opcode::InplaceAddUnicode,
+        (Opcode::InplaceSubtract, Value::Int(_), Value::Int(_)) => Opcode::InplaceSubtractInt,
+        (Opcode::InplaceSubtract, Value::Float(_), Value::Float(_)) => Opcode::InplaceSubtractFloat,
+        (Opcode::InplaceMultiply, Value::Int(_), Value::Int(_)) => Opcode::InplaceMultiplyInt,
+        (Opcode::InplaceMultiply, Value::Float(_), Value::Float(_)) => Opcode::InplaceMultiplyFloat,
+        _ => return None,
+    })
+}
+
+/// The form of the comparison `generic`, fused with a jump or not, for
+/// these operands, if it has one.
+pub fn compare_op(generic: Opcode, left: &Value, right: &Value) -> Option<Opcode> {
+    Some(match (generic, left, right) {
+        (Opcode::CompareOp, Value::Int(_), Value::Int(_)) => Opcode::CompareOpInt,
+        (Opcode::CompareOp, Value::Float(_), Value::Float(_)) => Opcode::CompareOpFloat,
+        (Opcode::CompareOp, Value::Str(_), Value::Str(_)) => Opcode::CompareOpStr,
+        (Opcode::CompareJumpIfFalse, Value::Int(_), Value::Int(_)) => Opcode::CompareJumpIfFalseInt,
+        (Opcode::CompareJumpIfFalse, Value::Float(_), Value::Float(_)) => {
+            Opcode::CompareJumpIfFalseFloat
+        }
+        (Opcode::CompareJumpIfFalse, Value::Str(_), Value::Str(_)) => Opcode::CompareJumpIfFalseStr,
+        (Opcode::CompareJumpIfTrue, Value::Int(_), Value::Int(_)) => Opcode::CompareJumpIfTrueInt,
+        (Opcode::CompareJumpIfTrue, Value::Float(_), Value::Float(_)) => {
+            Opcode::CompareJumpIfTrueFloat
+        }
+        (Opcode::CompareJumpIfTrue, Value::Str(_), Value::Str(_)) => Opcode::CompareJumpIfTrueStr,
+        _ => return None,
+    })
+}
+
+/// How the operands of a specialized comparison compare, or `None` if
+/// they are not of the kind it was specialized for.
+pub fn ordering(opcode: Opcode, left: &Value, right: &Value) -> Option<Option<Ordering>> {
+    match (opcode, left, right) {
+        (
+            Opcode::CompareOpInt | Opcode::CompareJumpIfFalseInt | Opcode::CompareJumpIfTrueInt,
+            Value::Int(a),
+            Value::Int(b),
+        ) => Some(Some(a.cmp(b))),
+        (
+            Opcode::CompareOpFloat
+            | Opcode::CompareJumpIfFalseFloat
+            | Opcode::CompareJumpIfTrueFloat,
+            Value::Float(a),
+            Value::Float(b),
+        ) => Some(a.partial_cmp(b)),
+        (
+            Opcode::CompareOpStr | Opcode::CompareJumpIfFalseStr | Opcode::CompareJumpIfTrueStr,
+            Value::Str(a),
+            Value::Str(b),
+        ) => Some(Some(a.cmp(b))),
+        _ => None,
+    }
+}
+
+/// The form of a call of `callee` with `nargs` positional arguments, if it
+/// has one.
+pub fn call(callee: &Value, nargs: usize) -> Option<Opcode> {
+    match callee {
+        Value::Function(function) if takes_exactly(function, nargs) => {
+            Some(Opcode::CallPyExactArgs)
+        }
+        Value::Method(function, _) if takes_exactly(function, nargs + 1) => {
+            Some(Opcode::CallMethodExactArgs)
+        }
+        Value::Builtin(_) => Some(Opcode::CallBuiltin),
+        _ => None,
+    }
+}
+
+/// Whether `function` takes exactly `nargs` positional arguments and no
+/// others, so that they are its first fast locals as they are.
+pub fn takes_exactly(function: &Function, nargs: usize) -> bool {
+    let code = &function.code;
+    code.argcount == nargs
+        && code.kwonlyargcount == 0
+        && code.flags & (CO_VARARGS | CO_VARKEYWORDS) == 0
+}
+
+/// The form of loading the attribute `name` of `owner`, if it has one,
+/// with the index it finds the attribute at: among the instance's own
+/// attributes, or its class's for a method.
+pub fn load_attr(owner: &Value, name: Symbol) -> Option<(Opcode, u16)> {
+    let Value::Instance(instance) = owner else {
+        return None;
+    };
+    let position = |attrs: &[(Symbol, Value)]| attrs.iter().position(|(attr, _)| *attr == name);
+    let (opcode, index) = match position(&instance.attrs.borrow()) {
+        Some(index) => (Opcode::LoadAttrInstance, index),
+        None => {
+            let attrs = instance.class.attrs.borrow();
+            let index = position(&attrs)?;
+            if !matches!(attrs[index].1, Value::Function(_)) {
+                return None;
+            }
+            (Opcode::LoadAttrMethod, index)
+        }
+    };
+    Some((opcode, u16::try_from(index).ok()?))
+}
+
+/// The attribute `name` of `owner` that a `LOAD_ATTR_INSTANCE` finds at
+/// `index` among the instance's own, if it is there.
+pub fn instance_attribute(owner: &Value, name: Symbol, index: usize) -> Option<Value> {
+    let Value::Instance(instance) = owner else {
+        return None;
+    };
+    match instance.attrs.borrow().get(index) {
+        Some((attr, value)) if *attr == name => Some(value.clone()),
+        _ => None,
+    }
+}
+
+/// The method `name` of `owner` that a `LOAD_ATTR_METHOD` finds at `index`
+/// among its class's attributes, bound to it, if it is there and the
+/// instance has no attribute of its own by that name.
+pub fn method(owner: &Value, name: Symbol, index: usize) -> Option<Value> {
+    let Value::Instance(instance) = owner else {
+        return None;
+    };
+    let Some((attr, Value::Function(function))) = instance.class.attrs.borrow().get(index).cloned()
+    else {
+        return None;
+    };
+    let shadowed = instance.attrs.borrow().iter().any(|(own, _)| *own == name);
+    (attr == name && !shadowed).then(|| Value::Method(function, instance.clone()))
+}
+
+/// The instructions specialized alike, which their statistics are kept
+/// for: each a generic instruction, or for `BINARY_OP`, `COMPARE_OP` and
+/// `CALL` a few, with their specialized forms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Family {
+    BinaryOp,
+    CompareOp,
+    LoadAttr,
+    LoadGlobal,
+    Call,
+}
+
+impl Family {
+    pub const ALL: [Family; 5] = [
+        Family::BinaryOp,
+        Family::CompareOp,
+        Family::LoadAttr,
+        Family::LoadGlobal,
+        Family::Call,
+    ];
+
+    /// The family of an instruction with an inline cache.
+    pub fn of(opcode: Opcode) -> Family {
+        match opcode.generic() {
+            Opcode::LoadAttr => Family::LoadAttr,
+            Opcode::LoadGlobal => Family::LoadGlobal,
+            Opcode::CallFunction => Family::Call,
+            Opcode::CompareOp | Opcode::CompareJumpIfFalse | Opcode::CompareJumpIfTrue => {
+                Family::CompareOp
+            }
+            generic if generic.operator().is_some() => Family::BinaryOp,
+            _ => unreachable!("{} is not specialized", opcode.name()),
+        }
+    }
+
+    /// The name of the family in CPython's statistics.
+    pub fn name(self) -> &'static str {
+        match self {
+            Family::BinaryOp => "BINARY_OP",
+            Family::CompareOp => "COMPARE_OP",
+            Family::LoadAttr => "LOAD_ATTR",
+            Family::LoadGlobal => "LOAD_GLOBAL",
+            Family::Call => "CALL",
+        }
+    }
+}
+
+/// How specialization has gone for a family of instructions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FamilyStats {
+    /// Times an instruction was specialized.
+    pub success: u64,
+    /// Times an instruction due to be specialized had no form for its
+    /// operands.
+    pub failure: u64,
+    /// Runs of specialized instructions whose guards held.
+    pub hit: u64,
+    /// Runs of specialized instructions whose guards failed, which ran the
+    /// generic instruction instead.
+    pub miss: u64,
+    /// Times a specialized instruction went back to its generic form.
+    pub deopt: u64,
+}
+
+/// How specialization has gone, for each [`Family`], as CPython's
+/// `--enable-pystats` counts it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    families: [FamilyStats; 5],
+}
+
+impl Stats {
+    pub fn family(&self, family: Family) -> &FamilyStats {
+        &self.families[family as usize]
+    }
+
+    /// Counts a run of a specialized instruction of `family` whose guards
+    /// held.
+    pub fn hit(&mut self, family: Family) {
+        self.families[family as usize].hit += 1;
+    }
+
+    /// Counts a run of a specialized instruction of `family` whose guards
+    /// failed.
+    pub fn miss(&mut self, family: Family) {
+        self.families[family as usize].miss += 1;
+    }
+
+    /// The statistics of the family of `opcode`.
+    pub fn family_mut(&mut self, opcode: Opcode) -> &mut FamilyStats {
+        &mut self.families[Family::of(opcode) as usize]
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<12} {:>10} {:>10} {:>12} {:>12} {:>10}",
+            "family", "success", "failure", "hit", "miss", "deopt"
+        )?;
+        for family in Family::ALL {
+            let stats = self.family(family);
+            writeln!(
+                f,
+                "{:<12} {:>10} {:>10} {:>12} {:>12} {:>10}",
+                family.name(),
+                stats.success,
+                stats.failure,
+                stats.hit,
+                stats.miss,
+                stats.deopt
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1589 @@
+//! Scope analysis: what each scope binds and where each name it uses is
+//! found.
+//!
+//! `symtable` builds one table per scope of a module, nested as the scopes
+//! are: the module, every function, class and lambda, every comprehension,
+//! and the annotation scopes PEP 695 evaluates type parameters and alias
+//! values in. Names are classified the way CPython's compiler classifies
+//! them, and the tables answer the questions Python's `symtable` module
+//! answers, under the same names less the `get_` prefix, so the two can be
+//! checked against each other.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::{
+    Arena, Arguments, Comprehension, ExceptHandler, ExprContext, ExprId, ExprKind, List, Module,
+    Span, StmtId, StmtKind, TypeParam, TypeParamKind,
+};
+use crate::intern::Symbol;
+use crate::parser::ParseError;
+use crate::visitor::{walk_expr, walk_stmt, Visitor};
+
+const DEF_GLOBAL: u32 = 1;
+const DEF_LOCAL: u32 = 2;
+const DEF_PARAM: u32 = 4;
+const DEF_NONLOCAL: u32 = 8;
+const USE: u32 = 16;
+/// A free variable of a method that the class also binds.
+const DEF_FREE_CLASS: u32 = 64;
+const DEF_IMPORT: u32 = 128;
+const DEF_ANNOT: u32 = 256;
+/// A comprehension's iteration variable.
+const DEF_COMP_ITER: u32 = 512;
+const DEF_TYPE_PARAM: u32 = 1024;
+const DEF_BOUND: u32 = DEF_LOCAL | DEF_PARAM | DEF_IMPORT;
+
+/// Where a name is looked up at run time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Local,
+    /// Declared `global`.
+    GlobalExplicit,
+    /// Bound neither here nor in an enclosing function.
+    GlobalImplicit,
+    /// Bound in an enclosing function.
+    Free,
+    /// Local, and used by a nested scope.
+    Cell,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableKind {
+    Module,
+    /// A function, lambda or comprehension.
+    Function,
+    Class,
+    /// The scope holding the type parameters of a generic definition.
+    TypeParams,
+    TypeVarBound,
+    TypeAlias,
+}
+
+impl TableKind {
+    /// The name Python's `SymbolTable.get_type` gives the kind.
+    pub fn name(self) -> &'static str {
+        match self {
+            TableKind::Module => "module",
+            TableKind::Function => "function",
+            TableKind::Class => "class",
+            TableKind::TypeParams => "type parameter",
+            TableKind::TypeVarBound => "TypeVar bound",
+            TableKind::TypeAlias => "type alias",
+        }
+    }
+
+    /// Whether names bound in the scope are fast locals, visible to the
+    /// scopes nested in it.
+    fn is_function_like(self) -> bool {
+        !matches!(self, TableKind::Module | TableKind::Class)
+    }
+}
+
+/// The node a scope belongs to. A generic definition owns two scopes, the
+/// one for its type parameters and its own, and each bound of its type
+/// parameters a third.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BlockKey {
+    Module,
+    /// A function, class or type alias.
+    Stmt(StmtId),
+    /// A lambda or comprehension.
+    Expr(ExprId),
+    TypeParams(StmtId),
+    /// The bound of the given type parameter of a definition.
+    TypeVarBound(StmtId, usize),
+}
+
+/// Returns the private name `name` is rewritten to inside the class named
+/// `private`: `__spam` becomes `_Ham__spam` in `class Ham`. Dunder names
+/// and dotted module names are left alone.
+pub fn mangle(private: Option<Symbol>, name: Symbol) -> Symbol {
+    let Some(private) = private else {
+        return name;
+    };
+    let text = name.as_str();
+    if !text.starts_with("__") || text.ends_with("__") || text.contains('.') {
+        return name;
+    }
+    let class = private.as_str().trim_start_matches('_');
+    if class.is_empty() {
+        return name;
+    }
+    Symbol::intern(&format!("_{}{}", class, text))
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    flags: u32,
+    scope: Scope,
+}
+
+#[derive(Debug)]
+struct Block {
+    kind: TableKind,
+    name: Symbol,
+    key: BlockKey,
+    line: usize,
+    /// Names in the order they were first seen, which is the order
+    /// `identifiers` returns them in.
+    names: Vec<Symbol>,
+    entries: HashMap<Symbol, Entry>,
+    /// The parameters, in the order they are passed.
+    varnames: Vec<Symbol>,
+    children: Vec<usize>,
+    /// Where each name was declared `global` or `nonlocal`.
+    directives: HashMap<Symbol, Span>,
+    nested: bool,
+    generator: bool,
+    coroutine: bool,
+    /// Whether an annotation scope in a class body sees the class's names.
+    can_see_class_scope: bool,
+    needs_class_closure: bool,
+    needs_classdict: bool,
+    /// Whether the scope has free variables, or a scope nested in it does.
+    free: bool,
+    child_free: bool,
+    /// Whether a comprehension's target is being visited.
+    comp_iter_target: bool,
+    /// How deep in comprehension iterables the visit is.
+    comp_iter_expr: usize,
+    comprehension: bool,
+}
+
+impl Block {
+    fn flags(&self, name: Symbol) -> u32 {
+        self.entries.get(&name).map_or(0, |entry| entry.flags)
+    }
+
+    fn set_flags(&mut self, name: Symbol, flags: u32) {
+        match self.entries.get_mut(&name) {
+            Some(entry) => entry.flags = flags,
+            None => {
+                self.names.push(name);
+                self.entries.insert(
+                    name,
+                    Entry {
+                        flags,
+                        scope: Scope::Local,
+                    },
+                );
+            }
+        }
+    }
+}
+
+/// The scopes of a module.
+#[derive(Debug)]
+pub struct SymbolTable {
+    blocks: Vec<Block>,
+    keys: HashMap<BlockKey, usize>,
+}
+
+impl SymbolTable {
+    /// The module's own table.
+    pub fn top(&self) -> Table<'_> {
+        Table {
+            symtable: self,
+            id: 0,
+        }
+    }
+
+    pub fn get(&self, key: BlockKey) -> Option<Table<'_>> {
+        self.keys.get(&key).map(|&id| Table { symtable: self, id })
+    }
+}
+
+/// Builds the symbol table of `module`, or returns the first error in its
+/// `global` and `nonlocal` declarations, parameter lists and assignment
+/// expressions.
+pub fn symtable(module: &Module) -> Result<SymbolTable, ParseError> {
+    let mut builder = Builder {
+        blocks: Vec::new(),
+        stack: Vec::new(),
+        private: None,
+        error: None,
+    };
+    builder.enter(
+        TableKind::Module,
+        Symbol::intern("top"),
+        BlockKey::Module,
+        0,
+    );
+    builder.visit_module(module);
+    if let Some(error) = builder.error {
+        return Err(error);
+    }
+    let mut analyzer = Analyzer {
+        blocks: builder.blocks,
+    };
+    analyzer.analyze_block(0, None, HashSet::new(), None)?;
+    let keys = analyzer
+        .blocks
+        .iter()
+        .enumerate()
+        .map(|(id, block)| (block.key, id))
+        .collect();
+    Ok(SymbolTable {
+        blocks: analyzer.blocks,
+        keys,
+    })
+}
+
+/// A view of one scope.
+#[derive(Debug, Clone, Copy)]
+pub struct Table<'a> {
+    symtable: &'a SymbolTable,
+    id: usize,
+}
+
+impl<'a> Table<'a> {
+    fn block(&self) -> &'a Block {
+        &self.symtable.blocks[self.id]
+    }
+
+    fn names_where(&self, f: impl Fn(Entry) -> bool) -> Vec<Symbol> {
+        let block = self.block();
+        block
+            .names
+            .iter()
+            .copied()
+            .filter(|name| f(block.entries[name]))
+            .collect()
+    }
+
+    pub fn kind(&self) -> TableKind {
+        self.block().kind
+    }
+
+    /// A number identifying the table within its symbol table.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn key(&self) -> BlockKey {
+        self.block().key
+    }
+
+    /// The name of the function, class or type alias, `lambda`, one of
+    /// `listcomp`, `setcomp`, `dictcomp` and `genexpr`, or `top`.
+    pub fn name(&self) -> Symbol {
+        self.block().name
+    }
+
+    pub fn lineno(&self) -> usize {
+        self.block().line
+    }
+
+    pub fn is_optimized(&self) -> bool {
+        self.kind().is_function_like()
+    }
+
+    pub fn is_nested(&self) -> bool {
+        self.block().nested
+    }
+
+    pub fn has_children(&self) -> bool {
+        !self.block().children.is_empty()
+    }
+
+    pub fn is_generator(&self) -> bool {
+        self.block().generator
+    }
+
+    pub fn is_coroutine(&self) -> bool {
+        self.block().coroutine
+    }
+
+    /// Whether the class's methods use `__class__`, directly or through
+    /// `super`, so that the class body must create the cell.
+    pub fn needs_class_closure(&self) -> bool {
+        self.block().needs_class_closure
+    }
+
+    /// Every name the scope uses or binds, in the order they appear.
+    pub fn identifiers(&self) -> impl Iterator<Item = Symbol> + 'a {
+        self.block().names.iter().copied()
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<Binding<'a>> {
+        let name = Symbol::intern(name);
+        let entry = *self.block().entries.get(&name)?;
+        Some(Binding {
+            table: *self,
+            name,
+            entry,
+        })
+    }
+
+    pub fn symbols(&self) -> impl Iterator<Item = Binding<'a>> + 'a {
+        let table = *self;
+        let block = self.block();
+        block.names.iter().map(move |&name| Binding {
+            table,
+            name,
+            entry: block.entries[&name],
+        })
+    }
+
+    pub fn children(&self) -> impl Iterator<Item = Table<'a>> + 'a {
+        let symtable = self.symtable;
+        self.block()
+            .children
+            .iter()
+            .map(move |&id| Table { symtable, id })
+    }
+
+    /// The parameters, in the order `identifiers` lists them.
+    pub fn parameters(&self) -> Vec<Symbol> {
+        self.names_where(|entry| entry.flags & DEF_PARAM != 0)
+    }
+
+    /// The parameters, in the order they are passed.
+    pub fn varnames(&self) -> &'a [Symbol] {
+        &self.block().varnames
+    }
+
+    pub fn locals(&self) -> Vec<Symbol> {
+        self.names_where(|entry| matches!(entry.scope, Scope::Local | Scope::Cell))
+    }
+
+    pub fn globals(&self) -> Vec<Symbol> {
+        self.names_where(|entry| {
+            matches!(entry.scope, Scope::GlobalExplicit | Scope::GlobalImplicit)
+        })
+    }
+
+    pub fn nonlocals(&self) -> Vec<Symbol> {
+        self.names_where(|entry| entry.flags & DEF_NONLOCAL != 0)
+    }
+
+    pub fn frees(&self) -> Vec<Symbol> {
+        self.names_where(|entry| entry.scope == Scope::Free)
+    }
+
+    /// The names of the scopes nested in a class body, each once.
+    pub fn methods(&self) -> Vec<Symbol> {
+        let mut methods = Vec::new();
+        for child in self.children() {
+            if !methods.contains(&child.name()) {
+                methods.push(child.name());
+            }
+        }
+        methods
+    }
+}
+
+/// What a scope knows about one of its names.
+#[derive(Debug, Clone, Copy)]
+pub struct Binding<'a> {
+    table: Table<'a>,
+    name: Symbol,
+    entry: Entry,
+}
+
+impl<'a> Binding<'a> {
+    fn has(&self, flag: u32) -> bool {
+        self.entry.flags & flag != 0
+    }
+
+    /// Names bound at module level are both local and global.
+    fn module_bound(&self) -> bool {
+        self.table.kind() == TableKind::Module && self.has(DEF_BOUND)
+    }
+
+    pub fn name(&self) -> Symbol {
+        self.name
+    }
+
+    pub fn scope(&self) -> Scope {
+        self.entry.scope
+    }
+
+    pub fn is_referenced(&self) -> bool {
+        self.has(USE)
+    }
+
+    pub fn is_parameter(&self) -> bool {
+        self.has(DEF_PARAM)
+    }
+
+    pub fn is_type_parameter(&self) -> bool {
+        self.has(DEF_TYPE_PARAM)
+    }
+
+    pub fn is_global(&self) -> bool {
+        matches!(self.scope(), Scope::GlobalExplicit | Scope::GlobalImplicit) || self.module_bound()
+    }
+
+    pub fn is_nonlocal(&self) -> bool {
+        self.has(DEF_NONLOCAL)
+    }
+
+    pub fn is_declared_global(&self) -> bool {
+        self.scope() == Scope::GlobalExplicit
+    }
+
+    pub fn is_local(&self) -> bool {
+        matches!(self.scope(), Scope::Local | Scope::Cell) || self.module_bound()
+    }
+
+    pub fn is_annotated(&self) -> bool {
+        self.has(DEF_ANNOT)
+    }
+
+    pub fn is_free(&self) -> bool {
+        self.scope() == Scope::Free
+    }
+
+    pub fn is_cell(&self) -> bool {
+        self.scope() == Scope::Cell
+    }
+
+    /// Whether a class's method needs this name from an enclosing function
+    /// although the class body binds it too.
+    pub fn is_free_class(&self) -> bool {
+        self.has(DEF_FREE_CLASS)
+    }
+
+    pub fn is_imported(&self) -> bool {
+        self.has(DEF_IMPORT)
+    }
+
+    pub fn is_assigned(&self) -> bool {
+        self.has(DEF_LOCAL)
+    }
+
+    /// Whether the name is bound by a definition with a scope of its own.
+    pub fn is_namespace(&self) -> bool {
+        self.table.children().any(|child| child.name() == self.name)
+    }
+
+    /// The scopes of the definitions binding the name.
+    pub fn namespaces(&self) -> Vec<Table<'a>> {
+        self.table
+            .children()
+            .filter(|child| child.name() == self.name)
+            .collect()
+    }
+}
+
+/// The first pass: records, scope by scope, how each name is used.
+struct Builder {
+    blocks: Vec<Block>,
+    /// The scopes being visited, innermost last.
+    stack: Vec<usize>,
+    /// The class whose names are mangled.
+    private: Option<Symbol>,
+    error: Option<ParseError>,
+}
+
+impl Builder {
+    fn current(&self) -> usize {
+        *self.stack.last().unwrap()
+    }
+
+    fn cur(&mut self) -> &mut Block {
+        let id = self.current();
+        &mut self.blocks[id]
+    }
+
+    fn error(&mut self, message: String, span: Span) {
+        if self.error.is_none() {
+            self.error = Some(ParseError::new(message, span));
+        }
+    }
+
+    fn enter(&mut self, kind: TableKind, name: Symbol, key: BlockKey, line: usize) {
+        let id = self.blocks.len();
+        let nested = match self.stack.last() {
+            Some(&parent) => {
+                let parent = &mut self.blocks[parent];
+                parent.children.push(id);
+                parent.nested || parent.kind.is_function_like()
+            }
+            None => false,
+        };
+        self.blocks.push(Block {
+            kind,
+            name,
+            key,
+            line,
+            names: Vec::new(),
+            entries: HashMap::new(),
+            varnames: Vec::new(),
+            children: Vec::new(),
+            directives: HashMap::new(),
+            nested,
+            generator: false,
+            coroutine: false,
+            can_see_class_scope: false,
+            needs_class_closure: false,
+            needs_classdict: false,
+            free: false,
+            child_free: false,
+            comp_iter_target: false,
+            comp_iter_expr: 0,
+            comprehension: false,
+        });
+        self.stack.push(id);
+    }
+
+    fn exit(&mut self) {
+        self.stack.pop();
+    }
+
+    fn lookup(&self, name: Symbol) -> u32 {
+        self.blocks[self.current()].flags(mangle(self.private, name))
+    }
+
+    fn add_def(&mut self, name: Symbol, flag: u32, span: Span) {
+        self.add_def_in(self.current(), name, flag, span)
+    }
+
+    fn add_def_in(&mut self, id: usize, name: Symbol, flag: u32, span: Span) {
+        let mangled = mangle(self.private, name);
+        let block = &self.blocks[id];
+        let old = block.flags(mangled);
+        if flag & DEF_PARAM != 0 && old & DEF_PARAM != 0 {
+            return self.error(
+                format!("duplicate argument '{}' in function definition", name),
+                span,
+            );
+        }
+        let mut flags = old | flag;
+        if block.comp_iter_target {
+            if flags & (DEF_GLOBAL | DEF_NONLOCAL) != 0 {
+                return self.error(
+                    format!(
+                        "comprehension inner loop cannot rebind assignment expression target '{}'",
+                        name
+                    ),
+                    span,
+                );
+            }
+            flags |= DEF_COMP_ITER;
+        }
+        let block = &mut self.blocks[id];
+        block.set_flags(mangled, flags);
+        if flag & DEF_PARAM != 0 {
+            block.varnames.push(mangled);
+        } else if flag & DEF_GLOBAL != 0 {
+            // The module learns of the names its functions declare global.
+            let module = &mut self.blocks[0];
+            let flags = module.flags(mangled) | flag;
+            module.set_flags(mangled, flags);
+        }
+    }
+
+    fn record_directive(&mut self, name: Symbol, span: Span) {
+        let name = mangle(self.private, name);
+        self.cur().directives.entry(name).or_insert(span);
+    }
+
+    fn add_params(&mut self, arena: &Arena, arguments: &Arguments) {
+        for arg in arguments.params(arena) {
+            self.add_def(arg.arg, DEF_PARAM, arg.span);
+        }
+    }
+
+    /// Visits the annotations of a function's parameters and its return
+    /// annotation, in the order CPython does.
+    fn annotations(&mut self, arena: &Arena, arguments: &Arguments, returns: Option<ExprId>) {
+        let annotations = arena[arguments.posonlyargs]
+            .iter()
+            .chain(&arena[arguments.args])
+            .chain(&arguments.vararg)
+            .chain(&arguments.kwarg)
+            .chain(&arena[arguments.kwonlyargs])
+            .filter_map(|arg| arg.annotation)
+            .chain(returns);
+        for annotation in annotations {
+            self.visit_expr(arena, annotation);
+        }
+    }
+
+    /// Enters the scope holding the type parameters of the definition
+    /// `stmt`. A generic class stores its parameters and its `Generic`
+    /// base there, a generic function its default values.
+    fn enter_type_params(
+        &mut self,
+        arena: &Arena,
+        stmt: StmtId,
+        name: Symbol,
+        arguments: Option<&Arguments>,
+        span: Span,
+    ) {
+        let in_class = self.blocks[self.current()].kind == TableKind::Class;
+        self.enter(
+            TableKind::TypeParams,
+            name,
+            BlockKey::TypeParams(stmt),
+            span.line,
+        );
+        if in_class {
+            self.cur().can_see_class_scope = true;
+            self.add_def(Symbol::intern("__classdict__"), USE, span);
+        }
+        match arguments {
+            None => {
+                let type_params = Symbol::intern(".type_params");
+                self.add_def(type_params, DEF_LOCAL, span);
+                self.add_def(type_params, USE, span);
+                self.private = Some(name);
+                let generic_base = Symbol::intern(".generic_base");
+                self.add_def(generic_base, DEF_LOCAL, span);
+                self.add_def(generic_base, USE, span);
+            }
+            Some(arguments) => {
+                if !arguments.defaults.is_empty() {
+                    self.add_def(Symbol::intern(".defaults"), DEF_PARAM, span);
+                }
+                if arena[arguments.kw_defaults].iter().any(Option::is_some) {
+                    self.add_def(Symbol::intern(".kwdefaults"), DEF_PARAM, span);
+                }
+            }
+        }
+    }
+
+    fn type_params(&mut self, arena: &Arena, stmt: StmtId, type_params: List<TypeParam>) {
+        for (i, type_param) in arena[type_params].iter().enumerate() {
+            let name = type_param.name();
+            self.add_def(name, DEF_TYPE_PARAM | DEF_LOCAL, type_param.span);
+            if let TypeParamKind::TypeVar {
+                bound: Some(bound), ..
+            } = type_param.kind
+            {
+                let in_class = self.blocks[self.current()].can_see_class_scope;
+                self.enter(
+                    TableKind::TypeVarBound,
+                    name,
+                    BlockKey::TypeVarBound(stmt, i),
+                    type_param.span.line,
+                );
+                self.cur().can_see_class_scope = in_class;
+                if in_class {
+                    self.add_def(Symbol::intern("__classdict__"), USE, arena[bound].span);
+                }
+                self.visit_expr(arena, bound);
+                self.exit();
+            }
+        }
+    }
+
+    fn function(&mut self, arena: &Arena, stmt: StmtId) {
+        let span = arena[stmt].span;
+        let (StmtKind::FunctionDef {
+            name,
+            args,
+            body,
+            decorator_list,
+            returns,
+            type_params,
+        }
+        | StmtKind::AsyncFunctionDef {
+            name,
+            args,
+            body,
+            decorator_list,
+            returns,
+            type_params,
+        }) = &arena[stmt].kind
+        else {
+            unreachable!()
+        };
+        self.add_def(*name, DEF_LOCAL, span);
+        for default in args.default_values(arena) {
+            self.visit_expr(arena, default);
+        }
+        let generic = !type_params.is_empty();
+        if generic {
+            for &decorator in &arena[*decorator_list] {
+                self.visit_expr(arena, decorator);
+            }
+            self.enter_type_params(arena, stmt, *name, Some(args), span);
+            self.type_params(arena, stmt, *type_params);
+            self.annotations(arena, args, *returns);
+        } else {
+            self.annotations(arena, args, *returns);
+            for &decorator in &arena[*decorator_list] {
+                self.visit_expr(arena, decorator);
+            }
+        }
+        self.enter(TableKind::Function, *name, BlockKey::Stmt(stmt), span.line);
+        if matches!(arena[stmt].kind, StmtKind::AsyncFunctionDef { .. }) {
+            self.cur().coroutine = true;
+        }
+        self.add_params(arena, args);
+        for &stmt in &arena[*body] {
+            self.visit_stmt(arena, stmt);
+        }
+        self.exit();
+        if generic {
+            self.exit();
+        }
+    }
+
+    fn class(&mut self, arena: &Arena, stmt: StmtId) {
+        let span = arena[stmt].span;
+        let StmtKind::ClassDef {
+            name,
+            bases,
+            keywords,
+            body,
+            decorator_list,
+            type_params,
+        } = &arena[stmt].kind
+        else {
+            unreachable!()
+        };
+        self.add_def(*name, DEF_LOCAL, span);
+        let private = self.private;
+        let generic = !type_params.is_empty();
+        if generic {
+            for &decorator in &arena[*decorator_list] {
+                self.visit_expr(arena, decorator);
+            }
+            self.enter_type_params(arena, stmt, *name, None, span);
+            self.private = Some(*name);
+            self.type_params(arena, stmt, *type_params);
+        }
+        for &base in &arena[*bases] {
+            self.visit_expr(arena, base);
+        }
+        for keyword in &arena[*keywords] {
+            self.visit_keyword(arena, keyword);
+        }
+        if !generic {
+            for &decorator in &arena[*decorator_list] {
+                self.visit_expr(arena, decorator);
+            }
+        }
+        self.enter(TableKind::Class, *name, BlockKey::Stmt(stmt), span.line);
+        self.private = Some(*name);
+        if generic {
+            self.add_def(Symbol::intern("__type_params__"), DEF_LOCAL, span);
+            self.add_def(Symbol::intern(".type_params"), USE, span);
+        }
+        for &stmt in &arena[*body] {
+            self.visit_stmt(arena, stmt);
+        }
+        self.exit();
+        if generic {
+            self.exit();
+        }
+        self.private = private;
+    }
+
+    fn type_alias(&mut self, arena: &Arena, stmt: StmtId) {
+        let span = arena[stmt].span;
+        let StmtKind::TypeAlias {
+            name,
+            type_params,
+            value,
+        } = &arena[stmt].kind
+        else {
+            unreachable!()
+        };
+        self.visit_expr(arena, *name);
+        let ExprKind::Name { id: name, .. } = arena[*name].kind else {
+            unreachable!()
+        };
+        let in_class = self.blocks[self.current()].kind == TableKind::Class;
+        let generic = !type_params.is_empty();
+        if generic {
+            self.enter_type_params(arena, stmt, name, Some(&Arguments::default()), span);
+            self.type_params(arena, stmt, *type_params);
+        }
+        self.enter(TableKind::TypeAlias, name, BlockKey::Stmt(stmt), span.line);
+        self.cur().can_see_class_scope = in_class;
+        if in_class {
+            self.add_def(Symbol::intern("__classdict__"), USE, arena[*value].span);
+        }
+        self.visit_expr(arena, *value);
+        self.exit();
+        if generic {
+            self.exit();
+        }
+    }
+
+    /// Declares the names of a `global` or `nonlocal` statement, which must
+    /// come before any other use of them in the scope.
+    fn declare(&mut self, names: &[Symbol], flag: u32, span: Span) {
+        let kind = if flag == DEF_GLOBAL {
+            "global"
+        } else {
+            "nonlocal"
+        };
+        for &name in names {
+            let flags = self.lookup(name);
+            if flags & (DEF_PARAM | DEF_LOCAL | USE | DEF_ANNOT) != 0 {
+                let message = if flags & DEF_PARAM != 0 {
+                    format!("name '{}' is parameter and {}", name, kind)
+                } else if flags & USE != 0 {
+                    format!("name '{}' is used prior to {} declaration", name, kind)
+                } else if flags & DEF_ANNOT != 0 {
+                    format!("annotated name '{}' can't be {}", name, kind)
+                } else {
+                    format!("name '{}' is assigned to before {} declaration", name, kind)
+                };
+                return self.error(message, span);
+            }
+            self.add_def(name, flag, span);
+            self.record_directive(name, span);
+        }
+    }
+
+    /// An assignment expression in a comprehension binds its target in the
+    /// nearest enclosing scope that is not a comprehension.
+    fn extend_named_expr_scope(&mut self, arena: &Arena, target: ExprId) {
+        let span = arena[target].span;
+        let ExprKind::Name { id: name, .. } = arena[target].kind else {
+            return;
+        };
+        for &id in self.stack.clone().iter().rev() {
+            let block = &self.blocks[id];
+            if block.comprehension {
+                if block.flags(name) & DEF_COMP_ITER != 0 {
+                    return self.error(
+                        format!(
+                            "assignment expression cannot rebind comprehension iteration variable '{}'",
+                            name
+                        ),
+                        span,
+                    );
+                }
+                continue;
+            }
+            match block.kind {
+                TableKind::Function => {
+                    let flag = if block.flags(name) & DEF_GLOBAL != 0 {
+                        DEF_GLOBAL
+                    } else {
+                        DEF_NONLOCAL
+                    };
+                    self.add_def(name, flag, span);
+                    self.record_directive(name, span);
+                    return self.add_def_in(id, name, DEF_LOCAL, span);
+                }
+                TableKind::Module => {
+                    self.add_def(name, DEF_GLOBAL, span);
+                    self.record_directive(name, span);
+                    return self.add_def_in(id, name, DEF_GLOBAL, span);
+                }
+                TableKind::Class => {
+                    return self.error(
+                        "assignment expression within a comprehension cannot be used in a class body"
+                            .to_string(),
+                        span,
+                    )
+                }
+                _ => {
+                    return self.error(
+                        "assignment expression within a comprehension cannot be used within the definition of a generic"
+                            .to_string(),
+                        span,
+                    )
+                }
+            }
+        }
+    }
+
+    /// A comprehension runs in a function scope of its own, which receives
+    /// the first iterable, evaluated outside, as its parameter `.0`.
+    fn comprehension(
+        &mut self,
+        arena: &Arena,
+        expr: ExprId,
+        name: &str,
+        generators: List<Comprehension>,
+        elt: ExprId,
+        value: Option<ExprId>,
+    ) {
+        let is_generator = matches!(arena[expr].kind, ExprKind::GeneratorExp { .. });
+        let (first, rest) = arena[generators].split_first().unwrap();
+        self.cur().comp_iter_expr += 1;
+        self.visit_expr(arena, first.iter);
+        self.cur().comp_iter_expr -= 1;
+        let span = arena[expr].span;
+        self.enter(
+            TableKind::Function,
+            Symbol::intern(name),
+            BlockKey::Expr(expr),
+            span.line,
+        );
+        self.cur().comprehension = true;
+        if first.is_async {
+            self.cur().coroutine = true;
+        }
+        self.add_def(Symbol::intern(".0"), DEF_PARAM, span);
+        self.cur().comp_iter_target = true;
+        self.visit_expr(arena, first.target);
+        self.cur().comp_iter_target = false;
+        for &condition in &arena[first.ifs] {
+            self.visit_expr(arena, condition);
+        }
+        for comprehension in rest {
+            self.visit_comprehension(arena, comprehension);
+        }
+        if let Some(value) = value {
+            self.visit_expr(arena, value);
+        }
+        self.visit_expr(arena, elt);
+        self.cur().generator = is_generator;
+        let is_async = self.cur().coroutine && !is_generator;
+        self.exit();
+        if is_async {
+            self.cur().coroutine = true;
+        }
+    }
+}
+
+impl<'ast> Visitor<'ast> for Builder {
+    fn visit_stmt(&mut self, arena: &'ast Arena, stmt: StmtId) {
+        let span = arena[stmt].span;
+        match &arena[stmt].kind {
+            StmtKind::FunctionDef { .. } | StmtKind::AsyncFunctionDef { .. } => {
+                self.function(arena, stmt)
+            }
+            StmtKind::ClassDef { .. } => self.class(arena, stmt),
+            StmtKind::TypeAlias { .. } => self.type_alias(arena, stmt),
+            StmtKind::AnnAssign {
+                target,
+                annotation,
+                value,
+                simple,
+            } => {
+                if let ExprKind::Name { id, .. } = arena[*target].kind {
+                    let flags = self.lookup(id);
+                    if flags & (DEF_GLOBAL | DEF_NONLOCAL) != 0 && self.current() != 0 && *simple {
+                        let kind = if flags & DEF_GLOBAL != 0 {
+                            "global"
+                        } else {
+                            "nonlocal"
+                        };
+                        return self.error(
+                            format!("annotated name '{}' can't be {}", id, kind),
+                            arena[*target].span,
+                        );
+                    }
+                    if *simple {
+                        self.add_def(id, DEF_ANNOT | DEF_LOCAL, arena[*target].span);
+                    } else if value.is_some() {
+                        self.add_def(id, DEF_LOCAL, arena[*target].span);
+                    }
+                } else {
+                    self.visit_expr(arena, *target);
+                }
+                self.visit_expr(arena, *annotation);
+                if let Some(value) = value {
+                    self.visit_expr(arena, *value);
+                }
+            }
+            StmtKind::Try {
+                body,
+                handlers,
+                orelse,
+                finalbody,
+            } => {
+                let visit_body = |this: &mut Self, body: List<StmtId>| {
+                    for &stmt in &arena[body] {
+                        this.visit_stmt(arena, stmt);
+                    }
+                };
+                visit_body(self, *body);
+                visit_body(self, *orelse);
+                for handler in &arena[*handlers] {
+                    self.visit_except_handler(arena, handler);
+                }
+                visit_body(self, *finalbody);
+            }
+            StmtKind::Import { names } | StmtKind::ImportFrom { names, .. } => {
+                for alias in &arena[*names] {
+                    let name = alias.asname.unwrap_or(alias.name);
+                    if name.as_str() == "*" {
+                        if self.blocks[self.current()].kind != TableKind::Module {
+                            return self
+                                .error("import * only allowed at module level".to_string(), span);
+                        }
+                        continue;
+                    }
+                    // `import a.b` binds `a`.
+                    let store = match name.as_str().split_once('.') {
+                        Some((first, _)) => Symbol::intern(first),
+                        None => name,
+                    };
+                    self.add_def(store, DEF_IMPORT, span);
+                }
+            }
+            StmtKind::Global { names } => self.declare(&arena[*names], DEF_GLOBAL, span),
+            StmtKind::Nonlocal { names } => self.declare(&arena[*names], DEF_NONLOCAL, span),
+            _ => walk_stmt(self, arena, stmt),
+        }
+    }
+
+    fn visit_expr(&mut self, arena: &'ast Arena, expr: ExprId) {
+        let span = arena[expr].span;
+        match &arena[expr].kind {
+            ExprKind::Name { id, ctx } => {
+                let flag = if *ctx == ExprContext::Load {
+                    USE
+                } else {
+                    DEF_LOCAL
+                };
+                self.add_def(*id, flag, span);
+                // `super()` finds the class through the `__class__` cell.
+                if *ctx == ExprContext::Load
+                    && self.blocks[self.current()].kind == TableKind::Function
+                    && id.as_str() == "super"
+                {
+                    self.add_def(Symbol::intern("__class__"), USE, span);
+                }
+            }
+            ExprKind::NamedExpr { target, value } => {
+                if self.blocks[self.current()].comp_iter_expr > 0 {
+                    return self.error(
+                        "assignment expression cannot be used in a comprehension iterable expression"
+                            .to_string(),
+                        span,
+                    );
+                }
+                if self.blocks[self.current()].comprehension {
+                    self.extend_named_expr_scope(arena, *target);
+                }
+                self.visit_expr(arena, *value);
+                self.visit_expr(arena, *target);
+            }
+            ExprKind::Lambda { args, body } => {
+                for default in args.default_values(arena) {
+                    self.visit_expr(arena, default);
+                }
+                self.enter(
+                    TableKind::Function,
+                    Symbol::intern("lambda"),
+                    BlockKey::Expr(expr),
+                    span.line,
+                );
+                self.add_params(arena, args);
+                self.visit_expr(arena, *body);
+                self.exit();
+            }
+            ExprKind::ListComp { elt, generators } => {
+                self.comprehension(arena, expr, "listcomp", *generators, *elt, None)
+            }
+            ExprKind::SetComp { elt, generators } => {
+                self.comprehension(arena, expr, "setcomp", *generators, *elt, None)
+            }
+            ExprKind::GeneratorExp { elt, generators } => {
+                self.comprehension(arena, expr, "genexpr", *generators, *elt, None)
+            }
+            ExprKind::DictComp {
+                key,
+                value,
+                generators,
+            } => self.comprehension(arena, expr, "dictcomp", *generators, *key, Some(*value)),
+            ExprKind::Await { .. } => {
+                walk_expr(self, arena, expr);
+                self.cur().coroutine = true;
+            }
+            ExprKind::Yield { .. } | ExprKind::YieldFrom { .. } => {
+                walk_expr(self, arena, expr);
+                self.cur().generator = true;
+            }
+            _ => walk_expr(self, arena, expr),
+        }
+    }
+
+    fn visit_except_handler(&mut self, arena: &'ast Arena, handler: &'ast ExceptHandler) {
+        if let Some(type_) = handler.type_ {
+            self.visit_expr(arena, type_);
+        }
+        if let Some(name) = handler.name {
+            self.add_def(name, DEF_LOCAL, handler.span);
+        }
+        for &stmt in &arena[handler.body] {
+            self.visit_stmt(arena, stmt);
+        }
+    }
+
+    fn visit_comprehension(&mut self, arena: &'ast Arena, comprehension: &'ast Comprehension) {
+        self.cur().comp_iter_target = true;
+        self.visit_expr(arena, comprehension.target);
+        self.cur().comp_iter_target = false;
+        self.cur().comp_iter_expr += 1;
+        self.visit_expr(arena, comprehension.iter);
+        self.cur().comp_iter_expr -= 1;
+        for &condition in &arena[comprehension.ifs] {
+            self.visit_expr(arena, condition);
+        }
+        if comprehension.is_async {
+            self.cur().coroutine = true;
+        }
+    }
+}
+
+/// The second pass: decides, from the outside in, the scope of every name,
+/// and passes the free variables of nested scopes back out.
+struct Analyzer {
+    blocks: Vec<Block>,
+}
+
+impl Analyzer {
+    fn error_at_directive(&self, id: usize, name: Symbol, message: String) -> ParseError {
+        let span = self.blocks[id]
+            .directives
+            .get(&name)
+            .copied()
+            .unwrap_or_default();
+        ParseError::new(message, span)
+    }
+
+    /// Analyzes the scope `id`, given the names bound by the functions
+    /// enclosing it (none at module level) and the names declared global
+    /// around it, and returns its free variables, including the ones its
+    /// nested scopes take from outside it.
+    fn analyze_block(
+        &mut self,
+        id: usize,
+        mut bound: Option<HashSet<Symbol>>,
+        mut global: HashSet<Symbol>,
+        class_entry: Option<usize>,
+    ) -> Result<HashSet<Symbol>, ParseError> {
+        let kind = self.blocks[id].kind;
+        let mut scopes = HashMap::new();
+        let mut local = HashSet::new();
+        let mut free = HashSet::new();
+        let mut new_bound = HashSet::new();
+        let mut new_global = HashSet::new();
+        // A class's names are not visible in its methods, so what the class
+        // passes down is what it was given.
+        if kind == TableKind::Class {
+            new_global.extend(global.iter().copied());
+            new_bound.extend(bound.iter().flatten().copied());
+        }
+        for i in 0..self.blocks[id].names.len() {
+            let name = self.blocks[id].names[i];
+            let flags = self.blocks[id].entries[&name].flags;
+            let scope = self.analyze_name(
+                id,
+                name,
+                flags,
+                bound.as_mut(),
+                &mut local,
+                &mut free,
+                &mut global,
+                class_entry,
+            )?;
+            scopes.insert(name, scope);
+        }
+        if kind == TableKind::Class {
+            new_bound.insert(Symbol::intern("__class__"));
+            new_bound.insert(Symbol::intern("__classdict__"));
+        } else {
+            if kind.is_function_like() {
+                new_bound.extend(local.iter().copied());
+            }
+            new_bound.extend(bound.iter().flatten().copied());
+            new_global.extend(global.iter().copied());
+        }
+        let mut new_free = HashSet::new();
+        for child in self.blocks[id].children.clone() {
+            let child_class_entry = if !self.blocks[child].can_see_class_scope {
+                None
+            } else if kind == TableKind::Class {
+                Some(id)
+            } else {
+                class_entry
+            };
+            let child_free = self.analyze_block(
+                child,
+                Some(new_bound.clone()),
+                new_global.clone(),
+                child_class_entry,
+            )?;
+            new_free.extend(child_free);
+            if self.blocks[child].free || self.blocks[child].child_free {
+                self.blocks[id].child_free = true;
+            }
+        }
+        if kind.is_function_like() {
+            // A local that a nested scope uses lives in a cell.
+            for (name, scope) in scopes.iter_mut() {
+                if *scope == Scope::Local && new_free.remove(name) {
+                    *scope = Scope::Cell;
+                }
+            }
+        } else if kind == TableKind::Class {
+            if new_free.remove(&Symbol::intern("__class__")) {
+                self.blocks[id].needs_class_closure = true;
+            }
+            if new_free.remove(&Symbol::intern("__classdict__")) {
+                self.blocks[id].needs_classdict = true;
+            }
+        }
+        self.update_symbols(id, &scopes, bound.as_ref(), &new_free);
+        free.extend(new_free);
+        Ok(free)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn analyze_name(
+        &mut self,
+        id: usize,
+        name: Symbol,
+        flags: u32,
+        bound: Option<&mut HashSet<Symbol>>,
+        local: &mut HashSet<Symbol>,
+        free: &mut HashSet<Symbol>,
+        global: &mut HashSet<Symbol>,
+        class_entry: Option<usize>,
+    ) -> Result<Scope, ParseError> {
+        if flags & DEF_GLOBAL != 0 {
+            if flags & DEF_NONLOCAL != 0 {
+                return Err(self.error_at_directive(
+                    id,
+                    name,
+                    format!("name '{}' is nonlocal and global", name),
+                ));
+            }
+            global.insert(name);
+            if let Some(bound) = bound {
+                bound.remove(&name);
+            }
+            return Ok(Scope::GlobalExplicit);
+        }
+        if flags & DEF_NONLOCAL != 0 {
+            let Some(bound) = bound else {
+                return Err(self.error_at_directive(
+                    id,
+                    name,
+                    "nonlocal declaration not allowed at module level".to_string(),
+                ));
+            };
+            if !bound.contains(&name) {
+                return Err(self.error_at_directive(
+                    id,
+                    name,
+                    format!("no binding for nonlocal '{}' found", name),
+                ));
+            }
+            self.blocks[id].free = true;
+            free.insert(name);
+            return Ok(Scope::Free);
+        }
+        if flags & DEF_BOUND != 0 {
+            local.insert(name);
+            global.remove(&name);
+            return Ok(Scope::Local);
+        }
+        // An annotation scope in a class body looks in the class first.
+        if let Some(class) = class_entry {
+            let class_flags = self.blocks[class].flags(name);
+            if class_flags & DEF_GLOBAL != 0 {
+                return Ok(Scope::GlobalExplicit);
+            }
+            if class_flags & DEF_BOUND != 0 && class_flags & DEF_NONLOCAL == 0 {
+                return Ok(Scope::GlobalImplicit);
+            }
+        }
+        if bound.is_some_and(|bound| bound.contains(&name)) {
+            self.blocks[id].free = true;
+            free.insert(name);
+            return Ok(Scope::Free);
+        }
+        if global.contains(&name) {
+            return Ok(Scope::GlobalImplicit);
+        }
+        if self.blocks[id].nested {
+            self.blocks[id].free = true;
+        }
+        Ok(Scope::GlobalImplicit)
+    }
+
+    /// Records the scopes decided for the block's names, and adds the free
+    /// variables its nested scopes take from further out, which it must
+    /// pass through.
+    fn update_symbols(
+        &mut self,
+        id: usize,
+        scopes: &HashMap<Symbol, Scope>,
+        bound: Option<&HashSet<Symbol>>,
+        free: &HashSet<Symbol>,
+    ) {
+        let block = &mut self.blocks[id];
+        for (name, entry) in block.entries.iter_mut() {
+            entry.scope = scopes[name];
+        }
+        let mut free: Vec<Symbol> = free.iter().copied().collect();
+        free.sort_by_key(|name| name.as_str());
+        for name in free {
+            if let Some(entry) = block.entries.get_mut(&name) {
+                // A method's free variable that the class binds as well.
+                if block.kind == TableKind::Class && entry.flags & (DEF_BOUND | DEF_GLOBAL) != 0 {
+                    entry.flags |= DEF_FREE_CLASS;
+                }
+                continue;
+            }
+            if bound.is_some_and(|bound| !bound.contains(&name)) {
+                continue;
+            }
+            block.names.push(name);
+            block.entries.insert(
+                name,
+                Entry {
+                    flags: 0,
+                    scope: Scope::Free,
+                },
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{mangle, symtable, BlockKey, Scope, SymbolTable, Table, TableKind};
+    use crate::intern::Symbol;
+    use crate::parser::parse;
+
+    fn build(source: &str) -> SymbolTable {
+        symtable(&parse(source).unwrap()).unwrap()
+    }
+
+    fn names(names: impl IntoIterator<Item = Symbol>) -> Vec<&'static str> {
+        names.into_iter().map(Symbol::as_str).collect()
+    }
+
+    fn child<'a>(table: Table<'a>, i: usize) -> Table<'a> {
+        table.children().nth(i).unwrap()
+    }
+
+    fn first_error(source: &str) -> String {
+        match symtable(&parse(source).unwrap()) {
+            Ok(_) => "OK".to_string(),
+            Err(error) => error.to_string(),
+        }
+    }
+
+    // The expected answers below are those of CPython 3.11's `symtable`.
+
+    #[test]
+    fn test_symtable_fib() {
+        let source = std::fs::read_to_string("tests/fib.py").unwrap();
+        let symtable = build(&source);
+        let top = symtable.top();
+        assert_eq!(top.kind(), TableKind::Module);
+        assert_eq!(top.name().as_str(), "top");
+        assert_eq!(names(top.identifiers()), ["fib", "print"]);
+        let fib = child(top, 0);
+        assert_eq!(fib.kind().name(), "function");
+        assert_eq!(fib.lineno(), 1);
+        assert!(fib.is_optimized() && !fib.is_nested() && !fib.has_children());
+        assert_eq!(names(fib.parameters()), ["x"]);
+        assert_eq!(names(fib.globals()), ["fib"]);
+        assert!(top.lookup("fib").unwrap().is_namespace());
+        let print = top.lookup("print").unwrap();
+        assert!(print.is_global() && !print.is_local() && print.is_referenced());
+    }
+
+    #[test]
+    fn test_symtable_closures() {
+        let source = "\
+def counter():
+    count = 0
+    def increment(step=1):
+        nonlocal count
+        count += step
+        return count
+    def total():
+        global grand
+        grand = count
+        return lambda: count + grand
+    return increment
+";
+        let symtable = build(source);
+        let top = symtable.top();
+        assert_eq!(names(top.identifiers()), ["counter", "grand"]);
+        assert!(top.lookup("grand").unwrap().is_declared_global());
+        let counter = child(top, 0);
+        assert_eq!(names(counter.locals()), ["count", "increment", "total"]);
+        assert_eq!(counter.lookup("count").unwrap().scope(), Scope::Cell);
+        let increment = child(counter, 0);
+        assert_eq!(names(increment.identifiers()), ["step", "count"]);
+        assert_eq!(names(increment.nonlocals()), ["count"]);
+        assert_eq!(names(increment.frees()), ["count"]);
+        let total = child(counter, 1);
+        assert_eq!(names(total.globals()), ["grand"]);
+        assert_eq!(names(total.frees()), ["count"]);
+        let lambda = child(total, 0);
+        assert_eq!(lambda.name().as_str(), "lambda");
+        assert!(lambda.is_nested());
+        assert_eq!(names(lambda.frees()), ["count"]);
+        assert_eq!(names(lambda.globals()), ["grand"]);
+    }
+
+    #[test]
+    fn test_symtable_class() {
+        let source = "\
+class Spam(Base):
+    __eggs = 1
+    name = 'spam'
+    def method(self):
+        return super().method(self.__eggs, name)
+    def method(self, /, *args, key, **kwargs):
+        pass
+";
+        let symtable = build(source);
+        let class = child(symtable.top(), 0);
+        assert_eq!(class.kind(), TableKind::Class);
+        assert!(!class.is_optimized());
+        assert_eq!(
+            names(class.identifiers()),
+            ["_Spam__eggs", "name", "method"]
+        );
+        assert_eq!(names(class.methods()), ["method"]);
+        assert!(class.needs_class_closure());
+        let method = child(class, 0);
+        assert_eq!(
+            names(method.identifiers()),
+            ["self", "super", "__class__", "name"]
+        );
+        // A class's names are not visible in its methods.
+        assert_eq!(names(method.frees()), ["__class__"]);
+        assert_eq!(names(method.globals()), ["super", "name"]);
+        let other = child(class, 1);
+        assert_eq!(names(other.parameters()), ["self", "key", "args", "kwargs"]);
+        assert_eq!(
+            names(other.varnames().iter().copied()),
+            ["self", "key", "args", "kwargs"]
+        );
+        assert_eq!(symtable.get(BlockKey::Module).unwrap().id(), 0);
+    }
+
+    #[test]
+    fn test_symtable_comprehensions() {
+        let source = "\
+def f(data):
+    squares = [x * x for x in data if x]
+    pairs = {k: v for k, v in data}
+    found = any((y := z) > 0 for z in data)
+    return y
+";
+        let symtable = build(source);
+        let f = child(symtable.top(), 0);
+        assert_eq!(
+            names(f.identifiers()),
+            ["data", "squares", "pairs", "found", "any", "y"]
+        );
+        assert_eq!(f.lookup("y").unwrap().scope(), Scope::Cell);
+        let expected = [
+            ("listcomp", vec![".0", "x"], 2),
+            ("dictcomp", vec![".0", "k", "v"], 3),
+            ("genexpr", vec![".0", "z", "y"], 4),
+        ];
+        for (table, (name, identifiers, line)) in f.children().zip(expected) {
+            assert_eq!(table.name().as_str(), name);
+            assert_eq!(names(table.identifiers()), identifiers);
+            assert_eq!(names(table.parameters()), [".0"]);
+            assert_eq!(table.lineno(), line);
+        }
+        assert!(child(f, 2).is_generator());
+        assert_eq!(names(child(f, 2).frees()), ["y"]);
+    }
+
+    #[test]
+    fn test_symtable_type_params() {
+        let source = "\
+class Box[T: int](Base[T]):
+    def get[U](self, default: U = None) -> T | U: ...
+type Pair[K] = tuple[K, K]
+";
+        let symtable = build(source);
+        let top = symtable.top();
+        assert_eq!(names(top.identifiers()), ["Box", "Pair"]);
+        let generic = child(top, 0);
+        assert_eq!(generic.kind().name(), "type parameter");
+        assert_eq!(
+            names(generic.identifiers()),
+            [".type_params", ".generic_base", "T", "Base"]
+        );
+        assert_eq!(child(generic, 0).kind(), TableKind::TypeVarBound);
+        let class = child(generic, 1);
+        assert_eq!(
+            names(class.identifiers()),
+            ["__type_params__", ".type_params", "get", "T"]
+        );
+        assert_eq!(class.lookup(".type_params").unwrap().scope(), Scope::Free);
+        let get = child(class, 0);
+        assert_eq!(get.kind(), TableKind::TypeParams);
+        assert_eq!(
+            names(get.identifiers()),
+            ["__classdict__", ".defaults", "U", "T"]
+        );
+        assert!(get.lookup("U").unwrap().is_type_parameter());
+        let alias = child(child(top, 1), 0);
+        assert_eq!(alias.kind().name(), "type alias");
+        assert_eq!(names(alias.frees()), ["K"]);
+    }
+
+    #[test]
+    fn test_symtable_errors() {
+        let cases = [
+            (
+                "def f(x):\n    global x\n",
+                "name 'x' is parameter and global (line 2)",
+            ),
+            (
+                "def f():\n    print(x)\n    global x\n",
+                "name 'x' is used prior to global declaration (line 3)",
+            ),
+            (
+                "nonlocal x\n",
+                "nonlocal declaration not allowed at module level (line 1)",
+            ),
+            (
+                "def f():\n    nonlocal x\n",
+                "no binding for nonlocal 'x' found (line 2)",
+            ),
+            (
+                "def f():\n    global x\n    nonlocal x\n",
+                "name 'x' is nonlocal and global (line 2)",
+            ),
+            (
+                "lambda a, a: 1\n",
+                "duplicate argument 'a' in function definition (line 1)",
+            ),
+            (
+                "def f():\n    from os import *\n",
+                "import * only allowed at module level (line 2)",
+            ),
+            (
+                "[i := 0 for i in x]\n",
+                "assignment expression cannot rebind comprehension iteration variable 'i' (line 1)",
+            ),
+            (
+                "class C:\n    [y := 1 for x in z]\n",
+                "assignment expression within a comprehension cannot be used in a class body (line 2)",
+            ),
+            ("def f():\n    x = 1\n    def g():\n        nonlocal x\n", "OK"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(first_error(source), expected, "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn test_mangle() {
+        let ham = Some(Symbol::intern("_Ham"));
+        let cases = [
+            ("__spam", "_Ham__spam"),
+            ("__spam__", "__spam__"),
+            ("_spam", "_spam"),
+            ("__a.b", "__a.b"),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(mangle(ham, Symbol::intern(name)).as_str(), expected);
+        }
+        let name = Symbol::intern("__spam");
+        assert_eq!(mangle(Some(Symbol::intern("__")), name), name);
+        assert_eq!(mangle(None, name), name);
+    }
+}
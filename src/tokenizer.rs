@@ -1,15 +1,19 @@
 
 
-use core::panic;
-
+use lazy_static::lazy_static;
 use phf::phf_map;
 use regex::Regex;
 
+use crate::ast::Span;
+use crate::parser::ParseError;
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum TokenType {
     And,
     As,
     Assert,
+    Async,
+    Await,
     Break,
     Class,
     Continue,
@@ -96,7 +100,8 @@ pub enum TokenType {
     Rarrow,
     Ellipsis,
     ColonEqual,
-    Comment
+    Comment,
+    ErrorToken
 }
 
 
@@ -104,6 +109,8 @@ static KEYWORDS : phf::Map<&'static str, TokenType> = phf_map!(
     "and" => TokenType::And,
     "as" => TokenType::As,
     "assert" => TokenType::Assert,
+    "async" => TokenType::Async,
+    "await" => TokenType::Await,
     "break" => TokenType::Break,
     "class" => TokenType::Class,
     "continue" => TokenType::Continue,
@@ -186,6 +193,13 @@ static OPERATORS  : phf::Map<&'static str, TokenType> = phf_map!(
     "@=" => TokenType::AtEqual,
 );
 
+lazy_static! {
+    static ref IDENTIFIER_PATTERN: Regex = Regex::new(r"^[a-zA-Z_][a-zA-Z0-9_]*").unwrap();
+    static ref NUMBER_PATTERN: Regex = Regex::new(
+        r"^(0[xX][0-9a-fA-F_]+|0[oO][0-7_]+|0[bB][01_]+|([0-9][0-9_]*(\.[0-9_]*)?|\.[0-9][0-9_]*)([eE][+-]?[0-9_]+)?[jJ]?)",
+    ).unwrap();
+}
+
 
 #[derive(Clone, PartialEq)]
 pub struct SourceRef<'source> {
@@ -225,14 +239,14 @@ pub struct Token<'source> {
 }
 
 impl<'source> Token<'source> {
-    pub fn new(token_type: TokenType, start: usize, end: usize, line: usize, source: &str) -> Token {
+    pub fn new(token_type: TokenType, start: usize, end: usize, line: usize, source: &'source str) -> Token<'source> {
         Token {
             token_type,
             source_ref: SourceRef::new(source, line, start, end)
         }
     }
 
-    pub fn endmarker(start: usize, line: usize, source: &str) -> Token {
+    pub fn endmarker(start: usize, line: usize, source: &'source str) -> Token<'source> {
         Token {
             token_type: TokenType::EndMarker,
             source_ref: SourceRef::new(source, line, start, start)
@@ -266,6 +280,7 @@ pub struct Tokenizer<'source> {
     pub current: usize,
     pub start: usize,
     pub line: usize,
+    pub errors: Vec<ParseError>,
 }
 
 impl <'source> Tokenizer<'source> {
@@ -275,9 +290,18 @@ impl <'source> Tokenizer<'source> {
             current: 0,
             start: 0,
             line: 1,
+            errors: Vec::new(),
         }
     }
 
+    /// Records an error and returns an `ErrorToken` covering the text
+    /// scanned since `self.start`, so the parser fails on this line.
+    fn error_token(&mut self, message: String, line: usize) -> Token<'source> {
+        self.errors.push(ParseError::new(message, Span::new(line, self.start, self.current)));
+        Token::new(TokenType::ErrorToken, self.start, self.current, line, self.source)
+    }
+
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Token<'source> {
         if self.current >= self.source.len() {
             return Token::endmarker(self.current, self.line, self.source);
@@ -285,47 +309,81 @@ impl <'source> Tokenizer<'source> {
         let ch = self.source[self.current..].chars().next().unwrap();
         self.start = self.current;
         match ch {
-            ' ' | '\r' | '\t' => {
+            ' ' | '\r' | '\t' | '\x0c' => {
                self.advance();
                self.next()
             }
             '\n' => {
+                self.advance();
                 let token = Token::new(TokenType::Newline, self.start, self.current, self.line, self.source);
                 self.line += 1;
-                self.start = 0;
+                token
+            }
+
+            // explicit line joining
+            '\\' => {
                 self.advance();
-                return token;
+                if self.source[self.current..].starts_with("\r\n") {
+                    self.advance();
+                }
+                if self.advance() != '\n' {
+                    return self.error_token("unexpected character after line continuation character".to_string(), self.line);
+                }
+                self.line += 1;
+                self.next()
+            }
+
+            '#' => {
+                while !self.is_at_end() && !self.source[self.current..].starts_with('\n') {
+                    self.advance();
+                }
+                Token::new(TokenType::Comment, self.start, self.current, self.line, self.source)
             }
 
             // is character
             'a'..='z' | 'A'..='Z' | '_' => {
-                return self.get_identifier();
+                self.get_identifier()
             }
 
             // is number
             '0'..='9' => {
-                return self.get_number();
+                self.get_number()
+            }
+            '.' if self.source[self.current + 1..].starts_with(|c: char| c.is_ascii_digit()) => {
+                self.get_number()
+            }
+
+            '"' | '\'' => {
+                self.get_string()
             }
 
-            '|' | '&' | '/' | '+' | '-' | '*' |'<' | '>' | '=' | '.' | '%' | '{' | '}' | '~' | '^' | '@' | ',' | ':' | ';' | '(' | ')' | '[' | ']' => {
-                return self.get_operator();
+            '|' | '&' | '/' | '+' | '-' | '*' |'<' | '>' | '=' | '!' | '.' | '%' | '{' | '}' | '~' | '^' | '@' | ',' | ':' | ';' | '(' | ')' | '[' | ']' => {
+                self.get_operator()
             }
 
             _ => {
-                panic!("next error")
+                self.advance();
+                let message = if ch.is_ascii() {
+                    "invalid syntax".to_string()
+                } else {
+                    format!("invalid character '{}' (U+{:04X})", ch, ch as u32)
+                };
+                self.error_token(message, self.line)
             }
         }
 
     }
 
     pub fn get_identifier(&mut self) -> Token<'source> {
-        let identifier_pattern = Regex::new(r"^[a-zA-Z_][a-zA-Z0-9_]*").unwrap();
-        if self.match_pattern(&identifier_pattern) {
+        if self.match_pattern(&IDENTIFIER_PATTERN) {
             // get the matched string value
             let text = &self.source[self.current..];
-            let matched_value = identifier_pattern.find(text).unwrap();
+            let matched_value = IDENTIFIER_PATTERN.find(text).unwrap();
             let matched_value = &text[matched_value.start()..matched_value.end()];
             self.current += matched_value.len();
+            if is_string_prefix(matched_value) && self.source[self.current..].starts_with(['"', '\'']) {
+                return self.get_string();
+            }
             let token_type = KEYWORDS.get(matched_value).unwrap_or(&TokenType::Name);
             return Token::new(*token_type, self.start, self.current, self.line, self.source);
         }
@@ -333,11 +391,10 @@ impl <'source> Tokenizer<'source> {
     }
 
     pub fn get_number(&mut self) -> Token<'source> {
-        let number_pattern = Regex::new(r"^[0-9]+").unwrap();
-        if self.match_pattern(&number_pattern) {
+        if self.match_pattern(&NUMBER_PATTERN) {
             // get the matched string value
             let text = &self.source[self.current..];
-            let matched_value = number_pattern.find(text).unwrap();
+            let matched_value = NUMBER_PATTERN.find(text).unwrap();
             let matched_value = &text[matched_value.start()..matched_value.end()];
             self.current += matched_value.len();
             return Token::new(TokenType::Number, self.start, self.current, self.line, self.source);
@@ -345,33 +402,69 @@ impl <'source> Tokenizer<'source> {
         panic!("get_number error")
     }
 
-    pub fn get_operator(&mut self) -> Token<'source> {
-        let two_char_operator = OPERATORS.keys().filter(|x| x.len() == 2).collect::<Vec<_>>();
-        let three_char_operator = OPERATORS.keys().filter(|x| x.len() == 3).collect::<Vec<_>>();
-        let single_char_operator = OPERATORS.keys().filter(|x| x.len() == 1).collect::<Vec<_>>();
-
-        let text = &self.source[self.current..];
-        for operator in three_char_operator {
-            if text.starts_with(operator) {
-                self.current += operator.len();
-                return Token::new(*OPERATORS.get(operator).unwrap(), self.start, self.current, self.line, self.source);
+    /// Scans a string literal; `self.current` is at the opening quote and
+    /// `self.start` at the prefix, if any. Triple-quoted strings may span lines.
+    /// An unterminated string becomes an `ErrorToken` running to the end of
+    /// its line, or of the file if it is triple-quoted.
+    pub fn get_string(&mut self) -> Token<'source> {
+        let line = self.line;
+        let quote = self.advance();
+        let closing = quote.to_string().repeat(2);
+        let triple = if self.source[self.current..].starts_with(&closing) {
+            self.advance();
+            self.advance();
+            true
+        } else {
+            false
+        };
+        loop {
+            match self.advance() {
+                '\0' if self.is_at_end() => return self.unterminated_string(line, triple),
+                '\\' if self.advance() == '\n' => self.line += 1,
+                '\n' if !triple => {
+                    self.current -= 1;
+                    return self.unterminated_string(line, triple);
+                }
+                '\n' => self.line += 1,
+                c if c == quote => {
+                    if !triple {
+                        break;
+                    }
+                    if self.source[self.current..].starts_with(&closing) {
+                        self.advance();
+                        self.advance();
+                        break;
+                    }
+                }
+                _ => {}
             }
         }
-        for operator in two_char_operator {
-            if text.starts_with(operator) {
-                self.current += operator.len();
-                return Token::new(*OPERATORS.get(operator).unwrap(), self.start, self.current, self.line, self.source);
-            }
+        Token::new(TokenType::String, self.start, self.current, line, self.source)
+    }
+
+    fn unterminated_string(&mut self, line: usize, triple: bool) -> Token<'source> {
+        let kind = if triple { "triple-quoted string" } else { "string" };
+        // like CPython, the newline ending the file starts no line of its own
+        let mut detected = self.line;
+        if self.is_at_end() && self.source.ends_with('\n') && detected > line {
+            detected -= 1;
         }
+        let message = format!("unterminated {} literal (detected at line {})", kind, detected);
+        self.error_token(message, line)
+    }
 
-        for operator in single_char_operator {
-            if text.starts_with(operator) {
-                self.current += operator.len();
-                return Token::new(*OPERATORS.get(operator).unwrap(), self.start, self.current, self.line, self.source);
+    pub fn get_operator(&mut self) -> Token<'source> {
+        // the longest operator that matches wins
+        let text = &self.source[self.current..];
+        for length in (1..=3).rev() {
+            if let Some(token_type) = text.get(..length).and_then(|operator| OPERATORS.get(operator)) {
+                self.current += length;
+                return Token::new(*token_type, self.start, self.current, self.line, self.source);
             }
         }
-
-        panic!("get_operator error")
+        // only `!` can get here, when no `=` follows it
+        self.advance();
+        self.error_token("invalid syntax".to_string(), self.line)
     }
     pub fn match_pattern(&mut self, pattern: &Regex) -> bool {
         let source = &self.source[self.current..];
@@ -393,6 +486,204 @@ impl <'source> Tokenizer<'source> {
 }
 
 
+fn is_string_prefix(text: &str) -> bool {
+    matches!(
+        text.to_ascii_lowercase().as_str(),
+        "r" | "u" | "b" | "f" | "br" | "rb" | "fr" | "rf"
+    )
+}
+
+fn line_start(source: &str, position: usize) -> usize {
+    source[..position].rfind('\n').map(|i| i + 1).unwrap_or(0)
+}
+
+/// Width of the indentation in front of `start`, with tabs advancing to the
+/// next multiple of eight as in CPython.
+fn indentation(source: &str, start: usize) -> usize {
+    let mut column = 0;
+    for ch in source[line_start(source, start)..start].chars() {
+        match ch {
+            ' ' => column += 1,
+            '\t' => column = (column / 8 + 1) * 8,
+            '\x0c' => column = 0,
+            _ => {}
+        }
+    }
+    column
+}
+
+/// Turns the raw token stream into the logical token stream the parser
+/// consumes: comments and blank lines are dropped, newlines inside brackets
+/// are ignored, and indentation changes become `Indent`/`Dedent` tokens.
+/// The stream always ends with `Newline`, any pending `Dedent`s and
+/// `EndMarker`.
+///
+/// Errors do not stop tokenizing. The offending text becomes an
+/// `ErrorToken`, and a bracket left open when a line starts with a keyword
+/// that can only begin a statement is reported as never closed and
+/// dropped, so the parser can carry on from that line.
+pub fn tokenize(source: &str) -> (Vec<Token<'_>>, Vec<ParseError>) {
+    let mut tokenizer = Tokenizer::new(source);
+    let mut tokens = Vec::new();
+    let mut errors = Vec::new();
+    let mut indents = vec![0];
+    let mut brackets: Vec<Token> = Vec::new();
+    let mut at_line_start = true;
+    let mut new_physical_line = false;
+    loop {
+        let token = tokenizer.next();
+        errors.append(&mut tokenizer.errors);
+        match token.token_type {
+            TokenType::Comment => continue,
+            TokenType::Newline => {
+                if brackets.is_empty() && !at_line_start {
+                    at_line_start = true;
+                    tokens.push(token);
+                }
+                new_physical_line = true;
+                continue;
+            }
+            TokenType::EndMarker => {
+                let end = token.start();
+                let line = token.line();
+                if let Some(bracket) = brackets.pop() {
+                    close_unclosed(&mut tokens, &mut errors, &bracket, end, line, source);
+                }
+                if !at_line_start {
+                    tokens.push(Token::new(TokenType::Newline, end, end, line, source));
+                }
+                for _ in 1..indents.len() {
+                    tokens.push(Token::new(TokenType::Dedent, end, end, line, source));
+                }
+                tokens.push(token);
+                return (tokens, errors);
+            }
+            _ => {}
+        }
+        if new_physical_line && starts_statement(token.token_type) {
+            if let Some(bracket) = brackets.pop() {
+                close_unclosed(&mut tokens, &mut errors, &bracket, token.start(), token.line(), source);
+                brackets.clear();
+                tokens.push(Token::new(TokenType::Newline, token.start(), token.start(), token.line(), source));
+                at_line_start = true;
+            }
+        }
+        new_physical_line = false;
+        if at_line_start {
+            at_line_start = false;
+            let column = indentation(source, token.start());
+            if column > *indents.last().unwrap() {
+                indents.push(column);
+                let start = line_start(source, token.start());
+                tokens.push(Token::new(
+                    TokenType::Indent,
+                    start,
+                    token.start(),
+                    token.line(),
+                    source,
+                ));
+            } else {
+                while column < *indents.last().unwrap() {
+                    indents.pop();
+                    tokens.push(Token::new(
+                        TokenType::Dedent,
+                        token.start(),
+                        token.start(),
+                        token.line(),
+                        source,
+                    ));
+                }
+                if column != *indents.last().unwrap() {
+                    errors.push(ParseError::new(
+                        "unindent does not match any outer indentation level",
+                        Span::new(token.line(), token.start(), token.end()),
+                    ));
+                    *indents.last_mut().unwrap() = column;
+                }
+            }
+        }
+        match token.token_type {
+            TokenType::Lpar | TokenType::Lsqb | TokenType::Lbrace => brackets.push(token.clone()),
+            TokenType::Rpar | TokenType::Rsqb | TokenType::Rbrace => {
+                let message = match brackets.pop() {
+                    None => Some(format!("unmatched '{}'", token.value())),
+                    Some(open) if !brackets_match(open.token_type, token.token_type) => {
+                        let mut message = format!(
+                            "closing parenthesis '{}' does not match opening parenthesis '{}'",
+                            token.value(),
+                            open.value()
+                        );
+                        if open.line() != token.line() {
+                            message.push_str(&format!(" on line {}", open.line()));
+                        }
+                        Some(message)
+                    }
+                    Some(_) => None,
+                };
+                if let Some(message) = message {
+                    errors.push(ParseError::new(message, Span::new(token.line(), token.start(), token.end())));
+                    tokens.push(Token { token_type: TokenType::ErrorToken, ..token });
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        tokens.push(token);
+    }
+}
+
+fn brackets_match(open: TokenType, close: TokenType) -> bool {
+    matches!(
+        (open, close),
+        (TokenType::Lpar, TokenType::Rpar) | (TokenType::Lsqb, TokenType::Rsqb) | (TokenType::Lbrace, TokenType::Rbrace)
+    )
+}
+
+/// Keywords that cannot appear inside brackets, so a line starting with one
+/// means a bracket on an earlier line was left open.
+fn starts_statement(token_type: TokenType) -> bool {
+    matches!(
+        token_type,
+        TokenType::Def
+            | TokenType::Class
+            | TokenType::Return
+            | TokenType::Import
+            | TokenType::While
+            | TokenType::With
+            | TokenType::Try
+            | TokenType::Global
+            | TokenType::Nonlocal
+            | TokenType::Pass
+            | TokenType::Break
+            | TokenType::Continue
+            | TokenType::Raise
+            | TokenType::Del
+            | TokenType::Assert
+            | TokenType::Elif
+            | TokenType::Except
+            | TokenType::Finally
+    )
+}
+
+/// Reports `bracket` as never closed and ends the statement it opened with
+/// an `ErrorToken` at `end`. The error's span runs to `end`, covering the
+/// parser errors that the missing bracket causes.
+fn close_unclosed<'source>(
+    tokens: &mut Vec<Token<'source>>,
+    errors: &mut Vec<ParseError>,
+    bracket: &Token,
+    end: usize,
+    line: usize,
+    source: &'source str,
+) {
+    errors.push(ParseError::new(
+        format!("'{}' was never closed", bracket.value()),
+        Span::new(bracket.line(), bracket.start(), end),
+    ));
+    tokens.push(Token::new(TokenType::ErrorToken, end, end, line, source));
+}
+
+
 
 
 #[cfg(test)]
@@ -468,4 +759,95 @@ mod tests {
             token = tokenizer.next();
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_tokenize_indent() {
+        let source = std::fs::read_to_string("tests/return.py").unwrap();
+        let (tokens, errors) = tokenize(&source);
+        assert!(errors.is_empty());
+        let types = tokens.iter().map(|token| token.token_type).collect::<Vec<_>>();
+        assert_eq!(
+            types,
+            vec![
+                TokenType::Def,
+                TokenType::Name,
+                TokenType::Lpar,
+                TokenType::Rpar,
+                TokenType::Colon,
+                TokenType::Newline,
+                TokenType::Indent,
+                TokenType::Return,
+                TokenType::Number,
+                TokenType::Newline,
+                TokenType::Dedent,
+                TokenType::Name,
+                TokenType::Lpar,
+                TokenType::Rpar,
+                TokenType::Newline,
+                TokenType::EndMarker,
+            ]
+        );
+        assert_eq!(tokens[6].value(), "    ");
+    }
+
+    #[test]
+    fn test_tokenize_brackets_and_comments() {
+        let source = "x = (1 +  # comment\n     2)\n\n# only a comment\ny = 'a\\'b' \\\n  + '''c\nd'''\n";
+        let (tokens, _) = tokenize(source);
+        let values = tokens.iter().map(|token| token.value()).collect::<Vec<_>>();
+        assert_eq!(
+            values,
+            vec!["x", "=", "(", "1", "+", "2", ")", "\n", "y", "=", "'a\\'b'", "+", "'''c\nd'''", "\n", ""]
+        );
+        assert_eq!(tokens[8].line(), 5);
+        assert_eq!(tokens[12].line(), 6);
+    }
+
+    #[test]
+    fn test_tokenize_errors() {
+        let (tokens, errors) = tokenize("x = (1 $\ny = ]\nz = 'a\n");
+        let error_tokens = tokens.iter().filter(|token| token.token_type == TokenType::ErrorToken).count();
+        assert_eq!(error_tokens, 3);
+        let messages = errors.iter().map(|error| error.message.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec![
+                "invalid syntax",
+                "closing parenthesis ']' does not match opening parenthesis '(' on line 1",
+                "unterminated string literal (detected at line 3)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_unterminated_triple_quoted() {
+        for (source, detected) in [("x = '''abc", 1), ("x = '''abc\n", 1), ("x = '''abc\n\n", 2), ("x = '''abc\ndef\n", 2)] {
+            let (_, errors) = tokenize(source);
+            let message = format!("unterminated triple-quoted string literal (detected at line {})", detected);
+            assert_eq!(errors[0].message, message, "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn test_tokenize_lone_exclamation_mark() {
+        let (tokens, errors) = tokenize("x = a!b != !\n");
+        let types = tokens.iter().map(|token| token.token_type).collect::<Vec<_>>();
+        assert_eq!(
+            types,
+            vec![
+                TokenType::Name,
+                TokenType::Equal,
+                TokenType::Name,
+                TokenType::ErrorToken,
+                TokenType::Name,
+                TokenType::NotEqual,
+                TokenType::ErrorToken,
+                TokenType::Newline,
+                TokenType::EndMarker,
+            ]
+        );
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].message, "invalid syntax");
+        assert_eq!(errors[0].span, Span::new(1, 5, 6));
+    }
+}
@@ -0,0 +1,729 @@
+//! Rewriting traversal of the AST.
+//!
+//! A `Transformer` rewrites the tree in place in its arena: each method
+//! takes a node and returns its replacement, which may be the node itself
+//! with its children rewritten or a freshly allocated one. `transform_stmt`
+//! returns a list, which lets it delete a statement or expand it into
+//! several, like returning a list from `ast.NodeTransformer.visit`. The
+//! defaults transform a node's children through the matching `walk_*`
+//! function and keep the node itself; like the visitor walks, these
+//! destructure every variant and field.
+//!
+//! Lists are rewritten in place, item by item, except for a body whose
+//! statements a transformer deleted or expanded, which gets a new list.
+
+use std::mem;
+
+use crate::ast::{
+    Alias, Arena, Arg, Arguments, Comprehension, Constant, ExceptHandler, ExprId, ExprKind,
+    Keyword, List, ListItem, Module, StmtId, StmtKind, TypeParam, TypeParamKind, WithItem,
+};
+
+pub trait Transformer: Sized {
+    fn transform_module(&mut self, module: Module) -> Module {
+        walk_module(self, module)
+    }
+
+    fn transform_stmt(&mut self, arena: &mut Arena, stmt: StmtId) -> Vec<StmtId> {
+        vec![walk_stmt(self, arena, stmt)]
+    }
+
+    fn transform_expr(&mut self, arena: &mut Arena, expr: ExprId) -> ExprId {
+        walk_expr(self, arena, expr)
+    }
+
+    fn transform_arguments(&mut self, arena: &mut Arena, arguments: Arguments) -> Arguments {
+        walk_arguments(self, arena, arguments)
+    }
+
+    fn transform_arg(&mut self, arena: &mut Arena, arg: Arg) -> Arg {
+        walk_arg(self, arena, arg)
+    }
+
+    fn transform_keyword(&mut self, arena: &mut Arena, keyword: Keyword) -> Keyword {
+        walk_keyword(self, arena, keyword)
+    }
+
+    fn transform_type_param(&mut self, arena: &mut Arena, type_param: TypeParam) -> TypeParam {
+        walk_type_param(self, arena, type_param)
+    }
+
+    fn transform_alias(&mut self, alias: Alias) -> Alias {
+        alias
+    }
+
+    fn transform_with_item(&mut self, arena: &mut Arena, item: WithItem) -> WithItem {
+        walk_with_item(self, arena, item)
+    }
+
+    fn transform_except_handler(
+        &mut self,
+        arena: &mut Arena,
+        handler: ExceptHandler,
+    ) -> ExceptHandler {
+        walk_except_handler(self, arena, handler)
+    }
+
+    fn transform_comprehension(
+        &mut self,
+        arena: &mut Arena,
+        comprehension: Comprehension,
+    ) -> Comprehension {
+        walk_comprehension(self, arena, comprehension)
+    }
+}
+
+pub fn walk_module<T: Transformer>(transformer: &mut T, module: Module) -> Module {
+    let Module { body, mut arena } = module;
+    let body = walk_body(transformer, &mut arena, body);
+    Module { body, arena }
+}
+
+pub fn walk_body<T: Transformer>(
+    transformer: &mut T,
+    arena: &mut Arena,
+    body: List<StmtId>,
+) -> List<StmtId> {
+    let mut stmts = Vec::with_capacity(body.len());
+    for i in 0..body.len() {
+        let stmt = arena[body][i];
+        stmts.extend(transformer.transform_stmt(arena, stmt));
+    }
+    if arena[body] == stmts[..] {
+        return body;
+    }
+    arena.add_list(stmts)
+}
+
+/// Replaces each item of `list` with what `f` makes of it.
+fn walk_list<I: ListItem>(
+    arena: &mut Arena,
+    list: List<I>,
+    mut f: impl FnMut(&mut Arena, I) -> I,
+) -> List<I> {
+    for i in 0..list.len() {
+        let item = f(arena, arena[list][i]);
+        arena[list][i] = item;
+    }
+    list
+}
+
+fn walk_exprs<T: Transformer>(
+    transformer: &mut T,
+    arena: &mut Arena,
+    exprs: List<ExprId>,
+) -> List<ExprId> {
+    walk_list(arena, exprs, |arena, expr| {
+        transformer.transform_expr(arena, expr)
+    })
+}
+
+fn walk_opt_expr<T: Transformer>(
+    transformer: &mut T,
+    arena: &mut Arena,
+    expr: Option<ExprId>,
+) -> Option<ExprId> {
+    expr.map(|expr| transformer.transform_expr(arena, expr))
+}
+
+fn walk_keywords<T: Transformer>(
+    transformer: &mut T,
+    arena: &mut Arena,
+    keywords: List<Keyword>,
+) -> List<Keyword> {
+    walk_list(arena, keywords, |arena, keyword| {
+        transformer.transform_keyword(arena, keyword)
+    })
+}
+
+fn walk_type_params<T: Transformer>(
+    transformer: &mut T,
+    arena: &mut Arena,
+    type_params: List<TypeParam>,
+) -> List<TypeParam> {
+    walk_list(arena, type_params, |arena, type_param| {
+        transformer.transform_type_param(arena, type_param)
+    })
+}
+
+fn walk_generators<T: Transformer>(
+    transformer: &mut T,
+    arena: &mut Arena,
+    generators: List<Comprehension>,
+) -> List<Comprehension> {
+    walk_list(arena, generators, |arena, comprehension| {
+        transformer.transform_comprehension(arena, comprehension)
+    })
+}
+
+/// Transforms the children of `stmt` and keeps the statement. Its kind is
+/// moved out of the arena while the children are rewritten, so a
+/// transformer sees `pass` if it looks at an ancestor in the meantime.
+pub fn walk_stmt<T: Transformer>(transformer: &mut T, arena: &mut Arena, stmt: StmtId) -> StmtId {
+    let kind = mem::replace(&mut arena[stmt].kind, StmtKind::Pass);
+    let kind = match kind {
+        StmtKind::FunctionDef {
+            name,
+            args,
+            body,
+            decorator_list,
+            returns,
+            type_params,
+        } => StmtKind::FunctionDef {
+            name,
+            args: Box::new(transformer.transform_arguments(arena, *args)),
+            body: walk_body(transformer, arena, body),
+            decorator_list: walk_exprs(transformer, arena, decorator_list),
+            returns: walk_opt_expr(transformer, arena, returns),
+            type_params: walk_type_params(transformer, arena, type_params),
+        },
+        StmtKind::AsyncFunctionDef {
+            name,
+            args,
+            body,
+            decorator_list,
+            returns,
+            type_params,
+        } => StmtKind::AsyncFunctionDef {
+            name,
+            args: Box::new(transformer.transform_arguments(arena, *args)),
+            body: walk_body(transformer, arena, body),
+            decorator_list: walk_exprs(transformer, arena, decorator_list),
+            returns: walk_opt_expr(transformer, arena, returns),
+            type_params: walk_type_params(transformer, arena, type_params),
+        },
+        StmtKind::ClassDef {
+            name,
+            bases,
+            keywords,
+            body,
+            decorator_list,
+            type_params,
+        } => StmtKind::ClassDef {
+            name,
+            bases: walk_exprs(transformer, arena, bases),
+            keywords: walk_keywords(transformer, arena, keywords),
+            body: walk_body(transformer, arena, body),
+            decorator_list: walk_exprs(transformer, arena, decorator_list),
+            type_params: walk_type_params(transformer, arena, type_params),
+        },
+        StmtKind::Return { value } => StmtKind::Return {
+            value: walk_opt_expr(transformer, arena, value),
+        },
+        StmtKind::Delete { targets } => StmtKind::Delete {
+            targets: walk_exprs(transformer, arena, targets),
+        },
+        StmtKind::Assign { targets, value } => StmtKind::Assign {
+            targets: walk_exprs(transformer, arena, targets),
+            value: transformer.transform_expr(arena, value),
+        },
+        StmtKind::TypeAlias {
+            name,
+            type_params,
+            value,
+        } => StmtKind::TypeAlias {
+            name: transformer.transform_expr(arena, name),
+            type_params: walk_type_params(transformer, arena, type_params),
+            value: transformer.transform_expr(arena, value),
+        },
+        StmtKind::AugAssign { target, op, value } => StmtKind::AugAssign {
+            target: transformer.transform_expr(arena, target),
+            op,
+            value: transformer.transform_expr(arena, value),
+        },
+        StmtKind::AnnAssign {
+            target,
+            annotation,
+            value,
+            simple,
+        } => StmtKind::AnnAssign {
+            target: transformer.transform_expr(arena, target),
+            annotation: transformer.transform_expr(arena, annotation),
+            value: walk_opt_expr(transformer, arena, value),
+            simple,
+        },
+        StmtKind::For {
+            target,
+            iter,
+            body,
+            orelse,
+        } => StmtKind::For {
+            target: transformer.transform_expr(arena, target),
+            iter: transformer.transform_expr(arena, iter),
+            body: walk_body(transformer, arena, body),
+            orelse: walk_body(transformer, arena, orelse),
+        },
+        StmtKind::AsyncFor {
+            target,
+            iter,
+            body,
+            orelse,
+        } => StmtKind::AsyncFor {
+            target: transformer.transform_expr(arena, target),
+            iter: transformer.transform_expr(arena, iter),
+            body: walk_body(transformer, arena, body),
+            orelse: walk_body(transformer, arena, orelse),
+        },
+        StmtKind::While { test, body, orelse } => StmtKind::While {
+            test: transformer.transform_expr(arena, test),
+            body: walk_body(transformer, arena, body),
+            orelse: walk_body(transformer, arena, orelse),
+        },
+        StmtKind::If { test, body, orelse } => StmtKind::If {
+            test: transformer.transform_expr(arena, test),
+            body: walk_body(transformer, arena, body),
+            orelse: walk_body(transformer, arena, orelse),
+        },
+        StmtKind::With { items, body } => StmtKind::With {
+            items: walk_with_items(transformer, arena, items),
+            body: walk_body(transformer, arena, body),
+        },
+        StmtKind::AsyncWith { items, body } => StmtKind::AsyncWith {
+            items: walk_with_items(transformer, arena, items),
+            body: walk_body(transformer, arena, body),
+        },
+        StmtKind::Raise { exc, cause } => StmtKind::Raise {
+            exc: walk_opt_expr(transformer, arena, exc),
+            cause: walk_opt_expr(transformer, arena, cause),
+        },
+        StmtKind::Try {
+            body,
+            handlers,
+            orelse,
+            finalbody,
+        } => StmtKind::Try {
+            body: walk_body(transformer, arena, body),
+            handlers: walk_list(arena, handlers, |arena, handler| {
+                transformer.transform_except_handler(arena, handler)
+            }),
+            orelse: walk_body(transformer, arena, orelse),
+            finalbody: walk_body(transformer, arena, finalbody),
+        },
+        StmtKind::Assert { test, msg } => StmtKind::Assert {
+            test: transformer.transform_expr(arena, test),
+            msg: walk_opt_expr(transformer, arena, msg),
+        },
+        StmtKind::Import { names } => StmtKind::Import {
+            names: walk_aliases(transformer, arena, names),
+        },
+        StmtKind::ImportFrom {
+            module,
+            names,
+            level,
+        } => StmtKind::ImportFrom {
+            module,
+            names: walk_aliases(transformer, arena, names),
+            level,
+        },
+        StmtKind::Global { names } => StmtKind::Global { names },
+        StmtKind::Nonlocal { names } => StmtKind::Nonlocal { names },
+        StmtKind::Expr { value } => StmtKind::Expr {
+            value: transformer.transform_expr(arena, value),
+        },
+        StmtKind::Pass => StmtKind::Pass,
+        StmtKind::Break => StmtKind::Break,
+        StmtKind::Continue => StmtKind::Continue,
+    };
+    arena[stmt].kind = kind;
+    stmt
+}
+
+fn walk_with_items<T: Transformer>(
+    transformer: &mut T,
+    arena: &mut Arena,
+    items: List<WithItem>,
+) -> List<WithItem> {
+    walk_list(arena, items, |arena, item| {
+        transformer.transform_with_item(arena, item)
+    })
+}
+
+fn walk_aliases<T: Transformer>(
+    transformer: &mut T,
+    arena: &mut Arena,
+    names: List<Alias>,
+) -> List<Alias> {
+    walk_list(arena, names, |_, alias| transformer.transform_alias(alias))
+}
+
+/// Transforms the children of `expr` and keeps the expression, moving its
+/// kind out of the arena meanwhile as `walk_stmt` does.
+pub fn walk_expr<T: Transformer>(transformer: &mut T, arena: &mut Arena, expr: ExprId) -> ExprId {
+    let placeholder = ExprKind::Constant {
+        value: Constant::None,
+    };
+    let kind = mem::replace(&mut arena[expr].kind, placeholder);
+    let kind = match kind {
+        ExprKind::BoolOp { op, values } => ExprKind::BoolOp {
+            op,
+            values: walk_exprs(transformer, arena, values),
+        },
+        ExprKind::NamedExpr { target, value } => ExprKind::NamedExpr {
+            target: transformer.transform_expr(arena, target),
+            value: transformer.transform_expr(arena, value),
+        },
+        ExprKind::BinOp { left, op, right } => ExprKind::BinOp {
+            left: transformer.transform_expr(arena, left),
+            op,
+            right: transformer.transform_expr(arena, right),
+        },
+        ExprKind::UnaryOp { op, operand } => ExprKind::UnaryOp {
+            op,
+            operand: transformer.transform_expr(arena, operand),
+        },
+        ExprKind::Lambda { args, body } => ExprKind::Lambda {
+            args: Box::new(transformer.transform_arguments(arena, *args)),
+            body: transformer.transform_expr(arena, body),
+        },
+        ExprKind::IfExp { test, body, orelse } => ExprKind::IfExp {
+            test: transformer.transform_expr(arena, test),
+            body: transformer.transform_expr(arena, body),
+            orelse: transformer.transform_expr(arena, orelse),
+        },
+        ExprKind::Dict { keys, values } => ExprKind::Dict {
+            keys: walk_list(arena, keys, |arena, key| {
+                key.map(|key| transformer.transform_expr(arena, key))
+            }),
+            values: walk_exprs(transformer, arena, values),
+        },
+        ExprKind::Set { elts } => ExprKind::Set {
+            elts: walk_exprs(transformer, arena, elts),
+        },
+        ExprKind::ListComp { elt, generators } => ExprKind::ListComp {
+            elt: transformer.transform_expr(arena, elt),
+            generators: walk_generators(transformer, arena, generators),
+        },
+        ExprKind::SetComp { elt, generators } => ExprKind::SetComp {
+            elt: transformer.transform_expr(arena, elt),
+            generators: walk_generators(transformer, arena, generators),
+        },
+        ExprKind::DictComp {
+            key,
+            value,
+            generators,
+        } => ExprKind::DictComp {
+            key: transformer.transform_expr(arena, key),
+            value: transformer.transform_expr(arena, value),
+            generators: walk_generators(transformer, arena, generators),
+        },
+        ExprKind::GeneratorExp { elt, generators } => ExprKind::GeneratorExp {
+            elt: transformer.transform_expr(arena, elt),
+            generators: walk_generators(transformer, arena, generators),
+        },
+        ExprKind::Await { value } => ExprKind::Await {
+            value: transformer.transform_expr(arena, value),
+        },
+        ExprKind::Yield { value } => ExprKind::Yield {
+            value: walk_opt_expr(transformer, arena, value),
+        },
+        ExprKind::YieldFrom { value } => ExprKind::YieldFrom {
+            value: transformer.transform_expr(arena, value),
+        },
+        ExprKind::Compare {
+            left,
+            ops,
+            comparators,
+        } => ExprKind::Compare {
+            left: transformer.transform_expr(arena, left),
+            ops,
+            comparators: walk_exprs(transformer, arena, comparators),
+        },
+        ExprKind::Call {
+            func,
+            args,
+            keywords,
+        } => ExprKind::Call {
+            func: transformer.transform_expr(arena, func),
+            args: walk_exprs(transformer, arena, args),
+            keywords: walk_keywords(transformer, arena, keywords),
+        },
+        ExprKind::Constant { value } => ExprKind::Constant { value },
+        ExprKind::Attribute { value, attr, ctx } => ExprKind::Attribute {
+            value: transformer.transform_expr(arena, value),
+            attr,
+            ctx,
+        },
+        ExprKind::Subscript { value, slice, ctx } => ExprKind::Subscript {
+            value: transformer.transform_expr(arena, value),
+            slice: transformer.transform_expr(arena, slice),
+            ctx,
+        },
+        ExprKind::Starred { value, ctx } => ExprKind::Starred {
+            value: transformer.transform_expr(arena, value),
+            ctx,
+        },
+        ExprKind::Name { id, ctx } => ExprKind::Name { id, ctx },
+        ExprKind::List { elts, ctx } => ExprKind::List {
+            elts: walk_exprs(transformer, arena, elts),
+            ctx,
+        },
+        ExprKind::Tuple { elts, ctx } => ExprKind::Tuple {
+            elts: walk_exprs(transformer, arena, elts),
+            ctx,
+        },
+        ExprKind::Slice { lower, upper, step } => ExprKind::Slice {
+            lower: walk_opt_expr(transformer, arena, lower),
+            upper: walk_opt_expr(transformer, arena, upper),
+            step: walk_opt_expr(transformer, arena, step),
+        },
+    };
+    arena[expr].kind = kind;
+    expr
+}
+
+pub fn walk_arguments<T: Transformer>(
+    transformer: &mut T,
+    arena: &mut Arena,
+    arguments: Arguments,
+) -> Arguments {
+    let Arguments {
+        posonlyargs,
+        args,
+        vararg,
+        kwonlyargs,
+        kw_defaults,
+        kwarg,
+        defaults,
+    } = arguments;
+    Arguments {
+        posonlyargs: walk_args(transformer, arena, posonlyargs),
+        args: walk_args(transformer, arena, args),
+        vararg: vararg.map(|arg| transformer.transform_arg(arena, arg)),
+        kwonlyargs: walk_args(transformer, arena, kwonlyargs),
+        kw_defaults: walk_list(arena, kw_defaults, |arena, default| {
+            default.map(|default| transformer.transform_expr(arena, default))
+        }),
+        kwarg: kwarg.map(|arg| transformer.transform_arg(arena, arg)),
+        defaults: walk_exprs(transformer, arena, defaults),
+    }
+}
+
+fn walk_args<T: Transformer>(transformer: &mut T, arena: &mut Arena, args: List<Arg>) -> List<Arg> {
+    walk_list(arena, args, |arena, arg| {
+        transformer.transform_arg(arena, arg)
+    })
+}
+
+pub fn walk_arg<T: Transformer>(transformer: &mut T, arena: &mut Arena, arg: Arg) -> Arg {
+    let Arg {
+        arg,
+        annotation,
+        span,
+    } = arg;
+    Arg {
+        arg,
+        annotation: walk_opt_expr(transformer, arena, annotation),
+        span,
+    }
+}
+
+pub fn walk_keyword<T: Transformer>(
+    transformer: &mut T,
+    arena: &mut Arena,
+    keyword: Keyword,
+) -> Keyword {
+    let Keyword { arg, value } = keyword;
+    Keyword {
+        arg,
+        value: transformer.transform_expr(arena, value),
+    }
+}
+
+pub fn walk_type_param<T: Transformer>(
+    transformer: &mut T,
+    arena: &mut Arena,
+    type_param: TypeParam,
+) -> TypeParam {
+    let TypeParam { kind, span } = type_param;
+    let kind = match kind {
+        TypeParamKind::TypeVar { name, bound } => TypeParamKind::TypeVar {
+            name,
+            bound: walk_opt_expr(transformer, arena, bound),
+        },
+        TypeParamKind::ParamSpec { name } => TypeParamKind::ParamSpec { name },
+        TypeParamKind::TypeVarTuple { name } => TypeParamKind::TypeVarTuple { name },
+    };
+    TypeParam { kind, span }
+}
+
+pub fn walk_with_item<T: Transformer>(
+    transformer: &mut T,
+    arena: &mut Arena,
+    item: WithItem,
+) -> WithItem {
+    let WithItem {
+        context_expr,
+        optional_vars,
+    } = item;
+    WithItem {
+        context_expr: transformer.transform_expr(arena, context_expr),
+        optional_vars: optional_vars.map(|vars| transformer.transform_expr(arena, vars)),
+    }
+}
+
+pub fn walk_except_handler<T: Transformer>(
+    transformer: &mut T,
+    arena: &mut Arena,
+    handler: ExceptHandler,
+) -> ExceptHandler {
+    let ExceptHandler {
+        type_,
+        name,
+        body,
+        span,
+    } = handler;
+    ExceptHandler {
+        type_: type_.map(|type_| transformer.transform_expr(arena, type_)),
+        name,
+        body: walk_body(transformer, arena, body),
+        span,
+    }
+}
+
+pub fn walk_comprehension<T: Transformer>(
+    transformer: &mut T,
+    arena: &mut Arena,
+    comprehension: Comprehension,
+) -> Comprehension {
+    let Comprehension {
+        target,
+        iter,
+        ifs,
+        is_async,
+    } = comprehension;
+    Comprehension {
+        target: transformer.transform_expr(arena, target),
+        iter: transformer.transform_expr(arena, iter),
+        ifs: walk_exprs(transformer, arena, ifs),
+        is_async,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::dump;
+    use crate::intern::Symbol;
+    use crate::parser::parse;
+
+    fn transform(transformer: &mut impl Transformer, source: &str) -> String {
+        dump(&transformer.transform_module(parse(source).unwrap()))
+    }
+
+    /// Renames every occurrence of one name, and drops `pass` statements
+    /// from blocks that have anything else in them.
+    struct Rename;
+
+    impl Transformer for Rename {
+        fn transform_stmt(&mut self, arena: &mut Arena, stmt: StmtId) -> Vec<StmtId> {
+            let stmt = walk_stmt(self, arena, stmt);
+            if let StmtKind::If { body, orelse, .. } = arena[stmt].kind {
+                let mut drop_pass = |body: List<StmtId>| {
+                    if body.len() == 1 {
+                        return body;
+                    }
+                    let stmts: Vec<StmtId> = arena[body]
+                        .iter()
+                        .copied()
+                        .filter(|&stmt| arena[stmt].kind != StmtKind::Pass)
+                        .collect();
+                    arena.add_list(stmts)
+                };
+                let (new_body, new_orelse) = (drop_pass(body), drop_pass(orelse));
+                if let StmtKind::If { body, orelse, .. } = &mut arena[stmt].kind {
+                    *body = new_body;
+                    *orelse = new_orelse;
+                }
+            }
+            vec![stmt]
+        }
+
+        fn transform_expr(&mut self, arena: &mut Arena, expr: ExprId) -> ExprId {
+            let expr = walk_expr(self, arena, expr);
+            if let ExprKind::Name { id, .. } = &mut arena[expr].kind {
+                if id.as_str() == "x" {
+                    *id = Symbol::intern("y");
+                }
+            }
+            expr
+        }
+
+        fn transform_arg(&mut self, arena: &mut Arena, arg: Arg) -> Arg {
+            let arg = walk_arg(self, arena, arg);
+            if arg.arg.as_str() == "x" {
+                return Arg {
+                    arg: Symbol::intern("y"),
+                    ..arg
+                };
+            }
+            arg
+        }
+    }
+
+    #[test]
+    fn test_transform_rename() {
+        let source = std::fs::read_to_string("tests/fib.py").unwrap();
+        let expected = dump(&parse(&source.replace('x', "y")).unwrap());
+        assert_eq!(transform(&mut Rename, &source), expected);
+        assert_eq!(
+            transform(&mut Rename, "if x:\n    pass\n    f(x)\nelse:\n    pass\n"),
+            dump(&parse("if y:\n    f(y)\nelse:\n    pass\n").unwrap())
+        );
+    }
+
+    /// Expands `del a, b` into one statement per target.
+    struct SplitDel;
+
+    impl Transformer for SplitDel {
+        fn transform_stmt(&mut self, arena: &mut Arena, stmt: StmtId) -> Vec<StmtId> {
+            let stmt = walk_stmt(self, arena, stmt);
+            let StmtKind::Delete { targets } = arena[stmt].kind else {
+                return vec![stmt];
+            };
+            let span = arena[stmt].span;
+            (0..targets.len())
+                .map(|i| {
+                    let targets = arena.add_list([arena[targets][i]]);
+                    arena.add_stmt(StmtKind::Delete { targets }, span)
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_transform_expands_statements() {
+        assert_eq!(
+            transform(&mut SplitDel, "def f():\n    del a, b[0]\n"),
+            dump(&parse("def f():\n    del a\n    del b[0]\n").unwrap())
+        );
+    }
+
+    /// The default transformer rebuilds an identical tree.
+    struct Identity;
+
+    impl Transformer for Identity {}
+
+    #[test]
+    fn test_transform_identity() {
+        let source = "\
+@d(k=1)
+class A(B, metaclass=M):
+    def f(self, a, /, b: int = 2, *c, d, e=3, **f) -> None:
+        with open(a) as g, h:
+            x: int = {k: v for k, v in g if k}
+            return [*c, {**f}, (lambda: a)(), a[1:2, ::3], -b or not d]
+try:
+    import os.path as p
+    from . import q
+except E as e:
+    raise X from e
+else:
+    assert a, 'm'
+finally:
+    global z
+";
+        let module = parse(source).unwrap();
+        let transformed = Identity.transform_module(module.clone());
+        assert_eq!(transformed, module);
+    }
+}
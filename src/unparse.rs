@@ -0,0 +1,1389 @@
+//! Turns an AST back into Python source, like CPython's `ast.unparse`.
+//!
+//! The output is laid out the way `ast.unparse` lays it out and
+//! parenthesises only where the grammar needs it, so parsing the result
+//! gives back the same tree.
+
+use crate::ast::{
+    Alias, Arena, Arg, Arguments, BoolOperator, Comprehension, Constant, ExceptHandler, ExprId,
+    ExprKind, Keyword, List, Module, Operator, StmtId, StmtKind, TypeParam, TypeParamKind,
+    UnaryOperator, WithItem,
+};
+
+/// How tightly an expression binds, from loosest to tightest. An expression
+/// is parenthesised when its context requires a tighter level than its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Precedence {
+    Yield,
+    NamedExpr,
+    Tuple,
+    Test,
+    Or,
+    And,
+    Not,
+    Cmp,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Shift,
+    Arith,
+    Term,
+    Factor,
+    Power,
+    Await,
+    Atom,
+}
+
+impl Precedence {
+    fn next(self) -> Precedence {
+        use Precedence::*;
+        match self {
+            Yield => NamedExpr,
+            NamedExpr => Tuple,
+            Tuple => Test,
+            Test => Or,
+            Or => And,
+            And => Not,
+            Not => Cmp,
+            Cmp => BitOr,
+            BitOr => BitXor,
+            BitXor => BitAnd,
+            BitAnd => Shift,
+            Shift => Arith,
+            Arith => Term,
+            Term => Factor,
+            Factor => Power,
+            Power => Await,
+            Await | Atom => Atom,
+        }
+    }
+}
+
+fn binop_precedence(op: Operator) -> Precedence {
+    match op {
+        Operator::Add | Operator::Sub => Precedence::Arith,
+        Operator::Mult | Operator::MatMult | Operator::Div | Operator::Mod | Operator::FloorDiv => {
+            Precedence::Term
+        }
+        Operator::LShift | Operator::RShift => Precedence::Shift,
+        Operator::BitOr => Precedence::BitOr,
+        Operator::BitXor => Precedence::BitXor,
+        Operator::BitAnd => Precedence::BitAnd,
+        Operator::Pow => Precedence::Power,
+    }
+}
+
+/// Infinities cannot be written as literals; a float too large for `f64`
+/// parses back as one.
+const INFINITY: &str = "1e309";
+
+pub fn unparse(module: &Module) -> String {
+    let mut unparser = Unparser::new(&module.arena);
+    unparser.body(module.body);
+    unparser.out
+}
+
+pub fn unparse_expr(arena: &Arena, expr: ExprId) -> String {
+    let mut unparser = Unparser::new(arena);
+    unparser.expr(expr, Precedence::Test);
+    unparser.out
+}
+
+struct Unparser<'a> {
+    arena: &'a Arena,
+    out: String,
+    indent: usize,
+}
+
+impl<'a> Unparser<'a> {
+    fn new(arena: &'a Arena) -> Self {
+        Unparser {
+            arena,
+            out: String::new(),
+            indent: 0,
+        }
+    }
+
+    fn write(&mut self, text: &str) {
+        self.out.push_str(text);
+    }
+
+    /// Starts a new line at the current indentation.
+    fn fill(&mut self, text: &str) {
+        if !self.out.is_empty() {
+            self.out.push('\n');
+        }
+        for _ in 0..self.indent {
+            self.out.push_str("    ");
+        }
+        self.out.push_str(text);
+    }
+
+    fn body(&mut self, body: List<StmtId>) {
+        let arena = self.arena;
+        for &stmt in &arena[body] {
+            self.stmt(stmt);
+        }
+    }
+
+    fn block(&mut self, body: List<StmtId>) {
+        self.write(":");
+        self.indent += 1;
+        self.body(body);
+        self.indent -= 1;
+    }
+
+    fn else_block(&mut self, orelse: List<StmtId>) {
+        if !orelse.is_empty() {
+            self.fill("else");
+            self.block(orelse);
+        }
+    }
+
+    fn comma_separated<T>(&mut self, items: &[T], mut write: impl FnMut(&mut Self, &T)) {
+        for (i, item) in items.iter().enumerate() {
+            if i > 0 {
+                self.write(", ");
+            }
+            write(self, item);
+        }
+    }
+
+    fn exprs(&mut self, exprs: List<ExprId>, precedence: Precedence) {
+        let arena = self.arena;
+        self.comma_separated(&arena[exprs], |this, &expr| this.expr(expr, precedence));
+    }
+
+    fn decorators(&mut self, decorator_list: List<ExprId>) {
+        if !self.out.is_empty() {
+            self.out.push('\n');
+        }
+        for decorator in &self.arena[decorator_list] {
+            self.fill("@");
+            self.expr(*decorator, Precedence::Test);
+        }
+    }
+
+    fn stmt(&mut self, stmt: StmtId) {
+        let arena = self.arena;
+        let kind = &arena[stmt].kind;
+        let prefix = match kind {
+            StmtKind::AsyncFunctionDef { .. }
+            | StmtKind::AsyncFor { .. }
+            | StmtKind::AsyncWith { .. } => "async ",
+            _ => "",
+        };
+        match kind {
+            StmtKind::FunctionDef {
+                name,
+                args,
+                body,
+                decorator_list,
+                returns,
+                type_params,
+            }
+            | StmtKind::AsyncFunctionDef {
+                name,
+                args,
+                body,
+                decorator_list,
+                returns,
+                type_params,
+            } => {
+                self.decorators(*decorator_list);
+                self.fill(prefix);
+                self.write("def ");
+                self.write(name.as_str());
+                self.type_params(*type_params);
+                self.write("(");
+                self.arguments(args);
+                self.write(")");
+                if let Some(returns) = returns {
+                    self.write(" -> ");
+                    self.expr(*returns, Precedence::Test);
+                }
+                self.block(*body);
+            }
+            StmtKind::ClassDef {
+                name,
+                bases,
+                keywords,
+                body,
+                decorator_list,
+                type_params,
+            } => {
+                self.decorators(*decorator_list);
+                self.fill("class ");
+                self.write(name.as_str());
+                self.type_params(*type_params);
+                if !bases.is_empty() || !keywords.is_empty() {
+                    self.write("(");
+                    self.call_arguments(*bases, *keywords);
+                    self.write(")");
+                }
+                self.block(*body);
+            }
+            StmtKind::Return { value } => {
+                self.fill("return");
+                if let Some(value) = value {
+                    self.write(" ");
+                    self.expr(*value, Precedence::Tuple);
+                }
+            }
+            StmtKind::Delete { targets } => {
+                self.fill("del ");
+                self.exprs(*targets, Precedence::Test);
+            }
+            StmtKind::Assign { targets, value } => {
+                self.fill("");
+                for target in &arena[*targets] {
+                    self.expr(*target, Precedence::Tuple);
+                    self.write(" = ");
+                }
+                self.value(*value);
+            }
+            StmtKind::TypeAlias {
+                name,
+                type_params,
+                value,
+            } => {
+                self.fill("type ");
+                self.expr(*name, Precedence::Atom);
+                self.type_params(*type_params);
+                self.write(" = ");
+                self.expr(*value, Precedence::Test);
+            }
+            StmtKind::AugAssign { target, op, value } => {
+                self.fill("");
+                self.expr(*target, Precedence::Tuple);
+                self.write(" ");
+                self.write(op.symbol());
+                self.write("= ");
+                self.value(*value);
+            }
+            StmtKind::AnnAssign {
+                target,
+                annotation,
+                value,
+                simple,
+            } => {
+                self.fill("");
+                let parenthesize = !simple && matches!(arena[*target].kind, ExprKind::Name { .. });
+                if parenthesize {
+                    self.write("(");
+                }
+                self.expr(*target, Precedence::Test);
+                if parenthesize {
+                    self.write(")");
+                }
+                self.write(": ");
+                self.expr(*annotation, Precedence::Test);
+                if let Some(value) = value {
+                    self.write(" = ");
+                    self.value(*value);
+                }
+            }
+            StmtKind::For {
+                target,
+                iter,
+                body,
+                orelse,
+            }
+            | StmtKind::AsyncFor {
+                target,
+                iter,
+                body,
+                orelse,
+            } => {
+                self.fill(prefix);
+                self.write("for ");
+                self.expr(*target, Precedence::Tuple);
+                self.write(" in ");
+                self.expr(*iter, Precedence::Tuple);
+                self.block(*body);
+                self.else_block(*orelse);
+            }
+            StmtKind::While { test, body, orelse } => {
+                self.fill("while ");
+                self.condition(*test);
+                self.block(*body);
+                self.else_block(*orelse);
+            }
+            StmtKind::If { test, body, orelse } => {
+                self.fill("if ");
+                self.condition(*test);
+                self.block(*body);
+                let mut orelse = *orelse;
+                // An `else` holding only an `if` is written as `elif`.
+                while let [next] = arena[orelse] {
+                    let StmtKind::If {
+                        test,
+                        body,
+                        orelse: next,
+                    } = arena[next].kind
+                    else {
+                        break;
+                    };
+                    self.fill("elif ");
+                    self.condition(test);
+                    self.block(body);
+                    orelse = next;
+                }
+                self.else_block(orelse);
+            }
+            StmtKind::With { items, body } | StmtKind::AsyncWith { items, body } => {
+                self.fill(prefix);
+                self.write("with ");
+                self.comma_separated(&arena[*items], Self::with_item);
+                self.block(*body);
+            }
+            StmtKind::Raise { exc, cause } => {
+                self.fill("raise");
+                if let Some(exc) = exc {
+                    self.write(" ");
+                    self.expr(*exc, Precedence::Test);
+                }
+                if let Some(cause) = cause {
+                    self.write(" from ");
+                    self.expr(*cause, Precedence::Test);
+                }
+            }
+            StmtKind::Try {
+                body,
+                handlers,
+                orelse,
+                finalbody,
+            } => {
+                self.fill("try");
+                self.block(*body);
+                for handler in &arena[*handlers] {
+                    self.except_handler(handler);
+                }
+                self.else_block(*orelse);
+                if !finalbody.is_empty() {
+                    self.fill("finally");
+                    self.block(*finalbody);
+                }
+            }
+            StmtKind::Assert { test, msg } => {
+                self.fill("assert ");
+                self.expr(*test, Precedence::Test);
+                if let Some(msg) = msg {
+                    self.write(", ");
+                    self.expr(*msg, Precedence::Test);
+                }
+            }
+            StmtKind::Import { names } => {
+                self.fill("import ");
+                self.comma_separated(&arena[*names], Self::alias);
+            }
+            StmtKind::ImportFrom {
+                module,
+                names,
+                level,
+            } => {
+                self.fill("from ");
+                self.write(&".".repeat(*level));
+                if let Some(module) = module {
+                    self.write(module.as_str());
+                }
+                self.write(" import ");
+                self.comma_separated(&arena[*names], Self::alias);
+            }
+            StmtKind::Global { names } => {
+                self.fill("global ");
+                self.comma_separated(&arena[*names], |this, name| this.write(name.as_str()));
+            }
+            StmtKind::Nonlocal { names } => {
+                self.fill("nonlocal ");
+                self.comma_separated(&arena[*names], |this, name| this.write(name.as_str()));
+            }
+            StmtKind::Expr { value } => {
+                self.fill("");
+                self.value(*value);
+            }
+            StmtKind::Pass => self.fill("pass"),
+            StmtKind::Break => self.fill("break"),
+            StmtKind::Continue => self.fill("continue"),
+        }
+    }
+
+    fn type_params(&mut self, type_params: List<TypeParam>) {
+        if type_params.is_empty() {
+            return;
+        }
+        self.write("[");
+        let arena = self.arena;
+        self.comma_separated(&arena[type_params], |this, type_param| match &type_param.kind {
+            TypeParamKind::TypeVar { name, bound } => {
+                this.write(name.as_str());
+                if let Some(bound) = bound {
+                    this.write(": ");
+                    this.expr(*bound, Precedence::Test);
+                }
+            }
+            TypeParamKind::ParamSpec { name } => {
+                this.write("**");
+                this.write(name.as_str());
+            }
+            TypeParamKind::TypeVarTuple { name } => {
+                this.write("*");
+                this.write(name.as_str());
+            }
+        });
+        self.write("]");
+    }
+
+    /// The value of an assignment or expression statement, the only place
+    /// a yield expression may go without parentheses.
+    fn value(&mut self, value: ExprId) {
+        let precedence = match self.arena[value].kind {
+            ExprKind::Yield { .. } | ExprKind::YieldFrom { .. } => Precedence::Yield,
+            _ => Precedence::Tuple,
+        };
+        self.expr(value, precedence);
+    }
+
+    /// The test of an `if` or `while` may be an unparenthesised `:=` but
+    /// not a tuple.
+    fn condition(&mut self, test: ExprId) {
+        match &self.arena[test].kind {
+            &ExprKind::Tuple { elts, .. } if !elts.is_empty() => {
+                self.write("(");
+                self.tuple_elements(elts);
+                self.write(")");
+            }
+            _ => self.expr(test, Precedence::NamedExpr),
+        }
+    }
+
+    fn except_handler(&mut self, handler: &ExceptHandler) {
+        self.fill("except");
+        if let Some(type_) = &handler.type_ {
+            self.write(" ");
+            self.expr(*type_, Precedence::Test);
+        }
+        if let Some(name) = &handler.name {
+            self.write(" as ");
+            self.write(name.as_str());
+        }
+        self.block(handler.body);
+    }
+
+    fn with_item(&mut self, item: &WithItem) {
+        self.expr(item.context_expr, Precedence::Test);
+        if let Some(vars) = &item.optional_vars {
+            self.write(" as ");
+            self.expr(*vars, Precedence::Test);
+        }
+    }
+
+    fn alias(&mut self, alias: &Alias) {
+        self.write(alias.name.as_str());
+        if let Some(asname) = &alias.asname {
+            self.write(" as ");
+            self.write(asname.as_str());
+        }
+    }
+
+    fn arguments(&mut self, arguments: &Arguments) {
+        let mut first = true;
+        let mut separator = |this: &mut Self| {
+            if !first {
+                this.write(", ");
+            }
+            first = false;
+        };
+        // Defaults belong to the last positional parameters.
+        let arena = self.arena;
+        let positional = arguments.posonlyargs.len() + arguments.args.len();
+        let first_default = positional - arguments.defaults.len();
+        for (i, arg) in arena[arguments.posonlyargs]
+            .iter()
+            .chain(&arena[arguments.args])
+            .enumerate()
+        {
+            separator(self);
+            self.arg(arg);
+            if i >= first_default {
+                self.write("=");
+                self.expr(arena[arguments.defaults][i - first_default], Precedence::Test);
+            }
+            if i + 1 == arguments.posonlyargs.len() {
+                self.write(", /");
+            }
+        }
+        if arguments.vararg.is_some() || !arguments.kwonlyargs.is_empty() {
+            separator(self);
+            self.write("*");
+            if let Some(vararg) = &arguments.vararg {
+                self.arg(vararg);
+            }
+        }
+        for (arg, default) in arena[arguments.kwonlyargs]
+            .iter()
+            .zip(&arena[arguments.kw_defaults])
+        {
+            separator(self);
+            self.arg(arg);
+            if let Some(default) = default {
+                self.write("=");
+                self.expr(*default, Precedence::Test);
+            }
+        }
+        if let Some(kwarg) = &arguments.kwarg {
+            separator(self);
+            self.write("**");
+            self.arg(kwarg);
+        }
+    }
+
+    fn arg(&mut self, arg: &Arg) {
+        self.write(arg.arg.as_str());
+        if let Some(annotation) = &arg.annotation {
+            self.write(": ");
+            self.expr(*annotation, Precedence::Test);
+        }
+    }
+
+    fn call_arguments(&mut self, args: List<ExprId>, keywords: List<Keyword>) {
+        self.exprs(args, Precedence::Test);
+        for (i, keyword) in self.arena[keywords].iter().enumerate() {
+            if i > 0 || !args.is_empty() {
+                self.write(", ");
+            }
+            match &keyword.arg {
+                Some(arg) => {
+                    self.write(arg.as_str());
+                    self.write("=");
+                }
+                None => self.write("**"),
+            }
+            self.expr(keyword.value, Precedence::Test);
+        }
+    }
+
+    fn comprehensions(&mut self, generators: List<Comprehension>) {
+        let arena = self.arena;
+        for comprehension in &arena[generators] {
+            self.write(if comprehension.is_async {
+                " async for "
+            } else {
+                " for "
+            });
+            self.expr(comprehension.target, Precedence::Tuple);
+            self.write(" in ");
+            self.expr(comprehension.iter, Precedence::Or);
+            for condition in &arena[comprehension.ifs] {
+                self.write(" if ");
+                self.expr(*condition, Precedence::Or);
+            }
+        }
+    }
+
+    /// Writes the elements of a tuple: a single element keeps its comma.
+    fn tuple_elements(&mut self, elts: List<ExprId>) {
+        self.exprs(elts, Precedence::Test);
+        if elts.len() == 1 {
+            self.write(",");
+        }
+    }
+
+    fn constant(&mut self, value: &Constant) {
+        match value {
+            Constant::Float(value) if value.is_infinite() => {
+                self.write(if *value > 0.0 { INFINITY } else { "-1e309" })
+            }
+            Constant::Float(value) if value.is_nan() => {
+                self.write(&format!("({}-{})", INFINITY, INFINITY))
+            }
+            Constant::Ellipsis => self.write("..."),
+            Constant::Tuple(items) => {
+                self.write("(");
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        self.write(", ");
+                    }
+                    self.constant(item);
+                }
+                if items.len() == 1 {
+                    self.write(",");
+                }
+                self.write(")");
+            }
+            _ => self.write(&value.repr()),
+        }
+    }
+
+    /// The precedence of `expr` itself; `None` for expressions that never
+    /// need parentheses.
+    fn precedence_of(&self, expr: ExprId) -> Option<Precedence> {
+        let precedence = match &self.arena[expr].kind {
+            ExprKind::NamedExpr { .. } => Precedence::NamedExpr,
+            ExprKind::Tuple { elts, .. } if !elts.is_empty() => Precedence::Tuple,
+            ExprKind::Lambda { .. } | ExprKind::IfExp { .. } => Precedence::Test,
+            ExprKind::BoolOp {
+                op: BoolOperator::Or,
+                ..
+            } => Precedence::Or,
+            ExprKind::BoolOp {
+                op: BoolOperator::And,
+                ..
+            } => Precedence::And,
+            ExprKind::UnaryOp {
+                op: UnaryOperator::Not,
+                ..
+            } => Precedence::Not,
+            ExprKind::UnaryOp { .. } => Precedence::Factor,
+            ExprKind::Await { .. } => Precedence::Await,
+            ExprKind::Yield { .. } | ExprKind::YieldFrom { .. } => Precedence::Yield,
+            ExprKind::Compare { .. } => Precedence::Cmp,
+            ExprKind::BinOp { op, .. } => binop_precedence(*op),
+            ExprKind::Constant {
+                value: Constant::Int(value),
+            } if *value < 0 => Precedence::Factor,
+            ExprKind::Constant {
+                value: Constant::Float(value),
+            } if value.is_sign_negative() && !value.is_nan() => Precedence::Factor,
+            _ => return None,
+        };
+        Some(precedence)
+    }
+
+    /// Writes `expr` in a context that requires at least `precedence`.
+    fn expr(&mut self, expr: ExprId, precedence: Precedence) {
+        let parenthesize = self.precedence_of(expr).is_some_and(|own| own < precedence);
+        if parenthesize {
+            self.write("(");
+        }
+        self.expr_inner(expr);
+        if parenthesize {
+            self.write(")");
+        }
+    }
+
+    fn expr_inner(&mut self, expr: ExprId) {
+        let arena = self.arena;
+        match &arena[expr].kind {
+            ExprKind::BoolOp { op, values } => {
+                let (keyword, precedence) = match op {
+                    BoolOperator::And => (" and ", Precedence::And),
+                    BoolOperator::Or => (" or ", Precedence::Or),
+                };
+                for (i, value) in arena[*values].iter().enumerate() {
+                    if i > 0 {
+                        self.write(keyword);
+                    }
+                    self.expr(*value, precedence.next());
+                }
+            }
+            ExprKind::NamedExpr { target, value } => {
+                self.expr(*target, Precedence::Atom);
+                self.write(" := ");
+                self.expr(*value, Precedence::Test);
+            }
+            ExprKind::BinOp { left, op, right } => {
+                let precedence = binop_precedence(*op);
+                // `**` is right-associative and takes a unary operand on its
+                // right: `a ** -b`.
+                let (left_precedence, right_precedence) = if *op == Operator::Pow {
+                    (precedence.next(), Precedence::Factor)
+                } else {
+                    (precedence, precedence.next())
+                };
+                self.expr(*left, left_precedence);
+                self.write(" ");
+                self.write(op.symbol());
+                self.write(" ");
+                self.expr(*right, right_precedence);
+            }
+            ExprKind::UnaryOp { op, operand } => {
+                self.write(op.symbol());
+                let precedence = match op {
+                    UnaryOperator::Not => Precedence::Not,
+                    _ => Precedence::Factor,
+                };
+                self.expr(*operand, precedence);
+            }
+            ExprKind::Await { value } => {
+                self.write("await ");
+                self.expr(*value, Precedence::Atom);
+            }
+            ExprKind::Yield { value } => {
+                self.write("yield");
+                if let Some(value) = value {
+                    self.write(" ");
+                    self.expr(*value, Precedence::Tuple);
+                }
+            }
+            ExprKind::YieldFrom { value } => {
+                self.write("yield from ");
+                self.expr(*value, Precedence::Test);
+            }
+            ExprKind::Lambda { args, body } => {
+                self.write("lambda");
+                let mut params = Unparser::new(arena);
+                params.arguments(args);
+                if !params.out.is_empty() {
+                    self.write(" ");
+                    self.write(&params.out);
+                }
+                self.write(": ");
+                self.expr(*body, Precedence::Test);
+            }
+            ExprKind::IfExp { test, body, orelse } => {
+                self.expr(*body, Precedence::Or);
+                self.write(" if ");
+                self.expr(*test, Precedence::Or);
+                self.write(" else ");
+                self.expr(*orelse, Precedence::Test);
+            }
+            ExprKind::Dict { keys, values } => {
+                self.write("{");
+                for (i, (key, value)) in arena[*keys].iter().zip(&arena[*values]).enumerate() {
+                    if i > 0 {
+                        self.write(", ");
+                    }
+                    match key {
+                        Some(key) => {
+                            self.expr(*key, Precedence::Test);
+                            self.write(": ");
+                            self.expr(*value, Precedence::Test);
+                        }
+                        None => {
+                            self.write("**");
+                            self.expr(*value, Precedence::BitOr);
+                        }
+                    }
+                }
+                self.write("}");
+            }
+            ExprKind::Set { elts } => {
+                if elts.is_empty() {
+                    self.write("{*()}");
+                } else {
+                    self.write("{");
+                    self.exprs(*elts, Precedence::Test);
+                    self.write("}");
+                }
+            }
+            ExprKind::ListComp { elt, generators } => {
+                self.write("[");
+                self.expr(*elt, Precedence::Test);
+                self.comprehensions(*generators);
+                self.write("]");
+            }
+            ExprKind::SetComp { elt, generators } => {
+                self.write("{");
+                self.expr(*elt, Precedence::Test);
+                self.comprehensions(*generators);
+                self.write("}");
+            }
+            ExprKind::DictComp {
+                key,
+                value,
+                generators,
+            } => {
+                self.write("{");
+                self.expr(*key, Precedence::Test);
+                self.write(": ");
+                self.expr(*value, Precedence::Test);
+                self.comprehensions(*generators);
+                self.write("}");
+            }
+            ExprKind::GeneratorExp { elt, generators } => {
+                self.write("(");
+                self.expr(*elt, Precedence::Test);
+                self.comprehensions(*generators);
+                self.write(")");
+            }
+            ExprKind::Compare {
+                left,
+                ops,
+                comparators,
+            } => {
+                self.expr(*left, Precedence::Cmp.next());
+                for (op, comparator) in arena[*ops].iter().zip(&arena[*comparators]) {
+                    self.write(" ");
+                    self.write(op.symbol());
+                    self.write(" ");
+                    self.expr(*comparator, Precedence::Cmp.next());
+                }
+            }
+            ExprKind::Call {
+                func,
+                args,
+                keywords,
+            } => {
+                self.expr(*func, Precedence::Atom);
+                self.write("(");
+                self.call_arguments(*args, *keywords);
+                self.write(")");
+            }
+            ExprKind::Constant { value } => self.constant(value),
+            ExprKind::Attribute { value, attr, .. } => {
+                self.expr(*value, Precedence::Atom);
+                // `1.x` would read as a float literal.
+                if let ExprKind::Constant {
+                    value: Constant::Int(0..),
+                } = arena[*value].kind
+                {
+                    self.write(" ");
+                }
+                self.write(".");
+                self.write(attr.as_str());
+            }
+            ExprKind::Subscript { value, slice, .. } => {
+                self.expr(*value, Precedence::Atom);
+                self.write("[");
+                match &arena[*slice].kind {
+                    ExprKind::Tuple { elts, .. } if !elts.is_empty() => self.tuple_elements(*elts),
+                    _ => self.expr(*slice, Precedence::Tuple),
+                }
+                self.write("]");
+            }
+            ExprKind::Starred { value, .. } => {
+                self.write("*");
+                self.expr(*value, Precedence::BitOr);
+            }
+            ExprKind::Name { id, .. } => self.write(id.as_str()),
+            ExprKind::List { elts, .. } => {
+                self.write("[");
+                self.exprs(*elts, Precedence::Test);
+                self.write("]");
+            }
+            ExprKind::Tuple { elts, .. } => {
+                if elts.is_empty() {
+                    self.write("()");
+                } else {
+                    self.tuple_elements(*elts);
+                }
+            }
+            ExprKind::Slice { lower, upper, step } => {
+                if let Some(lower) = lower {
+                    self.expr(*lower, Precedence::Test);
+                }
+                self.write(":");
+                if let Some(upper) = upper {
+                    self.expr(*upper, Precedence::Test);
+                }
+                if let Some(step) = step {
+                    self.write(":");
+                    self.expr(*step, Precedence::Test);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{dump, CmpOp, ExprContext, ListItem, Span};
+    use crate::intern::Symbol;
+    use crate::parser::parse;
+
+    fn round_trip(source: &str) -> String {
+        unparse(&parse(source).unwrap())
+    }
+
+    fn assert_round_trips(module: &Module) {
+        let source = unparse(module);
+        let reparsed = parse(&source).unwrap_or_else(|errors| panic!("{}\n{}", source, errors[0]));
+        assert_eq!(dump(&reparsed), dump(module), "{}", source);
+    }
+
+    #[test]
+    fn test_unparse_round_trips_test_files() {
+        let mut checked = 0;
+        for entry in std::fs::read_dir("tests").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|extension| extension == "py") {
+                let source = std::fs::read_to_string(&path).unwrap();
+                if let Ok(module) = parse(&source) {
+                    assert_round_trips(&module);
+                    checked += 1;
+                }
+            }
+        }
+        assert!(checked > 0);
+    }
+
+    #[test]
+    fn test_unparse_minimal_parentheses() {
+        for (source, expected) in [
+            ("(a + b) * c", "(a + b) * c"),
+            ("a + (b * c)", "a + b * c"),
+            ("a - (b - c)", "a - (b - c)"),
+            ("(a - b) - c", "a - b - c"),
+            ("(a ** b) ** c", "(a ** b) ** c"),
+            ("a ** (b ** c)", "a ** b ** c"),
+            ("a ** (-b)", "a ** -b"),
+            ("(-a) ** b", "(-a) ** b"),
+            ("-(a ** b)", "-a ** b"),
+            ("not (a and b)", "not (a and b)"),
+            ("(not a) and b", "not a and b"),
+            ("a or (b and c)", "a or b and c"),
+            ("(a or b) and c", "(a or b) and c"),
+            ("(a < b) < c", "(a < b) < c"),
+            ("(lambda: x)()", "(lambda: x)()"),
+            (
+                "lambda x, *y, z=1, **w: (x if y else z)",
+                "lambda x, *y, z=1, **w: x if y else z",
+            ),
+            ("(a if b else c) if d else e", "(a if b else c) if d else e"),
+            ("x = (1, 2)", "x = 1, 2"),
+            ("x = ()", "x = ()"),
+            ("x = (1,)", "x = 1,"),
+            ("f((1, 2))", "f((1, 2))"),
+            ("a[(1, 2)]", "a[1, 2]"),
+            ("a[1:2, ::3]", "a[1:2, ::3]"),
+            ("(x := 1)", "(x := 1)"),
+            ("if (x := 1): pass", "if x := 1:\n    pass"),
+            ("while (a, b): pass", "while (a, b):\n    pass"),
+            ("x = ...", "x = ..."),
+            ("(1).real", "1 .real"),
+            ("(1.5).real", "1.5.real"),
+            ("(-1).real", "(-1).real"),
+            ("[*(a or b)]", "[*(a or b)]"),
+            ("{**(a or b)}", "{**(a or b)}"),
+            ("set()", "set()"),
+            (
+                "[x for (x, y) in (a, b) if (c if d else e)]",
+                "[x for x, y in (a, b) if (c if d else e)]",
+            ),
+            ("f(x for x in y)", "f((x for x in y))"),
+            ("x = 1e400", "x = 1e309"),
+            ("'a' 'b'", "'ab'"),
+        ] {
+            assert_eq!(round_trip(source), expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_unparse_async() {
+        for (source, expected) in [
+            ("await x ** 2", "await x ** 2"),
+            ("(await x) ** 2", "await x ** 2"),
+            ("(await x).y", "(await x).y"),
+            ("-(await x)", "-await x"),
+            ("await (a + b)", "await (a + b)"),
+            ("[x async for x in await y]", "[x async for x in await y]"),
+            (
+                "async for a in b:\n  async with c as d, e: pass",
+                "async for a in b:\n    async with c as d, e:\n        pass",
+            ),
+        ] {
+            let source = format!("async def f():\n    {}", source.replace('\n', "\n    "));
+            let expected = format!("async def f():\n    {}", expected.replace('\n', "\n    "));
+            assert_eq!(round_trip(&source), expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_unparse_yield() {
+        for (source, expected) in [
+            ("x = (yield a, b)", "x = yield a, b"),
+            ("x += (yield)", "x += yield"),
+            ("x: int = (yield from a)", "x: int = yield from a"),
+            ("(yield) + 1", "(yield) + 1"),
+            ("f((yield))", "f((yield))"),
+            ("yield (yield)", "yield (yield)"),
+            ("yield (x if y else z)", "yield x if y else z"),
+            ("yield from (a, b)", "yield from (a, b)"),
+            ("yield (a := 1)", "yield (a := 1)"),
+            ("return (yield)", "return (yield)"),
+            ("a[(yield)]", "a[(yield)]"),
+            ("lambda: (yield)", "lambda: (yield)"),
+        ] {
+            let source = format!("def f():\n    {}", source);
+            let expected = format!("def f():\n    {}", expected);
+            assert_eq!(round_trip(&source), expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_unparse_type_params() {
+        for source in [
+            "def f[T, *Ts, **P](x: T) -> T:\n    pass",
+            "class Box[T: int, U: (int, str)](Base[T]):\n    pass",
+            "type Alias[T: lambda: int] = list[T]",
+            "type X = int | None",
+        ] {
+            assert_eq!(round_trip(source), source);
+        }
+    }
+
+    #[test]
+    fn test_unparse_statements() {
+        let source = "\
+import os.path as p
+from .. import a, b as c
+@d
+class A(B, metaclass=M):
+    x: int = 1
+    (y): int
+    def f(self, a, /, b=2, *, c, d=3, **e) -> None:
+        global g
+        for i in range(10):
+            if i:
+                continue
+            elif i > 1:
+                break
+            else:
+                pass
+        else:
+            return
+try:
+    del a, b
+except (E, F) as e:
+    raise X from e
+finally:
+    assert a, 'm'
+";
+        let expected = "\
+import os.path as p
+from .. import a, b as c
+
+@d
+class A(B, metaclass=M):
+    x: int = 1
+    (y): int
+
+    def f(self, a, /, b=2, *, c, d=3, **e) -> None:
+        global g
+        for i in range(10):
+            if i:
+                continue
+            elif i > 1:
+                break
+            else:
+                pass
+        else:
+            return
+try:
+    del a, b
+except (E, F) as e:
+    raise X from e
+finally:
+    assert a, 'm'";
+        assert_eq!(round_trip(source), expected);
+    }
+
+    /// A xorshift generator, so failures reproduce.
+    struct Random(u64);
+
+    impl Random {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+
+        fn pick<T: Clone>(&mut self, items: &[T]) -> T {
+            items[self.below(items.len())].clone()
+        }
+    }
+
+    /// Builds random trees in an arena.
+    struct Generator {
+        random: Random,
+        arena: Arena,
+    }
+
+    impl Generator {
+        fn below(&mut self, n: usize) -> usize {
+            self.random.below(n)
+        }
+
+        fn pick<T: Clone>(&mut self, items: &[T]) -> T {
+            self.random.pick(items)
+        }
+
+        fn expr(&mut self, kind: ExprKind) -> ExprId {
+            self.arena.add_expr(kind, Span::default())
+        }
+
+        fn name(&mut self, ctx: ExprContext) -> ExprId {
+            let id = Symbol::intern(self.pick(&["a", "b", "c", "d"]));
+            self.expr(ExprKind::Name { id, ctx })
+        }
+
+        /// A list of `len` items made by `item`.
+        fn list<T: ListItem>(
+            &mut self,
+            len: usize,
+            mut item: impl FnMut(&mut Self) -> T,
+        ) -> List<T> {
+            let items: Vec<T> = (0..len).map(|_| item(self)).collect();
+            self.arena.add_list(items)
+        }
+
+        fn exprs(&mut self, depth: usize, max: usize) -> List<ExprId> {
+            let len = self.below(max + 1);
+            self.list(len, |this| this.random_expr(depth))
+        }
+
+        fn comprehensions(&mut self, depth: usize) -> List<Comprehension> {
+            let len = 1 + self.below(2);
+            self.list(len, |this| Comprehension {
+                target: this.name(ExprContext::Store),
+                iter: this.random_expr(depth),
+                ifs: this.exprs(depth, 1),
+                is_async: false,
+            })
+        }
+
+        fn random_expr(&mut self, depth: usize) -> ExprId {
+            if depth == 0 {
+                let kind = match self.below(3) {
+                    0 => ExprKind::Constant {
+                        value: self.pick(&[
+                            Constant::Int(0),
+                            Constant::Int(7),
+                            Constant::Float(2.5),
+                            Constant::Bool(true),
+                            Constant::None,
+                            Constant::Ellipsis,
+                        ]),
+                    },
+                    1 => ExprKind::Constant {
+                        value: Constant::Str(self.pick(&["s", "it's", ""]).to_string()),
+                    },
+                    _ => return self.name(ExprContext::Load),
+                };
+                return self.expr(kind);
+            }
+            let depth = depth - 1;
+            let kind = match self.below(19) {
+                0 => {
+                    let op = self.pick(&[BoolOperator::And, BoolOperator::Or]);
+                    let len = 2 + self.below(2);
+                    ExprKind::BoolOp {
+                        op,
+                        values: self.list(len, |this| this.random_expr(depth)),
+                    }
+                }
+                1 => ExprKind::NamedExpr {
+                    target: self.name(ExprContext::Store),
+                    value: self.random_expr(depth),
+                },
+                2 | 3 => ExprKind::BinOp {
+                    left: self.random_expr(depth),
+                    op: self.pick(&[
+                        Operator::Add,
+                        Operator::Sub,
+                        Operator::Mult,
+                        Operator::MatMult,
+                        Operator::Div,
+                        Operator::Mod,
+                        Operator::Pow,
+                        Operator::LShift,
+                        Operator::RShift,
+                        Operator::BitOr,
+                        Operator::BitXor,
+                        Operator::BitAnd,
+                        Operator::FloorDiv,
+                    ]),
+                    right: self.random_expr(depth),
+                },
+                4 => ExprKind::UnaryOp {
+                    op: self.pick(&[
+                        UnaryOperator::Invert,
+                        UnaryOperator::Not,
+                        UnaryOperator::UAdd,
+                        UnaryOperator::USub,
+                    ]),
+                    operand: self.random_expr(depth),
+                },
+                5 => {
+                    let len = self.below(2);
+                    ExprKind::Lambda {
+                        args: Box::new(Arguments {
+                            args: self.list(len, |_| Arg {
+                                arg: Symbol::intern("p"),
+                                annotation: None,
+                                span: Span::default(),
+                            }),
+                            ..Arguments::default()
+                        }),
+                        body: self.random_expr(depth),
+                    }
+                }
+                6 => ExprKind::IfExp {
+                    test: self.random_expr(depth),
+                    body: self.random_expr(depth),
+                    orelse: self.random_expr(depth),
+                },
+                7 => {
+                    let keys: Vec<_> = (0..self.below(3))
+                        .map(|_| (self.below(4) > 0).then(|| self.random_expr(depth)))
+                        .collect();
+                    let values = self.list(keys.len(), |this| this.random_expr(depth));
+                    ExprKind::Dict {
+                        keys: self.arena.add_list(keys),
+                        values,
+                    }
+                }
+                8 => {
+                    let len = 1 + self.below(3);
+                    ExprKind::Set {
+                        elts: self.list(len, |this| this.random_expr(depth)),
+                    }
+                }
+                9 => ExprKind::ListComp {
+                    elt: self.random_expr(depth),
+                    generators: self.comprehensions(depth),
+                },
+                10 => ExprKind::DictComp {
+                    key: self.random_expr(depth),
+                    value: self.random_expr(depth),
+                    generators: self.comprehensions(depth),
+                },
+                11 => ExprKind::GeneratorExp {
+                    elt: self.random_expr(depth),
+                    generators: self.comprehensions(depth),
+                },
+                12 => {
+                    let ops: Vec<_> = (0..1 + self.below(2))
+                        .map(|_| {
+                            self.pick(&[
+                                CmpOp::Eq,
+                                CmpOp::Lt,
+                                CmpOp::GtE,
+                                CmpOp::IsNot,
+                                CmpOp::NotIn,
+                            ])
+                        })
+                        .collect();
+                    let left = self.random_expr(depth);
+                    let comparators = self.list(ops.len(), |this| this.random_expr(depth));
+                    ExprKind::Compare {
+                        left,
+                        ops: self.arena.add_list(ops),
+                        comparators,
+                    }
+                }
+                13 => {
+                    let func = self.random_expr(depth);
+                    let args = self.exprs(depth, 2);
+                    let len = self.below(2);
+                    let keywords = self.list(len, |this| Keyword {
+                        arg: Some(Symbol::intern("k")),
+                        value: this.random_expr(depth),
+                    });
+                    ExprKind::Call {
+                        func,
+                        args,
+                        keywords,
+                    }
+                }
+                14 => ExprKind::Attribute {
+                    value: self.random_expr(depth),
+                    attr: Symbol::intern("x"),
+                    ctx: ExprContext::Load,
+                },
+                15 => {
+                    let slice = match self.below(3) {
+                        0 => {
+                            let kind = ExprKind::Slice {
+                                lower: (self.below(2) == 0).then(|| self.random_expr(depth)),
+                                upper: (self.below(2) == 0).then(|| self.random_expr(depth)),
+                                step: (self.below(2) == 0).then(|| self.random_expr(depth)),
+                            };
+                            self.expr(kind)
+                        }
+                        _ => self.random_expr(depth),
+                    };
+                    ExprKind::Subscript {
+                        value: self.random_expr(depth),
+                        slice,
+                        ctx: ExprContext::Load,
+                    }
+                }
+                16 => ExprKind::List {
+                    elts: self.exprs(depth, 3),
+                    ctx: ExprContext::Load,
+                },
+                17 => ExprKind::Tuple {
+                    elts: self.exprs(depth, 3),
+                    ctx: ExprContext::Load,
+                },
+                _ => ExprKind::Starred {
+                    value: self.random_expr(depth),
+                    ctx: ExprContext::Load,
+                },
+            };
+            // A starred expression is only valid as an element of a display.
+            let starred = matches!(kind, ExprKind::Starred { .. });
+            let node = self.expr(kind);
+            if starred {
+                let elts = self.arena.add_list([node]);
+                return self.expr(ExprKind::List {
+                    elts,
+                    ctx: ExprContext::Load,
+                });
+            }
+            node
+        }
+
+        fn body(&mut self, depth: usize) -> List<StmtId> {
+            let len = 1 + self.below(2);
+            self.list(len, |this| this.random_stmt(depth.saturating_sub(1)))
+        }
+
+        fn random_stmt(&mut self, depth: usize) -> StmtId {
+            let kind = match self.below(if depth == 0 { 3 } else { 6 }) {
+                0 => StmtKind::Expr {
+                    value: self.random_expr(3),
+                },
+                1 => {
+                    let target = self.name(ExprContext::Store);
+                    StmtKind::Assign {
+                        targets: self.arena.add_list([target]),
+                        value: self.random_expr(3),
+                    }
+                }
+                2 => StmtKind::Return {
+                    value: (self.below(2) == 0).then(|| self.random_expr(3)),
+                },
+                3 => StmtKind::If {
+                    test: self.random_expr(2),
+                    body: self.body(depth),
+                    orelse: if self.below(2) == 0 {
+                        List::new()
+                    } else {
+                        self.body(depth)
+                    },
+                },
+                4 => StmtKind::While {
+                    test: self.random_expr(2),
+                    body: self.body(depth),
+                    orelse: List::new(),
+                },
+                _ => StmtKind::For {
+                    target: self.name(ExprContext::Store),
+                    iter: self.random_expr(2),
+                    body: self.body(depth),
+                    orelse: List::new(),
+                },
+            };
+            self.arena.add_stmt(kind, Span::default())
+        }
+    }
+
+    #[test]
+    fn test_unparse_round_trips_random_asts() {
+        let mut generator = Generator {
+            random: Random(0x2545_f491_4f6c_dd1d),
+            arena: Arena::new(),
+        };
+        for _ in 0..500 {
+            let len = 1 + generator.below(3);
+            let body = generator.list(len, |generator| generator.random_stmt(2));
+            let arena = std::mem::take(&mut generator.arena);
+            assert_round_trips(&Module { body, arena });
+        }
+    }
+}
@@ -1,31 +1,1252 @@
-// use std::{cell::RefCell, rc::Rc};
-
-// use crate::{environment::Environment, parser::Stmt, token::Token};
-
-// #[derive(Debug, Clone, PartialEq)]
-// pub enum Value {
-//     Int(i32),
-//     Bool(bool),
-
-//     // used for break
-//     Interrupted,
-//     Null,
-//     Function(Function),
-//     Builtin(String),
-// }
-
-// #[derive(Debug, Clone, PartialEq)]
-// pub struct Function {
-//     pub name: String,
-//     pub args: Vec<String>,
-//     pub body: Box<Stmt>,
-// }
-
-
-// pub fn builtin_print(args: Vec<Value>) -> Value {
-//     for arg in args {
-//         print!("{:?} ", arg);
-//     }
-//     println!();
-//     Value::Null
-// }
\ No newline at end of file
+//! Run-time values and the operations on them.
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::ast::{repr_float, repr_str, CmpOp, Constant, Operator, UnaryOperator};
+use crate::code::CodeObject;
+use crate::intern::Symbol;
+
+/// A variable shared between the function binding it and the functions
+/// nested in it that use it. It is empty until the variable is first
+/// assigned, and every closure sees later assignments, not a copy of the
+/// value at the time the closure was made.
+pub type Cell = RefCell<Option<Value>>;
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    None,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(Rc<str>),
+    Tuple(Rc<[Value]>),
+    List(Rc<RefCell<Vec<Value>>>),
+    /// A dict, its items in the order their keys were first inserted.
+    /// Keys are found by comparing them, not by hashing.
+    Dict(Rc<RefCell<Vec<(Value, Value)>>>),
+    /// A set, its items in the order they were first added, found by
+    /// comparing them like the keys of a dict.
+    Set(Rc<RefCell<Vec<Value>>>),
+    Range(Range),
+    Iterator(Rc<RefCell<Iter>>),
+    Function(Rc<Function>),
+    Builtin(Builtin),
+    Code(Rc<CodeObject>),
+    Cell(Rc<Cell>),
+    /// An exception, as a handler catches it or as calling its class makes
+    /// it.
+    Exception(Rc<Exception>),
+    /// A built-in exception class, one of [`EXCEPTION_TYPES`].
+    ExceptionType(&'static str),
+    Class(Rc<Class>),
+    Instance(Rc<Instance>),
+    /// A function of a class bound to an instance, which it is called
+    /// with first.
+    Method(Rc<Function>, Rc<Instance>),
+    /// A method of a built-in type bound to the value it belongs to, such
+    /// as a list's `append`.
+    BuiltinMethod(Builtin, Box<Value>),
+    TypeVar(Rc<TypeVar>),
+    TypeAlias(Rc<TypeAlias>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Range {
+    pub start: i64,
+    pub stop: i64,
+    pub step: i64,
+}
+
+/// The state of a `for` loop over a value.
+#[derive(Debug)]
+pub enum Iter {
+    Range {
+        next: i64,
+        stop: i64,
+        step: i64,
+    },
+    Seq {
+        items: Rc<[Value]>,
+        index: usize,
+    },
+    List {
+        list: Rc<RefCell<Vec<Value>>>,
+        index: usize,
+    },
+    /// The keys of a dict.
+    Dict {
+        dict: Rc<RefCell<Vec<(Value, Value)>>>,
+        index: usize,
+    },
+    Set {
+        set: Rc<RefCell<Vec<Value>>>,
+        index: usize,
+    },
+}
+
+impl Iterator for Iter {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        match self {
+            Iter::Range { next, stop, step } => {
+                if (*step > 0 && *next >= *stop) || (*step < 0 && *next <= *stop) {
+                    return None;
+                }
+                let value = *next;
+                *next += *step;
+                Some(Value::Int(value))
+            }
+            Iter::Seq { items, index } => {
+                let item = items.get(*index)?.clone();
+                *index += 1;
+                Some(item)
+            }
+            // A list is read afresh at each step, so appending to it while
+            // looping over it extends the loop, as in CPython.
+            Iter::List { list: items, index } | Iter::Set { set: items, index } => {
+                let item = items.borrow().get(*index)?.clone();
+                *index += 1;
+                Some(item)
+            }
+            Iter::Dict { dict, index } => {
+                let key = dict.borrow().get(*index)?.0.clone();
+                *index += 1;
+                Some(key)
+            }
+        }
+    }
+}
+
+/// A function made by a `def` or `lambda` at run time: its code, and the
+/// cells holding the variables it uses from enclosing functions, one for
+/// each of the code's free variables, in the same order. Its defaults and
+/// annotations are evaluated when it is made.
+#[derive(Debug)]
+pub struct Function {
+    pub code: Rc<CodeObject>,
+    pub qualname: Rc<str>,
+    pub closure: Vec<Rc<Cell>>,
+    /// The defaults of the last positional parameters.
+    pub defaults: Vec<Value>,
+    /// The defaults of keyword-only parameters, by name.
+    pub kwdefaults: Vec<(Symbol, Value)>,
+    /// The annotations of the parameters, and of the result as `return`.
+    pub annotations: Vec<(Symbol, Value)>,
+    /// The type parameters of a generic function, `T` of `def f[T]()`.
+    pub type_params: Vec<Value>,
+}
+
+/// A value that a function without parameters computes the first time it
+/// is asked for, as the value of a type alias is.
+#[derive(Debug)]
+pub enum Lazy {
+    Pending(Rc<Function>),
+    Ready(Value),
+}
+
+/// A type parameter of a generic function, class or type alias.
+#[derive(Debug)]
+pub struct TypeVar {
+    pub kind: TypeVarKind,
+    pub name: Symbol,
+    /// The bound of a `TypeVar`, `int` of `T: int`, or its constraints,
+    /// `(int, str)` of `T: (int, str)`.
+    pub bound: Option<RefCell<Lazy>>,
+    pub constraints: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TypeVarKind {
+    /// `T`.
+    TypeVar,
+    /// `**P`.
+    ParamSpec,
+    /// `*Ts`.
+    TypeVarTuple,
+}
+
+impl TypeVarKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            TypeVarKind::TypeVar => "TypeVar",
+            TypeVarKind::ParamSpec => "ParamSpec",
+            TypeVarKind::TypeVarTuple => "TypeVarTuple",
+        }
+    }
+}
+
+/// The alias a `type` statement makes, its value evaluated only when it is
+/// asked for.
+#[derive(Debug)]
+pub struct TypeAlias {
+    pub name: Rc<str>,
+    pub type_params: Vec<Value>,
+    pub value: RefCell<Lazy>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Builtin {
+    /// `__build_class__`, which runs the body of a `class` statement and
+    /// makes the class from the namespace it leaves.
+    BuildClass,
+    Len,
+    Print,
+    Range,
+    Repr,
+    /// `list.append`, which is only reached through a list.
+    ListAppend,
+}
+
+impl Builtin {
+    pub const ALL: [Builtin; 5] = [
+        Builtin::BuildClass,
+        Builtin::Len,
+        Builtin::Print,
+        Builtin::Range,
+        Builtin::Repr,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Builtin::BuildClass => "__build_class__",
+            Builtin::Len => "len",
+            Builtin::Print => "print",
+            Builtin::Range => "range",
+            Builtin::Repr => "repr",
+            Builtin::ListAppend => "append",
+        }
+    }
+
+    /// The name errors give the builtin, with its type for a method.
+    pub fn qualname(self) -> &'static str {
+        match self {
+            Builtin::ListAppend => "list.append",
+            _ => self.name(),
+        }
+    }
+}
+
+/// A class made by a `class` statement: its name and the namespace its
+/// body left, where its instances look up what they lack themselves.
+#[derive(Debug)]
+pub struct Class {
+    pub name: Symbol,
+    pub qualname: Rc<str>,
+    pub attrs: RefCell<Vec<(Symbol, Value)>>,
+}
+
+/// An instance of a [`Class`], with attributes of its own.
+#[derive(Debug)]
+pub struct Instance {
+    pub class: Rc<Class>,
+    pub attrs: RefCell<Vec<(Symbol, Value)>>,
+}
+
+impl Class {
+    /// The class attribute `name`, if the class body bound it.
+    pub fn lookup(&self, name: Symbol) -> Option<Value> {
+        lookup(&self.attrs.borrow(), name)
+    }
+}
+
+impl Instance {
+    /// The attribute `name` of the instance: its own, or else its class's,
+    /// a function of which is bound to the instance.
+    pub fn attribute(self: &Rc<Instance>, name: Symbol) -> Option<Value> {
+        if let Some(value) = lookup(&self.attrs.borrow(), name) {
+            return Some(value);
+        }
+        Some(match self.class.lookup(name)? {
+            Value::Function(function) => Value::Method(function, self.clone()),
+            value => value,
+        })
+    }
+
+    pub fn set_attribute(&self, name: Symbol, value: Value) {
+        set(&mut self.attrs.borrow_mut(), name, value);
+    }
+}
+
+/// The value bound to `name` among `attrs`.
+fn lookup(attrs: &[(Symbol, Value)], name: Symbol) -> Option<Value> {
+    attrs
+        .iter()
+        .find(|(attr, _)| *attr == name)
+        .map(|(_, value)| value.clone())
+}
+
+/// Binds `name` among `attrs` to `value`, in place of any value it had.
+pub fn set(attrs: &mut Vec<(Symbol, Value)>, name: Symbol, value: Value) {
+    match attrs.iter_mut().find(|(attr, _)| *attr == name) {
+        Some(attr) => attr.1 = value,
+        None => attrs.push((name, value)),
+    }
+}
+
+/// Unbinds `name` among `attrs`, returning whether it was bound.
+pub fn unset(attrs: &mut Vec<(Symbol, Value)>, name: Symbol) -> bool {
+    let found = attrs.iter().position(|(attr, _)| *attr == name);
+    if let Some(at) = found {
+        attrs.remove(at);
+    }
+    found.is_some()
+}
+
+/// The built-in exception classes, each with its base class, but for
+/// `BaseException`, at the root.
+pub const EXCEPTION_TYPES: [(&str, &str); 20] = [
+    ("BaseException", ""),
+    ("Exception", "BaseException"),
+    ("ArithmeticError", "Exception"),
+    ("OverflowError", "ArithmeticError"),
+    ("ZeroDivisionError", "ArithmeticError"),
+    ("AssertionError", "Exception"),
+    ("AttributeError", "Exception"),
+    ("LookupError", "Exception"),
+    ("IndexError", "LookupError"),
+    ("KeyError", "LookupError"),
+    ("NameError", "Exception"),
+    ("UnboundLocalError", "NameError"),
+    ("OSError", "Exception"),
+    ("RuntimeError", "Exception"),
+    ("NotImplementedError", "RuntimeError"),
+    ("RecursionError", "RuntimeError"),
+    ("StopIteration", "Exception"),
+    ("SystemError", "Exception"),
+    ("TypeError", "Exception"),
+    ("ValueError", "Exception"),
+];
+
+/// Whether the exception class `kind` is `base` or derives from it.
+pub fn is_subclass(kind: &str, base: &str) -> bool {
+    let mut kind = kind;
+    while kind != base {
+        match EXCEPTION_TYPES.iter().find(|(name, _)| *name == kind) {
+            Some((_, parent)) if !parent.is_empty() => kind = parent,
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// A Python exception, raised by the virtual machine or by a `raise`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exception {
+    /// The exception's class, `TypeError` for instance, one of
+    /// [`EXCEPTION_TYPES`].
+    pub kind: &'static str,
+    /// The arguments the exception was made with, which its message shows.
+    pub args: Vec<Value>,
+    /// The exception a `raise ... from` named as the cause, its
+    /// `__cause__`.
+    pub cause: Option<Rc<Exception>>,
+    /// Where the exception passed through, one entry for each frame it
+    /// unwound, innermost first.
+    pub traceback: Vec<TracebackEntry>,
+}
+
+/// A frame an exception was raised in or passed through, and the line it
+/// was running.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TracebackEntry {
+    pub filename: String,
+    /// The name of the frame's code, `<module>` for a module's.
+    pub name: String,
+    pub line: usize,
+}
+
+impl Exception {
+    /// An exception with `message` as its one argument, or none if it is
+    /// empty.
+    pub fn new(kind: &'static str, message: impl Into<String>) -> Exception {
+        let message = message.into();
+        let args = if message.is_empty() {
+            Vec::new()
+        } else {
+            vec![Value::Str(message.into())]
+        };
+        Exception::with_args(kind, args)
+    }
+
+    pub fn with_args(kind: &'static str, args: Vec<Value>) -> Exception {
+        Exception {
+            kind,
+            args,
+            cause: None,
+            traceback: Vec::new(),
+        }
+    }
+
+    /// What `str()` gives for the exception.
+    pub fn message(&self) -> String {
+        match &self.args[..] {
+            [] => String::new(),
+            // A missing key shows as the key's repr.
+            [key] if self.kind == "KeyError" => key.repr(),
+            [arg] => arg.to_string(),
+            args => Value::tuple(args.to_vec()).repr(),
+        }
+    }
+
+    pub fn type_error(message: impl Into<String>) -> Exception {
+        Exception::new("TypeError", message)
+    }
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = self.message();
+        if message.is_empty() {
+            return f.write_str(self.kind);
+        }
+        write!(f, "{}: {}", self.kind, message)
+    }
+}
+
+pub type PyResult<T = Value> = Result<T, Exception>;
+
+impl Value {
+    pub fn str(value: &str) -> Value {
+        Value::Str(value.into())
+    }
+
+    pub fn tuple(items: Vec<Value>) -> Value {
+        Value::Tuple(items.into())
+    }
+
+    /// A dict of `items`, where a later item replaces an earlier one with
+    /// an equal key but keeps its place.
+    pub fn dict(items: Vec<(Value, Value)>) -> PyResult {
+        let dict = Value::Dict(Rc::new(RefCell::new(Vec::with_capacity(items.len()))));
+        for (key, value) in items {
+            dict.set_item(key, value)?;
+        }
+        Ok(dict)
+    }
+
+    /// A set of `items`, each kept once.
+    pub fn set(items: Vec<Value>) -> PyResult {
+        let set = Value::Set(Rc::new(RefCell::new(Vec::with_capacity(items.len()))));
+        for item in items {
+            set.add(item)?;
+        }
+        Ok(set)
+    }
+
+    /// A dict keyed by names, as made for keyword arguments.
+    pub fn names_dict(items: &[(Symbol, Value)]) -> Value {
+        let items = items
+            .iter()
+            .map(|(name, value)| (Value::str(name.as_str()), value.clone()))
+            .collect();
+        Value::Dict(Rc::new(RefCell::new(items)))
+    }
+
+    /// Fails unless the value can be a dict key or in a set: the mutable
+    /// containers cannot, nor can a tuple holding one.
+    fn check_hashable(&self) -> PyResult<()> {
+        match self {
+            Value::List(_) | Value::Dict(_) | Value::Set(_) => Err(Exception::type_error(format!(
+                "unhashable type: '{}'",
+                self.type_name()
+            ))),
+            Value::Tuple(items) => items.iter().try_for_each(Value::check_hashable),
+            _ => Ok(()),
+        }
+    }
+
+    /// The value of a constant, or `None` for the constants with no type
+    /// here yet, bytes and `...`.
+    pub fn from_constant(constant: &Constant) -> Option<Value> {
+        Some(match constant {
+            Constant::None => Value::None,
+            Constant::Bool(value) => Value::Bool(*value),
+            Constant::Int(value) => Value::Int(*value),
+            Constant::Float(value) => Value::Float(*value),
+            Constant::Str(value) => Value::str(value),
+            Constant::Tuple(items) => Value::tuple(
+                items
+                    .iter()
+                    .map(Value::from_constant)
+                    .collect::<Option<_>>()?,
+            ),
+            Constant::Bytes(_) | Constant::Ellipsis => return None,
+        })
+    }
+
+    /// The constant with this value, if it is one that can be a constant.
+    pub fn to_constant(&self) -> Option<Constant> {
+        Some(match self {
+            Value::None => Constant::None,
+            Value::Bool(value) => Constant::Bool(*value),
+            Value::Int(value) => Constant::Int(*value),
+            Value::Float(value) => Constant::Float(*value),
+            Value::Str(value) => Constant::Str(value.to_string()),
+            Value::Tuple(items) => Constant::Tuple(
+                items
+                    .iter()
+                    .map(Value::to_constant)
+                    .collect::<Option<_>>()?,
+            ),
+            _ => return None,
+        })
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::None => "NoneType",
+            Value::Bool(_) => "bool",
+            Value::Int(_) => "int",
+            Value::Float(_) => "float",
+            Value::Str(_) => "str",
+            Value::Tuple(_) => "tuple",
+            Value::List(_) => "list",
+            Value::Dict(_) => "dict",
+            Value::Set(_) => "set",
+            Value::Range(_) => "range",
+            Value::Iterator(iter) => match *iter.borrow() {
+                Iter::Range { .. } => "range_iterator",
+                Iter::Seq { .. } => "tuple_iterator",
+                Iter::List { .. } => "list_iterator",
+                Iter::Dict { .. } => "dict_keyiterator",
+                Iter::Set { .. } => "set_iterator",
+            },
+            Value::Function(_) => "function",
+            Value::Builtin(_) | Value::BuiltinMethod(..) => "builtin_function_or_method",
+            Value::Code(_) => "code",
+            Value::Cell(_) => "cell",
+            Value::Exception(exception) => exception.kind,
+            Value::ExceptionType(_) | Value::Class(_) => "type",
+            Value::Instance(instance) => instance.class.name.as_str(),
+            Value::Method(..) => "method",
+            Value::TypeVar(type_var) => type_var.kind.name(),
+            Value::TypeAlias(_) => "TypeAliasType",
+        }
+    }
+
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::None => false,
+            Value::Bool(value) => *value,
+            Value::Int(value) => *value != 0,
+            Value::Float(value) => *value != 0.0,
+            Value::Str(value) => !value.is_empty(),
+            Value::Tuple(items) => !items.is_empty(),
+            Value::List(items) => !items.borrow().is_empty(),
+            Value::Dict(items) => !items.borrow().is_empty(),
+            Value::Set(items) => !items.borrow().is_empty(),
+            Value::Range(range) => !range.is_empty(),
+            _ => true,
+        }
+    }
+
+    /// The address CPython would print in a default `repr`, here the
+    /// address of the shared value, or 0 for plain data.
+    fn address(&self) -> usize {
+        match self {
+            Value::Str(value) => Rc::as_ptr(value) as *const u8 as usize,
+            Value::Tuple(items) => Rc::as_ptr(items) as *const Value as usize,
+            Value::List(items) => Rc::as_ptr(items) as usize,
+            Value::Dict(items) => Rc::as_ptr(items) as usize,
+            Value::Set(items) => Rc::as_ptr(items) as usize,
+            Value::Iterator(iter) => Rc::as_ptr(iter) as usize,
+            Value::Function(function) => Rc::as_ptr(function) as usize,
+            Value::Code(code) => Rc::as_ptr(code) as usize,
+            Value::Cell(cell) => Rc::as_ptr(cell) as usize,
+            Value::Exception(exception) => Rc::as_ptr(exception) as usize,
+            Value::Class(class) => Rc::as_ptr(class) as usize,
+            Value::Instance(instance) => Rc::as_ptr(instance) as usize,
+            Value::TypeVar(type_var) => Rc::as_ptr(type_var) as usize,
+            Value::TypeAlias(alias) => Rc::as_ptr(alias) as usize,
+            _ => 0,
+        }
+    }
+
+    pub fn repr(&self) -> String {
+        match self {
+            Value::None => "None".to_string(),
+            Value::Bool(true) => "True".to_string(),
+            Value::Bool(false) => "False".to_string(),
+            Value::Int(value) => value.to_string(),
+            Value::Float(value) => repr_float(*value),
+            Value::Str(value) => repr_str(value),
+            Value::Tuple(items) if items.len() == 1 => format!("({},)", items[0].repr()),
+            Value::Tuple(items) => format!("({})", join_reprs(items)),
+            Value::List(items) => format!("[{}]", join_reprs(&items.borrow())),
+            Value::Dict(items) => {
+                let items: Vec<String> = items
+                    .borrow()
+                    .iter()
+                    .map(|(key, value)| format!("{}: {}", key.repr(), value.repr()))
+                    .collect();
+                format!("{{{}}}", items.join(", "))
+            }
+            // `{}` is an empty dict.
+            Value::Set(items) if items.borrow().is_empty() => "set()".to_string(),
+            Value::Set(items) => format!("{{{}}}", join_reprs(&items.borrow())),
+            Value::Range(range) if range.step == 1 => {
+                format!("range({}, {})", range.start, range.stop)
+            }
+            Value::Range(range) => {
+                format!("range({}, {}, {})", range.start, range.stop, range.step)
+            }
+            Value::Iterator(_) => {
+                format!("<{} object at {:#x}>", self.type_name(), self.address())
+            }
+            Value::Function(function) => {
+                format!("<function {} at {:#x}>", function.qualname, self.address())
+            }
+            Value::Builtin(builtin) => format!("<built-in function {}>", builtin.name()),
+            Value::BuiltinMethod(builtin, owner) => format!(
+                "<built-in method {} of {} object at {:#x}>",
+                builtin.name(),
+                owner.type_name(),
+                owner.address()
+            ),
+            Value::Code(code) => format!(
+                "<code object {} at {:#x}, file \"{}\", line {}>",
+                code.name,
+                self.address(),
+                code.filename,
+                code.firstlineno
+            ),
+            Value::Cell(cell) => match &*cell.borrow() {
+                Some(value) => format!(
+                    "<cell at {:#x}: {} object at {:#x}>",
+                    self.address(),
+                    value.type_name(),
+                    value.address()
+                ),
+                None => format!("<cell at {:#x}: empty>", self.address()),
+            },
+            Value::Exception(exception) => {
+                let args: Vec<String> = exception.args.iter().map(Value::repr).collect();
+                format!("{}({})", exception.kind, args.join(", "))
+            }
+            Value::ExceptionType(kind) => format!("<class '{}'>", kind),
+            Value::Class(class) => format!("<class '__main__.{}'>", class.qualname),
+            Value::Instance(instance) => format!(
+                "<__main__.{} object at {:#x}>",
+                instance.class.qualname,
+                self.address()
+            ),
+            Value::Method(function, instance) => format!(
+                "<bound method {} of {}>",
+                function.qualname,
+                Value::Instance(instance.clone()).repr()
+            ),
+            // Their variance is inferred, which leaves no prefix.
+            Value::TypeVar(type_var) => type_var.name.to_string(),
+            Value::TypeAlias(alias) => alias.name.to_string(),
+        }
+    }
+
+    /// Python's `==`, which compares numbers by value whatever their type.
+    pub fn py_eq(&self, other: &Value) -> bool {
+        match (self.as_number(), other.as_number()) {
+            (Some(Number::Int(a)), Some(Number::Int(b))) => return a == b,
+            (Some(a), Some(b)) => return a.to_float() == b.to_float(),
+            _ => {}
+        }
+        match (self, other) {
+            (Value::None, Value::None) => true,
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::Tuple(a), Value::Tuple(b)) => seq_eq(a, b),
+            (Value::List(a), Value::List(b)) => seq_eq(&a.borrow(), &b.borrow()),
+            // Dicts are equal with the same items, whatever their order.
+            (Value::Dict(a), Value::Dict(b)) => {
+                let (a, b) = (a.borrow(), b.borrow());
+                a.len() == b.len()
+                    && a.iter()
+                        .all(|(key, value)| dict_get(&b, key).is_some_and(|v| v.py_eq(value)))
+            }
+            (Value::Set(a), Value::Set(b)) => {
+                let (a, b) = (a.borrow(), b.borrow());
+                a.len() == b.len() && a.iter().all(|item| b.iter().any(|other| other.py_eq(item)))
+            }
+            (Value::Range(a), Value::Range(b)) => a == b,
+            (Value::Builtin(a), Value::Builtin(b)) => a == b,
+            (Value::Method(f, a), Value::Method(g, b)) => Rc::ptr_eq(f, g) && Rc::ptr_eq(a, b),
+            (Value::BuiltinMethod(f, a), Value::BuiltinMethod(g, b)) => f == g && a.is(b),
+            _ => self.is(other),
+        }
+    }
+
+    /// Python's `is`: the same object. Small data such as numbers compares
+    /// by value, as CPython's cached small objects do.
+    pub fn is(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::None, Value::None) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Builtin(a), Value::Builtin(b)) => a == b,
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            (Value::Code(a), Value::Code(b)) => Rc::ptr_eq(a, b),
+            (Value::Cell(a), Value::Cell(b)) => Rc::ptr_eq(a, b),
+            (Value::List(a), Value::List(b)) => Rc::ptr_eq(a, b),
+            (Value::Dict(a), Value::Dict(b)) => Rc::ptr_eq(a, b),
+            (Value::Set(a), Value::Set(b)) => Rc::ptr_eq(a, b),
+            (Value::Iterator(a), Value::Iterator(b)) => Rc::ptr_eq(a, b),
+            (Value::Str(a), Value::Str(b)) => Rc::ptr_eq(a, b),
+            (Value::Tuple(a), Value::Tuple(b)) => Rc::ptr_eq(a, b),
+            (Value::Exception(a), Value::Exception(b)) => Rc::ptr_eq(a, b),
+            (Value::ExceptionType(a), Value::ExceptionType(b)) => a == b,
+            (Value::Class(a), Value::Class(b)) => Rc::ptr_eq(a, b),
+            (Value::Instance(a), Value::Instance(b)) => Rc::ptr_eq(a, b),
+            (Value::TypeVar(a), Value::TypeVar(b)) => Rc::ptr_eq(a, b),
+            (Value::TypeAlias(a), Value::TypeAlias(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+
+    fn as_number(&self) -> Option<Number> {
+        match *self {
+            Value::Bool(value) => Some(Number::Int(value as i64)),
+            Value::Int(value) => Some(Number::Int(value)),
+            Value::Float(value) => Some(Number::Float(value)),
+            _ => None,
+        }
+    }
+
+    /// Python's `len()`.
+    pub fn py_len(&self) -> PyResult<usize> {
+        match self {
+            Value::Str(value) => Ok(value.chars().count()),
+            Value::Tuple(items) => Ok(items.len()),
+            Value::List(items) => Ok(items.borrow().len()),
+            Value::Dict(items) => Ok(items.borrow().len()),
+            Value::Set(items) => Ok(items.borrow().len()),
+            Value::Range(range) => Ok(range.len()),
+            _ => Err(Exception::type_error(format!(
+                "object of type '{}' has no len()",
+                self.type_name()
+            ))),
+        }
+    }
+
+    pub fn iter(&self) -> PyResult {
+        let iter = match self {
+            Value::Range(range) => Iter::Range {
+                next: range.start,
+                stop: range.stop,
+                step: range.step,
+            },
+            Value::Tuple(items) => Iter::Seq {
+                items: items.clone(),
+                index: 0,
+            },
+            Value::List(list) => Iter::List {
+                list: list.clone(),
+                index: 0,
+            },
+            Value::Dict(dict) => Iter::Dict {
+                dict: dict.clone(),
+                index: 0,
+            },
+            Value::Set(set) => Iter::Set {
+                set: set.clone(),
+                index: 0,
+            },
+            Value::Str(value) => Iter::Seq {
+                items: value.chars().map(|c| Value::str(&c.to_string())).collect(),
+                index: 0,
+            },
+            Value::Iterator(_) => return Ok(self.clone()),
+            _ => {
+                return Err(Exception::type_error(format!(
+                    "'{}' object is not iterable",
+                    self.type_name()
+                )))
+            }
+        };
+        Ok(Value::Iterator(Rc::new(RefCell::new(iter))))
+    }
+
+    pub fn unary_op(&self, op: UnaryOperator) -> PyResult {
+        match (op, self.as_number()) {
+            (UnaryOperator::Not, _) => Ok(Value::Bool(!self.is_truthy())),
+            (UnaryOperator::USub, Some(Number::Int(value))) => {
+                value.checked_neg().map(Value::Int).ok_or_else(overflow)
+            }
+            (UnaryOperator::USub, Some(Number::Float(value))) => Ok(Value::Float(-value)),
+            (UnaryOperator::UAdd, Some(Number::Int(value))) => Ok(Value::Int(value)),
+            (UnaryOperator::UAdd, Some(Number::Float(value))) => Ok(Value::Float(value)),
+            (UnaryOperator::Invert, Some(Number::Int(value))) => Ok(Value::Int(!value)),
+            _ => Err(Exception::type_error(format!(
+                "bad operand type for unary {}: '{}'",
+                op.symbol(),
+                self.type_name()
+            ))),
+        }
+    }
+
+    pub fn binary_op(&self, op: Operator, right: &Value) -> PyResult {
+        if let (Some(a), Some(b)) = (self.as_number(), right.as_number()) {
+            if let Some(result) = arithmetic(op, a, b) {
+                return result;
+            }
+        }
+        let result = match (op, self, right) {
+            (Operator::Add, Value::Str(a), Value::Str(b)) => {
+                Some(Value::str(&format!("{}{}", a, b)))
+            }
+            (Operator::Add, Value::Tuple(a), Value::Tuple(b)) => {
+                Some(Value::tuple(a.iter().chain(b.iter()).cloned().collect()))
+            }
+            (Operator::Add, Value::List(a), Value::List(b)) => {
+                let items = a
+                    .borrow()
+                    .iter()
+                    .chain(b.borrow().iter())
+                    .cloned()
+                    .collect();
+                Some(Value::List(Rc::new(RefCell::new(items))))
+            }
+            (Operator::Mult, Value::Str(_) | Value::Tuple(_) | Value::List(_), Value::Int(_))
+            | (Operator::Mult, Value::Int(_), Value::Str(_) | Value::Tuple(_) | Value::List(_)) => {
+                let (seq, count) = match (self, right) {
+                    (Value::Int(count), seq) | (seq, Value::Int(count)) => (seq, *count),
+                    _ => unreachable!(),
+                };
+                Some(repeat(seq, count.max(0) as usize))
+            }
+            _ => None,
+        };
+        result.ok_or_else(|| {
+            Exception::type_error(format!(
+                "unsupported operand type(s) for {}: '{}' and '{}'",
+                op.symbol(),
+                self.type_name(),
+                right.type_name()
+            ))
+        })
+    }
+
+    /// An augmented assignment. A list is extended in place, so every name
+    /// bound to it sees the change; everything else is rebound.
+    pub fn inplace_op(&self, op: Operator, right: &Value) -> PyResult {
+        if let (Operator::Add, Value::List(list)) = (op, self) {
+            let items: Vec<Value> = match right {
+                Value::List(other) => other.borrow().clone(),
+                _ => {
+                    let mut items = Vec::new();
+                    let iter = right.iter()?;
+                    let Value::Iterator(iter) = iter else {
+                        unreachable!()
+                    };
+                    items.extend(&mut *iter.borrow_mut());
+                    items
+                }
+            };
+            list.borrow_mut().extend(items);
+            return Ok(self.clone());
+        }
+        self.binary_op(op, right)
+    }
+
+    /// `<`, `<=`, `>` and `>=`; the other comparisons have instructions of
+    /// their own.
+    pub fn compare(&self, op: CmpOp, right: &Value) -> PyResult {
+        let ordering = match (self.as_number(), right.as_number()) {
+            (Some(Number::Int(a)), Some(Number::Int(b))) => Some(a.cmp(&b)),
+            (Some(a), Some(b)) => a.to_float().partial_cmp(&b.to_float()),
+            _ => match (self, right) {
+                (Value::Str(a), Value::Str(b)) => Some(a.cmp(b)),
+                _ => {
+                    return match op {
+                        CmpOp::Eq => Ok(Value::Bool(self.py_eq(right))),
+                        CmpOp::NotEq => Ok(Value::Bool(!self.py_eq(right))),
+                        _ => Err(Exception::type_error(format!(
+                            "'{}' not supported between instances of '{}' and '{}'",
+                            op.symbol(),
+                            self.type_name(),
+                            right.type_name()
+                        ))),
+                    }
+                }
+            },
+        };
+        Ok(Value::Bool(ordering_holds(op, ordering)))
+    }
+
+    pub fn contains(&self, item: &Value) -> PyResult<bool> {
+        match self {
+            Value::Str(value) => match item {
+                Value::Str(item) => Ok(value.contains(&**item)),
+                _ => Err(Exception::type_error(format!(
+                    "'in <string>' requires string as left operand, not {}",
+                    item.type_name()
+                ))),
+            },
+            Value::Tuple(items) => Ok(items.iter().any(|value| value.py_eq(item))),
+            Value::List(items) => Ok(items.borrow().iter().any(|value| value.py_eq(item))),
+            Value::Dict(items) => {
+                item.check_hashable()?;
+                Ok(dict_get(&items.borrow(), item).is_some())
+            }
+            // CPython looks a set up as the frozenset of its items, which
+            // no set here can equal.
+            Value::Set(_) if matches!(item, Value::Set(_)) => Ok(false),
+            Value::Set(items) => {
+                item.check_hashable()?;
+                Ok(items.borrow().iter().any(|value| value.py_eq(item)))
+            }
+            Value::Range(range) => Ok(match item.as_number() {
+                Some(Number::Int(value)) => range.contains(value),
+                _ => false,
+            }),
+            _ => Err(Exception::type_error(format!(
+                "argument of type '{}' is not iterable",
+                self.type_name()
+            ))),
+        }
+    }
+
+    /// `set.add(item)`, which does nothing if an equal item is there.
+    pub fn add(&self, item: Value) -> PyResult<()> {
+        let Value::Set(items) = self else {
+            return Err(Exception::type_error(format!(
+                "'{}' object has no attribute 'add'",
+                self.type_name()
+            )));
+        };
+        item.check_hashable()?;
+        let mut items = items.borrow_mut();
+        if !items.iter().any(|other| other.py_eq(&item)) {
+            items.push(item);
+        }
+        Ok(())
+    }
+
+    /// `self[key] = value`.
+    pub fn set_item(&self, key: Value, value: Value) -> PyResult<()> {
+        let Value::Dict(items) = self else {
+            return Err(Exception::type_error(format!(
+                "'{}' object does not support item assignment",
+                self.type_name()
+            )));
+        };
+        key.check_hashable()?;
+        let mut items = items.borrow_mut();
+        match items.iter_mut().find(|(k, _)| k.py_eq(&key)) {
+            Some(item) => item.1 = value,
+            None => items.push((key, value)),
+        }
+        Ok(())
+    }
+
+    pub fn delete_item(&self, key: &Value) -> PyResult<()> {
+        match self {
+            Value::Dict(items) => {
+                key.check_hashable()?;
+                let mut items = items.borrow_mut();
+                let Some(at) = items.iter().position(|(k, _)| k.py_eq(key)) else {
+                    return Err(Exception::with_args("KeyError", vec![key.clone()]));
+                };
+                items.remove(at);
+                Ok(())
+            }
+            Value::List(items) => {
+                let Some(Number::Int(i)) = key.as_number() else {
+                    return Err(Exception::type_error(format!(
+                        "list indices must be integers, not {}",
+                        key.type_name()
+                    )));
+                };
+                let mut items = items.borrow_mut();
+                let len = items.len() as i64;
+                let i = if i < 0 { i + len } else { i };
+                if !(0..len).contains(&i) {
+                    return Err(Exception::new(
+                        "IndexError",
+                        "list assignment index out of range",
+                    ));
+                }
+                items.remove(i as usize);
+                Ok(())
+            }
+            _ => Err(Exception::type_error(format!(
+                "'{}' object doesn't support item deletion",
+                self.type_name()
+            ))),
+        }
+    }
+
+    pub fn subscript(&self, index: &Value) -> PyResult {
+        if let Value::Dict(items) = self {
+            index.check_hashable()?;
+            return dict_get(&items.borrow(), index)
+                .ok_or_else(|| Exception::with_args("KeyError", vec![index.clone()]));
+        }
+        let Some(Number::Int(i)) = index.as_number() else {
+            return Err(Exception::type_error(format!(
+                "{} indices must be integers, not {}",
+                self.type_name(),
+                index.type_name()
+            )));
+        };
+        let item = |items: &[Value]| {
+            let len = items.len() as i64;
+            let i = if i < 0 { i + len } else { i };
+            (0..len).contains(&i).then(|| items[i as usize].clone())
+        };
+        let found = match self {
+            Value::Tuple(items) => item(items),
+            Value::List(items) => item(&items.borrow()),
+            Value::Str(value) => {
+                let chars: Vec<Value> = value.chars().map(|c| Value::str(&c.to_string())).collect();
+                item(&chars)
+            }
+            Value::Range(range) => {
+                let len = range.len() as i64;
+                let i = if i < 0 { i + len } else { i };
+                (0..len)
+                    .contains(&i)
+                    .then(|| Value::Int(range.start + i * range.step))
+            }
+            _ => {
+                return Err(Exception::type_error(format!(
+                    "'{}' object is not subscriptable",
+                    self.type_name()
+                )))
+            }
+        };
+        found.ok_or_else(|| {
+            let kind = match self {
+                Value::Str(_) => "string",
+                _ => self.type_name(),
+            };
+            Exception::new("IndexError", format!("{} index out of range", kind))
+        })
+    }
+}
+
+impl fmt::Display for Value {
+    /// Python's `str()`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Str(value) => f.write_str(value),
+            Value::Exception(exception) => f.write_str(&exception.message()),
+            _ => f.write_str(&self.repr()),
+        }
+    }
+}
+
+impl PartialEq for Value {
+    /// The same value of the same type, as constants are compared when
+    /// they are deduplicated: `1`, `1.0` and `True` are all different.
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Float(a), Value::Float(b)) => a.to_bits() == b.to_bits(),
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::Tuple(a), Value::Tuple(b)) => a == b,
+            (Value::Code(a), Value::Code(b)) => a == b,
+            _ => self.is(other),
+        }
+    }
+}
+
+impl Range {
+    pub fn new(start: i64, stop: i64, step: i64) -> PyResult<Range> {
+        if step == 0 {
+            return Err(Exception::new(
+                "ValueError",
+                "range() arg 3 must not be zero",
+            ));
+        }
+        Ok(Range { start, stop, step })
+    }
+
+    pub fn len(&self) -> usize {
+        let (low, high, step) = if self.step > 0 {
+            (self.start, self.stop, self.step)
+        } else {
+            (self.stop, self.start, -self.step)
+        };
+        if low >= high {
+            0
+        } else {
+            ((high - low - 1) / step + 1) as usize
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn contains(&self, value: i64) -> bool {
+        let in_bounds = if self.step > 0 {
+            self.start <= value && value < self.stop
+        } else {
+            self.stop < value && value <= self.start
+        };
+        in_bounds && (value - self.start) % self.step == 0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Number {
+    Int(i64),
+    Float(f64),
+}
+
+impl Number {
+    fn to_float(self) -> f64 {
+        match self {
+            Number::Int(value) => value as f64,
+            Number::Float(value) => value,
+        }
+    }
+}
+
+fn overflow() -> Exception {
+    Exception::new("OverflowError", "integer overflow")
+}
+
+fn zero_division(message: &str) -> Exception {
+    Exception::new("ZeroDivisionError", message)
+}
+
+/// Arithmetic on two numbers, or `None` if `op` does not apply to them.
+/// Whether values that order as `ordering` compare true with `op`, one of
+/// the comparisons [`Value::compare`] makes; `None` is the ordering of NaN
+/// with anything.
+pub fn ordering_holds(op: CmpOp, ordering: Option<Ordering>) -> bool {
+    match ordering {
+        // NaN compares false with everything.
+        None => op == CmpOp::NotEq,
+        Some(ordering) => match op {
+            CmpOp::Eq => ordering.is_eq(),
+            CmpOp::NotEq => ordering.is_ne(),
+            CmpOp::Lt => ordering.is_lt(),
+            CmpOp::LtE => ordering.is_le(),
+            CmpOp::Gt => ordering.is_gt(),
+            CmpOp::GtE => ordering.is_ge(),
+            _ => unreachable!(),
+        },
+    }
+}
+
+fn arithmetic(op: Operator, a: Number, b: Number) -> Option<PyResult> {
+    if let (Number::Int(a), Number::Int(b)) = (a, b) {
+        return int_arithmetic(op, a, b);
+    }
+    let (a, b) = (a.to_float(), b.to_float());
+    let result = match op {
+        Operator::Add => a + b,
+        Operator::Sub => a - b,
+        Operator::Mult => a * b,
+        Operator::Div if b == 0.0 => return Some(Err(zero_division("float division by zero"))),
+        Operator::Div => a / b,
+        Operator::FloorDiv if b == 0.0 => {
+            return Some(Err(zero_division("float floor division by zero")))
+        }
+        Operator::FloorDiv => (a / b).floor(),
+        Operator::Mod if b == 0.0 => return Some(Err(zero_division("float modulo"))),
+        Operator::Mod => {
+            let result = a % b;
+            if result != 0.0 && (result < 0.0) != (b < 0.0) {
+                result + b
+            } else {
+                result
+            }
+        }
+        Operator::Pow => a.powf(b),
+        _ => return None,
+    };
+    Some(Ok(Value::Float(result)))
+}
+
+fn int_arithmetic(op: Operator, a: i64, b: i64) -> Option<PyResult> {
+    let result = match op {
+        Operator::Add => a.checked_add(b),
+        Operator::Sub => a.checked_sub(b),
+        Operator::Mult => a.checked_mul(b),
+        Operator::Div if b == 0 => return Some(Err(zero_division("division by zero"))),
+        Operator::Div => return Some(Ok(Value::Float(a as f64 / b as f64))),
+        Operator::FloorDiv if b == 0 => {
+            return Some(Err(zero_division("integer division or modulo by zero")))
+        }
+        // Division truncates; Python's rounds towards negative infinity.
+        Operator::FloorDiv => a.checked_div(b).map(|q| {
+            if a % b != 0 && (a < 0) != (b < 0) {
+                q - 1
+            } else {
+                q
+            }
+        }),
+        Operator::Mod if b == 0 => return Some(Err(zero_division("integer modulo by zero"))),
+        Operator::Mod => a.checked_rem(b).map(|r| {
+            if r != 0 && (r < 0) != (b < 0) {
+                r + b
+            } else {
+                r
+            }
+        }),
+        Operator::Pow if b < 0 => return Some(Ok(Value::Float((a as f64).powf(b as f64)))),
+        Operator::Pow => u32::try_from(b).ok().and_then(|b| a.checked_pow(b)),
+        Operator::LShift if b < 0 => {
+            return Some(Err(Exception::new("ValueError", "negative shift count")))
+        }
+        Operator::LShift => u32::try_from(b)
+            .ok()
+            .and_then(|b| a.checked_shl(b))
+            .filter(|r| r >> b == a),
+        Operator::RShift if b < 0 => {
+            return Some(Err(Exception::new("ValueError", "negative shift count")))
+        }
+        Operator::RShift => Some(a >> b.min(63)),
+        Operator::BitAnd => Some(a & b),
+        Operator::BitOr => Some(a | b),
+        Operator::BitXor => Some(a ^ b),
+        Operator::MatMult => return None,
+    };
+    Some(result.map(Value::Int).ok_or_else(overflow))
+}
+
+fn repeat(seq: &Value, count: usize) -> Value {
+    match seq {
+        Value::Str(value) => Value::str(&value.repeat(count)),
+        Value::Tuple(items) => Value::tuple(
+            items
+                .iter()
+                .cloned()
+                .cycle()
+                .take(items.len() * count)
+                .collect(),
+        ),
+        Value::List(items) => {
+            let items = items.borrow();
+            let repeated = items
+                .iter()
+                .cloned()
+                .cycle()
+                .take(items.len() * count)
+                .collect();
+            Value::List(Rc::new(RefCell::new(repeated)))
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn join_reprs(items: &[Value]) -> String {
+    items.iter().map(Value::repr).collect::<Vec<_>>().join(", ")
+}
+
+/// The value of `key` among a dict's items.
+fn dict_get(items: &[(Value, Value)], key: &Value) -> Option<Value> {
+    items
+        .iter()
+        .find(|(k, _)| k.py_eq(key))
+        .map(|(_, value)| value.clone())
+}
+
+fn seq_eq(a: &[Value], b: &[Value]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.py_eq(b))
+}
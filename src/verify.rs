@@ -0,0 +1,642 @@
+//! The bytecode verifier, which checks that a code object is safe to run
+//! before the VM runs it.
+//!
+//! Verification follows every path through the instructions, and each
+//! exception handler from where its range can raise, keeping the depth of
+//! the value stack. No instruction may take more values than the stack
+//! holds, and paths meeting at an instruction must bring the same depth,
+//! so that each instruction has one depth whichever way it is reached;
+//! the deepest is the code's stack size. Every index into the code's
+//! tables, every jump target and every exception table entry must be in
+//! range, each parameter must have a local variable to bind to, and no
+//! path may run off the end of the code.
+//!
+//! The VM verifies all code before running it, so malformed code, as the
+//! assembler or a `.pyc` file can make, raises a `SystemError` up front
+//! instead of making the VM panic partway through. The types of the values
+//! on the stack are not known here: an instruction given a value of the
+//! wrong type, `FOR_ITER` something other than an iterator say, raises a
+//! `SystemError` as it runs.
+
+use std::fmt;
+
+use crate::code::{CodeObject, CO_VARARGS, CO_VARKEYWORDS};
+use crate::intruction::Instruction;
+use crate::value::Value;
+
+/// What is wrong with a code object, and at which instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    /// The qualified name of the code object.
+    pub qualname: String,
+    pub index: usize,
+    pub message: String,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: instruction {}: {}",
+            self.qualname, self.index, self.message
+        )
+    }
+}
+
+pub type VerifyResult<T> = Result<T, VerifyError>;
+
+/// Verifies `code` and, in turn, the code objects among its constants.
+pub fn verify_all(code: &CodeObject) -> VerifyResult<()> {
+    verify(code)?;
+    for constant in &code.consts {
+        if let Value::Code(nested) = constant {
+            verify_all(nested)?;
+        }
+    }
+    Ok(())
+}
+
+/// Verifies `code` alone, returning the most values its stack holds.
+pub fn verify(code: &CodeObject) -> VerifyResult<usize> {
+    let error = |index: usize, message: String| VerifyError {
+        qualname: code.qualname.clone(),
+        index,
+        message,
+    };
+    let instructions = &code.instructions();
+    if instructions.is_empty() {
+        return Err(error(0, "the code has no instructions".to_string()));
+    }
+    check_parameters(code).map_err(|message| error(0, message))?;
+    for (index, &instruction) in instructions.iter().enumerate() {
+        check_ranges(code, instructions.len(), instruction)
+            .map_err(|message| error(index, message))?;
+    }
+    check_exception_table(code, instructions.len())
+        .map_err(|(index, message)| error(index, message))?;
+
+    // The depth of the stack before each instruction, with the
+    // instruction that first brought a path there.
+    let mut depths: Vec<Option<(u32, usize)>> = vec![None; instructions.len()];
+    let mut pending = vec![(0, 0, 0)];
+    let mut max = 0;
+    let mut handlers_seen = vec![false; code.exception_table.len()];
+    while let Some((index, depth, from)) = pending.pop() {
+        if index >= instructions.len() {
+            return Err(error(
+                from,
+                "execution runs off the end of the code".to_string(),
+            ));
+        }
+        match depths[index] {
+            Some((seen, _)) if seen == depth => continue,
+            Some((seen, first)) => {
+                return Err(error(
+                    index,
+                    format!(
+                        "the stack depth is {} coming from instruction {} but {} coming from \
+                         instruction {}",
+                        seen, first, depth, from
+                    ),
+                ))
+            }
+            None => depths[index] = Some((depth, from)),
+        }
+        max = max.max(depth);
+        let instruction = instructions[index];
+        let inputs = instruction.stack_inputs();
+        if inputs > depth {
+            return Err(error(
+                index,
+                format!(
+                    "stack underflow: {} needs {} but the stack holds {}",
+                    instruction.opname(),
+                    inputs,
+                    depth
+                ),
+            ));
+        }
+        // A handler is reached from the first instruction of its range
+        // that is reached, with the stack cut down to the entry's depth and
+        // the exception pushed, over the index of the raising instruction
+        // if the entry keeps it.
+        for (entry_index, entry) in code.exception_table.iter().enumerate() {
+            if (entry.start..entry.end).contains(&(index as u32)) {
+                if depth < entry.depth {
+                    return Err(error(
+                        index,
+                        format!(
+                            "the stack depth is {}, under the depth {} of exception entry {}",
+                            depth, entry.depth, entry_index
+                        ),
+                    ));
+                }
+                if !handlers_seen[entry_index] {
+                    handlers_seen[entry_index] = true;
+                    let pushed = 1 + entry.lasti as u32;
+                    pending.push((entry.target as usize, entry.depth + pushed, index));
+                }
+            }
+        }
+        if !instruction.is_terminal() {
+            let next = depth as i32 + instruction.stack_effect(false);
+            pending.push((index + 1, next as u32, index));
+        }
+        if let Some(target) = instruction.jump_target() {
+            let jumped = depth as i32 + instruction.stack_effect(true);
+            pending.push((target as usize, jumped as u32, index));
+        }
+    }
+    Ok(max as usize)
+}
+
+/// Checks that the parameters of `code` are the first of its local
+/// variables, the positional-only ones among the positional ones.
+fn check_parameters(code: &CodeObject) -> Result<(), String> {
+    if code.posonlyargcount > code.argcount {
+        return Err(format!(
+            "there are {} positional parameters, fewer than the {} positional-only ones",
+            code.argcount, code.posonlyargcount
+        ));
+    }
+    let params = code.argcount
+        + code.kwonlyargcount
+        + (code.flags & CO_VARARGS != 0) as usize
+        + (code.flags & CO_VARKEYWORDS != 0) as usize;
+    if params > code.varnames.len() {
+        return Err(format!(
+            "there are {} local variables, fewer than the {} parameters",
+            code.varnames.len(),
+            params
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that the indexes and jump target of an instruction of `code`,
+/// which has `len` instructions, are in range.
+fn check_ranges(code: &CodeObject, len: usize, instruction: Instruction) -> Result<(), String> {
+    let (index, table, size) = match instruction {
+        Instruction::LoadConst(index) => (index, "constants", code.consts.len()),
+        Instruction::LoadName(index)
+        | Instruction::StoreName(index)
+        | Instruction::DeleteName(index)
+        | Instruction::LoadGlobal(index)
+        | Instruction::StoreGlobal(index)
+        | Instruction::DeleteGlobal(index)
+        | Instruction::LoadAttr(index)
+        | Instruction::StoreAttr(index)
+        | Instruction::DeleteAttr(index) => (index, "names", code.names.len()),
+        Instruction::LoadFast(index)
+        | Instruction::StoreFast(index)
+        | Instruction::DeleteFast(index) => (index, "local variables", code.varnames.len()),
+        Instruction::LoadDeref(index)
+        | Instruction::StoreDeref(index)
+        | Instruction::DeleteDeref(index)
+        | Instruction::LoadClosure(index) => {
+            (index, "cells", code.cellvars.len() + code.freevars.len())
+        }
+        Instruction::MakeFunction(flags) if flags > 0xf => {
+            return Err(format!("MAKE_FUNCTION has no flags {:#x}", flags));
+        }
+        Instruction::Copy(0) => return Err("COPY 0 copies nothing".to_string()),
+        Instruction::ListAppend(0)
+        | Instruction::SetAdd(0)
+        | Instruction::ListExtend(0)
+        | Instruction::DictMerge(0)
+        | Instruction::MapAdd(0) => {
+            return Err(format!(
+                "{} 0 has no container under the values it takes",
+                instruction.opname()
+            ));
+        }
+        Instruction::RaiseVarargs(count) if count > 2 => {
+            return Err(format!("RAISE_VARARGS {} is not supported", count));
+        }
+        Instruction::CallIntrinsic1(function) | Instruction::CallIntrinsic2(function)
+            if !instruction
+                .intrinsics()
+                .unwrap()
+                .iter()
+                .any(|&(number, _)| number == function) =>
+        {
+            return Err(format!(
+                "{} {} is not supported",
+                instruction.opname(),
+                function
+            ));
+        }
+        _ => match instruction.jump_target() {
+            Some(target) => (target, "instructions", len),
+            None => return Ok(()),
+        },
+    };
+    if index as usize >= size {
+        return Err(format!(
+            "{} {} is out of range: there are {} {}",
+            instruction.opname(),
+            index,
+            size,
+            table
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that each exception table entry covers a range of the `len`
+/// instructions and jumps to one, and that each pair of ranges is either
+/// apart or nested, the inner one first.
+fn check_exception_table(code: &CodeObject, len: usize) -> Result<(), (usize, String)> {
+    let len = len as u32;
+    let table = &code.exception_table;
+    for (i, entry) in table.iter().enumerate() {
+        let at = entry.start.min(len.saturating_sub(1)) as usize;
+        if entry.start >= entry.end || entry.end > len {
+            return Err((
+                at,
+                format!(
+                    "exception entry {} covers {}..{}, not a range of the {} instructions",
+                    i, entry.start, entry.end, len
+                ),
+            ));
+        }
+        if entry.target >= len {
+            return Err((
+                at,
+                format!(
+                    "exception entry {} jumps to {}, out of range: there are {} instructions",
+                    i, entry.target, len
+                ),
+            ));
+        }
+        for (j, later) in table.iter().enumerate().skip(i + 1) {
+            let apart = later.end <= entry.start || entry.end <= later.start;
+            let inside = later.start <= entry.start && entry.end <= later.end;
+            if !apart && !inside {
+                return Err((
+                    at,
+                    format!(
+                        "exception entry {} overlaps entry {} without being inside it",
+                        i, j
+                    ),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::{verify, verify_all};
+    use crate::asm::assemble;
+    use crate::ast::Operator;
+    use crate::code::ExceptionEntry;
+    use crate::interpreter::compile_source;
+    use crate::intruction::Instruction::{self, *};
+    use crate::value::Value;
+    use crate::vm::Vm;
+
+    #[test]
+    fn test_verify_compiled() {
+        let source = std::fs::read_to_string("tests/dis.py").unwrap();
+        let code = compile_source(&source, "dis.py", 0).unwrap();
+        assert_eq!(verify_all(&code), Ok(()));
+        assert_eq!(verify(&code), Ok(code.stacksize));
+    }
+
+    #[test]
+    fn test_verify_errors() {
+        let mut code = compile_source("x = 1\n", "x.py", 0).unwrap();
+        let mut instructions = code.instructions();
+        instructions.pop();
+        code.set_instructions(&instructions);
+        assert_eq!(
+            verify(&code).unwrap_err().to_string(),
+            "<module>: instruction 2: execution runs off the end of the code"
+        );
+        code.set_instructions(&[]);
+        assert_eq!(
+            verify(&code).unwrap_err().to_string(),
+            "<module>: instruction 0: the code has no instructions"
+        );
+        let mut code = compile_source("x = 1\n", "x.py", 0).unwrap();
+        let mut instructions = code.instructions();
+        instructions[0] = LoadConst(7);
+        code.set_instructions(&instructions);
+        assert_eq!(
+            verify(&code).unwrap_err().to_string(),
+            "<module>: instruction 0: LOAD_CONST 7 is out of range: there are 2 constants"
+        );
+        instructions[0] = LoadConst(0);
+        instructions[1] = CallIntrinsic1(5);
+        code.set_instructions(&instructions);
+        assert_eq!(
+            verify(&code).unwrap_err().to_string(),
+            "<module>: instruction 1: CALL_INTRINSIC_1 5 is not supported"
+        );
+        instructions[1] = ListAppend(0);
+        code.set_instructions(&instructions);
+        assert_eq!(
+            verify(&code).unwrap_err().to_string(),
+            "<module>: instruction 1: LIST_APPEND 0 has no container under the values it takes"
+        );
+    }
+
+    #[test]
+    fn test_verify_parameters() {
+        let listing = ".code f\n.argcount 1\n    LOAD_CONST (None)\n    RETURN_VALUE\n";
+        assert_eq!(
+            assemble(listing).unwrap_err().message,
+            "there are 0 local variables, fewer than the 1 parameters"
+        );
+        let mut code = compile_source("def f(a, *b, c, **d):\n    pass\n", "f.py", 0).unwrap();
+        let Value::Code(f) = &mut code.consts[0] else {
+            panic!("expected a code object, got {:?}", code.consts[0]);
+        };
+        let f = Rc::make_mut(f);
+        assert_eq!(verify(f).map(|_| ()), Ok(()));
+        f.varnames.pop();
+        assert_eq!(
+            verify(f).unwrap_err().to_string(),
+            "f: instruction 0: there are 3 local variables, fewer than the 4 parameters"
+        );
+        f.posonlyargcount = 2;
+        assert_eq!(
+            verify(f).unwrap_err().to_string(),
+            "f: instruction 0: there are 1 positional parameters, fewer than the 2 \
+             positional-only ones"
+        );
+    }
+
+    /// Verified module code may use fast locals and cells, though the
+    /// compiler gives a module none.
+    #[test]
+    fn test_module_fast_and_deref() {
+        let run = |listing: &str| {
+            let code = assemble(listing).unwrap();
+            let mut vm = Vm::new(Vec::new());
+            vm.run(Rc::new(code)).map_err(|error| error.to_string())
+        };
+        let fast =
+            ".code m\n.varnames a\n    LOAD_CONST (1)\n    STORE_FAST a\n    LOAD_FAST a\n    \
+                    RETURN_VALUE\n";
+        assert_eq!(run(fast), Ok(Value::Int(1)));
+        let deref = ".code m\n.cellvars c\n    LOAD_CONST (2)\n    STORE_DEREF c\n    \
+                     LOAD_DEREF c\n    RETURN_VALUE\n";
+        assert_eq!(run(deref), Ok(Value::Int(2)));
+        let unbound = ".code m\n.freevars x\n    LOAD_DEREF x\n    RETURN_VALUE\n";
+        assert_eq!(
+            run(unbound),
+            Err(
+                "NameError: cannot access free variable 'x' where it is not associated with a \
+                 value in enclosing scope"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_verify_before_running() {
+        let mut code = compile_source("def f():\n    return 1\nf()\n", "f.py", 0).unwrap();
+        let Value::Code(f) = &mut code.consts[0] else {
+            panic!("expected a code object, got {:?}", code.consts[0]);
+        };
+        Rc::make_mut(f).set_instructions(&[PopTop, ReturnValue]);
+        let mut vm = Vm::new(Vec::new());
+        assert_eq!(
+            vm.run(Rc::new(code)).unwrap_err().to_string(),
+            "SystemError: f: instruction 0: stack underflow: POP_TOP needs 1 but the stack \
+             holds 0"
+        );
+    }
+
+    /// What running `code` with `instructions` raises, the code's
+    /// constants `consts`.
+    fn raised(consts: Vec<Value>, instructions: &[Instruction]) -> String {
+        let mut code = compile_source("x = 1\n", "x.py", 0).unwrap();
+        code.consts = consts;
+        code.set_instructions(instructions);
+        let mut vm = Vm::new(Vec::new());
+        vm.run(Rc::new(code)).unwrap_err().to_string()
+    }
+
+    // The verifier knows the depth of the stack but not the types on it,
+    // which the VM checks as it runs.
+
+    #[test]
+    fn test_list_append_to_non_list() {
+        assert_eq!(
+            raised(
+                vec![Value::Int(1)],
+                &[LoadConst(0), LoadConst(0), ListAppend(1), ReturnValue]
+            ),
+            "SystemError: LIST_APPEND without a list"
+        );
+    }
+
+    #[test]
+    fn test_make_function_of_non_code() {
+        assert_eq!(
+            raised(
+                vec![Value::str("f")],
+                &[LoadConst(0), LoadConst(0), MakeFunction(0), ReturnValue]
+            ),
+            "SystemError: MAKE_FUNCTION without a code object"
+        );
+    }
+
+    /// `def f(a): pass`, and the constants for a `MAKE_FUNCTION` of it:
+    /// its code then its qualified name.
+    fn function_consts() -> Vec<Value> {
+        let module = compile_source("def f(a):\n    pass\n", "f.py", 0).unwrap();
+        let f = module
+            .consts
+            .iter()
+            .find(|value| matches!(value, Value::Code(_)));
+        vec![f.unwrap().clone(), Value::str("f")]
+    }
+
+    #[test]
+    fn test_make_function_with_odd_annotations() {
+        let mut consts = function_consts();
+        consts.push(Value::tuple(vec![Value::str("a")]));
+        assert_eq!(
+            raised(
+                consts,
+                &[
+                    LoadConst(2),
+                    LoadConst(0),
+                    LoadConst(1),
+                    MakeFunction(4),
+                    ReturnValue,
+                ]
+            ),
+            "SystemError: MAKE_FUNCTION with annotations that are not name and value pairs"
+        );
+    }
+
+    #[test]
+    fn test_make_function_with_surplus_defaults() {
+        let mut consts = function_consts();
+        consts.push(Value::tuple(vec![Value::Int(1), Value::Int(2)]));
+        assert_eq!(
+            raised(
+                consts,
+                &[
+                    LoadConst(2),
+                    LoadConst(0),
+                    LoadConst(1),
+                    MakeFunction(1),
+                    ReturnValue,
+                ]
+            ),
+            "SystemError: MAKE_FUNCTION with more defaults than positional parameters"
+        );
+    }
+
+    #[test]
+    fn test_closure_of_non_cells() {
+        let source = "def f():\n    y = 1\n    def g():\n        return y\n";
+        let module = compile_source(source, "f.py", 0).unwrap();
+        let Value::Code(f) = &module.consts[0] else {
+            panic!("expected a code object, got {:?}", module.consts[0]);
+        };
+        let g = f
+            .consts
+            .iter()
+            .find(|value| matches!(value, Value::Code(_)));
+        let consts = vec![g.unwrap().clone(), Value::str("g"), Value::Int(1)];
+        assert_eq!(
+            raised(
+                consts.clone(),
+                &[
+                    LoadConst(2),
+                    BuildTuple(1),
+                    LoadConst(0),
+                    LoadConst(1),
+                    MakeFunction(8),
+                    ReturnValue,
+                ]
+            ),
+            "SystemError: closure item that is not a cell"
+        );
+        // `g` has a free variable, so it needs a closure.
+        assert_eq!(
+            raised(
+                consts,
+                &[LoadConst(0), LoadConst(1), MakeFunction(0), ReturnValue]
+            ),
+            "SystemError: MAKE_FUNCTION without a cell for each free variable"
+        );
+    }
+
+    #[test]
+    fn test_for_iter_over_non_iterator() {
+        assert_eq!(
+            raised(
+                vec![Value::Int(1)],
+                &[
+                    LoadConst(0),
+                    ForIter(4),
+                    PopTop,
+                    JumpAbsolute(1),
+                    LoadConst(0),
+                    ReturnValue,
+                ]
+            ),
+            "SystemError: FOR_ITER without an iterator"
+        );
+    }
+
+    #[test]
+    fn test_verify_stack() {
+        let mut code = compile_source("x = 1\n", "x.py", 0).unwrap();
+        code.set_instructions(&[LoadConst(0), BinaryOp(Operator::Add), ReturnValue]);
+        assert_eq!(
+            verify(&code).unwrap_err().to_string(),
+            "<module>: instruction 1: stack underflow: BINARY_ADD needs 2 but the stack holds 1"
+        );
+        // One path to the return pushes a value more than the other.
+        code.set_instructions(&[
+            LoadConst(0),
+            PopJumpIfFalse(3),
+            LoadConst(0),
+            LoadConst(1),
+            ReturnValue,
+        ]);
+        assert_eq!(
+            verify(&code).unwrap_err().to_string(),
+            "<module>: instruction 3: the stack depth is 0 coming from instruction 1 but 1 \
+             coming from instruction 2"
+        );
+        code.set_instructions(&[LoadConst(0), JumpAbsolute(9), ReturnValue]);
+        assert_eq!(
+            verify(&code).unwrap_err().to_string(),
+            "<module>: instruction 1: JUMP_ABSOLUTE 9 is out of range: there are 3 instructions"
+        );
+    }
+
+    #[test]
+    fn test_verify_exception_table() {
+        let entry = |start, end, target, depth| ExceptionEntry {
+            start,
+            end,
+            target,
+            depth,
+            lasti: false,
+        };
+        let mut code = compile_source("x = 1\n", "x.py", 0).unwrap();
+        // Loads under a handler that drops the exception and returns.
+        code.set_instructions(&[
+            LoadConst(0),
+            LoadConst(0),
+            PopTop,
+            ReturnValue,
+            PopTop,
+            LoadConst(1),
+            ReturnValue,
+        ]);
+        code.exception_table = vec![entry(1, 3, 4, 1)];
+        assert_eq!(verify(&code), Ok(2));
+        // An entry keeping the raising instruction's index pushes it too.
+        code.exception_table = vec![ExceptionEntry {
+            lasti: true,
+            ..entry(1, 3, 4, 1)
+        }];
+        assert_eq!(verify(&code), Ok(3));
+        code.exception_table = vec![entry(1, 3, 4, 2)];
+        assert_eq!(
+            verify(&code).unwrap_err().to_string(),
+            "<module>: instruction 1: the stack depth is 1, under the depth 2 of exception \
+             entry 0"
+        );
+        // The handler is also where the range goes on to.
+        code.exception_table = vec![entry(1, 2, 3, 1)];
+        assert_eq!(
+            verify(&code).unwrap_err().to_string(),
+            "<module>: instruction 3: the stack depth is 1 coming from instruction 2 but 2 \
+             coming from instruction 1"
+        );
+        code.exception_table = vec![entry(0, 8, 4, 0)];
+        assert_eq!(
+            verify(&code).unwrap_err().to_string(),
+            "<module>: instruction 0: exception entry 0 covers 0..8, not a range of the 7 \
+             instructions"
+        );
+        code.exception_table = vec![entry(0, 2, 9, 0)];
+        assert_eq!(
+            verify(&code).unwrap_err().to_string(),
+            "<module>: instruction 0: exception entry 0 jumps to 9, out of range: there are \
+             7 instructions"
+        );
+        code.exception_table = vec![entry(0, 2, 4, 0), entry(1, 3, 4, 0)];
+        assert_eq!(
+            verify(&code).unwrap_err().to_string(),
+            "<module>: instruction 0: exception entry 0 overlaps entry 1 without being \
+             inside it"
+        );
+    }
+}
@@ -0,0 +1,545 @@
+//! Read-only traversal of the AST.
+//!
+//! Each `visit_*` method defaults to the matching `walk_*` function, which
+//! visits the node's children in the order of CPython's `_fields`, as
+//! `ast.NodeVisitor.generic_visit` does. An implementation overrides the
+//! methods for the nodes it cares about and calls `walk_*` from them to
+//! keep descending. The walks destructure every variant and field, so a
+//! change to the AST does not compile until they are updated.
+
+use crate::ast::{
+    Alias, Arena, Arg, Arguments, Comprehension, Constant, ExceptHandler, ExprContext, ExprId,
+    ExprKind, Keyword, List, Module, StmtId, StmtKind, TypeParam, TypeParamKind, WithItem,
+};
+
+pub trait Visitor<'ast>: Sized {
+    fn visit_module(&mut self, module: &'ast Module) {
+        walk_module(self, module)
+    }
+
+    fn visit_stmt(&mut self, arena: &'ast Arena, stmt: StmtId) {
+        walk_stmt(self, arena, stmt)
+    }
+
+    fn visit_expr(&mut self, arena: &'ast Arena, expr: ExprId) {
+        walk_expr(self, arena, expr)
+    }
+
+    fn visit_constant(&mut self, _constant: &'ast Constant) {}
+
+    fn visit_expr_context(&mut self, _ctx: ExprContext) {}
+
+    fn visit_arguments(&mut self, arena: &'ast Arena, arguments: &'ast Arguments) {
+        walk_arguments(self, arena, arguments)
+    }
+
+    fn visit_arg(&mut self, arena: &'ast Arena, arg: &'ast Arg) {
+        walk_arg(self, arena, arg)
+    }
+
+    fn visit_keyword(&mut self, arena: &'ast Arena, keyword: &'ast Keyword) {
+        walk_keyword(self, arena, keyword)
+    }
+
+    fn visit_type_param(&mut self, arena: &'ast Arena, type_param: &'ast TypeParam) {
+        walk_type_param(self, arena, type_param)
+    }
+
+    fn visit_alias(&mut self, _alias: &'ast Alias) {}
+
+    fn visit_with_item(&mut self, arena: &'ast Arena, item: &'ast WithItem) {
+        walk_with_item(self, arena, item)
+    }
+
+    fn visit_except_handler(&mut self, arena: &'ast Arena, handler: &'ast ExceptHandler) {
+        walk_except_handler(self, arena, handler)
+    }
+
+    fn visit_comprehension(&mut self, arena: &'ast Arena, comprehension: &'ast Comprehension) {
+        walk_comprehension(self, arena, comprehension)
+    }
+}
+
+pub fn walk_module<'ast, V: Visitor<'ast>>(visitor: &mut V, module: &'ast Module) {
+    let Module { body, arena } = module;
+    walk_body(visitor, arena, *body);
+}
+
+pub fn walk_body<'ast, V: Visitor<'ast>>(visitor: &mut V, arena: &'ast Arena, body: List<StmtId>) {
+    for &stmt in &arena[body] {
+        visitor.visit_stmt(arena, stmt);
+    }
+}
+
+fn walk_exprs<'ast, V: Visitor<'ast>>(visitor: &mut V, arena: &'ast Arena, exprs: List<ExprId>) {
+    for &expr in &arena[exprs] {
+        visitor.visit_expr(arena, expr);
+    }
+}
+
+fn walk_opt_expr<'ast, V: Visitor<'ast>>(
+    visitor: &mut V,
+    arena: &'ast Arena,
+    expr: &Option<ExprId>,
+) {
+    if let Some(expr) = expr {
+        visitor.visit_expr(arena, *expr);
+    }
+}
+
+pub fn walk_stmt<'ast, V: Visitor<'ast>>(visitor: &mut V, arena: &'ast Arena, stmt: StmtId) {
+    match &arena[stmt].kind {
+        StmtKind::FunctionDef {
+            name: _,
+            args,
+            body,
+            decorator_list,
+            returns,
+            type_params,
+        }
+        | StmtKind::AsyncFunctionDef {
+            name: _,
+            args,
+            body,
+            decorator_list,
+            returns,
+            type_params,
+        } => {
+            visitor.visit_arguments(arena, args);
+            walk_body(visitor, arena, *body);
+            walk_exprs(visitor, arena, *decorator_list);
+            walk_opt_expr(visitor, arena, returns);
+            walk_type_params(visitor, arena, *type_params);
+        }
+        StmtKind::ClassDef {
+            name: _,
+            bases,
+            keywords,
+            body,
+            decorator_list,
+            type_params,
+        } => {
+            walk_exprs(visitor, arena, *bases);
+            for keyword in &arena[*keywords] {
+                visitor.visit_keyword(arena, keyword);
+            }
+            walk_body(visitor, arena, *body);
+            walk_exprs(visitor, arena, *decorator_list);
+            walk_type_params(visitor, arena, *type_params);
+        }
+        StmtKind::Return { value } => walk_opt_expr(visitor, arena, value),
+        StmtKind::Delete { targets } => walk_exprs(visitor, arena, *targets),
+        StmtKind::Assign { targets, value } => {
+            walk_exprs(visitor, arena, *targets);
+            visitor.visit_expr(arena, *value);
+        }
+        StmtKind::TypeAlias {
+            name,
+            type_params,
+            value,
+        } => {
+            visitor.visit_expr(arena, *name);
+            walk_type_params(visitor, arena, *type_params);
+            visitor.visit_expr(arena, *value);
+        }
+        StmtKind::AugAssign {
+            target,
+            op: _,
+            value,
+        } => {
+            visitor.visit_expr(arena, *target);
+            visitor.visit_expr(arena, *value);
+        }
+        StmtKind::AnnAssign {
+            target,
+            annotation,
+            value,
+            simple: _,
+        } => {
+            visitor.visit_expr(arena, *target);
+            visitor.visit_expr(arena, *annotation);
+            walk_opt_expr(visitor, arena, value);
+        }
+        StmtKind::For {
+            target,
+            iter,
+            body,
+            orelse,
+        }
+        | StmtKind::AsyncFor {
+            target,
+            iter,
+            body,
+            orelse,
+        } => {
+            visitor.visit_expr(arena, *target);
+            visitor.visit_expr(arena, *iter);
+            walk_body(visitor, arena, *body);
+            walk_body(visitor, arena, *orelse);
+        }
+        StmtKind::While { test, body, orelse } | StmtKind::If { test, body, orelse } => {
+            visitor.visit_expr(arena, *test);
+            walk_body(visitor, arena, *body);
+            walk_body(visitor, arena, *orelse);
+        }
+        StmtKind::With { items, body } | StmtKind::AsyncWith { items, body } => {
+            for item in &arena[*items] {
+                visitor.visit_with_item(arena, item);
+            }
+            walk_body(visitor, arena, *body);
+        }
+        StmtKind::Raise { exc, cause } => {
+            walk_opt_expr(visitor, arena, exc);
+            walk_opt_expr(visitor, arena, cause);
+        }
+        StmtKind::Try {
+            body,
+            handlers,
+            orelse,
+            finalbody,
+        } => {
+            walk_body(visitor, arena, *body);
+            for handler in &arena[*handlers] {
+                visitor.visit_except_handler(arena, handler);
+            }
+            walk_body(visitor, arena, *orelse);
+            walk_body(visitor, arena, *finalbody);
+        }
+        StmtKind::Assert { test, msg } => {
+            visitor.visit_expr(arena, *test);
+            walk_opt_expr(visitor, arena, msg);
+        }
+        StmtKind::Import { names }
+        | StmtKind::ImportFrom {
+            module: _,
+            names,
+            level: _,
+        } => {
+            for alias in &arena[*names] {
+                visitor.visit_alias(alias);
+            }
+        }
+        StmtKind::Global { names: _ } | StmtKind::Nonlocal { names: _ } => {}
+        StmtKind::Expr { value } => visitor.visit_expr(arena, *value),
+        StmtKind::Pass | StmtKind::Break | StmtKind::Continue => {}
+    }
+}
+
+pub fn walk_expr<'ast, V: Visitor<'ast>>(visitor: &mut V, arena: &'ast Arena, expr: ExprId) {
+    match &arena[expr].kind {
+        ExprKind::BoolOp { op: _, values } => walk_exprs(visitor, arena, *values),
+        ExprKind::NamedExpr { target, value } => {
+            visitor.visit_expr(arena, *target);
+            visitor.visit_expr(arena, *value);
+        }
+        ExprKind::BinOp { left, op: _, right } => {
+            visitor.visit_expr(arena, *left);
+            visitor.visit_expr(arena, *right);
+        }
+        ExprKind::UnaryOp { op: _, operand } => visitor.visit_expr(arena, *operand),
+        ExprKind::Await { value } | ExprKind::YieldFrom { value } => {
+            visitor.visit_expr(arena, *value)
+        }
+        ExprKind::Yield { value } => walk_opt_expr(visitor, arena, value),
+        ExprKind::Lambda { args, body } => {
+            visitor.visit_arguments(arena, args);
+            visitor.visit_expr(arena, *body);
+        }
+        ExprKind::IfExp { test, body, orelse } => {
+            visitor.visit_expr(arena, *test);
+            visitor.visit_expr(arena, *body);
+            visitor.visit_expr(arena, *orelse);
+        }
+        ExprKind::Dict { keys, values } => {
+            for key in arena[*keys].iter().flatten() {
+                visitor.visit_expr(arena, *key);
+            }
+            walk_exprs(visitor, arena, *values);
+        }
+        ExprKind::Set { elts } => walk_exprs(visitor, arena, *elts),
+        ExprKind::ListComp { elt, generators }
+        | ExprKind::SetComp { elt, generators }
+        | ExprKind::GeneratorExp { elt, generators } => {
+            visitor.visit_expr(arena, *elt);
+            for comprehension in &arena[*generators] {
+                visitor.visit_comprehension(arena, comprehension);
+            }
+        }
+        ExprKind::DictComp {
+            key,
+            value,
+            generators,
+        } => {
+            visitor.visit_expr(arena, *key);
+            visitor.visit_expr(arena, *value);
+            for comprehension in &arena[*generators] {
+                visitor.visit_comprehension(arena, comprehension);
+            }
+        }
+        ExprKind::Compare {
+            left,
+            ops: _,
+            comparators,
+        } => {
+            visitor.visit_expr(arena, *left);
+            walk_exprs(visitor, arena, *comparators);
+        }
+        ExprKind::Call {
+            func,
+            args,
+            keywords,
+        } => {
+            visitor.visit_expr(arena, *func);
+            walk_exprs(visitor, arena, *args);
+            for keyword in &arena[*keywords] {
+                visitor.visit_keyword(arena, keyword);
+            }
+        }
+        ExprKind::Constant { value } => visitor.visit_constant(value),
+        ExprKind::Attribute {
+            value,
+            attr: _,
+            ctx,
+        } => {
+            visitor.visit_expr(arena, *value);
+            visitor.visit_expr_context(*ctx);
+        }
+        ExprKind::Subscript { value, slice, ctx } => {
+            visitor.visit_expr(arena, *value);
+            visitor.visit_expr(arena, *slice);
+            visitor.visit_expr_context(*ctx);
+        }
+        ExprKind::Starred { value, ctx } => {
+            visitor.visit_expr(arena, *value);
+            visitor.visit_expr_context(*ctx);
+        }
+        ExprKind::Name { id: _, ctx } => visitor.visit_expr_context(*ctx),
+        ExprKind::List { elts, ctx } | ExprKind::Tuple { elts, ctx } => {
+            walk_exprs(visitor, arena, *elts);
+            visitor.visit_expr_context(*ctx);
+        }
+        ExprKind::Slice { lower, upper, step } => {
+            walk_opt_expr(visitor, arena, lower);
+            walk_opt_expr(visitor, arena, upper);
+            walk_opt_expr(visitor, arena, step);
+        }
+    }
+}
+
+pub fn walk_arguments<'ast, V: Visitor<'ast>>(
+    visitor: &mut V,
+    arena: &'ast Arena,
+    arguments: &'ast Arguments,
+) {
+    let Arguments {
+        posonlyargs,
+        args,
+        vararg,
+        kwonlyargs,
+        kw_defaults,
+        kwarg,
+        defaults,
+    } = arguments;
+    for arg in arena[*posonlyargs].iter().chain(&arena[*args]) {
+        visitor.visit_arg(arena, arg);
+    }
+    if let Some(arg) = vararg {
+        visitor.visit_arg(arena, arg);
+    }
+    for arg in &arena[*kwonlyargs] {
+        visitor.visit_arg(arena, arg);
+    }
+    for default in arena[*kw_defaults].iter().flatten() {
+        visitor.visit_expr(arena, *default);
+    }
+    if let Some(arg) = kwarg {
+        visitor.visit_arg(arena, arg);
+    }
+    walk_exprs(visitor, arena, *defaults);
+}
+
+pub fn walk_arg<'ast, V: Visitor<'ast>>(visitor: &mut V, arena: &'ast Arena, arg: &'ast Arg) {
+    let Arg {
+        arg: _,
+        annotation,
+        span: _,
+    } = arg;
+    walk_opt_expr(visitor, arena, annotation);
+}
+
+pub fn walk_keyword<'ast, V: Visitor<'ast>>(
+    visitor: &mut V,
+    arena: &'ast Arena,
+    keyword: &'ast Keyword,
+) {
+    let Keyword { arg: _, value } = keyword;
+    visitor.visit_expr(arena, *value);
+}
+
+fn walk_type_params<'ast, V: Visitor<'ast>>(
+    visitor: &mut V,
+    arena: &'ast Arena,
+    type_params: List<TypeParam>,
+) {
+    for type_param in &arena[type_params] {
+        visitor.visit_type_param(arena, type_param);
+    }
+}
+
+pub fn walk_type_param<'ast, V: Visitor<'ast>>(
+    visitor: &mut V,
+    arena: &'ast Arena,
+    type_param: &'ast TypeParam,
+) {
+    let TypeParam { kind, span: _ } = type_param;
+    match kind {
+        TypeParamKind::TypeVar { name: _, bound } => walk_opt_expr(visitor, arena, bound),
+        TypeParamKind::ParamSpec { name: _ } | TypeParamKind::TypeVarTuple { name: _ } => {}
+    }
+}
+
+pub fn walk_with_item<'ast, V: Visitor<'ast>>(
+    visitor: &mut V,
+    arena: &'ast Arena,
+    item: &'ast WithItem,
+) {
+    let WithItem {
+        context_expr,
+        optional_vars,
+    } = item;
+    visitor.visit_expr(arena, *context_expr);
+    if let Some(vars) = optional_vars {
+        visitor.visit_expr(arena, *vars);
+    }
+}
+
+pub fn walk_except_handler<'ast, V: Visitor<'ast>>(
+    visitor: &mut V,
+    arena: &'ast Arena,
+    handler: &'ast ExceptHandler,
+) {
+    let ExceptHandler {
+        type_,
+        name: _,
+        body,
+        span: _,
+    } = handler;
+    if let Some(type_) = type_ {
+        visitor.visit_expr(arena, *type_);
+    }
+    walk_body(visitor, arena, *body);
+}
+
+pub fn walk_comprehension<'ast, V: Visitor<'ast>>(
+    visitor: &mut V,
+    arena: &'ast Arena,
+    comprehension: &'ast Comprehension,
+) {
+    let Comprehension {
+        target,
+        iter,
+        ifs,
+        is_async: _,
+    } = comprehension;
+    visitor.visit_expr(arena, *target);
+    visitor.visit_expr(arena, *iter);
+    walk_exprs(visitor, arena, *ifs);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    /// Collects every name in load context, in visiting order.
+    struct Loads<'ast> {
+        names: Vec<&'ast str>,
+    }
+
+    impl<'ast> Visitor<'ast> for Loads<'ast> {
+        fn visit_expr(&mut self, arena: &'ast Arena, expr: ExprId) {
+            if let ExprKind::Name {
+                id,
+                ctx: ExprContext::Load,
+            } = arena[expr].kind
+            {
+                self.names.push(id.as_str());
+            }
+            walk_expr(self, arena, expr);
+        }
+    }
+
+    #[test]
+    fn test_visit_names() {
+        let source = std::fs::read_to_string("tests/fib.py").unwrap();
+        let module = parse(&source).unwrap();
+        let mut loads = Loads { names: Vec::new() };
+        loads.visit_module(&module);
+        assert_eq!(
+            loads.names,
+            vec!["x", "x", "fib", "x", "fib", "x", "print", "fib"]
+        );
+    }
+
+    /// Counts nodes by kind, stopping at nested function bodies.
+    #[derive(Default)]
+    struct Counter {
+        stmts: usize,
+        exprs: usize,
+        args: usize,
+        handlers: usize,
+        comprehensions: usize,
+    }
+
+    impl<'ast> Visitor<'ast> for Counter {
+        fn visit_stmt(&mut self, arena: &'ast Arena, stmt: StmtId) {
+            self.stmts += 1;
+            if let StmtKind::FunctionDef { .. } = arena[stmt].kind {
+                return;
+            }
+            walk_stmt(self, arena, stmt);
+        }
+
+        fn visit_expr(&mut self, arena: &'ast Arena, expr: ExprId) {
+            self.exprs += 1;
+            walk_expr(self, arena, expr);
+        }
+
+        fn visit_arg(&mut self, arena: &'ast Arena, arg: &'ast Arg) {
+            self.args += 1;
+            walk_arg(self, arena, arg);
+        }
+
+        fn visit_except_handler(&mut self, arena: &'ast Arena, handler: &'ast ExceptHandler) {
+            self.handlers += 1;
+            walk_except_handler(self, arena, handler);
+        }
+
+        fn visit_comprehension(&mut self, arena: &'ast Arena, comprehension: &'ast Comprehension) {
+            self.comprehensions += 1;
+            walk_comprehension(self, arena, comprehension);
+        }
+    }
+
+    #[test]
+    fn test_visit_counts() {
+        let source = "\
+def f(a, b=1):
+    return a
+try:
+    x = [y for y in z if y]
+except E as e:
+    g(key=lambda k, *, j=2: k)
+";
+        let module = parse(source).unwrap();
+        let mut counter = Counter::default();
+        counter.visit_module(&module);
+        assert_eq!(counter.stmts, 4);
+        // x, the list comprehension, y, y, z, y, E, g(...), g, the lambda,
+        // 2 and k.
+        assert_eq!(counter.exprs, 12);
+        assert_eq!(counter.args, 2);
+        assert_eq!(counter.handlers, 1);
+        assert_eq!(counter.comprehensions, 1);
+    }
+}
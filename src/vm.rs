@@ -1,87 +1,1978 @@
-// use crate::{environment::Environment, intruction::Instruction, value::Value};
-
-// pub struct Vm {
-//     pub codes: Vec<Instruction>,
-//     pub stack: Vec<Value>,
-//     pub env: Environment,
-// }
-
-// impl Vm {
-//     pub fn new() -> Self {
-//         Self {
-//             codes: Vec::new(),
-//             stack: Vec::new(),
-//             env: Environment::new(),
-//         }
-//     }
-
-//     pub fn run(&mut self) -> Value {
-//         let mut pc = 0;
-//         loop {
-//             if pc >= self.codes.len() {
-//                 break;
-//             }
-
-//             let instruction = &self.codes[pc];
-//             match instruction {
-//                 Instruction::LoadConst(value) => {
-//                     self.stack.push(Value::Int(*value));
-//                 }
-//                 Instruction::LoadName(name) => {
-//                     let value = self.env.get(name, 0).unwrap();
-//                     self.stack.push(value);
-//                 }
-//                 Instruction::StoreName(name) => {
-//                     let value = self.stack.pop().unwrap();
-//                     self.env.set(name, value, 0);
-//                 }
-//                 Instruction::BinaryOp(op) => {
-//                     if let Value::Int(right) = self.stack.pop().unwrap() {
-//                         if let Value::Int(left) = self.stack.pop().unwrap() {
-//                             let result = match op.as_str() {
-//                                 "+" => left + right,
-//                                 "-" => left - right,
-//                                 "*" => left * right,
-//                                 "/" => left / right,
-//                                 "%" => left % right,
-//                                 _ => panic!("Unknown operator"),
-//                             };
-//                             self.stack.push(Value::Int(result));
-//                         }
-//                     } else {
-//                         panic!("Invalid operand");
-//                     }
-//                 }
-//             }
-//             pc += 1;
-//         }
-//         self.stack.pop().unwrap()
-//     }
-// }
-
-// #[cfg(test)]
-
-// mod tests {
-
-//     use super::*;
-
-//     use crate::{parser::parse, token::TokenType};
-
-//     #[test]
-//     fn test_vm() {
-//         let mut vm = Vm::new();
-//         let codes = vec![
-//             Instruction::LoadConst(1),
-//             Instruction::LoadConst(2),
-//             Instruction::BinaryOp("+".to_string()),
-//             Instruction::LoadConst(3),
-//             Instruction::BinaryOp("+".to_string()),
-//             Instruction::LoadConst(4),
-//             Instruction::BinaryOp("+".to_string()),
-//         ];
-//         vm.codes = codes;
-//         let result = vm.run();
-//         assert_eq!(result, Value::Int(10));
-//         // assert_eq!(result, Value::Int(7));
-//     }
-// }
+//! The virtual machine, which runs code objects.
+//!
+//! Each call runs in a frame of its own, holding the function's fast
+//! locals, its cells and its value stack. Cells outlive the frame that made
+//! them when a closure keeps them: a nested function reads and writes the
+//! same cell as the function that defined it.
+//!
+//! An exception raised in a frame unwinds it with the code's exception
+//! table, as in CPython 3.11: the innermost entry covering the instruction
+//! that raised names the handler to jump to, and how many values of the
+//! stack to keep under the exception it pushes. Code that raises nothing
+//! pays nothing for its handlers.
+//!
+//! The VM runs the code units, not [`Instruction`]s: each code object's
+//! adaptive copy of them, decoded once into an opcode and a whole argument
+//! per unit, with pairs of instructions often run together fused into
+//! superinstructions. It rewrites hot instructions to forms specialized
+//! for what they see, as [`crate::specialize`] describes.
+//!
+//! [`Instruction`]: crate::intruction::Instruction
+
+use std::cell::{self, RefCell};
+use std::collections::HashMap;
+use std::io::Write;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::code::{CodeObject, CO_VARARGS, CO_VARKEYWORDS};
+use crate::intern::Symbol;
+use crate::intruction::{
+    Opcode, CMP_OPS, INTRINSIC_PARAMSPEC, INTRINSIC_SET_FUNCTION_TYPE_PARAMS, INTRINSIC_TYPEALIAS,
+    INTRINSIC_TYPEVAR, INTRINSIC_TYPEVARTUPLE, INTRINSIC_TYPEVAR_WITH_BOUND,
+    INTRINSIC_TYPEVAR_WITH_CONSTRAINTS, MAKE_ANNOTATIONS, MAKE_CLOSURE, MAKE_DEFAULTS,
+    MAKE_KWDEFAULTS,
+};
+use crate::specialize::{self, Family, Stats, Unit};
+use crate::value::{
+    is_subclass, ordering_holds, set, unset, Builtin, Cell, Class, Exception, Function, Instance,
+    Lazy, PyResult, Range, TracebackEntry, TypeAlias, TypeVar, TypeVarKind, Value, EXCEPTION_TYPES,
+};
+use crate::verify::verify_all;
+
+/// How deep calls may nest before a `RecursionError`, CPython's default.
+const RECURSION_LIMIT: usize = 1000;
+
+pub struct Vm<W: Write> {
+    globals: Namespace,
+    builtins: Namespace,
+    /// Where `print` writes.
+    out: W,
+    depth: usize,
+    /// The exception being handled, which a bare `raise` raises again, or
+    /// `None`.
+    exc_info: Value,
+    /// Whether hot instructions are specialized.
+    specializing: bool,
+    stats: Stats,
+}
+
+/// The global variables of the module being run, or the builtins, each
+/// kept at the index it was first bound at. The version changes whenever
+/// a new name is bound, so that a `LOAD_GLOBAL` specialized for a version
+/// may take the value at the index it found the name at.
+struct Namespace {
+    indexes: HashMap<Symbol, usize>,
+    values: Vec<Value>,
+    /// Taken from [`VERSIONS`] as names are bound, and 0 once it has run
+    /// out, when no instruction is specialized for it.
+    version: u32,
+}
+
+/// The next version of any namespace. Code objects, and the instructions
+/// specialized in them, are shared by every VM that runs them, so no two
+/// namespaces, in this VM or another, may have the same version.
+static VERSIONS: AtomicU32 = AtomicU32::new(1);
+
+/// A version no namespace has had, or 0 once there are none left.
+fn next_version() -> u32 {
+    VERSIONS
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |version| {
+            version.checked_add(1)
+        })
+        .unwrap_or(0)
+}
+
+impl Namespace {
+    fn get(&self, name: Symbol) -> Option<&Value> {
+        self.indexes.get(&name).map(|&index| &self.values[index])
+    }
+
+    fn index(&self, name: Symbol) -> Option<usize> {
+        self.indexes.get(&name).copied()
+    }
+
+    fn insert(&mut self, name: Symbol, value: Value) {
+        match self.indexes.get(&name) {
+            Some(&index) => self.values[index] = value,
+            None => {
+                self.indexes.insert(name, self.values.len());
+                self.values.push(value);
+                self.bump();
+            }
+        }
+    }
+
+    /// Unbinds `name`, returning whether it was bound. Its slot stays, so
+    /// that no other name moves.
+    fn remove(&mut self, name: Symbol) -> bool {
+        let Some(index) = self.indexes.remove(&name) else {
+            return false;
+        };
+        self.values[index] = Value::None;
+        self.bump();
+        true
+    }
+
+    fn bump(&mut self) {
+        if self.version != 0 {
+            self.version = next_version();
+        }
+    }
+}
+
+impl FromIterator<(Symbol, Value)> for Namespace {
+    fn from_iter<I: IntoIterator<Item = (Symbol, Value)>>(items: I) -> Namespace {
+        let mut namespace = Namespace {
+            indexes: HashMap::new(),
+            values: Vec::new(),
+            version: next_version(),
+        };
+        for (name, value) in items {
+            namespace.insert(name, value);
+        }
+        namespace
+    }
+}
+
+struct Frame {
+    code: Rc<CodeObject>,
+    fast: Vec<Option<Value>>,
+    /// The cells of the code's cell variables, then those of its free
+    /// variables, taken from the function's closure.
+    cells: Vec<Rc<Cell>>,
+    stack: Vec<Value>,
+    /// The offset of the next code unit to run.
+    pc: usize,
+    /// The namespace of a class body, where its names are bound rather
+    /// than in the globals.
+    locals: Option<Vec<(Symbol, Value)>>,
+    /// Whether the exception the frame raises is one it raised before and
+    /// is raising again, which its traceback already shows.
+    reraised: bool,
+}
+
+impl Frame {
+    fn new(code: Rc<CodeObject>, fast: Vec<Option<Value>>, cells: Vec<Rc<Cell>>) -> Frame {
+        Frame {
+            stack: Vec::with_capacity(code.stacksize),
+            code,
+            fast,
+            cells,
+            pc: 0,
+            locals: None,
+            reraised: false,
+        }
+    }
+
+    #[inline]
+    fn load_fast(&mut self, index: u32) -> PyResult<()> {
+        let Some(value) = &self.fast[index as usize] else {
+            return Err(unbound_local(self.code.varnames[index as usize]));
+        };
+        self.stack.push(copy(value));
+        Ok(())
+    }
+
+    #[inline]
+    fn store_fast(&mut self, index: u32) {
+        let value = self.stack.pop();
+        if let Some(old) = std::mem::replace(&mut self.fast[index as usize], value) {
+            discard(old);
+        }
+    }
+
+    /// Pops the two operands of a specialized instruction whose guards held.
+    #[inline]
+    fn pop_operands(&mut self) {
+        discard(self.stack.pop().unwrap());
+        discard(self.stack.pop().unwrap());
+    }
+
+    /// The argument of the second instruction of the superinstruction just
+    /// run, which it then moves past.
+    #[inline]
+    fn next_arg(&mut self, units: &[cell::Cell<Unit>]) -> u32 {
+        let arg = units[self.pc].get().arg;
+        self.pc += 1;
+        arg
+    }
+}
+
+impl<W: Write> Vm<W> {
+    pub fn new(out: W) -> Vm<W> {
+        let builtins = Builtin::ALL
+            .iter()
+            .map(|&builtin| (Symbol::intern(builtin.name()), Value::Builtin(builtin)))
+            .chain(
+                EXCEPTION_TYPES
+                    .iter()
+                    .map(|&(kind, _)| (Symbol::intern(kind), Value::ExceptionType(kind))),
+            )
+            .collect();
+        // The module run is the main one, as `class` statements find.
+        let globals = [(Symbol::intern("__name__"), Value::str("__main__"))]
+            .into_iter()
+            .collect();
+        Vm {
+            globals,
+            builtins,
+            out,
+            depth: 0,
+            exc_info: Value::None,
+            specializing: true,
+            stats: Stats::default(),
+        }
+    }
+
+    pub fn out(&self) -> &W {
+        &self.out
+    }
+
+    /// The value of a global variable.
+    pub fn global(&self, name: &str) -> Option<&Value> {
+        self.globals.get(Symbol::intern(name))
+    }
+
+    /// Turns the specialization of hot instructions on or off; it is on
+    /// unless turned off. Instructions already specialized stay so.
+    pub fn set_specializing(&mut self, specializing: bool) {
+        self.specializing = specializing;
+    }
+
+    /// How specialization has gone so far.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Runs the code of a module, whose variables are the globals, once it
+    /// and the code nested in it are verified: the VM takes the stack and
+    /// the indexes in verified code to be sound.
+    pub fn run(&mut self, code: Rc<CodeObject>) -> PyResult {
+        verify_all(&code).map_err(|error| Exception::new("SystemError", error.to_string()))?;
+        // Compiled module code has no fast locals or cells, but verified
+        // code may use them. With no closure, its free variables start out
+        // unbound like its cell variables.
+        let fast = vec![None; code.varnames.len()];
+        let cells = (0..code.cellvars.len() + code.freevars.len())
+            .map(|_| Rc::new(RefCell::new(None)))
+            .collect();
+        // The module's frame counts towards the recursion limit, as in
+        // CPython.
+        self.depth += 1;
+        let result = self.execute(&mut Frame::new(code, fast, cells));
+        self.depth -= 1;
+        result
+    }
+
+    pub fn call(&mut self, callee: &Value, args: Vec<Value>) -> PyResult {
+        self.call_with_keywords(callee, args, Vec::new())
+    }
+
+    /// Calls with positional arguments and keyword arguments, by name.
+    pub fn call_with_keywords(
+        &mut self,
+        callee: &Value,
+        args: Vec<Value>,
+        kwargs: Vec<(Symbol, Value)>,
+    ) -> PyResult {
+        match callee {
+            Value::Function(function) => self.call_function(function, args, kwargs),
+            Value::Builtin(builtin) => self.call_builtin(*builtin, args, kwargs),
+            Value::Method(function, instance) => {
+                let args = std::iter::once(Value::Instance(instance.clone()))
+                    .chain(args)
+                    .collect();
+                self.call_function(function, args, kwargs)
+            }
+            Value::BuiltinMethod(builtin, owner) => {
+                let args = std::iter::once((**owner).clone()).chain(args).collect();
+                self.call_builtin(*builtin, args, kwargs)
+            }
+            Value::Class(class) => self.instantiate(class, args, kwargs),
+            Value::ExceptionType(kind) => {
+                if !kwargs.is_empty() {
+                    return Err(Exception::type_error(format!(
+                        "{}() takes no keyword arguments",
+                        kind
+                    )));
+                }
+                Ok(Value::Exception(Rc::new(Exception::with_args(kind, args))))
+            }
+            _ => Err(Exception::type_error(format!(
+                "'{}' object is not callable",
+                callee.type_name()
+            ))),
+        }
+    }
+
+    fn call_function(
+        &mut self,
+        function: &Function,
+        args: Vec<Value>,
+        kwargs: Vec<(Symbol, Value)>,
+    ) -> PyResult {
+        let mut frame = self.function_frame(function, args, kwargs)?;
+        self.depth += 1;
+        let result = self.execute(&mut frame);
+        self.depth -= 1;
+        result
+    }
+
+    /// The frame of a call of `function`, its arguments bound.
+    fn function_frame(
+        &self,
+        function: &Function,
+        args: Vec<Value>,
+        kwargs: Vec<(Symbol, Value)>,
+    ) -> PyResult<Frame> {
+        let fast = bind_arguments(function, args, kwargs)?;
+        self.frame_with_fast(function, fast)
+    }
+
+    /// The frame of a call of `function` whose arguments are bound to
+    /// `fast`.
+    fn frame_with_fast(
+        &self,
+        function: &Function,
+        mut fast: Vec<Option<Value>>,
+    ) -> PyResult<Frame> {
+        let code = &function.code;
+        if self.depth >= RECURSION_LIMIT {
+            return Err(Exception::new(
+                "RecursionError",
+                "maximum recursion depth exceeded",
+            ));
+        }
+        // A parameter that a nested function uses starts out in its cell.
+        let params = code.argcount
+            + code.kwonlyargcount
+            + (code.flags & CO_VARARGS != 0) as usize
+            + (code.flags & CO_VARKEYWORDS != 0) as usize;
+        let mut cells: Vec<Rc<Cell>> = code
+            .cellvars
+            .iter()
+            .map(|name| {
+                let param = code.varnames[..params]
+                    .iter()
+                    .position(|param| param == name);
+                let value = param.and_then(|param| fast[param].take());
+                Rc::new(RefCell::new(value))
+            })
+            .collect();
+        cells.extend(function.closure.iter().cloned());
+        Ok(Frame::new(code.clone(), fast, cells))
+    }
+
+    /// Makes an instance of `class`, which its `__init__` initializes.
+    fn instantiate(
+        &mut self,
+        class: &Rc<Class>,
+        args: Vec<Value>,
+        kwargs: Vec<(Symbol, Value)>,
+    ) -> PyResult {
+        let instance = Rc::new(Instance {
+            class: class.clone(),
+            attrs: RefCell::new(Vec::new()),
+        });
+        match instance.attribute(Symbol::intern("__init__")) {
+            Some(init) => {
+                let result = self.call_with_keywords(&init, args, kwargs)?;
+                if !matches!(result, Value::None) {
+                    return Err(Exception::type_error(format!(
+                        "__init__() should return None, not '{}'",
+                        result.type_name()
+                    )));
+                }
+            }
+            None if !args.is_empty() || !kwargs.is_empty() => {
+                return Err(Exception::type_error(format!(
+                    "{}() takes no arguments",
+                    class.name
+                )))
+            }
+            None => {}
+        }
+        Ok(Value::Instance(instance))
+    }
+
+    fn call_builtin(
+        &mut self,
+        builtin: Builtin,
+        args: Vec<Value>,
+        kwargs: Vec<(Symbol, Value)>,
+    ) -> PyResult {
+        if builtin == Builtin::BuildClass {
+            return self.build_class(args, kwargs);
+        }
+        if builtin != Builtin::Print && !kwargs.is_empty() {
+            return Err(Exception::type_error(format!(
+                "{}() takes no keyword arguments",
+                builtin.qualname()
+            )));
+        }
+        let one_arg = |args: Vec<Value>| {
+            let count = args.len();
+            <[Value; 1]>::try_from(args).map(|[arg]| arg).map_err(|_| {
+                Exception::type_error(format!(
+                    "{}() takes exactly one argument ({} given)",
+                    builtin.qualname(),
+                    count
+                ))
+            })
+        };
+        match builtin {
+            Builtin::Print => {
+                let (mut sep, mut end) = (" ".to_string(), "\n".to_string());
+                for (name, value) in kwargs {
+                    let option = match name.as_str() {
+                        "sep" => &mut sep,
+                        "end" => &mut end,
+                        _ => {
+                            return Err(Exception::type_error(format!(
+                                "'{}' is an invalid keyword argument for print()",
+                                name
+                            )))
+                        }
+                    };
+                    match value {
+                        Value::None => {}
+                        Value::Str(value) => *option = value.to_string(),
+                        _ => {
+                            return Err(Exception::type_error(format!(
+                                "{} must be None or a string, not {}",
+                                name,
+                                value.type_name()
+                            )))
+                        }
+                    }
+                }
+                let line: Vec<String> = args.iter().map(Value::to_string).collect();
+                write!(self.out, "{}{}", line.join(&sep), end)
+                    .map_err(|error| Exception::new("OSError", error.to_string()))?;
+                Ok(Value::None)
+            }
+            Builtin::BuildClass => unreachable!("__build_class__ is handled above"),
+            Builtin::Len => Ok(Value::Int(one_arg(args)?.py_len()? as i64)),
+            Builtin::Repr => Ok(Value::str(&one_arg(args)?.repr())),
+            Builtin::ListAppend => {
+                let mut args = args.into_iter();
+                let Some(Value::List(list)) = args.next() else {
+                    unreachable!("list.append is only bound to a list")
+                };
+                list.borrow_mut().push(one_arg(args.collect())?);
+                Ok(Value::None)
+            }
+            Builtin::Range => {
+                let bounds = args
+                    .iter()
+                    .map(|arg| match *arg {
+                        Value::Int(value) => Ok(value),
+                        Value::Bool(value) => Ok(value as i64),
+                        _ => Err(Exception::type_error(format!(
+                            "'{}' object cannot be interpreted as an integer",
+                            arg.type_name()
+                        ))),
+                    })
+                    .collect::<PyResult<Vec<i64>>>()?;
+                let range = match bounds[..] {
+                    [] => {
+                        return Err(Exception::type_error(
+                            "range expected at least 1 argument, got 0",
+                        ))
+                    }
+                    [stop] => Range::new(0, stop, 1)?,
+                    [start, stop] => Range::new(start, stop, 1)?,
+                    [start, stop, step] => Range::new(start, stop, step)?,
+                    _ => {
+                        return Err(Exception::type_error(format!(
+                            "range expected at most 3 arguments, got {}",
+                            bounds.len()
+                        )))
+                    }
+                };
+                Ok(Value::Range(range))
+            }
+        }
+    }
+
+    /// Runs the body of a `class` statement, as a function taking no
+    /// arguments, and makes the class named `name` from what it binds.
+    fn build_class(&mut self, args: Vec<Value>, kwargs: Vec<(Symbol, Value)>) -> PyResult {
+        let (body, name) = match (&args[..], kwargs.is_empty()) {
+            ([Value::Function(body), Value::Str(name)], true) => (body.clone(), name.clone()),
+            _ => {
+                return Err(Exception::type_error(
+                    "__build_class__: expected a function and a name",
+                ))
+            }
+        };
+        let mut frame = self.function_frame(&body, Vec::new(), Vec::new())?;
+        frame.locals = Some(Vec::new());
+        self.depth += 1;
+        let result = self.execute(&mut frame);
+        self.depth -= 1;
+        result?;
+        Ok(Value::Class(Rc::new(Class {
+            name: Symbol::intern(&name),
+            qualname: body.qualname.clone(),
+            attrs: RefCell::new(frame.locals.unwrap_or_default()),
+        })))
+    }
+
+    fn load_global(&self, name: Symbol) -> PyResult {
+        self.globals
+            .get(name)
+            .or_else(|| self.builtins.get(name))
+            .cloned()
+            .ok_or_else(|| not_defined(name))
+    }
+
+    /// Runs a frame to its end, adding it to the traceback of any exception
+    /// that it raises or that passes through it, and jumping to the handler
+    /// of any that its exception table covers.
+    fn execute(&mut self, frame: &mut Frame) -> PyResult {
+        loop {
+            let mut exception = match self.eval(frame) {
+                Ok(value) => return Ok(value),
+                Err(exception) => exception,
+            };
+            let code = &frame.code;
+            // The tables count instructions rather than code units.
+            let index = code.instruction_index(frame.pc - 1);
+            if !std::mem::take(&mut frame.reraised) {
+                exception.traceback.push(TracebackEntry {
+                    filename: code.filename.clone(),
+                    name: code.name.clone(),
+                    line: code.line(index),
+                });
+            }
+            let Some(handler) = code
+                .exception_table
+                .iter()
+                .find(|entry| entry.start as usize <= index && index < entry.end as usize)
+            else {
+                return Err(exception);
+            };
+            frame.stack.truncate(handler.depth as usize);
+            // The VM keeps no position for a `RERAISE` to restore beyond the
+            // traceback, so this is only pushed to lay the stack out as
+            // CPython does.
+            if handler.lasti {
+                frame.stack.push(Value::Int(index as i64));
+            }
+            frame.stack.push(Value::Exception(Rc::new(exception)));
+            frame.pc = code.instruction_offset(handler.target as usize);
+        }
+    }
+
+    fn eval(&mut self, frame: &mut Frame) -> PyResult {
+        let code = frame.code.clone();
+        let units = code.adaptive();
+        loop {
+            let Unit { opcode, arg } = units[frame.pc].get();
+            frame.pc += 1;
+            let stack = &mut frame.stack;
+            match opcode {
+                Opcode::ExtendedArg => frame.pc = arg as usize,
+                Opcode::Cache => unreachable!("ran an inline cache"),
+                Opcode::Nop => {}
+                Opcode::PopTop => {
+                    stack.pop();
+                }
+                Opcode::RotTwo => {
+                    let len = stack.len();
+                    stack.swap(len - 1, len - 2);
+                }
+                Opcode::RotThree => {
+                    let top = stack.pop().unwrap();
+                    stack.insert(stack.len() - 2, top);
+                }
+                Opcode::DupTop => {
+                    let top = stack.last().unwrap().clone();
+                    stack.push(top);
+                }
+                Opcode::LoadConst => stack.push(copy(&code.consts[arg as usize])),
+                Opcode::Copy => {
+                    let value = stack[stack.len() - arg as usize].clone();
+                    stack.push(value);
+                }
+                Opcode::LoadName => {
+                    let name = code.names[arg as usize];
+                    let local = frame.locals.as_ref().and_then(|locals| {
+                        locals
+                            .iter()
+                            .find(|(local, _)| *local == name)
+                            .map(|(_, value)| value.clone())
+                    });
+                    let value = match local {
+                        Some(value) => value,
+                        None => self.load_global(name)?,
+                    };
+                    stack.push(value);
+                }
+                Opcode::StoreName => {
+                    let value = stack.pop().unwrap();
+                    let name = code.names[arg as usize];
+                    match &mut frame.locals {
+                        Some(locals) => set(locals, name, value),
+                        None => {
+                            self.globals.insert(name, value);
+                        }
+                    }
+                }
+                Opcode::DeleteName => {
+                    let name = code.names[arg as usize];
+                    let removed = match &mut frame.locals {
+                        Some(locals) => unset(locals, name),
+                        None => self.globals.remove(name),
+                    };
+                    if !removed {
+                        return Err(not_defined(name));
+                    }
+                }
+                Opcode::LoadGlobal => {
+                    let name = code.names[arg as usize];
+                    stack.push(self.adaptive_load_global(units, frame.pc, opcode, name)?);
+                    frame.pc += opcode.caches();
+                }
+                Opcode::LoadGlobalModule | Opcode::LoadGlobalBuiltin => {
+                    let cache = frame.pc;
+                    let namespace = match opcode {
+                        Opcode::LoadGlobalModule => &self.globals,
+                        _ => &self.builtins,
+                    };
+                    if specialize::read(units, cache + 1) == self.globals.version {
+                        self.stats.hit(Family::LoadGlobal);
+                        let index = specialize::read(units, cache + 3) as usize;
+                        stack.push(namespace.values[index].clone());
+                    } else {
+                        self.stats.miss(Family::LoadGlobal);
+                        let name = code.names[arg as usize];
+                        stack.push(self.adaptive_load_global(units, cache, opcode, name)?);
+                    }
+                    frame.pc += opcode.caches();
+                }
+                Opcode::StoreGlobal => {
+                    let value = stack.pop().unwrap();
+                    self.globals.insert(code.names[arg as usize], value);
+                }
+                Opcode::DeleteGlobal => {
+                    let name = code.names[arg as usize];
+                    if !self.globals.remove(name) {
+                        return Err(not_defined(name));
+                    }
+                }
+                Opcode::LoadFast => frame.load_fast(arg)?,
+                Opcode::StoreFast => frame.store_fast(arg),
+                Opcode::LoadFastLoadFast => {
+                    frame.load_fast(arg)?;
+                    let arg = frame.next_arg(units);
+                    frame.load_fast(arg)?;
+                }
+                Opcode::LoadFastLoadConst => {
+                    frame.load_fast(arg)?;
+                    let arg = frame.next_arg(units);
+                    frame.stack.push(copy(&code.consts[arg as usize]));
+                }
+                Opcode::LoadConstLoadFast => {
+                    stack.push(copy(&code.consts[arg as usize]));
+                    let arg = frame.next_arg(units);
+                    frame.load_fast(arg)?;
+                }
+                Opcode::StoreFastLoadFast => {
+                    frame.store_fast(arg);
+                    let arg = frame.next_arg(units);
+                    frame.load_fast(arg)?;
+                }
+                Opcode::StoreFastStoreFast => {
+                    frame.store_fast(arg);
+                    let arg = frame.next_arg(units);
+                    frame.store_fast(arg);
+                }
+                Opcode::DeleteFast => {
+                    if frame.fast[arg as usize].take().is_none() {
+                        return Err(unbound_local(code.varnames[arg as usize]));
+                    }
+                }
+                Opcode::LoadDeref => {
+                    let Some(value) = frame.cells[arg as usize].borrow().clone() else {
+                        return Err(unbound_deref(&code, arg as usize));
+                    };
+                    stack.push(value);
+                }
+                Opcode::StoreDeref => {
+                    *frame.cells[arg as usize].borrow_mut() = stack.pop();
+                }
+                Opcode::DeleteDeref => {
+                    if frame.cells[arg as usize].borrow_mut().take().is_none() {
+                        return Err(unbound_deref(&code, arg as usize));
+                    }
+                }
+                Opcode::LoadClosure => {
+                    stack.push(Value::Cell(frame.cells[arg as usize].clone()));
+                }
+                Opcode::LoadAttr => {
+                    let owner = stack.pop().unwrap();
+                    let name = code.names[arg as usize];
+                    stack.push(self.adaptive_load_attr(units, frame.pc, opcode, &owner, name)?);
+                    frame.pc += opcode.caches();
+                }
+                Opcode::LoadAttrInstance | Opcode::LoadAttrMethod => {
+                    let owner = stack.pop().unwrap();
+                    let name = code.names[arg as usize];
+                    let index = specialize::read(units, frame.pc + 1) as usize;
+                    let found = match opcode {
+                        Opcode::LoadAttrInstance => {
+                            specialize::instance_attribute(&owner, name, index)
+                        }
+                        _ => specialize::method(&owner, name, index),
+                    };
+                    let value = match found {
+                        Some(value) => {
+                            self.stats.hit(Family::LoadAttr);
+                            value
+                        }
+                        None => {
+                            self.stats.miss(Family::LoadAttr);
+                            self.adaptive_load_attr(units, frame.pc, opcode, &owner, name)?
+                        }
+                    };
+                    stack.push(value);
+                    frame.pc += opcode.caches();
+                }
+                Opcode::StoreAttr => {
+                    let owner = stack.pop().unwrap();
+                    let value = stack.pop().unwrap();
+                    let name = code.names[arg as usize];
+                    match &owner {
+                        Value::Instance(instance) => instance.set_attribute(name, value),
+                        Value::Class(class) => set(&mut class.attrs.borrow_mut(), name, value),
+                        _ => return Err(no_attribute(&owner, name)),
+                    }
+                }
+                Opcode::DeleteAttr => {
+                    let owner = stack.pop().unwrap();
+                    let name = code.names[arg as usize];
+                    let removed = match &owner {
+                        Value::Instance(instance) => unset(&mut instance.attrs.borrow_mut(), name),
+                        Value::Class(class) => unset(&mut class.attrs.borrow_mut(), name),
+                        _ => false,
+                    };
+                    if !removed {
+                        return Err(no_attribute(&owner, name));
+                    }
+                }
+                Opcode::BinarySubscr => {
+                    let index = stack.pop().unwrap();
+                    let value = stack.pop().unwrap();
+                    stack.push(value.subscript(&index)?);
+                }
+                Opcode::DeleteSubscr => {
+                    let key = stack.pop().unwrap();
+                    let container = stack.pop().unwrap();
+                    container.delete_item(&key)?;
+                }
+                Opcode::UnaryPositive
+                | Opcode::UnaryNegative
+                | Opcode::UnaryNot
+                | Opcode::UnaryInvert => {
+                    let op = opcode.unary_operator().unwrap();
+                    let operand = stack.pop().unwrap();
+                    stack.push(operand.unary_op(op)?);
+                }
+                Opcode::BinaryAdd
+                | Opcode::BinarySubtract
+                | Opcode::BinaryMultiply
+                | Opcode::InplaceAdd
+                | Opcode::InplaceSubtract
+                | Opcode::InplaceMultiply => self.adaptive_binary_op(frame, units, opcode)?,
+                Opcode::BinaryAddInt | Opcode::InplaceAddInt => {
+                    let result = ints(stack).and_then(|(a, b)| a.checked_add(b));
+                    self.specialized_binary_op(frame, units, opcode, result.map(Value::Int))?;
+                }
+                Opcode::BinarySubtractInt | Opcode::InplaceSubtractInt => {
+                    let result = ints(stack).and_then(|(a, b)| a.checked_sub(b));
+                    self.specialized_binary_op(frame, units, opcode, result.map(Value::Int))?;
+                }
+                Opcode::BinaryMultiplyInt | Opcode::InplaceMultiplyInt => {
+                    let result = ints(stack).and_then(|(a, b)| a.checked_mul(b));
+                    self.specialized_binary_op(frame, units, opcode, result.map(Value::Int))?;
+                }
+                Opcode::BinaryAddFloat | Opcode::InplaceAddFloat => {
+                    let result = floats(stack).map(|(a, b)| Value::Float(a + b));
+                    self.specialized_binary_op(frame, units, opcode, result)?;
+                }
+                Opcode::BinarySubtractFloat | Opcode::InplaceSubtractFloat => {
+                    let result = floats(stack).map(|(a, b)| Value::Float(a - b));
+                    self.specialized_binary_op(frame, units, opcode, result)?;
+                }
+                Opcode::BinaryMultiplyFloat | Opcode::InplaceMultiplyFloat => {
+                    let result = floats(stack).map(|(a, b)| Value::Float(a * b));
+                    self.specialized_binary_op(frame, units, opcode, result)?;
+                }
+                Opcode::BinaryAddUnicode | Opcode::InplaceAddUnicode => {
+                    let result = match &stack[stack.len() - 2..] {
+                        [Value::Str(a), Value::Str(b)] => Some(Value::str(&[&**a, &**b].concat())),
+                        _ => None,
+                    };
+                    self.specialized_binary_op(frame, units, opcode, result)?;
+                }
+                Opcode::BinaryMatrixMultiply
+                | Opcode::BinaryTrueDivide
+                | Opcode::BinaryModulo
+                | Opcode::BinaryPower
+                | Opcode::BinaryLshift
+                | Opcode::BinaryRshift
+                | Opcode::BinaryOr
+                | Opcode::BinaryXor
+                | Opcode::BinaryAnd
+                | Opcode::BinaryFloorDivide => {
+                    let op = opcode.operator().unwrap();
+                    let right = stack.pop().unwrap();
+                    let left = stack.pop().unwrap();
+                    stack.push(left.binary_op(op, &right)?);
+                }
+                Opcode::InplaceMatrixMultiply
+                | Opcode::InplaceTrueDivide
+                | Opcode::InplaceModulo
+                | Opcode::InplacePower
+                | Opcode::InplaceLshift
+                | Opcode::InplaceRshift
+                | Opcode::InplaceOr
+                | Opcode::InplaceXor
+                | Opcode::InplaceAnd
+                | Opcode::InplaceFloorDivide => {
+                    let op = opcode.operator().unwrap();
+                    let right = stack.pop().unwrap();
+                    let left = stack.pop().unwrap();
+                    stack.push(left.inplace_op(op, &right)?);
+                }
+                Opcode::CompareOp | Opcode::CompareJumpIfFalse | Opcode::CompareJumpIfTrue => {
+                    self.adaptive_compare_op(frame, units, opcode, arg)?;
+                }
+                Opcode::CompareOpInt | Opcode::CompareOpFloat | Opcode::CompareOpStr => {
+                    let len = stack.len();
+                    match specialize::ordering(opcode, &stack[len - 2], &stack[len - 1]) {
+                        Some(ordering) => {
+                            self.stats.hit(Family::CompareOp);
+                            frame.pop_operands();
+                            let result = ordering_holds(CMP_OPS[arg as usize], ordering);
+                            frame.stack.push(Value::Bool(result));
+                            frame.pc += opcode.caches();
+                        }
+                        None => {
+                            self.stats.miss(Family::CompareOp);
+                            self.adaptive_compare_op(frame, units, opcode, arg)?;
+                        }
+                    }
+                }
+                Opcode::CompareJumpIfFalseInt
+                | Opcode::CompareJumpIfFalseFloat
+                | Opcode::CompareJumpIfFalseStr
+                | Opcode::CompareJumpIfTrueInt
+                | Opcode::CompareJumpIfTrueFloat
+                | Opcode::CompareJumpIfTrueStr => {
+                    let len = stack.len();
+                    match specialize::ordering(opcode, &stack[len - 2], &stack[len - 1]) {
+                        Some(ordering) => {
+                            self.stats.hit(Family::CompareOp);
+                            frame.pop_operands();
+                            let result = ordering_holds(CMP_OPS[arg as usize & 0xf], ordering);
+                            if result == (opcode.generic() == Opcode::CompareJumpIfTrue) {
+                                frame.pc = (arg >> 4) as usize;
+                            } else {
+                                frame.pc += opcode.caches();
+                            }
+                        }
+                        None => {
+                            self.stats.miss(Family::CompareOp);
+                            self.adaptive_compare_op(frame, units, opcode, arg)?;
+                        }
+                    }
+                }
+                Opcode::IsOp => {
+                    let invert = arg != 0;
+                    let right = stack.pop().unwrap();
+                    let left = stack.pop().unwrap();
+                    stack.push(Value::Bool(left.is(&right) != invert));
+                }
+                Opcode::ContainsOp => {
+                    let invert = arg != 0;
+                    let container = stack.pop().unwrap();
+                    let item = stack.pop().unwrap();
+                    stack.push(Value::Bool(container.contains(&item)? != invert));
+                }
+                Opcode::BuildTuple => {
+                    let items = stack.split_off(stack.len() - arg as usize);
+                    stack.push(Value::tuple(items));
+                }
+                Opcode::BuildList => {
+                    let items = stack.split_off(stack.len() - arg as usize);
+                    stack.push(Value::List(Rc::new(RefCell::new(items))));
+                }
+                Opcode::BuildSet => {
+                    let items = stack.split_off(stack.len() - arg as usize);
+                    stack.push(Value::set(items)?);
+                }
+                Opcode::BuildMap => {
+                    let items = stack.split_off(stack.len() - 2 * arg as usize);
+                    let pairs = items
+                        .chunks(2)
+                        .map(|pair| (pair[0].clone(), pair[1].clone()))
+                        .collect();
+                    stack.push(Value::dict(pairs)?);
+                }
+                Opcode::BuildConstKeyMap => {
+                    let Some(Value::Tuple(keys)) = stack.pop() else {
+                        return Err(malformed("BUILD_CONST_KEY_MAP without keys"));
+                    };
+                    let values = stack.split_off(stack.len() - arg as usize);
+                    stack.push(Value::dict(keys.iter().cloned().zip(values).collect())?);
+                }
+                Opcode::ListAppend => {
+                    let value = stack.pop().unwrap();
+                    let Value::List(list) = &stack[stack.len() - arg as usize] else {
+                        return Err(malformed("LIST_APPEND without a list"));
+                    };
+                    list.borrow_mut().push(value);
+                }
+                Opcode::SetAdd => {
+                    let value = stack.pop().unwrap();
+                    let set = &stack[stack.len() - arg as usize];
+                    if !matches!(set, Value::Set(_)) {
+                        return Err(malformed("SET_ADD without a set"));
+                    }
+                    set.add(value)?;
+                }
+                Opcode::ListExtend => {
+                    let iterable = stack.pop().unwrap();
+                    let Ok(Value::Iterator(iter)) = iterable.iter() else {
+                        return Err(Exception::type_error(format!(
+                            "Value after * must be an iterable, not {}",
+                            iterable.type_name()
+                        )));
+                    };
+                    // Collected first, as a list may be extended with itself.
+                    let items: Vec<Value> = iter.borrow_mut().by_ref().collect();
+                    let Value::List(list) = &stack[stack.len() - arg as usize] else {
+                        return Err(malformed("LIST_EXTEND without a list"));
+                    };
+                    list.borrow_mut().extend(items);
+                }
+                Opcode::ListToTuple => {
+                    let Some(Value::List(list)) = stack.pop() else {
+                        return Err(malformed("LIST_TO_TUPLE without a list"));
+                    };
+                    let items = list.borrow().clone();
+                    stack.push(Value::tuple(items));
+                }
+                Opcode::DictMerge => {
+                    let mapping = stack.pop().unwrap();
+                    // The callable is under the positional arguments, where
+                    // a call's `**` argument is merged.
+                    let callee = stack
+                        .len()
+                        .checked_sub(arg as usize + 2)
+                        .map_or(Value::None, |index| stack[index].clone());
+                    let Value::Dict(items) = &mapping else {
+                        return Err(Exception::type_error(format!(
+                            "{} argument after ** must be a mapping, not {}",
+                            callable_name(&callee),
+                            mapping.type_name()
+                        )));
+                    };
+                    let Value::Dict(dict) = &stack[stack.len() - arg as usize] else {
+                        return Err(malformed("DICT_MERGE without a dict"));
+                    };
+                    // Collected first, as a dict may be merged with itself.
+                    let items = items.borrow().clone();
+                    for (key, value) in items {
+                        if dict.borrow().iter().any(|(other, _)| other.py_eq(&key)) {
+                            let Value::Str(key) = key else {
+                                return Err(Exception::type_error("keywords must be strings"));
+                            };
+                            return Err(Exception::type_error(format!(
+                                "{} got multiple values for keyword argument '{}'",
+                                callable_name(&callee),
+                                key
+                            )));
+                        }
+                        dict.borrow_mut().push((key, value));
+                    }
+                }
+                Opcode::MapAdd => {
+                    let value = stack.pop().unwrap();
+                    let key = stack.pop().unwrap();
+                    stack[stack.len() - arg as usize].set_item(key, value)?;
+                }
+                Opcode::UnpackSequence => {
+                    let value = stack.pop().unwrap();
+                    for item in unpack(&value, arg as usize)?.into_iter().rev() {
+                        stack.push(item);
+                    }
+                }
+                Opcode::CallFunction => self.adaptive_call(frame, units, opcode, arg as usize)?,
+                Opcode::CallPyExactArgs | Opcode::CallMethodExactArgs | Opcode::CallBuiltin => {
+                    let nargs = arg as usize;
+                    let hit = match (opcode, &stack[stack.len() - nargs - 1]) {
+                        (Opcode::CallPyExactArgs, Value::Function(function)) => {
+                            specialize::takes_exactly(function, nargs)
+                        }
+                        (Opcode::CallMethodExactArgs, Value::Method(function, _)) => {
+                            specialize::takes_exactly(function, nargs + 1)
+                        }
+                        (Opcode::CallBuiltin, Value::Builtin(_)) => true,
+                        _ => false,
+                    };
+                    if !hit {
+                        self.stats.miss(Family::Call);
+                        self.adaptive_call(frame, units, opcode, nargs)?;
+                        continue;
+                    }
+                    self.stats.hit(Family::Call);
+                    let args = stack.split_off(stack.len() - nargs);
+                    let result = match stack.pop().unwrap() {
+                        Value::Function(function) => self.call_exact(&function, args)?,
+                        Value::Method(function, instance) => {
+                            let args = std::iter::once(Value::Instance(instance))
+                                .chain(args)
+                                .collect();
+                            self.call_exact(&function, args)?
+                        }
+                        Value::Builtin(builtin) => self.call_builtin(builtin, args, Vec::new())?,
+                        _ => unreachable!("a call specialized for what it is not calling"),
+                    };
+                    frame.stack.push(result);
+                    frame.pc += opcode.caches();
+                }
+                Opcode::CallFunctionKw => {
+                    let Some(Value::Tuple(names)) = stack.pop() else {
+                        return Err(malformed("CALL_FUNCTION_KW without keyword names"));
+                    };
+                    if names.len() > arg as usize {
+                        return Err(malformed(
+                            "CALL_FUNCTION_KW with more keyword names than arguments",
+                        ));
+                    }
+                    let mut args = stack.split_off(stack.len() - arg as usize);
+                    let values = args.split_off(args.len() - names.len());
+                    let kwargs = names
+                        .iter()
+                        .zip(values)
+                        .map(|(name, value)| (Symbol::intern(&name.to_string()), value))
+                        .collect();
+                    let callee = stack.pop().unwrap();
+                    let result = self.call_with_keywords(&callee, args, kwargs)?;
+                    frame.stack.push(result);
+                }
+                Opcode::CallFunctionEx => {
+                    let kwargs = match arg & 1 {
+                        0 => Vec::new(),
+                        _ => {
+                            let Some(Value::Dict(items)) = stack.pop() else {
+                                return Err(malformed("CALL_FUNCTION_EX without a dict"));
+                            };
+                            let items = items.borrow();
+                            items
+                                .iter()
+                                .map(|(key, value)| match key {
+                                    Value::Str(key) => Ok((Symbol::intern(key), value.clone())),
+                                    _ => Err(Exception::type_error("keywords must be strings")),
+                                })
+                                .collect::<PyResult<Vec<_>>>()?
+                        }
+                    };
+                    let iterable = stack.pop().unwrap();
+                    let callee = stack.pop().unwrap();
+                    let args = match &iterable {
+                        Value::Tuple(items) => items.to_vec(),
+                        _ => match iterable.iter() {
+                            Ok(Value::Iterator(iter)) => iter.borrow_mut().by_ref().collect(),
+                            _ => {
+                                return Err(Exception::type_error(format!(
+                                    "{} argument after * must be an iterable, not {}",
+                                    callable_name(&callee),
+                                    iterable.type_name()
+                                )))
+                            }
+                        },
+                    };
+                    let result = self.call_with_keywords(&callee, args, kwargs)?;
+                    frame.stack.push(result);
+                }
+                Opcode::MakeFunction => {
+                    let Some(Value::Str(qualname)) = stack.pop() else {
+                        return Err(malformed("MAKE_FUNCTION without a qualified name"));
+                    };
+                    let Some(Value::Code(code)) = stack.pop() else {
+                        return Err(malformed("MAKE_FUNCTION without a code object"));
+                    };
+                    let mut function = Function {
+                        code,
+                        qualname,
+                        closure: Vec::new(),
+                        defaults: Vec::new(),
+                        kwdefaults: Vec::new(),
+                        annotations: Vec::new(),
+                        type_params: Vec::new(),
+                    };
+                    if arg & MAKE_CLOSURE != 0 {
+                        let Some(Value::Tuple(cells)) = stack.pop() else {
+                            return Err(malformed("MAKE_FUNCTION without a closure"));
+                        };
+                        function.closure = cells
+                            .iter()
+                            .map(|cell| match cell {
+                                Value::Cell(cell) => Ok(cell.clone()),
+                                _ => Err(malformed("closure item that is not a cell")),
+                            })
+                            .collect::<PyResult<_>>()?;
+                    }
+                    if function.closure.len() != function.code.freevars.len() {
+                        return Err(malformed(
+                            "MAKE_FUNCTION without a cell for each free variable",
+                        ));
+                    }
+                    if arg & MAKE_ANNOTATIONS != 0 {
+                        let Some(Value::Tuple(items)) = stack.pop() else {
+                            return Err(malformed("MAKE_FUNCTION without annotations"));
+                        };
+                        if items.len() % 2 != 0 {
+                            return Err(malformed(
+                                "MAKE_FUNCTION with annotations that are not name and value pairs",
+                            ));
+                        }
+                        function.annotations = items
+                            .chunks(2)
+                            .map(|pair| (Symbol::intern(&pair[0].to_string()), pair[1].clone()))
+                            .collect();
+                    }
+                    if arg & MAKE_KWDEFAULTS != 0 {
+                        let Some(Value::Dict(items)) = stack.pop() else {
+                            return Err(malformed("MAKE_FUNCTION without keyword-only defaults"));
+                        };
+                        function.kwdefaults = items
+                            .borrow()
+                            .iter()
+                            .map(|(name, value)| (Symbol::intern(&name.to_string()), value.clone()))
+                            .collect();
+                    }
+                    if arg & MAKE_DEFAULTS != 0 {
+                        let Some(Value::Tuple(defaults)) = stack.pop() else {
+                            return Err(malformed("MAKE_FUNCTION without defaults"));
+                        };
+                        if defaults.len() > function.code.argcount {
+                            return Err(malformed(
+                                "MAKE_FUNCTION with more defaults than positional parameters",
+                            ));
+                        }
+                        function.defaults = defaults.to_vec();
+                    }
+                    stack.push(Value::Function(Rc::new(function)));
+                }
+                Opcode::ReturnValue => return Ok(stack.pop().unwrap()),
+                Opcode::LoadBuildClass => stack.push(Value::Builtin(Builtin::BuildClass)),
+                Opcode::LoadAssertionError => stack.push(Value::ExceptionType("AssertionError")),
+                Opcode::CallIntrinsic1 => {
+                    let value = stack.pop().unwrap();
+                    stack.push(intrinsic1(arg, value)?);
+                }
+                Opcode::CallIntrinsic2 => {
+                    let second = stack.pop().unwrap();
+                    let first = stack.pop().unwrap();
+                    stack.push(intrinsic2(arg, first, second)?);
+                }
+                Opcode::RaiseVarargs if arg == 0 => {
+                    let Value::Exception(exception) = &self.exc_info else {
+                        return Err(Exception::new(
+                            "RuntimeError",
+                            "No active exception to reraise",
+                        ));
+                    };
+                    frame.reraised = true;
+                    return Err((**exception).clone());
+                }
+                Opcode::RaiseVarargs => {
+                    let cause = (arg == 2).then(|| stack.pop().unwrap());
+                    let mut exception = raised(stack.pop().unwrap())?;
+                    // `from None` clears the cause.
+                    match cause {
+                        None => {}
+                        Some(Value::None) => exception.cause = None,
+                        Some(cause) => {
+                            let cause = raised(cause).map_err(|_| {
+                                Exception::type_error(
+                                    "exception causes must derive from BaseException",
+                                )
+                            })?;
+                            exception.cause = Some(Rc::new(cause));
+                        }
+                    }
+                    return Err(exception);
+                }
+                Opcode::Reraise => {
+                    let Some(Value::Exception(exception)) = stack.pop() else {
+                        return Err(malformed("RERAISE without an exception"));
+                    };
+                    frame.reraised = true;
+                    return Err((*exception).clone());
+                }
+                Opcode::PushExcInfo => {
+                    let exception = stack.pop().unwrap();
+                    stack.push(std::mem::replace(&mut self.exc_info, exception.clone()));
+                    stack.push(exception);
+                }
+                Opcode::PopExcept => self.exc_info = stack.pop().unwrap(),
+                Opcode::CheckExcMatch => {
+                    let class = stack.pop().unwrap();
+                    let Some(Value::Exception(exception)) = stack.last() else {
+                        return Err(malformed("CHECK_EXC_MATCH without an exception"));
+                    };
+                    let matched = exception_matches(exception.kind, &class)?;
+                    stack.push(Value::Bool(matched));
+                }
+                Opcode::BeforeWith => {
+                    let manager = stack.pop().unwrap();
+                    let method = |name: &str| match &manager {
+                        Value::Instance(instance) => instance.attribute(Symbol::intern(name)),
+                        _ => None,
+                    };
+                    let (Some(enter), Some(exit)) = (method("__enter__"), method("__exit__"))
+                    else {
+                        return Err(Exception::type_error(format!(
+                            "'{}' object does not support the context manager protocol",
+                            manager.type_name()
+                        )));
+                    };
+                    stack.push(exit);
+                    let result = self.call(&enter, Vec::new())?;
+                    frame.stack.push(result);
+                }
+                Opcode::WithExceptStart => {
+                    // Under the exception are the previous one being handled,
+                    // the index that raised it and the `__exit__` method.
+                    let exit = stack[stack.len() - 4].clone();
+                    let Some(Value::Exception(exception)) = stack.last() else {
+                        return Err(malformed("WITH_EXCEPT_START without an exception"));
+                    };
+                    // There are no traceback objects, so `None` stands in
+                    // for the third argument.
+                    let args = vec![
+                        Value::ExceptionType(exception.kind),
+                        Value::Exception(exception.clone()),
+                        Value::None,
+                    ];
+                    let result = self.call(&exit, args)?;
+                    frame.stack.push(result);
+                }
+                Opcode::GetIter => {
+                    let value = stack.pop().unwrap();
+                    stack.push(value.iter()?);
+                }
+                Opcode::ForIter => {
+                    let Some(Value::Iterator(iter)) = stack.last() else {
+                        return Err(malformed("FOR_ITER without an iterator"));
+                    };
+                    let next = iter.borrow_mut().next();
+                    match next {
+                        Some(item) => stack.push(item),
+                        None => {
+                            stack.pop();
+                            frame.pc = arg as usize;
+                        }
+                    }
+                }
+                Opcode::JumpAbsolute => frame.pc = arg as usize,
+                Opcode::PopJumpIfFalse => {
+                    if !stack.pop().unwrap().is_truthy() {
+                        frame.pc = arg as usize;
+                    }
+                }
+                Opcode::PopJumpIfTrue => {
+                    if stack.pop().unwrap().is_truthy() {
+                        frame.pc = arg as usize;
+                    }
+                }
+                Opcode::JumpIfFalseOrPop => {
+                    if stack.last().unwrap().is_truthy() {
+                        stack.pop();
+                    } else {
+                        frame.pc = arg as usize;
+                    }
+                }
+                Opcode::JumpIfTrueOrPop => {
+                    if stack.last().unwrap().is_truthy() {
+                        frame.pc = arg as usize;
+                    } else {
+                        stack.pop();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Runs a binary or in-place operation that has specialized forms
+    /// generically, first specializing it if it is due.
+    fn adaptive_binary_op(
+        &mut self,
+        frame: &mut Frame,
+        units: &[cell::Cell<Unit>],
+        opcode: Opcode,
+    ) -> PyResult<()> {
+        let right = frame.stack.pop().unwrap();
+        let left = frame.stack.pop().unwrap();
+        let generic = opcode.generic();
+        if self.specializing && specialize::due(units, frame.pc) {
+            let specialized = specialize::binary_op(generic, &left, &right);
+            specialize::rewrite(units, frame.pc, opcode, specialized, &mut self.stats);
+        }
+        let op = generic.operator().unwrap();
+        let result = match generic {
+            Opcode::InplaceAdd | Opcode::InplaceSubtract | Opcode::InplaceMultiply => {
+                left.inplace_op(op, &right)?
+            }
+            _ => left.binary_op(op, &right)?,
+        };
+        frame.stack.push(result);
+        frame.pc += generic.caches();
+        Ok(())
+    }
+
+    /// Pushes the `result` of a specialized binary operation in place of
+    /// its operands, or without one, its guards having failed, runs the
+    /// operation generically.
+    #[inline]
+    fn specialized_binary_op(
+        &mut self,
+        frame: &mut Frame,
+        units: &[cell::Cell<Unit>],
+        opcode: Opcode,
+        result: Option<Value>,
+    ) -> PyResult<()> {
+        let Some(result) = result else {
+            return self.binary_op_miss(frame, units, opcode);
+        };
+        self.stats.hit(Family::BinaryOp);
+        frame.pop_operands();
+        frame.stack.push(result);
+        frame.pc += opcode.caches();
+        Ok(())
+    }
+
+    /// Runs a specialized binary operation whose guards failed generically,
+    /// kept out of line so that the hits stay small.
+    #[cold]
+    #[inline(never)]
+    fn binary_op_miss(
+        &mut self,
+        frame: &mut Frame,
+        units: &[cell::Cell<Unit>],
+        opcode: Opcode,
+    ) -> PyResult<()> {
+        self.stats.miss(Family::BinaryOp);
+        self.adaptive_binary_op(frame, units, opcode)
+    }
+
+    /// Runs a comparison, fused with a jump or not, generically, first
+    /// specializing it if it is due.
+    fn adaptive_compare_op(
+        &mut self,
+        frame: &mut Frame,
+        units: &[cell::Cell<Unit>],
+        opcode: Opcode,
+        arg: u32,
+    ) -> PyResult<()> {
+        let right = frame.stack.pop().unwrap();
+        let left = frame.stack.pop().unwrap();
+        let generic = opcode.generic();
+        if self.specializing && specialize::due(units, frame.pc) {
+            let specialized = specialize::compare_op(generic, &left, &right);
+            specialize::rewrite(units, frame.pc, opcode, specialized, &mut self.stats);
+        }
+        let result = left.compare(CMP_OPS[arg as usize & 0xf], &right)?;
+        match generic {
+            Opcode::CompareOp => frame.stack.push(result),
+            _ if result.is_truthy() == (generic == Opcode::CompareJumpIfTrue) => {
+                frame.pc = (arg >> 4) as usize;
+                return Ok(());
+            }
+            _ => {}
+        }
+        frame.pc += generic.caches();
+        Ok(())
+    }
+
+    /// Loads the global `name` generically, first specializing the
+    /// instruction if it is due. The builtins are never bound after the
+    /// VM starts, so the version of the globals, which a new global that
+    /// hides a builtin changes, guards builtins too.
+    fn adaptive_load_global(
+        &mut self,
+        units: &[cell::Cell<Unit>],
+        cache: usize,
+        opcode: Opcode,
+        name: Symbol,
+    ) -> PyResult {
+        if self.specializing && specialize::due(units, cache) {
+            let version = self.globals.version;
+            let found = match (self.globals.index(name), self.builtins.index(name)) {
+                _ if version == 0 => None,
+                (Some(index), _) => Some((Opcode::LoadGlobalModule, index)),
+                (None, Some(index)) => Some((Opcode::LoadGlobalBuiltin, index)),
+                (None, None) => None,
+            };
+            let found =
+                found.and_then(|(opcode, index)| Some((opcode, u16::try_from(index).ok()?)));
+            if let Some((_, index)) = found {
+                specialize::write(units, cache + 1, version);
+                specialize::write(units, cache + 3, index as u32);
+            }
+            let specialized = found.map(|(opcode, _)| opcode);
+            specialize::rewrite(units, cache, opcode, specialized, &mut self.stats);
+        }
+        self.load_global(name)
+    }
+
+    /// Loads the attribute `name` of `owner` generically, first
+    /// specializing the instruction if it is due.
+    fn adaptive_load_attr(
+        &mut self,
+        units: &[cell::Cell<Unit>],
+        cache: usize,
+        opcode: Opcode,
+        owner: &Value,
+        name: Symbol,
+    ) -> PyResult {
+        if self.specializing && specialize::due(units, cache) {
+            let found = specialize::load_attr(owner, name);
+            if let Some((_, index)) = found {
+                specialize::write(units, cache + 1, index as u32);
+            }
+            let specialized = found.map(|(opcode, _)| opcode);
+            specialize::rewrite(units, cache, opcode, specialized, &mut self.stats);
+        }
+        if let Some(lazy) = lazy_attribute(owner, name) {
+            return self.evaluate(lazy);
+        }
+        attribute(owner, name)
+    }
+
+    /// The value of `lazy`, computed the first time it is asked for.
+    fn evaluate(&mut self, lazy: &RefCell<Lazy>) -> PyResult {
+        let function = match &*lazy.borrow() {
+            Lazy::Pending(function) => function.clone(),
+            Lazy::Ready(value) => return Ok(value.clone()),
+        };
+        let value = self.call_function(&function, Vec::new(), Vec::new())?;
+        *lazy.borrow_mut() = Lazy::Ready(value.clone());
+        Ok(value)
+    }
+
+    /// Runs a call with `nargs` positional arguments generically, first
+    /// specializing it if it is due.
+    fn adaptive_call(
+        &mut self,
+        frame: &mut Frame,
+        units: &[cell::Cell<Unit>],
+        opcode: Opcode,
+        nargs: usize,
+    ) -> PyResult<()> {
+        let stack = &mut frame.stack;
+        if self.specializing && specialize::due(units, frame.pc) {
+            let specialized = specialize::call(&stack[stack.len() - nargs - 1], nargs);
+            specialize::rewrite(units, frame.pc, opcode, specialized, &mut self.stats);
+        }
+        let args = stack.split_off(stack.len() - nargs);
+        let callee = stack.pop().unwrap();
+        let result = self.call(&callee, args)?;
+        frame.stack.push(result);
+        frame.pc += opcode.caches();
+        Ok(())
+    }
+
+    /// Calls `function` with exactly the positional arguments it takes, as
+    /// [`specialize::takes_exactly`] checks, without binding them by name.
+    fn call_exact(&mut self, function: &Function, args: Vec<Value>) -> PyResult {
+        let mut fast: Vec<Option<Value>> = args.into_iter().map(Some).collect();
+        fast.resize(function.code.varnames.len(), None);
+        let mut frame = self.frame_with_fast(function, fast)?;
+        self.depth += 1;
+        let result = self.execute(&mut frame);
+        self.depth -= 1;
+        result
+    }
+}
+
+/// A clone of `value`, copying the ints and floats that locals and
+/// constants mostly are without a call to [`Value::clone`].
+#[inline]
+fn copy(value: &Value) -> Value {
+    match *value {
+        Value::Int(value) => Value::Int(value),
+        Value::Float(value) => Value::Float(value),
+        ref value => value.clone(),
+    }
+}
+
+/// Drops `value`, without a call to the drop glue of [`Value`] for the
+/// ints and floats that hot instructions mostly pop and overwrite.
+#[inline]
+fn discard(value: Value) {
+    if matches!(value, Value::Int(_) | Value::Float(_)) {
+        // Owning nothing, they need no dropping.
+        std::mem::forget(value);
+    }
+}
+
+/// The two ints on top of the stack, if they are.
+#[inline]
+fn ints(stack: &[Value]) -> Option<(i64, i64)> {
+    match stack[stack.len() - 2..] {
+        [Value::Int(a), Value::Int(b)] => Some((a, b)),
+        _ => None,
+    }
+}
+
+/// The two floats on top of the stack, if they are.
+#[inline]
+fn floats(stack: &[Value]) -> Option<(f64, f64)> {
+    match stack[stack.len() - 2..] {
+        [Value::Float(a), Value::Float(b)] => Some((a, b)),
+        _ => None,
+    }
+}
+
+/// Binds the arguments of a call to the parameters of `function`, as
+/// CPython does, failing with its messages: the fast locals that the call
+/// starts with. Missing arguments take the defaults, surplus positional
+/// ones go in a `*args` tuple, and unknown keywords in a `**kwargs` dict.
+fn bind_arguments(
+    function: &Function,
+    args: Vec<Value>,
+    kwargs: Vec<(Symbol, Value)>,
+) -> PyResult<Vec<Option<Value>>> {
+    let code = &function.code;
+    let name = &function.qualname;
+    let argcount = code.argcount;
+    let kwonly_end = argcount + code.kwonlyargcount;
+    let mut fast = vec![None; code.varnames.len()];
+    let given = args.len();
+    let mut args = args.into_iter();
+    for (slot, arg) in fast.iter_mut().zip(args.by_ref().take(argcount)) {
+        *slot = Some(arg);
+    }
+    let mut next = kwonly_end;
+    if code.flags & CO_VARARGS != 0 {
+        fast[next] = Some(Value::tuple(args.collect()));
+        next += 1;
+    }
+    let mut extra = (code.flags & CO_VARKEYWORDS != 0).then(Vec::new);
+    let posonly = &code.varnames[..code.posonlyargcount];
+    let posonly_as_keyword: Vec<String> = kwargs
+        .iter()
+        .filter(|(name, _)| extra.is_none() && posonly.contains(name))
+        .map(|(name, _)| format!("'{}'", name))
+        .collect();
+    for (keyword, value) in kwargs {
+        let slot = code.varnames[code.posonlyargcount..kwonly_end]
+            .iter()
+            .position(|&param| param == keyword)
+            .map(|slot| slot + code.posonlyargcount);
+        match (slot, &mut extra) {
+            (Some(slot), _) if fast[slot].is_some() => {
+                return Err(Exception::type_error(format!(
+                    "{}() got multiple values for argument '{}'",
+                    name, keyword
+                )))
+            }
+            (Some(slot), _) => fast[slot] = Some(value),
+            (None, Some(extra)) => extra.push((keyword, value)),
+            (None, None) if !posonly_as_keyword.is_empty() => {
+                return Err(Exception::type_error(format!(
+                    "{}() got some positional-only arguments passed as keyword arguments: {}",
+                    name,
+                    posonly_as_keyword.join(", ")
+                )))
+            }
+            (None, None) => {
+                return Err(Exception::type_error(format!(
+                    "{}() got an unexpected keyword argument '{}'",
+                    name, keyword
+                )))
+            }
+        }
+    }
+    if let Some(extra) = extra {
+        fast[next] = Some(Value::names_dict(&extra));
+    }
+
+    let defaults = &function.defaults;
+    if given > argcount && code.flags & CO_VARARGS == 0 {
+        let plural = |count| if count == 1 { "" } else { "s" };
+        let takes = if defaults.is_empty() {
+            format!("{} positional argument{}", argcount, plural(argcount))
+        } else {
+            format!(
+                "from {} to {} positional arguments",
+                argcount - defaults.len(),
+                argcount
+            )
+        };
+        let kwonly_given = fast[argcount..kwonly_end].iter().flatten().count();
+        let kwonly = if kwonly_given == 0 {
+            String::new()
+        } else {
+            format!(
+                " positional argument{} (and {} keyword-only argument{})",
+                plural(given),
+                kwonly_given,
+                plural(kwonly_given)
+            )
+        };
+        let verb = if given == 1 && kwonly_given == 0 {
+            "was"
+        } else {
+            "were"
+        };
+        return Err(Exception::type_error(format!(
+            "{}() takes {} but {}{} {} given",
+            name, takes, given, kwonly, verb
+        )));
+    }
+    let first_default = argcount - defaults.len();
+    let missing: Vec<Symbol> = (0..first_default)
+        .filter(|&slot| fast[slot].is_none())
+        .map(|slot| code.varnames[slot])
+        .collect();
+    if !missing.is_empty() {
+        return Err(missing_arguments(name, "positional", &missing));
+    }
+    for (slot, default) in fast[first_default..argcount].iter_mut().zip(defaults) {
+        slot.get_or_insert_with(|| default.clone());
+    }
+    let mut missing = Vec::new();
+    let kwonly = code.varnames[argcount..kwonly_end].iter();
+    for (slot, &param) in fast[argcount..kwonly_end].iter_mut().zip(kwonly) {
+        if slot.is_none() {
+            match function.kwdefaults.iter().find(|(name, _)| *name == param) {
+                Some((_, default)) => *slot = Some(default.clone()),
+                None => missing.push(param),
+            }
+        }
+    }
+    if !missing.is_empty() {
+        return Err(missing_arguments(name, "keyword-only", &missing));
+    }
+    Ok(fast)
+}
+
+fn missing_arguments(function: &str, kind: &str, missing: &[Symbol]) -> Exception {
+    let missing: Vec<String> = missing.iter().map(|name| format!("'{}'", name)).collect();
+    // 'x', then 'x' and 'y', then 'x', 'y', and 'z'.
+    let names = match &missing[..] {
+        [name] => name.clone(),
+        [first, second] => format!("{} and {}", first, second),
+        [rest @ .., last] => format!("{}, and {}", rest.join(", "), last),
+        [] => unreachable!(),
+    };
+    Exception::type_error(format!(
+        "{}() missing {} required {} argument{}: {}",
+        function,
+        missing.len(),
+        kind,
+        if missing.len() == 1 { "" } else { "s" },
+        names
+    ))
+}
+
+fn unbound_local(name: Symbol) -> Exception {
+    Exception::new(
+        "UnboundLocalError",
+        format!(
+            "cannot access local variable '{}' where it is not associated with a value",
+            name
+        ),
+    )
+}
+
+/// The error for code that verified but is still malformed, in the types
+/// of the values an instruction takes, which the verifier does not know.
+fn malformed(message: &str) -> Exception {
+    Exception::new("SystemError", message)
+}
+
+/// Runs the intrinsic function of one argument that `CALL_INTRINSIC_1`
+/// numbers.
+fn intrinsic1(function: u32, value: Value) -> PyResult {
+    let kind = match function {
+        INTRINSIC_TYPEVAR => TypeVarKind::TypeVar,
+        INTRINSIC_PARAMSPEC => TypeVarKind::ParamSpec,
+        INTRINSIC_TYPEVARTUPLE => TypeVarKind::TypeVarTuple,
+        INTRINSIC_TYPEALIAS => {
+            let Value::Tuple(items) = &value else {
+                return Err(malformed("INTRINSIC_TYPEALIAS without a tuple"));
+            };
+            let [Value::Str(name), type_params, Value::Function(function)] = &items[..] else {
+                return Err(malformed(
+                    "INTRINSIC_TYPEALIAS without a name, type parameters and a function",
+                ));
+            };
+            let type_params = match type_params {
+                Value::None => Vec::new(),
+                Value::Tuple(type_params) => type_params.to_vec(),
+                _ => return Err(malformed("INTRINSIC_TYPEALIAS without type parameters")),
+            };
+            return Ok(Value::TypeAlias(Rc::new(TypeAlias {
+                name: name.clone(),
+                type_params,
+                value: RefCell::new(Lazy::Pending(function.clone())),
+            })));
+        }
+        _ => return Err(malformed("CALL_INTRINSIC_1 of an unknown function")),
+    };
+    let Value::Str(name) = value else {
+        return Err(malformed("type parameter without a name"));
+    };
+    Ok(Value::TypeVar(Rc::new(TypeVar {
+        kind,
+        name: Symbol::intern(&name),
+        bound: None,
+        constraints: false,
+    })))
+}
+
+/// Runs the intrinsic function of two arguments that `CALL_INTRINSIC_2`
+/// numbers.
+fn intrinsic2(function: u32, first: Value, second: Value) -> PyResult {
+    match (function, first, second) {
+        (
+            INTRINSIC_SET_FUNCTION_TYPE_PARAMS,
+            Value::Function(mut function),
+            Value::Tuple(type_params),
+        ) => {
+            // The function was only just made, so nothing else has it yet.
+            let Some(unshared) = Rc::get_mut(&mut function) else {
+                return Err(malformed(
+                    "INTRINSIC_SET_FUNCTION_TYPE_PARAMS of a shared function",
+                ));
+            };
+            unshared.type_params = type_params.to_vec();
+            Ok(Value::Function(function))
+        }
+        (INTRINSIC_SET_FUNCTION_TYPE_PARAMS, ..) => Err(malformed(
+            "INTRINSIC_SET_FUNCTION_TYPE_PARAMS without a function and a tuple",
+        )),
+        (
+            INTRINSIC_TYPEVAR_WITH_BOUND | INTRINSIC_TYPEVAR_WITH_CONSTRAINTS,
+            Value::Str(name),
+            Value::Function(bound),
+        ) => Ok(Value::TypeVar(Rc::new(TypeVar {
+            kind: TypeVarKind::TypeVar,
+            name: Symbol::intern(&name),
+            bound: Some(RefCell::new(Lazy::Pending(bound))),
+            constraints: function == INTRINSIC_TYPEVAR_WITH_CONSTRAINTS,
+        }))),
+        (INTRINSIC_TYPEVAR_WITH_BOUND | INTRINSIC_TYPEVAR_WITH_CONSTRAINTS, ..) => {
+            Err(malformed("type parameter without a name and a function"))
+        }
+        _ => Err(malformed("CALL_INTRINSIC_2 of an unknown function")),
+    }
+}
+
+/// How the errors of a call's `*` and `**` arguments name what is called.
+fn callable_name(callee: &Value) -> String {
+    match callee {
+        Value::Function(function) | Value::Method(function, _) => {
+            format!("__main__.{}()", function.qualname)
+        }
+        Value::Class(class) => format!("__main__.{}()", class.qualname),
+        Value::Builtin(builtin) | Value::BuiltinMethod(builtin, _) => {
+            format!("{}()", builtin.qualname())
+        }
+        _ => format!("{} object", callee.type_name()),
+    }
+}
+
+fn not_defined(name: Symbol) -> Exception {
+    Exception::new("NameError", format!("name '{}' is not defined", name))
+}
+
+/// The error for reading or deleting the empty cell at `index` in `code`.
+fn unbound_deref(code: &CodeObject, index: usize) -> Exception {
+    match index.checked_sub(code.cellvars.len()) {
+        None => unbound_local(code.cellvars[index]),
+        Some(free) => Exception::new(
+            "NameError",
+            format!(
+                "cannot access free variable '{}' where it is not associated with a \
+                 value in enclosing scope",
+                code.freevars[free]
+            ),
+        ),
+    }
+}
+
+/// The items of `value`, which is unpacked into `count` targets.
+fn unpack(value: &Value, count: usize) -> PyResult<Vec<Value>> {
+    let Ok(Value::Iterator(iter)) = value.iter() else {
+        return Err(Exception::type_error(format!(
+            "cannot unpack non-iterable {} object",
+            value.type_name()
+        )));
+    };
+    let items: Vec<Value> = iter.borrow_mut().by_ref().take(count + 1).collect();
+    match items.len() {
+        len if len > count => Err(Exception::new(
+            "ValueError",
+            format!("too many values to unpack (expected {})", count),
+        )),
+        len if len < count => Err(Exception::new(
+            "ValueError",
+            format!(
+                "not enough values to unpack (expected {}, got {})",
+                count, len
+            ),
+        )),
+        _ => Ok(items),
+    }
+}
+
+/// The exception a `raise` of `value`, an exception or its class, raises.
+fn raised(value: Value) -> PyResult<Exception> {
+    match value {
+        Value::ExceptionType(kind) => Ok(Exception::new(kind, "")),
+        Value::Exception(exception) => Ok((*exception).clone()),
+        _ => Err(Exception::type_error(
+            "exceptions must derive from BaseException",
+        )),
+    }
+}
+
+/// Whether an exception of class `kind` is caught by an `except` naming
+/// `class`, an exception class or a tuple of them.
+fn exception_matches(kind: &str, class: &Value) -> PyResult<bool> {
+    match class {
+        Value::ExceptionType(base) => Ok(is_subclass(kind, base)),
+        Value::Tuple(classes) => {
+            for class in classes.iter() {
+                if exception_matches(kind, class)? {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        }
+        _ => Err(Exception::type_error(
+            "catching classes that do not inherit from BaseException is not allowed",
+        )),
+    }
+}
+
+fn no_attribute(value: &Value, name: Symbol) -> Exception {
+    Exception::new(
+        "AttributeError",
+        format!("'{}' object has no attribute '{}'", value.type_name(), name),
+    )
+}
+
+/// The attribute `name` of `value` if it is one evaluated when it is first
+/// asked for: the value of a type alias, or the bound or constraints of a
+/// type parameter.
+fn lazy_attribute(value: &Value, name: Symbol) -> Option<&RefCell<Lazy>> {
+    match (value, name.as_str()) {
+        (Value::TypeAlias(alias), "__value__") => Some(&alias.value),
+        (Value::TypeVar(type_var), "__bound__") if !type_var.constraints => type_var.bound.as_ref(),
+        (Value::TypeVar(type_var), "__constraints__") if type_var.constraints => {
+            type_var.bound.as_ref()
+        }
+        _ => None,
+    }
+}
+
+fn attribute(value: &Value, name: Symbol) -> PyResult {
+    let names = |names: &[Symbol]| {
+        Value::tuple(names.iter().map(|name| Value::str(name.as_str())).collect())
+    };
+    let found = match (value, name.as_str()) {
+        (Value::Function(function), "__name__") => Some(Value::str(&function.code.name)),
+        (Value::Function(function), "__qualname__") => Some(Value::Str(function.qualname.clone())),
+        (Value::Function(function), "__code__") => Some(Value::Code(function.code.clone())),
+        (Value::Function(function), "__defaults__") if function.defaults.is_empty() => {
+            Some(Value::None)
+        }
+        (Value::Function(function), "__defaults__") => {
+            Some(Value::tuple(function.defaults.clone()))
+        }
+        (Value::Function(function), "__kwdefaults__") if function.kwdefaults.is_empty() => {
+            Some(Value::None)
+        }
+        (Value::Function(function), "__kwdefaults__") => {
+            Some(Value::names_dict(&function.kwdefaults))
+        }
+        (Value::Function(function), "__annotations__") => {
+            Some(Value::names_dict(&function.annotations))
+        }
+        (Value::Function(function), "__closure__") if function.closure.is_empty() => {
+            Some(Value::None)
+        }
+        (Value::Function(function), "__closure__") => Some(Value::tuple(
+            function.closure.iter().cloned().map(Value::Cell).collect(),
+        )),
+        (Value::Cell(cell), "cell_contents") => match cell.borrow().clone() {
+            Some(value) => Some(value),
+            None => return Err(Exception::new("ValueError", "Cell is empty")),
+        },
+        (Value::Code(code), "co_name") => Some(Value::str(&code.name)),
+        (Value::Code(code), "co_qualname") => Some(Value::str(&code.qualname)),
+        (Value::Code(code), "co_filename") => Some(Value::str(&code.filename)),
+        (Value::Code(code), "co_firstlineno") => Some(Value::Int(code.firstlineno as i64)),
+        (Value::Code(code), "co_argcount") => Some(Value::Int(code.argcount as i64)),
+        (Value::Code(code), "co_posonlyargcount") => Some(Value::Int(code.posonlyargcount as i64)),
+        (Value::Code(code), "co_kwonlyargcount") => Some(Value::Int(code.kwonlyargcount as i64)),
+        (Value::Code(code), "co_nlocals") => Some(Value::Int(code.varnames.len() as i64)),
+        (Value::Code(code), "co_flags") => Some(Value::Int(code.flags as i64)),
+        (Value::Code(code), "co_stacksize") => Some(Value::Int(code.stacksize as i64)),
+        (Value::Code(code), "co_consts") => Some(Value::tuple(code.consts.clone())),
+        (Value::Code(code), "co_names") => Some(names(&code.names)),
+        (Value::Code(code), "co_varnames") => Some(names(&code.varnames)),
+        (Value::Code(code), "co_cellvars") => Some(names(&code.cellvars)),
+        (Value::Code(code), "co_freevars") => Some(names(&code.freevars)),
+        (Value::Class(class), "__name__") => Some(Value::str(class.name.as_str())),
+        (Value::Class(class), "__qualname__") => Some(Value::Str(class.qualname.clone())),
+        (Value::Function(function), "__type_params__") => {
+            Some(Value::tuple(function.type_params.clone()))
+        }
+        (Value::Class(class), "__type_params__") => Some(
+            class
+                .lookup(name)
+                .unwrap_or_else(|| Value::tuple(Vec::new())),
+        ),
+        (Value::Class(class), _) => class.lookup(name),
+        (Value::Instance(instance), "__class__") => Some(Value::Class(instance.class.clone())),
+        (Value::Instance(instance), _) => instance.attribute(name),
+        (Value::ExceptionType(kind), "__name__") => Some(Value::str(kind)),
+        (Value::List(_), "append") => Some(Value::BuiltinMethod(
+            Builtin::ListAppend,
+            Box::new(value.clone()),
+        )),
+        (Value::TypeVar(type_var), "__name__") => Some(Value::str(type_var.name.as_str())),
+        (Value::TypeVar(type_var), "__bound__") if type_var.kind != TypeVarKind::TypeVarTuple => {
+            Some(Value::None)
+        }
+        (Value::TypeVar(type_var), "__constraints__") if type_var.kind == TypeVarKind::TypeVar => {
+            Some(Value::tuple(Vec::new()))
+        }
+        (Value::TypeAlias(alias), "__name__") => Some(Value::Str(alias.name.clone())),
+        (Value::TypeAlias(alias), "__type_params__") => {
+            Some(Value::tuple(alias.type_params.clone()))
+        }
+        (Value::TypeAlias(_), "__module__") => Some(Value::str("__main__")),
+        (Value::Exception(exception), "args") => Some(Value::tuple(exception.args.clone())),
+        (Value::Exception(exception), "__cause__") => Some(match &exception.cause {
+            Some(cause) => Value::Exception(cause.clone()),
+            None => Value::None,
+        }),
+        _ => None,
+    };
+    found.ok_or_else(|| no_attribute(value, name))
+}
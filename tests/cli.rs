@@ -0,0 +1,45 @@
+//! Runs the `rustypy` binary on modules written to a scratch directory.
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+/// A fresh directory for `name` under the system's temporary directory.
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rustypy-cli-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn rustypy(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rustypy"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_reports_every_syntax_error() {
+    let dir = scratch("syntax");
+    let path = dir.join("bad.py");
+    fs::write(&path, "x = 1 +\ny = 2\nz = 3 *\n").unwrap();
+    let path = path.to_str().unwrap();
+    let expected = "SyntaxError: invalid syntax (line 1)\nSyntaxError: invalid syntax (line 3)\n";
+    for args in [vec![path], vec!["dis", path], vec!["pyc", path]] {
+        let output = rustypy(&args);
+        assert!(!output.status.success(), "{:?}", args);
+        assert_eq!(
+            String::from_utf8(output.stderr).unwrap(),
+            expected,
+            "{:?}",
+            args
+        );
+    }
+
+    let output = rustypy(&["compileall", dir.to_str().unwrap()]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.ends_with(expected), "{}", stdout);
+    fs::remove_dir_all(&dir).unwrap();
+}
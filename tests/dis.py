@@ -0,0 +1,17 @@
+def counter(start=0, *, step=1):
+    count = start
+    def bump():
+        nonlocal count
+        count += step
+        return count
+    return bump
+
+def evens(items):
+    result = []
+    for item in items:
+        if item % 2 == 0 and item is not None:
+            result = result + [item]
+    return [x * x for x in result if x > 2]
+
+squares = {n: n * n for n in range(3)}
+print(counter(step=2)(), evens([1, 2, 4]), squares)
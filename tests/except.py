@@ -0,0 +1,56 @@
+class Box:
+    pass
+
+
+def unwrap(mapping, key):
+    try:
+        return mapping[key]
+    except KeyError as error:
+        print("missing", repr(error))
+    try:
+        error
+    except UnboundLocalError:
+        print("error is unbound")
+
+
+def forget():
+    value = 1
+    def read():
+        return value
+    del value
+    try:
+        read()
+    except NameError as error:
+        print(error)
+
+
+counter = 0
+
+
+def reset():
+    global counter
+    del counter
+
+
+try:
+    try:
+        unwrap({}, "k")
+        assert unwrap({"k": 1}, "k") == 2, "not two"
+    except AssertionError as error:
+        raise ValueError("checked") from error
+except ValueError as error:
+    print(repr(error), repr(error.__cause__))
+try:
+    error
+except NameError as missing:
+    print(missing)
+forget()
+reset()
+box = Box()
+box.item = [1, 2, 3]
+items = {"a": 1, "b": 2}
+del box.item[0], items["a"]
+print(box.item, items)
+del box.item
+del box
+print("done")
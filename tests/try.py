@@ -0,0 +1,44 @@
+class Manager:
+    def __init__(self, name, suppress):
+        self.name = name
+        self.suppress = suppress
+
+    def __enter__(self):
+        print("enter", self.name)
+        return self
+
+    def __exit__(self, kind, value, traceback):
+        print("exit", self.name, kind)
+        return self.suppress
+
+
+def divide(a, b):
+    try:
+        result = a // b
+    except ZeroDivisionError:
+        print("division by zero")
+        return None
+    else:
+        print("no exception")
+    finally:
+        print("finally", a, b)
+    return result
+
+
+def swallow():
+    try:
+        raise KeyError("lost")
+    finally:
+        return "swallowed"
+
+
+print(divide(7, 2), divide(1, 0), swallow())
+for i in range(3):
+    with Manager("loop", False) as manager:
+        if i == 1:
+            break
+        print("body", manager.name, i)
+with Manager("outer", True):
+    with Manager("inner", False):
+        raise ValueError("suppressed")
+print("done")